@@ -0,0 +1,201 @@
+//! Component for a BLE peripheral with a GATT server, and its syscall
+//! driver.
+//!
+//! The GATT server holds the Generic Access service with the device name,
+//! followed by the services of the applications.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let ble_gatt = components::ble_gatt::BleGattComponent::new(
+//!     board_kernel,
+//!     capsules_extra::ble::driver::DRIVER_NUM,
+//!     &nrf52840_peripherals.ble_radio,
+//!     mux_alarm,
+//!     [0x11, 0x22, 0x33, 0x44, 0x55, 0xc6],
+//!     b"Tock",
+//! )
+//! .finalize(components::ble_gatt_component_static!(
+//!     nrf52840::ble_radio::Radio,
+//!     nrf52840::rtc::Rtc
+//! ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::ble::att::{self, Uuid};
+use capsules_extra::ble::driver::BleGattDriver;
+use capsules_extra::ble::gatt::{properties, Attribute, GattServer, Value};
+use capsules_extra::ble::l2cap::{self, L2cap};
+use capsules_extra::ble::link_layer::{self, LinkLayer};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::ble_advertising::{BleAdvertisementDriver, BleConnectionConfig};
+use kernel::hil::time::Alarm;
+use kernel::utilities::cells::OptionalCell;
+
+/// Number of attributes of the GATT server, at most
+/// `capsules_extra::ble::driver::MAX_ATTRIBUTES`.
+pub const ATTRIBUTES: usize = 32;
+/// Number of characteristic values of the GATT server.
+pub const VALUES: usize = 8;
+
+/// The Generic Access service, and its Device Name characteristic.
+const GENERIC_ACCESS: u16 = 0x1800;
+const DEVICE_NAME: u16 = 0x2a00;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ble_gatt_component_static {
+    ($R:ty, $A:ty $(,)?) => {{
+        let alarm = kernel::static_buf!(
+            capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>
+        );
+        let link_layer = kernel::static_buf!(
+            capsules_extra::ble::link_layer::LinkLayer<
+                'static,
+                $R,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        let link_layer_buffer =
+            kernel::static_buf!([u8; capsules_extra::ble::link_layer::BUFFER_LEN]);
+        let l2cap = kernel::static_buf!(capsules_extra::ble::l2cap::L2cap<'static>);
+        let l2cap_rx_buffer = kernel::static_buf!([u8; capsules_extra::ble::l2cap::BUFFER_LEN]);
+        let l2cap_tx_buffer = kernel::static_buf!([u8; capsules_extra::ble::l2cap::BUFFER_LEN]);
+        let attributes = kernel::static_buf!(
+            [kernel::utilities::cells::OptionalCell<capsules_extra::ble::gatt::Attribute>;
+                $crate::ble_gatt::ATTRIBUTES]
+        );
+        let values =
+            kernel::static_buf!([capsules_extra::ble::gatt::Value; $crate::ble_gatt::VALUES]);
+        let response_buffer = kernel::static_buf!([u8; capsules_extra::ble::att::MAX_MTU]);
+        let gatt = kernel::static_buf!(capsules_extra::ble::gatt::GattServer<'static>);
+        let driver = kernel::static_buf!(
+            capsules_extra::ble::driver::BleGattDriver<
+                'static,
+                $R,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+
+        (
+            alarm,
+            link_layer,
+            link_layer_buffer,
+            l2cap,
+            l2cap_rx_buffer,
+            l2cap_tx_buffer,
+            attributes,
+            values,
+            response_buffer,
+            gatt,
+            driver,
+        )
+    };};
+}
+
+pub struct BleGattComponent<
+    R: BleAdvertisementDriver<'static> + BleConnectionConfig + 'static,
+    A: Alarm<'static> + 'static,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    radio: &'static R,
+    mux_alarm: &'static MuxAlarm<'static, A>,
+    address: [u8; 6],
+    device_name: &'static [u8],
+}
+
+impl<
+        R: BleAdvertisementDriver<'static> + BleConnectionConfig + 'static,
+        A: Alarm<'static> + 'static,
+    > BleGattComponent<R, A>
+{
+    /// `address` is the random static address of the device, in little
+    /// endian: its two most significant bits must be set.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        radio: &'static R,
+        mux_alarm: &'static MuxAlarm<'static, A>,
+        address: [u8; 6],
+        device_name: &'static [u8],
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            radio,
+            mux_alarm,
+            address,
+            device_name,
+        }
+    }
+}
+
+impl<
+        R: BleAdvertisementDriver<'static> + BleConnectionConfig + 'static,
+        A: Alarm<'static> + 'static,
+    > Component for BleGattComponent<R, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<LinkLayer<'static, R, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<[u8; link_layer::BUFFER_LEN]>,
+        &'static mut MaybeUninit<L2cap<'static>>,
+        &'static mut MaybeUninit<[u8; l2cap::BUFFER_LEN]>,
+        &'static mut MaybeUninit<[u8; l2cap::BUFFER_LEN]>,
+        &'static mut MaybeUninit<[OptionalCell<Attribute>; ATTRIBUTES]>,
+        &'static mut MaybeUninit<[Value; VALUES]>,
+        &'static mut MaybeUninit<[u8; att::MAX_MTU]>,
+        &'static mut MaybeUninit<GattServer<'static>>,
+        &'static mut MaybeUninit<BleGattDriver<'static, R, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static BleGattDriver<'static, R, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let alarm = s.0.write(VirtualMuxAlarm::new(self.mux_alarm));
+        alarm.setup();
+
+        let link_layer_buffer = s.2.write([0; link_layer::BUFFER_LEN]);
+        let link_layer = s.1.write(LinkLayer::new(
+            self.radio,
+            alarm,
+            link_layer_buffer,
+            self.address,
+        ));
+        self.radio.set_receive_client(link_layer);
+        self.radio.set_transmit_client(link_layer);
+        alarm.set_alarm_client(link_layer);
+
+        let l2cap_rx_buffer = s.4.write([0; l2cap::BUFFER_LEN]);
+        let l2cap_tx_buffer = s.5.write([0; l2cap::BUFFER_LEN]);
+        let l2cap = s.3.write(L2cap::new(l2cap_rx_buffer, l2cap_tx_buffer));
+        link_layer.set_client(l2cap);
+
+        let attributes = s.6.write([(); ATTRIBUTES].map(|_| OptionalCell::empty()));
+        let values = s.7.write([(); VALUES].map(|_| Value::default()));
+        let response_buffer = s.8.write([0; att::MAX_MTU]);
+        let gatt =
+            s.9.write(GattServer::new(attributes, values, response_buffer));
+        l2cap.set_att_client(gatt);
+
+        let _ = gatt.add_service(Uuid::Uuid16(GENERIC_ACCESS));
+        if let Ok(handle) = gatt.add_characteristic(Uuid::Uuid16(DEVICE_NAME), properties::READ) {
+            let _ = gatt.set_value(handle, self.device_name);
+        }
+
+        let driver = s.10.write(BleGattDriver::new(
+            gatt,
+            link_layer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        gatt.set_client(driver);
+
+        driver
+    }
+}
@@ -5,10 +5,18 @@
 //
 // Author: Teona Severin <teona.severin@oxidos.io>
 
-//! Component for CAN syscall interface.
+//! Components for CAN.
 //!
-//! This provides one Component, `CanComponent`, which implements a
-//! userspace syscall interface to the Can peripheral.
+//! This provides four Components:
+//!
+//! 1. `CanComponent` implements a userspace syscall interface to CAN
+//!    devices.
+//! 2. `CanChannelComponent` gives the syscall interface a CAN device, which
+//!    one process uses at a time.
+//! 3. `CanMuxComponent` provides a virtualization layer for a CAN
+//!    controller.
+//! 4. `VirtualCanComponent` provides a virtual CAN device, for a kernel
+//!    capsule or for a channel of the syscall interface.
 //!
 //! Usage
 //! -----
@@ -16,76 +24,233 @@
 //! let can = components::can::CanComponent::new(
 //!     board_kernel,
 //!     capsules_extra::can::DRIVER_NUM,
-//!     &peripherals.can1
 //! ).finalize(components::can_component_static!(
 //!     stm32f429zi::can::Can<'static>
 //! ));
+//! components::can::CanChannelComponent::new(can, &peripherals.can1, None).finalize(
+//!     components::can_channel_component_static!(stm32f429zi::can::Can<'static>),
+//! );
+//! ```
+//!
+//! To share the controller between processes, each channel has a virtual
+//! device:
+//!
+//! ```rust
+//! let mux_can = components::can::CanMuxComponent::new(
+//!     &peripherals.can1,
+//!     None,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::can_mux_component_static!(
+//!     stm32f429zi::can::Can<'static>
+//! ));
+//! type Device = capsules_core::virtualizers::virtual_can::VirtualCanDevice<
+//!     'static,
+//!     stm32f429zi::can::Can<'static>,
+//! >;
+//! let can = components::can::CanComponent::new(
+//!     board_kernel,
+//!     capsules_extra::can::DRIVER_NUM,
+//! )
+//! .finalize(components::can_component_static!(Device));
+//! for _ in 0..2 {
+//!     let can_device = components::can::VirtualCanComponent::new(mux_can).finalize(
+//!         components::virtual_can_component_static!(stm32f429zi::can::Can<'static>),
+//!     );
+//!     components::can::CanChannelComponent::new(can, can_device, Some(can_device))
+//!         .finalize(components::can_channel_component_static!(Device));
+//! }
 //! ```
 //!
 
-use capsules_extra::can::CanCapsule;
+use capsules_core::virtualizers::virtual_can::{MuxCan, VirtualCanDevice};
+use capsules_extra::can::{CanCapsule, CanChannel};
+use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use kernel::component::Component;
+use kernel::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::hil::can;
 use kernel::{capabilities, create_capability};
 
 #[macro_export]
 macro_rules! can_component_static {
     ($C:ty $(,)?) => {{
-        use capsules_extra::can::CanCapsule;
-        use core::mem::MaybeUninit;
+        kernel::static_buf!(capsules_extra::can::CanCapsule<'static, $C>)
+    };};
+}
+
+#[macro_export]
+macro_rules! can_channel_component_static {
+    ($C:ty $(,)?) => {{
         use kernel::hil::can;
         use kernel::static_buf;
 
         let CAN_TX_BUF = static_buf!([u8; can::STANDARD_CAN_PACKET_SIZE]);
         let CAN_RX_BUF = static_buf!([u8; can::STANDARD_CAN_PACKET_SIZE]);
-        let can = static_buf!(capsules_extra::can::CanCapsule<'static, $C>);
-        (can, CAN_TX_BUF, CAN_RX_BUF)
+        let channel = static_buf!(capsules_extra::can::CanChannel<'static, $C>);
+        (channel, CAN_TX_BUF, CAN_RX_BUF)
+    };};
+}
+
+#[macro_export]
+macro_rules! can_mux_component_static {
+    ($C:ty $(,)?) => {{
+        let rx_buffer = kernel::static_buf!([u8; kernel::hil::can::STANDARD_CAN_PACKET_SIZE]);
+        let mux =
+            kernel::static_buf!(capsules_core::virtualizers::virtual_can::MuxCan<'static, $C>);
+        (mux, rx_buffer)
+    };};
+}
+
+#[macro_export]
+macro_rules! virtual_can_component_static {
+    ($C:ty $(,)?) => {{
+        kernel::static_buf!(capsules_core::virtualizers::virtual_can::VirtualCanDevice<'static, $C>)
     };};
 }
 
 pub struct CanComponent<A: 'static + can::Can> {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
-    can: &'static A,
+    _can: PhantomData<A>,
 }
 
 impl<A: 'static + can::Can> CanComponent<A> {
-    pub fn new(
-        board_kernel: &'static kernel::Kernel,
-        driver_num: usize,
-        can: &'static A,
-    ) -> CanComponent<A> {
+    pub fn new(board_kernel: &'static kernel::Kernel, driver_num: usize) -> CanComponent<A> {
         CanComponent {
             board_kernel,
             driver_num,
-            can,
+            _can: PhantomData,
         }
     }
 }
 
 impl<A: 'static + can::Can> Component for CanComponent<A> {
-    type StaticInput = (
-        &'static mut MaybeUninit<CanCapsule<'static, A>>,
-        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
-        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
-    );
+    type StaticInput = &'static mut MaybeUninit<CanCapsule<'static, A>>;
     type Output = &'static CanCapsule<'static, A>;
 
     fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
         let grant_can = self.board_kernel.create_grant(self.driver_num, &grant_cap);
 
-        let can = static_buffer.0.write(capsules_extra::can::CanCapsule::new(
+        static_buffer.write(CanCapsule::new(grant_can))
+    }
+}
+
+pub struct CanChannelComponent<A: 'static + can::Can> {
+    capsule: &'static CanCapsule<'static, A>,
+    can: &'static A,
+    filter: Option<&'static dyn can::Filter>,
+}
+
+impl<A: 'static + can::Can> CanChannelComponent<A> {
+    /// `filter` gives the processes using the channel the acceptance filters
+    /// of `can`, if it has any.
+    pub fn new(
+        capsule: &'static CanCapsule<'static, A>,
+        can: &'static A,
+        filter: Option<&'static dyn can::Filter>,
+    ) -> CanChannelComponent<A> {
+        CanChannelComponent {
+            capsule,
+            can,
+            filter,
+        }
+    }
+}
+
+impl<A: 'static + can::Can> Component for CanChannelComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<CanChannel<'static, A>>,
+        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
+        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    );
+    type Output = &'static CanChannel<'static, A>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let channel = static_buffer.0.write(CanChannel::new(
+            self.capsule,
             self.can,
-            grant_can,
             static_buffer.1.write([0; can::STANDARD_CAN_PACKET_SIZE]),
             static_buffer.2.write([0; can::STANDARD_CAN_PACKET_SIZE]),
         ));
-        can::Controller::set_client(self.can, Some(can));
-        can::Transmit::set_client(self.can, Some(can));
-        can::Receive::set_client(self.can, Some(can));
+        channel.setup();
+        if let Some(filter) = self.filter {
+            channel.set_filter(filter);
+        }
+        can::Controller::set_client(self.can, Some(channel));
+        can::Transmit::set_client(self.can, Some(channel));
+        can::Receive::set_client(self.can, Some(channel));
+
+        channel
+    }
+}
+
+pub struct CanMuxComponent<C: 'static + can::Can> {
+    can: &'static C,
+    filter: Option<&'static dyn can::Filter>,
+    deferred_caller: &'static DynamicDeferredCall,
+}
 
-        can
+impl<C: 'static + can::Can> CanMuxComponent<C> {
+    /// `filter` gives the mux the filter banks of the controller, if it
+    /// has any.
+    pub fn new(
+        can: &'static C,
+        filter: Option<&'static dyn can::Filter>,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> CanMuxComponent<C> {
+        CanMuxComponent {
+            can,
+            filter,
+            deferred_caller,
+        }
+    }
+}
+
+impl<C: 'static + can::Can> Component for CanMuxComponent<C> {
+    type StaticInput = (
+        &'static mut MaybeUninit<MuxCan<'static, C>>,
+        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    );
+    type Output = &'static MuxCan<'static, C>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let rx_buffer = static_buffer.1.write([0; can::STANDARD_CAN_PACKET_SIZE]);
+        let mux = static_buffer.0.write(MuxCan::new(
+            self.can,
+            self.filter,
+            rx_buffer,
+            self.deferred_caller,
+        ));
+        mux.initialize_callback_handle(
+            self.deferred_caller.register(mux).unwrap(), // Unwrap fail = no deferred call slot available for CAN mux
+        );
+        can::Controller::set_client(self.can, Some(mux));
+        can::Transmit::set_client(self.can, Some(mux));
+        can::Receive::set_client(self.can, Some(mux));
+
+        mux
+    }
+}
+
+pub struct VirtualCanComponent<C: 'static + can::Can> {
+    mux: &'static MuxCan<'static, C>,
+}
+
+impl<C: 'static + can::Can> VirtualCanComponent<C> {
+    pub fn new(mux: &'static MuxCan<'static, C>) -> VirtualCanComponent<C> {
+        VirtualCanComponent { mux }
+    }
+}
+
+impl<C: 'static + can::Can> Component for VirtualCanComponent<C> {
+    type StaticInput = &'static mut MaybeUninit<VirtualCanDevice<'static, C>>;
+    type Output = &'static VirtualCanDevice<'static, C>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let device = static_buffer.write(VirtualCanDevice::new(self.mux));
+        device.setup();
+        device
     }
 }
@@ -0,0 +1,207 @@
+//! Component to initialize the CoAP endpoint and its userland driver.
+//!
+//! This provides one Component, CoapComponent. This component creates a
+//! CoapEndpoint bound to the given UDP port, and the syscall driver that lets
+//! apps issue requests through it and register resources it serves.
+//!
+//! The UDP port table only accepts kernel bindings once the userland UDP
+//! driver has been created, so this component must be finalized after the
+//! `UDPDriverComponent`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let coap_driver = CoapComponent::new(
+//!        board_kernel,
+//!        capsules_extra::net::coap::DRIVER_NUM,
+//!        udp_send_mux,
+//!        udp_recv_mux,
+//!        udp_port_table,
+//!        mux_alarm,
+//!        capsules_extra::net::coap::message::COAP_DEFAULT_PORT,
+//!     )
+//!     .finalize(components::coap_component_static!(sam4l::ast::Ast));
+//! ```
+//!
+//! As with the `UDPDriverComponent`, the 6LoWPAN IPv6 sender is assumed by
+//! default, and any other IPv6 sender can be passed explicitly:
+//!
+//! ```rust
+//!     .finalize(components::coap_component_static!(
+//!         @sender capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetStruct<
+//!             'static,
+//!             VirtualMuxAlarm<'static, sifive::clint::Clic<'static>>,
+//!         >,
+//!         sifive::clint::Clic<'static>,
+//!     ));
+//! ```
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::net::coap::endpoint::CoapEndpoint;
+use capsules_extra::net::coap::CoapDriver;
+use capsules_extra::net::ipv6::ipv6_send::IP6Sender;
+use capsules_extra::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules_extra::net::udp::udp_port_table::UdpPortManager;
+use capsules_extra::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules_extra::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::capabilities::NetworkCapabilityCreationCapability;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::time::Alarm;
+
+const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! coap_component_static {
+    (@sender $S:ty, $A:ty $(,)?) => {{
+        use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+        use components::udp_mux::MAX_PAYLOAD_LEN;
+
+        let alarm = kernel::static_buf!(VirtualMuxAlarm<'static, $A>);
+        let udp_send =
+            kernel::static_buf!(capsules_extra::net::udp::udp_send::UDPSendStruct<'static, $S>);
+        let udp_recv =
+            kernel::static_buf!(capsules_extra::net::udp::udp_recv::UDPReceiver<'static>);
+        let udp_vis_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::UdpVisibilityCapability);
+        let net_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::NetworkCapability);
+        let request_buf = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
+        let response_buf = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
+        let endpoint = kernel::static_buf!(
+            capsules_extra::net::coap::endpoint::CoapEndpoint<
+                'static,
+                VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        let driver = kernel::static_buf!(
+            capsules_extra::net::coap::CoapDriver<'static, VirtualMuxAlarm<'static, $A>>
+        );
+
+        (
+            alarm,
+            udp_send,
+            udp_recv,
+            udp_vis_cap,
+            net_cap,
+            request_buf,
+            response_buf,
+            endpoint,
+            driver,
+        )
+    };};
+    ($A:ty $(,)?) => {{
+        $crate::coap_component_static!(
+            @sender capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
+                'static,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >,
+            $A,
+        )
+    };};
+}
+
+pub struct CoapComponent<S: IP6Sender<'static> + 'static, A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    udp_send_mux: &'static MuxUdpSender<'static, S>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    port: u16,
+}
+
+impl<S: IP6Sender<'static>, A: Alarm<'static>> CoapComponent<S, A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        udp_send_mux: &'static MuxUdpSender<'static, S>,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        port: u16,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            alarm_mux,
+            port,
+        }
+    }
+}
+
+impl<S: IP6Sender<'static>, A: Alarm<'static>> Component for CoapComponent<S, A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<UDPSendStruct<'static, S>>,
+        &'static mut MaybeUninit<UDPReceiver<'static>>,
+        &'static mut MaybeUninit<UdpVisibilityCapability>,
+        &'static mut MaybeUninit<NetworkCapability>,
+        &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
+        &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
+        &'static mut MaybeUninit<CoapEndpoint<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<CoapDriver<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static CoapDriver<'static, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(NetworkCapabilityCreationCapability);
+
+        let coap_alarm = s.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        coap_alarm.setup();
+
+        let udp_vis = s.3.write(UdpVisibilityCapability::new(&create_cap));
+        let udp_send = s.1.write(UDPSendStruct::new(self.udp_send_mux, udp_vis));
+        let udp_recv = s.2.write(UDPReceiver::new());
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let net_cap = s.4.write(NetworkCapability::new(
+            AddrRange::Any,
+            PortRange::Any,
+            PortRange::Any,
+            &create_cap,
+        ));
+
+        // Bind the endpoint to its port
+        let socket = self
+            .port_table
+            .create_socket()
+            .unwrap_or_else(|_| panic!("No socket available for the CoAP endpoint"));
+        let (send_bind, recv_bind) = self
+            .port_table
+            .bind(socket, self.port, net_cap)
+            .unwrap_or_else(|_| panic!("Failed to bind the CoAP endpoint to its port"));
+        udp_send.set_binding(send_bind);
+        udp_recv.set_binding(recv_bind);
+
+        let request_buf = s.5.write([0; MAX_PAYLOAD_LEN]);
+        let response_buf = s.6.write([0; MAX_PAYLOAD_LEN]);
+        let endpoint = s.7.write(CoapEndpoint::new(
+            udp_send,
+            coap_alarm,
+            net_cap,
+            request_buf,
+            response_buf,
+        ));
+        udp_send.set_client(endpoint);
+        udp_recv.set_client(endpoint);
+        coap_alarm.set_alarm_client(endpoint);
+
+        let driver = s.8.write(CoapDriver::new(
+            endpoint,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        endpoint.set_client(driver);
+        endpoint.set_server(driver);
+        driver
+    }
+}
@@ -0,0 +1,94 @@
+//! Component for a composite USB device, exposing several USB functions at
+//! once.
+//!
+//! The functions are created by their own components, which make themselves
+//! the client of the USB controller. This component must therefore be
+//! finalized after them, so that the composite device becomes the client of
+//! the controller instead. The functions are added to the device in the
+//! order given, before it is enabled.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpinator", // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//! let composite = components::composite_usb::CompositeUsbComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules_extra::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005a,
+//!     STRINGS,
+//!     &[cdc, ctap],
+//! )
+//! .finalize(components::composite_usb_component_static!(nrf52::usbd::Usbd));
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::composite::{CompositeDevice, UsbFunction};
+use kernel::component::Component;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! composite_usb_component_static {
+    ($U:ty $(,)?) => {{
+        kernel::static_buf!(capsules_extra::usb::composite::CompositeDevice<'static, $U>)
+    };};
+}
+
+pub struct CompositeUsbComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    functions: &'static [&'static dyn UsbFunction<'static>],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> CompositeUsbComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        functions: &'static [&'static dyn UsbFunction<'static>],
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            functions,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for CompositeUsbComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<CompositeDevice<'static, U>>;
+    type Output = &'static CompositeDevice<'static, U>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let composite = s.write(CompositeDevice::new(
+            self.usb,
+            self.max_ctrl_packet_size,
+            self.vendor_id,
+            self.product_id,
+            self.strings,
+        ));
+        for function in self.functions.iter() {
+            // Fails if the functions use the same endpoints, or are too many
+            composite.add_function(*function).unwrap();
+        }
+        self.usb.set_client(composite);
+
+        composite
+    }
+}
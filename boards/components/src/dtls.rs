@@ -0,0 +1,294 @@
+//! Component to initialize a DTLS session and the secure UDP driver.
+//!
+//! This provides one Component, DtlsComponent. This component creates a
+//! DtlsSession bound to the given UDP port, secured with the given pre-shared
+//! key and identity, and the syscall driver that lets an app use it.
+//!
+//! The session needs a SHA-256 engine, a random number generator and an
+//! AES-128-CCM engine, which should be dedicated to the session. If the CCM
+//! engine is a `VirtualAES128CCM`, its crypt buffer must hold the 32 bytes of
+//! the first blocks on top of the largest record, that is
+//! `32 + MAX_PAYLOAD_LEN` rounded up to a multiple of 16 (240 bytes).
+//!
+//! As with the `CoapComponent`, this component must be finalized after the
+//! `UDPDriverComponent`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let dtls_driver = DtlsComponent::new(
+//!        board_kernel,
+//!        capsules_extra::net::dtls::DRIVER_NUM,
+//!        udp_send_mux,
+//!        udp_recv_mux,
+//!        udp_port_table,
+//!        mux_alarm,
+//!        sha,
+//!        rng,
+//!        ccm,
+//!        b"device-1",
+//!        &PSK,
+//!        5684,
+//!     )
+//!     .finalize(components::dtls_component_static!(
+//!         sam4l::ast::Ast,
+//!         capsules_extra::sha256::Sha256Software<'static>,
+//!         capsules_core::virtualizers::virtual_aes_ccm::VirtualAES128CCM<
+//!             'static,
+//!             sam4l::aes::Aes<'static>,
+//!         >,
+//!     ));
+//! ```
+//!
+//! Any other IPv6 sender can be passed explicitly, as with the
+//! `CoapComponent`:
+//!
+//! ```rust
+//!     .finalize(components::dtls_component_static!(
+//!         @sender capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetStruct<
+//!             'static,
+//!             VirtualMuxAlarm<'static, sifive::clint::Clic<'static>>,
+//!         >,
+//!         sifive::clint::Clic<'static>,
+//!         capsules_extra::sha256::Sha256Software<'static>,
+//!         Ccm,
+//!     ));
+//! ```
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::net::dtls::prf::{Prf, SHA256_LEN};
+use capsules_extra::net::dtls::session::{DtlsSession, TRANSCRIPT_LEN};
+use capsules_extra::net::dtls::SecureUdpDriver;
+use capsules_extra::net::ipv6::ipv6_send::IP6Sender;
+use capsules_extra::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules_extra::net::udp::udp_port_table::UdpPortManager;
+use capsules_extra::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules_extra::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::capabilities::NetworkCapabilityCreationCapability;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::digest::{Digest, Sha256};
+use kernel::hil::rng::Rng;
+use kernel::hil::symmetric_encryption::AES128CCM;
+use kernel::hil::time::Alarm;
+
+const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! dtls_component_static {
+    (@sender $S:ty, $A:ty, $D:ty, $C:ty $(,)?) => {{
+        use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+        use capsules_extra::net::dtls::prf::SHA256_LEN;
+        use capsules_extra::net::dtls::session::TRANSCRIPT_LEN;
+        use components::udp_mux::MAX_PAYLOAD_LEN;
+
+        let alarm = kernel::static_buf!(VirtualMuxAlarm<'static, $A>);
+        let udp_send =
+            kernel::static_buf!(capsules_extra::net::udp::udp_send::UDPSendStruct<'static, $S>);
+        let udp_recv =
+            kernel::static_buf!(capsules_extra::net::udp::udp_recv::UDPReceiver<'static>);
+        let udp_vis_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::UdpVisibilityCapability);
+        let net_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::NetworkCapability);
+        let prf_buf = kernel::static_buf!([u8; TRANSCRIPT_LEN]);
+        let digest_buf = kernel::static_buf!([u8; SHA256_LEN]);
+        let prf = kernel::static_buf!(capsules_extra::net::dtls::prf::Prf<'static, $D>);
+        let tx_buf = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
+        let rx_buf = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
+        let session = kernel::static_buf!(
+            capsules_extra::net::dtls::session::DtlsSession<
+                'static,
+                VirtualMuxAlarm<'static, $A>,
+                $D,
+                $C,
+            >
+        );
+        let driver = kernel::static_buf!(
+            capsules_extra::net::dtls::SecureUdpDriver<
+                'static,
+                VirtualMuxAlarm<'static, $A>,
+                $D,
+                $C,
+            >
+        );
+
+        (
+            alarm,
+            udp_send,
+            udp_recv,
+            udp_vis_cap,
+            net_cap,
+            prf_buf,
+            digest_buf,
+            prf,
+            tx_buf,
+            rx_buf,
+            session,
+            driver,
+        )
+    };};
+    ($A:ty, $D:ty, $C:ty $(,)?) => {{
+        $crate::dtls_component_static!(
+            @sender capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
+                'static,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >,
+            $A,
+            $D,
+            $C,
+        )
+    };};
+}
+
+pub struct DtlsComponent<
+    S: IP6Sender<'static> + 'static,
+    A: Alarm<'static> + 'static,
+    D: Digest<'static, SHA256_LEN> + Sha256 + 'static,
+    C: AES128CCM<'static> + 'static,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    udp_send_mux: &'static MuxUdpSender<'static, S>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    sha: &'static D,
+    rng: &'static dyn Rng<'static>,
+    ccm: &'static C,
+    identity: &'static [u8],
+    psk: &'static [u8],
+    port: u16,
+}
+
+impl<
+        S: IP6Sender<'static>,
+        A: Alarm<'static>,
+        D: Digest<'static, SHA256_LEN> + Sha256,
+        C: AES128CCM<'static>,
+    > DtlsComponent<S, A, D, C>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        udp_send_mux: &'static MuxUdpSender<'static, S>,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        sha: &'static D,
+        rng: &'static dyn Rng<'static>,
+        ccm: &'static C,
+        identity: &'static [u8],
+        psk: &'static [u8],
+        port: u16,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            alarm_mux,
+            sha,
+            rng,
+            ccm,
+            identity,
+            psk,
+            port,
+        }
+    }
+}
+
+impl<
+        S: IP6Sender<'static>,
+        A: Alarm<'static>,
+        D: Digest<'static, SHA256_LEN> + Sha256,
+        C: AES128CCM<'static>,
+    > Component for DtlsComponent<S, A, D, C>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<UDPSendStruct<'static, S>>,
+        &'static mut MaybeUninit<UDPReceiver<'static>>,
+        &'static mut MaybeUninit<UdpVisibilityCapability>,
+        &'static mut MaybeUninit<NetworkCapability>,
+        &'static mut MaybeUninit<[u8; TRANSCRIPT_LEN]>,
+        &'static mut MaybeUninit<[u8; SHA256_LEN]>,
+        &'static mut MaybeUninit<Prf<'static, D>>,
+        &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
+        &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
+        &'static mut MaybeUninit<DtlsSession<'static, VirtualMuxAlarm<'static, A>, D, C>>,
+        &'static mut MaybeUninit<SecureUdpDriver<'static, VirtualMuxAlarm<'static, A>, D, C>>,
+    );
+    type Output = &'static SecureUdpDriver<'static, VirtualMuxAlarm<'static, A>, D, C>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(NetworkCapabilityCreationCapability);
+
+        let dtls_alarm = s.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        dtls_alarm.setup();
+
+        let udp_vis = s.3.write(UdpVisibilityCapability::new(&create_cap));
+        let udp_send = s.1.write(UDPSendStruct::new(self.udp_send_mux, udp_vis));
+        let udp_recv = s.2.write(UDPReceiver::new());
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let net_cap = s.4.write(NetworkCapability::new(
+            AddrRange::Any,
+            PortRange::Any,
+            PortRange::Any,
+            &create_cap,
+        ));
+
+        // Bind the session to its port
+        let socket = self
+            .port_table
+            .create_socket()
+            .unwrap_or_else(|_| panic!("No socket available for the DTLS session"));
+        let (send_bind, recv_bind) = self
+            .port_table
+            .bind(socket, self.port, net_cap)
+            .unwrap_or_else(|_| panic!("Failed to bind the DTLS session to its port"));
+        udp_send.set_binding(send_bind);
+        udp_recv.set_binding(recv_bind);
+
+        let prf_buf = s.5.write([0; TRANSCRIPT_LEN]);
+        let digest_buf = s.6.write([0; SHA256_LEN]);
+        let prf = s.7.write(Prf::new(self.sha, prf_buf, digest_buf));
+        self.sha.set_client(prf);
+
+        let tx_buf = s.8.write([0; MAX_PAYLOAD_LEN]);
+        let rx_buf = s.9.write([0; MAX_PAYLOAD_LEN]);
+        let session = s.10.write(DtlsSession::new(
+            udp_send,
+            net_cap,
+            dtls_alarm,
+            self.rng,
+            self.ccm,
+            prf,
+            self.identity,
+            self.psk,
+            tx_buf,
+            rx_buf,
+        ));
+        prf.set_client(session);
+        self.ccm.set_client(session);
+        self.rng.set_client(session);
+        dtls_alarm.set_alarm_client(session);
+        udp_send.set_client(session);
+        udp_recv.set_client(session);
+
+        let driver = s.11.write(SecureUdpDriver::new(
+            session,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        session.set_client(driver);
+        driver
+    }
+}
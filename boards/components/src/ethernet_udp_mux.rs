@@ -0,0 +1,212 @@
+//! Component to initialize the udp/Ethernet interface.
+//!
+//! This provides one Component, EthernetUDPMuxComponent. This component
+//! exposes a MuxUdpSender that other components can implement UDPSenders on
+//! top of to use the UDP stack over an Ethernet adapter. Link-layer addresses
+//! of destinations are resolved through IPv6 Neighbor Discovery.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let (udp_mux, udp_recv, udp_port_table) = EthernetUDPMuxComponent::new(
+//!        ethernet_adapter,
+//!        SRC_MAC_ADDR,
+//!        local_ip_ifaces,
+//!        mux_alarm,
+//!    )
+//!    .finalize(components::ethernet_udp_mux_component_static!(
+//!        sifive::clint::Clic<'static>
+//!    ));
+//! ```
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::net::ethernet::{EthernetAddress, ETHERNET_HDR_LEN};
+use capsules_extra::net::ipv6::ip_utils::IPAddr;
+use capsules_extra::net::ipv6::ipv6_ethernet::{IP6EthernetStruct, NDP_FRAME_LEN};
+use capsules_extra::net::ipv6::ipv6_recv::IP6Receiver;
+use capsules_extra::net::ipv6::ipv6_send::IP6Sender;
+use capsules_extra::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules_extra::net::network_capabilities::{IpVisibilityCapability, UdpVisibilityCapability};
+use capsules_extra::net::udp::udp_port_table::{
+    SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS,
+};
+use capsules_extra::net::udp::udp_recv::MuxUdpReceiver;
+use capsules_extra::net::udp::udp_send::MuxUdpSender;
+use capsules_extra::net::udp::UDPHeader;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::ethernet::EthernetAdapter;
+use kernel::hil::time::Alarm;
+
+use crate::udp_mux::MAX_PAYLOAD_LEN;
+
+// The UDP stack over Ethernet requires the following packet buffers:
+//
+//   1. FRAME_BUF: buffer the IP6EthernetStruct serializes full frames into
+//   2. NDP_BUF: buffer for Neighbor Solicitations and Advertisements
+//   3. UDP_DGRAM: The payload of the IP6_Packet, which holds full IP Packets before they are tx'd.
+//
+// Received frames are processed in place in the Ethernet adapter's buffer.
+
+/// Size of the frame buffer, fitting an IPv6 packet with a UDP payload of
+/// `MAX_PAYLOAD_LEN` bytes.
+pub const FRAME_BUF_LEN: usize = ETHERNET_HDR_LEN + 40 + 8 + MAX_PAYLOAD_LEN;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ethernet_udp_mux_component_static {
+    ($A:ty $(,)?) => {{
+        use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+        use capsules_extra::net::ipv6::ipv6_ethernet::{IP6EthernetStruct, NDP_FRAME_LEN};
+        use capsules_extra::net::udp::udp_send::MuxUdpSender;
+        use components::ethernet_udp_mux::FRAME_BUF_LEN;
+        use components::udp_mux::MAX_PAYLOAD_LEN;
+
+        let alarm = kernel::static_buf!(VirtualMuxAlarm<'static, $A>);
+        let ip6_ethernet =
+            kernel::static_buf!(IP6EthernetStruct<'static, VirtualMuxAlarm<'static, $A>>);
+        let mux_udp_send = kernel::static_buf!(
+            MuxUdpSender<'static, IP6EthernetStruct<'static, VirtualMuxAlarm<'static, $A>>>
+        );
+        let mux_udp_recv =
+            kernel::static_buf!(capsules_extra::net::udp::udp_recv::MuxUdpReceiver<'static>);
+        let udp_port_manager =
+            kernel::static_buf!(capsules_extra::net::udp::udp_port_table::UdpPortManager);
+
+        let ip6_packet = kernel::static_buf!(capsules_extra::net::ipv6::IP6Packet<'static>);
+
+        // See `udp_mux_component_static!` for how ports are bound.
+        let used_ports = kernel::static_buf!(
+            [Option<capsules_extra::net::udp::udp_port_table::SocketBindingEntry>;
+                capsules_extra::net::udp::udp_port_table::MAX_NUM_BOUND_PORTS]
+        );
+
+        let frame_buf = kernel::static_buf!([u8; FRAME_BUF_LEN]);
+        let ndp_buf = kernel::static_buf!([u8; NDP_FRAME_LEN]);
+        let udp_dgram = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
+
+        let udp_vis_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::UdpVisibilityCapability);
+        let ip_vis_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::IpVisibilityCapability);
+
+        (
+            alarm,
+            ip6_ethernet,
+            mux_udp_send,
+            mux_udp_recv,
+            udp_port_manager,
+            ip6_packet,
+            used_ports,
+            frame_buf,
+            ndp_buf,
+            udp_dgram,
+            udp_vis_cap,
+            ip_vis_cap,
+        )
+    };};
+}
+
+pub struct EthernetUDPMuxComponent<A: Alarm<'static> + 'static> {
+    adapter: &'static dyn EthernetAdapter<'static>,
+    src_mac_addr: EthernetAddress,
+    interface_list: &'static [IPAddr],
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> EthernetUDPMuxComponent<A> {
+    pub fn new(
+        adapter: &'static dyn EthernetAdapter<'static>,
+        src_mac_addr: EthernetAddress,
+        interface_list: &'static [IPAddr],
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            adapter,
+            src_mac_addr,
+            interface_list,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for EthernetUDPMuxComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<IP6EthernetStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<
+            MuxUdpSender<'static, IP6EthernetStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+        &'static mut MaybeUninit<MuxUdpReceiver<'static>>,
+        &'static mut MaybeUninit<UdpPortManager>,
+        &'static mut MaybeUninit<IP6Packet<'static>>,
+        &'static mut MaybeUninit<[Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS]>,
+        &'static mut MaybeUninit<[u8; FRAME_BUF_LEN]>,
+        &'static mut MaybeUninit<[u8; NDP_FRAME_LEN]>,
+        &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
+        &'static mut MaybeUninit<UdpVisibilityCapability>,
+        &'static mut MaybeUninit<IpVisibilityCapability>,
+    );
+    type Output = (
+        &'static MuxUdpSender<'static, IP6EthernetStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static MuxUdpReceiver<'static>,
+        &'static UdpPortManager,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let ip6_virtual_alarm = s.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        ip6_virtual_alarm.setup();
+
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = s.10.write(UdpVisibilityCapability::new(&create_cap));
+        let ip_vis = s.11.write(IpVisibilityCapability::new(&create_cap));
+
+        let udp_dgram_buffer = s.9.write([0; MAX_PAYLOAD_LEN]);
+        let tr_hdr = TransportHeader::UDP(UDPHeader::new());
+        let ip_pyld: IPPayload = IPPayload {
+            header: tr_hdr,
+            payload: udp_dgram_buffer,
+        };
+        let ip6_dg = s.5.write(IP6Packet::new(ip_pyld));
+
+        let frame_buf = s.7.write([0; FRAME_BUF_LEN]);
+        let ndp_buf = s.8.write([0; NDP_FRAME_LEN]);
+
+        let ip6_ethernet = s.1.write(IP6EthernetStruct::new(
+            ip6_dg,
+            ip6_virtual_alarm,
+            frame_buf,
+            ndp_buf,
+            self.adapter,
+            self.src_mac_addr,
+            ip_vis,
+        ));
+        ip6_virtual_alarm.set_alarm_client(ip6_ethernet);
+
+        // Initially, set src IP of the sender to be the first IP in the
+        // Interface list. This is also the address we answer Neighbor
+        // Solicitations for.
+        ip6_ethernet.set_addr(self.interface_list[0]);
+        self.adapter.set_client(ip6_ethernet);
+
+        let udp_recv_mux = s.3.write(MuxUdpReceiver::new());
+        IP6Receiver::set_client(ip6_ethernet, udp_recv_mux);
+
+        let udp_send_mux = s.2.write(MuxUdpSender::new(ip6_ethernet));
+        IP6Sender::set_client(ip6_ethernet, udp_send_mux);
+
+        let kernel_ports = s.6.write([None; MAX_NUM_BOUND_PORTS]);
+        let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let udp_port_table = s.4.write(UdpPortManager::new(
+            &create_table_cap,
+            kernel_ports,
+            udp_vis,
+        ));
+
+        self.adapter.enable_receive();
+
+        (udp_send_mux, udp_recv_mux, udp_port_table)
+    }
+}
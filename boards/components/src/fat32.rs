@@ -0,0 +1,115 @@
+//! Component for a FAT32 filesystem on an SD card.
+//!
+//! This provides one component, Fat32Component, which mounts the filesystem
+//! of an SD card and provides a system call interface to it.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let fat32 = components::fat32::Fat32Component::new(
+//!     board_kernel,
+//!     capsules_extra::fat32::driver::DRIVER_NUM,
+//!     sdcard,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::fat32_component_static!(
+//!     capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>
+//! ));
+//! ```
+
+use capsules_extra::fat32::block::{BlockDevice, SdCardBlocks};
+use capsules_extra::fat32::driver::{Fat32Driver, BUF_LEN};
+use capsules_extra::fat32::filesystem::FatFs;
+use capsules_extra::fat32::layout::SECTOR_SIZE;
+use capsules_extra::sdcard::SDCard;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! fat32_component_static {
+    ($A:ty $(,)?) => {{
+        let blocks = kernel::static_buf!(capsules_extra::fat32::block::SdCardBlocks<'static, $A>);
+        let sector = kernel::static_buf!([u8; capsules_extra::fat32::layout::SECTOR_SIZE]);
+        let fs = kernel::static_buf!(
+            capsules_extra::fat32::filesystem::FatFs<
+                'static,
+                capsules_extra::fat32::block::SdCardBlocks<'static, $A>,
+            >
+        );
+        let driver = kernel::static_buf!(
+            capsules_extra::fat32::driver::Fat32Driver<
+                'static,
+                capsules_extra::fat32::block::SdCardBlocks<'static, $A>,
+            >
+        );
+        let buffer = kernel::static_buf!([u8; capsules_extra::fat32::driver::BUF_LEN]);
+
+        (blocks, sector, fs, driver, buffer)
+    };};
+}
+
+pub struct Fat32Component<A: 'static + hil::time::Alarm<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    sdcard: &'static SDCard<'static, A>,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<A: 'static + hil::time::Alarm<'static>> Fat32Component<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        sdcard: &'static SDCard<'static, A>,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            sdcard,
+            deferred_caller,
+        }
+    }
+}
+
+impl<A: 'static + hil::time::Alarm<'static>> Component for Fat32Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<SdCardBlocks<'static, A>>,
+        &'static mut MaybeUninit<[u8; SECTOR_SIZE]>,
+        &'static mut MaybeUninit<FatFs<'static, SdCardBlocks<'static, A>>>,
+        &'static mut MaybeUninit<Fat32Driver<'static, SdCardBlocks<'static, A>>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static Fat32Driver<'static, SdCardBlocks<'static, A>>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let blocks = static_buffer.0.write(SdCardBlocks::new(self.sdcard));
+        self.sdcard.set_client(blocks);
+
+        let sector = static_buffer.1.write([0; SECTOR_SIZE]);
+        let fs = static_buffer
+            .2
+            .write(FatFs::new(blocks, sector, self.deferred_caller));
+        blocks.set_client(fs);
+        fs.initialize_callback_handle(
+            self.deferred_caller.register(fs).unwrap(), // Unwrap fail = no deferred call slot available for FAT32
+        );
+
+        let buffer = static_buffer.4.write([0; BUF_LEN]);
+        let driver = static_buffer.3.write(Fat32Driver::new(
+            fs,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+            buffer,
+        ));
+        fs.set_client(driver);
+
+        let _ = fs.mount();
+        driver
+    }
+}
@@ -0,0 +1,115 @@
+//! Component for the flash filesystem.
+//!
+//! This provides one component, FlashFsComponent, which mounts a filesystem
+//! in a range of flash pages and provides a system call interface to it.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let flash_fs = components::flash_fs::FlashFsComponent::new(
+//!     board_kernel,
+//!     capsules_extra::flash_fs::driver::DRIVER_NUM,
+//!     &peripherals.nvmc,
+//!     0x70,
+//!     16,
+//! )
+//! .finalize(components::flash_fs_component_static!(nrf52840::nvmc::Nvmc));
+//! ```
+
+use capsules_extra::flash_fs::driver::{FlashFsDriver, BUF_LEN};
+use capsules_extra::flash_fs::filesystem::FlashFs;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! flash_fs_component_static {
+    ($F:ty $(,)?) => {{
+        let metadata = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+        let data = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+        let fs = kernel::static_buf!(capsules_extra::flash_fs::filesystem::FlashFs<'static, $F>);
+        let driver =
+            kernel::static_buf!(capsules_extra::flash_fs::driver::FlashFsDriver<'static, $F>);
+        let buffer = kernel::static_buf!([u8; capsules_extra::flash_fs::driver::BUF_LEN]);
+
+        (metadata, data, fs, driver, buffer)
+    };};
+}
+
+pub struct FlashFsComponent<
+    F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, FlashFs<'static, F>>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    flash: &'static F,
+    start_page: usize,
+    pages: usize,
+}
+
+impl<F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, FlashFs<'static, F>>>
+    FlashFsComponent<F>
+{
+    /// The filesystem uses `pages` flash pages starting at `start_page`.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        flash: &'static F,
+        start_page: usize,
+        pages: usize,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            flash,
+            start_page,
+            pages,
+        }
+    }
+}
+
+impl<F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, FlashFs<'static, F>>> Component
+    for FlashFsComponent<F>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<FlashFs<'static, F>>,
+        &'static mut MaybeUninit<FlashFsDriver<'static, F>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static FlashFsDriver<'static, F>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let metadata = static_buffer
+            .0
+            .write(<F as hil::flash::Flash>::Page::default());
+        let data = static_buffer
+            .1
+            .write(<F as hil::flash::Flash>::Page::default());
+
+        let fs = static_buffer.2.write(FlashFs::new(
+            self.flash,
+            self.start_page,
+            self.pages,
+            metadata,
+            data,
+        ));
+        hil::flash::HasClient::set_client(self.flash, fs);
+
+        let buffer = static_buffer.4.write([0; BUF_LEN]);
+        let driver = static_buffer.3.write(FlashFsDriver::new(
+            fs,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+            buffer,
+        ));
+        fs.set_client(driver);
+
+        let _ = fs.mount();
+        driver
+    }
+}
@@ -0,0 +1,122 @@
+//! Component for ISO-TP over a CAN controller, and its syscall driver.
+//!
+//! The component sets the bitrate of the controller and enables it, and
+//! ISO-TP starts receiving once it is enabled.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let isotp = components::isotp::IsoTpComponent::new(
+//!     board_kernel,
+//!     capsules_extra::isotp::driver::DRIVER_NUM,
+//!     &peripherals.can1,
+//!     mux_alarm,
+//!     500_000,
+//! )
+//! .finalize(components::isotp_component_static!(
+//!     stm32f429zi::can::Can<'static>,
+//!     stm32f429zi::tim2::Tim2
+//! ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::isotp::driver::IsoTpDriver;
+use capsules_extra::isotp::transport::IsoTp;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::can::{self, STANDARD_CAN_PACKET_SIZE};
+use kernel::hil::time::Alarm;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! isotp_component_static {
+    ($C:ty, $A:ty $(,)?) => {{
+        let alarm = kernel::static_buf!(
+            capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>
+        );
+        let isotp = kernel::static_buf!(
+            capsules_extra::isotp::transport::IsoTp<
+                'static,
+                $C,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        let tx_buffer = kernel::static_buf!([u8; kernel::hil::can::STANDARD_CAN_PACKET_SIZE]);
+        let rx_buffer = kernel::static_buf!([u8; kernel::hil::can::STANDARD_CAN_PACKET_SIZE]);
+        let driver = kernel::static_buf!(
+            capsules_extra::isotp::driver::IsoTpDriver<
+                'static,
+                $C,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+
+        (alarm, isotp, tx_buffer, rx_buffer, driver)
+    };};
+}
+
+pub struct IsoTpComponent<C: can::Can + 'static, A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    can: &'static C,
+    mux_alarm: &'static MuxAlarm<'static, A>,
+    bitrate: u32,
+}
+
+impl<C: can::Can + 'static, A: Alarm<'static> + 'static> IsoTpComponent<C, A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        can: &'static C,
+        mux_alarm: &'static MuxAlarm<'static, A>,
+        bitrate: u32,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            can,
+            mux_alarm,
+            bitrate,
+        }
+    }
+}
+
+impl<C: can::Can + 'static, A: Alarm<'static> + 'static> Component for IsoTpComponent<C, A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<IsoTp<'static, C, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<[u8; STANDARD_CAN_PACKET_SIZE]>,
+        &'static mut MaybeUninit<[u8; STANDARD_CAN_PACKET_SIZE]>,
+        &'static mut MaybeUninit<IsoTpDriver<'static, C, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static IsoTpDriver<'static, C, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let alarm = s.0.write(VirtualMuxAlarm::new(self.mux_alarm));
+        alarm.setup();
+
+        let tx_buffer = s.2.write([0; STANDARD_CAN_PACKET_SIZE]);
+        let rx_buffer = s.3.write([0; STANDARD_CAN_PACKET_SIZE]);
+        let isotp = s.1.write(IsoTp::new(self.can, alarm, tx_buffer, rx_buffer));
+        alarm.set_alarm_client(isotp);
+        can::Controller::set_client(self.can, Some(isotp));
+        can::Transmit::set_client(self.can, Some(isotp));
+        can::Receive::set_client(self.can, Some(isotp));
+
+        let driver = s.4.write(IsoTpDriver::new(
+            isotp,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        isotp.set_client(driver);
+
+        let _ = can::Configure::set_bitrate(self.can, self.bitrate);
+        let _ = can::Controller::enable(self.can);
+
+        driver
+    }
+}
@@ -36,12 +36,107 @@
 //! ```
 
 use capsules_extra::kv_driver::KVSystemDriver;
-use capsules_extra::kv_store::{KVStore, MuxKVStore};
+use capsules_extra::kv_encryption::{KVEncryption, KeySource, VersionStore};
+use capsules_extra::kv_store::{KVStore, MuxKVStore, METADATA_LENGTH};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil::kv_system::{KVSystem, KeyType};
+use kernel::hil::rng::Rng;
+use kernel::hil::symmetric_encryption::AES128CCM;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! kv_encryption_component_static {
+    ($K:ty, $T:ty, $A:ty, $R:ty, $S:ty, $VS:ty, $V:expr $(,)?) => {{
+        let kv_encryption = kernel::static_buf!(
+            capsules_extra::kv_encryption::KVEncryption<'static, $K, $T, $A, $R, $S, $VS>
+        );
+        let record = kernel::static_buf!(
+            [u8; capsules_extra::kv_encryption::record_length(core::mem::size_of::<$T>(), $V)]
+        );
+
+        (kv_encryption, record)
+    };};
+}
+
+pub struct KVEncryptionComponent<
+    K: 'static + KVSystem<'static, K = T>,
+    T: 'static + KeyType,
+    A: 'static + AES128CCM<'static>,
+    R: 'static + Rng<'static>,
+    S: 'static + KeySource,
+    V: 'static + VersionStore,
+    const RECORD_LENGTH: usize,
+> {
+    kv: &'static K,
+    ccm: &'static A,
+    rng: &'static R,
+    key_source: &'static S,
+    version_store: &'static V,
+}
+
+impl<
+        K: 'static + KVSystem<'static, K = T>,
+        T: 'static + KeyType,
+        A: 'static + AES128CCM<'static>,
+        R: 'static + Rng<'static>,
+        S: 'static + KeySource,
+        V: 'static + VersionStore,
+        const RECORD_LENGTH: usize,
+    > KVEncryptionComponent<K, T, A, R, S, V, RECORD_LENGTH>
+{
+    pub fn new(
+        kv: &'static K,
+        ccm: &'static A,
+        rng: &'static R,
+        key_source: &'static S,
+        version_store: &'static V,
+    ) -> Self {
+        Self {
+            kv,
+            ccm,
+            rng,
+            key_source,
+            version_store,
+        }
+    }
+}
+
+impl<
+        K: 'static + KVSystem<'static, K = T>,
+        T: 'static + KeyType,
+        A: 'static + AES128CCM<'static>,
+        R: 'static + Rng<'static>,
+        S: 'static + KeySource,
+        V: 'static + VersionStore,
+        const RECORD_LENGTH: usize,
+    > Component for KVEncryptionComponent<K, T, A, R, S, V, RECORD_LENGTH>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<KVEncryption<'static, K, T, A, R, S, V>>,
+        &'static mut MaybeUninit<[u8; RECORD_LENGTH]>,
+    );
+    type Output = &'static KVEncryption<'static, K, T, A, R, S, V>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let record = static_buffer.1.write([0; RECORD_LENGTH]);
+
+        let kv_encryption = static_buffer.0.write(KVEncryption::new(
+            self.kv,
+            self.ccm,
+            self.rng,
+            self.key_source,
+            self.version_store,
+            record,
+        ));
+        self.kv.set_client(kv_encryption);
+        self.ccm.set_client(kv_encryption);
+        self.rng.set_client(kv_encryption);
+        kv_encryption
+    }
+}
 
 // Setup static space for the objects.
 #[macro_export]
@@ -83,7 +178,7 @@ macro_rules! kv_store_component_static {
     ($K:ty, $T:ty $(,)?) => {{
         let kv_store = kernel::static_buf!(capsules_extra::kv_store::KVStore<'static, $K, $T>);
         let key = kernel::static_buf!($T);
-        let buffer = kernel::static_buf!([u8; 9]);
+        let buffer = kernel::static_buf!([u8; capsules_extra::kv_store::METADATA_LENGTH]);
 
         (kv_store, key, buffer)
     };};
@@ -105,16 +200,18 @@ impl<K: 'static + KVSystem<'static, K = T>, T: 'static + KeyType + Default> Comp
     type StaticInput = (
         &'static mut MaybeUninit<KVStore<'static, K, T>>,
         &'static mut MaybeUninit<T>,
-        &'static mut MaybeUninit<[u8; 9]>,
+        &'static mut MaybeUninit<[u8; METADATA_LENGTH]>,
     );
     type Output = &'static KVStore<'static, K, T>;
 
     fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
         let key_buf = static_buffer.1.write(T::default());
-        let buffer = static_buffer.2.write([0; 9]);
-        static_buffer
+        let buffer = static_buffer.2.write([0; METADATA_LENGTH]);
+        let kv_store = static_buffer
             .0
-            .write(KVStore::new(self.kv_store, key_buf, buffer))
+            .write(KVStore::new(self.kv_store, key_buf, buffer));
+        kv_store.setup();
+        kv_store
     }
 }
 
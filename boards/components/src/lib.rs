@@ -8,6 +8,7 @@ pub mod analog_comparator;
 pub mod apds9960;
 pub mod app_flash_driver;
 pub mod ble;
+pub mod ble_gatt;
 pub mod bme280;
 pub mod bmp280;
 pub mod bus;
@@ -15,6 +16,8 @@ pub mod button;
 pub mod can;
 pub mod ccs811;
 pub mod cdc;
+pub mod coap;
+pub mod composite_usb;
 pub mod console;
 pub mod crc;
 pub mod ctap;
@@ -22,7 +25,11 @@ pub mod dac;
 pub mod debug_queue;
 pub mod debug_writer;
 pub mod digest;
+pub mod dtls;
+pub mod ethernet_udp_mux;
+pub mod fat32;
 pub mod flash;
+pub mod flash_fs;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700;
@@ -34,11 +41,13 @@ pub mod humidity;
 pub mod i2c;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod isotp;
 pub mod kv_system;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
 pub mod lldb;
+pub mod log;
 pub mod lpm013m126;
 pub mod lps25hb;
 pub mod lsm303agr;
@@ -76,3 +85,7 @@ pub mod touch;
 pub mod udp_driver;
 pub mod udp_mux;
 pub mod usb;
+pub mod usb_dfu;
+pub mod usb_ecm;
+pub mod usb_hid;
+pub mod usb_msc;
@@ -0,0 +1,138 @@
+//! Component for the log syscall driver.
+//!
+//! This provides one component, LogComponent, which keeps a log in a flash
+//! region and provides apps with a log of their own in it.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let log = components::log::LogComponent::new(
+//!     board_kernel,
+//!     capsules_extra::log_driver::DRIVER_NUM,
+//!     &peripherals.nvmc,
+//!     &LOG_VOLUME,
+//!     true,
+//!     1024,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::log_component_static!(nrf52840::nvmc::Nvmc));
+//! ```
+
+use capsules_extra::log::Log;
+use capsules_extra::log_driver::{LogDriver, BUF_LEN};
+use capsules_extra::log_mux::{MuxLog, OWNER_SIZE};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::hil;
+use kernel::hil::log::{LogRead, LogWrite};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! log_component_static {
+    ($F:ty $(,)?) => {{
+        let page = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+        let log = kernel::static_buf!(capsules_extra::log::Log<'static, $F>);
+        let entry = kernel::static_buf!(
+            [u8; capsules_extra::log_driver::BUF_LEN + capsules_extra::log_mux::OWNER_SIZE]
+        );
+        let mux = kernel::static_buf!(
+            capsules_extra::log_mux::MuxLog<'static, capsules_extra::log::Log<'static, $F>>
+        );
+        let driver = kernel::static_buf!(
+            capsules_extra::log_driver::LogDriver<'static, capsules_extra::log::Log<'static, $F>>
+        );
+        let buffer = kernel::static_buf!([u8; capsules_extra::log_driver::BUF_LEN]);
+
+        (page, log, entry, mux, driver, buffer)
+    };};
+}
+
+pub struct LogComponent<
+    F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, Log<'static, F>>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    flash: &'static F,
+    volume: &'static [u8],
+    circular: bool,
+    quota: usize,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, Log<'static, F>>>
+    LogComponent<F>
+{
+    /// The log is kept in `volume`, and each app may have `quota` bytes of
+    /// it.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        flash: &'static F,
+        volume: &'static [u8],
+        circular: bool,
+        quota: usize,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            flash,
+            volume,
+            circular,
+            quota,
+            deferred_caller,
+        }
+    }
+}
+
+impl<F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, Log<'static, F>>> Component
+    for LogComponent<F>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<Log<'static, F>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN + OWNER_SIZE]>,
+        &'static mut MaybeUninit<MuxLog<'static, Log<'static, F>>>,
+        &'static mut MaybeUninit<LogDriver<'static, Log<'static, F>>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static LogDriver<'static, Log<'static, F>>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let page = static_buffer
+            .0
+            .write(<F as hil::flash::Flash>::Page::default());
+        let log = static_buffer.1.write(Log::new(
+            self.volume,
+            self.flash,
+            page,
+            self.deferred_caller,
+            self.circular,
+        ));
+        hil::flash::HasClient::set_client(self.flash, log);
+        log.initialize_callback_handle(
+            self.deferred_caller.register(log).unwrap(), // Unwrap fail = no deferred call slot available for the log
+        );
+
+        let entry = static_buffer.2.write([0; BUF_LEN + OWNER_SIZE]);
+        let mux = static_buffer.3.write(MuxLog::new(log, entry));
+        log.set_read_client(mux);
+        log.set_append_client(mux);
+
+        let buffer = static_buffer.5.write([0; BUF_LEN]);
+        let driver = static_buffer.4.write(LogDriver::new(
+            mux,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+            self.quota,
+            buffer,
+        ));
+        mux.set_client(driver);
+
+        driver
+    }
+}
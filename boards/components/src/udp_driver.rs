@@ -14,13 +14,24 @@
 //!        local_ip_ifaces,
 //!        PAYLOAD_LEN,
 //!     )
-//!     .finalize(components::udp_driver_component_static!());
+//!     .finalize(components::udp_driver_component_static!(sam4l::ast::Ast));
+//! ```
+//!
+//! By default, the driver is instantiated on top of the 6LoWPAN IPv6 sender
+//! created by `UDPMuxComponent`. To use it with any other IPv6 sender, such
+//! as the one created by `EthernetUDPMuxComponent`, pass its type instead:
+//!
+//! ```rust
+//!     .finalize(components::udp_driver_component_static!(
+//!         @sender capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetStruct<
+//!             'static,
+//!             VirtualMuxAlarm<'static, sifive::clint::Clic<'static>>,
+//!         >
+//!     ));
 //! ```
 
-use capsules_core;
-use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
 use capsules_extra::net::ipv6::ip_utils::IPAddr;
-use capsules_extra::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules_extra::net::ipv6::ipv6_send::IP6Sender;
 use capsules_extra::net::network_capabilities::{
     AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
 };
@@ -34,25 +45,17 @@ use kernel::capabilities;
 use kernel::capabilities::NetworkCapabilityCreationCapability;
 use kernel::component::Component;
 use kernel::create_capability;
-use kernel::hil::time::Alarm;
 
 const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
 
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! udp_driver_component_static {
-    ($A:ty $(,)?) => {{
+    (@sender $S:ty $(,)?) => {{
         use components::udp_mux::MAX_PAYLOAD_LEN;
 
-        let udp_send = kernel::static_buf!(
-            capsules_extra::net::udp::udp_send::UDPSendStruct<
-                'static,
-                capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
-                    'static,
-                    capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
-                >,
-            >
-        );
+        let udp_send =
+            kernel::static_buf!(capsules_extra::net::udp::udp_send::UDPSendStruct<'static, $S>);
         let udp_vis_cap =
             kernel::static_buf!(capsules_extra::net::network_capabilities::UdpVisibilityCapability);
         let net_cap =
@@ -64,26 +67,30 @@ macro_rules! udp_driver_component_static {
 
         (udp_send, udp_vis_cap, net_cap, udp_driver, buffer, udp_recv)
     };};
+    ($A:ty $(,)?) => {{
+        $crate::udp_driver_component_static!(
+            @sender capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
+                'static,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        )
+    };};
 }
 
-pub struct UDPDriverComponent<A: Alarm<'static> + 'static> {
+pub struct UDPDriverComponent<S: IP6Sender<'static> + 'static> {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
-    udp_send_mux:
-        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_send_mux: &'static MuxUdpSender<'static, S>,
     udp_recv_mux: &'static MuxUdpReceiver<'static>,
     port_table: &'static UdpPortManager,
     interface_list: &'static [IPAddr],
 }
 
-impl<A: Alarm<'static>> UDPDriverComponent<A> {
+impl<S: IP6Sender<'static>> UDPDriverComponent<S> {
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         driver_num: usize,
-        udp_send_mux: &'static MuxUdpSender<
-            'static,
-            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
-        >,
+        udp_send_mux: &'static MuxUdpSender<'static, S>,
         udp_recv_mux: &'static MuxUdpReceiver<'static>,
         port_table: &'static UdpPortManager,
         interface_list: &'static [IPAddr],
@@ -99,17 +106,9 @@ impl<A: Alarm<'static>> UDPDriverComponent<A> {
     }
 }
 
-impl<A: Alarm<'static>> Component for UDPDriverComponent<A> {
+impl<S: IP6Sender<'static>> Component for UDPDriverComponent<S> {
     type StaticInput = (
-        &'static mut MaybeUninit<
-            UDPSendStruct<
-                'static,
-                capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
-                    'static,
-                    VirtualMuxAlarm<'static, A>,
-                >,
-            >,
-        >,
+        &'static mut MaybeUninit<UDPSendStruct<'static, S>>,
         &'static mut MaybeUninit<
             capsules_extra::net::network_capabilities::UdpVisibilityCapability,
         >,
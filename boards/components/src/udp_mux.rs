@@ -4,10 +4,15 @@
 //! exposes a MuxUdpSender that other components can implement
 //! UDPSenders on top of to use the UDP/6Lowpan stack.
 //!
+//! The 6LoWPAN layer can reassemble several fragmented packets concurrently.
+//! By default it has a single reassembly context, and
+//! `udp_mux_component_static!` takes the number of contexts as an optional
+//! second argument. Each context needs a 1280 byte buffer.
+//!
 //! Usage
 //! -----
 //! ```rust
-//!    let (udp_mux, udp_recv) = UDPMuxComponent::new(
+//!    let (udp_mux, udp_recv, udp_port_table, sixlowpan) = UDPMuxComponent::new(
 //!        mux_mac,
 //!        DEFAULT_CTX_PREFIX_LEN,
 //!        DEFAULT_CTX_PREFIX,
@@ -17,7 +22,7 @@
 //!        mux_alarm,
 //!        MAX_PAYLOAD_LEN,
 //!    )
-//!    .finalize(components::udp_mux_component_static!());
+//!    .finalize(components::udp_mux_component_static!(nrf52840::rtc::Rtc, 2));
 //! ```
 
 // Author: Hudson Ayers <hayers@stanford.edu>
@@ -52,7 +57,8 @@ use kernel::hil::time::Alarm;
 // The UDP stack requires several packet buffers:
 //
 //   1. RADIO_BUF: buffer the IP6_Sender uses to pass frames to the radio after fragmentation
-//   2. SIXLOWPAN_RX_BUF: Buffer to hold full IP packets after they are decompressed by 6LoWPAN
+//   2. SIXLOWPAN_RX_BUF: Buffers to hold full IP packets after they are decompressed by 6LoWPAN,
+//      one per reassembly context
 //   3. UDP_DGRAM: The payload of the IP6_Packet, which holds full IP Packets before they are tx'd.
 //
 //   Additionally, every capsule using the stack needs an additional buffer to craft packets for
@@ -64,6 +70,9 @@ pub const MAX_PAYLOAD_LEN: usize = 200; //The max size UDP message that can be s
 #[macro_export]
 macro_rules! udp_mux_component_static {
     ($A:ty $(,)?) => {{
+        $crate::udp_mux_component_static!($A, 1)
+    };};
+    ($A:ty, $N:expr $(,)?) => {{
         use capsules_core;
         use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
         use capsules_extra::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
@@ -81,7 +90,8 @@ macro_rules! udp_mux_component_static {
                 sixlowpan_compression::Context,
             >
         );
-        let rx_state = kernel::static_buf!(sixlowpan_state::RxState<'static>);
+        let sixlowpan_alarm = kernel::static_buf!(VirtualMuxAlarm<'static, $A>);
+        let rx_states = kernel::static_buf!([sixlowpan_state::RxState<'static>; $N]);
         let ip6_send = kernel::static_buf!(
             capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
                 'static,
@@ -123,7 +133,7 @@ macro_rules! udp_mux_component_static {
         );
 
         let radio_buf = kernel::static_buf!([u8; kernel::hil::radio::MAX_BUF_SIZE]);
-        let sixlowpan_rx = kernel::static_buf!([u8; 1280]);
+        let sixlowpan_rx = kernel::static_buf!([[u8; 1280]; $N]);
         let udp_dgram = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
 
         let udp_vis_cap =
@@ -135,7 +145,8 @@ macro_rules! udp_mux_component_static {
             alarm,
             mac_user,
             sixlowpan,
-            rx_state,
+            sixlowpan_alarm,
+            rx_states,
             ip6_send,
             mux_udp_send,
             mux_udp_recv,
@@ -152,7 +163,7 @@ macro_rules! udp_mux_component_static {
     };};
 }
 
-pub struct UDPMuxComponent<A: Alarm<'static> + 'static> {
+pub struct UDPMuxComponent<A: Alarm<'static> + 'static, const RX_STATES: usize = 1> {
     mux_mac: &'static capsules_extra::ieee802154::virtual_mac::MuxMac<'static>,
     ctx_pfix_len: u8,
     ctx_pfix: [u8; 16],
@@ -162,7 +173,7 @@ pub struct UDPMuxComponent<A: Alarm<'static> + 'static> {
     alarm_mux: &'static MuxAlarm<'static, A>,
 }
 
-impl<A: Alarm<'static> + 'static> UDPMuxComponent<A> {
+impl<A: Alarm<'static> + 'static, const RX_STATES: usize> UDPMuxComponent<A, RX_STATES> {
     pub fn new(
         mux_mac: &'static capsules_extra::ieee802154::virtual_mac::MuxMac<'static>,
         ctx_pfix_len: u8,
@@ -184,7 +195,9 @@ impl<A: Alarm<'static> + 'static> UDPMuxComponent<A> {
     }
 }
 
-impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
+impl<A: Alarm<'static> + 'static, const RX_STATES: usize> Component
+    for UDPMuxComponent<A, RX_STATES>
+{
     type StaticInput = (
         &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
         &'static mut MaybeUninit<capsules_extra::ieee802154::virtual_mac::MacUser<'static>>,
@@ -195,7 +208,8 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
                 sixlowpan_compression::Context,
             >,
         >,
-        &'static mut MaybeUninit<sixlowpan_state::RxState<'static>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<[sixlowpan_state::RxState<'static>; RX_STATES]>,
         &'static mut MaybeUninit<
             capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
                 'static,
@@ -217,7 +231,7 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         &'static mut MaybeUninit<IP6RecvStruct<'static>>,
         &'static mut MaybeUninit<[Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS]>,
         &'static mut MaybeUninit<[u8; radio::MAX_BUF_SIZE]>,
-        &'static mut MaybeUninit<[u8; 1280]>,
+        &'static mut MaybeUninit<[[u8; 1280]; RX_STATES]>,
         &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
         &'static mut MaybeUninit<UdpVisibilityCapability>,
         &'static mut MaybeUninit<IpVisibilityCapability>,
@@ -226,6 +240,11 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
         &'static MuxUdpReceiver<'static>,
         &'static UdpPortManager,
+        &'static sixlowpan_state::Sixlowpan<
+            'static,
+            VirtualMuxAlarm<'static, A>,
+            sixlowpan_compression::Context,
+        >,
     );
 
     fn finalize(self, s: Self::StaticInput) -> Self::Output {
@@ -238,8 +257,13 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
             ));
         self.mux_mac.add_user(udp_mac);
         let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
-        let udp_vis = s.14.write(UdpVisibilityCapability::new(&create_cap));
-        let ip_vis = s.15.write(IpVisibilityCapability::new(&create_cap));
+        let udp_vis = s.15.write(UdpVisibilityCapability::new(&create_cap));
+        let ip_vis = s.16.write(IpVisibilityCapability::new(&create_cap));
+
+        // The 6LoWPAN layer sets alarms to time out reassemblies, so it
+        // needs its own virtual alarm
+        let sixlowpan_alarm = s.3.write(VirtualMuxAlarm::new(self.alarm_mux));
+        sixlowpan_alarm.setup();
 
         let sixlowpan = s.2.write(sixlowpan_state::Sixlowpan::new(
             sixlowpan_compression::Context {
@@ -248,26 +272,31 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
                 id: 0,
                 compress: false,
             },
-            ipsender_virtual_alarm, // OK to reuse bc only used to get time, not set alarms
+            sixlowpan_alarm,
         ));
+        sixlowpan_alarm.set_alarm_client(sixlowpan);
 
-        let sixlowpan_rx_buffer = s.12.write([0; 1280]);
+        let sixlowpan_rx_buffers = s.13.write([[0; 1280]; RX_STATES]);
         let sixlowpan_state = sixlowpan as &dyn sixlowpan_state::SixlowpanState;
         let sixlowpan_tx = sixlowpan_state::TxState::new(sixlowpan_state);
-        let default_rx_state =
-            s.3.write(sixlowpan_state::RxState::new(sixlowpan_rx_buffer));
-        sixlowpan_state.add_rx_state(default_rx_state);
+        let mut rx_buffers = sixlowpan_rx_buffers.iter_mut();
+        let rx_states = s.4.write(core::array::from_fn(|_| {
+            sixlowpan_state::RxState::new(rx_buffers.next().unwrap())
+        }));
+        for rx_state in rx_states.iter() {
+            sixlowpan_state.add_rx_state(rx_state);
+        }
         udp_mac.set_receive_client(sixlowpan);
 
-        let udp_dgram_buffer = s.13.write([0; MAX_PAYLOAD_LEN]);
+        let udp_dgram_buffer = s.14.write([0; MAX_PAYLOAD_LEN]);
         let tr_hdr = TransportHeader::UDP(UDPHeader::new());
         let ip_pyld: IPPayload = IPPayload {
             header: tr_hdr,
             payload: udp_dgram_buffer,
         };
-        let ip6_dg = s.8.write(IP6Packet::new(ip_pyld));
+        let ip6_dg = s.9.write(IP6Packet::new(ip_pyld));
 
-        let radio_buf = s.11.write([0; radio::MAX_BUF_SIZE]);
+        let radio_buf = s.12.write([0; radio::MAX_BUF_SIZE]);
 
         // In current design, all udp senders share same IP sender, and the IP
         // sender holds the destination mac address. This means all UDP senders
@@ -277,7 +306,7 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         // addresses on a local network. This will be fixed once we have an
         // ipv6_nd cache mapping IP addresses to dst macs
         let ip_send =
-            s.4.write(capsules_extra::net::ipv6::ipv6_send::IP6SendStruct::new(
+            s.5.write(capsules_extra::net::ipv6::ipv6_send::IP6SendStruct::new(
                 ip6_dg,
                 ipsender_virtual_alarm,
                 radio_buf,
@@ -297,22 +326,22 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         udp_mac.set_transmit_client(ip_send);
 
         let ip_receive =
-            s.9.write(capsules_extra::net::ipv6::ipv6_recv::IP6RecvStruct::new());
+            s.10.write(capsules_extra::net::ipv6::ipv6_recv::IP6RecvStruct::new());
         sixlowpan_state.set_rx_client(ip_receive);
-        let udp_recv_mux = s.6.write(MuxUdpReceiver::new());
+        let udp_recv_mux = s.7.write(MuxUdpReceiver::new());
         ip_receive.set_client(udp_recv_mux);
 
-        let udp_send_mux = s.5.write(MuxUdpSender::new(ip_send));
+        let udp_send_mux = s.6.write(MuxUdpSender::new(ip_send));
         ip_send.set_client(udp_send_mux);
 
-        let kernel_ports = s.10.write([None; MAX_NUM_BOUND_PORTS]);
+        let kernel_ports = s.11.write([None; MAX_NUM_BOUND_PORTS]);
         let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
-        let udp_port_table = s.7.write(UdpPortManager::new(
+        let udp_port_table = s.8.write(UdpPortManager::new(
             &create_table_cap,
             kernel_ports,
             udp_vis,
         ));
 
-        (udp_send_mux, udp_recv_mux, udp_port_table)
+        (udp_send_mux, udp_recv_mux, udp_port_table, sixlowpan)
     }
 }
@@ -0,0 +1,83 @@
+//! Component for installing applications over USB with the Device Firmware
+//! Upgrade class.
+//!
+//! The DFU interface is a function of a composite USB device, and must be
+//! given to `CompositeUsbComponent` along with the other functions of the
+//! device. Images are written to app flash through `storage`, and checked
+//! by `checker`. The component makes the function the client of the checker,
+//! so it must not be the instance the kernel checks processes with.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let dfu = components::usb_dfu::UsbDfuComponent::new(
+//!     nv_to_page,
+//!     core::slice::from_raw_parts(
+//!         &_sapps as *const u8,
+//!         &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+//!     ),
+//!     checker,
+//! )
+//! .finalize(components::usb_dfu_component_static!());
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::dfu::{UsbDfu, TRANSFER_SIZE};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::process_checker::AppCredentialsChecker;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_dfu_component_static {
+    () => {{
+        let buffer = kernel::static_buf!([u8; capsules_extra::usb::dfu::TRANSFER_SIZE]);
+        let dfu = kernel::static_buf!(capsules_extra::usb::dfu::UsbDfu<'static>);
+
+        (buffer, dfu)
+    };};
+}
+
+pub struct UsbDfuComponent {
+    storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    app_flash: &'static [u8],
+    checker: &'static dyn AppCredentialsChecker<'static>,
+}
+
+impl UsbDfuComponent {
+    pub fn new(
+        storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        app_flash: &'static [u8],
+        checker: &'static dyn AppCredentialsChecker<'static>,
+    ) -> Self {
+        Self {
+            storage,
+            app_flash,
+            checker,
+        }
+    }
+}
+
+impl Component for UsbDfuComponent {
+    type StaticInput = (
+        &'static mut MaybeUninit<[u8; TRANSFER_SIZE]>,
+        &'static mut MaybeUninit<UsbDfu<'static>>,
+    );
+    type Output = &'static UsbDfu<'static>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let buffer = s.0.write([0; TRANSFER_SIZE]);
+
+        let dfu = s.1.write(UsbDfu::new(
+            self.storage,
+            self.app_flash,
+            self.checker,
+            buffer,
+        ));
+        self.storage.set_client(dfu);
+        self.checker.set_client(dfu);
+
+        dfu
+    }
+}
@@ -0,0 +1,102 @@
+//! Component for a USB CDC-ECM function, which makes the board appear as a
+//! USB Ethernet adapter to the host.
+//!
+//! The function is an Ethernet adapter for `EthernetUDPMuxComponent`, and a
+//! function of a composite USB device, which must be given to
+//! `CompositeUsbComponent` along with the other functions of the device. The
+//! index of the string holding the MAC address of the host must follow the
+//! strings of the device.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let ecm = components::usb_ecm::UsbEcmComponent::new(
+//!     &nrf52840_peripherals.usbd,
+//!     4,
+//!     EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+//!     1,
+//!     2,
+//!     3,
+//! )
+//! .finalize(components::usb_ecm_component_static!(nrf52840::usbd::Usbd));
+//!
+//! let (udp_send_mux, udp_recv_mux, udp_port_table) =
+//!     components::ethernet_udp_mux::EthernetUDPMuxComponent::new(
+//!         ecm,
+//!         EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]),
+//!         local_ip_ifaces,
+//!         mux_alarm,
+//!     )
+//!     .finalize(components::ethernet_udp_mux_component_static!(
+//!         nrf52840::rtc::Rtc
+//!     ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::net::ethernet::EthernetAddress;
+use capsules_extra::usb::ecm::{CdcEcm, MAX_FRAME_LEN};
+use kernel::component::Component;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_ecm_component_static {
+    ($U:ty $(,)?) => {{
+        let rx_buffer = kernel::static_buf!([u8; capsules_extra::usb::ecm::MAX_FRAME_LEN]);
+        let ecm = kernel::static_buf!(capsules_extra::usb::ecm::CdcEcm<'static, $U>);
+
+        (rx_buffer, ecm)
+    };};
+}
+
+pub struct UsbEcmComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    mac_string_index: u8,
+    host_address: EthernetAddress,
+    endpoint_notify: usize,
+    endpoint_in: usize,
+    endpoint_out: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbEcmComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        mac_string_index: u8,
+        host_address: EthernetAddress,
+        endpoint_notify: usize,
+        endpoint_in: usize,
+        endpoint_out: usize,
+    ) -> Self {
+        Self {
+            usb,
+            mac_string_index,
+            host_address,
+            endpoint_notify,
+            endpoint_in,
+            endpoint_out,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbEcmComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<[u8; MAX_FRAME_LEN]>,
+        &'static mut MaybeUninit<CdcEcm<'static, U>>,
+    );
+    type Output = &'static CdcEcm<'static, U>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let rx_buffer = s.0.write([0; MAX_FRAME_LEN]);
+
+        s.1.write(CdcEcm::new(
+            self.usb,
+            self.mac_string_index,
+            self.host_address,
+            self.endpoint_notify,
+            self.endpoint_in,
+            self.endpoint_out,
+            rx_buffer,
+        ))
+    }
+}
@@ -0,0 +1,181 @@
+//! Components for USB HID keyboards and mice, with their syscall drivers.
+//!
+//! The keyboard or mouse is a function of a composite USB device, and must
+//! be given to `CompositeUsbComponent` along with the other functions of the
+//! device.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (keyboard, keyboard_driver) = components::usb_hid::UsbHidKeyboardComponent::new(
+//!     board_kernel,
+//!     capsules_extra::hid_keyboard::DRIVER_NUM,
+//!     &nrf52840_peripherals.usbd,
+//!     1,
+//! )
+//! .finalize(components::usb_hid_keyboard_component_static!(
+//!     nrf52840::usbd::Usbd
+//! ));
+//!
+//! let (mouse, mouse_driver) = components::usb_hid::UsbHidMouseComponent::new(
+//!     board_kernel,
+//!     capsules_extra::hid_mouse::DRIVER_NUM,
+//!     &nrf52840_peripherals.usbd,
+//!     2,
+//! )
+//! .finalize(components::usb_hid_mouse_component_static!(
+//!     nrf52840::usbd::Usbd
+//! ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::hid_keyboard::HidKeyboardDriver;
+use capsules_extra::hid_mouse::HidMouseDriver;
+use capsules_extra::usb::hid::{BootDevice, BootHid, REPORT_SIZE};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_hid_keyboard_component_static {
+    ($U:ty $(,)?) => {{
+        let hid = kernel::static_buf!(capsules_extra::usb::hid::BootHid<'static, $U>);
+        let driver = kernel::static_buf!(
+            capsules_extra::hid_keyboard::HidKeyboardDriver<
+                'static,
+                capsules_extra::usb::hid::BootHid<'static, $U>,
+            >
+        );
+        let send_buffer = kernel::static_buf!([u8; capsules_extra::usb::hid::REPORT_SIZE]);
+        let recv_buffer = kernel::static_buf!([u8; capsules_extra::usb::hid::REPORT_SIZE]);
+
+        (hid, driver, send_buffer, recv_buffer)
+    };};
+}
+
+#[macro_export]
+macro_rules! usb_hid_mouse_component_static {
+    ($U:ty $(,)?) => {{
+        let hid = kernel::static_buf!(capsules_extra::usb::hid::BootHid<'static, $U>);
+        let driver = kernel::static_buf!(
+            capsules_extra::hid_mouse::HidMouseDriver<
+                'static,
+                capsules_extra::usb::hid::BootHid<'static, $U>,
+            >
+        );
+        let send_buffer = kernel::static_buf!([u8; capsules_extra::usb::hid::REPORT_SIZE]);
+
+        (hid, driver, send_buffer)
+    };};
+}
+
+pub struct UsbHidKeyboardComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    usb: &'static U,
+    endpoint: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbHidKeyboardComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        usb: &'static U,
+        endpoint: usize,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            usb,
+            endpoint,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbHidKeyboardComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<BootHid<'static, U>>,
+        &'static mut MaybeUninit<HidKeyboardDriver<'static, BootHid<'static, U>>>,
+        &'static mut MaybeUninit<[u8; REPORT_SIZE]>,
+        &'static mut MaybeUninit<[u8; REPORT_SIZE]>,
+    );
+    type Output = (
+        &'static BootHid<'static, U>,
+        &'static HidKeyboardDriver<'static, BootHid<'static, U>>,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let hid =
+            s.0.write(BootHid::new(self.usb, BootDevice::Keyboard, self.endpoint));
+
+        let send_buffer = s.2.write([0; REPORT_SIZE]);
+        let recv_buffer = s.3.write([0; REPORT_SIZE]);
+        let driver = s.1.write(HidKeyboardDriver::new(
+            hid,
+            send_buffer,
+            recv_buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        hid.set_client(driver);
+        let _ = driver.start_receive();
+
+        (hid, driver)
+    }
+}
+
+pub struct UsbHidMouseComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    usb: &'static U,
+    endpoint: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbHidMouseComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        usb: &'static U,
+        endpoint: usize,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            usb,
+            endpoint,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbHidMouseComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<BootHid<'static, U>>,
+        &'static mut MaybeUninit<HidMouseDriver<'static, BootHid<'static, U>>>,
+        &'static mut MaybeUninit<[u8; REPORT_SIZE]>,
+    );
+    type Output = (
+        &'static BootHid<'static, U>,
+        &'static HidMouseDriver<'static, BootHid<'static, U>>,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let hid =
+            s.0.write(BootHid::new(self.usb, BootDevice::Mouse, self.endpoint));
+
+        let send_buffer = s.2.write([0; REPORT_SIZE]);
+        let driver = s.1.write(HidMouseDriver::new(
+            hid,
+            send_buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        hid.set_client(driver);
+
+        (hid, driver)
+    }
+}
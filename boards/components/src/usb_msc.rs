@@ -0,0 +1,90 @@
+//! Component for a USB mass storage drive, exposing a region of nonvolatile
+//! storage to the host.
+//!
+//! The drive is a function of a composite USB device, and must be given to
+//! `CompositeUsbComponent` along with the other functions of the device.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let msc = components::usb_msc::UsbMassStorageComponent::new(
+//!     &nrf52840_peripherals.usbd,
+//!     nv_to_page,
+//!     0x40000,
+//!     2048,
+//!     1,
+//!     2,
+//! )
+//! .finalize(components::usb_msc_component_static!(nrf52840::usbd::Usbd));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::msc::{UsbMassStorage, BLOCK_SIZE};
+use kernel::component::Component;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_msc_component_static {
+    ($U:ty $(,)?) => {{
+        let buffer = kernel::static_buf!([u8; capsules_extra::usb::msc::BLOCK_SIZE]);
+        let msc = kernel::static_buf!(capsules_extra::usb::msc::UsbMassStorage<'static, $U>);
+
+        (buffer, msc)
+    };};
+}
+
+pub struct UsbMassStorageComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    start_address: usize,
+    block_count: u32,
+    endpoint_in: usize,
+    endpoint_out: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbMassStorageComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start_address: usize,
+        block_count: u32,
+        endpoint_in: usize,
+        endpoint_out: usize,
+    ) -> Self {
+        Self {
+            usb,
+            storage,
+            start_address,
+            block_count,
+            endpoint_in,
+            endpoint_out,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbMassStorageComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<[u8; BLOCK_SIZE]>,
+        &'static mut MaybeUninit<UsbMassStorage<'static, U>>,
+    );
+    type Output = &'static UsbMassStorage<'static, U>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let buffer = s.0.write([0; BLOCK_SIZE]);
+
+        let msc = s.1.write(UsbMassStorage::new(
+            self.usb,
+            self.storage,
+            self.start_address,
+            self.block_count,
+            self.endpoint_in,
+            self.endpoint_out,
+            buffer,
+        ));
+        self.storage.set_client(msc);
+
+        msc
+    }
+}
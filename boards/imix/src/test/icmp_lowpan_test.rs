@@ -86,6 +86,11 @@ pub unsafe fn run(
         VirtualMuxAlarm::new(mux_alarm)
     );
     ipsender_virtual_alarm.setup();
+    let sixlo_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    sixlo_alarm.setup();
 
     let sixlowpan = static_init!(
         Sixlowpan<
@@ -100,9 +105,10 @@ pub unsafe fn run(
                 id: 0,
                 compress: false,
             },
-            ipsender_virtual_alarm
+            sixlo_alarm
         )
     );
+    sixlo_alarm.set_alarm_client(sixlowpan);
 
     let sixlowpan_state = sixlowpan as &dyn SixlowpanState;
     let sixlowpan_tx = TxState::new(sixlowpan_state);
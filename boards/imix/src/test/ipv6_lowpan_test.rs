@@ -158,6 +158,7 @@ pub unsafe fn initialize_all(
             sixlo_alarm
         )
     );
+    sixlo_alarm.set_alarm_client(sixlowpan);
 
     let sixlowpan_state = sixlowpan as &dyn SixlowpanState;
     let sixlowpan_tx = TxState::new(sixlowpan_state);
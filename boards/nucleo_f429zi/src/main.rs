@@ -593,14 +593,13 @@ pub unsafe fn main() {
     .finalize(components::rng_component_static!());
 
     // CAN
-    let can = components::can::CanComponent::new(
-        board_kernel,
-        capsules_extra::can::DRIVER_NUM,
-        &peripherals.can1,
-    )
-    .finalize(components::can_component_static!(
-        stm32f429zi::can::Can<'static>
-    ));
+    let can = components::can::CanComponent::new(board_kernel, capsules_extra::can::DRIVER_NUM)
+        .finalize(components::can_component_static!(
+            stm32f429zi::can::Can<'static>
+        ));
+    components::can::CanChannelComponent::new(can, &peripherals.can1, None).finalize(
+        components::can_channel_component_static!(stm32f429zi::can::Can<'static>),
+    );
 
     // PROCESS CONSOLE
     let process_console = components::process_console::ProcessConsoleComponent::new(
@@ -37,6 +37,10 @@ static mut PROCESS_PRINTER: Option<&'static kernel::process::ProcessPrinterText>
 // How should the kernel respond when a process faults.
 const FAULT_RESPONSE: kernel::process::PanicFaultPolicy = kernel::process::PanicFaultPolicy {};
 
+// MAC address of the VirtIO network adapter if the device does not report
+// one, a locally administered unicast address.
+const FALLBACK_MAC_ADDRESS: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
 /// Dummy buffer that causes the linker to reserve enough space for the stack.
 #[no_mangle]
 #[link_section = ".stack_buffer"]
@@ -69,6 +73,13 @@ struct QemuRv32VirtPlatform {
         VirtualMuxAlarm<'static, qemu_rv32_virt_chip::chip::QemuRv32VirtClint<'static>>,
     >,
     virtio_rng: Option<&'static capsules_core::rng::RngDriver<'static>>,
+    virtio_net_udp: Option<&'static capsules_extra::net::udp::UDPDriver<'static>>,
+    virtio_net_coap: Option<
+        &'static capsules_extra::net::coap::CoapDriver<
+            'static,
+            VirtualMuxAlarm<'static, qemu_rv32_virt_chip::chip::QemuRv32VirtClint<'static>>,
+        >,
+    >,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -88,6 +99,20 @@ impl SyscallDriverLookup for QemuRv32VirtPlatform {
                     f(None)
                 }
             }
+            capsules_extra::net::udp::DRIVER_NUM => {
+                if let Some(udp_driver) = self.virtio_net_udp {
+                    f(Some(udp_driver))
+                } else {
+                    f(None)
+                }
+            }
+            capsules_extra::net::coap::DRIVER_NUM => {
+                if let Some(coap_driver) = self.virtio_net_coap {
+                    f(Some(coap_driver))
+                } else {
+                    f(None)
+                }
+            }
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -311,14 +336,11 @@ pub unsafe fn main() {
         };
 
     // If there is a VirtIO NetworkCard present, use the appropriate VirtIONet
-    // driver. Currently this is not used, as work on the userspace network
-    // driver and kernel network stack is in progress.
-    //
-    // A template dummy driver is provided to verify basic functionality of this
-    // interface.
-    let _virtio_net_if: Option<
-        &'static qemu_rv32_virt_chip::virtio::devices::virtio_net::VirtIONet<'static>,
-    > = if let Some(net_idx) = virtio_net_idx {
+    // driver and expose the UDP stack and a CoAP endpoint over it to
+    // userspace. IPv6 packets are sent in Ethernet II frames, resolving
+    // link-layer addresses through NDP.
+    let (virtio_net_udp_driver, virtio_net_coap_driver) = if let Some(net_idx) = virtio_net_idx {
+        use capsules_extra::net::ipv6::ip_utils::IPAddr;
         use qemu_rv32_virt_chip::virtio::devices::virtio_net::VirtIONet;
         use qemu_rv32_virt_chip::virtio::queues::split_queue::{
             SplitVirtqueue, VirtqueueAvailableRing, VirtqueueDescriptors, VirtqueueUsedRing,
@@ -387,17 +409,67 @@ pub unsafe fn main() {
             .initialize(virtio_net, mmio_queues)
             .unwrap();
 
-        // Don't forget to enable RX once when integrating this into a
-        // proper Ethernet stack:
-        // virtio_net.enable_rx();
-
-        // TODO: When we have a proper Ethernet driver available for userspace,
-        // return that. For now, just return a reference to the raw VirtIONet
-        // driver:
-        Some(virtio_net as &'static VirtIONet)
+        // The MAC address assigned to the adapter by the host, and the IPv6
+        // link-local address derived from it. A device not reporting one gets
+        // a fixed locally administered address.
+        let mac_addr = capsules_extra::net::ethernet::EthernetAddress(
+            virtio_net
+                .mac_address(&peripherals.virtio_mmio[net_idx])
+                .unwrap_or(FALLBACK_MAC_ADDRESS),
+        );
+        let local_ip_ifaces = static_init!([IPAddr; 1], [IPAddr::generate_from_ethernet(mac_addr)]);
+
+        // The UDP stack over Ethernet, which also enables reception on the
+        // VirtIONet driver
+        let (udp_send_mux, udp_recv_mux, udp_port_table) =
+            components::ethernet_udp_mux::EthernetUDPMuxComponent::new(
+                virtio_net,
+                mac_addr,
+                local_ip_ifaces,
+                mux_alarm,
+            )
+            .finalize(components::ethernet_udp_mux_component_static!(
+                qemu_rv32_virt_chip::chip::QemuRv32VirtClint
+            ));
+
+        let udp_driver = components::udp_driver::UDPDriverComponent::new(
+            board_kernel,
+            capsules_extra::net::udp::DRIVER_NUM,
+            udp_send_mux,
+            udp_recv_mux,
+            udp_port_table,
+            local_ip_ifaces,
+        )
+        .finalize(components::udp_driver_component_static!(
+            @sender capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetStruct<
+                'static,
+                VirtualMuxAlarm<'static, qemu_rv32_virt_chip::chip::QemuRv32VirtClint>,
+            >
+        ));
+
+        // CoAP endpoint on the default CoAP port. This must come after the
+        // UDP driver, which the port table consults for bound ports.
+        let coap_driver = components::coap::CoapComponent::new(
+            board_kernel,
+            capsules_extra::net::coap::DRIVER_NUM,
+            udp_send_mux,
+            udp_recv_mux,
+            udp_port_table,
+            mux_alarm,
+            capsules_extra::net::coap::message::COAP_DEFAULT_PORT,
+        )
+        .finalize(components::coap_component_static!(
+            @sender capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetStruct<
+                'static,
+                VirtualMuxAlarm<'static, qemu_rv32_virt_chip::chip::QemuRv32VirtClint>,
+            >,
+            qemu_rv32_virt_chip::chip::QemuRv32VirtClint,
+        ));
+
+        (Some(udp_driver), Some(coap_driver))
     } else {
         // No VirtIO NetworkCard discovered
-        None
+        (None, None)
     };
 
     // ---------- INITIALIZE CHIP, ENABLE INTERRUPTS ---------
@@ -472,6 +544,8 @@ pub unsafe fn main() {
         scheduler,
         scheduler_timer,
         virtio_rng: virtio_rng_driver,
+        virtio_net_udp: virtio_net_udp_driver,
+        virtio_net_coap: virtio_net_coap_driver,
         ipc: kernel::ipc::IPC::new(
             board_kernel,
             kernel::ipc::DRIVER_NUM,
@@ -30,11 +30,17 @@ pub enum NUM {
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
     Can                   = 0x20007,
+    HidKeyboard           = 0x20008,
+    HidMouse              = 0x20009,
+    IsoTp                 = 0x2000a,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    Coap                  = 0x30003,
+    SecureUdp             = 0x30004,
+    BleGatt               = 0x30005,
 
     // Cryptography
     Rng                   = 0x40001,
@@ -49,6 +55,9 @@ pub enum NUM {
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
     KVSystem              = 0x50003,
+    FlashFs               = 0x50004,
+    Fat32                 = 0x50005,
+    Log                   = 0x50006,
 
     // Sensors
     Temperature           = 0x60000,
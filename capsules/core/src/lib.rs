@@ -1,6 +1,10 @@
 #![forbid(unsafe_code)]
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 pub mod test;
 
 #[macro_use]
@@ -0,0 +1,334 @@
+//! A CAN controller in software that receives every message it sends, for
+//! testing CAN capsules on boards without a CAN transceiver, or without any
+//! other node on the bus.
+//!
+//! The controller has `FILTER_COUNT` filter banks. While none is enabled it
+//! receives every message, as a controller does once the receive process
+//! starts, and otherwise it receives the messages accepted by one of them.
+//! Transmissions complete after the message is received, in a deferred
+//! call.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let loopback = static_init!(
+//!     capsules_core::test::can_loopback::CanLoopback<'static>,
+//!     capsules_core::test::can_loopback::CanLoopback::new(dynamic_deferred_caller)
+//! );
+//! loopback.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(loopback).unwrap(),
+//! );
+//! ```
+
+use core::cell::Cell;
+
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::can;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// The number of filter banks of the controller.
+pub const FILTER_COUNT: usize = 4;
+
+pub struct CanLoopback<'a> {
+    state: Cell<can::State>,
+    bitrate: Cell<Option<u32>>,
+    bit_timing: Cell<Option<can::BitTiming>>,
+    operation_mode: Cell<can::OperationMode>,
+    automatic_retransmission: Cell<bool>,
+    wake_up: Cell<bool>,
+    filters: [Cell<Option<can::FilterParameters>>; FILTER_COUNT],
+
+    // The message being sent, and whether the controller is enabling or
+    // disabling
+    tx: Cell<Option<(can::Id, usize)>>,
+    tx_buffer: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    switching: Cell<Option<can::State>>,
+    rx_buffer: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    stopping: Cell<bool>,
+
+    controller_client: OptionalCell<&'static dyn can::ControllerClient>,
+    transmit_client:
+        OptionalCell<&'static dyn can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+    receive_client:
+        OptionalCell<&'static dyn can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> CanLoopback<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> CanLoopback<'a> {
+        CanLoopback {
+            state: Cell::new(can::State::Disabled),
+            bitrate: Cell::new(None),
+            bit_timing: Cell::new(None),
+            operation_mode: Cell::new(can::OperationMode::Loopback),
+            automatic_retransmission: Cell::new(false),
+            wake_up: Cell::new(false),
+            filters: core::array::from_fn(|_| Cell::new(None)),
+            tx: Cell::new(None),
+            tx_buffer: TakeCell::empty(),
+            switching: Cell::new(None),
+            rx_buffer: TakeCell::empty(),
+            stopping: Cell::new(false),
+            controller_client: OptionalCell::empty(),
+            transmit_client: OptionalCell::empty(),
+            receive_client: OptionalCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Returns how many filter banks are enabled.
+    pub fn enabled_filters(&self) -> usize {
+        self.filters.iter().filter(|f| f.get().is_some()).count()
+    }
+
+    fn schedule(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn running(&self) -> bool {
+        self.state.get() == can::State::Running && self.switching.get().is_none()
+    }
+
+    fn accepts(&self, id: can::Id) -> bool {
+        let mut enabled = self.filters.iter().filter_map(|filter| filter.get());
+        let mut any = false;
+        let accepted = enabled.any(|filter| {
+            any = true;
+            filter.accepts(id)
+        });
+        accepted || !any
+    }
+}
+
+impl DynamicDeferredCallClient for CanLoopback<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(state) = self.switching.take() {
+            self.state.set(state);
+            self.controller_client.map(|client| {
+                client.state_changed(state);
+                if state == can::State::Running {
+                    client.enabled(Ok(()));
+                } else {
+                    client.disabled(Ok(()));
+                }
+            });
+        }
+
+        if let Some((id, len)) = self.tx.take() {
+            self.tx_buffer.take().map(|buffer| {
+                if self.accepts(id) {
+                    self.rx_buffer.map(|rx_buffer| {
+                        rx_buffer.copy_from_slice(buffer);
+                        self.receive_client
+                            .map(|client| client.message_received(id, rx_buffer, len, Ok(())));
+                    });
+                }
+                self.transmit_client
+                    .map(move |client| client.transmit_complete(Ok(()), buffer));
+            });
+        }
+
+        if self.stopping.take() {
+            self.rx_buffer.take().map(|buffer| {
+                self.receive_client
+                    .map(move |client| client.stopped(buffer))
+            });
+        }
+    }
+}
+
+impl can::Configure for CanLoopback<'_> {
+    const MIN_BIT_TIMINGS: can::BitTiming = can::BitTiming {
+        segment1: 0,
+        segment2: 0,
+        propagation: 0,
+        sync_jump_width: 0,
+        baud_rate_prescaler: 1,
+    };
+    const MAX_BIT_TIMINGS: can::BitTiming = can::BitTiming {
+        segment1: 0xf,
+        segment2: 0x7,
+        propagation: 0,
+        sync_jump_width: 0x3,
+        baud_rate_prescaler: 0x3ff,
+    };
+    const SYNC_SEG: u8 = 1;
+
+    fn set_bitrate(&self, bitrate: u32) -> Result<(), ErrorCode> {
+        if self.state.get() != can::State::Disabled {
+            return Err(ErrorCode::BUSY);
+        }
+        self.bitrate.set(Some(bitrate));
+        Ok(())
+    }
+
+    fn set_bit_timing(&self, bit_timing: can::BitTiming) -> Result<(), ErrorCode> {
+        if self.state.get() != can::State::Disabled {
+            return Err(ErrorCode::BUSY);
+        }
+        self.bit_timing.set(Some(bit_timing));
+        Ok(())
+    }
+
+    fn set_operation_mode(&self, mode: can::OperationMode) -> Result<(), ErrorCode> {
+        if self.state.get() != can::State::Disabled {
+            return Err(ErrorCode::BUSY);
+        }
+        self.operation_mode.set(mode);
+        Ok(())
+    }
+
+    fn get_bit_timing(&self) -> Result<can::BitTiming, ErrorCode> {
+        self.bit_timing.get().ok_or(ErrorCode::INVAL)
+    }
+
+    fn get_operation_mode(&self) -> Result<can::OperationMode, ErrorCode> {
+        Ok(self.operation_mode.get())
+    }
+
+    fn set_automatic_retransmission(&self, automatic: bool) -> Result<(), ErrorCode> {
+        self.automatic_retransmission.set(automatic);
+        Ok(())
+    }
+
+    fn set_wake_up(&self, wake_up: bool) -> Result<(), ErrorCode> {
+        self.wake_up.set(wake_up);
+        Ok(())
+    }
+
+    fn get_automatic_retransmission(&self) -> Result<bool, ErrorCode> {
+        Ok(self.automatic_retransmission.get())
+    }
+
+    fn get_wake_up(&self) -> Result<bool, ErrorCode> {
+        Ok(self.wake_up.get())
+    }
+
+    fn receive_fifo_count(&self) -> usize {
+        1
+    }
+}
+
+impl can::Controller for CanLoopback<'_> {
+    fn set_client(&self, client: Option<&'static dyn can::ControllerClient>) {
+        self.controller_client.insert(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != can::State::Disabled || self.switching.get().is_some() {
+            Err(ErrorCode::BUSY)
+        } else if self.bitrate.get().is_none() && self.bit_timing.get().is_none() {
+            Err(ErrorCode::INVAL)
+        } else {
+            self.switching.set(Some(can::State::Running));
+            self.schedule();
+            Ok(())
+        }
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if !self.running() {
+            Err(ErrorCode::OFF)
+        } else {
+            self.switching.set(Some(can::State::Disabled));
+            self.schedule();
+            Ok(())
+        }
+    }
+
+    fn get_state(&self) -> Result<can::State, ErrorCode> {
+        Ok(self.state.get())
+    }
+}
+
+impl can::Filter for CanLoopback<'_> {
+    fn enable_filter(&self, filter: can::FilterParameters) -> Result<(), ErrorCode> {
+        self.filters
+            .get(filter.number as usize)
+            .map(|bank| bank.set(Some(filter)))
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    fn disable_filter(&self, number: u32) -> Result<(), ErrorCode> {
+        self.filters
+            .get(number as usize)
+            .map(|bank| bank.set(None))
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    fn filter_count(&self) -> usize {
+        FILTER_COUNT
+    }
+}
+
+impl can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }> for CanLoopback<'_> {
+    fn set_client(
+        &self,
+        client: Option<&'static dyn can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+    ) {
+        self.transmit_client.insert(client);
+    }
+
+    fn send(
+        &self,
+        id: can::Id,
+        buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE])> {
+        if !self.running() {
+            Err((ErrorCode::OFF, buffer))
+        } else if self.tx.get().is_some() {
+            Err((ErrorCode::BUSY, buffer))
+        } else if len > can::STANDARD_CAN_PACKET_SIZE {
+            Err((ErrorCode::SIZE, buffer))
+        } else {
+            self.tx.set(Some((id, len)));
+            self.tx_buffer.replace(buffer);
+            self.schedule();
+            Ok(())
+        }
+    }
+}
+
+impl can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }> for CanLoopback<'_> {
+    fn set_client(
+        &self,
+        client: Option<&'static dyn can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+    ) {
+        self.receive_client.insert(client);
+    }
+
+    fn start_receive_process(
+        &self,
+        buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE])> {
+        if !self.running() {
+            Err((ErrorCode::OFF, buffer))
+        } else if self.rx_buffer.is_some() {
+            Err((ErrorCode::BUSY, buffer))
+        } else {
+            self.rx_buffer.replace(buffer);
+            Ok(())
+        }
+    }
+
+    fn stop_receive(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_none() || self.stopping.get() {
+            Err(ErrorCode::OFF)
+        } else {
+            self.stopping.set(true);
+            self.schedule();
+            Ok(())
+        }
+    }
+}
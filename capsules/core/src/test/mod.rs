@@ -1,5 +1,6 @@
 pub mod alarm;
 pub mod alarm_edge_cases;
+pub mod can_loopback;
 pub mod double_grant_entry;
 pub mod random_alarm;
 pub mod random_timer;
@@ -1,6 +1,7 @@
 pub mod virtual_adc;
 pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
+pub mod virtual_can;
 pub mod virtual_digest;
 pub mod virtual_flash;
 pub mod virtual_hmac;
@@ -36,6 +36,10 @@
 //! combine saved_tag and the unencrypted tag to form the encrypted tag and
 //! verify its correctness.
 //!
+//! CCM* uses 13 byte nonces, but shorter nonces are also accepted, following
+//! the CCM specification (RFC 3610): a nonce of `n` bytes leaves `15 - n`
+//! bytes to encode the message length. TLS and DTLS use 12 byte nonces.
+//!
 //! Usage
 //! -----
 //!
@@ -89,7 +93,8 @@ use kernel::dynamic_deferred_call::{
 };
 use kernel::hil::symmetric_encryption;
 use kernel::hil::symmetric_encryption::{
-    AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE, CCM_NONCE_LENGTH,
+    AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+    CCM_MIN_NONCE_LENGTH, CCM_NONCE_LENGTH,
 };
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::ErrorCode;
@@ -261,6 +266,7 @@ pub struct VirtualAES128CCM<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128EC
     pos: Cell<(usize, usize, usize, usize)>,
     key: Cell<[u8; AES128_KEY_SIZE]>,
     nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+    nonce_len: Cell<usize>,
     saved_tag: Cell<[u8; AES128_BLOCK_SIZE]>,
     queued_up: OptionalCell<CryptFunctionParameters>,
 }
@@ -285,6 +291,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
             pos: Cell::new((0, 0, 0, 0)),
             key: Cell::new(Default::default()),
             nonce: Cell::new(Default::default()),
+            nonce_len: Cell::new(CCM_NONCE_LENGTH),
             saved_tag: Cell::new(Default::default()),
             queued_up: OptionalCell::empty(),
         }
@@ -300,7 +307,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
     /// not present or if it is not long enough.
     fn prepare_ccm_buffer(
         &self,
-        nonce: &[u8; CCM_NONCE_LENGTH],
+        nonce: &[u8],
         mic_len: usize,
         a_data: &[u8],
         m_data: &[u8],
@@ -338,7 +345,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
     /// guaranteed to be >= AES128_BLOCK_SIZE
     fn encode_ccm_buffer(
         buf: &mut [u8],
-        nonce: &[u8; CCM_NONCE_LENGTH],
+        nonce: &[u8],
         mic_len: usize,
         a_data: &[u8],
         m_data: &[u8],
@@ -346,7 +353,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
         // IEEE 802.15.4-2015: Appendix B.4.1.2, CCM* authentication
         // The authentication tag T is computed with AES128-CBC-MAC on
         // B_0 | AuthData, where
-        //   B_0 = Flags (1 byte) | nonce (13 bytes) | m length (2 bytes)
+        //   B_0 = Flags (1 byte) | nonce (15 - L bytes) | m length (L bytes)
         //   Flags = 0 | A data present? (1 bit) | M (3 bits) | L (3 bits)
         //   AuthData = AddAuthData | PlaintextData
         //   AddAuthData = L(a) (encoding of a_data.len()) | a_data
@@ -362,14 +369,22 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
         if mic_len != 0 {
             flags |= (((mic_len - 2) / 2) as u8) << 3;
         }
-        flags |= 1;
+        // L is 2 with the 13 byte nonces of CCM*
+        let l = AES128_BLOCK_SIZE - 1 - nonce.len();
+        flags |= (l - 1) as u8;
 
         stream_len_cond!(buf, AES128_BLOCK_SIZE);
+        stream_cond!(l >= 8 || m_data.len() >> (8 * l) == 0);
         // The first block is flags | nonce | m length
         buf[0] = flags;
-        buf[1..14].copy_from_slice(nonce.as_ref());
-        let mut off = enc_consume!(buf, 14; encode_u16,
-                                            (m_data.len() as u16).to_le());
+        buf[1..1 + nonce.len()].copy_from_slice(nonce);
+        for (i, byte) in buf[1 + nonce.len()..AES128_BLOCK_SIZE]
+            .iter_mut()
+            .enumerate()
+        {
+            *byte = (m_data.len() >> (8 * (l - 1 - i))) as u8;
+        }
+        let mut off = AES128_BLOCK_SIZE;
 
         // After that comes L(a) | a, where L(a) is the following
         // encoding of a_len:
@@ -478,9 +493,10 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
 
         let mut iv = [0u8; AES128_BLOCK_SIZE];
         // flags = reserved | reserved | 0 | (L - 1)
-        // Since L = 2, flags = 1.
-        iv[0] = 1;
-        iv[1..1 + CCM_NONCE_LENGTH].copy_from_slice(&self.nonce.get());
+        // With the 13 byte nonces of CCM*, L = 2 and flags = 1.
+        let nonce_len = self.nonce_len.get();
+        iv[0] = (AES128_BLOCK_SIZE - 2 - nonce_len) as u8;
+        iv[1..1 + nonce_len].copy_from_slice(&self.nonce.get()[..nonce_len]);
         let res = self.aes.set_iv(&iv);
         if res != Ok(()) {
             return res;
@@ -638,7 +654,7 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> VirtualAES128CCM<'a,
         self.encrypting.set(encrypting);
 
         let res = self.prepare_ccm_buffer(
-            &self.nonce.get(),
+            &self.nonce.get()[..self.nonce_len.get()],
             mic_len,
             &buf[a_off..m_off],
             &buf[m_off..m_off + m_len],
@@ -690,12 +706,13 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption
     }
 
     fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
-        if nonce.len() < CCM_NONCE_LENGTH {
+        if nonce.len() < CCM_MIN_NONCE_LENGTH || nonce.len() > CCM_NONCE_LENGTH {
             Err(ErrorCode::INVAL)
         } else {
             let mut new_nonce = [0u8; CCM_NONCE_LENGTH];
-            new_nonce.copy_from_slice(nonce);
+            new_nonce[..nonce.len()].copy_from_slice(nonce);
             self.nonce.set(new_nonce);
+            self.nonce_len.set(nonce.len());
             Ok(())
         }
     }
@@ -918,3 +935,396 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> ListNode<'a, Virtual
         &self.next
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use kernel::hil::symmetric_encryption::{CCMClient, Client, AES128CCM};
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    fn xtime(a: u8) -> u8 {
+        (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 }
+    }
+
+    fn gmul(mut a: u8, mut b: u8) -> u8 {
+        let mut product = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                product ^= a;
+            }
+            a = xtime(a);
+            b >>= 1;
+        }
+        product
+    }
+
+    #[derive(Copy, Clone, PartialEq)]
+    enum Mode {
+        Ecb,
+        Cbc,
+        Ctr,
+    }
+
+    /// AES-128 in software, encryption only, which is all CCM needs. The
+    /// operations complete when the test calls `complete`.
+    struct SoftAes<'a> {
+        sbox: [u8; 256],
+        key: Cell<[u8; AES128_KEY_SIZE]>,
+        iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+        /// CBC chaining value or CTR counter of the current message
+        chain: Cell<[u8; AES128_BLOCK_SIZE]>,
+        mode: Cell<Mode>,
+        done: RefCell<Option<(Option<&'static mut [u8]>, &'static mut [u8])>>,
+        client: OptionalCell<&'a dyn Client<'a>>,
+    }
+
+    impl<'a> SoftAes<'a> {
+        fn new() -> SoftAes<'a> {
+            // The S-box is the affine transformation of the multiplicative
+            // inverse in GF(2^8)
+            let mut sbox = [0; 256];
+            for (x, s) in sbox.iter_mut().enumerate() {
+                let inv = (1..=255u8).find(|&y| gmul(x as u8, y) == 1).unwrap_or(0);
+                *s = inv
+                    ^ inv.rotate_left(1)
+                    ^ inv.rotate_left(2)
+                    ^ inv.rotate_left(3)
+                    ^ inv.rotate_left(4)
+                    ^ 0x63;
+            }
+            SoftAes {
+                sbox,
+                key: Cell::new([0; AES128_KEY_SIZE]),
+                iv: Cell::new([0; AES128_BLOCK_SIZE]),
+                chain: Cell::new([0; AES128_BLOCK_SIZE]),
+                mode: Cell::new(Mode::Ecb),
+                done: RefCell::new(None),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        fn round_keys(&self) -> [[u8; AES128_BLOCK_SIZE]; 11] {
+            let mut words = [[0u8; 4]; 44];
+            for (i, word) in self.key.get().chunks(4).enumerate() {
+                words[i].copy_from_slice(word);
+            }
+            let mut rcon = 1;
+            for i in 4..44 {
+                let mut temp = words[i - 1];
+                if i % 4 == 0 {
+                    temp.rotate_left(1);
+                    temp.iter_mut().for_each(|b| *b = self.sbox[*b as usize]);
+                    temp[0] ^= rcon;
+                    rcon = xtime(rcon);
+                }
+                for j in 0..4 {
+                    words[i][j] = words[i - 4][j] ^ temp[j];
+                }
+            }
+            let mut keys = [[0; AES128_BLOCK_SIZE]; 11];
+            for (round, key) in keys.iter_mut().enumerate() {
+                for (i, word) in words[4 * round..4 * round + 4].iter().enumerate() {
+                    key[4 * i..4 * i + 4].copy_from_slice(word);
+                }
+            }
+            keys
+        }
+
+        fn encrypt_block(&self, block: &mut [u8]) {
+            let keys = self.round_keys();
+            block.iter_mut().zip(keys[0]).for_each(|(b, k)| *b ^= k);
+            for (round, key) in keys.iter().enumerate().skip(1) {
+                let mut state = [0; AES128_BLOCK_SIZE];
+                // SubBytes and ShiftRows; the state is stored column by column
+                for c in 0..4 {
+                    for r in 0..4 {
+                        state[4 * c + r] = self.sbox[block[4 * ((c + r) % 4) + r] as usize];
+                    }
+                }
+                if round < 10 {
+                    for column in state.chunks_mut(4) {
+                        let a = [column[0], column[1], column[2], column[3]];
+                        for r in 0..4 {
+                            column[r] = gmul(a[r], 2)
+                                ^ gmul(a[(r + 1) % 4], 3)
+                                ^ a[(r + 2) % 4]
+                                ^ a[(r + 3) % 4];
+                        }
+                    }
+                }
+                block
+                    .iter_mut()
+                    .zip(state.iter().zip(key))
+                    .for_each(|(b, (s, k))| *b = s ^ k);
+            }
+        }
+
+        fn complete(&self) -> bool {
+            let done = self.done.borrow_mut().take();
+            match done {
+                Some((source, dest)) => {
+                    self.client
+                        .map(move |client| client.crypt_done(source, dest));
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl<'a> AES128<'a> for SoftAes<'a> {
+        fn enable(&self) {}
+
+        fn disable(&self) {}
+
+        fn set_client(&'a self, client: &'a dyn Client<'a>) {
+            self.client.set(client);
+        }
+
+        fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+            self.key.set(key.try_into().map_err(|_| ErrorCode::INVAL)?);
+            Ok(())
+        }
+
+        fn set_iv(&self, iv: &[u8]) -> Result<(), ErrorCode> {
+            self.iv.set(iv.try_into().map_err(|_| ErrorCode::INVAL)?);
+            Ok(())
+        }
+
+        fn start_message(&self) {
+            self.chain.set(self.iv.get());
+        }
+
+        fn crypt(
+            &self,
+            source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            start_index: usize,
+            stop_index: usize,
+        ) -> Option<(
+            Result<(), ErrorCode>,
+            Option<&'static mut [u8]>,
+            &'static mut [u8],
+        )> {
+            if self.done.borrow().is_some() {
+                return Some((Err(ErrorCode::BUSY), source, dest));
+            }
+            if start_index > stop_index
+                || stop_index > dest.len()
+                || (stop_index - start_index) % AES128_BLOCK_SIZE != 0
+            {
+                return Some((Err(ErrorCode::INVAL), source, dest));
+            }
+            if let Some(source) = source.as_ref() {
+                dest[start_index..stop_index].copy_from_slice(source);
+            }
+
+            let mut chain = self.chain.get();
+            for block in dest[start_index..stop_index].chunks_mut(AES128_BLOCK_SIZE) {
+                match self.mode.get() {
+                    Mode::Ecb => self.encrypt_block(block),
+                    Mode::Cbc => {
+                        block.iter_mut().zip(chain).for_each(|(b, c)| *b ^= c);
+                        self.encrypt_block(block);
+                        chain.copy_from_slice(block);
+                    }
+                    Mode::Ctr => {
+                        let mut stream = chain;
+                        self.encrypt_block(&mut stream);
+                        block.iter_mut().zip(stream).for_each(|(b, s)| *b ^= s);
+                        for byte in chain.iter_mut().rev() {
+                            *byte = byte.wrapping_add(1);
+                            if *byte != 0 {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            self.chain.set(chain);
+            *self.done.borrow_mut() = Some((source, dest));
+            None
+        }
+    }
+
+    impl AES128Ctr for SoftAes<'_> {
+        fn set_mode_aes128ctr(&self, _encrypting: bool) -> Result<(), ErrorCode> {
+            self.mode.set(Mode::Ctr);
+            Ok(())
+        }
+    }
+
+    impl AES128CBC for SoftAes<'_> {
+        fn set_mode_aes128cbc(&self, encrypting: bool) -> Result<(), ErrorCode> {
+            if !encrypting {
+                return Err(ErrorCode::NOSUPPORT);
+            }
+            self.mode.set(Mode::Cbc);
+            Ok(())
+        }
+    }
+
+    impl AES128ECB for SoftAes<'_> {
+        fn set_mode_aes128ecb(&self, encrypting: bool) -> Result<(), ErrorCode> {
+            if !encrypting {
+                return Err(ErrorCode::NOSUPPORT);
+            }
+            self.mode.set(Mode::Ecb);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        done: RefCell<Option<(&'static mut [u8], Result<(), ErrorCode>, bool)>>,
+    }
+
+    impl CCMClient for Recorder {
+        fn crypt_done(
+            &self,
+            buf: &'static mut [u8],
+            res: Result<(), ErrorCode>,
+            tag_is_valid: bool,
+        ) {
+            *self.done.borrow_mut() = Some((buf, res, tag_is_valid));
+        }
+    }
+
+    type Mux = MuxAES128CCM<'static, SoftAes<'static>>;
+
+    struct Harness {
+        aes: &'static SoftAes<'static>,
+        mux: &'static Mux,
+        handle: DeferredCallHandle,
+        ccm: &'static dyn AES128CCM<'static>,
+        recorder: &'static Recorder,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let states: &'static [DynamicDeferredCallClientState] =
+                Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+            let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+            let aes = Box::leak(Box::new(SoftAes::new()));
+            let mux: &'static Mux = Box::leak(Box::new(MuxAES128CCM::new(aes, ddc)));
+            aes.set_client(mux);
+            let handle = ddc.register(mux).unwrap();
+            mux.initialize_callback_handle(handle);
+            let ccm = Box::leak(Box::new(VirtualAES128CCM::new(
+                mux,
+                Box::leak(Box::new([0; 7 * AES128_BLOCK_SIZE])),
+            )));
+            ccm.setup();
+            let recorder = Box::leak(Box::new(Recorder::default()));
+            AES128CCM::set_client(ccm, recorder);
+            Harness {
+                aes,
+                mux,
+                handle,
+                ccm,
+                recorder,
+            }
+        }
+
+        /// Runs the queued CCM operation to completion.
+        fn run(
+            &self,
+            buf: Vec<u8>,
+            a_len: usize,
+            m_len: usize,
+            mic_len: usize,
+            encrypting: bool,
+        ) -> (Vec<u8>, bool) {
+            self.ccm
+                .crypt(buf.leak(), 0, a_len, m_len, mic_len, true, encrypting)
+                .map_err(|(e, _)| e)
+                .unwrap();
+            self.mux.call(self.handle);
+            while self.aes.complete() {}
+            let (buf, res, tag_is_valid) = self.recorder.done.borrow_mut().take().unwrap();
+            assert_eq!(res, Ok(()));
+            (buf.to_vec(), tag_is_valid)
+        }
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Encrypts `plaintext`, checks the ciphertext and tag against
+    /// `expected`, and checks that decryption restores the plaintext and
+    /// rejects a modified ciphertext.
+    fn check_vector(key: &[u8], nonce: &[u8], a_data: &[u8], plaintext: &[u8], expected: &str) {
+        let expected = from_hex(expected);
+        let mic_len = expected.len() - plaintext.len();
+        let harness = Harness::new();
+        harness.ccm.set_key(key).unwrap();
+        harness.ccm.set_nonce(nonce).unwrap();
+
+        let buf = [a_data, plaintext, &vec![0; mic_len]].concat();
+        let (buf, tag_is_valid) = harness.run(buf, a_data.len(), plaintext.len(), mic_len, true);
+        assert_eq!(buf[a_data.len()..], expected);
+        assert!(tag_is_valid);
+
+        let (mut buf, tag_is_valid) =
+            harness.run(buf, a_data.len(), plaintext.len(), mic_len, false);
+        assert_eq!(
+            buf[a_data.len()..a_data.len() + plaintext.len()],
+            *plaintext
+        );
+        assert!(tag_is_valid);
+
+        buf[a_data.len()..].copy_from_slice(&expected);
+        buf[a_data.len() + 1] ^= 1;
+        let (_, tag_is_valid) = harness.run(buf, a_data.len(), plaintext.len(), mic_len, false);
+        assert!(!tag_is_valid);
+    }
+
+    #[test]
+    fn ccm_13_byte_nonce_vector() {
+        // RFC 3610, packet vector #1
+        let plaintext: Vec<u8> = (0x08..0x1f).collect();
+        check_vector(
+            &from_hex("c0c1c2c3c4c5c6c7c8c9cacbcccdcecf"),
+            &from_hex("00000003020100a0a1a2a3a4a5"),
+            &[0, 1, 2, 3, 4, 5, 6, 7],
+            &plaintext,
+            "588c979a61c663d2f066d0c2c0f989806d5f6b61dac38417e8d12cfdf926e0",
+        );
+    }
+
+    #[test]
+    fn ccm_12_byte_nonce_vector() {
+        // NIST SP 800-38C, example 3. RFC 3610 only has vectors for 13 byte
+        // nonces, while (D)TLS uses 12 byte nonces.
+        let a_data: Vec<u8> = (0x00..0x14).collect();
+        let plaintext: Vec<u8> = (0x20..0x38).collect();
+        check_vector(
+            &from_hex("404142434445464748494a4b4c4d4e4f"),
+            &from_hex("101112131415161718191a1b"),
+            &a_data,
+            &plaintext,
+            "e3b201a9f5b71a7a9b1ceaeccd97e70b6176aad9a4428aa5484392fbc1b09951",
+        );
+    }
+
+    #[test]
+    fn ccm_rejects_nonce_lengths() {
+        let harness = Harness::new();
+        assert_eq!(
+            harness.ccm.set_nonce(&[0; CCM_MIN_NONCE_LENGTH - 1]),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            harness.ccm.set_nonce(&[0; CCM_NONCE_LENGTH + 1]),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(harness.ccm.set_nonce(&[0; CCM_MIN_NONCE_LENGTH]), Ok(()));
+    }
+}
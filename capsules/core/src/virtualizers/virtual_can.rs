@@ -0,0 +1,941 @@
+//! Virtualize a CAN controller so that several kernel capsules, and through
+//! them applications, can share it.
+//!
+//! Each `VirtualCanDevice` implements the CAN traits, so a capsule written
+//! for a CAN controller runs on a virtual device unchanged. The controller
+//! is enabled when the first device enables it and disabled when the last
+//! one disables it; its configuration is shared by all devices.
+//!
+//! A device receives the messages accepted by its acceptance filters, which
+//! are set through the `Filter` trait, or every message while it has none.
+//! When the controller has filter banks, the mux programs them with the
+//! filters of the receiving devices, so that the controller drops the other
+//! messages itself. If the filters do not fit in the banks, the banks
+//! accept everything and the filters apply in software only.
+//!
+//! Messages are sent one at a time. When the controller is free, the mux
+//! sends the pending message with the highest CAN priority: the lowest
+//! identifier, with a standard identifier ahead of the extended ones that
+//! share its 11 base bits, as in the arbitration on the bus.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let mux_can = static_init!(
+//!     MuxCan<'static, stm32f429zi::can::Can<'static>>,
+//!     MuxCan::new(&peripherals.can1, None, rx_buffer, dynamic_deferred_caller)
+//! );
+//! mux_can.initialize_callback_handle(dynamic_deferred_caller.register(mux_can).unwrap());
+//! kernel::hil::can::Controller::set_client(&peripherals.can1, Some(mux_can));
+//! kernel::hil::can::Transmit::set_client(&peripherals.can1, Some(mux_can));
+//! kernel::hil::can::Receive::set_client(&peripherals.can1, Some(mux_can));
+//!
+//! let can_device = static_init!(
+//!     VirtualCanDevice<'static, stm32f429zi::can::Can<'static>>,
+//!     VirtualCanDevice::new(mux_can)
+//! );
+//! can_device.setup();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::can;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// The number of acceptance filters of each virtual device.
+pub const FILTERS_PER_DEVICE: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Power {
+    Disabled,
+    Enabling,
+    Enabled,
+    Disabling,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Tx {
+    Idle,
+    Pending(can::Id, usize),
+    InFlight,
+    Failed,
+}
+
+/// The key messages are sent in order of: the 29 bits of an extended
+/// identifier, or the 11 bits of a standard one aligned with its base bits,
+/// and then the IDE bit, which is recessive for extended identifiers.
+fn priority(id: can::Id) -> (u32, bool) {
+    match id {
+        can::Id::Standard(id) => ((id as u32) << 18, false),
+        can::Id::Extended(id) => (id, true),
+    }
+}
+
+pub struct MuxCan<'a, C: can::Can> {
+    can: &'a C,
+    filter: Option<&'a dyn can::Filter>,
+    devices: List<'a, VirtualCanDevice<'a, C>>,
+    power: Cell<Power>,
+    inflight: OptionalCell<&'a VirtualCanDevice<'a, C>>,
+
+    // The buffer of the controller's receive process, which is out while
+    // the controller receives or is stopping
+    rx_buffer: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    receiving: Cell<bool>,
+    // How many filter banks of the controller may be enabled
+    banks: Cell<usize>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a, C: can::Can> MuxCan<'a, C> {
+    /// `filter` gives access to the filter banks of the controller, if it
+    /// has any.
+    pub fn new(
+        can: &'a C,
+        filter: Option<&'a dyn can::Filter>,
+        rx_buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> MuxCan<'a, C> {
+        MuxCan {
+            can,
+            filter,
+            devices: List::new(),
+            power: Cell::new(Power::Disabled),
+            inflight: OptionalCell::empty(),
+            rx_buffer: TakeCell::new(rx_buffer),
+            receiving: Cell::new(false),
+            // The banks are in an unknown state until they are programmed
+            banks: Cell::new(filter.map_or(0, |filter| filter.filter_count())),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn do_next_tx(&self) {
+        if self.inflight.is_some() || self.power.get() != Power::Enabled {
+            return;
+        }
+        let mut next: Option<(&'a VirtualCanDevice<'a, C>, can::Id, usize)> = None;
+        for device in self.devices.iter() {
+            if let Tx::Pending(id, len) = device.tx.get() {
+                if next.map_or(true, |(_, best, _)| priority(id) < priority(best)) {
+                    next = Some((device, id, len));
+                }
+            }
+        }
+        if let Some((device, id, len)) = next {
+            device
+                .tx_buffer
+                .take()
+                .map(|buffer| match self.can.send(id, buffer, len) {
+                    Ok(()) => {
+                        device.tx.set(Tx::InFlight);
+                        self.inflight.set(device);
+                    }
+                    Err((_, buffer)) => {
+                        device.tx_buffer.replace(buffer);
+                        device.tx.set(Tx::Failed);
+                        self.schedule();
+                    }
+                });
+        }
+    }
+
+    /// Starts the receive process of the controller if a device receives,
+    /// or stops it if none does, and programs the filter banks with the
+    /// filters of the receiving devices.
+    fn update_receive(&self) -> Result<(), ErrorCode> {
+        let wanted = self.devices.iter().any(|device| device.receiving.get());
+        if wanted && !self.receiving.get() {
+            // While the controller is stopping, it restarts when it hands
+            // the buffer back.
+            if let Some(buffer) = self.rx_buffer.take() {
+                if let Err((err, buffer)) = self.can.start_receive_process(buffer) {
+                    self.rx_buffer.replace(buffer);
+                    return Err(err);
+                }
+                self.receiving.set(true);
+                // Controllers may enable banks of their own to receive, as
+                // the stm32f4xx one does, which are disabled unless used
+                self.banks
+                    .set(self.filter.map_or(0, |filter| filter.filter_count()));
+            }
+        } else if !wanted && self.receiving.get() {
+            self.receiving.set(false);
+            let _ = self.can.stop_receive();
+        }
+        self.program_filter_banks();
+        Ok(())
+    }
+
+    fn program_filter_banks(&self) {
+        if let Some(filter) = self.filter {
+            let mut used = 0;
+            if self.receiving.get() {
+                let receiving = || self.devices.iter().filter(|device| device.receiving.get());
+                let count: usize = receiving().map(|device| device.active_filters()).sum();
+                let open = receiving().any(|device| device.active_filters() == 0);
+                if open || count > filter.filter_count() {
+                    let _ = filter.enable_filter(can::FilterParameters {
+                        number: 0,
+                        scale_bits: can::ScaleBits::Bits32,
+                        identifier_mode: can::IdentifierMode::Mask,
+                        fifo_number: 0,
+                        id: can::Id::Standard(0),
+                        mask: 0,
+                    });
+                    used = 1;
+                } else {
+                    for device in receiving() {
+                        for parameters in device.filters.iter().filter_map(|f| f.get()) {
+                            let _ = filter.enable_filter(can::FilterParameters {
+                                number: used as u32,
+                                fifo_number: 0,
+                                ..parameters
+                            });
+                            used += 1;
+                        }
+                    }
+                }
+            }
+            for number in used..self.banks.get() {
+                let _ = filter.disable_filter(number as u32);
+            }
+            self.banks.set(used);
+        }
+    }
+
+    fn others_powered(&self, device: &VirtualCanDevice<'a, C>) -> bool {
+        self.devices.iter().any(|other| {
+            !core::ptr::eq(other, device)
+                && matches!(other.power.get(), Power::Enabled | Power::Enabling)
+        })
+    }
+}
+
+impl<'a, C: can::Can> DynamicDeferredCallClient for MuxCan<'a, C> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        for device in self.devices.iter() {
+            match (device.power.get(), self.power.get()) {
+                (Power::Enabling, Power::Enabled) => {
+                    device.power.set(Power::Enabled);
+                    device.controller_client.map(|client| {
+                        client.state_changed(can::State::Running);
+                        client.enabled(Ok(()));
+                    });
+                }
+                (Power::Disabling, Power::Enabled) => {
+                    device.power.set(Power::Disabled);
+                    device.controller_client.map(|client| {
+                        client.state_changed(can::State::Disabled);
+                        client.disabled(Ok(()));
+                    });
+                }
+                _ => {}
+            }
+
+            if device.tx.get() == Tx::Failed {
+                device.tx.set(Tx::Idle);
+                device.tx_buffer.take().map(|buffer| {
+                    device.transmit_client.map(move |client| {
+                        client.transmit_complete(Err(can::Error::Transmission), buffer)
+                    })
+                });
+            }
+
+            if !device.receiving.get() {
+                device.rx_buffer.take().map(|buffer| {
+                    device
+                        .receive_client
+                        .map(move |client| client.stopped(buffer))
+                });
+            }
+        }
+    }
+}
+
+impl<'a, C: can::Can> can::ControllerClient for MuxCan<'a, C> {
+    fn state_changed(&self, state: can::State) {
+        for device in self.devices.iter() {
+            if device.power.get() != Power::Disabled {
+                device
+                    .controller_client
+                    .map(|client| client.state_changed(state));
+            }
+        }
+    }
+
+    fn enabled(&self, status: Result<(), ErrorCode>) {
+        let power = if status.is_ok() {
+            Power::Enabled
+        } else {
+            Power::Disabled
+        };
+        self.power.set(power);
+        for device in self.devices.iter() {
+            if device.power.get() == Power::Enabling {
+                device.power.set(power);
+                device
+                    .controller_client
+                    .map(|client| client.enabled(status));
+            }
+        }
+        let _ = self.update_receive();
+        self.do_next_tx();
+    }
+
+    fn disabled(&self, status: Result<(), ErrorCode>) {
+        let power = if status.is_ok() {
+            Power::Disabled
+        } else {
+            Power::Enabled
+        };
+        self.power.set(power);
+        for device in self.devices.iter() {
+            if device.power.get() == Power::Disabling {
+                device.power.set(power);
+                device
+                    .controller_client
+                    .map(|client| client.disabled(status));
+            }
+        }
+    }
+}
+
+impl<'a, C: can::Can> can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }> for MuxCan<'a, C> {
+    fn transmit_complete(
+        &self,
+        status: Result<(), can::Error>,
+        buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+    ) {
+        let device = self.inflight.take();
+        device.map(|device| device.tx.set(Tx::Idle));
+        // Start the next message before signaling, so that a client that
+        // sends again from the callback waits for its turn.
+        self.do_next_tx();
+        device.map(move |device| {
+            device
+                .transmit_client
+                .map(move |client| client.transmit_complete(status, buffer))
+        });
+    }
+}
+
+impl<'a, C: can::Can> can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }> for MuxCan<'a, C> {
+    fn message_received(
+        &self,
+        id: can::Id,
+        buffer: &mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+        len: usize,
+        status: Result<(), can::Error>,
+    ) {
+        for device in self.devices.iter() {
+            if device.receiving.get() && (status.is_err() || device.accepts(id)) {
+                device
+                    .receive_client
+                    .map(|client| client.message_received(id, buffer, len, status));
+            }
+        }
+    }
+
+    fn stopped(&self, buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE]) {
+        self.rx_buffer.replace(buffer);
+        self.receiving.set(false);
+        let _ = self.update_receive();
+    }
+}
+
+pub struct VirtualCanDevice<'a, C: can::Can> {
+    mux: &'a MuxCan<'a, C>,
+    next: ListLink<'a, VirtualCanDevice<'a, C>>,
+    power: Cell<Power>,
+    filters: [Cell<Option<can::FilterParameters>>; FILTERS_PER_DEVICE],
+    tx: Cell<Tx>,
+    tx_buffer: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    // The buffer of the client's receive process, kept until it is handed
+    // back by `stopped`
+    rx_buffer: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    receiving: Cell<bool>,
+    controller_client: OptionalCell<&'static dyn can::ControllerClient>,
+    transmit_client:
+        OptionalCell<&'static dyn can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+    receive_client:
+        OptionalCell<&'static dyn can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+}
+
+impl<'a, C: can::Can> VirtualCanDevice<'a, C> {
+    pub fn new(mux: &'a MuxCan<'a, C>) -> VirtualCanDevice<'a, C> {
+        VirtualCanDevice {
+            mux,
+            next: ListLink::empty(),
+            power: Cell::new(Power::Disabled),
+            filters: core::array::from_fn(|_| Cell::new(None)),
+            tx: Cell::new(Tx::Idle),
+            tx_buffer: TakeCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            receiving: Cell::new(false),
+            controller_client: OptionalCell::empty(),
+            transmit_client: OptionalCell::empty(),
+            receive_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn setup(&'a self) {
+        self.mux.devices.push_head(self);
+    }
+
+    fn active_filters(&self) -> usize {
+        self.filters.iter().filter(|f| f.get().is_some()).count()
+    }
+
+    fn accepts(&self, id: can::Id) -> bool {
+        self.active_filters() == 0
+            || self
+                .filters
+                .iter()
+                .filter_map(|f| f.get())
+                .any(|filter| filter.accepts(id))
+    }
+}
+
+impl<'a, C: can::Can> ListNode<'a, VirtualCanDevice<'a, C>> for VirtualCanDevice<'a, C> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualCanDevice<'a, C>> {
+        &self.next
+    }
+}
+
+impl<'a, C: can::Can> can::Configure for VirtualCanDevice<'a, C> {
+    const MIN_BIT_TIMINGS: can::BitTiming = C::MIN_BIT_TIMINGS;
+    const MAX_BIT_TIMINGS: can::BitTiming = C::MAX_BIT_TIMINGS;
+    const SYNC_SEG: u8 = C::SYNC_SEG;
+
+    fn set_bitrate(&self, bitrate: u32) -> Result<(), ErrorCode> {
+        self.mux.can.set_bitrate(bitrate)
+    }
+
+    fn set_bit_timing(&self, bit_timing: can::BitTiming) -> Result<(), ErrorCode> {
+        self.mux.can.set_bit_timing(bit_timing)
+    }
+
+    fn set_operation_mode(&self, mode: can::OperationMode) -> Result<(), ErrorCode> {
+        self.mux.can.set_operation_mode(mode)
+    }
+
+    fn get_bit_timing(&self) -> Result<can::BitTiming, ErrorCode> {
+        self.mux.can.get_bit_timing()
+    }
+
+    fn get_operation_mode(&self) -> Result<can::OperationMode, ErrorCode> {
+        self.mux.can.get_operation_mode()
+    }
+
+    fn set_automatic_retransmission(&self, automatic: bool) -> Result<(), ErrorCode> {
+        self.mux.can.set_automatic_retransmission(automatic)
+    }
+
+    fn set_wake_up(&self, wake_up: bool) -> Result<(), ErrorCode> {
+        self.mux.can.set_wake_up(wake_up)
+    }
+
+    fn get_automatic_retransmission(&self) -> Result<bool, ErrorCode> {
+        self.mux.can.get_automatic_retransmission()
+    }
+
+    fn get_wake_up(&self) -> Result<bool, ErrorCode> {
+        self.mux.can.get_wake_up()
+    }
+
+    fn receive_fifo_count(&self) -> usize {
+        self.mux.can.receive_fifo_count()
+    }
+}
+
+impl<'a, C: can::Can> can::Controller for VirtualCanDevice<'a, C> {
+    fn set_client(&self, client: Option<&'static dyn can::ControllerClient>) {
+        self.controller_client.insert(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        if self.power.get() != Power::Disabled {
+            return Err(ErrorCode::BUSY);
+        }
+        match self.mux.power.get() {
+            Power::Disabled => {
+                self.mux.can.enable()?;
+                self.mux.power.set(Power::Enabling);
+            }
+            Power::Enabling => {}
+            Power::Enabled => self.mux.schedule(),
+            Power::Disabling => return Err(ErrorCode::BUSY),
+        }
+        self.power.set(Power::Enabling);
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        if self.power.get() != Power::Enabled {
+            return Err(ErrorCode::OFF);
+        }
+        if self.mux.others_powered(self) {
+            self.mux.schedule();
+        } else {
+            self.mux.can.disable()?;
+            self.mux.power.set(Power::Disabling);
+        }
+        self.power.set(Power::Disabling);
+        Ok(())
+    }
+
+    fn get_state(&self) -> Result<can::State, ErrorCode> {
+        match self.power.get() {
+            Power::Enabled | Power::Disabling => self.mux.can.get_state(),
+            Power::Disabled | Power::Enabling => Ok(can::State::Disabled),
+        }
+    }
+}
+
+impl<'a, C: can::Can> can::Filter for VirtualCanDevice<'a, C> {
+    fn enable_filter(&self, filter: can::FilterParameters) -> Result<(), ErrorCode> {
+        let slot = self
+            .filters
+            .get(filter.number as usize)
+            .ok_or(ErrorCode::INVAL)?;
+        slot.set(Some(filter));
+        self.mux.program_filter_banks();
+        Ok(())
+    }
+
+    fn disable_filter(&self, number: u32) -> Result<(), ErrorCode> {
+        let slot = self.filters.get(number as usize).ok_or(ErrorCode::INVAL)?;
+        slot.set(None);
+        self.mux.program_filter_banks();
+        Ok(())
+    }
+
+    fn filter_count(&self) -> usize {
+        FILTERS_PER_DEVICE
+    }
+}
+
+impl<'a, C: can::Can> can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }> for VirtualCanDevice<'a, C> {
+    fn set_client(
+        &self,
+        client: Option<&'static dyn can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+    ) {
+        self.transmit_client.insert(client);
+    }
+
+    fn send(
+        &self,
+        id: can::Id,
+        buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE])> {
+        if self.power.get() != Power::Enabled {
+            Err((ErrorCode::OFF, buffer))
+        } else if self.tx.get() != Tx::Idle {
+            Err((ErrorCode::BUSY, buffer))
+        } else if len > can::STANDARD_CAN_PACKET_SIZE {
+            Err((ErrorCode::SIZE, buffer))
+        } else {
+            self.tx_buffer.replace(buffer);
+            self.tx.set(Tx::Pending(id, len));
+            self.mux.do_next_tx();
+            Ok(())
+        }
+    }
+}
+
+impl<'a, C: can::Can> can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }> for VirtualCanDevice<'a, C> {
+    fn set_client(
+        &self,
+        client: Option<&'static dyn can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }>>,
+    ) {
+        self.receive_client.insert(client);
+    }
+
+    fn start_receive_process(
+        &self,
+        buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE])> {
+        if self.power.get() != Power::Enabled {
+            return Err((ErrorCode::OFF, buffer));
+        } else if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        self.receiving.set(true);
+        if let Err(err) = self.mux.update_receive() {
+            self.receiving.set(false);
+            return Err((err, buffer));
+        }
+        self.rx_buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn stop_receive(&self) -> Result<(), ErrorCode> {
+        if !self.receiving.get() {
+            return Err(ErrorCode::OFF);
+        }
+        self.receiving.set(false);
+        let _ = self.mux.update_receive();
+        self.mux.schedule();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::can_loopback::CanLoopback;
+    use core::cell::RefCell;
+    use kernel::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use kernel::hil::can::{Configure, Controller, Filter, Receive, Transmit};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    type Device = VirtualCanDevice<'static, CanLoopback<'static>>;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Enabled(Result<(), ErrorCode>),
+        Disabled(Result<(), ErrorCode>),
+        Sent(Result<(), can::Error>),
+        Received(can::Id, u8),
+        Stopped,
+    }
+
+    struct Client {
+        events: RefCell<Vec<Event>>,
+        buffer: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    }
+
+    impl Client {
+        fn events(&self) -> Vec<Event> {
+            self.events.take()
+        }
+    }
+
+    impl can::ControllerClient for Client {
+        fn state_changed(&self, _state: can::State) {}
+
+        fn enabled(&self, status: Result<(), ErrorCode>) {
+            self.events.borrow_mut().push(Event::Enabled(status));
+        }
+
+        fn disabled(&self, status: Result<(), ErrorCode>) {
+            self.events.borrow_mut().push(Event::Disabled(status));
+        }
+    }
+
+    impl can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }> for Client {
+        fn transmit_complete(
+            &self,
+            status: Result<(), can::Error>,
+            buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+        ) {
+            self.buffer.replace(buffer);
+            self.events.borrow_mut().push(Event::Sent(status));
+        }
+    }
+
+    impl can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }> for Client {
+        fn message_received(
+            &self,
+            id: can::Id,
+            buffer: &mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+            _len: usize,
+            _status: Result<(), can::Error>,
+        ) {
+            self.events
+                .borrow_mut()
+                .push(Event::Received(id, buffer[0]));
+        }
+
+        fn stopped(&self, _buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE]) {
+            self.events.borrow_mut().push(Event::Stopped);
+        }
+    }
+
+    struct Bus {
+        loopback: &'static CanLoopback<'static>,
+        loopback_handle: DeferredCallHandle,
+        mux: &'static MuxCan<'static, CanLoopback<'static>>,
+        mux_handle: DeferredCallHandle,
+    }
+
+    impl Bus {
+        fn new() -> Bus {
+            let states: &'static [DynamicDeferredCallClientState] = Box::leak(Box::new([
+                DynamicDeferredCallClientState::default(),
+                DynamicDeferredCallClientState::default(),
+            ]));
+            let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+            let loopback = Box::leak(Box::new(CanLoopback::new(ddc)));
+            let loopback_handle = ddc.register(loopback).unwrap();
+            loopback.initialize_callback_handle(loopback_handle);
+            let mux = Box::leak(Box::new(MuxCan::new(
+                loopback,
+                Some(loopback),
+                Box::leak(Box::new([0; can::STANDARD_CAN_PACKET_SIZE])),
+                ddc,
+            )));
+            let mux_handle = ddc.register(mux).unwrap();
+            mux.initialize_callback_handle(mux_handle);
+            can::Controller::set_client(loopback, Some(mux));
+            can::Transmit::set_client(loopback, Some(mux));
+            can::Receive::set_client(loopback, Some(mux));
+            loopback.set_bitrate(500_000).unwrap();
+            Bus {
+                loopback,
+                loopback_handle,
+                mux,
+                mux_handle,
+            }
+        }
+
+        fn device(&self) -> (&'static Device, &'static Client) {
+            let device = Box::leak(Box::new(VirtualCanDevice::new(self.mux)));
+            device.setup();
+            let client = Box::leak(Box::new(Client {
+                events: RefCell::new(Vec::new()),
+                buffer: TakeCell::new(Box::leak(Box::new([0; can::STANDARD_CAN_PACKET_SIZE]))),
+            }));
+            can::Controller::set_client(device, Some(client));
+            can::Transmit::set_client(device, Some(client));
+            can::Receive::set_client(device, Some(client));
+            (device, client)
+        }
+
+        /// Enables the device and starts its receive process.
+        fn receiver(&self) -> (&'static Device, &'static Client) {
+            let (device, client) = self.device();
+            device.enable().unwrap();
+            self.run();
+            assert!(device
+                .start_receive_process(Box::leak(Box::new([0; can::STANDARD_CAN_PACKET_SIZE])))
+                .is_ok());
+            assert_eq!(client.events(), vec![Event::Enabled(Ok(()))]);
+            (device, client)
+        }
+
+        /// Runs the deferred calls until the bus is idle.
+        fn run(&self) {
+            for _ in 0..16 {
+                self.loopback.call(self.loopback_handle);
+                self.mux.call(self.mux_handle);
+            }
+        }
+    }
+
+    fn send(device: &Device, client: &Client, id: can::Id, byte: u8) {
+        let buffer = client.buffer.take().unwrap();
+        buffer[0] = byte;
+        assert!(device.send(id, buffer, 1).is_ok());
+    }
+
+    fn filter(number: u32, id: can::Id, mask: u32) -> can::FilterParameters {
+        can::FilterParameters {
+            number,
+            scale_bits: can::ScaleBits::Bits32,
+            identifier_mode: can::IdentifierMode::Mask,
+            fifo_number: 0,
+            id,
+            mask,
+        }
+    }
+
+    #[test]
+    fn controller_is_shared() {
+        let bus = Bus::new();
+        let (a, a_client) = bus.device();
+        let (b, b_client) = bus.device();
+
+        let buffer = a_client.buffer.take().unwrap();
+        assert!(matches!(
+            a.send(can::Id::Standard(1), buffer, 1),
+            Err((ErrorCode::OFF, _))
+        ));
+
+        // Both devices wait for the controller to be enabled.
+        a.enable().unwrap();
+        b.enable().unwrap();
+        bus.run();
+        assert_eq!(a_client.events(), vec![Event::Enabled(Ok(()))]);
+        assert_eq!(b_client.events(), vec![Event::Enabled(Ok(()))]);
+        assert_eq!(a.enable(), Err(ErrorCode::BUSY));
+
+        // The controller stays enabled until the last device disables it.
+        a.disable().unwrap();
+        bus.run();
+        assert_eq!(a_client.events(), vec![Event::Disabled(Ok(()))]);
+        assert_eq!(bus.loopback.get_state(), Ok(can::State::Running));
+        assert_eq!(a.get_state(), Ok(can::State::Disabled));
+
+        a.enable().unwrap();
+        bus.run();
+        assert_eq!(a_client.events(), vec![Event::Enabled(Ok(()))]);
+
+        a.disable().unwrap();
+        b.disable().unwrap();
+        bus.run();
+        assert_eq!(a_client.events(), vec![Event::Disabled(Ok(()))]);
+        assert_eq!(b_client.events(), vec![Event::Disabled(Ok(()))]);
+        assert_eq!(bus.loopback.get_state(), Ok(can::State::Disabled));
+    }
+
+    #[test]
+    fn filters_select_messages() {
+        let bus = Bus::new();
+        let (a, a_client) = bus.receiver();
+        let (b, b_client) = bus.receiver();
+        let (_, c_client) = bus.receiver();
+        a.enable_filter(filter(0, can::Id::Standard(0x100), 0x700))
+            .unwrap();
+        b.enable_filter(can::FilterParameters {
+            identifier_mode: can::IdentifierMode::List,
+            ..filter(0, can::Id::Extended(0x18da_00f1), 0)
+        })
+        .unwrap();
+        // The device without filters keeps the controller open.
+        assert_eq!(bus.loopback.enabled_filters(), 1);
+
+        send(a, a_client, can::Id::Standard(0x123), 1);
+        bus.run();
+        send(a, a_client, can::Id::Extended(0x18da_00f1), 2);
+        bus.run();
+        send(a, a_client, can::Id::Standard(0x300), 3);
+        bus.run();
+
+        assert_eq!(
+            a_client.events(),
+            vec![
+                Event::Received(can::Id::Standard(0x123), 1),
+                Event::Sent(Ok(())),
+                Event::Sent(Ok(())),
+                Event::Sent(Ok(())),
+            ]
+        );
+        assert_eq!(
+            b_client.events(),
+            vec![Event::Received(can::Id::Extended(0x18da_00f1), 2)]
+        );
+        assert_eq!(
+            c_client.events(),
+            vec![
+                Event::Received(can::Id::Standard(0x123), 1),
+                Event::Received(can::Id::Extended(0x18da_00f1), 2),
+                Event::Received(can::Id::Standard(0x300), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_banks_follow_receivers() {
+        let bus = Bus::new();
+        let (a, a_client) = bus.receiver();
+        let (b, b_client) = bus.receiver();
+        a.enable_filter(filter(0, can::Id::Standard(0x100), 0x7ff))
+            .unwrap();
+        b.enable_filter(filter(0, can::Id::Standard(0x200), 0x7ff))
+            .unwrap();
+        assert_eq!(bus.loopback.enabled_filters(), 2);
+
+        // More filters than banks: the controller accepts everything, and
+        // the devices still only see their messages.
+        for number in 1..4 {
+            a.enable_filter(filter(number, can::Id::Extended(number), 0x1fff_ffff))
+                .unwrap();
+        }
+        assert_eq!(bus.loopback.enabled_filters(), 1);
+        send(a, a_client, can::Id::Standard(0x300), 1);
+        bus.run();
+        send(a, a_client, can::Id::Standard(0x200), 2);
+        bus.run();
+        assert_eq!(
+            a_client.events(),
+            vec![Event::Sent(Ok(())), Event::Sent(Ok(()))]
+        );
+        assert_eq!(
+            b_client.events(),
+            vec![Event::Received(can::Id::Standard(0x200), 2)]
+        );
+
+        // Only the filters of the receiving devices use banks.
+        a.stop_receive().unwrap();
+        bus.run();
+        assert_eq!(a_client.events(), vec![Event::Stopped]);
+        assert_eq!(bus.loopback.enabled_filters(), 1);
+        assert_eq!(a.stop_receive(), Err(ErrorCode::OFF));
+        b.disable_filter(0).unwrap();
+        b.stop_receive().unwrap();
+        bus.run();
+        assert_eq!(b_client.events(), vec![Event::Stopped]);
+        assert_eq!(bus.loopback.enabled_filters(), 0);
+    }
+
+    #[test]
+    fn controller_banks_are_replaced() {
+        let bus = Bus::new();
+        // Some controllers enable accept-all banks of their own when they
+        // start receiving
+        for number in 0..2 {
+            bus.loopback
+                .enable_filter(filter(number, can::Id::Standard(0), 0))
+                .unwrap();
+        }
+        let (a, a_client) = bus.receiver();
+        a.enable_filter(filter(0, can::Id::Standard(0x100), 0x7ff))
+            .unwrap();
+        assert_eq!(bus.loopback.enabled_filters(), 1);
+        assert_eq!(a_client.events(), vec![]);
+    }
+
+    #[test]
+    fn messages_are_sent_by_priority() {
+        let bus = Bus::new();
+        let (_, listener) = bus.receiver();
+        let (a, a_client) = bus.receiver();
+        let (b, b_client) = bus.receiver();
+        let (c, c_client) = bus.receiver();
+        let (d, d_client) = bus.receiver();
+
+        // The first message goes out at once, and the others wait for it.
+        send(a, a_client, can::Id::Standard(0x7ff), 1);
+        send(b, b_client, can::Id::Extended(0x100 << 18), 2);
+        send(c, c_client, can::Id::Standard(0x200), 3);
+        send(d, d_client, can::Id::Standard(0x100), 4);
+        assert!(matches!(
+            d.send(can::Id::Standard(0), Box::leak(Box::new([0; 8])), 1),
+            Err((ErrorCode::BUSY, _))
+        ));
+        bus.run();
+
+        let order: Vec<u8> = listener
+            .events()
+            .into_iter()
+            .map(|event| match event {
+                Event::Received(_, byte) => byte,
+                _ => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(order, vec![1, 4, 2, 3]);
+        for client in [a_client, b_client, c_client, d_client] {
+            assert!(client.events().contains(&Event::Sent(Ok(()))));
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Definitions of the Attribute Protocol (Bluetooth Core Specification 5.3,
+//! Vol 3, Part F), used by the GATT server.
+//!
+//! ATT PDUs are carried on L2CAP channel `0x0004`. Each starts with an
+//! opcode, followed by parameters in little endian. The client sends one
+//! request at a time and waits for its response, or for an error response;
+//! commands and notifications have no response.
+
+/// Default ATT_MTU on LE, in force until the client exchanges another one.
+pub const DEFAULT_MTU: usize = 23;
+
+/// Largest ATT_MTU the server supports.
+pub const MAX_MTU: usize = 128;
+
+/// Opcodes.
+pub mod opcode {
+    pub const ERROR_RSP: u8 = 0x01;
+    pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+    pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+    pub const FIND_INFORMATION_REQ: u8 = 0x04;
+    pub const FIND_INFORMATION_RSP: u8 = 0x05;
+    pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+    pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+    pub const READ_BY_TYPE_REQ: u8 = 0x08;
+    pub const READ_BY_TYPE_RSP: u8 = 0x09;
+    pub const READ_REQ: u8 = 0x0a;
+    pub const READ_RSP: u8 = 0x0b;
+    pub const READ_BLOB_REQ: u8 = 0x0c;
+    pub const READ_BLOB_RSP: u8 = 0x0d;
+    pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+    pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+    pub const WRITE_REQ: u8 = 0x12;
+    pub const WRITE_RSP: u8 = 0x13;
+    pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+    pub const WRITE_CMD: u8 = 0x52;
+
+    /// Bit set in the opcode of commands, which have no response.
+    pub const COMMAND_FLAG: u8 = 0x40;
+}
+
+/// Error codes of error responses.
+pub mod error {
+    pub const INVALID_HANDLE: u8 = 0x01;
+    pub const READ_NOT_PERMITTED: u8 = 0x02;
+    pub const WRITE_NOT_PERMITTED: u8 = 0x03;
+    pub const INVALID_PDU: u8 = 0x04;
+    pub const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+    pub const INVALID_OFFSET: u8 = 0x07;
+    pub const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+    pub const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+    pub const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+}
+
+/// Attribute types defined by GATT.
+pub mod types {
+    pub const PRIMARY_SERVICE: u16 = 0x2800;
+    pub const SECONDARY_SERVICE: u16 = 0x2801;
+    pub const CHARACTERISTIC: u16 = 0x2803;
+    pub const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+}
+
+/// Base UUID, from which 16-bit UUIDs are shortened.
+const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// A UUID, identifying the type of an attribute. 128-bit UUIDs are stored
+/// in little endian, as they are sent.
+#[derive(Copy, Clone, Debug)]
+pub enum Uuid {
+    Uuid16(u16),
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Decodes a UUID of 2 or 16 bytes.
+    pub fn decode(buf: &[u8]) -> Option<Uuid> {
+        match buf.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([buf[0], buf[1]]))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(buf);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    /// Length of the encoded UUID.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    /// Writes the UUID into `buf`, which must be long enough, and returns
+    /// its length.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        match self {
+            Uuid::Uuid16(uuid) => buf[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(uuid) => buf[..16].copy_from_slice(uuid),
+        }
+        self.encoded_len()
+    }
+
+    /// The UUID on 128 bits.
+    fn expand(&self) -> [u8; 16] {
+        match self {
+            Uuid::Uuid16(uuid) => {
+                let mut expanded = BASE_UUID;
+                expanded[12..14].copy_from_slice(&uuid.to_le_bytes());
+                expanded
+            }
+            Uuid::Uuid128(uuid) => *uuid,
+        }
+    }
+}
+
+impl PartialEq for Uuid {
+    /// UUIDs are equal if they are the same once expanded to 128 bits.
+    fn eq(&self, other: &Uuid) -> bool {
+        self.expand() == other.expand()
+    }
+}
+
+impl PartialEq<u16> for Uuid {
+    fn eq(&self, other: &u16) -> bool {
+        *self == Uuid::Uuid16(*other)
+    }
+}
+
+/// Reads the little endian `u16` at `offset` of `buf`.
+pub fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Writes `value` in little endian at `offset` of `buf`.
+pub fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Writes an error response to `request` about `handle` into `buf`, and
+/// returns its length.
+pub fn error_response(buf: &mut [u8], request: u8, handle: u16, error: u8) -> usize {
+    buf[0] = opcode::ERROR_RSP;
+    buf[1] = request;
+    put_u16(buf, 2, handle);
+    buf[4] = error;
+    5
+}
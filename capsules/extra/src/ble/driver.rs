@@ -0,0 +1,369 @@
+//! Provides userspace with a BLE peripheral: applications add services and
+//! characteristics to the GATT server, advertise, and exchange the values of
+//! their characteristics with the connected central.
+//!
+//! Services are shared by all applications, and a characteristic belongs to
+//! the service added last, so an application adds each of its services
+//! followed by all of its characteristics. A characteristic belongs to the
+//! application that added it: only that application sets its value and
+//! notifies it, and learns when the central writes it. Writes are copied
+//! into its read-write buffer.
+//!
+//! Setup
+//! -----
+//!
+//! You need a radio that provides `hil::ble_advertising::BleAdvertisementDriver`
+//! and `hil::ble_advertising::BleConnectionConfig`, and an alarm.
+//!
+//! ```rust
+//! let ble_gatt = components::ble_gatt::BleGattComponent::new(
+//!     board_kernel,
+//!     capsules_extra::ble::driver::DRIVER_NUM,
+//!     &nrf52840_peripherals.ble_radio,
+//!     mux_alarm,
+//!     [0x11, 0x22, 0x33, 0x44, 0x55, 0xc6],
+//!     b"Tock",
+//! )
+//! .finalize(components::ble_gatt_component_static!(
+//!     nrf52840::ble_radio::Radio,
+//!     nrf52840::rtc::Rtc
+//! ));
+//! ```
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::ble_advertising::{BleAdvertisementDriver, BleConnectionConfig};
+use kernel::hil::time::Alarm;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::{ErrorCode, ProcessId};
+
+use super::att::Uuid;
+use super::gatt::{GattClient, GattServer, MAX_VALUE_LEN};
+use super::link_layer::{LinkLayer, MAX_ADVERTISING_DATA_LEN};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// The UUID of the service or characteristic to add, of 2 or 16 bytes
+    pub const UUID: usize = 0;
+    /// The value to set
+    pub const VALUE: usize = 1;
+    /// The advertising data
+    pub const ADVERTISING_DATA: usize = 2;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 3;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Receives the values written by the central
+    pub const WRITTEN: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A central connected or disconnected
+    pub const CONNECTION: usize = 0;
+    /// The central wrote a characteristic
+    pub const WRITTEN: usize = 1;
+    /// A notification was sent
+    pub const NOTIFIED: usize = 2;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 3;
+}
+
+/// Shortest advertising interval, in milliseconds.
+const MIN_ADVERTISING_INTERVAL_MS: usize = 20;
+
+/// Handles of characteristic values are recorded in a bitmask, so the
+/// database has at most this many attributes.
+pub const MAX_ATTRIBUTES: usize = 63;
+
+#[derive(Default)]
+pub struct App {
+    /// Bitmask of the handles of the characteristic values of the
+    /// application.
+    handles: u64,
+}
+
+impl App {
+    fn owns(&self, handle: usize) -> bool {
+        handle < 64 && self.handles & (1 << handle) != 0
+    }
+}
+
+pub struct BleGattDriver<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    gatt: &'a GattServer<'a>,
+    link_layer: &'a LinkLayer<'a, R, A>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+}
+
+impl<'a, R, A> BleGattDriver<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    pub fn new(
+        gatt: &'a GattServer<'a>,
+        link_layer: &'a LinkLayer<'a, R, A>,
+        grant: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> BleGattDriver<'a, R, A> {
+        BleGattDriver {
+            gatt: gatt,
+            link_layer: link_layer,
+            apps: grant,
+        }
+    }
+
+    /// Adds a service, or a characteristic with `properties`, whose UUID is
+    /// in the UUID buffer of the application.
+    fn add(&self, processid: ProcessId, characteristic: Option<u8>) -> CommandReturn {
+        self.apps
+            .enter(processid, |app, kernel_data| {
+                let mut buf = [0; 16];
+                let uuid = kernel_data
+                    .get_readonly_processbuffer(ro_allow::UUID)
+                    .and_then(|uuid| {
+                        uuid.enter(|uuid| {
+                            let len = uuid.len();
+                            if len > buf.len() {
+                                return None;
+                            }
+                            uuid.copy_to_slice(&mut buf[..len]);
+                            Uuid::decode(&buf[..len])
+                        })
+                    })
+                    .unwrap_or(None);
+                let uuid = match uuid {
+                    Some(uuid) => uuid,
+                    None => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                let result = match characteristic {
+                    None => self.gatt.add_service(uuid),
+                    Some(properties) => self.gatt.add_characteristic(uuid, properties),
+                };
+                match result {
+                    Ok(handle) => {
+                        if characteristic.is_some() {
+                            if handle as usize > MAX_ATTRIBUTES {
+                                return CommandReturn::failure(ErrorCode::NOMEM);
+                            }
+                            app.handles |= 1 << handle;
+                        }
+                        CommandReturn::success_u32(handle as u32)
+                    }
+                    Err(err) => CommandReturn::failure(err),
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Sets the value of the characteristic at `handle` to the first `len`
+    /// bytes of the value buffer of the application.
+    fn set_value(&self, processid: ProcessId, handle: usize, len: usize) -> CommandReturn {
+        self.apps
+            .enter(processid, |app, kernel_data| {
+                if !app.owns(handle) {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                if len > MAX_VALUE_LEN {
+                    return CommandReturn::failure(ErrorCode::SIZE);
+                }
+                let mut value = [0; MAX_VALUE_LEN];
+                let copied = kernel_data
+                    .get_readonly_processbuffer(ro_allow::VALUE)
+                    .and_then(|buffer| {
+                        buffer.enter(|buffer| {
+                            if buffer.len() < len {
+                                return false;
+                            }
+                            buffer[..len].copy_to_slice(&mut value[..len]);
+                            true
+                        })
+                    })
+                    .unwrap_or(false);
+                if !copied {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.gatt.set_value(handle as u16, &value[..len]).into()
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn notify(&self, processid: ProcessId, handle: usize) -> CommandReturn {
+        self.apps
+            .enter(processid, |app, _| {
+                if !app.owns(handle) {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.gatt.notify(handle as u16).into()
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn start_advertising(&self, processid: ProcessId, interval_ms: usize) -> CommandReturn {
+        if interval_ms < MIN_ADVERTISING_INTERVAL_MS || interval_ms > u16::MAX as usize {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        }
+        self.apps
+            .enter(processid, |_, kernel_data| {
+                let mut data = [0; MAX_ADVERTISING_DATA_LEN];
+                let len = kernel_data
+                    .get_readonly_processbuffer(ro_allow::ADVERTISING_DATA)
+                    .and_then(|buffer| {
+                        buffer.enter(|buffer| {
+                            if buffer.len() > MAX_ADVERTISING_DATA_LEN {
+                                return None;
+                            }
+                            buffer.copy_to_slice(&mut data[..buffer.len()]);
+                            Some(buffer.len())
+                        })
+                    })
+                    .unwrap_or(Some(0));
+                match len {
+                    Some(len) => self
+                        .link_layer
+                        .start_advertising(&data[..len], interval_ms as u32)
+                        .into(),
+                    None => CommandReturn::failure(ErrorCode::SIZE),
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Schedules `upcall_num` for the application owning the characteristic
+    /// at `handle`.
+    fn schedule_owner(&self, handle: u16, upcall_num: usize, value: &[u8]) {
+        self.apps.each(|_, app, kernel_data| {
+            if !app.owns(handle as usize) {
+                return;
+            }
+            let len = kernel_data
+                .get_readwrite_processbuffer(rw_allow::WRITTEN)
+                .and_then(|buffer| {
+                    buffer.mut_enter(|buffer| {
+                        let len = core::cmp::min(value.len(), buffer.len());
+                        buffer[..len].copy_from_slice(&value[..len]);
+                        len
+                    })
+                })
+                .unwrap_or(0);
+            kernel_data
+                .schedule_upcall(upcall_num, (handle as usize, len, 0))
+                .ok();
+        });
+    }
+}
+
+impl<'a, R, A> GattClient for BleGattDriver<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    fn connected(&self) {
+        self.apps.each(|_, _, kernel_data| {
+            kernel_data
+                .schedule_upcall(upcall::CONNECTION, (1, 0, 0))
+                .ok();
+        });
+    }
+
+    fn disconnected(&self) {
+        self.apps.each(|_, _, kernel_data| {
+            kernel_data
+                .schedule_upcall(upcall::CONNECTION, (0, 0, 0))
+                .ok();
+        });
+    }
+
+    fn written(&self, handle: u16, value: &[u8]) {
+        self.schedule_owner(handle, upcall::WRITTEN, value);
+    }
+
+    fn notified(&self, handle: u16) {
+        self.schedule_owner(handle, upcall::NOTIFIED, &[]);
+    }
+}
+
+impl<'a, R, A> SyscallDriver for BleGattDriver<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    /// Control the GATT server and advertising.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver existence check.
+    /// - `1`: Add a primary service, whose UUID is in the UUID buffer.
+    ///        Returns the handle of its declaration.
+    /// - `2`: Add a characteristic to the last service added, whose UUID is
+    ///        in the UUID buffer, with the properties `data1`. Returns the
+    ///        handle of its value.
+    /// - `3`: Set the value of the characteristic at handle `data1` to the
+    ///        first `data2` bytes of the value buffer.
+    /// - `4`: Notify the central of the value of the characteristic at handle
+    ///        `data1`.
+    /// - `5`: Start advertising the data of the advertising buffer every
+    ///        `data1` milliseconds, at least 20.
+    /// - `6`: Stop advertising.
+    /// - `7`: Close the connection.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => self.add(processid, None),
+
+            2 => {
+                if data1 > u8::MAX as usize {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.add(processid, Some(data1 as u8))
+            }
+
+            3 => self.set_value(processid, data1, data2),
+
+            4 => self.notify(processid, data1),
+
+            5 => self.start_advertising(processid, data1),
+
+            6 => {
+                self.link_layer.stop_advertising();
+                CommandReturn::success()
+            }
+
+            7 => self.link_layer.disconnect().into(),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
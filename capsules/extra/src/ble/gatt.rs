@@ -0,0 +1,613 @@
+//! GATT server (Bluetooth Core Specification 5.3, Vol 3, Part G), answering
+//! the attribute protocol requests of the connected client from a database
+//! of primary services and characteristics.
+//!
+//! The database is built at run time: each service is added with
+//! `add_service`, followed by its characteristics, added with
+//! `add_characteristic`. A service takes one attribute, its declaration, and
+//! a characteristic two, its declaration and its value, plus a Client
+//! Characteristic Configuration descriptor if it supports notifications.
+//! Handles are given in order, starting at 1, and services cannot be
+//! removed.
+//!
+//! Characteristic values are stored by the server, up to `MAX_VALUE_LEN`
+//! bytes each. The client reads them (with Read Blob for the parts past its
+//! MTU) and writes them if the properties of the characteristic allow it;
+//! the `GattClient` learns of each write. Values are notified to the client
+//! if it enabled notifications for them. Indications, long writes, and
+//! security requirements are not supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let gatt = static_init!(GattServer<'static>, GattServer::new(attributes, values, buffer));
+//! l2cap.set_att_client(gatt);
+//! gatt.add_service(Uuid::Uuid16(0x180f))?;
+//! let level = gatt.add_characteristic(Uuid::Uuid16(0x2a19), properties::READ | properties::NOTIFY)?;
+//! gatt.set_value(level, &[100])?;
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::att::{self, error, opcode, types, Uuid};
+use super::l2cap::ChannelClient;
+
+/// Longest value of a characteristic.
+pub const MAX_VALUE_LEN: usize = 32;
+
+/// Properties of a characteristic.
+pub mod properties {
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+
+    /// The properties the server supports.
+    pub const SUPPORTED: u8 = READ | WRITE_WITHOUT_RESPONSE | WRITE | NOTIFY;
+}
+
+/// Bit of the Client Characteristic Configuration enabling notifications.
+const NOTIFICATIONS_ENABLED: u16 = 0x0001;
+
+/// An attribute of the database.
+#[derive(Copy, Clone)]
+pub enum Attribute {
+    PrimaryService(Uuid),
+    /// A characteristic declaration, whose value attribute follows.
+    Characteristic {
+        properties: u8,
+        uuid: Uuid,
+    },
+    /// The value of a characteristic, stored in `slot` of the values.
+    Value {
+        uuid: Uuid,
+        slot: usize,
+    },
+    /// The Client Characteristic Configuration of the value stored in
+    /// `slot`.
+    ClientConfiguration {
+        slot: usize,
+    },
+}
+
+impl Attribute {
+    fn uuid(&self) -> Uuid {
+        match *self {
+            Attribute::PrimaryService(_) => Uuid::Uuid16(types::PRIMARY_SERVICE),
+            Attribute::Characteristic { .. } => Uuid::Uuid16(types::CHARACTERISTIC),
+            Attribute::Value { uuid, .. } => uuid,
+            Attribute::ClientConfiguration { .. } => {
+                Uuid::Uuid16(types::CLIENT_CHARACTERISTIC_CONFIGURATION)
+            }
+        }
+    }
+}
+
+/// Storage of a characteristic value.
+#[derive(Default)]
+pub struct Value {
+    data: [Cell<u8>; MAX_VALUE_LEN],
+    len: Cell<usize>,
+    properties: Cell<u8>,
+    /// The Client Characteristic Configuration of the connected client.
+    configuration: Cell<u16>,
+}
+
+/// Receives the events of the GATT server.
+pub trait GattClient {
+    /// A client connected.
+    fn connected(&self);
+
+    /// The client disconnected.
+    fn disconnected(&self);
+
+    /// The client wrote `value` into the characteristic value at `handle`.
+    fn written(&self, handle: u16, value: &[u8]);
+
+    /// The notification of the value at `handle` was handed to the link
+    /// layer.
+    fn notified(&self, handle: u16);
+}
+
+pub struct GattServer<'a> {
+    attributes: &'a [OptionalCell<Attribute>],
+    /// Number of attributes in the database.
+    count: Cell<usize>,
+    values: &'a [Value],
+    /// Number of values in use.
+    value_count: Cell<usize>,
+
+    client: OptionalCell<&'a dyn GattClient>,
+
+    connected: Cell<bool>,
+    mtu: Cell<usize>,
+    /// The response to the last request, waiting to be sent.
+    response: TakeCell<'static, [u8]>,
+    response_len: Cell<usize>,
+    /// The handle of the value to notify.
+    notification: OptionalCell<u16>,
+}
+
+impl<'a> GattServer<'a> {
+    /// `response_buffer` must hold `att::MAX_MTU` bytes.
+    pub fn new(
+        attributes: &'a [OptionalCell<Attribute>],
+        values: &'a [Value],
+        response_buffer: &'static mut [u8],
+    ) -> GattServer<'a> {
+        GattServer {
+            attributes: attributes,
+            count: Cell::new(0),
+            values: values,
+            value_count: Cell::new(0),
+            client: OptionalCell::empty(),
+            connected: Cell::new(false),
+            mtu: Cell::new(att::DEFAULT_MTU),
+            response: TakeCell::new(response_buffer),
+            response_len: Cell::new(0),
+            notification: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn GattClient) {
+        self.client.set(client);
+    }
+
+    fn push(&self, attribute: Attribute) -> u16 {
+        let count = self.count.get();
+        self.attributes[count].set(attribute);
+        self.count.set(count + 1);
+        count as u16 + 1
+    }
+
+    /// Adds a primary service, and returns the handle of its declaration.
+    pub fn add_service(&self, uuid: Uuid) -> Result<u16, ErrorCode> {
+        if self.count.get() >= self.attributes.len() {
+            return Err(ErrorCode::NOMEM);
+        }
+        Ok(self.push(Attribute::PrimaryService(uuid)))
+    }
+
+    /// Adds a characteristic to the last service added, and returns the
+    /// handle of its value.
+    pub fn add_characteristic(&self, uuid: Uuid, properties: u8) -> Result<u16, ErrorCode> {
+        if self.count.get() == 0 || properties & !properties::SUPPORTED != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let notify = properties & properties::NOTIFY != 0;
+        let needed = if notify { 3 } else { 2 };
+        let slot = self.value_count.get();
+        if self.count.get() + needed > self.attributes.len() || slot >= self.values.len() {
+            return Err(ErrorCode::NOMEM);
+        }
+
+        let value = &self.values[slot];
+        value.len.set(0);
+        value.properties.set(properties);
+        value.configuration.set(0);
+        self.value_count.set(slot + 1);
+
+        self.push(Attribute::Characteristic { properties, uuid });
+        let handle = self.push(Attribute::Value { uuid, slot });
+        if notify {
+            self.push(Attribute::ClientConfiguration { slot });
+        }
+        Ok(handle)
+    }
+
+    fn attribute(&self, handle: u16) -> Option<Attribute> {
+        let index = (handle as usize).checked_sub(1)?;
+        if index < self.count.get() {
+            self.attributes[index].extract()
+        } else {
+            None
+        }
+    }
+
+    /// The storage of the characteristic value at `handle`.
+    fn value(&self, handle: u16) -> Option<&Value> {
+        match self.attribute(handle)? {
+            Attribute::Value { slot, .. } => Some(&self.values[slot]),
+            _ => None,
+        }
+    }
+
+    /// Sets the characteristic value at `handle`.
+    pub fn set_value(&self, handle: u16, data: &[u8]) -> Result<(), ErrorCode> {
+        let value = self.value(handle).ok_or(ErrorCode::INVAL)?;
+        if data.len() > MAX_VALUE_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        for (cell, byte) in value.data.iter().zip(data.iter()) {
+            cell.set(*byte);
+        }
+        value.len.set(data.len());
+        Ok(())
+    }
+
+    /// Copies the characteristic value at `handle` into `buf`, and returns
+    /// its length.
+    pub fn get_value(&self, handle: u16, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+        let value = self.value(handle).ok_or(ErrorCode::INVAL)?;
+        let len = cmp::min(value.len.get(), buf.len());
+        for (byte, cell) in buf[..len].iter_mut().zip(value.data.iter()) {
+            *byte = cell.get();
+        }
+        Ok(len)
+    }
+
+    /// Notifies the client of the characteristic value at `handle`. The
+    /// client is called back once the notification is handed to the link
+    /// layer.
+    ///
+    /// Returns OFF if no client is connected or the client did not enable
+    /// notifications, INVAL if `handle` is not a value that supports them,
+    /// and BUSY if a notification is already pending.
+    pub fn notify(&self, handle: u16) -> Result<(), ErrorCode> {
+        let value = self.value(handle).ok_or(ErrorCode::INVAL)?;
+        if value.properties.get() & properties::NOTIFY == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        if !self.connected.get() || value.configuration.get() & NOTIFICATIONS_ENABLED == 0 {
+            return Err(ErrorCode::OFF);
+        }
+        if self.notification.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.notification.set(handle);
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.get()
+    }
+
+    /// Writes the value of the attribute at `handle` into `buf`, as many
+    /// bytes as fit, and returns its full length.
+    fn read_attribute(&self, handle: u16, attribute: Attribute, buf: &mut [u8]) -> usize {
+        let mut value = [0; MAX_VALUE_LEN];
+        let len = match attribute {
+            Attribute::PrimaryService(uuid) => uuid.encode(&mut value),
+            Attribute::Characteristic { properties, uuid } => {
+                // The value always follows its declaration
+                value[0] = properties;
+                att::put_u16(&mut value, 1, handle + 1);
+                3 + uuid.encode(&mut value[3..])
+            }
+            Attribute::Value { slot, .. } => {
+                let stored = &self.values[slot];
+                for (byte, cell) in value.iter_mut().zip(stored.data.iter()) {
+                    *byte = cell.get();
+                }
+                stored.len.get()
+            }
+            Attribute::ClientConfiguration { slot } => {
+                att::put_u16(&mut value, 0, self.values[slot].configuration.get());
+                2
+            }
+        };
+        let copied = cmp::min(len, buf.len());
+        buf[..copied].copy_from_slice(&value[..copied]);
+        len
+    }
+
+    /// The last handle of the service declared at `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        (handle + 1..=self.count.get() as u16)
+            .find(|h| matches!(self.attribute(*h), Some(Attribute::PrimaryService(_))))
+            .map_or(self.count.get() as u16, |next| next - 1)
+    }
+
+    /// Checks the handle range of a request, and returns the handles in the
+    /// database it covers.
+    fn handle_range(&self, pdu: &[u8]) -> Result<core::ops::RangeInclusive<u16>, (u16, u8)> {
+        let start = att::get_u16(pdu, 1);
+        let end = att::get_u16(pdu, 3);
+        if start == 0 || start > end {
+            return Err((start, error::INVALID_HANDLE));
+        }
+        let last = cmp::min(end, self.count.get() as u16);
+        if start > last {
+            return Err((start, error::ATTRIBUTE_NOT_FOUND));
+        }
+        Ok(start..=last)
+    }
+
+    fn exchange_mtu(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        if pdu.len() != 3 {
+            return Err((0, error::INVALID_PDU));
+        }
+        let client_mtu = att::get_u16(pdu, 1) as usize;
+        self.mtu.set(cmp::max(
+            att::DEFAULT_MTU,
+            cmp::min(client_mtu, att::MAX_MTU),
+        ));
+        rsp[0] = opcode::EXCHANGE_MTU_RSP;
+        att::put_u16(rsp, 1, att::MAX_MTU as u16);
+        Ok(3)
+    }
+
+    fn find_information(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        if pdu.len() != 5 {
+            return Err((0, error::INVALID_PDU));
+        }
+        let mut len = 2;
+        let mut format_len = 0;
+        for handle in self.handle_range(pdu)? {
+            let uuid = match self.attribute(handle) {
+                Some(attribute) => attribute.uuid(),
+                None => continue,
+            };
+            if format_len == 0 {
+                format_len = uuid.encoded_len();
+                rsp[1] = if format_len == 2 { 1 } else { 2 };
+            } else if uuid.encoded_len() != format_len {
+                break;
+            }
+            if len + 2 + format_len > rsp.len() {
+                break;
+            }
+            att::put_u16(rsp, len, handle);
+            uuid.encode(&mut rsp[len + 2..]);
+            len += 2 + format_len;
+        }
+        if format_len == 0 {
+            return Err((att::get_u16(pdu, 1), error::ATTRIBUTE_NOT_FOUND));
+        }
+        rsp[0] = opcode::FIND_INFORMATION_RSP;
+        Ok(len)
+    }
+
+    fn find_by_type_value(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        if pdu.len() < 7 {
+            return Err((0, error::INVALID_PDU));
+        }
+        let range = self.handle_range(pdu)?;
+        let attribute_type = att::get_u16(pdu, 5);
+        let wanted = &pdu[7..];
+        let mut len = 1;
+        if attribute_type == types::PRIMARY_SERVICE {
+            for handle in range {
+                if let Some(Attribute::PrimaryService(uuid)) = self.attribute(handle) {
+                    if Uuid::decode(wanted) != Some(uuid) {
+                        continue;
+                    }
+                    if len + 4 > rsp.len() {
+                        break;
+                    }
+                    att::put_u16(rsp, len, handle);
+                    att::put_u16(rsp, len + 2, self.group_end(handle));
+                    len += 4;
+                }
+            }
+        }
+        if len == 1 {
+            return Err((att::get_u16(pdu, 1), error::ATTRIBUTE_NOT_FOUND));
+        }
+        rsp[0] = opcode::FIND_BY_TYPE_VALUE_RSP;
+        Ok(len)
+    }
+
+    fn read_by_type(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        let wanted = Uuid::decode(pdu.get(5..).unwrap_or(&[])).ok_or((0, error::INVALID_PDU))?;
+        let mut len = 2;
+        let mut entry_len = 0;
+        for handle in self.handle_range(pdu)? {
+            let attribute = match self.attribute(handle) {
+                Some(attribute) if attribute.uuid() == wanted => attribute,
+                _ => continue,
+            };
+            if !self.readable(attribute) {
+                if entry_len == 0 {
+                    return Err((handle, error::READ_NOT_PERMITTED));
+                }
+                break;
+            }
+            let mut value = [0; MAX_VALUE_LEN];
+            let value_len = self.read_attribute(handle, attribute, &mut value);
+            // Entries hold as much of the value as fits in one response
+            let this_len = cmp::min(2 + value_len, rsp.len() - 2);
+            if entry_len == 0 {
+                entry_len = this_len;
+            } else if this_len != entry_len || len + entry_len > rsp.len() {
+                break;
+            }
+            att::put_u16(rsp, len, handle);
+            rsp[len + 2..len + entry_len].copy_from_slice(&value[..entry_len - 2]);
+            len += entry_len;
+        }
+        if entry_len == 0 {
+            return Err((att::get_u16(pdu, 1), error::ATTRIBUTE_NOT_FOUND));
+        }
+        rsp[0] = opcode::READ_BY_TYPE_RSP;
+        rsp[1] = entry_len as u8;
+        Ok(len)
+    }
+
+    fn read_by_group_type(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        let wanted = Uuid::decode(pdu.get(5..).unwrap_or(&[])).ok_or((0, error::INVALID_PDU))?;
+        let range = self.handle_range(pdu)?;
+        if wanted != types::PRIMARY_SERVICE {
+            return Err((*range.start(), error::UNSUPPORTED_GROUP_TYPE));
+        }
+        let mut len = 2;
+        let mut entry_len = 0;
+        for handle in range {
+            if let Some(Attribute::PrimaryService(uuid)) = self.attribute(handle) {
+                let this_len = 4 + uuid.encoded_len();
+                if entry_len == 0 {
+                    entry_len = this_len;
+                } else if this_len != entry_len || len + entry_len > rsp.len() {
+                    break;
+                }
+                att::put_u16(rsp, len, handle);
+                att::put_u16(rsp, len + 2, self.group_end(handle));
+                uuid.encode(&mut rsp[len + 4..]);
+                len += entry_len;
+            }
+        }
+        if entry_len == 0 {
+            return Err((att::get_u16(pdu, 1), error::ATTRIBUTE_NOT_FOUND));
+        }
+        rsp[0] = opcode::READ_BY_GROUP_TYPE_RSP;
+        rsp[1] = entry_len as u8;
+        Ok(len)
+    }
+
+    fn readable(&self, attribute: Attribute) -> bool {
+        match attribute {
+            Attribute::Value { slot, .. } => {
+                self.values[slot].properties.get() & properties::READ != 0
+            }
+            _ => true,
+        }
+    }
+
+    /// Answers Read and Read Blob requests.
+    fn read(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        let blob = pdu[0] == opcode::READ_BLOB_REQ;
+        if pdu.len() != if blob { 5 } else { 3 } {
+            return Err((0, error::INVALID_PDU));
+        }
+        let handle = att::get_u16(pdu, 1);
+        let offset = if blob {
+            att::get_u16(pdu, 3) as usize
+        } else {
+            0
+        };
+        let attribute = self
+            .attribute(handle)
+            .ok_or((handle, error::INVALID_HANDLE))?;
+        if !self.readable(attribute) {
+            return Err((handle, error::READ_NOT_PERMITTED));
+        }
+        let mut value = [0; MAX_VALUE_LEN];
+        let len = self.read_attribute(handle, attribute, &mut value);
+        if offset > len {
+            return Err((handle, error::INVALID_OFFSET));
+        }
+        let copied = cmp::min(len - offset, rsp.len() - 1);
+        rsp[0] = if blob {
+            opcode::READ_BLOB_RSP
+        } else {
+            opcode::READ_RSP
+        };
+        rsp[1..1 + copied].copy_from_slice(&value[offset..offset + copied]);
+        Ok(1 + copied)
+    }
+
+    /// Answers Write requests and executes Write commands.
+    fn write(&self, pdu: &[u8], rsp: &mut [u8]) -> Result<usize, (u16, u8)> {
+        if pdu.len() < 3 {
+            return Err((0, error::INVALID_PDU));
+        }
+        let command = pdu[0] == opcode::WRITE_CMD;
+        let handle = att::get_u16(pdu, 1);
+        let data = &pdu[3..];
+        match self.attribute(handle) {
+            Some(Attribute::Value { slot, .. }) => {
+                let needed = if command {
+                    properties::WRITE_WITHOUT_RESPONSE
+                } else {
+                    properties::WRITE
+                };
+                if self.values[slot].properties.get() & needed == 0 {
+                    return Err((handle, error::WRITE_NOT_PERMITTED));
+                }
+                self.set_value(handle, data)
+                    .map_err(|_| (handle, error::INVALID_ATTRIBUTE_VALUE_LENGTH))?;
+                self.client.map(|client| client.written(handle, data));
+            }
+            Some(Attribute::ClientConfiguration { slot }) => {
+                if data.len() != 2 {
+                    return Err((handle, error::INVALID_ATTRIBUTE_VALUE_LENGTH));
+                }
+                self.values[slot]
+                    .configuration
+                    .set(att::get_u16(data, 0) & NOTIFICATIONS_ENABLED);
+            }
+            Some(_) => return Err((handle, error::WRITE_NOT_PERMITTED)),
+            None => return Err((handle, error::INVALID_HANDLE)),
+        }
+        rsp[0] = opcode::WRITE_RSP;
+        Ok(1)
+    }
+}
+
+impl ChannelClient for GattServer<'_> {
+    fn connected(&self) {
+        self.connected.set(true);
+        self.mtu.set(att::DEFAULT_MTU);
+        self.response_len.set(0);
+        self.notification.clear();
+        // Configurations are not kept across connections, as there is no
+        // bonding
+        for value in self.values.iter() {
+            value.configuration.set(0);
+        }
+        self.client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self) {
+        self.connected.set(false);
+        self.response_len.set(0);
+        self.notification.clear();
+        self.client.map(|client| client.disconnected());
+    }
+
+    fn received(&self, pdu: &[u8]) {
+        if pdu.is_empty() {
+            return;
+        }
+        let request = pdu[0];
+        self.response.map(|buf| {
+            let rsp = &mut buf[..self.mtu.get()];
+            let result = match request {
+                opcode::EXCHANGE_MTU_REQ => self.exchange_mtu(pdu, rsp),
+                opcode::FIND_INFORMATION_REQ => self.find_information(pdu, rsp),
+                opcode::FIND_BY_TYPE_VALUE_REQ => self.find_by_type_value(pdu, rsp),
+                opcode::READ_BY_TYPE_REQ => self.read_by_type(pdu, rsp),
+                opcode::READ_REQ | opcode::READ_BLOB_REQ => self.read(pdu, rsp),
+                opcode::READ_BY_GROUP_TYPE_REQ => self.read_by_group_type(pdu, rsp),
+                opcode::WRITE_REQ | opcode::WRITE_CMD => self.write(pdu, rsp),
+                _ => Err((0, error::REQUEST_NOT_SUPPORTED)),
+            };
+            if request & opcode::COMMAND_FLAG != 0 {
+                // Commands are never answered, even with an error
+                return;
+            }
+            let len = match result {
+                Ok(len) => len,
+                Err((handle, error)) => att::error_response(rsp, request, handle, error),
+            };
+            self.response_len.set(len);
+        });
+    }
+
+    fn next_pdu(&self, buf: &mut [u8]) -> Option<usize> {
+        let mtu = cmp::min(self.mtu.get(), buf.len());
+        let len = self.response_len.take();
+        if len > 0 {
+            return self.response.map(|response| {
+                buf[..len].copy_from_slice(&response[..len]);
+                len
+            });
+        }
+
+        let handle = self.notification.take()?;
+        let value = self.value(handle)?;
+        buf[0] = opcode::HANDLE_VALUE_NTF;
+        att::put_u16(buf, 1, handle);
+        let len = cmp::min(value.len.get(), mtu - 3);
+        for (byte, cell) in buf[3..3 + len].iter_mut().zip(value.data.iter()) {
+            *byte = cell.get();
+        }
+        self.client.map(|client| client.notified(handle));
+        Some(3 + len)
+    }
+}
@@ -0,0 +1,226 @@
+//! L2CAP in basic mode over an LE connection (Bluetooth Core Specification
+//! 5.3, Vol 3, Part A), for the fixed channels of the attribute protocol,
+//! LE signaling and the security manager.
+//!
+//! Each L2CAP PDU starts with a 4-byte header, the length of its payload and
+//! the channel identifier, both in little endian. The link layer carries it
+//! in a data PDU marked as a start, followed by continuation PDUs if it is
+//! longer than the payload of one data PDU. Received PDUs are reassembled
+//! before being passed to the protocol of their channel, and PDUs to send are
+//! fragmented as the link layer asks for data.
+//!
+//! Only the attribute protocol is supported. Signaling requests are rejected
+//! with a Command Reject, and pairing requests with a Pairing Failed, so the
+//! central knows not to wait for them.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+
+use super::att;
+use super::link_layer::LinkLayerClient;
+
+/// Length of the basic L2CAP header.
+pub const HEADER_LEN: usize = 4;
+
+/// Length of the buffers, which hold the largest ATT PDU and its header.
+pub const BUFFER_LEN: usize = HEADER_LEN + att::MAX_MTU;
+
+/// Fixed channel of the attribute protocol.
+pub const ATT_CID: u16 = 0x0004;
+/// Fixed channel of LE signaling.
+pub const SIGNALING_CID: u16 = 0x0005;
+/// Fixed channel of the security manager.
+pub const SMP_CID: u16 = 0x0006;
+
+// Signaling codes
+const COMMAND_REJECT: u8 = 0x01;
+const CONNECTION_PARAMETER_UPDATE_RSP: u8 = 0x13;
+/// Reason of a Command Reject: the command is not understood.
+const COMMAND_NOT_UNDERSTOOD: u16 = 0x0000;
+
+// Security manager codes
+const PAIRING_REQUEST: u8 = 0x01;
+const PAIRING_FAILED: u8 = 0x05;
+/// Reason of a Pairing Failed: pairing is not supported.
+const PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+/// The protocol of a fixed channel.
+pub trait ChannelClient {
+    /// A connection was established.
+    fn connected(&self);
+
+    /// The connection was closed.
+    fn disconnected(&self);
+
+    /// A PDU was received on the channel.
+    fn received(&self, pdu: &[u8]);
+
+    /// Writes the next PDU to send on the channel into `buf`, if there is
+    /// one, and returns its length.
+    fn next_pdu(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+pub struct L2cap<'a> {
+    att: OptionalCell<&'a dyn ChannelClient>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// Number of bytes of the PDU being reassembled.
+    rx_len: Cell<usize>,
+    /// Whether the PDU being reassembled is dropped, because it does not fit
+    /// in the buffer.
+    rx_dropping: Cell<bool>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Length of the PDU being sent, header included.
+    tx_len: Cell<usize>,
+    /// Number of bytes of this PDU already given to the link layer.
+    tx_offset: Cell<usize>,
+
+    /// A signaling or security manager response waiting to be sent: its
+    /// channel, payload and length.
+    response: Cell<Option<(u16, [u8; 6], usize)>>,
+}
+
+impl<'a> L2cap<'a> {
+    pub fn new(rx_buffer: &'static mut [u8], tx_buffer: &'static mut [u8]) -> L2cap<'a> {
+        L2cap {
+            att: OptionalCell::empty(),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            rx_dropping: Cell::new(false),
+            tx_buffer: TakeCell::new(tx_buffer),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            response: Cell::new(None),
+        }
+    }
+
+    pub fn set_att_client(&self, client: &'a dyn ChannelClient) {
+        self.att.set(client);
+    }
+
+    fn reset(&self) {
+        self.rx_len.set(0);
+        self.rx_dropping.set(false);
+        self.tx_len.set(0);
+        self.tx_offset.set(0);
+        self.response.set(None);
+    }
+
+    /// Passes a complete PDU to the protocol of its channel.
+    fn dispatch(&self, cid: u16, payload: &[u8]) {
+        match cid {
+            ATT_CID => {
+                self.att.map(|att| att.received(payload));
+            }
+            SIGNALING_CID => {
+                if payload.len() < 2
+                    || payload[0] == COMMAND_REJECT
+                    || payload[0] == CONNECTION_PARAMETER_UPDATE_RSP
+                {
+                    return;
+                }
+                let mut response = [0; 6];
+                response[0] = COMMAND_REJECT;
+                response[1] = payload[1]; // identifier
+                att::put_u16(&mut response, 2, 2);
+                att::put_u16(&mut response, 4, COMMAND_NOT_UNDERSTOOD);
+                self.response.set(Some((SIGNALING_CID, response, 6)));
+            }
+            SMP_CID => {
+                if payload.first() == Some(&PAIRING_REQUEST) {
+                    let response = [PAIRING_FAILED, PAIRING_NOT_SUPPORTED, 0, 0, 0, 0];
+                    self.response.set(Some((SMP_CID, response, 2)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fills the transmit buffer with the next PDU to send, if any.
+    fn next_pdu(&self, buf: &mut [u8]) -> Option<usize> {
+        let (cid, len) = match self.response.take() {
+            Some((cid, response, len)) => {
+                buf[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&response[..len]);
+                (cid, len)
+            }
+            None => {
+                let len = self
+                    .att
+                    .and_then(|att| att.next_pdu(&mut buf[HEADER_LEN..]))?;
+                (ATT_CID, len)
+            }
+        };
+        att::put_u16(buf, 0, len as u16);
+        att::put_u16(buf, 2, cid);
+        Some(HEADER_LEN + len)
+    }
+}
+
+impl LinkLayerClient for L2cap<'_> {
+    fn connected(&self, _peer: [u8; 6]) {
+        self.reset();
+        self.att.map(|att| att.connected());
+    }
+
+    fn disconnected(&self, _reason: u8) {
+        self.reset();
+        self.att.map(|att| att.disconnected());
+    }
+
+    fn received(&self, start: bool, payload: &[u8]) {
+        self.rx_buffer.map(|buf| {
+            if start {
+                // A new PDU replaces any incomplete one
+                self.rx_len.set(0);
+                self.rx_dropping.set(false);
+            } else if self.rx_len.get() == 0 && !self.rx_dropping.get() {
+                // Continuation of nothing
+                return;
+            }
+            if self.rx_dropping.get() {
+                return;
+            }
+
+            let offset = self.rx_len.get();
+            if offset + payload.len() > buf.len() {
+                self.rx_len.set(0);
+                self.rx_dropping.set(true);
+                return;
+            }
+            buf[offset..offset + payload.len()].copy_from_slice(payload);
+            let len = offset + payload.len();
+            self.rx_len.set(len);
+
+            if len < HEADER_LEN {
+                return;
+            }
+            let total = HEADER_LEN + att::get_u16(buf, 0) as usize;
+            if total > buf.len() {
+                self.rx_len.set(0);
+                self.rx_dropping.set(true);
+            } else if len >= total {
+                self.rx_len.set(0);
+                let cid = att::get_u16(buf, 2);
+                self.dispatch(cid, &buf[HEADER_LEN..total]);
+            }
+        });
+    }
+
+    fn next_fragment(&self, fragment: &mut [u8]) -> Option<(bool, usize)> {
+        self.tx_buffer.map_or(None, |buf| {
+            let start = self.tx_offset.get() >= self.tx_len.get();
+            if start {
+                self.tx_len.set(self.next_pdu(buf)?);
+                self.tx_offset.set(0);
+            }
+            let offset = self.tx_offset.get();
+            let len = cmp::min(fragment.len(), self.tx_len.get() - offset);
+            fragment[..len].copy_from_slice(&buf[offset..offset + len]);
+            self.tx_offset.set(offset + len);
+            Some((start, len))
+        })
+    }
+}
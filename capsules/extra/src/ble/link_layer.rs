@@ -0,0 +1,853 @@
+//! BLE link layer of a peripheral (Bluetooth Core Specification 5.3, Vol 6,
+//! Part B): connectable advertising, and a connection to one central.
+//!
+//! While advertising, an `ADV_IND` is sent on each advertising channel every
+//! advertising interval, plus a random delay of up to 10 ms. After each one
+//! the link layer listens for a `SCAN_REQ`, answered with an empty
+//! `SCAN_RSP`, or a `CONNECT_IND`, which ends advertising and creates the
+//! connection.
+//!
+//! In a connection, the central starts each connection event by sending a
+//! data channel PDU at the anchor point of the event, and the peripheral
+//! answers. The link layer listens from a little before the expected anchor
+//! point, answers the first PDU received, and closes the event: it never
+//! sets the more data bit, so one PDU is exchanged each way per event.
+//! Events are on the data channel given by channel selection algorithm #1.
+//!
+//! PDUs are acknowledged with the SN and NESN bits of their header: a PDU is
+//! new if its SN is the NESN expected, and the last PDU sent is acknowledged
+//! once the NESN received differs from its SN. Unacknowledged PDUs are sent
+//! again, and PDUs received with a CRC error are not acknowledged.
+//!
+//! The link layer answers the control procedures of the central itself
+//! (connection update, channel map update, features, version, ping, and
+//! data length), and passes data PDUs to its `LinkLayerClient`, the L2CAP
+//! layer, which also gives the data to send. The connection is lost when no
+//! valid PDU is received for the supervision timeout, or when none is
+//! received in the first six connection events.
+//!
+//! Once the connection is closed, advertising starts again, until
+//! `stop_advertising` is called.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let link_layer = static_init!(
+//!     LinkLayer<'static, nrf52::ble_radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     LinkLayer::new(radio, alarm, tx_buffer, address)
+//! );
+//! radio.set_transmit_client(link_layer);
+//! radio.set_receive_client(link_layer);
+//! alarm.set_alarm_client(link_layer);
+//! link_layer.set_client(l2cap);
+//! link_layer.start_advertising(&advertising_data, 100)?;
+//! ```
+
+use core::cell::Cell;
+
+use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{self, BleAdvertisementDriver, BleConnectionConfig};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Ticks};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Length of the transmit buffer, which holds the largest advertising PDU.
+pub const BUFFER_LEN: usize = 39;
+
+/// Longest advertising data.
+pub const MAX_ADVERTISING_DATA_LEN: usize = 31;
+
+/// Longest payload of a data channel PDU.
+pub const MAX_PAYLOAD_LEN: usize = 27;
+
+// Advertising PDU types
+const ADV_IND: u8 = 0x0;
+const SCAN_REQ: u8 = 0x3;
+const SCAN_RSP: u8 = 0x4;
+const CONNECT_IND: u8 = 0x5;
+/// Bit of the advertising PDU header set when the advertiser address is
+/// random.
+const TX_ADD: u8 = 1 << 6;
+
+// LLIDs of data channel PDUs
+const LLID_CONTINUATION: u8 = 0x1;
+const LLID_START: u8 = 0x2;
+const LLID_CONTROL: u8 = 0x3;
+const NESN: u8 = 1 << 2;
+const SN: u8 = 1 << 3;
+
+// Control PDU opcodes
+const CONNECTION_UPDATE_IND: u8 = 0x00;
+const CHANNEL_MAP_IND: u8 = 0x01;
+const TERMINATE_IND: u8 = 0x02;
+const UNKNOWN_RSP: u8 = 0x07;
+const FEATURE_REQ: u8 = 0x08;
+const FEATURE_RSP: u8 = 0x09;
+const VERSION_IND: u8 = 0x0c;
+const REJECT_IND: u8 = 0x0d;
+const PING_REQ: u8 = 0x12;
+const PING_RSP: u8 = 0x13;
+const LENGTH_REQ: u8 = 0x14;
+const LENGTH_RSP: u8 = 0x15;
+
+/// Supported features: LE Ping.
+const FEATURES: u8 = 1 << 4;
+/// Version of the specification implemented, 5.3.
+const VERSION: u8 = 0x0c;
+/// Company identifier sent in `VERSION_IND`, none.
+const COMPANY_ID: u16 = 0xffff;
+/// Longest time to send or receive a PDU of 27 bytes, in microseconds.
+const MAX_PDU_TIME: u16 = 328;
+
+// Disconnection reasons
+/// The supervision timeout expired.
+pub const CONNECTION_TIMEOUT: u8 = 0x08;
+/// The connection was closed by the application.
+pub const REMOTE_USER_TERMINATED: u8 = 0x13;
+/// The connection was closed by this device.
+pub const LOCAL_HOST_TERMINATED: u8 = 0x16;
+/// No PDU was received in the first six connection events.
+pub const FAILED_TO_ESTABLISH: u8 = 0x3e;
+
+/// Time listening for a request after each `ADV_IND`, in microseconds.
+const ADVERTISING_LISTEN_US: u32 = 600;
+/// Longest random delay added to the advertising interval, in milliseconds.
+const MAX_ADVERTISING_DELAY_MS: u32 = 10;
+/// Unit of the connection parameters, in microseconds.
+const UNIT_US: u32 = 1250;
+/// Time listening before the expected anchor point, in microseconds.
+const WIDENING_US: u32 = 250;
+/// Time listening after the expected anchor point, in microseconds, enough
+/// to receive the longest PDU.
+const RECEIVE_WINDOW_US: u32 = 500;
+/// Number of connection events in which the connection must be
+/// established.
+const ESTABLISHMENT_EVENTS: u16 = 6;
+/// Number of data channels.
+const DATA_CHANNELS: u8 = 37;
+
+/// Time to send or receive a PDU of `len` bytes, header included, in
+/// microseconds: preamble, access address, PDU and CRC, at 1 Mbit/s.
+fn airtime_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+fn advertising_channel(index: u8) -> RadioChannel {
+    match index {
+        0 => RadioChannel::AdvertisingChannel37,
+        1 => RadioChannel::AdvertisingChannel38,
+        _ => RadioChannel::AdvertisingChannel39,
+    }
+}
+
+/// Receives the events of a connection and exchanges its data.
+pub trait LinkLayerClient {
+    /// A connection was created with the central at address `peer`.
+    fn connected(&self, peer: [u8; 6]);
+
+    /// The connection was closed, for `reason`.
+    fn disconnected(&self, reason: u8);
+
+    /// A data PDU was received, starting an L2CAP PDU if `start` is set.
+    fn received(&self, start: bool, payload: &[u8]);
+
+    /// Writes the payload of the next data PDU to send into `fragment`, if
+    /// there is one, and returns whether it starts an L2CAP PDU and its
+    /// length.
+    fn next_fragment(&self, fragment: &mut [u8]) -> Option<(bool, usize)>;
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Idle,
+    /// Waiting for the next advertising event.
+    AdvertisingWait,
+    /// Sending an `ADV_IND` on the advertising channel of this index.
+    AdvertisingTransmit(u8),
+    /// Listening for a request after the `ADV_IND`.
+    AdvertisingListen(u8),
+    /// Sending a `SCAN_RSP`.
+    AdvertisingRespond(u8),
+    /// Waiting for the next connection event.
+    ConnectionWait,
+    /// Listening for the PDU of the central.
+    ConnectionListen,
+    /// Sending the answer of the peripheral.
+    ConnectionTransmit,
+}
+
+/// A connection update, taking effect at its instant.
+#[derive(Copy, Clone, Default)]
+struct Update {
+    window_size: u8,
+    window_offset: u16,
+    interval: u16,
+    latency: u16,
+    timeout: u16,
+    instant: u16,
+}
+
+/// A control PDU to send: its opcode and data, and its length.
+#[derive(Copy, Clone)]
+struct Control {
+    data: [u8; 9],
+    len: usize,
+}
+
+impl Control {
+    fn new(bytes: &[u8]) -> Control {
+        let mut data = [0; 9];
+        data[..bytes.len()].copy_from_slice(bytes);
+        Control {
+            data,
+            len: bytes.len(),
+        }
+    }
+}
+
+/// The state of a connection.
+#[derive(Copy, Clone, Default)]
+struct Connection {
+    access_address: u32,
+    crc_init: u32,
+    peer: [u8; 6],
+
+    /// Connection interval, in units of 1.25 ms.
+    interval: u16,
+    latency: u16,
+    /// Supervision timeout, in units of 10 ms.
+    timeout: u16,
+
+    channel_map: [u8; 5],
+    used_channels: u8,
+    hop: u8,
+    unmapped_channel: u8,
+    channel: u8,
+    event_counter: u16,
+
+    sn: bool,
+    nesn: bool,
+    /// Whether the last PDU sent is waiting to be acknowledged.
+    unacked: bool,
+    /// Whether the last PDU sent is a `TERMINATE_IND`.
+    terminating: bool,
+    /// The reason of a `TERMINATE_IND` received, closing the connection
+    /// once acknowledged.
+    closing: Option<u8>,
+    /// Whether a PDU was received.
+    established: bool,
+
+    /// Offset of the anchor point of the next event from the last anchor
+    /// point, in microseconds.
+    next_anchor_us: u32,
+    /// Time listening after the expected anchor point of the next event, in
+    /// microseconds.
+    window_us: u32,
+
+    update: Option<Update>,
+    channel_map_update: Option<([u8; 5], u16)>,
+    /// A control PDU waiting to be sent.
+    control: Option<Control>,
+}
+
+impl Connection {
+    fn is_used(&self, channel: u8) -> bool {
+        self.channel_map[channel as usize / 8] & (1 << (channel % 8)) != 0
+    }
+
+    fn set_channel_map(&mut self, channel_map: [u8; 5]) {
+        self.channel_map = channel_map;
+        self.channel_map[4] &= 0x1f;
+        self.used_channels = (0..DATA_CHANNELS).filter(|c| self.is_used(*c)).count() as u8;
+    }
+
+    /// Moves to the data channel of the next connection event, with channel
+    /// selection algorithm #1.
+    fn hop(&mut self) {
+        self.unmapped_channel = (self.unmapped_channel + self.hop) % DATA_CHANNELS;
+        self.channel = if self.is_used(self.unmapped_channel) {
+            self.unmapped_channel
+        } else {
+            let remapping_index = self.unmapped_channel % self.used_channels;
+            (0..DATA_CHANNELS)
+                .filter(|c| self.is_used(*c))
+                .nth(remapping_index as usize)
+                .unwrap_or(0)
+        };
+    }
+}
+
+pub struct LinkLayer<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    radio: &'a R,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn LinkLayerClient>,
+    address: [u8; 6],
+    tx_buffer: TakeCell<'static, [u8]>,
+
+    state: Cell<State>,
+    /// Whether to advertise when not connected.
+    advertising: Cell<bool>,
+    advertising_data: Cell<[u8; MAX_ADVERTISING_DATA_LEN]>,
+    advertising_data_len: Cell<usize>,
+    /// Advertising interval, in milliseconds.
+    advertising_interval: Cell<u32>,
+    /// State of the pseudo-random generator of the advertising delay.
+    random: Cell<u32>,
+
+    connection: Cell<Connection>,
+    /// The anchor point of the last connection event.
+    anchor: Cell<A::Ticks>,
+    /// The time the last valid PDU was received.
+    last_received: Cell<A::Ticks>,
+}
+
+impl<'a, R, A> LinkLayer<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    /// `tx_buffer` must hold `BUFFER_LEN` bytes. `address` is the random
+    /// static address of the device, in little endian as it is sent.
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        tx_buffer: &'static mut [u8],
+        address: [u8; 6],
+    ) -> LinkLayer<'a, R, A> {
+        let seed = u32::from_le_bytes([address[0], address[1], address[2], address[3]]);
+        LinkLayer {
+            radio,
+            alarm,
+            client: OptionalCell::empty(),
+            address,
+            tx_buffer: TakeCell::new(tx_buffer),
+            state: Cell::new(State::Idle),
+            advertising: Cell::new(false),
+            advertising_data: Cell::new([0; MAX_ADVERTISING_DATA_LEN]),
+            advertising_data_len: Cell::new(0),
+            advertising_interval: Cell::new(0),
+            random: Cell::new(seed | 1),
+            connection: Cell::new(Connection::default()),
+            anchor: Cell::new(A::Ticks::from(0)),
+            last_received: Cell::new(A::Ticks::from(0)),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn LinkLayerClient) {
+        self.client.set(client);
+    }
+
+    /// Starts advertising `data` every `interval_ms` milliseconds, or
+    /// changes the data and interval of the advertisements.
+    ///
+    /// Returns SIZE if `data` is longer than `MAX_ADVERTISING_DATA_LEN`, and
+    /// INVAL if `interval_ms` is 0.
+    pub fn start_advertising(&self, data: &[u8], interval_ms: u32) -> Result<(), ErrorCode> {
+        if data.len() > MAX_ADVERTISING_DATA_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if interval_ms == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut advertising_data = [0; MAX_ADVERTISING_DATA_LEN];
+        advertising_data[..data.len()].copy_from_slice(data);
+        self.advertising_data.set(advertising_data);
+        self.advertising_data_len.set(data.len());
+        self.advertising_interval.set(interval_ms);
+        self.advertising.set(true);
+
+        if self.state.get() == State::Idle {
+            self.radio.set_access_address(
+                ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+                ble_advertising::ADVERTISING_CRC_INIT,
+            );
+            self.state.set(State::AdvertisingWait);
+            self.alarm.set_alarm(self.alarm.now(), A::Ticks::from(0));
+        }
+        Ok(())
+    }
+
+    /// Stops advertising, and restarting it when the connection is closed.
+    pub fn stop_advertising(&self) {
+        self.advertising.set(false);
+        match self.state.get() {
+            State::AdvertisingWait => {
+                let _ = self.alarm.disarm();
+                self.state.set(State::Idle);
+            }
+            State::AdvertisingListen(_) => {
+                let _ = self.alarm.disarm();
+                self.radio.stop_receive();
+                self.state.set(State::Idle);
+            }
+            // The end of the transmission checks whether to advertise
+            _ => {}
+        }
+    }
+
+    /// Closes the connection, with a `TERMINATE_IND` sent to the central.
+    ///
+    /// Returns OFF if not connected, and BUSY if the connection is already
+    /// closing.
+    pub fn disconnect(&self) -> Result<(), ErrorCode> {
+        if !self.is_connected() {
+            return Err(ErrorCode::OFF);
+        }
+        let mut connection = self.connection.get();
+        let queued = connection
+            .control
+            .map_or(false, |control| control.data[0] == TERMINATE_IND);
+        if queued || connection.terminating || connection.closing.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        connection.control = Some(Control::new(&[TERMINATE_IND, REMOTE_USER_TERMINATED]));
+        self.connection.set(connection);
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self.state.get(),
+            State::ConnectionWait | State::ConnectionListen | State::ConnectionTransmit
+        )
+    }
+
+    /// The next number of the xorshift generator.
+    fn next_random(&self) -> u32 {
+        let mut x = self.random.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random.set(x);
+        x
+    }
+
+    /// Sends the `ADV_IND` of the advertising channel at `index`.
+    fn advertise(&self, index: u8) {
+        self.tx_buffer.take().map(|buf| {
+            let len = self.advertising_data_len.get();
+            buf[0] = ADV_IND | TX_ADD;
+            buf[1] = (6 + len) as u8;
+            buf[2..8].copy_from_slice(&self.address);
+            buf[8..8 + len].copy_from_slice(&self.advertising_data.get()[..len]);
+            self.state.set(State::AdvertisingTransmit(index));
+            self.radio
+                .transmit_advertisement(buf, 8 + len, advertising_channel(index));
+        });
+    }
+
+    /// Ends the advertising event on the advertising channel at `index`,
+    /// moving to the next channel or waiting for the next event.
+    fn next_advertising_channel(&self, index: u8) {
+        if !self.advertising.get() {
+            self.state.set(State::Idle);
+        } else if index < 2 {
+            self.advertise(index + 1);
+        } else {
+            let delay_us = self.next_random() % (MAX_ADVERTISING_DELAY_MS * 1000);
+            let interval_us = self.advertising_interval.get() * 1000 + delay_us;
+            self.state.set(State::AdvertisingWait);
+            self.alarm
+                .set_alarm(self.alarm.now(), self.alarm.ticks_from_us(interval_us));
+        }
+    }
+
+    /// Handles an advertising PDU received after the `ADV_IND` of the
+    /// advertising channel at `index`.
+    fn advertising_received(&self, index: u8, pdu: &[u8]) {
+        let pdu_type = pdu[0] & 0x0f;
+        let payload = &pdu[2..];
+        let for_us = payload.len() >= 12 && payload[6..12] == self.address;
+
+        if pdu_type == SCAN_REQ && payload.len() == 12 && for_us {
+            let _ = self.alarm.disarm();
+            self.tx_buffer.take().map(|buf| {
+                buf[0] = SCAN_RSP | TX_ADD;
+                buf[1] = 6;
+                buf[2..8].copy_from_slice(&self.address);
+                self.state.set(State::AdvertisingRespond(index));
+                self.radio
+                    .transmit_advertisement(buf, 8, advertising_channel(index));
+            });
+        } else if pdu_type == CONNECT_IND && payload.len() == 34 && for_us {
+            let _ = self.alarm.disarm();
+            self.connect(payload);
+        } else {
+            // Keep listening until the end of the window
+            self.radio.receive_advertisement(advertising_channel(index));
+        }
+    }
+
+    /// Creates the connection requested by the payload of a `CONNECT_IND`.
+    fn connect(&self, payload: &[u8]) {
+        let mut connection = Connection::default();
+        connection.peer.copy_from_slice(&payload[0..6]);
+        connection.access_address =
+            u32::from_le_bytes([payload[12], payload[13], payload[14], payload[15]]);
+        connection.crc_init = u32::from_le_bytes([payload[16], payload[17], payload[18], 0]);
+        let window_size = payload[19];
+        let window_offset = u16::from_le_bytes([payload[20], payload[21]]);
+        connection.interval = u16::from_le_bytes([payload[22], payload[23]]);
+        connection.latency = u16::from_le_bytes([payload[24], payload[25]]);
+        connection.timeout = u16::from_le_bytes([payload[26], payload[27]]);
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&payload[28..33]);
+        connection.set_channel_map(channel_map);
+        connection.hop = payload[33] & 0x1f;
+
+        if connection.interval == 0 || connection.used_channels < 2 || connection.hop < 5 {
+            // An invalid request, ignored
+            self.next_advertising_channel(2);
+            return;
+        }
+
+        // The transmit window starts 1.25 ms after the end of the request,
+        // plus the window offset
+        connection.next_anchor_us = UNIT_US + window_offset as u32 * UNIT_US;
+        connection.window_us = window_size as u32 * UNIT_US + RECEIVE_WINDOW_US;
+        connection.hop();
+        self.connection.set(connection);
+
+        let now = self.alarm.now();
+        self.anchor.set(now);
+        self.last_received.set(now);
+        self.radio
+            .set_access_address(connection.access_address, connection.crc_init);
+        self.schedule_event();
+        self.client.map(|client| client.connected(connection.peer));
+    }
+
+    /// Waits for the start of the next connection event.
+    fn schedule_event(&self) {
+        let connection = self.connection.get();
+        self.state.set(State::ConnectionWait);
+        self.alarm.set_alarm(
+            self.anchor.get(),
+            self.alarm
+                .ticks_from_us(connection.next_anchor_us.saturating_sub(WIDENING_US)),
+        );
+    }
+
+    /// Moves to the next connection event, applying the updates whose
+    /// instant it is.
+    fn next_event(&self, connection: &mut Connection) {
+        connection.event_counter = connection.event_counter.wrapping_add(1);
+        connection.next_anchor_us += connection.interval as u32 * UNIT_US;
+
+        if let Some((channel_map, instant)) = connection.channel_map_update {
+            if instant == connection.event_counter {
+                connection.set_channel_map(channel_map);
+                connection.channel_map_update = None;
+            }
+        }
+        if let Some(update) = connection.update {
+            if update.instant == connection.event_counter {
+                // The transmit window of the new parameters starts the
+                // window offset after the old anchor point of the instant
+                connection.next_anchor_us += update.window_offset as u32 * UNIT_US;
+                connection.window_us = update.window_size as u32 * UNIT_US + RECEIVE_WINDOW_US;
+                connection.interval = update.interval;
+                connection.latency = update.latency;
+                connection.timeout = update.timeout;
+                connection.update = None;
+            }
+        }
+        connection.hop();
+    }
+
+    /// Closes the connection, and advertises again if advertising was not
+    /// stopped.
+    fn close(&self, reason: u8) {
+        let _ = self.alarm.disarm();
+        self.state.set(State::Idle);
+        self.radio.set_access_address(
+            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+            ble_advertising::ADVERTISING_CRC_INIT,
+        );
+        self.client.map(|client| client.disconnected(reason));
+        if self.advertising.get() && self.state.get() == State::Idle {
+            self.state.set(State::AdvertisingWait);
+            self.alarm.set_alarm(self.alarm.now(), A::Ticks::from(0));
+        }
+    }
+
+    /// Handles the end of the receive window of a connection event in which
+    /// nothing was received.
+    fn event_missed(&self) {
+        let mut connection = self.connection.get();
+        let now = self.alarm.now();
+        if !connection.established {
+            if connection.event_counter + 1 >= ESTABLISHMENT_EVENTS {
+                self.close(FAILED_TO_ESTABLISH);
+                return;
+            }
+        } else {
+            let since = self
+                .alarm
+                .ticks_to_us(now.wrapping_sub(self.last_received.get()));
+            if since >= connection.timeout as u32 * 10_000 {
+                self.close(CONNECTION_TIMEOUT);
+                return;
+            }
+        }
+        self.next_event(&mut connection);
+        self.connection.set(connection);
+        self.schedule_event();
+    }
+
+    /// Handles the control PDU `payload` of the central.
+    fn control_received(&self, connection: &mut Connection, payload: &[u8]) {
+        let opcode = match payload.first() {
+            Some(opcode) => *opcode,
+            None => return,
+        };
+        match opcode {
+            CONNECTION_UPDATE_IND if payload.len() == 12 => {
+                connection.update = Some(Update {
+                    window_size: payload[1],
+                    window_offset: u16::from_le_bytes([payload[2], payload[3]]),
+                    interval: u16::from_le_bytes([payload[4], payload[5]]),
+                    latency: u16::from_le_bytes([payload[6], payload[7]]),
+                    timeout: u16::from_le_bytes([payload[8], payload[9]]),
+                    instant: u16::from_le_bytes([payload[10], payload[11]]),
+                });
+            }
+            CHANNEL_MAP_IND if payload.len() == 8 => {
+                let mut channel_map = [0; 5];
+                channel_map.copy_from_slice(&payload[1..6]);
+                let instant = u16::from_le_bytes([payload[6], payload[7]]);
+                connection.channel_map_update = Some((channel_map, instant));
+            }
+            TERMINATE_IND if payload.len() == 2 => {
+                connection.closing = Some(payload[1]);
+            }
+            FEATURE_REQ => {
+                connection.control =
+                    Some(Control::new(&[FEATURE_RSP, FEATURES, 0, 0, 0, 0, 0, 0, 0]));
+            }
+            VERSION_IND => {
+                let company = COMPANY_ID.to_le_bytes();
+                connection.control = Some(Control::new(&[
+                    VERSION_IND,
+                    VERSION,
+                    company[0],
+                    company[1],
+                    0,
+                    0,
+                ]));
+            }
+            PING_REQ => {
+                connection.control = Some(Control::new(&[PING_RSP]));
+            }
+            LENGTH_REQ => {
+                let octets = (MAX_PAYLOAD_LEN as u16).to_le_bytes();
+                let time = MAX_PDU_TIME.to_le_bytes();
+                connection.control = Some(Control::new(&[
+                    LENGTH_RSP, octets[0], octets[1], time[0], time[1], octets[0], octets[1],
+                    time[0], time[1],
+                ]));
+            }
+            // Responses to procedures that are never started
+            UNKNOWN_RSP | REJECT_IND | FEATURE_RSP | PING_RSP | LENGTH_RSP => {}
+            _ => {
+                connection.control = Some(Control::new(&[UNKNOWN_RSP, opcode]));
+            }
+        }
+    }
+
+    /// Handles the data channel PDU received in a connection event, and
+    /// sends the answer.
+    fn connection_received(&self, pdu: &[u8], crc_ok: bool) {
+        let mut connection = self.connection.get();
+        let now = self.alarm.now();
+        self.anchor
+            .set(now.wrapping_sub(self.alarm.ticks_from_us(airtime_us(pdu.len()))));
+        connection.next_anchor_us = 0;
+        connection.window_us = RECEIVE_WINDOW_US;
+
+        if crc_ok {
+            self.last_received.set(now);
+            connection.established = true;
+            let header = pdu[0];
+
+            // The central acknowledged the last PDU sent
+            if (header & NESN != 0) != connection.sn {
+                connection.sn = !connection.sn;
+                connection.unacked = false;
+                if connection.terminating {
+                    self.connection.set(connection);
+                    self.close(LOCAL_HOST_TERMINATED);
+                    return;
+                }
+            }
+
+            // A new PDU
+            if (header & SN != 0) == connection.nesn {
+                connection.nesn = !connection.nesn;
+                let payload = &pdu[2..];
+                match header & 0x3 {
+                    LLID_CONTROL => self.control_received(&mut connection, payload),
+                    LLID_START => {
+                        self.client.map(|client| client.received(true, payload));
+                    }
+                    LLID_CONTINUATION if !payload.is_empty() => {
+                        self.client.map(|client| client.received(false, payload));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.tx_buffer.take().map(|buf| {
+            if !connection.unacked {
+                // A new PDU: a control PDU, data, or an empty PDU
+                let (llid, len) = match connection.control.take() {
+                    Some(control) => {
+                        buf[2..2 + control.len].copy_from_slice(&control.data[..control.len]);
+                        connection.terminating = control.data[0] == TERMINATE_IND;
+                        (LLID_CONTROL, control.len)
+                    }
+                    None => match self
+                        .client
+                        .and_then(|client| client.next_fragment(&mut buf[2..2 + MAX_PAYLOAD_LEN]))
+                    {
+                        Some((true, len)) => (LLID_START, len),
+                        Some((false, len)) => (LLID_CONTINUATION, len),
+                        None => (LLID_CONTINUATION, 0),
+                    },
+                };
+                buf[0] = llid;
+                buf[1] = len as u8;
+                connection.unacked = true;
+            }
+            buf[0] &= !(SN | NESN);
+            if connection.sn {
+                buf[0] |= SN;
+            }
+            if connection.nesn {
+                buf[0] |= NESN;
+            }
+            let len = 2 + buf[1] as usize;
+            self.connection.set(connection);
+            self.state.set(State::ConnectionTransmit);
+            let channel = RadioChannel::data_channel(connection.channel)
+                .unwrap_or(RadioChannel::DataChannel0);
+            self.radio.transmit_advertisement(buf, len, channel);
+        });
+    }
+}
+
+impl<'a, R, A> AlarmClient for LinkLayer<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::AdvertisingWait => self.advertise(0),
+            State::AdvertisingListen(index) => {
+                self.radio.stop_receive();
+                self.next_advertising_channel(index);
+            }
+            State::ConnectionWait => {
+                let connection = self.connection.get();
+                self.state.set(State::ConnectionListen);
+                if let Some(channel) = RadioChannel::data_channel(connection.channel) {
+                    self.radio.receive_advertisement(channel);
+                }
+                self.alarm.set_alarm(
+                    self.anchor.get(),
+                    self.alarm
+                        .ticks_from_us(connection.next_anchor_us + connection.window_us),
+                );
+            }
+            State::ConnectionListen => {
+                self.radio.stop_receive();
+                self.event_missed();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R, A> ble_advertising::TxClient for LinkLayer<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    fn transmit_event(&self, buf: &'static mut [u8], _result: Result<(), ErrorCode>) {
+        self.tx_buffer.replace(buf);
+        match self.state.get() {
+            State::AdvertisingTransmit(index) => {
+                if self.advertising.get() {
+                    self.state.set(State::AdvertisingListen(index));
+                    self.radio.receive_advertisement(advertising_channel(index));
+                    self.alarm.set_alarm(
+                        self.alarm.now(),
+                        self.alarm.ticks_from_us(ADVERTISING_LISTEN_US),
+                    );
+                } else {
+                    self.state.set(State::Idle);
+                }
+            }
+            State::AdvertisingRespond(index) => self.next_advertising_channel(index),
+            State::ConnectionTransmit => {
+                let mut connection = self.connection.get();
+                if let Some(reason) = connection.closing {
+                    self.close(reason);
+                    return;
+                }
+                self.next_event(&mut connection);
+                self.connection.set(connection);
+                self.schedule_event();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R, A> ble_advertising::RxClient for LinkLayer<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a> + BleConnectionConfig,
+    A: Alarm<'a>,
+{
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>) {
+        let len = len as usize;
+        if len < 2 || len > buf.len() || len != 2 + buf[1] as usize {
+            // Only the header is checked before listening again
+            match self.state.get() {
+                State::AdvertisingListen(index) => {
+                    self.radio.receive_advertisement(advertising_channel(index));
+                }
+                State::ConnectionListen => {
+                    let connection = self.connection.get();
+                    if let Some(channel) = RadioChannel::data_channel(connection.channel) {
+                        self.radio.receive_advertisement(channel);
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match self.state.get() {
+            State::AdvertisingListen(index) => {
+                if result.is_ok() {
+                    self.advertising_received(index, &buf[..len]);
+                } else {
+                    self.radio.receive_advertisement(advertising_channel(index));
+                }
+            }
+            State::ConnectionListen => {
+                let _ = self.alarm.disarm();
+                self.connection_received(&buf[..len], result.is_ok());
+            }
+            _ => {}
+        }
+    }
+}
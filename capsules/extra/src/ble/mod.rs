@@ -0,0 +1,14 @@
+//! BLE peripheral with a GATT server: a link layer for connections, L2CAP
+//! and the attribute protocol on top of it, and a syscall driver through
+//! which applications add services and characteristics.
+
+pub mod att;
+pub mod driver;
+pub mod gatt;
+pub mod l2cap;
+pub mod link_layer;
+
+#[cfg(test)]
+mod sim;
+#[cfg(test)]
+mod tests;
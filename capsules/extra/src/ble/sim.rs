@@ -0,0 +1,120 @@
+//! A simulated BLE radio, to test the link layer and the layers above it on
+//! the host.
+//!
+//! The radio records the packets sent and the channel listened on. A test
+//! completes each transmission and delivers packets while the radio listens,
+//! playing the central.
+
+use core::cell::{Cell, RefCell};
+use std::boxed::Box;
+use std::vec::Vec;
+
+use kernel::hil::ble_advertising::{
+    BleAdvertisementDriver, BleConnectionConfig, RadioChannel, RxClient, TxClient,
+};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+pub struct SimRadio<'a> {
+    rx_client: OptionalCell<&'a dyn RxClient>,
+    tx_client: OptionalCell<&'a dyn TxClient>,
+    access_address: Cell<u32>,
+    /// The channel listened on.
+    listening: Cell<Option<RadioChannel>>,
+    /// The buffer being sent, and its channel.
+    transmitting: TakeCell<'static, [u8]>,
+    channel: Cell<Option<RadioChannel>>,
+    /// Every packet sent, with its channel.
+    sent: RefCell<Vec<(RadioChannel, Vec<u8>)>>,
+}
+
+impl<'a> SimRadio<'a> {
+    pub fn new() -> SimRadio<'a> {
+        SimRadio {
+            rx_client: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            access_address: Cell::new(0),
+            listening: Cell::new(None),
+            transmitting: TakeCell::empty(),
+            channel: Cell::new(None),
+            sent: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn access_address(&self) -> u32 {
+        self.access_address.get()
+    }
+
+    pub fn listening(&self) -> Option<RadioChannel> {
+        self.listening.get()
+    }
+
+    /// The channel of the packet being sent.
+    pub fn transmitting(&self) -> Option<RadioChannel> {
+        self.transmitting.map_or(None, |_| self.channel.get())
+    }
+
+    /// Ends the transmission in progress, and returns the packet sent.
+    pub fn complete_transmit(&self) -> Option<Vec<u8>> {
+        let buf = self.transmitting.take()?;
+        let packet = self.sent.borrow().last().map(|(_, packet)| packet.clone());
+        self.tx_client
+            .map(|client| client.transmit_event(buf, Ok(())));
+        packet
+    }
+
+    /// Delivers `packet`, with a CRC error unless `crc_ok`, if the radio is
+    /// listening.
+    pub fn deliver(&self, packet: &[u8], crc_ok: bool) -> bool {
+        if self.listening.take().is_none() {
+            return false;
+        }
+        let buf = Box::leak(std::vec![0; 255].into_boxed_slice());
+        buf[..packet.len()].copy_from_slice(packet);
+        let result = if crc_ok { Ok(()) } else { Err(ErrorCode::FAIL) };
+        self.rx_client
+            .map(|client| client.receive_event(buf, packet.len() as u8, result));
+        true
+    }
+
+    /// Takes the packets sent so far.
+    pub fn take_sent(&self) -> Vec<(RadioChannel, Vec<u8>)> {
+        self.sent.replace(Vec::new())
+    }
+}
+
+impl<'a> BleAdvertisementDriver<'a> for SimRadio<'a> {
+    fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel) {
+        assert!(self.transmitting.is_none(), "transmission in progress");
+        self.listening.set(None);
+        self.sent.borrow_mut().push((channel, buf[..len].to_vec()));
+        self.channel.set(Some(channel));
+        self.transmitting.replace(buf);
+    }
+
+    fn receive_advertisement(&self, channel: RadioChannel) {
+        self.listening.set(Some(channel));
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        None
+    }
+
+    fn set_receive_client(&self, client: &'a dyn RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_transmit_client(&self, client: &'a dyn TxClient) {
+        self.tx_client.set(client);
+    }
+}
+
+impl BleConnectionConfig for SimRadio<'_> {
+    fn set_access_address(&self, access_address: u32, _crc_init: u32) {
+        self.access_address.set(access_address);
+    }
+
+    fn stop_receive(&self) {
+        self.listening.set(None);
+    }
+}
@@ -0,0 +1,871 @@
+//! Tests of the link layer, L2CAP and the GATT server over the simulated
+//! radio, with the test playing the central.
+
+use core::cell::RefCell;
+use std::boxed::Box;
+use std::vec::Vec;
+
+use kernel::hil::ble_advertising::{BleAdvertisementDriver, RadioChannel};
+use kernel::hil::time::{Alarm, Freq1MHz, Ticks, Time};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+use super::att::{self, error, opcode, Uuid};
+use super::gatt::{properties, Attribute, GattClient, GattServer, Value};
+use super::l2cap::{self, L2cap};
+use super::link_layer::{self, LinkLayer, LinkLayerClient};
+use super::sim::SimRadio;
+use crate::testing::alarm::MockAlarm;
+
+const ADDRESS: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0xc6];
+const CENTRAL: [u8; 6] = [0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6];
+const ACCESS_ADDRESS: u32 = 0x50654c17;
+
+fn leak_buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(std::vec![0; len].into_boxed_slice())
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Connected([u8; 6]),
+    Disconnected(u8),
+    Written(u16, Vec<u8>),
+    Notified(u16),
+}
+
+/// Records the events of the link layer and the GATT server, and passes
+/// those of the link layer on to L2CAP.
+struct Recorder {
+    l2cap: &'static L2cap<'static>,
+    events: RefCell<Vec<Event>>,
+}
+
+impl Recorder {
+    fn take(&self) -> Vec<Event> {
+        self.events.replace(Vec::new())
+    }
+}
+
+impl LinkLayerClient for Recorder {
+    fn connected(&self, peer: [u8; 6]) {
+        self.events.borrow_mut().push(Event::Connected(peer));
+        LinkLayerClient::connected(self.l2cap, peer);
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.events.borrow_mut().push(Event::Disconnected(reason));
+        LinkLayerClient::disconnected(self.l2cap, reason);
+    }
+
+    fn received(&self, start: bool, payload: &[u8]) {
+        self.l2cap.received(start, payload);
+    }
+
+    fn next_fragment(&self, fragment: &mut [u8]) -> Option<(bool, usize)> {
+        self.l2cap.next_fragment(fragment)
+    }
+}
+
+impl GattClient for Recorder {
+    fn connected(&self) {}
+
+    fn disconnected(&self) {}
+
+    fn written(&self, handle: u16, value: &[u8]) {
+        self.events
+            .borrow_mut()
+            .push(Event::Written(handle, value.to_vec()));
+    }
+
+    fn notified(&self, handle: u16) {
+        self.events.borrow_mut().push(Event::Notified(handle));
+    }
+}
+
+type SimLinkLayer = LinkLayer<'static, SimRadio<'static>, MockAlarm<'static, Freq1MHz>>;
+
+/// Parameters of the `CONNECT_IND`.
+#[derive(Copy, Clone)]
+struct Parameters {
+    window_size: u8,
+    window_offset: u16,
+    interval: u16,
+    timeout: u16,
+    channel_map: [u8; 5],
+    hop: u8,
+}
+
+const PARAMETERS: Parameters = Parameters {
+    window_size: 1,
+    window_offset: 0,
+    interval: 24,
+    timeout: 100,
+    channel_map: [0xff, 0xff, 0xff, 0xff, 0x1f],
+    hop: 7,
+};
+
+/// The handles of the database built by `stack`.
+const BATTERY_LEVEL: u16 = 3;
+const BATTERY_LEVEL_CONFIGURATION: u16 = 4;
+const CONTROL: u16 = 6;
+const NAME: u16 = 9;
+
+/// The peripheral, and a central driving it.
+struct Central {
+    radio: &'static SimRadio<'static>,
+    alarm: &'static MockAlarm<'static, Freq1MHz>,
+    link_layer: &'static SimLinkLayer,
+    gatt: &'static GattServer<'static>,
+    recorder: &'static Recorder,
+    sn: bool,
+    nesn: bool,
+}
+
+/// Builds the peripheral, with a battery service holding the battery level
+/// (read, notify) and a control point (write, write without response), and
+/// a service with a long readable name.
+fn stack() -> Central {
+    let radio = Box::leak(Box::new(SimRadio::new()));
+    let alarm = Box::leak(Box::new(MockAlarm::new()));
+    let link_layer: &'static SimLinkLayer = Box::leak(Box::new(LinkLayer::new(
+        radio,
+        alarm,
+        leak_buffer(link_layer::BUFFER_LEN),
+        ADDRESS,
+    )));
+    radio.set_transmit_client(link_layer);
+    radio.set_receive_client(link_layer);
+    alarm.set_alarm_client(link_layer);
+
+    let l2cap = Box::leak(Box::new(L2cap::new(
+        leak_buffer(l2cap::BUFFER_LEN),
+        leak_buffer(l2cap::BUFFER_LEN),
+    )));
+    let recorder = Box::leak(Box::new(Recorder {
+        l2cap,
+        events: RefCell::new(Vec::new()),
+    }));
+    link_layer.set_client(recorder);
+
+    let attributes: &'static [OptionalCell<Attribute>] = Box::leak(
+        (0..16)
+            .map(|_| OptionalCell::empty())
+            .collect::<Vec<_>>()
+            .into_boxed_slice(),
+    );
+    let values: &'static [Value] = Box::leak(
+        (0..4)
+            .map(|_| Value::default())
+            .collect::<Vec<_>>()
+            .into_boxed_slice(),
+    );
+    let gatt = Box::leak(Box::new(GattServer::new(
+        attributes,
+        values,
+        leak_buffer(att::MAX_MTU),
+    )));
+    l2cap.set_att_client(gatt);
+    gatt.set_client(recorder);
+
+    assert_eq!(gatt.add_service(Uuid::Uuid16(0x180f)), Ok(1));
+    assert_eq!(
+        gatt.add_characteristic(Uuid::Uuid16(0x2a19), properties::READ | properties::NOTIFY),
+        Ok(BATTERY_LEVEL)
+    );
+    assert_eq!(
+        gatt.add_characteristic(
+            Uuid::Uuid128([7; 16]),
+            properties::WRITE | properties::WRITE_WITHOUT_RESPONSE
+        ),
+        Ok(CONTROL)
+    );
+    assert_eq!(gatt.add_service(Uuid::Uuid16(0x1800)), Ok(7));
+    assert_eq!(
+        gatt.add_characteristic(Uuid::Uuid16(0x2a00), properties::READ),
+        Ok(NAME)
+    );
+    gatt.set_value(BATTERY_LEVEL, &[100]).unwrap();
+    gatt.set_value(NAME, b"A peripheral with a long name")
+        .unwrap();
+
+    Central {
+        radio,
+        alarm,
+        link_layer,
+        gatt,
+        recorder,
+        sn: false,
+        nesn: false,
+    }
+}
+
+/// The channels of the events of a connection, following channel selection
+/// algorithm #1.
+fn channels(channel_map: [u8; 5], hop: u8, count: usize) -> Vec<u8> {
+    let used: Vec<u8> = (0..37)
+        .filter(|c| channel_map[*c as usize / 8] & (1 << (c % 8)) != 0)
+        .collect();
+    let mut unmapped = 0;
+    (0..count)
+        .map(|_| {
+            unmapped = (unmapped + hop) % 37;
+            if used.contains(&unmapped) {
+                unmapped
+            } else {
+                used[(unmapped % used.len() as u8) as usize]
+            }
+        })
+        .collect()
+}
+
+fn connect_ind(parameters: Parameters) -> Vec<u8> {
+    let mut pdu = std::vec![0x05 | 0xc0, 34];
+    pdu.extend_from_slice(&CENTRAL);
+    pdu.extend_from_slice(&ADDRESS);
+    pdu.extend_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+    pdu.extend_from_slice(&[0x12, 0x34, 0x56]);
+    pdu.push(parameters.window_size);
+    pdu.extend_from_slice(&parameters.window_offset.to_le_bytes());
+    pdu.extend_from_slice(&parameters.interval.to_le_bytes());
+    pdu.extend_from_slice(&0u16.to_le_bytes());
+    pdu.extend_from_slice(&parameters.timeout.to_le_bytes());
+    pdu.extend_from_slice(&parameters.channel_map);
+    pdu.push(parameters.hop);
+    pdu
+}
+
+impl Central {
+    /// Starts advertising, and connects with `parameters` after the first
+    /// `ADV_IND`.
+    fn connect(&mut self, parameters: Parameters) {
+        self.link_layer.start_advertising(&[2, 1, 6], 100).unwrap();
+        assert!(self.alarm.fire());
+        self.radio.complete_transmit().unwrap();
+        assert!(self.radio.deliver(&connect_ind(parameters), true));
+        assert!(self.link_layer.is_connected());
+        assert_eq!(self.radio.access_address(), ACCESS_ADDRESS);
+        assert_eq!(self.recorder.take(), [Event::Connected(CENTRAL)]);
+    }
+
+    /// Fires the alarm until the peripheral listens on a data channel, and
+    /// returns the channel, or `None` if the connection was closed.
+    fn wait_event(&self) -> Option<RadioChannel> {
+        for _ in 0..100 {
+            if let Some(channel) = self.radio.listening() {
+                if channel.get_channel_index() < 37 {
+                    return Some(channel);
+                }
+            }
+            if !self.link_layer.is_connected() || !self.alarm.fire() {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Sends the data channel PDU of `llid` in the next connection event,
+    /// and returns the answer of the peripheral, if it answered.
+    fn try_exchange(&mut self, llid: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        self.wait_event().expect("no connection event");
+        let mut pdu = std::vec![llid, payload.len() as u8];
+        if self.nesn {
+            pdu[0] |= 1 << 2;
+        }
+        if self.sn {
+            pdu[0] |= 1 << 3;
+        }
+        pdu.extend_from_slice(payload);
+        assert!(self.radio.deliver(&pdu, true));
+        let answer = self.radio.complete_transmit()?;
+        self.radio.take_sent();
+
+        // The peripheral acknowledged the PDU sent
+        if (answer[0] & (1 << 2) != 0) != self.sn {
+            self.sn = !self.sn;
+        }
+        // A new PDU from the peripheral
+        if (answer[0] & (1 << 3) != 0) == self.nesn {
+            self.nesn = !self.nesn;
+        }
+        Some(answer)
+    }
+
+    fn exchange(&mut self, llid: u8, payload: &[u8]) -> Vec<u8> {
+        self.try_exchange(llid, payload).expect("no answer")
+    }
+
+    /// Exchanges empty PDUs until the peripheral sends a control PDU, and
+    /// returns its payload.
+    fn control(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut answer = self.exchange(0x3, payload);
+        for _ in 0..4 {
+            if answer[0] & 0x3 == 0x3 {
+                return answer[2..].to_vec();
+            }
+            answer = self.exchange(0x1, &[]);
+        }
+        panic!("no control PDU");
+    }
+
+    /// Sends the L2CAP PDU `payload` on channel `cid`, then exchanges PDUs
+    /// until the peripheral sent a complete L2CAP PDU, and returns its
+    /// channel and payload.
+    fn l2cap(&mut self, cid: u16, payload: &[u8]) -> (u16, Vec<u8>) {
+        let mut pdu = Vec::new();
+        pdu.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        pdu.extend_from_slice(&cid.to_le_bytes());
+        pdu.extend_from_slice(payload);
+
+        let mut received = Vec::new();
+        let mut fragments = pdu.chunks(link_layer::MAX_PAYLOAD_LEN);
+        for i in 0..20 {
+            let answer = match fragments.next() {
+                Some(fragment) => self.exchange(if i == 0 { 0x2 } else { 0x1 }, fragment),
+                None => self.exchange(0x1, &[]),
+            };
+            match answer[0] & 0x3 {
+                0x2 => received = answer[2..].to_vec(),
+                0x1 => received.extend_from_slice(&answer[2..]),
+                _ => {}
+            }
+            if received.len() >= 4 && received.len() == 4 + att::get_u16(&received, 0) as usize {
+                return (att::get_u16(&received, 2), received[4..].to_vec());
+            }
+        }
+        panic!("no L2CAP PDU");
+    }
+
+    /// Sends the ATT request `pdu`, and returns the response.
+    fn att(&mut self, pdu: &[u8]) -> Vec<u8> {
+        let (cid, response) = self.l2cap(l2cap::ATT_CID, pdu);
+        assert_eq!(cid, l2cap::ATT_CID);
+        response
+    }
+}
+
+// Link layer
+
+#[test]
+fn advertising_and_scan_response() {
+    let central = stack();
+    central
+        .link_layer
+        .start_advertising(&[2, 1, 6], 100)
+        .unwrap();
+    assert_eq!(
+        central.link_layer.start_advertising(&[0; 32], 100),
+        Err(ErrorCode::SIZE)
+    );
+
+    let mut scan_req = std::vec![0x03 | 0xc0, 12];
+    scan_req.extend_from_slice(&CENTRAL);
+    scan_req.extend_from_slice(&ADDRESS);
+
+    for channel in [
+        RadioChannel::AdvertisingChannel37,
+        RadioChannel::AdvertisingChannel38,
+        RadioChannel::AdvertisingChannel39,
+    ] {
+        if channel == RadioChannel::AdvertisingChannel37 {
+            assert!(central.alarm.fire());
+        }
+        assert_eq!(central.radio.transmitting(), Some(channel));
+        let adv_ind = central.radio.complete_transmit().unwrap();
+        assert_eq!(adv_ind[0], 0x40);
+        assert_eq!(adv_ind[1], 9);
+        assert_eq!(&adv_ind[2..8], &ADDRESS);
+        assert_eq!(&adv_ind[8..], &[2, 1, 6]);
+        assert_eq!(central.radio.listening(), Some(channel));
+
+        if channel == RadioChannel::AdvertisingChannel38 {
+            // A request for another device is ignored
+            let mut other = scan_req.clone();
+            other[8] ^= 1;
+            assert!(central.radio.deliver(&other, true));
+            assert_eq!(central.radio.transmitting(), None);
+            assert!(central.radio.deliver(&scan_req, true));
+            assert_eq!(central.radio.transmitting(), Some(channel));
+            let scan_rsp = central.radio.complete_transmit().unwrap();
+            assert_eq!(scan_rsp, [0x44, 6, 0x11, 0x22, 0x33, 0x44, 0x55, 0xc6]);
+        } else {
+            // Nothing is received before the end of the listening window
+            assert!(central.alarm.fire());
+        }
+    }
+
+    // The next event is one interval later, plus a delay of up to 10 ms
+    let remaining = central.alarm.remaining().unwrap();
+    assert!((100_000..110_000).contains(&remaining));
+    assert!(central.alarm.fire());
+    assert_eq!(
+        central.radio.transmitting(),
+        Some(RadioChannel::AdvertisingChannel37)
+    );
+    central.radio.complete_transmit();
+
+    central.link_layer.stop_advertising();
+    assert_eq!(central.radio.listening(), None);
+    assert!(!central.alarm.is_armed());
+}
+
+#[test]
+fn connection_events_follow_channel_selection() {
+    let mut central = stack();
+    // Some channels are not used, to check the remapping
+    let parameters = Parameters {
+        channel_map: [0xf0, 0x0f, 0xff, 0x00, 0x1f],
+        hop: 9,
+        ..PARAMETERS
+    };
+    central.connect(parameters);
+
+    let expected = channels(parameters.channel_map, parameters.hop, 12);
+    let mut anchor = 0;
+    for (i, expected) in expected.iter().enumerate() {
+        let channel = central.wait_event().unwrap();
+        assert_eq!(channel.get_channel_index() as u8, *expected);
+        let now = central.alarm.now().into_u32();
+        if i > 0 {
+            // Listening starts a little before the anchor point
+            assert!(now < anchor + 30_000 && now + 400 > anchor + 30_000);
+        }
+        anchor = now;
+        central.exchange(0x1, &[]);
+    }
+}
+
+#[test]
+fn acknowledgement_and_retransmission() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    // An empty PDU is acknowledged by an empty PDU
+    let answer = central.exchange(0x1, &[]);
+    assert_eq!(answer, [0x1 | 1 << 2, 0]);
+    assert!(central.sn);
+    assert!(central.nesn);
+
+    // A read request, answered at once. It acknowledges the empty PDU.
+    let mut read = std::vec![0x2 | 1 << 2 | 1 << 3, 7, 3, 0, 4, 0, opcode::READ_REQ];
+    read.extend_from_slice(&BATTERY_LEVEL.to_le_bytes());
+    central.wait_event().unwrap();
+    central.radio.deliver(&read, true);
+    let first = central.radio.complete_transmit().unwrap();
+    assert_eq!(first[0], 0x2 | 1 << 3);
+    assert_eq!(&first[2..], &[2, 0, 4, 0, opcode::READ_RSP, 100]);
+
+    // The central does not acknowledge the answer, as its NESN is still the
+    // SN of the answer, so it is sent again
+    central.wait_event().unwrap();
+    central.radio.deliver(&[0x1 | 1 << 2, 0], true);
+    let second = central.radio.complete_transmit().unwrap();
+    assert_eq!(second[0], 0x2 | 1 << 2 | 1 << 3);
+    assert_eq!(&second[1..], &first[1..]);
+
+    // A PDU with a CRC error acknowledges nothing
+    central.wait_event().unwrap();
+    central.radio.deliver(&[0x1, 0], false);
+    let third = central.radio.complete_transmit().unwrap();
+    assert_eq!(third, second);
+
+    // Once acknowledged, the next PDU is empty
+    central.wait_event().unwrap();
+    central.radio.deliver(&[0x1 | 1 << 3, 0], true);
+    let fourth = central.radio.complete_transmit().unwrap();
+    assert_eq!(fourth, [0x1, 0]);
+}
+
+#[test]
+fn control_procedures() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    let features = central.control(&[0x08, 0xff, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(features, [0x09, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+
+    let version = central.control(&[0x0c, 0x0c, 0x59, 0x00, 0x01, 0x00]);
+    assert_eq!(version, [0x0c, 0x0c, 0xff, 0xff, 0, 0]);
+
+    let ping = central.control(&[0x12]);
+    assert_eq!(ping, [0x13]);
+
+    let length = central.control(&[0x14, 251, 0, 0x48, 0x08, 251, 0, 0x48, 0x08]);
+    assert_eq!(length, [0x15, 27, 0, 0x48, 0x01, 27, 0, 0x48, 0x01]);
+
+    let unknown = central.control(&[0x30, 1, 2]);
+    assert_eq!(unknown, [0x07, 0x30]);
+}
+
+#[test]
+fn connection_update() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+    central.exchange(0x1, &[]);
+    let instant: u16 = 4;
+
+    // From the instant, events are every 50 ms, after a window offset of
+    // 2.5 ms
+    let mut update = std::vec![0x00, 2];
+    update.extend_from_slice(&2u16.to_le_bytes());
+    update.extend_from_slice(&40u16.to_le_bytes());
+    update.extend_from_slice(&0u16.to_le_bytes());
+    update.extend_from_slice(&200u16.to_le_bytes());
+    update.extend_from_slice(&instant.to_le_bytes());
+    central.exchange(0x3, &update);
+
+    let mut times = Vec::new();
+    for _ in 0..5 {
+        central.wait_event().unwrap();
+        times.push(central.alarm.now().into_u32());
+        central.exchange(0x1, &[]);
+    }
+    // Events 2 and 3 use the old interval, and the instant is event 4
+    assert!(times[1] - times[0] > 29_000 && times[1] - times[0] < 31_000);
+    assert!(times[2] - times[1] > 30_000 && times[2] - times[1] < 34_000);
+    assert!(times[3] - times[2] > 49_000 && times[3] - times[2] < 51_000);
+}
+
+#[test]
+fn channel_map_update() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+    central.exchange(0x1, &[]);
+
+    // Only channels 0 to 7 are used from event 3
+    let new_map = [0xff, 0, 0, 0, 0];
+    let mut update = std::vec![0x01];
+    update.extend_from_slice(&new_map);
+    update.extend_from_slice(&3u16.to_le_bytes());
+    central.exchange(0x3, &update);
+
+    let old = channels(PARAMETERS.channel_map, PARAMETERS.hop, 8);
+    let new = channels(new_map, PARAMETERS.hop, 8);
+    assert_ne!(old[3], new[3]);
+    central.exchange(0x1, &[]);
+    for event in 3..8 {
+        let channel = central.wait_event().unwrap();
+        assert_eq!(channel.get_channel_index() as u8, new[event]);
+        central.exchange(0x1, &[]);
+    }
+}
+
+#[test]
+fn supervision_timeout() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+    central.exchange(0x1, &[]);
+    let last = central.alarm.now().into_u32();
+
+    // Nothing is received any more
+    while central.link_layer.is_connected() {
+        assert!(central.alarm.fire());
+    }
+    let elapsed = central.alarm.now().into_u32() - last;
+    assert!((1_000_000..1_040_000).contains(&elapsed));
+    assert_eq!(
+        central.recorder.take(),
+        [Event::Disconnected(link_layer::CONNECTION_TIMEOUT)]
+    );
+
+    // Advertising starts again
+    assert!(central.alarm.fire());
+    assert_eq!(
+        central.radio.transmitting(),
+        Some(RadioChannel::AdvertisingChannel37)
+    );
+}
+
+#[test]
+fn connection_fails_to_establish() {
+    let central = stack();
+    central.link_layer.start_advertising(&[], 100).unwrap();
+    central.link_layer.stop_advertising();
+    central.link_layer.start_advertising(&[], 100).unwrap();
+    assert!(central.alarm.fire());
+    central.radio.complete_transmit().unwrap();
+    central.radio.deliver(&connect_ind(PARAMETERS), true);
+    central.recorder.take();
+
+    let mut events = 0;
+    while central.link_layer.is_connected() {
+        if central.radio.listening().is_some() {
+            events += 1;
+        }
+        assert!(central.alarm.fire());
+    }
+    assert_eq!(events, 6);
+    assert_eq!(
+        central.recorder.take(),
+        [Event::Disconnected(link_layer::FAILED_TO_ESTABLISH)]
+    );
+}
+
+#[test]
+fn terminate() {
+    // By the central, once the peripheral acknowledged
+    let mut central = stack();
+    central.connect(PARAMETERS);
+    central.exchange(0x1, &[]);
+    central.exchange(0x3, &[0x02, 0x13]);
+    assert!(!central.link_layer.is_connected());
+    assert_eq!(central.recorder.take(), [Event::Disconnected(0x13)]);
+
+    // By the peripheral, once the central acknowledged
+    let mut central = stack();
+    central.connect(PARAMETERS);
+    assert_eq!(central.link_layer.disconnect(), Ok(()));
+    assert_eq!(central.link_layer.disconnect(), Err(ErrorCode::BUSY));
+    let answer = central.exchange(0x1, &[]);
+    assert_eq!(answer[0] & 0x3, 0x3);
+    assert_eq!(&answer[2..], &[0x02, 0x13]);
+    assert!(central.link_layer.is_connected());
+    assert_eq!(central.try_exchange(0x1, &[]), None);
+    assert!(!central.link_layer.is_connected());
+    assert_eq!(
+        central.recorder.take(),
+        [Event::Disconnected(link_layer::LOCAL_HOST_TERMINATED)]
+    );
+    assert_eq!(central.link_layer.disconnect(), Err(ErrorCode::OFF));
+}
+
+// L2CAP
+
+#[test]
+fn signaling_and_pairing_rejected() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    // Connection parameter update request
+    let (cid, reject) = central.l2cap(
+        l2cap::SIGNALING_CID,
+        &[0x12, 7, 8, 0, 6, 0, 12, 0, 0, 0, 200, 0],
+    );
+    assert_eq!(cid, l2cap::SIGNALING_CID);
+    assert_eq!(reject, [0x01, 7, 2, 0, 0, 0]);
+
+    let (cid, failed) = central.l2cap(l2cap::SMP_CID, &[0x01, 3, 0, 1, 16, 7, 7]);
+    assert_eq!(cid, l2cap::SMP_CID);
+    assert_eq!(failed, [0x05, 0x05]);
+}
+
+#[test]
+fn long_pdus_are_fragmented() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    let mtu = central.att(&[opcode::EXCHANGE_MTU_REQ, 100, 0]);
+    assert_eq!(mtu, [opcode::EXCHANGE_MTU_RSP, att::MAX_MTU as u8, 0]);
+
+    // A write of 32 bytes takes two data PDUs
+    let mut write = std::vec![opcode::WRITE_REQ];
+    write.extend_from_slice(&CONTROL.to_le_bytes());
+    write.extend_from_slice(&[0x5a; 32]);
+    assert_eq!(central.att(&write), [opcode::WRITE_RSP]);
+    assert_eq!(
+        central.recorder.take(),
+        [Event::Written(CONTROL, std::vec![0x5a; 32])]
+    );
+
+    // So does the whole name, read at once with the larger MTU
+    let mut read = std::vec![opcode::READ_REQ];
+    read.extend_from_slice(&NAME.to_le_bytes());
+    let name = central.att(&read);
+    assert_eq!(name[0], opcode::READ_RSP);
+    assert_eq!(&name[1..], b"A peripheral with a long name");
+}
+
+// GATT
+
+#[test]
+fn service_discovery() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    let services = central.att(&[opcode::READ_BY_GROUP_TYPE_REQ, 1, 0, 0xff, 0xff, 0x00, 0x28]);
+    assert_eq!(
+        services,
+        [
+            opcode::READ_BY_GROUP_TYPE_RSP,
+            6,
+            1,
+            0,
+            6,
+            0,
+            0x0f,
+            0x18,
+            7,
+            0,
+            9,
+            0,
+            0x00,
+            0x18
+        ]
+    );
+    let end = central.att(&[
+        opcode::READ_BY_GROUP_TYPE_REQ,
+        10,
+        0,
+        0xff,
+        0xff,
+        0x00,
+        0x28,
+    ]);
+    assert_eq!(
+        end,
+        [
+            opcode::ERROR_RSP,
+            opcode::READ_BY_GROUP_TYPE_REQ,
+            10,
+            0,
+            error::ATTRIBUTE_NOT_FOUND
+        ]
+    );
+    let unsupported = central.att(&[opcode::READ_BY_GROUP_TYPE_REQ, 1, 0, 0xff, 0xff, 0x03, 0x28]);
+    assert_eq!(unsupported[4], error::UNSUPPORTED_GROUP_TYPE);
+
+    let by_value = central.att(&[
+        opcode::FIND_BY_TYPE_VALUE_REQ,
+        1,
+        0,
+        0xff,
+        0xff,
+        0x00,
+        0x28,
+        0x00,
+        0x18,
+    ]);
+    assert_eq!(by_value, [opcode::FIND_BY_TYPE_VALUE_RSP, 7, 0, 9, 0]);
+
+    // Characteristic declarations: properties, value handle and UUID. The
+    // first response stops before the declaration with a 128-bit UUID.
+    let characteristics = central.att(&[opcode::READ_BY_TYPE_REQ, 1, 0, 6, 0, 0x03, 0x28]);
+    assert_eq!(
+        characteristics,
+        [opcode::READ_BY_TYPE_RSP, 7, 2, 0, 0x12, 3, 0, 0x19, 0x2a]
+    );
+    let characteristics = central.att(&[opcode::READ_BY_TYPE_REQ, 3, 0, 6, 0, 0x03, 0x28]);
+    assert_eq!(
+        &characteristics[..7],
+        &[opcode::READ_BY_TYPE_RSP, 21, 5, 0, 0x0c, 6, 0]
+    );
+    assert_eq!(&characteristics[7..], &[7; 16]);
+
+    let descriptors = central.att(&[opcode::FIND_INFORMATION_REQ, 4, 0, 4, 0]);
+    assert_eq!(
+        descriptors,
+        [opcode::FIND_INFORMATION_RSP, 1, 4, 0, 0x02, 0x29]
+    );
+
+    let invalid = central.att(&[opcode::FIND_INFORMATION_REQ, 0, 0, 4, 0]);
+    assert_eq!(
+        invalid,
+        [
+            opcode::ERROR_RSP,
+            opcode::FIND_INFORMATION_REQ,
+            0,
+            0,
+            error::INVALID_HANDLE
+        ]
+    );
+}
+
+#[test]
+fn read_and_read_blob() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    let mut read = std::vec![opcode::READ_REQ];
+    read.extend_from_slice(&NAME.to_le_bytes());
+    let name = central.att(&read);
+    assert_eq!(&name[1..], b"A peripheral with a lo");
+
+    let mut blob = std::vec![opcode::READ_BLOB_REQ];
+    blob.extend_from_slice(&NAME.to_le_bytes());
+    blob.extend_from_slice(&22u16.to_le_bytes());
+    let rest = central.att(&blob);
+    assert_eq!(rest[0], opcode::READ_BLOB_RSP);
+    assert_eq!(&rest[1..], b"ng name");
+
+    let mut past = std::vec![opcode::READ_BLOB_REQ];
+    past.extend_from_slice(&NAME.to_le_bytes());
+    past.extend_from_slice(&40u16.to_le_bytes());
+    assert_eq!(central.att(&past)[4], error::INVALID_OFFSET);
+
+    let mut not_readable = std::vec![opcode::READ_REQ];
+    not_readable.extend_from_slice(&CONTROL.to_le_bytes());
+    assert_eq!(central.att(&not_readable)[4], error::READ_NOT_PERMITTED);
+
+    assert_eq!(
+        central.att(&[opcode::READ_REQ, 99, 0])[4],
+        error::INVALID_HANDLE
+    );
+    assert_eq!(central.att(&[0x20, 1, 0])[4], error::REQUEST_NOT_SUPPORTED);
+}
+
+#[test]
+fn write_and_notify() {
+    let mut central = stack();
+    central.connect(PARAMETERS);
+
+    // Notifications are off until the central enables them
+    assert_eq!(central.gatt.notify(BATTERY_LEVEL), Err(ErrorCode::OFF));
+    assert_eq!(central.gatt.notify(CONTROL), Err(ErrorCode::INVAL));
+    let mut enable = std::vec![opcode::WRITE_REQ];
+    enable.extend_from_slice(&BATTERY_LEVEL_CONFIGURATION.to_le_bytes());
+    enable.extend_from_slice(&[1, 0]);
+    assert_eq!(central.att(&enable), [opcode::WRITE_RSP]);
+
+    central.gatt.set_value(BATTERY_LEVEL, &[42]).unwrap();
+    assert_eq!(central.gatt.notify(BATTERY_LEVEL), Ok(()));
+    assert_eq!(central.gatt.notify(BATTERY_LEVEL), Err(ErrorCode::BUSY));
+    let mut notification = Vec::new();
+    for _ in 0..3 {
+        let answer = central.exchange(0x1, &[]);
+        if answer[1] > 0 {
+            notification = answer[2..].to_vec();
+            break;
+        }
+    }
+    assert_eq!(
+        notification,
+        [4, 0, 4, 0, opcode::HANDLE_VALUE_NTF, 3, 0, 42]
+    );
+    assert_eq!(central.recorder.take(), [Event::Notified(BATTERY_LEVEL)]);
+
+    // A write command has no response
+    let mut command = std::vec![0x2, 8, 4, 0, 4, 0, opcode::WRITE_CMD];
+    command.extend_from_slice(&CONTROL.to_le_bytes());
+    command.push(9);
+    let answer = central.exchange(command[0], &command[2..]);
+    assert_eq!(answer[1], 0);
+    let answer = central.exchange(0x1, &[]);
+    assert_eq!(answer[1], 0);
+    assert_eq!(
+        central.recorder.take(),
+        [Event::Written(CONTROL, std::vec![9])]
+    );
+
+    let mut not_writable = std::vec![opcode::WRITE_REQ];
+    not_writable.extend_from_slice(&BATTERY_LEVEL.to_le_bytes());
+    not_writable.push(1);
+    assert_eq!(central.att(&not_writable)[4], error::WRITE_NOT_PERMITTED);
+
+    // Configurations are cleared by a new connection
+    central.exchange(0x3, &[0x02, 0x13]);
+    let central = Central {
+        sn: false,
+        nesn: false,
+        ..central
+    };
+    central.link_layer.start_advertising(&[], 100).unwrap();
+    central.alarm.advance(200_000);
+    central.radio.take_sent();
+    while central.radio.transmitting().is_some() || central.radio.listening().is_none() {
+        if central.radio.complete_transmit().is_none() {
+            assert!(central.alarm.fire());
+        }
+    }
+    assert!(central.radio.deliver(&connect_ind(PARAMETERS), true));
+    assert!(central.gatt.is_connected());
+    assert_eq!(central.gatt.notify(BATTERY_LEVEL), Err(ErrorCode::OFF));
+}
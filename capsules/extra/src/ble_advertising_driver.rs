@@ -7,15 +7,29 @@
 //! driver but processes can request an advertising or scanning interval.
 //! Processes can also control the TX power used for their advertisements.
 //!
+//! Scannable advertisements (`ADV_IND` and `ADV_SCAN_IND`) are followed by a
+//! short listening window on the same channel, and a scan request for the
+//! process's address is answered with the scan response data it provided.
+//! Scanning is either passive, or active: a scannable advertisement passing the
+//! filters gets a scan request, and the scan response is delivered as well.
+//!
+//! Processes can filter the packets they are woken for when scanning, by
+//! advertiser address, by an AD type the advertising data must contain, or by
+//! a minimum signal strength.
+//!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
 //! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
 //!
 //! ### Allow system calls
 //!
-//! There is one ReadWrite and one ReadOnly allow buffers, both at index `0`.
+//! There is one ReadWrite and three ReadOnly allow buffers.
 //!
-//! * ReadOnly: Advertising data, containing the full _payload_ (i.e. excluding the header) the
-//!             process wishes to advertise.
+//! * ReadOnly 0: Advertising data, containing the full _payload_ (i.e. excluding the header) the
+//!               process wishes to advertise.
+//! * ReadOnly 1: Scan response data, sent in reply to scan requests for scannable
+//!               advertisements.
+//! * ReadOnly 2: Addresses to accept when scanning, 6 bytes each. When empty, packets from any
+//!               address are accepted.
 //! * ReadWrite: Passive scanning buffer, which is populated during BLE scans with complete (i.e.
 //!              including headers) advertising packets received on channels 37, 38 and 39.
 //!
@@ -34,7 +48,9 @@
 //!  The `subscribe` is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. The arguments
+//!      are the status, the length of the packet and its signal strength in
+//!      dBm, as a signed integer, or 0 if the radio does not measure it.
 //!
 //! The possible return codes from the `allow` system call indicate the following:
 //!
@@ -49,7 +65,13 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
-//! * 5: start scanning
+//! * 2: set the transmitting power
+//! * 5: start passive scanning
+//! * 6: start active scanning
+//! * 7: only accept packets whose advertising data contains an AD type, or
+//!      any packet if 0
+//! * 8: only accept packets received at least at a signal strength in dBm, or
+//!      any packet if 0
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -100,6 +122,12 @@
 // This means that advertising events can collide. In this case, we just defer one of the
 // advertisements. Because we add a pseudo random pad to the timer interval each time (as required
 // by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
+//
+// Listening for scan requests after an advertisement, or for a scan response after a scan
+// request, reuses the app's timer: it is set to the end of the listening window, and firing in
+// one of the listening states moves on to the next channel. Starting the radio again resets it,
+// so a packet arriving too late is never reported. The interframe space of the specification
+// (150 µs) is not met exactly, since replies are sent from the radio callbacks.
 
 use core::cell::Cell;
 use core::cmp;
@@ -122,8 +150,10 @@ pub const DRIVER_NUM: usize = driver::NUM::BleAdvertising as usize;
 /// Ids for read-only allow buffers
 mod ro_allow {
     pub const ADV_DATA: usize = 0;
+    pub const SCAN_RSP_DATA: usize = 1;
+    pub const FILTER_ADDRESSES: usize = 2;
     /// The number of allow buffers the kernel stores for this grant
-    pub const COUNT: u8 = 1;
+    pub const COUNT: u8 = 3;
 }
 
 /// Ids for read-write allow buffers
@@ -136,14 +166,23 @@ mod rw_allow {
 const PACKET_ADDR_LEN: usize = 6;
 pub const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const ADV_HEADER_RXADD_OFFSET: usize = 7;
+const SCAN_REQ_PAYLOAD_LEN: usize = 2 * PACKET_ADDR_LEN;
+
+/// How long to listen for a scan request or a scan response.
+const LISTEN_WINDOW_US: u32 = 600;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
     Idle,
     ScanningIdle,
     Scanning(RadioChannel),
+    ScanRequesting(RadioChannel),
+    AwaitingScanResponse(RadioChannel),
     AdvertisingIdle,
     Advertising(RadioChannel),
+    AdvertisingListen(RadioChannel),
+    ScanResponding(RadioChannel),
 }
 
 #[derive(Copy, Clone)]
@@ -172,14 +211,75 @@ const ADV_IND: AdvPduType = 0b0000;
 #[allow(dead_code)]
 const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
-#[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
 
+// The channel following `channel` in an advertising or scanning event.
+fn next_channel(channel: RadioChannel) -> Option<RadioChannel> {
+    match channel {
+        RadioChannel::AdvertisingChannel37 => Some(RadioChannel::AdvertisingChannel38),
+        RadioChannel::AdvertisingChannel38 => Some(RadioChannel::AdvertisingChannel39),
+        _ => None,
+    }
+}
+
+// The advertising data of `packet`, or an empty slice for PDUs that carry none.
+fn advertising_data(packet: &[u8]) -> &[u8] {
+    match packet {
+        [header, len, ..] => match header & 0xf {
+            ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RESP => {
+                let end = cmp::min(packet.len(), *len as usize + 2);
+                packet.get(2 + PACKET_ADDR_LEN..end).unwrap_or(&[])
+            }
+            _ => &[],
+        },
+        _ => &[],
+    }
+}
+
+// Whether the AD structures in `data` include one of type `ad_type`.
+//
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part C], section 11
+fn has_ad_type(data: &[u8], ad_type: u8) -> bool {
+    let mut offset = 0;
+    while offset + 1 < data.len() {
+        let len = data[offset] as usize;
+        if len == 0 {
+            break;
+        }
+        if data[offset + 1] == ad_type {
+            return true;
+        }
+        offset += len + 1;
+    }
+    false
+}
+
+/// Criteria packets must meet for an app to be woken while scanning, besides
+/// the addresses it allows.
+#[derive(Copy, Clone, Default)]
+struct Filter {
+    ad_type: Option<u8>,
+    rssi_threshold: Option<i8>,
+}
+
+impl Filter {
+    // Packets whose signal strength is unknown are accepted.
+    fn accepts(&self, packet: &[u8], rssi: Option<i8>) -> bool {
+        let strong_enough = match (self.rssi_threshold, rssi) {
+            (Some(threshold), Some(rssi)) => rssi >= threshold,
+            _ => true,
+        };
+        strong_enough
+            && self.ad_type.map_or(true, |ad_type| {
+                has_ad_type(advertising_data(packet), ad_type)
+            })
+    }
+}
+
 /// Process specific memory
 pub struct App {
     process_status: Option<BLEState>,
@@ -190,6 +290,13 @@ pub struct App {
     pdu_type: AdvPduType,
     advertisement_interval_ms: u32,
     tx_power: u8,
+
+    // Scanning meta-data
+    active_scanning: bool,
+    filter: Filter,
+    /// The advertiser a scan request was sent to.
+    scan_target: [u8; PACKET_ADDR_LEN],
+
     /// The state of an app-specific pseudo random number.
     ///
     /// For example, it can be used for the pseudo-random `advDelay` parameter.
@@ -207,6 +314,9 @@ impl Default for App {
             process_status: Some(BLEState::Idle),
             tx_power: 0,
             advertisement_interval_ms: 200,
+            active_scanning: false,
+            filter: Filter::default(),
+            scan_target: [0; PACKET_ADDR_LEN],
             // Just use any non-zero starting value by default
             random_nonce: 0xdeadbeef,
         }
@@ -249,6 +359,53 @@ impl App {
         ble: &BLE<'a, B, A>,
         channel: RadioChannel,
     ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        let pdu_type = self.pdu_type;
+        self.send_pdu(
+            processid,
+            kernel_data,
+            ble,
+            pdu_type,
+            ro_allow::ADV_DATA,
+            channel,
+        )
+    }
+
+    fn send_scan_response<'a, B, A>(
+        &mut self,
+        processid: kernel::ProcessId,
+        kernel_data: &GrantKernelData,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        self.send_pdu(
+            processid,
+            kernel_data,
+            ble,
+            SCAN_RESP,
+            ro_allow::SCAN_RSP_DATA,
+            channel,
+        )
+    }
+
+    // Sends a PDU made of the app's address followed by the data in the
+    // read-only allow buffer `data_allow`.
+    fn send_pdu<'a, B, A>(
+        &mut self,
+        processid: kernel::ProcessId,
+        kernel_data: &GrantKernelData,
+        ble: &BLE<'a, B, A>,
+        pdu_type: AdvPduType,
+        data_allow: usize,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
     where
         B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
         A: kernel::hil::time::Alarm<'a>,
@@ -256,7 +413,7 @@ impl App {
         // Ensure we have an address set before advertisement
         self.generate_random_address(processid)?;
         kernel_data
-            .get_readonly_processbuffer(ro_allow::ADV_DATA)
+            .get_readonly_processbuffer(data_allow)
             .and_then(|adv_data| {
                 adv_data.enter(|adv_data| {
                     ble.kernel_tx
@@ -269,9 +426,9 @@ impl App {
                             let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
                             {
                                 let (header, payload) = kernel_tx.split_at_mut(2);
-                                header[0] = self.pdu_type;
-                                match self.pdu_type {
-                                    ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
+                                header[0] = pdu_type;
+                                match pdu_type {
+                                    ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RESP => {
                                         // Set TxAdd because AdvA field is going to be a "random"
                                         // address
                                         header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
@@ -295,6 +452,34 @@ impl App {
             .unwrap_or(Err(ErrorCode::FAIL))
     }
 
+    // Sends a scan request to the advertiser `adva`, whose address is random
+    // if `random_adva`. The scan request carries the app's address.
+    fn send_scan_request<'a, B, A>(
+        &self,
+        ble: &BLE<'a, B, A>,
+        adva: &[u8],
+        random_adva: bool,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        ble.kernel_tx
+            .take()
+            .map_or(Err(ErrorCode::FAIL), |kernel_tx| {
+                kernel_tx[0] = SCAN_REQ
+                    | 1 << ADV_HEADER_TXADD_OFFSET
+                    | (random_adva as u8) << ADV_HEADER_RXADD_OFFSET;
+                kernel_tx[1] = SCAN_REQ_PAYLOAD_LEN as u8;
+                kernel_tx[2..2 + PACKET_ADDR_LEN].copy_from_slice(&self.address);
+                kernel_tx[2 + PACKET_ADDR_LEN..2 + SCAN_REQ_PAYLOAD_LEN].copy_from_slice(adva);
+                ble.radio
+                    .transmit_advertisement(kernel_tx, 2 + SCAN_REQ_PAYLOAD_LEN, channel);
+                Ok(())
+            })
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -316,6 +501,29 @@ impl App {
         let period_ms = (self.advertisement_interval_ms + nonce) * F::frequency() / 1000;
         self.alarm_data.expiration = Expiration::Enabled(now, period_ms);
     }
+
+    // Set the alarm for this app to the end of a listening window starting
+    // now.
+    fn set_listen_alarm<F: Frequency>(&mut self, now: u32) {
+        let window = LISTEN_WINDOW_US as u64 * F::frequency() as u64 / 1_000_000;
+        self.alarm_data.expiration = Expiration::Enabled(now, cmp::max(1, window as u32));
+    }
+
+    // Whether the scan request in `packet` is for this app's advertisement.
+    fn is_scan_request_for(&self, packet: &[u8]) -> bool {
+        packet.len() == 2 + SCAN_REQ_PAYLOAD_LEN
+            && packet[0] & 0xf == SCAN_REQ
+            && packet[0] & (1 << ADV_HEADER_RXADD_OFFSET) != 0
+            && packet[1] as usize == SCAN_REQ_PAYLOAD_LEN
+            && packet[2 + PACKET_ADDR_LEN..] == self.address
+    }
+
+    // Whether `packet` is the scan response to this app's scan request.
+    fn is_scan_response_for(&self, packet: &[u8]) -> bool {
+        packet.len() >= 2 + PACKET_ADDR_LEN
+            && packet[0] & 0xf == SCAN_RESP
+            && packet[2..2 + PACKET_ADDR_LEN] == self.scan_target
+    }
 }
 
 pub struct BLE<'a, B, A>
@@ -395,6 +603,101 @@ where
                 .set_alarm(A::Ticks::from(next_ref), A::Ticks::from(next_dt));
         }
     }
+
+    // Advertises on the channel following `channel`, or ends the advertising
+    // event.
+    fn continue_advertising(
+        &self,
+        processid: ProcessId,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+        channel: RadioChannel,
+    ) {
+        match next_channel(channel) {
+            Some(channel) => {
+                app.process_status = Some(BLEState::Advertising(channel));
+                self.sending_app.set(processid);
+                let _ = app.send_advertisement(processid, kernel_data, &self, channel);
+            }
+            None => {
+                self.busy.set(false);
+                app.process_status = Some(BLEState::AdvertisingIdle);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+            }
+        }
+    }
+
+    // Scans on the channel following `channel`, or ends the scanning event.
+    fn continue_scanning(&self, processid: ProcessId, app: &mut App, channel: RadioChannel) {
+        match next_channel(channel) {
+            Some(channel) => {
+                app.process_status = Some(BLEState::Scanning(channel));
+                self.receiving_app.set(processid);
+                self.radio.receive_advertisement(channel);
+            }
+            None => {
+                self.busy.set(false);
+                app.process_status = Some(BLEState::ScanningIdle);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+            }
+        }
+    }
+
+    // Listens on `channel` until the app's listening window ends.
+    fn listen(&self, processid: ProcessId, app: &mut App, channel: RadioChannel) {
+        self.receiving_app.set(processid);
+        app.set_listen_alarm::<A::Frequency>(self.alarm.now().into_u32());
+        self.radio.receive_advertisement(channel);
+    }
+
+    // Copies `packet` to the app's scan buffer and notifies it.
+    fn deliver(&self, kernel_data: &GrantKernelData, packet: &[u8]) {
+        let success = kernel_data
+            .get_readwrite_processbuffer(rw_allow::SCAN_BUFFER)
+            .and_then(|scan_buffer| {
+                scan_buffer.mut_enter(|userland| {
+                    userland
+                        .get(0..packet.len())
+                        .map_or(false, |dest| dest.copy_from_slice_or_err(packet).is_ok())
+                })
+            })
+            .unwrap_or(false);
+
+        if success {
+            let rssi = self.radio.rssi().map_or(0, |rssi| rssi as i32 as usize);
+            kernel_data
+                .schedule_upcall(
+                    0,
+                    (
+                        kernel::errorcode::into_statuscode(Ok(())),
+                        packet.len(),
+                        rssi,
+                    ),
+                )
+                .ok();
+        }
+    }
+}
+
+// Whether the advertiser of `packet` is in the app's address list, or the list
+// is empty.
+fn address_listed(kernel_data: &GrantKernelData, packet: &[u8]) -> bool {
+    let adva = match packet.get(2..2 + PACKET_ADDR_LEN) {
+        Some(adva) => adva,
+        None => return false,
+    };
+    kernel_data
+        .get_readonly_processbuffer(ro_allow::FILTER_ADDRESSES)
+        .and_then(|addresses| {
+            addresses.enter(|addresses| {
+                addresses.len() < PACKET_ADDR_LEN
+                    || addresses.chunks(PACKET_ADDR_LEN).any(|address| {
+                        address.len() == PACKET_ADDR_LEN
+                            && address.iter().zip(adva.iter()).all(|(a, b)| a.get() == *b)
+                    })
+            })
+        })
+        .unwrap_or(true)
 }
 
 // Timer alarm
@@ -424,6 +727,21 @@ where
                 let t0 = A::Ticks::from(reference);
                 let expired = !now.within_range(t0, exp);
                 if expired {
+                    // Nothing answered within the listening window
+                    match app.process_status {
+                        Some(BLEState::AdvertisingListen(channel)) => {
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            self.continue_advertising(processid, app, kernel_data, channel);
+                            return;
+                        }
+                        Some(BLEState::AwaitingScanResponse(channel)) => {
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            self.continue_scanning(processid, app, channel);
+                            return;
+                        }
+                        _ => {}
+                    }
+
                     if self.busy.get() {
                         // The radio is currently busy, so we won't be able to start the
                         // operation at the appropriate time. Instead, reschedule the
@@ -488,51 +806,62 @@ where
                 // channels 37, 38 and 39 should only be used for advertisements!
                 // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
                 // only be sent on the other 37 RadioChannel channels.
-
-                if len <= PACKET_LENGTH as u8 && result == Ok(()) {
-                    // write to buffer in userland
-
-                    let success = kernel_data
-                        .get_readwrite_processbuffer(rw_allow::SCAN_BUFFER)
-                        .and_then(|scan_buffer| {
-                            scan_buffer.mut_enter(|userland| {
-                                userland[0..len as usize]
-                                    .copy_from_slice_or_err(&buf[0..len as usize])
-                                    .is_ok()
-                            })
-                        })
-                        .unwrap_or(false);
-
-                    if success {
-                        kernel_data
-                            .schedule_upcall(
-                                0,
-                                (kernel::errorcode::into_statuscode(result), len as usize, 0),
-                            )
-                            .ok();
-                    }
-                }
+                let packet = if len <= PACKET_LENGTH as u8 && result == Ok(()) {
+                    buf.get(..len as usize).unwrap_or(&[])
+                } else {
+                    &[]
+                };
 
                 match app.process_status {
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
-                        self.receiving_app.set(*processid);
-                        let _ = self.radio.set_tx_power(app.tx_power);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel38);
+                    Some(BLEState::Scanning(channel)) => {
+                        if packet.len() < 2 {
+                            self.continue_scanning(*processid, app, channel);
+                            return;
+                        }
+                        if !app.filter.accepts(packet, self.radio.rssi())
+                            || !address_listed(kernel_data, packet)
+                        {
+                            // Keep listening, rather than spend the channel on a
+                            // packet the app is not interested in
+                            self.radio.receive_advertisement(channel);
+                            return;
+                        }
+                        self.deliver(kernel_data, packet);
+
+                        let scannable = matches!(packet[0] & 0xf, ADV_IND | ADV_SCAN_IND);
+                        if app.active_scanning && scannable && packet.len() >= 2 + PACKET_ADDR_LEN {
+                            let adva = &packet[2..2 + PACKET_ADDR_LEN];
+                            let random_adva = packet[0] & (1 << ADV_HEADER_TXADD_OFFSET) != 0;
+                            app.scan_target.copy_from_slice(adva);
+                            app.process_status = Some(BLEState::ScanRequesting(channel));
+                            self.sending_app.set(*processid);
+                            if app
+                                .send_scan_request(self, adva, random_adva, channel)
+                                .is_ok()
+                            {
+                                return;
+                            }
+                        }
+                        self.continue_scanning(*processid, app, channel);
                     }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
-                        self.receiving_app.set(*processid);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel39);
+                    Some(BLEState::AwaitingScanResponse(channel)) => {
+                        if app.is_scan_response_for(packet) {
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            self.deliver(kernel_data, packet);
+                            self.continue_scanning(*processid, app, channel);
+                        } else {
+                            self.radio.receive_advertisement(channel);
+                        }
                     }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::ScanningIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                    Some(BLEState::AdvertisingListen(channel)) => {
+                        if app.is_scan_request_for(packet) {
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            app.process_status = Some(BLEState::ScanResponding(channel));
+                            self.sending_app.set(*processid);
+                            let _ = app.send_scan_response(*processid, kernel_data, &self, channel);
+                        } else {
+                            self.radio.receive_advertisement(channel);
+                        }
                     }
                     // Invalid state => don't care
                     _ => (),
@@ -556,35 +885,20 @@ where
         self.sending_app.map(|processid| {
             let _ = self.app.enter(*processid, |app, kernel_data| {
                 match app.process_status {
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
-                        self.sending_app.set(*processid);
-                        let _ = self.radio.set_tx_power(app.tx_power);
-                        let _ = app.send_advertisement(
-                            *processid,
-                            kernel_data,
-                            &self,
-                            RadioChannel::AdvertisingChannel38,
-                        );
-                    }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
-                        self.sending_app.set(*processid);
-                        let _ = app.send_advertisement(
-                            *processid,
-                            kernel_data,
-                            &self,
-                            RadioChannel::AdvertisingChannel39,
-                        );
+                    Some(BLEState::Advertising(channel)) => match app.pdu_type {
+                        // Scannable advertisements wait for scan requests
+                        ADV_IND | ADV_SCAN_IND => {
+                            app.process_status = Some(BLEState::AdvertisingListen(channel));
+                            self.listen(*processid, app, channel);
+                        }
+                        _ => self.continue_advertising(*processid, app, kernel_data, channel),
+                    },
+                    Some(BLEState::ScanResponding(channel)) => {
+                        self.continue_advertising(*processid, app, kernel_data, channel);
                     }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                    Some(BLEState::ScanRequesting(channel)) => {
+                        app.process_status = Some(BLEState::AwaitingScanResponse(channel));
+                        self.listen(*processid, app, channel);
                     }
                     // Invalid state => don't care
                     _ => (),
@@ -686,11 +1000,16 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
-            // Passive scanning mode
-            5 => {
+            // Passive or active scanning mode
+            5 | 6 => {
                 self.app
                     .enter(processid, |app, _| {
                         if let Some(BLEState::Idle) = app.process_status {
+                            app.active_scanning = command_num == 6;
+                            if app.active_scanning {
+                                // Scan requests carry the scanner's address
+                                app.generate_random_address(processid)?;
+                            }
                             app.process_status = Some(BLEState::ScanningIdle);
                             app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
                             Ok(())
@@ -711,6 +1030,34 @@ where
                     )
             }
 
+            // Only accept packets whose advertising data contains an AD type
+            //
+            // data - AD type, or 0 to accept any packet
+            7 => self
+                .app
+                .enter(processid, |app, _| {
+                    app.filter.ad_type = match data as u8 {
+                        0 => None,
+                        ad_type => Some(ad_type),
+                    };
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Only accept packets received with a signal strength above a threshold
+            //
+            // data - Threshold in dBm, or 0 to accept any packet
+            8 => self
+                .app
+                .enter(processid, |app, _| {
+                    app.filter.rssi_threshold = match data as i8 {
+                        0 => None,
+                        threshold => Some(threshold),
+                    };
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
         .into()
@@ -720,3 +1067,76 @@ where
         self.app.enter(processid, |_, _| {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An ADV_IND from a random address, with flags and a complete local name
+    const ADVERTISEMENT: [u8; 17] = [
+        0x40, 15, 1, 2, 3, 4, 5, 6, 0x02, 0x01, 0x06, 0x04, 0x09, b'T', b'o', b'c', b'k',
+    ];
+
+    #[test]
+    fn finds_ad_types() {
+        let data = advertising_data(&ADVERTISEMENT);
+        assert_eq!(data.len(), 9);
+        assert!(has_ad_type(data, 0x01));
+        assert!(has_ad_type(data, 0x09));
+        assert!(!has_ad_type(data, 0x06));
+        assert!(!has_ad_type(&[], 0x01));
+        // Structures running past the end, or empty ones, end the data
+        assert!(!has_ad_type(&[0x05, 0x01], 0x09));
+        assert!(!has_ad_type(&[0x00, 0x09], 0x09));
+    }
+
+    #[test]
+    fn scan_requests_carry_no_advertising_data() {
+        let mut request = [0; 14];
+        request[0] = SCAN_REQ;
+        request[1] = 12;
+        request[8] = 0x09;
+        assert!(advertising_data(&request).is_empty());
+        assert!(advertising_data(&[0x40]).is_empty());
+    }
+
+    #[test]
+    fn filters_on_ad_type_and_rssi() {
+        assert!(Filter::default().accepts(&ADVERTISEMENT, Some(-90)));
+
+        let filter = Filter {
+            ad_type: Some(0x09),
+            rssi_threshold: Some(-70),
+        };
+        assert!(filter.accepts(&ADVERTISEMENT, Some(-70)));
+        assert!(!filter.accepts(&ADVERTISEMENT, Some(-71)));
+        // An unknown signal strength passes
+        assert!(filter.accepts(&ADVERTISEMENT, None));
+
+        let filter = Filter {
+            ad_type: Some(0xff),
+            rssi_threshold: None,
+        };
+        assert!(!filter.accepts(&ADVERTISEMENT, Some(-20)));
+    }
+
+    #[test]
+    fn matches_scan_requests_and_responses() {
+        let mut app = App::default();
+        app.address = [0xf0, 1, 0, 0, 0, 0xf0];
+        let mut request = [0; 14];
+        request[0] = SCAN_REQ | 1 << ADV_HEADER_TXADD_OFFSET | 1 << ADV_HEADER_RXADD_OFFSET;
+        request[1] = 12;
+        request[8..].copy_from_slice(&app.address);
+        assert!(app.is_scan_request_for(&request));
+        request[13] = 0;
+        assert!(!app.is_scan_request_for(&request));
+
+        app.scan_target = [1, 2, 3, 4, 5, 6];
+        let mut response = ADVERTISEMENT;
+        assert!(!app.is_scan_response_for(&response));
+        response[0] = SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET;
+        assert!(app.is_scan_response_for(&response));
+        assert!(!app.is_scan_response_for(&response[..4]));
+    }
+}
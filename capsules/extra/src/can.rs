@@ -28,6 +28,17 @@
 //! - if it's greater the 0, the message will be copied to the RW buffer
 //!   but no upcall will be done
 //!
+//! Each process uses the CAN device through a `CanChannel`, which it holds
+//! from its first command until it disables the device. With a single
+//! channel over the controller, one process uses it at a time. Processes
+//! share the controller through channels over virtual devices of
+//! `capsules_core::virtualizers::virtual_can`, one channel per process.
+//!
+//! When the device of a channel has acceptance filters, as virtual devices
+//! do, the process can set them. They are kept in the grant of the process,
+//! and registered on the device of its channel, so that the process only
+//! receives the messages that pass one of them.
+//!
 //! Usage
 //! -----
 //!
@@ -36,21 +47,25 @@
 //! let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
 //! let grant_can = self.board_kernel.create_grant(
 //!     capsules::can::CanCapsule::DRIVER_NUM, &grant_cap);
-//! let can = capsules::can::CanCapsule::new(
+//! let can = capsules::can::CanCapsule::new(grant_can);
+//!
+//! let channel = capsules::can::CanChannel::new(
+//!    can,
 //!    can_peripheral,
-//!    grant_can,
 //!    tx_buffer,
 //!    rx_buffer,
 //! );
-//!
-//! kernel::hil::can::Controller::set_client(can_peripheral, Some(can));
-//! kernel::hil::can::Transmit::set_client(can_peripheral, Some(can));
-//! kernel::hil::can::Receive::set_client(can_peripheral, Some(can));
+//! channel.setup();
+//! kernel::hil::can::Controller::set_client(can_peripheral, Some(channel));
+//! kernel::hil::can::Transmit::set_client(can_peripheral, Some(channel));
+//! kernel::hil::can::Receive::set_client(can_peripheral, Some(channel));
 //! ```
 //!
 
+use core::cmp;
 use core::mem::size_of;
 
+use kernel::collections::list::{List, ListLink, ListNode};
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::can;
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
@@ -65,6 +80,10 @@ pub const BYTE4_MASK: usize = 0xff000000;
 pub const BYTE3_MASK: usize = 0xff0000;
 pub const BYTE2_MASK: usize = 0xff00;
 pub const BYTE1_MASK: usize = 0xff;
+/// Bit of a filter identifier that selects an extended identifier.
+pub const EXTENDED_ID: usize = 1 << 31;
+/// The number of acceptance filters a process can set.
+pub const MAX_FILTERS: usize = 4;
 
 mod error_upcalls {
     pub const ERROR_TX: usize = 100;
@@ -92,13 +111,6 @@ mod rw_allow {
 }
 
 pub struct CanCapsule<'a, Can: can::Can> {
-    // CAN driver
-    can: &'a Can,
-
-    // CAN buffers
-    can_tx: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
-    can_rx: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
-
     // Process
     processes: Grant<
         App,
@@ -106,16 +118,17 @@ pub struct CanCapsule<'a, Can: can::Can> {
         AllowRoCount<{ ro_allow::COUNT }>,
         AllowRwCount<{ rw_allow::COUNT }>,
     >,
-    processid: OptionalCell<ProcessId>,
 
-    // Variable used to store the current state of the CAN peripheral
-    // during an `enable` or `disable` command.
-    peripheral_state: OptionalCell<can::State>,
+    // Channels to the CAN device, each used by one process at a time
+    channels: List<'a, CanChannel<'a, Can>>,
 }
 
 pub struct App {
     receive_index: usize,
     lost_messages: u32,
+    // Acceptance filters of the process, registered on the device of its
+    // channel
+    filters: [Option<can::FilterParameters>; MAX_FILTERS],
 }
 
 impl Default for App {
@@ -123,52 +136,195 @@ impl Default for App {
         App {
             receive_index: 0,
             lost_messages: 0,
+            filters: [None; MAX_FILTERS],
         }
     }
 }
 
 impl<'a, Can: can::Can> CanCapsule<'a, Can> {
     pub fn new(
-        can: &'a Can,
         grant: Grant<
             App,
             UpcallCount<{ up_calls::COUNT }>,
             AllowRoCount<{ ro_allow::COUNT }>,
             AllowRwCount<{ rw_allow::COUNT }>,
         >,
-        can_tx: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
-        can_rx: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
     ) -> CanCapsule<'a, Can> {
         CanCapsule {
+            processes: grant,
+            channels: List::new(),
+        }
+    }
+
+    /// Returns the channel of `processid`. A process without one gets a
+    /// channel that no process uses, or whose process is gone.
+    fn channel(&self, processid: ProcessId) -> Option<&'a CanChannel<'a, Can>> {
+        if let Some(channel) = self
+            .channels
+            .iter()
+            .find(|channel| channel.processid.contains(&processid))
+        {
+            return Some(channel);
+        }
+        let channel = self.channels.iter().find(|channel| {
+            channel.processid.map_or(true, |owner| {
+                self.processes.enter(*owner, |_, _| {}).is_err()
+            })
+        })?;
+        channel.processid.set(processid);
+        channel.load_filters(processid);
+        Some(channel)
+    }
+
+    fn add_filter(
+        &self,
+        channel: &CanChannel<'a, Can>,
+        processid: ProcessId,
+        id: usize,
+        mask: usize,
+    ) -> Result<(), ErrorCode> {
+        channel.filter.map_or(Err(ErrorCode::NOSUPPORT), |filter| {
+            self.processes
+                .enter(processid, |app, _| {
+                    let count = cmp::min(filter.filter_count(), MAX_FILTERS);
+                    let number = app.filters[..count]
+                        .iter()
+                        .position(|filter| filter.is_none())
+                        .ok_or(ErrorCode::NOMEM)?;
+                    let id = if id & EXTENDED_ID != 0 {
+                        can::Id::Extended((id & !EXTENDED_ID) as u32)
+                    } else {
+                        can::Id::Standard(id as u16)
+                    };
+                    let parameters = can::FilterParameters {
+                        number: number as u32,
+                        scale_bits: can::ScaleBits::Bits32,
+                        identifier_mode: can::IdentifierMode::Mask,
+                        fifo_number: 0,
+                        id,
+                        mask: mask as u32,
+                    };
+                    filter.enable_filter(parameters)?;
+                    app.filters[number] = Some(parameters);
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    fn clear_filters(
+        &self,
+        channel: &CanChannel<'a, Can>,
+        processid: ProcessId,
+    ) -> Result<(), ErrorCode> {
+        channel.filter.map_or(Err(ErrorCode::NOSUPPORT), |filter| {
+            self.processes
+                .enter(processid, |app, _| {
+                    for (number, slot) in app.filters.iter_mut().enumerate() {
+                        if slot.is_some() {
+                            filter.disable_filter(number as u32)?;
+                            *slot = None;
+                        }
+                    }
+                    Ok(())
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+}
+
+/// The CAN device used by one process at a time, and the buffers the
+/// messages of the process go through.
+pub struct CanChannel<'a, Can: can::Can> {
+    capsule: &'a CanCapsule<'a, Can>,
+    next: ListLink<'a, CanChannel<'a, Can>>,
+
+    // CAN driver
+    can: &'a Can,
+
+    // CAN buffers
+    can_tx: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    can_rx: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+
+    // The process using the channel
+    processid: OptionalCell<ProcessId>,
+
+    // Variable used to store the current state of the CAN peripheral
+    // during an `enable` or `disable` command.
+    peripheral_state: OptionalCell<can::State>,
+
+    // Acceptance filters of the CAN device
+    filter: OptionalCell<&'a dyn can::Filter>,
+}
+
+impl<'a, Can: can::Can> CanChannel<'a, Can> {
+    pub fn new(
+        capsule: &'a CanCapsule<'a, Can>,
+        can: &'a Can,
+        can_tx: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+        can_rx: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
+    ) -> CanChannel<'a, Can> {
+        CanChannel {
+            capsule,
+            next: ListLink::empty(),
             can,
             can_tx: TakeCell::new(can_tx),
             can_rx: TakeCell::new(can_rx),
-            processes: grant,
-            peripheral_state: OptionalCell::empty(),
             processid: OptionalCell::empty(),
+            peripheral_state: OptionalCell::empty(),
+            filter: OptionalCell::empty(),
         }
     }
 
+    pub fn setup(&'a self) {
+        self.capsule.channels.push_head(self);
+    }
+
+    /// Gives the processes using the channel the acceptance filters of its
+    /// CAN device.
+    pub fn set_filter(&self, filter: &'a dyn can::Filter) {
+        self.filter.set(filter);
+    }
+
+    /// Replaces the filters of the device with those of `processid`.
+    fn load_filters(&self, processid: ProcessId) {
+        self.filter.map(|filter| {
+            let _ = self.capsule.processes.enter(processid, |app, _| {
+                let count = cmp::min(filter.filter_count(), MAX_FILTERS);
+                for number in 0..count {
+                    let _ = match app.filters[number] {
+                        Some(parameters) => filter.enable_filter(parameters),
+                        None => filter.disable_filter(number as u32),
+                    };
+                }
+            });
+        });
+    }
+
     fn schedule_callback(&self, callback_number: usize, data: (usize, usize, usize)) {
         self.processid.map(|processid| {
-            let _ = self.processes.enter(*processid, |_app, kernel_data| {
-                kernel_data
-                    .schedule_upcall(callback_number, (data.0, data.1, data.2))
-                    .ok();
-            });
+            let _ = self
+                .capsule
+                .processes
+                .enter(*processid, |_app, kernel_data| {
+                    kernel_data
+                        .schedule_upcall(callback_number, (data.0, data.1, data.2))
+                        .ok();
+                });
         });
     }
 
     /// This function makes a copy of the buffer in the grant and sends it
     /// to the low-level hardware, in order for it to be sent on the bus.
-    pub fn process_send_command(
+    fn process_send_command(
         &self,
-        processid: &mut ProcessId,
+        processid: ProcessId,
         id: can::Id,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        self.processes
-            .enter(*processid, |_, kernel_data| {
+        self.capsule
+            .processes
+            .enter(processid, |_, kernel_data| {
                 kernel_data
                     .get_readonly_processbuffer(ro_allow::RO_ALLOW_BUFFER)
                     .map_or_else(
@@ -198,13 +354,11 @@ impl<'a, Can: can::Can> CanCapsule<'a, Can> {
             })
             .unwrap_or_else(|err| err.into())
     }
+}
 
-    pub fn is_valid_process(&self, processid: ProcessId) -> bool {
-        self.processid.map_or(true, |owning_process| {
-            self.processes
-                .enter(*owning_process, |_, _| owning_process == &processid)
-                .unwrap_or(true)
-        })
+impl<'a, Can: can::Can> ListNode<'a, CanChannel<'a, Can>> for CanChannel<'a, Can> {
+    fn next(&'a self) -> &'a ListLink<'a, CanChannel<'a, Can>> {
+        &self.next
     }
 }
 
@@ -221,25 +375,23 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
             return CommandReturn::success();
         }
 
-        // Check to see if the process or no process at all
-        // owns the capsule. Only one application can use the
-        // capsule at a time.
-        if !self.is_valid_process(processid) {
-            return CommandReturn::failure(ErrorCode::RESERVE);
-        } else {
-            self.processid.set(processid);
-        }
+        // Each process uses a channel of its own, so there are as many
+        // processes using the device as there are channels.
+        let channel = match self.channel(processid) {
+            Some(channel) => channel,
+            None => return CommandReturn::failure(ErrorCode::RESERVE),
+        };
 
         match command_num {
             // Set the bitrate
-            1 => match self.can.set_bitrate(arg1 as u32) {
+            1 => match channel.can.set_bitrate(arg1 as u32) {
                 Ok(_) => CommandReturn::success(),
                 Err(err) => CommandReturn::failure(err),
             },
 
             // Set the operation mode (Loopback, Monitoring, etc)
             2 => {
-                match self.can.set_operation_mode(match arg1 {
+                match channel.can.set_operation_mode(match arg1 {
                     0 => can::OperationMode::Loopback,
                     1 => can::OperationMode::Monitoring,
                     2 => can::OperationMode::Freeze,
@@ -251,13 +403,13 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
             }
 
             // Enable the peripheral
-            3 => match self.can.enable() {
+            3 => match channel.can.enable() {
                 Ok(_) => CommandReturn::success(),
                 Err(err) => CommandReturn::failure(err),
             },
 
             // Disable the peripheral
-            4 => match self.can.disable() {
+            4 => match channel.can.disable() {
                 Ok(_) => CommandReturn::success(),
                 Err(err) => CommandReturn::failure(err),
             },
@@ -265,32 +417,25 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
             // Send a message with a 16-bit identifier
             5 => {
                 let id = can::Id::Standard(arg1 as u16);
-                self.processid
-                    .map_or(
-                        CommandReturn::failure(ErrorCode::BUSY),
-                        |processid| match self.process_send_command(processid, id, arg2) {
-                            Ok(_) => CommandReturn::success(),
-                            Err(err) => CommandReturn::failure(err),
-                        },
-                    )
+                match channel.process_send_command(processid, id, arg2) {
+                    Ok(_) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err),
+                }
             }
 
             // Send a message with a 32-bit identifier
             6 => {
                 let id = can::Id::Extended(arg1 as u32);
-                self.processid
-                    .map_or(
-                        CommandReturn::failure(ErrorCode::BUSY),
-                        |processid| match self.process_send_command(processid, id, arg2) {
-                            Ok(_) => CommandReturn::success(),
-                            Err(err) => CommandReturn::failure(err),
-                        },
-                    )
+                match channel.process_send_command(processid, id, arg2) {
+                    Ok(_) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err),
+                }
             }
 
             // Start receiving messages
             7 => {
-                self.can_rx
+                channel
+                    .can_rx
                     .take()
                     .map(|dest_buffer| {
                         self.processes
@@ -314,7 +459,7 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
                                             .unwrap_or_else(|err| err.into())
                                     },
                                 ) {
-                                    Ok(_) => match self.can.start_receive_process(dest_buffer) {
+                                    Ok(_) => match channel.can.start_receive_process(dest_buffer) {
                                         Ok(_) => CommandReturn::success(),
                                         Err((err, _)) => CommandReturn::failure(err),
                                     },
@@ -327,14 +472,14 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
             }
 
             // Stop receiving messages
-            8 => match self.can.stop_receive() {
+            8 => match channel.can.stop_receive() {
                 Ok(_) => CommandReturn::success(),
                 Err(err) => CommandReturn::failure(err),
             },
 
             // Set the timing parameters
             9 => {
-                match self.can.set_bit_timing(can::BitTiming {
+                match channel.can.set_bit_timing(can::BitTiming {
                     segment1: ((arg1 & BYTE4_MASK) >> 24) as u8,
                     segment2: ((arg1 & BYTE3_MASK) >> 16) as u8,
                     propagation: arg2 as u8,
@@ -346,6 +491,12 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
                 }
             }
 
+            // Add an acceptance filter
+            10 => self.add_filter(channel, processid, arg1, arg2).into(),
+
+            // Remove the acceptance filters
+            11 => self.clear_filters(channel, processid).into(),
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -355,7 +506,7 @@ impl<'a, Can: can::Can> SyscallDriver for CanCapsule<'a, Can> {
     }
 }
 
-impl<'a, Can: can::Can> can::ControllerClient for CanCapsule<'a, Can> {
+impl<'a, Can: can::Can> can::ControllerClient for CanChannel<'a, Can> {
     // This callback must be called after an `enable` or `disable` command was sent.
     // It stores the new state of the peripheral.
     fn state_changed(&self, state: can::State) {
@@ -416,12 +567,13 @@ impl<'a, Can: can::Can> can::ControllerClient for CanCapsule<'a, Can> {
                 self.schedule_callback(up_calls::UPCALL_ENABLE, (err as usize, 0, 0));
             }
         }
+        // The process is done with the channel
         self.processid.clear();
     }
 }
 
 impl<'a, Can: can::Can> can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }>
-    for CanCapsule<'a, Can>
+    for CanChannel<'a, Can>
 {
     // This callback is called when the hardware acknowledges that a message
     // was sent. This callback also makes an upcall to the userspace.
@@ -444,7 +596,7 @@ impl<'a, Can: can::Can> can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }>
 }
 
 impl<'a, Can: can::Can> can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }>
-    for CanCapsule<'a, Can>
+    for CanChannel<'a, Can>
 {
     // This callback is called when a new message is received on any receiving
     // fifo.
@@ -460,7 +612,8 @@ impl<'a, Can: can::Can> can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }>
         match status {
             Ok(_) => {
                 match self.processid.map_or(Err(ErrorCode::NOMEM), |processid| {
-                    self.processes
+                    self.capsule
+                        .processes
                         .enter(*processid, |app_data, kernel_data| {
                             kernel_data
                                 .get_readwrite_processbuffer(rw_allow::RW_ALLOW_BUFFER)
@@ -0,0 +1,191 @@
+//! Reading and writing the 512 byte blocks of the disk the filesystem is on.
+//!
+//! `SdCardBlocks` provides the blocks of an SD card, initializing the card
+//! before the first transfer.
+
+use core::cell::Cell;
+
+use kernel::hil;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::sdcard::{SDCard, SDCardClient};
+
+/// The buffer of a transfer is returned with its result. It is only missing
+/// if the disk failed the transfer and could not give the buffer back.
+pub trait BlockClient {
+    /// A block was read into `buffer`.
+    fn read_complete(&self, buffer: Option<&'static mut [u8]>, result: Result<(), ErrorCode>);
+    /// A block was written from `buffer`.
+    fn write_complete(&self, buffer: Option<&'static mut [u8]>, result: Result<(), ErrorCode>);
+}
+
+/// A disk of 512 byte blocks, one transfer at a time.
+pub trait BlockDevice<'a> {
+    fn set_client(&self, client: &'a dyn BlockClient);
+    /// Read `block` into the first 512 bytes of `buffer`.
+    fn read_block(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)>;
+    /// Write the first 512 bytes of `buffer` to `block`.
+    fn write_block(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)>;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transfer {
+    Read(u32),
+    Write(u32),
+}
+
+pub struct SdCardBlocks<'a, A: hil::time::Alarm<'a>> {
+    sdcard: &'a SDCard<'a, A>,
+    client: OptionalCell<&'a dyn BlockClient>,
+    /// The transfer in progress
+    transfer: OptionalCell<Transfer>,
+    /// Whether the card is being initialized before the transfer
+    initializing: Cell<bool>,
+    /// The buffer of the transfer, while the card is initialized
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, A: hil::time::Alarm<'a>> SdCardBlocks<'a, A> {
+    pub fn new(sdcard: &'a SDCard<'a, A>) -> SdCardBlocks<'a, A> {
+        SdCardBlocks {
+            sdcard,
+            client: OptionalCell::empty(),
+            transfer: OptionalCell::empty(),
+            initializing: Cell::new(false),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    fn start(
+        &self,
+        transfer: Transfer,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        if self.transfer.is_some() {
+            return Err((ErrorCode::BUSY, Some(buffer)));
+        }
+        if !self.sdcard.is_installed() {
+            return Err((ErrorCode::UNINSTALLED, Some(buffer)));
+        }
+
+        if self.sdcard.is_initialized() {
+            self.transfer.set(transfer);
+            self.issue(transfer, buffer)
+        } else {
+            if let Err(error) = self.sdcard.initialize() {
+                return Err((error, Some(buffer)));
+            }
+            self.transfer.set(transfer);
+            self.initializing.set(true);
+            self.buffer.replace(buffer);
+            Ok(())
+        }
+    }
+
+    fn issue(
+        &self,
+        transfer: Transfer,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        // The card does not keep the buffer of a transfer it refuses as busy
+        if self.sdcard.is_busy() {
+            self.transfer.clear();
+            return Err((ErrorCode::BUSY, Some(buffer)));
+        }
+        match transfer {
+            Transfer::Read(block) => self.sdcard.read_blocks(buffer, block, 1),
+            Transfer::Write(block) => self.sdcard.write_blocks(buffer, block, 1),
+        }
+        .map_err(|error| {
+            self.transfer.clear();
+            // The card keeps the buffer of any other transfer it could not
+            // start
+            (error, self.sdcard.take_failed_buffer())
+        })
+    }
+
+    fn complete(&self, buffer: Option<&'static mut [u8]>, result: Result<(), ErrorCode>) {
+        self.initializing.set(false);
+        match self.transfer.take() {
+            Some(Transfer::Read(_)) => {
+                self.client
+                    .map(|client| client.read_complete(buffer, result));
+            }
+            Some(Transfer::Write(_)) => {
+                self.client
+                    .map(|client| client.write_complete(buffer, result));
+            }
+            None => {}
+        }
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> BlockDevice<'a> for SdCardBlocks<'a, A> {
+    fn set_client(&self, client: &'a dyn BlockClient) {
+        self.client.set(client);
+    }
+
+    fn read_block(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        self.start(Transfer::Read(block), buffer)
+    }
+
+    fn write_block(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        self.start(Transfer::Write(block), buffer)
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SdCardBlocks<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {}
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if !self.initializing.get() {
+            return;
+        }
+        self.initializing.set(false);
+        if let (Some(transfer), Some(buffer)) = (self.transfer.extract(), self.buffer.take()) {
+            if let Err((error, buffer)) = self.issue(transfer, buffer) {
+                self.transfer.set(transfer);
+                self.complete(buffer, Err(error));
+            }
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        self.complete(Some(data), Ok(()));
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.complete(Some(buffer), Ok(()));
+    }
+
+    fn error(&self, _error: u32) {
+        if self.transfer.is_none() {
+            return;
+        }
+        // The buffer is with us while initializing, and with the card during
+        // a transfer
+        let buffer = if self.initializing.get() {
+            self.buffer.take()
+        } else {
+            self.sdcard.take_failed_buffer()
+        };
+        self.complete(buffer, Err(ErrorCode::FAIL));
+    }
+}
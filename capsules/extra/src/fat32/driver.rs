@@ -0,0 +1,633 @@
+//! Provides userspace with the files and directories of a FAT32 SD card.
+//!
+//! Paths are given in the read-only `PATH` buffer, ending at the end of the
+//! buffer or at a NUL, and are from the root directory of the card. The card
+//! is shared by all apps, and by any computer it is put into, so apps are not
+//! kept apart.
+//!
+//! Each app opens up to `MAX_FILES` files at once, which are identified by a
+//! file descriptor. Writing to a file is seen by every descriptor of the
+//! file. Opening, reading, writing, removing, listing and making directories
+//! complete with an upcall of the status and, for opening, reading, writing
+//! and listing, the file descriptor, number of bytes or name length. The
+//! other commands complete immediately.
+//!
+//! Setup
+//! -----
+//!
+//! You need an `SDCard`, as set up in `sdcard`, and a `DynamicDeferredCall`.
+//!
+//! ```rust
+//! let fat32 = components::fat32::Fat32Component::new(
+//!     board_kernel,
+//!     capsules_extra::fat32::driver::DRIVER_NUM,
+//!     sdcard,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::fat32_component_static!(
+//!     capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>
+//! ));
+//! ```
+
+use core::cell::Cell;
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+use super::block::BlockDevice;
+use super::filesystem::{FatFs, FatFsClient, File, MAX_PATH};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Fat32 as usize;
+
+/// The number of files each app can open at once.
+pub const MAX_FILES: usize = 4;
+/// The length of the buffer data is moved through, at most a sector at a
+/// time.
+pub const BUF_LEN: usize = 512;
+
+/// Creates the file when opening it, if it does not exist.
+pub const CREATE: usize = 1;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// The path of the file or directory
+    pub const PATH: usize = 0;
+    /// The data to write
+    pub const DATA: usize = 1;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Receives the data read, or the name of a directory entry
+    pub const BUFFER: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A command completed, or failed
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+#[derive(Clone, Copy)]
+enum Command {
+    Open { create: bool },
+    Read { fd: usize, len: usize },
+    Write { fd: usize, len: usize },
+    Unlink,
+    ReadDir { index: usize },
+    Mkdir,
+    Mount,
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    file: File,
+    position: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    files: [Option<OpenFile>; MAX_FILES],
+    /// A command waiting for the filesystem
+    pending: Option<Command>,
+}
+
+// Copies the path from the `PATH` buffer, returning its length.
+fn read_path(kernel_data: &GrantKernelData, path: &mut [u8; MAX_PATH]) -> Result<usize, ErrorCode> {
+    kernel_data
+        .get_readonly_processbuffer(ro_allow::PATH)
+        .and_then(|buffer| {
+            buffer.enter(|app_path| {
+                let len = app_path
+                    .iter()
+                    .position(|b| b.get() == 0)
+                    .unwrap_or(app_path.len());
+                if len > MAX_PATH {
+                    return Err(ErrorCode::INVAL);
+                }
+                app_path[..len].copy_to_slice(&mut path[..len]);
+                Ok(len)
+            })
+        })
+        .unwrap_or(Err(ErrorCode::RESERVE))
+}
+
+pub struct Fat32Driver<'a, B: BlockDevice<'a>> {
+    fs: &'a FatFs<'a, B>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The app the filesystem is working for, and its command
+    current: OptionalCell<(ProcessId, Command)>,
+    buffer: TakeCell<'static, [u8]>,
+    /// The number of bytes read or written so far
+    done: Cell<usize>,
+}
+
+impl<'a, B: BlockDevice<'a>> Fat32Driver<'a, B> {
+    pub fn new(
+        fs: &'a FatFs<'a, B>,
+        apps: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+        buffer: &'static mut [u8],
+    ) -> Fat32Driver<'a, B> {
+        Fat32Driver {
+            fs,
+            apps,
+            current: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            done: Cell::new(0),
+        }
+    }
+
+    fn open_file(&self, processid: ProcessId, fd: usize) -> Result<OpenFile, ErrorCode> {
+        self.apps
+            .enter(processid, |app, _| app.files.get(fd).copied().flatten())?
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    fn set_open_file(&self, processid: ProcessId, fd: usize, open: OpenFile) {
+        let _ = self.apps.enter(processid, |app, _| {
+            if let Some(Some(file)) = app.files.get_mut(fd) {
+                *file = open;
+            }
+        });
+    }
+
+    // Gives every descriptor of `file` the file as it is now, or closes them
+    // if it was removed.
+    fn update_files(&self, file: &File, removed: bool) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                for open in app.files.iter_mut() {
+                    match open {
+                        Some(o) if o.file.same(file) && removed => *open = None,
+                        Some(o) if o.file.same(file) => o.file = *file,
+                        _ => {}
+                    }
+                }
+            });
+        }
+    }
+
+    fn upcall(&self, processid: ProcessId, result: Result<(usize, usize), ErrorCode>) {
+        let _ = self.apps.enter(processid, |_, kernel_data| {
+            let (status, value, extra) = match result {
+                Ok((value, extra)) => (kernel::errorcode::into_statuscode(Ok(())), value, extra),
+                Err(error) => (kernel::errorcode::into_statuscode(Err(error)), 0, 0),
+            };
+            kernel_data
+                .schedule_upcall(upcall::DONE, (status, value, extra))
+                .ok();
+        });
+    }
+
+    fn path(&self, processid: ProcessId, path: &mut [u8; MAX_PATH]) -> Result<usize, ErrorCode> {
+        self.apps
+            .enter(processid, |_, kernel_data| read_path(kernel_data, path))?
+    }
+
+    fn start(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        self.done.set(0);
+        self.run(processid, command)
+    }
+
+    // Starts, or continues, `command`, reporting it if it finished.
+    fn run(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        match self.step(processid, command) {
+            Ok(None) => {
+                self.current.set((processid, command));
+                Ok(())
+            }
+            Ok(Some(value)) => {
+                self.current.clear();
+                self.upcall(processid, Ok((value, 0)));
+                Ok(())
+            }
+            Err(error) => {
+                self.current.clear();
+                Err(error)
+            }
+        }
+    }
+
+    // Returns the result if `command` finished, or `None` if the
+    // filesystem is working on it.
+    fn step(&self, processid: ProcessId, command: Command) -> Result<Option<usize>, ErrorCode> {
+        let mut path = [0; MAX_PATH];
+
+        match command {
+            Command::Open { .. } => {
+                let len = self.apps.enter(processid, |app, kernel_data| {
+                    if app.files.iter().all(Option::is_some) {
+                        return Err(ErrorCode::NOMEM);
+                    }
+                    read_path(kernel_data, &mut path)
+                })??;
+                self.fs.lookup(&path[..len])?;
+                Ok(None)
+            }
+            Command::Mkdir => {
+                let len = self.path(processid, &mut path)?;
+                self.fs.create(&path[..len], true)?;
+                Ok(None)
+            }
+            Command::Unlink => {
+                let len = self.path(processid, &mut path)?;
+                self.fs.unlink(&path[..len])?;
+                Ok(None)
+            }
+            Command::ReadDir { index } => {
+                let len = self.path(processid, &mut path)?;
+                self.fs.read_dir(&path[..len], index)?;
+                Ok(None)
+            }
+            Command::Mount => {
+                self.fs.mount()?;
+                Ok(None)
+            }
+            Command::Read { fd, len } => {
+                let available = self.apps.enter(processid, |_, kernel_data| {
+                    kernel_data
+                        .get_readwrite_processbuffer(rw_allow::BUFFER)
+                        .map_or(0, |buffer| buffer.len())
+                })?;
+                if len > available {
+                    return Err(ErrorCode::SIZE);
+                }
+
+                let open = self.open_file(processid, fd)?;
+                let remaining = len - self.done.get();
+                if remaining == 0 || open.position >= open.file.size() {
+                    return Ok(Some(self.done.get()));
+                }
+
+                let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+                self.fs
+                    .read(open.file, open.position, buffer, remaining)
+                    .map_err(|(error, buffer)| {
+                        self.buffer.replace(buffer);
+                        error
+                    })?;
+                Ok(None)
+            }
+            Command::Write { fd, len } => {
+                let open = self.open_file(processid, fd)?;
+                let done = self.done.get();
+                if len == done {
+                    return Ok(Some(done));
+                }
+
+                let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+                let chunk = (len - done).min(buffer.len());
+                let copied = self
+                    .apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::DATA)
+                            .and_then(|data| {
+                                data.enter(|data| {
+                                    if data.len() < len {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    data[done..done + chunk].copy_to_slice(&mut buffer[..chunk]);
+                                    Ok(())
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    })
+                    .map_err(ErrorCode::from)
+                    .and_then(|result| result);
+                if let Err(error) = copied {
+                    self.buffer.replace(buffer);
+                    return Err(error);
+                }
+
+                self.fs
+                    .write(open.file, open.position, buffer, chunk)
+                    .map_err(|(error, buffer)| {
+                        self.buffer.replace(buffer);
+                        error
+                    })?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Continues the current command after part of it succeeded, and starts
+    // the next command once it is finished.
+    fn next(&self, processid: ProcessId, command: Command, result: Result<(), ErrorCode>) {
+        if let Err(error) = result.and_then(|()| self.run(processid, command)) {
+            self.upcall(processid, Err(error));
+        }
+        if self.current.is_none() {
+            self.check_queue();
+        }
+    }
+
+    // Reports the current command, and starts the next one.
+    fn finish(&self, result: Result<(usize, usize), ErrorCode>) {
+        self.current.take().map(|(processid, _)| {
+            self.upcall(processid, result);
+        });
+        self.check_queue();
+    }
+
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let processid = cntr.processid();
+            let command = cntr.enter(|app, _| app.pending.take());
+            if let Some(command) = command {
+                if let Err(error) = self.start(processid, command) {
+                    self.upcall(processid, Err(error));
+                }
+                if self.current.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Runs `command` now if the filesystem is free, or queues it.
+    fn enqueue(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        if self.current.is_some() {
+            self.apps.enter(processid, |app, _| {
+                if app.pending.is_some() {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    app.pending = Some(command);
+                    Ok(())
+                }
+            })?
+        } else {
+            self.start(processid, command)
+        }
+    }
+
+    fn seek(&self, processid: ProcessId, fd: usize, position: usize) -> Result<(), ErrorCode> {
+        let mut open = self.open_file(processid, fd)?;
+        if position > open.file.size() {
+            return Err(ErrorCode::INVAL);
+        }
+        open.position = position;
+        self.set_open_file(processid, fd, open);
+        Ok(())
+    }
+
+    fn close(&self, processid: ProcessId, fd: usize) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(processid, |app, _| match app.files.get_mut(fd) {
+                Some(file) if file.is_some() => {
+                    *file = None;
+                    Ok(())
+                }
+                _ => Err(ErrorCode::INVAL),
+            })?
+    }
+
+    // Gives the app a descriptor for `file`.
+    fn add_file(&self, processid: ProcessId, file: File) -> Result<usize, ErrorCode> {
+        if file.is_directory() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps
+            .enter(processid, |app, _| {
+                let fd = app
+                    .files
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(ErrorCode::NOMEM)?;
+                app.files[fd] = Some(OpenFile { file, position: 0 });
+                Ok(fd)
+            })
+            .unwrap_or(Err(ErrorCode::FAIL))
+    }
+}
+
+impl<'a, B: BlockDevice<'a>> FatFsClient for Fat32Driver<'a, B> {
+    fn mount_complete(&self, result: Result<(), ErrorCode>) {
+        // Files of the card before are no longer valid
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| app.files = [None; MAX_FILES]);
+        }
+        self.finish(result.map(|()| (0, 0)));
+    }
+
+    fn lookup_complete(&self, result: Result<File, ErrorCode>) {
+        let create = match self.current.extract() {
+            Some((processid, Command::Open { create })) => match result {
+                Err(ErrorCode::NOSUPPORT) if create => Some(processid),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        // A missing file is created, once the filesystem is done
+        if let Some(processid) = create {
+            let mut path = [0; MAX_PATH];
+            let created = self
+                .path(processid, &mut path)
+                .and_then(|len| self.fs.create(&path[..len], false));
+            if created.is_err() {
+                self.finish(created.map(|()| (0, 0)));
+            }
+            return;
+        }
+
+        self.current.take().map(|(processid, _)| {
+            let result = result.and_then(|file| self.add_file(processid, file));
+            self.upcall(processid, result.map(|fd| (fd, 0)));
+        });
+        self.check_queue();
+    }
+
+    fn create_complete(&self, result: Result<File, ErrorCode>) {
+        self.current.take().map(|(processid, command)| {
+            let result = match command {
+                Command::Open { .. } => result.and_then(|file| self.add_file(processid, file)),
+                _ => result.map(|_| 0),
+            };
+            self.upcall(processid, result.map(|fd| (fd, 0)));
+        });
+        self.check_queue();
+    }
+
+    fn unlink_complete(&self, result: Result<File, ErrorCode>) {
+        if let Ok(file) = result {
+            self.update_files(&file, true);
+        }
+        self.finish(result.map(|_| (0, 0)));
+    }
+
+    fn read_dir_complete(&self, result: Result<(File, usize), ErrorCode>) {
+        let result = result.and_then(|(file, len)| {
+            let processid = self.current.extract().ok_or(ErrorCode::FAIL)?.0;
+            self.buffer
+                .map(|buffer| {
+                    self.fs.entry_name(buffer);
+                    let copied = len.min(buffer.len());
+                    self.apps
+                        .enter(processid, |_, kernel_data| {
+                            kernel_data
+                                .get_readwrite_processbuffer(rw_allow::BUFFER)
+                                .and_then(|app_buffer| {
+                                    app_buffer.mut_enter(|app_buffer| {
+                                        let copied = copied.min(app_buffer.len());
+                                        app_buffer[..copied].copy_from_slice(&buffer[..copied]);
+                                    })
+                                })
+                        })
+                        .map_err(ErrorCode::from)?
+                        .map_err(ErrorCode::from)
+                })
+                .unwrap_or(Err(ErrorCode::BUSY))?;
+            Ok((len, file.is_directory() as usize))
+        });
+        self.finish(result);
+    }
+
+    fn read_complete(&self, result: Result<(File, usize), ErrorCode>, buffer: &'static mut [u8]) {
+        self.current.take().map(|(processid, command)| {
+            let result = result.and_then(|(file, len)| {
+                let done = self.done.get();
+                self.apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readwrite_processbuffer(rw_allow::BUFFER)
+                            .and_then(|app_buffer| {
+                                app_buffer.mut_enter(|app_buffer| {
+                                    if let Some(dest) = app_buffer.get(done..done + len) {
+                                        dest.copy_from_slice(&buffer[..len]);
+                                    }
+                                })
+                            })
+                    })
+                    .map_err(ErrorCode::from)?
+                    .map_err(ErrorCode::from)?;
+
+                if let Command::Read { fd, .. } = command {
+                    let position = self.open_file(processid, fd)?.position;
+                    self.set_open_file(
+                        processid,
+                        fd,
+                        OpenFile {
+                            file,
+                            position: position + len,
+                        },
+                    );
+                }
+                self.done.set(done + len);
+                Ok(())
+            });
+            self.buffer.replace(buffer);
+            self.next(processid, command, result);
+        });
+    }
+
+    fn write_complete(&self, result: Result<(File, usize), ErrorCode>, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.current.take().map(|(processid, command)| {
+            let result = result.and_then(|(file, len)| {
+                self.update_files(&file, false);
+                if let Command::Write { fd, .. } = command {
+                    let position = self.open_file(processid, fd)?.position;
+                    self.set_open_file(
+                        processid,
+                        fd,
+                        OpenFile {
+                            file,
+                            position: position + len,
+                        },
+                    );
+                }
+                self.done.set(self.done.get() + len);
+                Ok(())
+            });
+            self.next(processid, command, result);
+        });
+    }
+}
+
+impl<'a, B: BlockDevice<'a>> SyscallDriver for Fat32Driver<'a, B> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Open the file at `PATH`, creating it if `data1` is `CREATE`.
+    ///   The upcall gives the file descriptor.
+    /// - `2`: Read up to `data2` bytes from file descriptor `data1` into
+    ///   `BUFFER`. The upcall gives the number of bytes read, which is less
+    ///   at the end of the file.
+    /// - `3`: Write `data2` bytes of `DATA` to file descriptor `data1`. The
+    ///   upcall gives the number of bytes written.
+    /// - `4`: Move file descriptor `data1` to offset `data2`, which may be at
+    ///   most the size of the file.
+    /// - `5`: Close file descriptor `data1`.
+    /// - `6`: Remove the file, or empty directory, at `PATH`.
+    /// - `7`: Copy the name of entry `data1` of the directory at `PATH`, as
+    ///   UTF-8, to `BUFFER`. The upcall gives the length of the name and 1
+    ///   if the entry is a directory, or `NOSUPPORT` after the last entry.
+    /// - `8`: Make a directory at `PATH`.
+    /// - `9`: Return the size of the file of file descriptor `data1`.
+    /// - `10`: Mount the card again, such as after it was changed. This
+    ///   closes every file.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let command = match command_num {
+            0 => return CommandReturn::success(),
+            1 => Command::Open {
+                create: data1 & CREATE != 0,
+            },
+            2 => Command::Read {
+                fd: data1,
+                len: data2,
+            },
+            3 => Command::Write {
+                fd: data1,
+                len: data2,
+            },
+            4 => return self.seek(processid, data1, data2).into(),
+            5 => return self.close(processid, data1).into(),
+            6 => Command::Unlink,
+            7 => Command::ReadDir { index: data1 },
+            8 => Command::Mkdir,
+            9 => {
+                return match self.open_file(processid, data1) {
+                    Ok(open) => CommandReturn::success_u32(open.file.size() as u32),
+                    Err(error) => CommandReturn::failure(error),
+                }
+            }
+            10 => Command::Mount,
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+        self.enqueue(processid, command).into()
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
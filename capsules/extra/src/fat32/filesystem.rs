@@ -0,0 +1,1395 @@
+//! The filesystem: files and directories on a FAT32 volume.
+//!
+//! The volume is either the first FAT32 partition of the disk, or the whole
+//! disk if it has no partition table. Files and directories are found by
+//! their paths, with components separated by `/`, which are compared as FAT
+//! does, ignoring the case of ASCII letters. Names which do not fit in an 8.3
+//! short name are stored as long names, along with a short name with a
+//! numeric tail, such as `LOGFIL~1.CSV`, for systems which only read short
+//! names.
+//!
+//! The filesystem works through a single sector buffer. Each operation is a
+//! sequence of steps which each use one sector, reading it, or writing back
+//! the sector in the buffer first if it was changed, when it is not the
+//! sector in the buffer. Changes are written back before an operation
+//! completes. The free cluster count of the FSInfo sector is not kept, so it
+//! is marked as unknown when mounting.
+//!
+//! Errors are reported as:
+//!
+//! - `NOSUPPORT`: the file or directory does not exist, or the disk does not
+//!   hold a FAT32 volume.
+//! - `ALREADY`: the file or directory already exists.
+//! - `NOMEM`: the volume, or the directory, is full.
+//! - `INVAL`: the path, name or offset is invalid, or the directory to remove
+//!   is not empty.
+//! - `FAIL`: the disk failed, or the volume is corrupted.
+//! - `BUSY`: another operation is in progress.
+//! - `OFF`: the filesystem is not mounted.
+
+use core::cell::Cell;
+
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::block::{BlockClient, BlockDevice};
+use super::layout::{
+    self, ShortEntry, ShortName, Slot, Volume, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_VOLUME_ID,
+    DELETED, END_OF_CHAIN, ENTRY_SIZE, FREE, LAST_LONG_ENTRY, MAX_NAME, SECTOR_SIZE,
+};
+
+/// The longest path.
+pub const MAX_PATH: usize = 128;
+
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+
+/// Evaluates to the value of an `Io`, or returns from the function if it is
+/// waiting for the disk.
+macro_rules! ready {
+    ($io:expr) => {
+        match $io? {
+            Some(value) => value,
+            None => return Ok(None),
+        }
+    };
+}
+
+/// The result of a step, or `None` if it is waiting for the disk.
+type Io<T> = Result<Option<T>, ErrorCode>;
+
+/// Where a directory entry is: entry `index` of `cluster`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Position {
+    cluster: u32,
+    index: u32,
+}
+
+/// A file or directory, as it was when the filesystem last reported it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct File {
+    first_cluster: u32,
+    size: u32,
+    directory: bool,
+    /// The short entry of the file, or `None` for the root directory
+    entry: Option<Position>,
+    /// A cluster of the file, and its index in the chain, to continue from
+    hint: (u32, u32),
+}
+
+impl File {
+    fn new(entry: &ShortEntry, position: Position) -> File {
+        File {
+            first_cluster: entry.first_cluster,
+            size: entry.size,
+            directory: entry.is_directory(),
+            entry: Some(position),
+            hint: (0, entry.first_cluster),
+        }
+    }
+
+    fn root(volume: &Volume) -> File {
+        File {
+            first_cluster: volume.root_cluster,
+            size: 0,
+            directory: true,
+            entry: None,
+            hint: (0, volume.root_cluster),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.directory
+    }
+
+    /// Whether `other` is this file, although it may have changed since.
+    pub fn same(&self, other: &File) -> bool {
+        self.entry == other.entry
+    }
+}
+
+pub trait FatFsClient {
+    fn mount_complete(&self, result: Result<(), ErrorCode>);
+    fn lookup_complete(&self, result: Result<File, ErrorCode>);
+    fn create_complete(&self, result: Result<File, ErrorCode>);
+    /// Returns the file, or directory, which was removed.
+    fn unlink_complete(&self, result: Result<File, ErrorCode>);
+    /// Returns the entry and the length of its name, which `entry_name`
+    /// copies.
+    fn read_dir_complete(&self, result: Result<(File, usize), ErrorCode>);
+    /// Returns the file, the buffer and the number of bytes read into it.
+    fn read_complete(&self, result: Result<(File, usize), ErrorCode>, buffer: &'static mut [u8]);
+    /// Returns the file, which may have grown, the buffer and the number of
+    /// bytes written from it.
+    fn write_complete(&self, result: Result<(File, usize), ErrorCode>, buffer: &'static mut [u8]);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Request {
+    Mount,
+    Lookup,
+    Create,
+    Unlink,
+    ReadDir,
+    Read,
+    Write,
+}
+
+/// Reading the entries of a directory.
+#[derive(Clone, Copy)]
+struct Scan {
+    /// The next entry
+    pos: Position,
+    /// The checksum and length of the long name being read
+    long: Option<(u8, usize)>,
+    /// The first entry of the last file, including its long name
+    start: Position,
+}
+
+impl Scan {
+    fn new(cluster: u32) -> Scan {
+        let pos = Position { cluster, index: 0 };
+        Scan {
+            pos,
+            long: None,
+            start: pos,
+        }
+    }
+}
+
+/// Finding a free cluster, from `cluster`, having looked at `searched`
+/// clusters.
+#[derive(Clone, Copy)]
+struct Alloc {
+    cluster: u32,
+    searched: u32,
+}
+
+/// Following a path.
+#[derive(Clone, Copy)]
+struct Walk {
+    /// The file or directory reached
+    file: File,
+    /// The offset in the path of the rest of the path
+    at: usize,
+    /// Looking for the next component, which ends at the offset
+    scan: Option<(Scan, usize)>,
+    /// The first entry of `file`, including its long name
+    start: Position,
+}
+
+#[derive(Clone, Copy)]
+enum Goal {
+    Lookup,
+    ReadDir(usize),
+    /// Create a directory, or a file, in the directory reached
+    Create(bool),
+    Unlink,
+}
+
+#[derive(Clone, Copy)]
+enum CreateStage {
+    /// Looking for the name, and free entries, in the directory
+    Scan,
+    /// Allocating the cluster of a new directory
+    Allocate(Alloc),
+    Mark(u32),
+    Zero(u32),
+    /// Adding a cluster to the directory, as it has too few free entries
+    Extend(Alloc),
+    ExtendMark(u32, u32),
+    ExtendLink(u32, u32),
+    ExtendZero(u32, u32),
+    /// Writing entry `k` of the new entries
+    Entries(u32, Position),
+}
+
+#[derive(Clone, Copy)]
+struct Create {
+    parent: File,
+    directory: bool,
+    stage: CreateStage,
+    scan: Scan,
+    /// The number of entries the name needs
+    needed: u32,
+    short: [u8; 11],
+    case: u8,
+    /// Whether `short` still needs a numeric tail
+    basis: bool,
+    /// The numeric tails taken by other names
+    tails: u128,
+    /// The run of free entries being counted, and its length
+    run: (Position, u32),
+    /// The first run of free entries long enough
+    found: Option<Position>,
+    /// Whether the end of the entries was reached
+    ended: bool,
+    /// The last cluster of the directory
+    last: u32,
+    /// The cluster of the new directory
+    cluster: u32,
+}
+
+#[derive(Clone, Copy)]
+enum UnlinkStage {
+    /// Checking the directory is empty, before marking the entries from
+    /// the position
+    Empty(Scan, Position),
+    /// Marking the entries of the file as deleted
+    Mark(Position),
+    /// Freeing `cluster`, which is followed by `next`
+    Free {
+        cluster: u32,
+        next: Option<u32>,
+        copy: u32,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum TransferStage {
+    /// Finding the cluster of the offset
+    Seek,
+    /// Adding a cluster to the file
+    Allocate(Alloc),
+    Mark(u32, u32),
+    Link(u32, u32),
+    Data,
+    /// Updating the size of the file in its entry
+    Entry,
+}
+
+#[derive(Clone, Copy)]
+struct Transfer {
+    file: File,
+    offset: u32,
+    len: usize,
+    stage: TransferStage,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Idle,
+    /// Reading the boot sector, or the partition table at sector 0
+    Mount(u32),
+    /// Marking the free cluster count of the FSInfo sector as unknown
+    MountInfo(u32),
+    Walk(Goal, Walk),
+    ReadDir(Scan, usize),
+    Create(Create),
+    Unlink(File, UnlinkStage),
+    Read(Transfer),
+    Write(Transfer),
+    /// Writing back the sector buffer before completing
+    Flush(Result<(File, usize), ErrorCode>),
+    /// Completing from a deferred call
+    Report(Result<(File, usize), ErrorCode>),
+}
+
+enum Progress {
+    Continue,
+    Then(Op),
+    Done(File, usize),
+}
+
+pub struct FatFs<'a, B: BlockDevice<'a>> {
+    device: &'a B,
+    client: OptionalCell<&'a dyn FatFsClient>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    volume: OptionalCell<Volume>,
+    request: Cell<Request>,
+    op: Cell<Op>,
+    /// Whether an operation is being started, and so may not complete yet
+    starting: Cell<bool>,
+    /// The sector in the buffer, and whether it was changed
+    cached: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    /// The sector being read into the buffer
+    loading: Cell<u32>,
+    /// The next cluster to consider allocating
+    next_free: Cell<u32>,
+    buffer: TakeCell<'static, [u8]>,
+    data: TakeCell<'static, [u8]>,
+    path: MapCell<[u8; MAX_PATH]>,
+    path_len: Cell<usize>,
+    /// The name being looked for, or created
+    name: MapCell<[u16; MAX_NAME]>,
+    name_len: Cell<usize>,
+    /// The name of the entry last read
+    long: MapCell<[u16; MAX_NAME]>,
+    long_len: Cell<usize>,
+}
+
+impl<'a, B: BlockDevice<'a>> FatFs<'a, B> {
+    /// `buffer` holds a sector, so must be at least 512 bytes long.
+    pub fn new(
+        device: &'a B,
+        buffer: &'static mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> FatFs<'a, B> {
+        FatFs {
+            device,
+            client: OptionalCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+            volume: OptionalCell::empty(),
+            request: Cell::new(Request::Mount),
+            op: Cell::new(Op::Idle),
+            starting: Cell::new(false),
+            cached: Cell::new(None),
+            dirty: Cell::new(false),
+            loading: Cell::new(0),
+            next_free: Cell::new(2),
+            buffer: TakeCell::new(buffer),
+            data: TakeCell::empty(),
+            path: MapCell::new([0; MAX_PATH]),
+            path_len: Cell::new(0),
+            name: MapCell::new([0; MAX_NAME]),
+            name_len: Cell::new(0),
+            long: MapCell::new([0; MAX_NAME]),
+            long_len: Cell::new(0),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.set(handle);
+    }
+
+    pub fn set_client(&self, client: &'a dyn FatFsClient) {
+        self.client.set(client);
+    }
+
+    fn volume(&self) -> Result<Volume, ErrorCode> {
+        self.volume.extract().ok_or(ErrorCode::OFF)
+    }
+
+    fn ready(&self) -> Result<(), ErrorCode> {
+        self.volume()?;
+        match self.op.get() {
+            Op::Idle => Ok(()),
+            _ => Err(ErrorCode::BUSY),
+        }
+    }
+
+    /// Mount the volume, which may be on a different disk than before.
+    pub fn mount(&self) -> Result<(), ErrorCode> {
+        if !matches!(self.op.get(), Op::Idle) {
+            return Err(ErrorCode::BUSY);
+        }
+        self.volume.clear();
+        self.cached.set(None);
+        self.dirty.set(false);
+        self.begin(Request::Mount, Op::Mount(0));
+        Ok(())
+    }
+
+    /// Find the file or directory at `path`.
+    pub fn lookup(&self, path: &[u8]) -> Result<(), ErrorCode> {
+        self.walk_path(path, Request::Lookup, Goal::Lookup)
+    }
+
+    /// Find entry `index` of the directory at `path`.
+    pub fn read_dir(&self, path: &[u8], index: usize) -> Result<(), ErrorCode> {
+        self.walk_path(path, Request::ReadDir, Goal::ReadDir(index))
+    }
+
+    /// Create an empty file, or directory, at `path`.
+    pub fn create(&self, path: &[u8], directory: bool) -> Result<(), ErrorCode> {
+        self.walk_path(path, Request::Create, Goal::Create(directory))
+    }
+
+    /// Remove the file, or empty directory, at `path`.
+    pub fn unlink(&self, path: &[u8]) -> Result<(), ErrorCode> {
+        self.walk_path(path, Request::Unlink, Goal::Unlink)
+    }
+
+    fn walk_path(&self, path: &[u8], request: Request, goal: Goal) -> Result<(), ErrorCode> {
+        self.ready()?;
+        if path.len() > MAX_PATH {
+            return Err(ErrorCode::INVAL);
+        }
+        self.path
+            .map(|buffer| buffer[..path.len()].copy_from_slice(path));
+        self.path_len.set(path.len());
+
+        let root = File::root(&self.volume()?);
+        let walk = Walk {
+            file: root,
+            at: 0,
+            scan: None,
+            start: Scan::new(root.first_cluster).start,
+        };
+        self.begin(request, Op::Walk(goal, walk));
+        Ok(())
+    }
+
+    /// Copy the name of the entry `read_dir` found, as UTF-8, to `name`,
+    /// returning the length of the name.
+    pub fn entry_name(&self, name: &mut [u8]) -> usize {
+        self.long.map_or(0, |long| {
+            layout::to_utf8(&long[..self.long_len.get()], name)
+        })
+    }
+
+    /// Read up to `len` bytes of `file` from `offset` into `buffer`. At most
+    /// one sector is read at a time, so fewer bytes may be read. Returns
+    /// `SIZE` at the end of the file.
+    pub fn read(
+        &self,
+        file: File,
+        offset: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let len = len.min(buffer.len());
+        let checked = self.ready().and_then(|()| {
+            if file.directory {
+                Err(ErrorCode::INVAL)
+            } else if offset >= file.size() {
+                Err(ErrorCode::SIZE)
+            } else {
+                Ok(len
+                    .min(file.size() - offset)
+                    .min(SECTOR_SIZE - offset % SECTOR_SIZE))
+            }
+        });
+        match checked {
+            Ok(len) => {
+                self.data.replace(buffer);
+                let transfer = Transfer {
+                    file,
+                    offset: offset as u32,
+                    len,
+                    stage: TransferStage::Seek,
+                };
+                self.begin(Request::Read, Op::Read(transfer));
+                Ok(())
+            }
+            Err(error) => Err((error, buffer)),
+        }
+    }
+
+    /// Write up to `len` bytes of `buffer` to `file` at `offset`, which must
+    /// not be past the end of the file. At most one sector is written at a
+    /// time, so fewer bytes may be written.
+    pub fn write(
+        &self,
+        file: File,
+        offset: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let len = len.min(buffer.len());
+        let checked = self.ready().and_then(|()| {
+            let len = len.min(SECTOR_SIZE - offset % SECTOR_SIZE);
+            if file.directory || offset > file.size() {
+                Err(ErrorCode::INVAL)
+            } else if len == 0 || offset + len > u32::MAX as usize {
+                Err(ErrorCode::SIZE)
+            } else {
+                Ok(len)
+            }
+        });
+        match checked {
+            Ok(len) => {
+                self.data.replace(buffer);
+                let stage = if file.first_cluster == 0 {
+                    TransferStage::Allocate(self.alloc())
+                } else {
+                    TransferStage::Seek
+                };
+                let transfer = Transfer {
+                    file,
+                    offset: offset as u32,
+                    len,
+                    stage,
+                };
+                self.begin(Request::Write, Op::Write(transfer));
+                Ok(())
+            }
+            Err(error) => Err((error, buffer)),
+        }
+    }
+
+    fn begin(&self, request: Request, op: Op) {
+        self.request.set(request);
+        self.op.set(op);
+        self.starting.set(true);
+        self.run();
+        self.starting.set(false);
+    }
+
+    // Takes steps until the operation waits for the disk, or completes.
+    fn run(&self) {
+        loop {
+            let mut op = self.op.get();
+            let progress = self.step(&mut op);
+            self.op.set(op);
+            match progress {
+                Ok(None) => return,
+                Ok(Some(Progress::Continue)) => {}
+                Ok(Some(Progress::Then(op))) => self.op.set(op),
+                Ok(Some(Progress::Done(file, value))) => return self.finish(Ok((file, value))),
+                Err(error) => return self.finish(Err(error)),
+            }
+        }
+    }
+
+    // Writes back the sector buffer, then completes the operation.
+    fn finish(&self, result: Result<(File, usize), ErrorCode>) {
+        if self.dirty.get() {
+            self.op.set(Op::Flush(result));
+            if let Err(error) = self.flush() {
+                self.end(Err(error));
+            }
+        } else {
+            self.end(result);
+        }
+    }
+
+    fn end(&self, result: Result<(File, usize), ErrorCode>) {
+        if self.starting.get() {
+            // The client is not ready for the callback until the operation
+            // has started
+            self.op.set(Op::Report(result));
+            self.handle.map(|handle| self.deferred_caller.set(*handle));
+        } else {
+            self.op.set(Op::Idle);
+            self.report(result);
+        }
+    }
+
+    fn report(&self, result: Result<(File, usize), ErrorCode>) {
+        let file = result.map(|(file, _)| file);
+        match self.request.get() {
+            Request::Mount => {
+                if result.is_err() {
+                    self.volume.clear();
+                }
+                self.client
+                    .map(|client| client.mount_complete(result.map(|_| ())));
+            }
+            Request::Lookup => {
+                self.client.map(|client| client.lookup_complete(file));
+            }
+            Request::Create => {
+                self.client.map(|client| client.create_complete(file));
+            }
+            Request::Unlink => {
+                self.client.map(|client| client.unlink_complete(file));
+            }
+            Request::ReadDir => {
+                self.client.map(|client| client.read_dir_complete(result));
+            }
+            Request::Read => {
+                self.data.take().map(|buffer| {
+                    self.client
+                        .map(|client| client.read_complete(result, buffer))
+                });
+            }
+            Request::Write => {
+                self.data.take().map(|buffer| {
+                    self.client
+                        .map(|client| client.write_complete(result, buffer))
+                });
+            }
+        }
+    }
+
+    fn flush(&self) -> Result<(), ErrorCode> {
+        let sector = self.cached.get().ok_or(ErrorCode::FAIL)?;
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        self.device
+            .write_block(sector, buffer)
+            .map_err(|(error, buffer)| {
+                self.buffer.put(buffer);
+                error
+            })
+    }
+
+    fn load(&self, sector: u32) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+        self.cached.set(None);
+        self.loading.set(sector);
+        self.device
+            .read_block(sector, buffer)
+            .map_err(|(error, buffer)| {
+                self.buffer.put(buffer);
+                error
+            })
+    }
+
+    /// Run `f` on `sector`, once it is in the buffer. If `read` is false the
+    /// sector is not read, but starts as zeros, and if `modify` is true it is
+    /// written back later.
+    fn access<R>(
+        &self,
+        sector: u32,
+        read: bool,
+        modify: bool,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Io<R> {
+        // The buffer is only missing if the disk lost it with a failed
+        // transfer
+        if self.buffer.is_none() {
+            return Err(ErrorCode::NOMEM);
+        }
+        if self.cached.get() != Some(sector) {
+            if self.dirty.get() {
+                self.flush()?;
+                return Ok(None);
+            }
+            if read {
+                self.load(sector)?;
+                return Ok(None);
+            }
+            self.cached.set(Some(sector));
+            self.buffer
+                .map(|buffer| buffer[..SECTOR_SIZE].iter_mut().for_each(|b| *b = 0));
+        }
+        if modify {
+            self.dirty.set(true);
+        }
+        Ok(self.buffer.map(|buffer| f(&mut buffer[..SECTOR_SIZE])))
+    }
+
+    fn sector<R>(&self, sector: u32, f: impl FnOnce(&mut [u8]) -> R) -> Io<R> {
+        self.access(sector, true, false, f)
+    }
+
+    fn sector_mut<R>(&self, sector: u32, f: impl FnOnce(&mut [u8]) -> R) -> Io<R> {
+        self.access(sector, true, true, f)
+    }
+
+    /// The sector, and offset in it, of the entry at `pos`.
+    fn entry_sector(&self, volume: &Volume, pos: Position) -> (u32, usize) {
+        let sector = volume.sector(pos.cluster, pos.index / ENTRIES_PER_SECTOR);
+        (
+            sector,
+            (pos.index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE,
+        )
+    }
+
+    /// The entry of `cluster` in the FAT.
+    fn fat_value(&self, cluster: u32) -> Io<u32> {
+        let (sector, offset) = self.volume()?.fat_entry(0, cluster);
+        self.sector(sector, |sector| layout::fat_entry(sector, offset))
+    }
+
+    /// The cluster after `cluster`, or `None` at the end of the chain.
+    fn next_cluster(&self, cluster: u32) -> Io<Option<u32>> {
+        let next = ready!(self.fat_value(cluster));
+        if next >= END_OF_CHAIN {
+            Ok(Some(None))
+        } else if self.volume()?.is_cluster(next) {
+            Ok(Some(Some(next)))
+        } else {
+            Err(ErrorCode::FAIL)
+        }
+    }
+
+    /// Set the entry of `cluster` in each FAT, from FAT `copy`.
+    fn set_fat(&self, cluster: u32, value: u32, copy: &mut u32) -> Io<()> {
+        let volume = self.volume()?;
+        while *copy < volume.fats {
+            let (sector, offset) = volume.fat_entry(*copy, cluster);
+            ready!(self.sector_mut(sector, |sector| {
+                layout::set_fat_entry(sector, offset, value)
+            }));
+            *copy += 1;
+        }
+        Ok(Some(()))
+    }
+
+    fn alloc(&self) -> Alloc {
+        Alloc {
+            cluster: self.next_free.get(),
+            searched: 0,
+        }
+    }
+
+    /// Find a free cluster.
+    fn allocate(&self, alloc: &mut Alloc) -> Io<u32> {
+        let volume = self.volume()?;
+        loop {
+            if alloc.searched >= volume.end_cluster - 2 {
+                return Err(ErrorCode::NOMEM);
+            }
+            if !volume.is_cluster(alloc.cluster) {
+                alloc.cluster = 2;
+            }
+
+            // Look at the rest of the FAT sector
+            let first = alloc.cluster;
+            let (sector, offset) = volume.fat_entry(0, first);
+            let count = (((SECTOR_SIZE - offset) / 4) as u32).min(volume.end_cluster - first);
+            let free = ready!(self.sector(sector, |sector| {
+                (0..count).find(|i| layout::fat_entry(sector, offset + *i as usize * 4) == FREE)
+            }));
+            if let Some(i) = free {
+                self.next_free.set(first + i + 1);
+                return Ok(Some(first + i));
+            }
+            alloc.searched += count;
+            alloc.cluster = first + count;
+        }
+    }
+
+    /// Zero `cluster`, from sector `n`, adding the `.` and `..` entries of a
+    /// directory if `parent` is given.
+    fn zero(&self, cluster: u32, n: &mut u32, parent: Option<u32>) -> Io<()> {
+        let volume = self.volume()?;
+        while *n < volume.sectors_per_cluster {
+            let first = *n == 0;
+            ready!(
+                self.access(volume.sector(cluster, *n), false, true, |sector| {
+                    sector.iter_mut().for_each(|b| *b = 0);
+                    if let (true, Some(parent)) = (first, parent) {
+                        let mut dot = ShortEntry::new(*b".          ", 0, ATTR_DIRECTORY);
+                        dot.first_cluster = cluster;
+                        dot.write(&mut sector[..ENTRY_SIZE]);
+                        dot.name[1] = b'.';
+                        dot.first_cluster = parent;
+                        dot.write(&mut sector[ENTRY_SIZE..2 * ENTRY_SIZE]);
+                    }
+                })
+            );
+            *n += 1;
+        }
+        Ok(Some(()))
+    }
+
+    /// The next entry of a directory, or `None` at the end of its clusters.
+    fn next_slot(&self, scan: &mut Scan) -> Io<Option<(Slot, Position)>> {
+        let volume = self.volume()?;
+        if scan.pos.index == volume.entries_per_cluster() {
+            match ready!(self.next_cluster(scan.pos.cluster)) {
+                Some(cluster) => scan.pos = Position { cluster, index: 0 },
+                None => return Ok(Some(None)),
+            }
+        }
+
+        let pos = scan.pos;
+        let (sector, offset) = self.entry_sector(&volume, pos);
+        let slot = ready!(self.sector(sector, |sector| {
+            Slot::parse(&sector[offset..offset + ENTRY_SIZE])
+        }));
+        scan.pos.index += 1;
+        Ok(Some(Some((slot, pos))))
+    }
+
+    /// Collect the name of a file from its entries, returning the short entry
+    /// and the length of the name, which is left in `long`, once the short
+    /// entry is reached. The volume label, `.` and `..` are skipped.
+    fn named(&self, scan: &mut Scan, slot: Slot, pos: Position) -> Option<(ShortEntry, usize)> {
+        match slot {
+            Slot::Long {
+                order,
+                checksum,
+                chars,
+            } => {
+                if order & LAST_LONG_ENTRY != 0 {
+                    scan.start = pos;
+                    scan.long = self
+                        .long
+                        .map(|long| layout::read_long_entry(long, order, &chars))
+                        .flatten()
+                        .map(|len| (checksum, len));
+                } else if scan.long.map(|(sum, _)| sum) == Some(checksum) {
+                    self.long
+                        .map(|long| layout::read_long_entry(long, order, &chars));
+                } else {
+                    scan.long = None;
+                }
+                None
+            }
+            Slot::Short(entry) => {
+                let long = scan.long.take();
+                if entry.attributes & ATTR_VOLUME_ID != 0 || entry.name[0] == b'.' {
+                    return None;
+                }
+                let len = match long {
+                    Some((checksum, len)) if checksum == entry.checksum() => len,
+                    _ => {
+                        scan.start = pos;
+                        self.long.map_or(0, |long| entry.display(long))
+                    }
+                };
+                Some((entry, len))
+            }
+            Slot::Free | Slot::End => {
+                scan.long = None;
+                None
+            }
+        }
+    }
+
+    /// Whether the name collected in `long` is the name looked for.
+    fn matches(&self, len: usize) -> bool {
+        self.name
+            .map(|name| {
+                self.long
+                    .map(|long| layout::names_equal(&name[..self.name_len.get()], &long[..len]))
+            })
+            .flatten()
+            .unwrap_or(false)
+    }
+
+    /// Find the entry with the name looked for.
+    fn find(&self, scan: &mut Scan) -> Io<Option<File>> {
+        loop {
+            match ready!(self.next_slot(scan)) {
+                None | Some((Slot::End, _)) => return Ok(Some(None)),
+                Some((slot, pos)) => {
+                    if let Some((entry, len)) = self.named(scan, slot, pos) {
+                        if self.matches(len) {
+                            return Ok(Some(Some(File::new(&entry, pos))));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The start and end of the component of the path at `at`.
+    fn component(&self, at: usize) -> (usize, usize) {
+        let len = self.path_len.get();
+        self.path
+            .map(|path| {
+                let start = (at..len).find(|i| path[*i] != b'/').unwrap_or(len);
+                let end = (start..len).find(|i| path[*i] == b'/').unwrap_or(len);
+                (start, end)
+            })
+            .unwrap_or((len, len))
+    }
+
+    /// Make the component from `start` to `end` the name looked for.
+    fn load_name(&self, start: usize, end: usize) -> Result<(), ErrorCode> {
+        let len = self
+            .path
+            .map(|path| {
+                self.name
+                    .map(|name| layout::to_utf16(&path[start..end], name))
+                    .flatten()
+                    .filter(|len| {
+                        self.name
+                            .map_or(false, |name| layout::valid_name(&name[..*len]))
+                    })
+            })
+            .flatten()
+            .ok_or(ErrorCode::INVAL)?;
+        self.name_len.set(len);
+        Ok(())
+    }
+
+    /// Follow the path, up to its last component if `parent` is true, which
+    /// is then left as the name looked for.
+    fn walk(&self, walk: &mut Walk, parent: bool) -> Io<File> {
+        loop {
+            let (scan, end) = match walk.scan.as_mut() {
+                Some((scan, end)) => (scan, *end),
+                None => {
+                    let (start, end) = self.component(walk.at);
+                    if start == end {
+                        return if parent {
+                            Err(ErrorCode::INVAL)
+                        } else {
+                            Ok(Some(walk.file))
+                        };
+                    }
+                    if !walk.file.directory {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    self.load_name(start, end)?;
+                    let (next, _) = self.component(end);
+                    if parent && next == self.path_len.get() {
+                        return Ok(Some(walk.file));
+                    }
+                    walk.scan = Some((Scan::new(walk.file.first_cluster), end));
+                    walk.at = end;
+                    continue;
+                }
+            };
+
+            match ready!(self.find(scan)) {
+                Some(mut file) => {
+                    // `..` of a directory in the root holds cluster 0
+                    if file.directory && file.first_cluster == 0 {
+                        file.first_cluster = self.volume()?.root_cluster;
+                    }
+                    walk.start = scan.start;
+                    walk.file = file;
+                    walk.at = end;
+                    walk.scan = None;
+                }
+                None => return Err(ErrorCode::NOSUPPORT),
+            }
+        }
+    }
+
+    fn step(&self, op: &mut Op) -> Io<Progress> {
+        match op {
+            Op::Idle | Op::Report(_) => Ok(None),
+            Op::Flush(result) => result.map(|(file, value)| Some(Progress::Done(file, value))),
+            Op::Mount(start) => self.mount_step(*start),
+            Op::MountInfo(sector) => {
+                ready!(self.sector_mut(*sector, layout::clear_fsinfo));
+                let root = File::root(&self.volume()?);
+                Ok(Some(Progress::Done(root, 0)))
+            }
+            Op::Walk(goal, walk) => {
+                let file = ready!(self.walk(walk, matches!(goal, Goal::Create(_))));
+                self.walked(*goal, file, walk.start)
+            }
+            Op::ReadDir(scan, remaining) => loop {
+                match ready!(self.next_slot(scan)) {
+                    None | Some((Slot::End, _)) => return Err(ErrorCode::NOSUPPORT),
+                    Some((slot, pos)) => {
+                        if let Some((entry, len)) = self.named(scan, slot, pos) {
+                            if *remaining == 0 {
+                                self.long_len.set(len);
+                                let utf8_len = self.entry_name(&mut []);
+                                return Ok(Some(Progress::Done(File::new(&entry, pos), utf8_len)));
+                            }
+                            *remaining -= 1;
+                        }
+                    }
+                }
+            },
+            Op::Create(create) => self.create_step(create),
+            Op::Unlink(file, stage) => self.unlink_step(file, stage),
+            Op::Read(transfer) => self.transfer_step(transfer, false),
+            Op::Write(transfer) => self.transfer_step(transfer, true),
+        }
+    }
+
+    fn mount_step(&self, start: u32) -> Io<Progress> {
+        let parsed = ready!(self.sector(start, |sector| {
+            Volume::parse(sector, start).map_err(|error| (error, layout::partition_start(sector)))
+        }));
+        match parsed {
+            Ok(volume) => {
+                self.volume.set(volume);
+                self.next_free.set(2);
+                Ok(Some(match volume.fsinfo {
+                    Some(sector) => Progress::Then(Op::MountInfo(sector)),
+                    None => Progress::Done(File::root(&volume), 0),
+                }))
+            }
+            Err((_, Some(partition))) if start == 0 => {
+                Ok(Some(Progress::Then(Op::Mount(partition))))
+            }
+            Err((error, _)) => Err(error),
+        }
+    }
+
+    /// Continue once the path has been followed to `file`, which starts at
+    /// `start`.
+    fn walked(&self, goal: Goal, file: File, start: Position) -> Io<Progress> {
+        let then = match goal {
+            Goal::Lookup => return Ok(Some(Progress::Done(file, 0))),
+            Goal::ReadDir(index) => {
+                if !file.directory {
+                    return Err(ErrorCode::INVAL);
+                }
+                Op::ReadDir(Scan::new(file.first_cluster), index)
+            }
+            Goal::Unlink => {
+                if file.entry.is_none() {
+                    return Err(ErrorCode::INVAL);
+                }
+                let stage = if file.directory && self.volume()?.is_cluster(file.first_cluster) {
+                    UnlinkStage::Empty(Scan::new(file.first_cluster), start)
+                } else {
+                    UnlinkStage::Mark(start)
+                };
+                Op::Unlink(file, stage)
+            }
+            Goal::Create(directory) => {
+                let len = self.name_len.get();
+                let short = self
+                    .name
+                    .map(|name| layout::short_name(&name[..len]))
+                    .ok_or(ErrorCode::FAIL)?;
+                let (short, case, basis, needed) = match short {
+                    ShortName::Exact(short, case) => (short, case, false, 1),
+                    ShortName::Basis(basis) => {
+                        let needed = self
+                            .name
+                            .map_or(0, |name| layout::long_entries(&name[..len]));
+                        (basis, 0, true, needed as u32 + 1)
+                    }
+                };
+                Op::Create(Create {
+                    parent: file,
+                    directory,
+                    stage: CreateStage::Scan,
+                    scan: Scan::new(file.first_cluster),
+                    needed,
+                    short,
+                    case,
+                    basis,
+                    tails: 0,
+                    run: (Scan::new(file.first_cluster).pos, 0),
+                    found: None,
+                    ended: false,
+                    last: file.first_cluster,
+                    cluster: 0,
+                })
+            }
+        };
+        Ok(Some(Progress::Then(then)))
+    }
+
+    fn create_step(&self, create: &mut Create) -> Io<Progress> {
+        let volume = self.volume()?;
+        match &mut create.stage {
+            CreateStage::Scan => {
+                if create.ended && create.found.is_some() {
+                    return self.create_entries(create);
+                }
+                let (slot, pos) = match ready!(self.next_slot(&mut create.scan)) {
+                    Some(next) => next,
+                    None => {
+                        create.last = create.scan.pos.cluster;
+                        return self.create_entries(create);
+                    }
+                };
+
+                // Every entry after the end is free
+                if create.ended || slot == Slot::Free || slot == Slot::End {
+                    if create.run.1 == 0 {
+                        create.run.0 = pos;
+                    }
+                    create.run.1 += 1;
+                    if create.run.1 == create.needed && create.found.is_none() {
+                        create.found = Some(create.run.0);
+                    }
+                    create.ended |= slot == Slot::End;
+                    return Ok(Some(Progress::Continue));
+                }
+
+                create.run.1 = 0;
+                if let Some((entry, len)) = self.named(&mut create.scan, slot, pos) {
+                    if self.matches(len) || (!create.basis && entry.name == create.short) {
+                        return Err(ErrorCode::ALREADY);
+                    }
+                    if create.basis {
+                        match layout::tail_number(&entry.name, &create.short) {
+                            Some(n) if n < 128 => create.tails |= 1 << n,
+                            _ => {}
+                        }
+                    }
+                }
+                Ok(Some(Progress::Continue))
+            }
+            CreateStage::Allocate(alloc) => {
+                let cluster = ready!(self.allocate(alloc));
+                create.cluster = cluster;
+                create.stage = CreateStage::Mark(0);
+                Ok(Some(Progress::Continue))
+            }
+            CreateStage::Mark(copy) => {
+                ready!(self.set_fat(create.cluster, END_OF_CHAIN | 0xF, copy));
+                create.stage = CreateStage::Zero(0);
+                Ok(Some(Progress::Continue))
+            }
+            CreateStage::Zero(n) => {
+                // The `..` of a directory in the root holds cluster 0
+                let parent = match create.parent.entry {
+                    Some(_) => create.parent.first_cluster,
+                    None => 0,
+                };
+                ready!(self.zero(create.cluster, n, Some(parent)));
+                create.stage = CreateStage::Scan;
+                self.create_entries(create)
+            }
+            CreateStage::Extend(alloc) => {
+                let cluster = ready!(self.allocate(alloc));
+                create.stage = CreateStage::ExtendMark(cluster, 0);
+                Ok(Some(Progress::Continue))
+            }
+            CreateStage::ExtendMark(cluster, copy) => {
+                let cluster = *cluster;
+                ready!(self.set_fat(cluster, END_OF_CHAIN | 0xF, copy));
+                create.stage = CreateStage::ExtendLink(cluster, 0);
+                Ok(Some(Progress::Continue))
+            }
+            CreateStage::ExtendLink(cluster, copy) => {
+                let cluster = *cluster;
+                ready!(self.set_fat(create.last, cluster, copy));
+                create.stage = CreateStage::ExtendZero(cluster, 0);
+                Ok(Some(Progress::Continue))
+            }
+            CreateStage::ExtendZero(cluster, n) => {
+                let cluster = *cluster;
+                ready!(self.zero(cluster, n, None));
+                // The new entries may start in the free entries at the end
+                if create.run.1 == 0 {
+                    create.run.0 = Position { cluster, index: 0 };
+                }
+                create.run.1 += volume.entries_per_cluster();
+                if create.run.1 >= create.needed {
+                    create.found = Some(create.run.0);
+                }
+                create.last = cluster;
+                create.stage = CreateStage::Scan;
+                self.create_entries(create)
+            }
+            CreateStage::Entries(k, pos) => {
+                if pos.index == volume.entries_per_cluster() {
+                    match ready!(self.next_cluster(pos.cluster)) {
+                        Some(cluster) => *pos = Position { cluster, index: 0 },
+                        None => return Err(ErrorCode::FAIL),
+                    }
+                }
+
+                let short_entry = *k + 1 == create.needed;
+                let mut entry = ShortEntry::new(
+                    create.short,
+                    create.case,
+                    if create.directory {
+                        ATTR_DIRECTORY
+                    } else {
+                        ATTR_ARCHIVE
+                    },
+                );
+                entry.first_cluster = create.cluster;
+                let (sector, offset) = self.entry_sector(&volume, *pos);
+                let part = (create.needed - 1 - *k) as usize;
+                let len = self.name_len.get();
+                ready!(self.sector_mut(sector, |sector| {
+                    let slot = &mut sector[offset..offset + ENTRY_SIZE];
+                    if short_entry {
+                        entry.write(slot);
+                    } else {
+                        self.name.map(|name| {
+                            layout::write_long_entry(slot, &name[..len], part, entry.checksum())
+                        });
+                    }
+                }));
+
+                if short_entry {
+                    return Ok(Some(Progress::Done(File::new(&entry, *pos), 0)));
+                }
+                *k += 1;
+                pos.index += 1;
+                Ok(Some(Progress::Continue))
+            }
+        }
+    }
+
+    /// Move on from scanning the directory: allocate the cluster of a new
+    /// directory, extend the directory if it has too few free entries, or
+    /// write the entries.
+    fn create_entries(&self, create: &mut Create) -> Io<Progress> {
+        if create.basis {
+            let n = (1..128)
+                .find(|n| create.tails & (1 << n) == 0)
+                .ok_or(ErrorCode::NOMEM)?;
+            create.short = layout::with_tail(&create.short, n);
+            create.basis = false;
+        }
+
+        create.stage = if create.directory && create.cluster == 0 {
+            CreateStage::Allocate(self.alloc())
+        } else {
+            match create.found {
+                Some(pos) => CreateStage::Entries(0, pos),
+                None => CreateStage::Extend(self.alloc()),
+            }
+        };
+        Ok(Some(Progress::Continue))
+    }
+
+    fn unlink_step(&self, file: &mut File, stage: &mut UnlinkStage) -> Io<Progress> {
+        let volume = self.volume()?;
+        match stage {
+            UnlinkStage::Empty(scan, start) => {
+                match ready!(self.next_slot(scan)) {
+                    None | Some((Slot::End, _)) => *stage = UnlinkStage::Mark(*start),
+                    Some((slot, pos)) => {
+                        if self.named(scan, slot, pos).is_some() {
+                            return Err(ErrorCode::INVAL);
+                        }
+                    }
+                }
+                Ok(Some(Progress::Continue))
+            }
+            UnlinkStage::Mark(pos) => {
+                if pos.index == volume.entries_per_cluster() {
+                    match ready!(self.next_cluster(pos.cluster)) {
+                        Some(cluster) => *pos = Position { cluster, index: 0 },
+                        None => return Err(ErrorCode::FAIL),
+                    }
+                }
+                let (sector, offset) = self.entry_sector(&volume, *pos);
+                ready!(self.sector_mut(sector, |sector| sector[offset] = DELETED));
+
+                if Some(*pos) == file.entry {
+                    *stage = UnlinkStage::Free {
+                        cluster: file.first_cluster,
+                        next: None,
+                        copy: 0,
+                    };
+                } else {
+                    pos.index += 1;
+                }
+                Ok(Some(Progress::Continue))
+            }
+            UnlinkStage::Free {
+                cluster,
+                next,
+                copy,
+            } => {
+                if !volume.is_cluster(*cluster) {
+                    return Ok(Some(Progress::Done(*file, 0)));
+                }
+                let following = match next {
+                    Some(following) => *following,
+                    None => {
+                        let following = ready!(self.fat_value(*cluster));
+                        *next = Some(following);
+                        following
+                    }
+                };
+                ready!(self.set_fat(*cluster, FREE, copy));
+                *cluster = following;
+                *next = None;
+                *copy = 0;
+                Ok(Some(Progress::Continue))
+            }
+        }
+    }
+
+    fn transfer_step(&self, transfer: &mut Transfer, write: bool) -> Io<Progress> {
+        let volume = self.volume()?;
+        let file = &mut transfer.file;
+        let cluster_index = transfer.offset / volume.cluster_bytes() as u32;
+        match &mut transfer.stage {
+            TransferStage::Seek => {
+                if file.hint.0 > cluster_index || !volume.is_cluster(file.hint.1) {
+                    file.hint = (0, file.first_cluster);
+                }
+                if file.hint.0 == cluster_index {
+                    transfer.stage = TransferStage::Data;
+                    return Ok(Some(Progress::Continue));
+                }
+                match ready!(self.next_cluster(file.hint.1)) {
+                    Some(next) => file.hint = (file.hint.0 + 1, next),
+                    // Only writing at the end of the file adds a cluster
+                    None if write => transfer.stage = TransferStage::Allocate(self.alloc()),
+                    None => return Err(ErrorCode::FAIL),
+                }
+                Ok(Some(Progress::Continue))
+            }
+            TransferStage::Allocate(alloc) => {
+                let cluster = ready!(self.allocate(alloc));
+                transfer.stage = TransferStage::Mark(cluster, 0);
+                Ok(Some(Progress::Continue))
+            }
+            TransferStage::Mark(cluster, copy) => {
+                let cluster = *cluster;
+                ready!(self.set_fat(cluster, END_OF_CHAIN | 0xF, copy));
+                if volume.is_cluster(file.first_cluster) {
+                    transfer.stage = TransferStage::Link(cluster, 0);
+                } else {
+                    file.first_cluster = cluster;
+                    file.hint = (0, cluster);
+                    transfer.stage = TransferStage::Seek;
+                }
+                Ok(Some(Progress::Continue))
+            }
+            TransferStage::Link(cluster, copy) => {
+                let cluster = *cluster;
+                ready!(self.set_fat(file.hint.1, cluster, copy));
+                file.hint = (file.hint.0 + 1, cluster);
+                transfer.stage = TransferStage::Seek;
+                Ok(Some(Progress::Continue))
+            }
+            TransferStage::Data => {
+                if !volume.is_cluster(file.hint.1) {
+                    return Err(ErrorCode::FAIL);
+                }
+                let in_cluster = transfer.offset as usize % volume.cluster_bytes();
+                let sector = volume.sector(file.hint.1, (in_cluster / SECTOR_SIZE) as u32);
+                let offset = in_cluster % SECTOR_SIZE;
+                let len = transfer.len;
+                if write {
+                    // A sector past the end of the file holds nothing to keep
+                    let fresh = offset == 0 && transfer.offset >= file.size;
+                    ready!(self.access(sector, !fresh, true, |sector| {
+                        self.data
+                            .map(|data| sector[offset..offset + len].copy_from_slice(&data[..len]))
+                    }));
+                    file.size = file.size.max(transfer.offset + len as u32);
+                    transfer.stage = TransferStage::Entry;
+                    Ok(Some(Progress::Continue))
+                } else {
+                    ready!(self.sector(sector, |sector| {
+                        self.data
+                            .map(|data| data[..len].copy_from_slice(&sector[offset..offset + len]))
+                    }));
+                    Ok(Some(Progress::Done(*file, len)))
+                }
+            }
+            TransferStage::Entry => {
+                let pos = file.entry.ok_or(ErrorCode::FAIL)?;
+                let (sector, offset) = self.entry_sector(&volume, pos);
+                let (first_cluster, size) = (file.first_cluster, file.size);
+                ready!(self.sector_mut(sector, |sector| {
+                    ShortEntry::update(
+                        &mut sector[offset..offset + ENTRY_SIZE],
+                        first_cluster,
+                        size,
+                    )
+                }));
+                Ok(Some(Progress::Done(*file, transfer.len)))
+            }
+        }
+    }
+}
+
+impl<'a, B: BlockDevice<'a>> BlockClient for FatFs<'a, B> {
+    fn read_complete(&self, buffer: Option<&'static mut [u8]>, result: Result<(), ErrorCode>) {
+        self.buffer.put(buffer);
+        match result {
+            Ok(()) => {
+                self.cached.set(Some(self.loading.get()));
+                self.run();
+            }
+            Err(_) => self.end(Err(ErrorCode::FAIL)),
+        }
+    }
+
+    fn write_complete(&self, buffer: Option<&'static mut [u8]>, result: Result<(), ErrorCode>) {
+        self.buffer.put(buffer);
+        match result {
+            Ok(()) => {
+                self.dirty.set(false);
+                self.run();
+            }
+            Err(_) => {
+                // The changes in the buffer are lost
+                self.cached.set(None);
+                self.dirty.set(false);
+                self.end(Err(ErrorCode::FAIL));
+            }
+        }
+    }
+}
+
+impl<'a, B: BlockDevice<'a>> DynamicDeferredCallClient for FatFs<'a, B> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Op::Report(result) = self.op.get() {
+            self.op.set(Op::Idle);
+            self.report(result);
+        }
+    }
+}
@@ -0,0 +1,555 @@
+//! The on-disk structures of FAT32: the boot sector, directory entries and
+//! file names.
+//!
+//! Directory entries are 32 bytes. A file has a short entry, holding its 8.3
+//! name, attributes, first cluster and size, which is preceded by long name
+//! entries if its name does not fit in 8.3 characters. Each long name entry
+//! holds 13 UTF-16 characters of the name, and the checksum of the short
+//! name it belongs to:
+//!
+//! ```text
+//! +-------+------------+------+------+----------+-------------+---+-------------+
+//! | order | chars 1..5 | 0x0F | 0    | checksum | chars 6..11 | 0 | chars 12,13 |
+//! | 1     | 10         | 1    | 1    | 1        | 12          | 2 | 4           |
+//! +-------+------------+------+------+----------+-------------+---+-------------+
+//! ```
+//!
+//! The long name entries are stored last part first, and the order of the
+//! first of them has `LAST_LONG_ENTRY` set.
+
+use kernel::ErrorCode;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const ENTRY_SIZE: usize = 32;
+/// The longest file name, in UTF-16 characters.
+pub const MAX_NAME: usize = 255;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Marks a deleted entry.
+pub const DELETED: u8 = 0xE5;
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+const CHARS_PER_LONG_ENTRY: usize = 13;
+
+/// The case flags of short names: the base, and extension, are lowercase.
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXTENSION: u8 = 0x10;
+
+/// Free clusters are 0 in the FAT, and the last cluster of a chain is at
+/// least `END_OF_CHAIN`.
+pub const FREE: u32 = 0;
+pub const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+
+/// 1980-01-01, the earliest date FAT can store.
+const DATE: u16 = 0x0021;
+
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn has_signature(sector: &[u8]) -> bool {
+    sector[510] == 0x55 && sector[511] == 0xAA
+}
+
+/// The first sector of the first FAT32 partition in a master boot record,
+/// or `None` if `sector` is not a master boot record.
+pub fn partition_start(sector: &[u8]) -> Option<u32> {
+    if !has_signature(sector) {
+        return None;
+    }
+    (0..4).map(|i| 446 + i * 16).find_map(|entry| {
+        let kind = sector[entry + 4];
+        let start = read_u32(sector, entry + 8);
+        ((kind == 0x0B || kind == 0x0C) && start != 0).then_some(start)
+    })
+}
+
+/// The layout of a FAT32 volume, from its boot sector.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Volume {
+    pub sectors_per_cluster: u32,
+    /// The first sector of the first FAT
+    fat_start: u32,
+    fat_sectors: u32,
+    /// The FATs to update: all of them, unless mirroring is disabled
+    first_fat: u32,
+    pub fats: u32,
+    /// The sector of cluster 2, the first data cluster
+    data_start: u32,
+    /// The number of the cluster after the last one
+    pub end_cluster: u32,
+    pub root_cluster: u32,
+    pub fsinfo: Option<u32>,
+}
+
+impl Volume {
+    /// Parse the boot sector of the volume starting at sector `start`.
+    pub fn parse(sector: &[u8], start: u32) -> Result<Volume, ErrorCode> {
+        let bytes_per_sector = read_u16(sector, 11) as usize;
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved = read_u16(sector, 14) as u32;
+        let fats = sector[16] as u32;
+        let root_entries = read_u16(sector, 17);
+        let fat_sectors_16 = read_u16(sector, 22);
+        let total_sectors = match read_u16(sector, 19) {
+            0 => read_u32(sector, 32),
+            sectors => sectors as u32,
+        };
+        let fat_sectors = read_u32(sector, 36);
+        let ext_flags = read_u16(sector, 40);
+        let root_cluster = read_u32(sector, 44);
+        let fsinfo = read_u16(sector, 48) as u32;
+
+        // Only FAT32 has no fixed root directory, and the 32 bit FAT size
+        if !has_signature(sector)
+            || bytes_per_sector != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || fats == 0
+            || root_entries != 0
+            || fat_sectors_16 != 0
+            || fat_sectors == 0
+        {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+
+        let data_start = reserved + fats * fat_sectors;
+        let clusters = total_sectors
+            .checked_sub(data_start)
+            .ok_or(ErrorCode::NOSUPPORT)?
+            / sectors_per_cluster;
+        // Each FAT sector holds the entries of 128 clusters
+        let end_cluster = (clusters + 2).min(fat_sectors * 128);
+        if root_cluster < 2 || root_cluster >= end_cluster {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+
+        let (first_fat, fats) = if ext_flags & 0x80 != 0 {
+            ((ext_flags & 0xF) as u32, 1)
+        } else {
+            (0, fats)
+        };
+        Ok(Volume {
+            sectors_per_cluster,
+            fat_start: start + reserved,
+            fat_sectors,
+            first_fat,
+            fats,
+            data_start: start + data_start,
+            end_cluster,
+            root_cluster,
+            fsinfo: (fsinfo != 0 && fsinfo != 0xFFFF).then_some(start + fsinfo),
+        })
+    }
+
+    pub fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    pub fn entries_per_cluster(&self) -> u32 {
+        self.sectors_per_cluster * (SECTOR_SIZE / ENTRY_SIZE) as u32
+    }
+
+    /// The sector holding sector `n` of `cluster`.
+    pub fn sector(&self, cluster: u32, n: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster + n
+    }
+
+    /// The sector, and offset, of the entry for `cluster` in FAT `copy`.
+    pub fn fat_entry(&self, copy: u32, cluster: u32) -> (u32, usize) {
+        let fat = self.fat_start + (self.first_fat + copy) * self.fat_sectors;
+        let offset = cluster as usize * 4;
+        (fat + (offset / SECTOR_SIZE) as u32, offset % SECTOR_SIZE)
+    }
+
+    pub fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.end_cluster
+    }
+}
+
+/// Read the FAT entry at `offset` of a FAT sector.
+pub fn fat_entry(sector: &[u8], offset: usize) -> u32 {
+    read_u32(sector, offset) & CLUSTER_MASK
+}
+
+/// Set the FAT entry at `offset`, keeping the reserved top bits.
+pub fn set_fat_entry(sector: &mut [u8], offset: usize, value: u32) {
+    let reserved = read_u32(sector, offset) & !CLUSTER_MASK;
+    write_u32(sector, offset, reserved | value);
+}
+
+/// Mark the free cluster count, and next free cluster, in the FSInfo sector
+/// as unknown, as they are not kept up to date.
+pub fn clear_fsinfo(sector: &mut [u8]) {
+    if read_u32(sector, 0) == 0x4161_5252 && read_u32(sector, 484) == 0x6141_7272 {
+        write_u32(sector, 488, 0xFFFF_FFFF);
+        write_u32(sector, 492, 0xFFFF_FFFF);
+    }
+}
+
+/// A short directory entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShortEntry {
+    pub name: [u8; 11],
+    pub attributes: u8,
+    /// The case flags of the name
+    pub case: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl ShortEntry {
+    pub fn new(name: [u8; 11], case: u8, attributes: u8) -> ShortEntry {
+        ShortEntry {
+            name,
+            attributes,
+            case,
+            first_cluster: 0,
+            size: 0,
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// The checksum of the name, which the long name entries hold.
+    pub fn checksum(&self) -> u8 {
+        self.name
+            .iter()
+            .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+    }
+
+    /// Write the entry. The times are kept at the earliest FAT date.
+    pub fn write(&self, entry: &mut [u8]) {
+        entry[..11].copy_from_slice(&self.name);
+        entry[11] = self.attributes;
+        entry[12] = self.case;
+        entry[13] = 0;
+        write_u16(entry, 14, 0);
+        write_u16(entry, 16, DATE);
+        write_u16(entry, 18, DATE);
+        write_u16(entry, 20, (self.first_cluster >> 16) as u16);
+        write_u16(entry, 22, 0);
+        write_u16(entry, 24, DATE);
+        write_u16(entry, 26, self.first_cluster as u16);
+        write_u32(entry, 28, self.size);
+    }
+
+    /// Change the first cluster and size of the entry, keeping the rest.
+    pub fn update(entry: &mut [u8], first_cluster: u32, size: u32) {
+        write_u16(entry, 20, (first_cluster >> 16) as u16);
+        write_u16(entry, 26, first_cluster as u16);
+        write_u32(entry, 28, size);
+        entry[11] |= ATTR_ARCHIVE;
+    }
+
+    /// The name as it is displayed, such as `README.TXT`.
+    pub fn display(&self, name: &mut [u16]) -> usize {
+        let mut len = 0;
+        let mut push = |b: u8, lower: bool| {
+            name[len] = if lower { b.to_ascii_lowercase() } else { b } as u16;
+            len += 1;
+        };
+        for (i, b) in self.name[..8].iter().enumerate() {
+            if *b != b' ' {
+                let b = if i == 0 && *b == 0x05 { DELETED } else { *b };
+                push(b, self.case & LOWER_BASE != 0);
+            }
+        }
+        if self.name[8] != b' ' {
+            push(b'.', false);
+            for b in self.name[8..].iter().filter(|b| **b != b' ') {
+                push(*b, self.case & LOWER_EXTENSION != 0);
+            }
+        }
+        len
+    }
+}
+
+/// A directory entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    /// The end of the directory: this and all later entries are free
+    End,
+    Free,
+    Long {
+        order: u8,
+        checksum: u8,
+        chars: [u16; CHARS_PER_LONG_ENTRY],
+    },
+    Short(ShortEntry),
+}
+
+const LONG_CHAR_OFFSETS: [usize; CHARS_PER_LONG_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+impl Slot {
+    pub fn parse(entry: &[u8]) -> Slot {
+        match entry[0] {
+            0 => return Slot::End,
+            DELETED => return Slot::Free,
+            _ => {}
+        }
+
+        if entry[11] & 0x3F == ATTR_LONG_NAME {
+            let mut chars = [0; CHARS_PER_LONG_ENTRY];
+            for (c, offset) in chars.iter_mut().zip(LONG_CHAR_OFFSETS) {
+                *c = read_u16(entry, offset);
+            }
+            return Slot::Long {
+                order: entry[0],
+                checksum: entry[13],
+                chars,
+            };
+        }
+
+        let mut name = [0; 11];
+        name.copy_from_slice(&entry[..11]);
+        Slot::Short(ShortEntry {
+            name,
+            attributes: entry[11],
+            case: entry[12],
+            first_cluster: (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32,
+            size: read_u32(entry, 28),
+        })
+    }
+}
+
+/// The number of long name entries a name needs.
+pub fn long_entries(name: &[u16]) -> usize {
+    (name.len() + CHARS_PER_LONG_ENTRY - 1) / CHARS_PER_LONG_ENTRY
+}
+
+/// Write part `n`, counting from 1, of the long name entries of `name`.
+pub fn write_long_entry(entry: &mut [u8], name: &[u16], n: usize, checksum: u8) {
+    let last = n == long_entries(name);
+    entry[0] = n as u8 | if last { LAST_LONG_ENTRY } else { 0 };
+    entry[11] = ATTR_LONG_NAME;
+    entry[12] = 0;
+    entry[13] = checksum;
+    write_u16(entry, 26, 0);
+
+    // The name ends with a NUL, and the rest of the entry is padding
+    let start = (n - 1) * CHARS_PER_LONG_ENTRY;
+    for (i, offset) in LONG_CHAR_OFFSETS.iter().enumerate() {
+        let c = match name.get(start + i) {
+            Some(c) => *c,
+            None if start + i == name.len() => 0,
+            None => 0xFFFF,
+        };
+        write_u16(entry, *offset, c);
+    }
+}
+
+/// Store the characters of a long name entry of `order` in `name`, returning
+/// the length of the name if this is its last part.
+pub fn read_long_entry(
+    name: &mut [u16; MAX_NAME],
+    order: u8,
+    chars: &[u16; CHARS_PER_LONG_ENTRY],
+) -> Option<usize> {
+    let start = ((order & !LAST_LONG_ENTRY) as usize).checked_sub(1)? * CHARS_PER_LONG_ENTRY;
+    let mut end = None;
+    for (i, c) in chars.iter().enumerate() {
+        if *c == 0 {
+            end = Some(start + i);
+            break;
+        }
+        *name.get_mut(start + i)? = *c;
+    }
+    if order & LAST_LONG_ENTRY != 0 {
+        Some(end.unwrap_or(start + CHARS_PER_LONG_ENTRY).min(MAX_NAME))
+    } else {
+        None
+    }
+}
+
+/// Decode a UTF-8 name to UTF-16, returning its length, or `None` if it is
+/// not valid UTF-8 or is too long.
+pub fn to_utf16(name: &[u8], out: &mut [u16; MAX_NAME]) -> Option<usize> {
+    let name = core::str::from_utf8(name).ok()?;
+    let mut len = 0;
+    for c in name.chars() {
+        let mut units = [0; 2];
+        for unit in c.encode_utf16(&mut units) {
+            *out.get_mut(len)? = *unit;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+/// Encode a UTF-16 name as UTF-8, returning the length of the whole name.
+/// Only as much of the name as fits is written to `out`.
+pub fn to_utf8(name: &[u16], out: &mut [u8]) -> usize {
+    let mut len = 0;
+    for c in char::decode_utf16(name.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let mut bytes = [0; 4];
+        let bytes = c.encode_utf8(&mut bytes).as_bytes();
+        if let Some(dest) = out.get_mut(len..len + bytes.len()) {
+            dest.copy_from_slice(bytes);
+        }
+        len += bytes.len();
+    }
+    len
+}
+
+/// Compare names as FAT does, ignoring the case of ASCII letters.
+pub fn names_equal(a: &[u16], b: &[u16]) -> bool {
+    let fold = |c: &u16| match *c {
+        c @ 0x61..=0x7A => c - 0x20,
+        c => c,
+    };
+    a.len() == b.len() && a.iter().map(fold).eq(b.iter().map(fold))
+}
+
+/// Whether `name` may be used for a file or directory.
+pub fn valid_name(name: &[u16]) -> bool {
+    !name.is_empty()
+        && name != [b'.' as u16]
+        && name != [b'.' as u16, b'.' as u16]
+        && name.last() != Some(&(b'.' as u16))
+        && name.last() != Some(&(b' ' as u16))
+        && name
+            .iter()
+            .all(|c| *c >= 0x20 && !b"\"*/:<>?\\|".iter().any(|b| *c == *b as u16))
+}
+
+fn short_char(c: u16) -> Option<u8> {
+    match c {
+        0x30..=0x39 | 0x41..=0x5A => Some(c as u8),
+        0x61..=0x7A => Some(c as u8 - 0x20),
+        _ if b"!#$%&'()-@^_`{}~".iter().any(|b| c == *b as u16) => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// The short name for a name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShortName {
+    /// The name fits in a short name, with these case flags.
+    Exact([u8; 11], u8),
+    /// The name needs long name entries, and a short name made from this
+    /// basis and a numeric tail.
+    Basis([u8; 11]),
+}
+
+pub fn short_name(name: &[u16]) -> ShortName {
+    let mut short = [b' '; 11];
+    let dot = name.iter().rposition(|c| *c == b'.' as u16);
+    let (base, extension) = match dot {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, &name[..0]),
+    };
+
+    // A name fits if it has valid characters and each part is one case
+    let case = |part: &[u16], flag| {
+        let lower = part.iter().any(|c| (0x61..=0x7A).contains(c));
+        let upper = part.iter().any(|c| (0x41..=0x5A).contains(c));
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    let fits = (1..=8).contains(&base.len())
+        && extension.len() <= 3
+        && (dot.is_none() || !extension.is_empty())
+        && base
+            .iter()
+            .chain(extension)
+            .all(|c| short_char(*c).is_some());
+    if fits {
+        if let (Some(base_case), Some(extension_case)) =
+            (case(base, LOWER_BASE), case(extension, LOWER_EXTENSION))
+        {
+            for (s, c) in short.iter_mut().zip(base) {
+                *s = short_char(*c).unwrap_or(b'_');
+            }
+            for (s, c) in short[8..].iter_mut().zip(extension) {
+                *s = short_char(*c).unwrap_or(b'_');
+            }
+            return ShortName::Exact(short, base_case | extension_case);
+        }
+    }
+
+    // Otherwise spaces and dots are dropped, and other characters replaced
+    let convert = |part: &[u16], out: &mut [u8]| {
+        let chars = part
+            .iter()
+            .filter(|c| **c != b' ' as u16 && **c != b'.' as u16)
+            .map(|c| short_char(*c).unwrap_or(b'_'));
+        for (s, c) in out.iter_mut().zip(chars) {
+            *s = c;
+        }
+    };
+    convert(base, &mut short[..8]);
+    convert(extension, &mut short[8..]);
+    if short[0] == b' ' {
+        short[0] = b'_';
+    }
+    ShortName::Basis(short)
+}
+
+/// The short name made from `basis` with the numeric tail `~n`.
+pub fn with_tail(basis: &[u8; 11], n: u32) -> [u8; 11] {
+    let mut digits = [0; 10];
+    let mut count = 0;
+    let mut rest = n;
+    loop {
+        digits[count] = b'0' + (rest % 10) as u8;
+        count += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    let mut short = *basis;
+    let base = basis[..8].iter().position(|b| *b == b' ').unwrap_or(8);
+    let start = base.min(8 - 1 - count);
+    short[start] = b'~';
+    for i in 0..count {
+        short[start + 1 + i] = digits[count - 1 - i];
+    }
+    short[start + 1 + count..8]
+        .iter_mut()
+        .for_each(|b| *b = b' ');
+    short
+}
+
+/// The tail of `short`, if it is `basis` with a numeric tail.
+pub fn tail_number(short: &[u8; 11], basis: &[u8; 11]) -> Option<u32> {
+    let tilde = short[..8].iter().position(|b| *b == b'~')?;
+    let mut n: u32 = 0;
+    for b in short[tilde + 1..8].iter().take_while(|b| **b != b' ') {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((b - b'0') as u32)?;
+    }
+    (n > 0 && with_tail(basis, n) == *short).then_some(n)
+}
@@ -0,0 +1,11 @@
+//! A FAT32 filesystem on an SD card, and a syscall driver through which
+//! applications read and write its files, such as to log data in CSV files
+//! a PC can then read.
+
+pub mod block;
+pub mod driver;
+pub mod filesystem;
+pub mod layout;
+
+#[cfg(test)]
+mod tests;
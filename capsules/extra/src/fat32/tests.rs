@@ -0,0 +1,1058 @@
+//! Tests of the filesystem over FAT32 disk images in memory.
+//!
+//! The images are made, read and seeded with files as a PC would write them
+//! by code here which is independent of the filesystem, so each checks the
+//! other.
+
+use core::cell::{Cell, RefCell};
+use std::boxed::Box;
+use std::format;
+use std::string::String;
+use std::vec;
+use std::vec::Vec;
+
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+    DynamicDeferredCallClientState,
+};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::block::{BlockClient, BlockDevice};
+use super::filesystem::{FatFs, FatFsClient, File};
+use super::layout::{self, ShortName};
+
+const SECTOR: usize = 512;
+const RESERVED: u32 = 32;
+
+fn get_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+fn get_u32(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Make an empty FAT32 volume of `sectors` sectors, in a partition starting
+/// at the given sector or on the whole disk.
+fn format(sectors: u32, sectors_per_cluster: u32, partition: Option<u32>) -> Vec<u8> {
+    let mut image = vec![0; sectors as usize * SECTOR];
+    let start = partition.unwrap_or(0);
+    let volume_sectors = sectors - start;
+
+    let mut fat_sectors = 1;
+    let clusters = loop {
+        let clusters = (volume_sectors - RESERVED - 2 * fat_sectors) / sectors_per_cluster;
+        let needed = ((clusters + 2) * 4 + SECTOR as u32 - 1) / SECTOR as u32;
+        if needed <= fat_sectors {
+            break clusters;
+        }
+        fat_sectors = needed;
+    };
+
+    if let Some(start) = partition {
+        image[446 + 4] = 0x0C;
+        put_u32(&mut image, 446 + 8, start);
+        put_u32(&mut image, 446 + 12, volume_sectors);
+        image[510] = 0x55;
+        image[511] = 0xAA;
+    }
+
+    let boot = start as usize * SECTOR;
+    let bpb = &mut image[boot..boot + SECTOR];
+    bpb[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    bpb[3..11].copy_from_slice(b"MSWIN4.1");
+    put_u16(bpb, 11, SECTOR as u16);
+    bpb[13] = sectors_per_cluster as u8;
+    put_u16(bpb, 14, RESERVED as u16);
+    bpb[16] = 2;
+    bpb[21] = 0xF8;
+    put_u32(bpb, 28, start);
+    put_u32(bpb, 32, volume_sectors);
+    put_u32(bpb, 36, fat_sectors);
+    put_u32(bpb, 44, 2);
+    put_u16(bpb, 48, 1);
+    put_u16(bpb, 50, 6);
+    bpb[66] = 0x29;
+    bpb[71..82].copy_from_slice(b"NO NAME    ");
+    bpb[82..90].copy_from_slice(b"FAT32   ");
+    bpb[510] = 0x55;
+    bpb[511] = 0xAA;
+
+    let fsinfo = boot + SECTOR;
+    put_u32(&mut image, fsinfo, 0x4161_5252);
+    put_u32(&mut image, fsinfo + 484, 0x6141_7272);
+    put_u32(&mut image, fsinfo + 488, clusters - 1);
+    put_u32(&mut image, fsinfo + 492, 3);
+    put_u32(&mut image, fsinfo + 508, 0xAA55_0000);
+
+    for copy in 0..2 {
+        let fat = boot + (RESERVED + copy * fat_sectors) as usize * SECTOR;
+        put_u32(&mut image, fat, 0x0FFF_FFF8);
+        put_u32(&mut image, fat + 4, 0x0FFF_FFFF);
+        put_u32(&mut image, fat + 8, 0x0FFF_FFFF);
+    }
+    image
+}
+
+/// A directory entry, as a PC reads it.
+#[derive(Debug)]
+struct PcEntry {
+    name: String,
+    short: [u8; 11],
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+}
+
+fn checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*b)
+    })
+}
+
+const LONG_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Reads, and adds files to, a FAT32 volume the way a PC does.
+struct Pc {
+    /// The byte offset of the volume
+    start: usize,
+    sectors_per_cluster: usize,
+    fat_sectors: usize,
+    clusters: u32,
+}
+
+impl Pc {
+    fn new(image: &[u8]) -> Pc {
+        let start = if image[446 + 4] == 0x0C {
+            get_u32(image, 446 + 8) as usize * SECTOR
+        } else {
+            0
+        };
+        let sectors_per_cluster = image[start + 13] as usize;
+        let fat_sectors = get_u32(image, start + 36) as usize;
+        let sectors = get_u32(image, start + 32) as usize;
+        let data_sectors = sectors - RESERVED as usize - 2 * fat_sectors;
+        Pc {
+            start,
+            sectors_per_cluster,
+            fat_sectors,
+            clusters: (data_sectors / sectors_per_cluster) as u32,
+        }
+    }
+
+    fn fat_offset(&self, copy: usize, cluster: u32) -> usize {
+        self.start + (RESERVED as usize + copy * self.fat_sectors) * SECTOR + cluster as usize * 4
+    }
+
+    fn fat(&self, image: &[u8], cluster: u32) -> u32 {
+        let value = get_u32(image, self.fat_offset(0, cluster)) & 0x0FFF_FFFF;
+        let mirror = get_u32(image, self.fat_offset(1, cluster)) & 0x0FFF_FFFF;
+        assert_eq!(value, mirror, "the FATs differ for cluster {}", cluster);
+        value
+    }
+
+    fn set_fat(&self, image: &mut [u8], cluster: u32, value: u32) {
+        for copy in 0..2 {
+            put_u32(image, self.fat_offset(copy, cluster), value);
+        }
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster * SECTOR
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        let data = self.start + (RESERVED as usize + 2 * self.fat_sectors) * SECTOR;
+        data + (cluster as usize - 2) * self.cluster_bytes()
+    }
+
+    fn chain(&self, image: &[u8], first: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while (2..0x0FFF_FFF8).contains(&cluster) {
+            assert!(chain.len() <= self.clusters as usize, "the chain loops");
+            chain.push(cluster);
+            cluster = self.fat(image, cluster);
+            assert_ne!(cluster, 0, "the chain runs into a free cluster");
+        }
+        chain
+    }
+
+    fn free_clusters(&self, image: &[u8]) -> usize {
+        (2..self.clusters + 2)
+            .filter(|cluster| self.fat(image, *cluster) == 0)
+            .count()
+    }
+
+    fn read(&self, image: &[u8], entry: &PcEntry) -> Vec<u8> {
+        let chain = self.chain(image, entry.cluster);
+        assert_eq!(
+            chain.len(),
+            (entry.size as usize + self.cluster_bytes() - 1) / self.cluster_bytes()
+        );
+        let mut data = Vec::new();
+        for cluster in chain {
+            let offset = self.cluster_offset(cluster);
+            data.extend_from_slice(&image[offset..offset + self.cluster_bytes()]);
+        }
+        data.truncate(entry.size as usize);
+        data
+    }
+
+    /// The byte offsets of the entries of a directory.
+    fn slots(&self, image: &[u8], cluster: u32) -> Vec<usize> {
+        let cluster = if cluster == 0 { 2 } else { cluster };
+        self.chain(image, cluster)
+            .into_iter()
+            .flat_map(|cluster| {
+                let offset = self.cluster_offset(cluster);
+                (0..self.cluster_bytes() / 32).map(move |i| offset + i * 32)
+            })
+            .collect()
+    }
+
+    fn list(&self, image: &[u8], cluster: u32) -> Vec<PcEntry> {
+        let mut entries = Vec::new();
+        let mut long: Vec<&[u8]> = Vec::new();
+        for offset in self.slots(image, cluster) {
+            let slot = &image[offset..offset + 32];
+            if slot[0] == 0 {
+                break;
+            }
+            if slot[0] == 0xE5 {
+                long.clear();
+                continue;
+            }
+            if slot[11] == 0x0F {
+                long.push(slot);
+                continue;
+            }
+
+            let mut short = [0; 11];
+            short.copy_from_slice(&slot[..11]);
+            let complete = !long.is_empty()
+                && long[0][0] & 0x40 != 0
+                && (long[0][0] & 0x3F) as usize == long.len()
+                && long.iter().all(|l| l[13] == checksum(&short));
+            let name = if complete {
+                let units: Vec<u16> = long
+                    .iter()
+                    .rev()
+                    .flat_map(|l| LONG_OFFSETS.iter().map(move |o| get_u16(l, *o)))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                String::from_utf16(&units).unwrap()
+            } else {
+                let part = |bytes: &[u8], lower: bool| {
+                    let text = String::from_utf8(bytes.to_vec()).unwrap();
+                    let text = text.trim_end();
+                    if lower {
+                        text.to_lowercase()
+                    } else {
+                        String::from(text)
+                    }
+                };
+                let base = part(&short[..8], slot[12] & 0x08 != 0);
+                let extension = part(&short[8..], slot[12] & 0x10 != 0);
+                if extension.is_empty() {
+                    base
+                } else {
+                    format!("{}.{}", base, extension)
+                }
+            };
+            long.clear();
+
+            if slot[11] & 0x08 == 0 {
+                entries.push(PcEntry {
+                    name,
+                    short,
+                    attributes: slot[11],
+                    cluster: (get_u16(slot, 20) as u32) << 16 | get_u16(slot, 26) as u32,
+                    size: get_u32(slot, 28),
+                });
+            }
+        }
+        entries
+    }
+
+    fn find(&self, image: &[u8], path: &str) -> Option<PcEntry> {
+        let mut cluster = 2;
+        let mut found = None;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let entry = self
+                .list(image, cluster)
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(component))?;
+            cluster = entry.cluster;
+            found = Some(entry);
+        }
+        found
+    }
+
+    fn allocate(&self, image: &mut [u8], count: usize) -> u32 {
+        let free: Vec<u32> = (2..self.clusters + 2)
+            .filter(|cluster| self.fat(image, *cluster) == 0)
+            .take(count)
+            .collect();
+        assert_eq!(free.len(), count);
+        for (i, cluster) in free.iter().enumerate() {
+            let next = free.get(i + 1).copied().unwrap_or(0x0FFF_FFFF);
+            self.set_fat(image, *cluster, next);
+        }
+        free[0]
+    }
+
+    /// Add a file, or directory, to the directory at `directory`, with a
+    /// long name if one is given.
+    fn add(
+        &self,
+        image: &mut [u8],
+        directory: &str,
+        long: Option<&str>,
+        short: &[u8; 11],
+        case: u8,
+        data: &[u8],
+        is_directory: bool,
+    ) {
+        let parent = self.find(image, directory).map_or(0, |entry| entry.cluster);
+
+        let clusters = if is_directory {
+            1
+        } else {
+            (data.len() + self.cluster_bytes() - 1) / self.cluster_bytes()
+        };
+        let cluster = if clusters > 0 {
+            self.allocate(image, clusters)
+        } else {
+            0
+        };
+        if is_directory {
+            let offset = self.cluster_offset(cluster);
+            image[offset..offset + self.cluster_bytes()].fill(0);
+            for (i, name) in [b".          ", b"..         "].iter().enumerate() {
+                let slot = &mut image[offset + i * 32..offset + i * 32 + 32];
+                slot[..11].copy_from_slice(*name);
+                slot[11] = 0x10;
+                let target = if i == 0 { cluster } else { parent };
+                put_u16(slot, 20, (target >> 16) as u16);
+                put_u16(slot, 26, target as u16);
+            }
+        } else {
+            for (i, cluster) in self.chain(image, cluster).into_iter().enumerate() {
+                let chunk = &data[i * self.cluster_bytes()..];
+                let chunk = &chunk[..chunk.len().min(self.cluster_bytes())];
+                let offset = self.cluster_offset(cluster);
+                image[offset..offset + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+
+        let mut slots: Vec<[u8; 32]> = Vec::new();
+        if let Some(long) = long {
+            let units: Vec<u16> = long.encode_utf16().collect();
+            let parts = (units.len() + 12) / 13;
+            for part in (1..=parts).rev() {
+                let mut slot = [0; 32];
+                slot[0] = part as u8 | if part == parts { 0x40 } else { 0 };
+                slot[11] = 0x0F;
+                slot[13] = checksum(short);
+                for (i, offset) in LONG_OFFSETS.iter().enumerate() {
+                    let n = (part - 1) * 13 + i;
+                    let c = match n.cmp(&units.len()) {
+                        core::cmp::Ordering::Less => units[n],
+                        core::cmp::Ordering::Equal => 0,
+                        core::cmp::Ordering::Greater => 0xFFFF,
+                    };
+                    put_u16(&mut slot, *offset, c);
+                }
+                slots.push(slot);
+            }
+        }
+        let mut slot = [0; 32];
+        slot[..11].copy_from_slice(short);
+        slot[11] = if is_directory { 0x10 } else { 0x20 };
+        slot[12] = case;
+        put_u16(&mut slot, 20, (cluster >> 16) as u16);
+        put_u16(&mut slot, 26, cluster as u16);
+        put_u32(
+            &mut slot,
+            28,
+            if is_directory { 0 } else { data.len() as u32 },
+        );
+        slots.push(slot);
+
+        let free = self
+            .slots(image, parent)
+            .into_iter()
+            .position(|offset| image[offset] == 0)
+            .unwrap();
+        let offsets = self.slots(image, parent);
+        for (slot, offset) in slots.iter().zip(&offsets[free..]) {
+            image[*offset..*offset + 32].copy_from_slice(slot);
+        }
+    }
+}
+
+/// A disk of sectors in memory, whose transfers the test completes. The
+/// next `fail` transfers fail, and keep their buffers if `lose` is set.
+struct RamDisk {
+    client: OptionalCell<&'static dyn BlockClient>,
+    image: RefCell<Vec<u8>>,
+    pending: Cell<Option<(bool, u32)>>,
+    buffer: TakeCell<'static, [u8]>,
+    fail: Cell<usize>,
+    lose: Cell<bool>,
+}
+
+impl RamDisk {
+    /// Complete the transfer in progress, returning false if there is none.
+    fn complete(&self) -> bool {
+        let (write, block) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return false,
+        };
+        let buffer = self.buffer.take().unwrap();
+        let offset = block as usize * SECTOR;
+        let result = if self.fail.get() > 0 {
+            self.fail.set(self.fail.get() - 1);
+            Err(ErrorCode::FAIL)
+        } else {
+            let mut image = self.image.borrow_mut();
+            if write {
+                image[offset..offset + SECTOR].copy_from_slice(&buffer[..SECTOR]);
+            } else {
+                buffer[..SECTOR].copy_from_slice(&image[offset..offset + SECTOR]);
+            }
+            Ok(())
+        };
+        let buffer = match result {
+            Err(_) if self.lose.get() => None,
+            _ => Some(buffer),
+        };
+        self.client.map(|client| {
+            if write {
+                client.write_complete(buffer, result)
+            } else {
+                client.read_complete(buffer, result)
+            }
+        });
+        true
+    }
+
+    fn start(
+        &self,
+        write: bool,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        assert!(self.pending.get().is_none());
+        if (block as usize + 1) * SECTOR > self.image.borrow().len() {
+            return Err((ErrorCode::INVAL, Some(buffer)));
+        }
+        self.pending.set(Some((write, block)));
+        self.buffer.replace(buffer);
+        Ok(())
+    }
+}
+
+impl BlockDevice<'static> for RamDisk {
+    fn set_client(&self, client: &'static dyn BlockClient) {
+        self.client.set(client);
+    }
+
+    fn read_block(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        self.start(false, block, buffer)
+    }
+
+    fn write_block(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, Option<&'static mut [u8]>)> {
+        self.start(true, block, buffer)
+    }
+}
+
+enum Done {
+    Mount(Result<(), ErrorCode>),
+    File(Result<File, ErrorCode>),
+    Entry(Result<(File, usize), ErrorCode>),
+    Data(Result<(File, usize), ErrorCode>, &'static mut [u8]),
+}
+
+struct TestClient {
+    done: RefCell<Option<Done>>,
+}
+
+impl TestClient {
+    fn set(&self, done: Done) {
+        assert!(self.done.borrow().is_none(), "two operations completed");
+        *self.done.borrow_mut() = Some(done);
+    }
+}
+
+impl FatFsClient for TestClient {
+    fn mount_complete(&self, result: Result<(), ErrorCode>) {
+        self.set(Done::Mount(result));
+    }
+
+    fn lookup_complete(&self, result: Result<File, ErrorCode>) {
+        self.set(Done::File(result));
+    }
+
+    fn create_complete(&self, result: Result<File, ErrorCode>) {
+        self.set(Done::File(result));
+    }
+
+    fn unlink_complete(&self, result: Result<File, ErrorCode>) {
+        self.set(Done::File(result));
+    }
+
+    fn read_dir_complete(&self, result: Result<(File, usize), ErrorCode>) {
+        self.set(Done::Entry(result));
+    }
+
+    fn read_complete(&self, result: Result<(File, usize), ErrorCode>, buffer: &'static mut [u8]) {
+        self.set(Done::Data(result, buffer));
+    }
+
+    fn write_complete(&self, result: Result<(File, usize), ErrorCode>, buffer: &'static mut [u8]) {
+        self.set(Done::Data(result, buffer));
+    }
+}
+
+struct TestFs {
+    disk: &'static RamDisk,
+    fs: &'static FatFs<'static, RamDisk>,
+    client: &'static TestClient,
+    handle: DeferredCallHandle,
+    buffer: Cell<Option<&'static mut [u8]>>,
+}
+
+impl TestFs {
+    fn new(image: Vec<u8>) -> TestFs {
+        let disk = Box::leak(Box::new(RamDisk {
+            client: OptionalCell::empty(),
+            image: RefCell::new(image),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            fail: Cell::new(0),
+            lose: Cell::new(false),
+        }));
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let sector = Box::leak(Box::new([0; SECTOR]));
+        let fs = Box::leak(Box::new(FatFs::new(disk, sector, ddc)));
+        let handle = ddc.register(fs).unwrap();
+        fs.initialize_callback_handle(handle);
+        disk.set_client(fs);
+        let client = Box::leak(Box::new(TestClient {
+            done: RefCell::new(None),
+        }));
+        fs.set_client(client);
+
+        TestFs {
+            disk,
+            fs,
+            client,
+            handle,
+            buffer: Cell::new(Some(Box::leak(Box::new([0; SECTOR])))),
+        }
+    }
+
+    fn mounted(image: Vec<u8>) -> TestFs {
+        let test = TestFs::new(image);
+        assert_eq!(test.mount(), Ok(()));
+        test
+    }
+
+    fn image(&self) -> std::cell::Ref<Vec<u8>> {
+        self.disk.image.borrow()
+    }
+
+    fn pc(&self) -> Pc {
+        Pc::new(&self.image())
+    }
+
+    // Completes transfers, and deferred calls, until the operation is done.
+    fn wait(&self) -> Done {
+        loop {
+            if let Some(done) = self.client.done.borrow_mut().take() {
+                assert!(self.disk.pending.get().is_none());
+                return done;
+            }
+            if !self.disk.complete() {
+                self.fs.call(self.handle);
+                assert!(
+                    self.client.done.borrow().is_some(),
+                    "the filesystem is stuck"
+                );
+            }
+        }
+    }
+
+    fn mount(&self) -> Result<(), ErrorCode> {
+        self.fs.mount()?;
+        match self.wait() {
+            Done::Mount(result) => result,
+            _ => panic!("wrong callback"),
+        }
+    }
+
+    fn file(&self, started: Result<(), ErrorCode>) -> Result<File, ErrorCode> {
+        started?;
+        match self.wait() {
+            Done::File(result) => result,
+            _ => panic!("wrong callback"),
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Result<File, ErrorCode> {
+        self.file(self.fs.lookup(path.as_bytes()))
+    }
+
+    fn create(&self, path: &str, directory: bool) -> Result<File, ErrorCode> {
+        self.file(self.fs.create(path.as_bytes(), directory))
+    }
+
+    fn unlink(&self, path: &str) -> Result<File, ErrorCode> {
+        self.file(self.fs.unlink(path.as_bytes()))
+    }
+
+    /// The names of the entries of a directory, and whether they are
+    /// directories.
+    fn list(&self, path: &str) -> Result<Vec<(String, bool)>, ErrorCode> {
+        let mut entries = Vec::new();
+        loop {
+            self.fs.read_dir(path.as_bytes(), entries.len())?;
+            let result = match self.wait() {
+                Done::Entry(result) => result,
+                _ => panic!("wrong callback"),
+            };
+            match result {
+                Ok((file, len)) => {
+                    let mut name = vec![0; len];
+                    assert_eq!(self.fs.entry_name(&mut name), len);
+                    entries.push((String::from_utf8(name).unwrap(), file.is_directory()));
+                }
+                Err(ErrorCode::NOSUPPORT) => return Ok(entries),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn transfer(
+        &self,
+        started: Result<(), (ErrorCode, &'static mut [u8])>,
+    ) -> Result<(File, usize), ErrorCode> {
+        let (result, buffer) = match started {
+            Ok(()) => match self.wait() {
+                Done::Data(result, buffer) => (result, buffer),
+                _ => panic!("wrong callback"),
+            },
+            Err((error, buffer)) => (Err(error), buffer),
+        };
+        self.buffer.set(Some(buffer));
+        result
+    }
+
+    fn read_all(&self, mut file: File) -> Vec<u8> {
+        let mut data = Vec::new();
+        loop {
+            let buffer = self.buffer.take().unwrap();
+            let started = self.fs.read(file, data.len(), buffer, SECTOR);
+            match self.transfer(started) {
+                Ok((read, len)) => {
+                    file = read;
+                    let buffer = self.buffer.take().unwrap();
+                    data.extend_from_slice(&buffer[..len]);
+                    self.buffer.set(Some(buffer));
+                }
+                Err(ErrorCode::SIZE) => return data,
+                Err(error) => panic!("read failed: {:?}", error),
+            }
+        }
+    }
+
+    fn write(&self, mut file: File, offset: usize, data: &[u8]) -> Result<File, ErrorCode> {
+        let mut done = 0;
+        while done < data.len() {
+            let buffer = self.buffer.take().unwrap();
+            let chunk = (data.len() - done).min(SECTOR);
+            buffer[..chunk].copy_from_slice(&data[done..done + chunk]);
+            let started = self.fs.write(file, offset + done, buffer, chunk);
+            let (written, len) = self.transfer(started)?;
+            file = written;
+            done += len;
+        }
+        Ok(file)
+    }
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+fn utf16(name: &str) -> Vec<u16> {
+    name.encode_utf16().collect()
+}
+
+#[test]
+fn short_names() {
+    assert_eq!(
+        layout::short_name(&utf16("README.TXT")),
+        ShortName::Exact(*b"README  TXT", 0)
+    );
+    assert_eq!(
+        layout::short_name(&utf16("data.csv")),
+        ShortName::Exact(*b"DATA    CSV", 0x18)
+    );
+    assert_eq!(
+        layout::short_name(&utf16("Readme.txt")),
+        ShortName::Basis(*b"README  TXT")
+    );
+    assert_eq!(
+        layout::short_name(&utf16("sensor log.2024.csv")),
+        ShortName::Basis(*b"SENSORLOCSV")
+    );
+    assert_eq!(
+        layout::short_name(&utf16(".profile")),
+        ShortName::Basis(*b"PROFILE    ")
+    );
+    assert_eq!(
+        layout::short_name(&utf16("a+b")),
+        ShortName::Basis(*b"A_B        ")
+    );
+
+    let basis = *b"SENSORLOCSV";
+    assert_eq!(&layout::with_tail(&basis, 1), b"SENSOR~1CSV");
+    assert_eq!(&layout::with_tail(&basis, 42), b"SENSO~42CSV");
+    assert_eq!(&layout::with_tail(b"AB         ", 3), b"AB~3       ");
+    assert_eq!(layout::tail_number(b"SENSO~42CSV", &basis), Some(42));
+    assert_eq!(layout::tail_number(b"SENSOR~1TXT", &basis), None);
+    assert_eq!(layout::tail_number(b"SENSORLOCSV", &basis), None);
+
+    // Long names survive being split over entries
+    let name = utf16("a name of exactly twenty-six");
+    let short = layout::ShortEntry::new(*b"ANAMEO~1   ", 0, 0);
+    let mut read = [0; layout::MAX_NAME];
+    let mut len = None;
+    for part in (1..=layout::long_entries(&name)).rev() {
+        let mut slot = [0; 32];
+        layout::write_long_entry(&mut slot, &name, part, short.checksum());
+        match layout::Slot::parse(&slot) {
+            layout::Slot::Long {
+                order,
+                checksum,
+                chars,
+            } => {
+                assert_eq!(checksum, checksum_of(&short));
+                if let Some(found) = layout::read_long_entry(&mut read, order, &chars) {
+                    len = Some(found);
+                }
+            }
+            _ => panic!("not a long entry"),
+        }
+    }
+    assert_eq!(&read[..len.unwrap()], &name[..]);
+
+    assert!(layout::names_equal(&utf16("Data.CSV"), &utf16("dATA.csv")));
+    assert!(!layout::valid_name(&utf16("a:b")));
+    assert!(!layout::valid_name(&utf16("..")));
+    assert!(!layout::valid_name(&utf16("trailing.")));
+    assert!(layout::valid_name(&utf16("Grüße, [2024].txt")));
+}
+
+fn checksum_of(entry: &layout::ShortEntry) -> u8 {
+    checksum(&entry.name)
+}
+
+#[test]
+fn mounts_disks_with_and_without_partitions() {
+    for partition in [Some(63), None] {
+        let test = TestFs::new(format(8192, 2, partition));
+        assert_eq!(test.lookup("/"), Err(ErrorCode::OFF));
+        assert_eq!(test.mount(), Ok(()));
+        assert!(test.lookup("/").unwrap().is_directory());
+        assert_eq!(test.list("/"), Ok(vec![]));
+
+        // The free cluster count is not kept up to date
+        let fsinfo = partition.unwrap_or(0) as usize * SECTOR + SECTOR;
+        assert_eq!(get_u32(&test.image(), fsinfo + 488), 0xFFFF_FFFF);
+    }
+
+    let test = TestFs::new(vec![0; 64 * SECTOR]);
+    assert_eq!(test.mount(), Err(ErrorCode::NOSUPPORT));
+    assert_eq!(test.lookup("/"), Err(ErrorCode::OFF));
+}
+
+#[test]
+fn reads_files_written_by_a_pc() {
+    let mut image = format(8192, 2, Some(63));
+    let pc = Pc::new(&image);
+    let measurements = pattern(5000, 7);
+    pc.add(
+        &mut image,
+        "/",
+        Some("Data Logs"),
+        b"DATALO~1   ",
+        0,
+        &[],
+        true,
+    );
+    pc.add(
+        &mut image,
+        "/Data Logs",
+        Some("2024 measurements.csv"),
+        b"2024ME~1CSV",
+        0,
+        &measurements,
+        false,
+    );
+    pc.add(&mut image, "/", None, b"README  TXT", 0, b"Hello", false);
+    pc.add(&mut image, "/", None, b"NOTES   TXT", 0x18, b"", false);
+    let test = TestFs::mounted(image);
+
+    let file = test.lookup("/data logs/2024 MEASUREMENTS.CSV").unwrap();
+    assert_eq!(file.size(), 5000);
+    assert_eq!(test.read_all(file), measurements);
+    // Reading from the middle starts from the right cluster
+    let buffer = test.buffer.take().unwrap();
+    let started = test.fs.read(file, 4097, buffer, 100);
+    assert_eq!(test.transfer(started).map(|(_, len)| len), Ok(100));
+
+    assert_eq!(test.read_all(test.lookup("readme.txt").unwrap()), b"Hello");
+    assert_eq!(test.lookup("/NOTES.TXT").unwrap().size(), 0);
+    assert_eq!(
+        test.list("/"),
+        Ok(vec![
+            (String::from("Data Logs"), true),
+            (String::from("README.TXT"), false),
+            (String::from("notes.txt"), false),
+        ])
+    );
+    assert_eq!(
+        test.list("/Data Logs"),
+        Ok(vec![(String::from("2024 measurements.csv"), false)])
+    );
+
+    assert_eq!(test.lookup("/missing.txt"), Err(ErrorCode::NOSUPPORT));
+    assert_eq!(test.lookup("/README.TXT/inside"), Err(ErrorCode::INVAL));
+    assert_eq!(test.lookup("/bad:name"), Err(ErrorCode::INVAL));
+    assert_eq!(test.list("/README.TXT"), Err(ErrorCode::INVAL));
+}
+
+#[test]
+fn files_written_can_be_read_by_a_pc() {
+    let test = TestFs::mounted(format(8192, 2, None));
+    let logs = test.create("/logs", true).unwrap();
+    assert!(logs.is_directory());
+    let file = test.create("/logs/sensor log.csv", false).unwrap();
+    let mut data = Vec::new();
+    let mut file = file;
+    for line in 0..100 {
+        let row = format!("{},{},{}\n", line, line * 3, line % 7);
+        file = test.write(file, data.len(), row.as_bytes()).unwrap();
+        data.extend_from_slice(row.as_bytes());
+    }
+    assert_eq!(file.size(), data.len());
+
+    // Overwriting keeps the size
+    file = test.write(file, 10, b"XYZ").unwrap();
+    data[10..13].copy_from_slice(b"XYZ");
+    assert_eq!(file.size(), data.len());
+    assert_eq!(
+        test.write(file, data.len() + 1, b"gap"),
+        Err(ErrorCode::INVAL)
+    );
+
+    test.create("/logs/Sensor Log 2.csv", false).unwrap();
+    test.create("/logs/data.csv", false).unwrap();
+    assert_eq!(
+        test.create("/logs/SENSOR LOG.CSV", false),
+        Err(ErrorCode::ALREADY)
+    );
+    assert_eq!(
+        test.create("/missing/file.txt", false),
+        Err(ErrorCode::NOSUPPORT)
+    );
+
+    let pc = test.pc();
+    let image = test.image();
+    let entry = pc.find(&image, "/logs/sensor log.csv").unwrap();
+    assert_eq!(&entry.short, b"SENSOR~1CSV");
+    assert_eq!(entry.attributes & 0x10, 0);
+    assert_eq!(pc.read(&image, &entry), data);
+    assert_eq!(
+        &pc.find(&image, "/logs/Sensor Log 2.csv").unwrap().short,
+        b"SENSOR~2CSV"
+    );
+    let names: Vec<String> = pc
+        .list(&image, logs_cluster(&pc, &image))
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(
+        names,
+        [".", "..", "sensor log.csv", "Sensor Log 2.csv", "data.csv"]
+    );
+    let dots = pc.list(&image, logs_cluster(&pc, &image));
+    assert_eq!(dots[0].cluster, logs_cluster(&pc, &image));
+    assert_eq!(dots[1].cluster, 0);
+}
+
+fn logs_cluster(pc: &Pc, image: &[u8]) -> u32 {
+    pc.find(image, "/logs").unwrap().cluster
+}
+
+#[test]
+fn directories_grow_and_shrink() {
+    let test = TestFs::mounted(format(4096, 1, None));
+    let free = test.pc().free_clusters(&test.image());
+    test.create("/many", true).unwrap();
+
+    // Each name takes three entries, so the directory grows over clusters
+    for i in 0..40 {
+        let file = test
+            .create(&format!("/many/file number {}.txt", i), false)
+            .unwrap();
+        test.write(file, 0, &pattern(100 + i * 10, i as u8))
+            .unwrap();
+    }
+    let names: Vec<String> = (0..40).map(|i| format!("file number {}.txt", i)).collect();
+    {
+        let pc = test.pc();
+        let image = test.image();
+        let many = pc.find(&image, "/many").unwrap();
+        assert!(pc.chain(&image, many.cluster).len() >= 8);
+        let listed: Vec<String> = pc.list(&image, many.cluster)[2..]
+            .iter()
+            .map(|entry| entry.name.clone())
+            .collect();
+        assert_eq!(listed, names);
+        let entry = pc.find(&image, "/many/file number 39.txt").unwrap();
+        assert_eq!(pc.read(&image, &entry), pattern(490, 39));
+    }
+    let listed: Vec<String> = test
+        .list("/many")
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(listed, names);
+
+    assert_eq!(test.unlink("/many"), Err(ErrorCode::INVAL));
+    for i in (0..40).step_by(2) {
+        test.unlink(&format!("/many/FILE NUMBER {}.TXT", i))
+            .unwrap();
+    }
+    assert_eq!(
+        test.lookup("/many/file number 0.txt"),
+        Err(ErrorCode::NOSUPPORT)
+    );
+    // The freed entries are used again
+    test.create("/many/file number 0.txt", false).unwrap();
+    {
+        let pc = test.pc();
+        let image = test.image();
+        let many = pc.find(&image, "/many").unwrap();
+        assert_eq!(pc.list(&image, many.cluster)[2].name, "file number 0.txt");
+    }
+
+    test.unlink("/many/file number 0.txt").unwrap();
+    for i in (1..40).step_by(2) {
+        test.unlink(&format!("/many/file number {}.txt", i))
+            .unwrap();
+    }
+    assert_eq!(test.list("/many"), Ok(vec![]));
+    // The directory keeps its clusters until it is removed
+    test.unlink("/many").unwrap();
+    assert_eq!(test.pc().free_clusters(&test.image()), free);
+    assert!(test.pc().find(&test.image(), "/many").is_none());
+    assert_eq!(test.list("/"), Ok(vec![]));
+}
+
+#[test]
+fn fills_up() {
+    let test = TestFs::mounted(format(400, 1, None));
+    let mut file = test.create("/big.bin", false).unwrap();
+    let free = test.pc().free_clusters(&test.image());
+
+    let chunk = pattern(SECTOR, 3);
+    let mut written = 0;
+    loop {
+        match test.write(file, written, &chunk) {
+            Ok(grown) => {
+                file = grown;
+                written += SECTOR;
+            }
+            Err(error) => {
+                assert_eq!(error, ErrorCode::NOMEM);
+                break;
+            }
+        }
+    }
+    assert_eq!(written, free * SECTOR);
+    assert_eq!(test.pc().free_clusters(&test.image()), 0);
+    assert_eq!(test.create("/more", true), Err(ErrorCode::NOMEM));
+    assert_eq!(
+        test.read_all(test.lookup("/big.bin").unwrap()).len(),
+        written
+    );
+
+    test.unlink("/big.bin").unwrap();
+    assert_eq!(test.pc().free_clusters(&test.image()), free);
+    let file = test.create("/small.bin", false).unwrap();
+    let file = test.write(file, 0, &pattern(1000, 9)).unwrap();
+    assert_eq!(test.read_all(file), pattern(1000, 9));
+}
+
+#[test]
+fn disk_errors_are_reported() {
+    let test = TestFs::mounted(format(8192, 2, None));
+    let file = test.create("/a.txt", false).unwrap();
+
+    // A failed mount leaves the filesystem unmounted until mounted again
+    test.disk.fail.set(1);
+    assert_eq!(test.mount(), Err(ErrorCode::FAIL));
+    assert_eq!(test.lookup("/a.txt"), Err(ErrorCode::OFF));
+    assert_eq!(test.mount(), Ok(()));
+    assert_eq!(test.lookup("/a.txt").map(|found| found.size()), Ok(0));
+
+    // A failed write leaves the file as it was
+    test.disk.fail.set(1);
+    assert_eq!(test.write(file, 0, b"lost"), Err(ErrorCode::FAIL));
+    assert_eq!(test.lookup("/a.txt").map(|found| found.size()), Ok(0));
+    let file = test.write(file, 0, b"kept").unwrap();
+    assert_eq!(test.read_all(file), b"kept");
+}
+
+#[test]
+fn lost_sector_buffers_are_reported() {
+    let test = TestFs::mounted(format(8192, 2, None));
+    let file = test.create("/a.txt", false).unwrap();
+
+    // A disk which fails a transfer without giving its buffer back fails the
+    // operation, and every later one, instead of leaving them waiting
+    test.disk.fail.set(1);
+    test.disk.lose.set(true);
+    assert_eq!(test.write(file, 0, b"lost"), Err(ErrorCode::FAIL));
+    assert_eq!(test.lookup("/a.txt"), Err(ErrorCode::NOMEM));
+    assert_eq!(test.write(file, 0, b"lost"), Err(ErrorCode::NOMEM));
+    assert_eq!(test.mount(), Err(ErrorCode::NOMEM));
+}
@@ -0,0 +1,584 @@
+//! Provides userspace with files and directories in flash.
+//!
+//! Paths are given in the read-only `PATH` buffer, ending at the end of the
+//! buffer or at a NUL. Relative paths are in the app's own directory; see
+//! `filesystem` for how apps are kept apart.
+//!
+//! Each app opens up to `MAX_FILES` files at once, which are identified by a
+//! file descriptor. Opening, reading, writing, removing and making
+//! directories complete with an upcall of the status and, for opening,
+//! reading and writing, the file descriptor or number of bytes. The other
+//! commands complete immediately.
+//!
+//! Setup
+//! -----
+//!
+//! You need a flash that provides `hil::flash::Flash`.
+//!
+//! ```rust
+//! let flash_fs = components::flash_fs::FlashFsComponent::new(
+//!     board_kernel,
+//!     capsules_extra::flash_fs::driver::DRIVER_NUM,
+//!     &peripherals.nvmc,
+//!     0x70,
+//!     16,
+//! )
+//! .finalize(components::flash_fs_component_static!(nrf52840::nvmc::Nvmc));
+//! ```
+
+use core::cell::Cell;
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
+use kernel::hil::flash::Flash;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+use super::filesystem::{FileId, FlashFs, FlashFsClient};
+use super::metadata::{Kind, NAME_LENGTH};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::FlashFs as usize;
+
+/// The number of files each app can open at once.
+pub const MAX_FILES: usize = 4;
+/// The longest path.
+pub const MAX_PATH: usize = 64;
+/// The length of the buffer data is moved through, at most a page at a time.
+pub const BUF_LEN: usize = 512;
+
+/// Creates the file when opening it, if it does not exist.
+pub const CREATE: usize = 1;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// The path of the file or directory
+    pub const PATH: usize = 0;
+    /// The data to write
+    pub const DATA: usize = 1;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Receives the data read, or the name of a directory entry
+    pub const BUFFER: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A command completed, or failed
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+#[derive(Clone, Copy)]
+enum Command {
+    Open { create: bool },
+    Read { fd: usize, len: usize },
+    Write { fd: usize, len: usize },
+    Unlink,
+    Mkdir,
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    file: FileId,
+    position: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    files: [Option<OpenFile>; MAX_FILES],
+    /// A command waiting for the filesystem
+    pending: Option<Command>,
+}
+
+// Copies the path from the `PATH` buffer, returning its length.
+fn read_path(kernel_data: &GrantKernelData, path: &mut [u8; MAX_PATH]) -> Result<usize, ErrorCode> {
+    kernel_data
+        .get_readonly_processbuffer(ro_allow::PATH)
+        .and_then(|buffer| {
+            buffer.enter(|app_path| {
+                let len = app_path
+                    .iter()
+                    .position(|b| b.get() == 0)
+                    .unwrap_or(app_path.len());
+                if len > MAX_PATH {
+                    return Err(ErrorCode::INVAL);
+                }
+                app_path[..len].copy_to_slice(&mut path[..len]);
+                Ok(len)
+            })
+        })
+        .unwrap_or(Err(ErrorCode::RESERVE))
+}
+
+pub struct FlashFsDriver<'a, F: Flash + 'static> {
+    fs: &'a FlashFs<'a, F>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The app the filesystem is working for, and its command
+    current: OptionalCell<(ProcessId, Command)>,
+    buffer: TakeCell<'static, [u8]>,
+    /// The number of bytes read or written so far
+    done: Cell<usize>,
+    /// The file being removed
+    unlinked: OptionalCell<FileId>,
+}
+
+impl<'a, F: Flash> FlashFsDriver<'a, F> {
+    pub fn new(
+        fs: &'a FlashFs<'a, F>,
+        apps: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+        buffer: &'static mut [u8],
+    ) -> FlashFsDriver<'a, F> {
+        FlashFsDriver {
+            fs,
+            apps,
+            current: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            done: Cell::new(0),
+            unlinked: OptionalCell::empty(),
+        }
+    }
+
+    fn open_file(&self, processid: ProcessId, fd: usize) -> Result<OpenFile, ErrorCode> {
+        self.apps
+            .enter(processid, |app, _| app.files.get(fd).copied().flatten())?
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    fn set_position(&self, processid: ProcessId, fd: usize, position: usize) {
+        let _ = self.apps.enter(processid, |app, _| {
+            if let Some(Some(file)) = app.files.get_mut(fd) {
+                file.position = position;
+            }
+        });
+    }
+
+    fn upcall(&self, processid: ProcessId, result: Result<usize, ErrorCode>) {
+        let _ = self.apps.enter(processid, |_, kernel_data| {
+            let (status, value) = match result {
+                Ok(value) => (kernel::errorcode::into_statuscode(Ok(())), value),
+                Err(error) => (kernel::errorcode::into_statuscode(Err(error)), 0),
+            };
+            kernel_data
+                .schedule_upcall(upcall::DONE, (status, value, 0))
+                .ok();
+        });
+    }
+
+    fn start(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        self.done.set(0);
+        self.run(processid, command)
+    }
+
+    // Starts, or continues, `command`, reporting it if it finished.
+    fn run(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        match self.step(processid, command) {
+            Ok(None) => {
+                self.current.set((processid, command));
+                Ok(())
+            }
+            Ok(Some(value)) => {
+                self.current.clear();
+                self.upcall(processid, Ok(value));
+                Ok(())
+            }
+            Err(error) => {
+                self.current.clear();
+                Err(error)
+            }
+        }
+    }
+
+    // Returns the result if `command` finished, or `None` if the
+    // filesystem is working on it.
+    fn step(&self, processid: ProcessId, command: Command) -> Result<Option<usize>, ErrorCode> {
+        let perms = processid
+            .get_storage_permissions()
+            .ok_or(ErrorCode::INVAL)?;
+        let mut path = [0; MAX_PATH];
+
+        match command {
+            Command::Open { create } => {
+                let (len, fd) = self.apps.enter(processid, |app, kernel_data| {
+                    let len = read_path(kernel_data, &mut path)?;
+                    let fd = app.files.iter().position(Option::is_none);
+                    Ok::<_, ErrorCode>((len, fd.ok_or(ErrorCode::NOMEM)?))
+                })??;
+
+                match self.fs.lookup(&path[..len], &perms) {
+                    Ok(file) => {
+                        if self.fs.stat(file)?.0 != Kind::File {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        self.apps.enter(processid, |app, _| {
+                            app.files[fd] = Some(OpenFile { file, position: 0 });
+                        })?;
+                        Ok(Some(fd))
+                    }
+                    Err(ErrorCode::NOSUPPORT) if create => {
+                        self.fs.create(&path[..len], Kind::File, &perms)?;
+                        Ok(None)
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            Command::Mkdir => {
+                let len = self.apps.enter(processid, |_, kernel_data| {
+                    read_path(kernel_data, &mut path)
+                })??;
+                self.fs.create(&path[..len], Kind::Directory, &perms)?;
+                Ok(None)
+            }
+            Command::Unlink => {
+                let len = self.apps.enter(processid, |_, kernel_data| {
+                    read_path(kernel_data, &mut path)
+                })??;
+                let file = self.fs.lookup(&path[..len], &perms)?;
+                self.fs.unlink(&path[..len], &perms)?;
+                self.unlinked.set(file);
+                Ok(None)
+            }
+            Command::Read { fd, len } => {
+                let available = self.apps.enter(processid, |_, kernel_data| {
+                    kernel_data
+                        .get_readwrite_processbuffer(rw_allow::BUFFER)
+                        .map_or(0, |buffer| buffer.len())
+                })?;
+                if len > available {
+                    return Err(ErrorCode::SIZE);
+                }
+
+                let open = self.open_file(processid, fd)?;
+                let (_, size) = self.fs.stat(open.file)?;
+                let remaining = len - self.done.get();
+                if remaining == 0 || open.position >= size {
+                    return Ok(Some(self.done.get()));
+                }
+
+                let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+                self.fs
+                    .read(open.file, open.position, buffer, remaining, &perms)
+                    .map_err(|(error, buffer)| {
+                        self.buffer.replace(buffer);
+                        error
+                    })?;
+                Ok(None)
+            }
+            Command::Write { fd, len } => {
+                let open = self.open_file(processid, fd)?;
+                let done = self.done.get();
+                if len == done {
+                    return Ok(Some(done));
+                }
+
+                let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+                let chunk = (len - done).min(buffer.len());
+                let copied = self
+                    .apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::DATA)
+                            .and_then(|data| {
+                                data.enter(|data| {
+                                    if data.len() < len {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    data[done..done + chunk].copy_to_slice(&mut buffer[..chunk]);
+                                    Ok(())
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    })
+                    .map_err(ErrorCode::from)
+                    .and_then(|result| result);
+                if let Err(error) = copied {
+                    self.buffer.replace(buffer);
+                    return Err(error);
+                }
+
+                self.fs
+                    .write(open.file, open.position, buffer, chunk, &perms)
+                    .map_err(|(error, buffer)| {
+                        self.buffer.replace(buffer);
+                        error
+                    })?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Continues the current command after part of it succeeded, and starts
+    // the next command once it is finished.
+    fn next(&self, processid: ProcessId, command: Command, result: Result<(), ErrorCode>) {
+        if let Err(error) = result.and_then(|()| self.run(processid, command)) {
+            self.upcall(processid, Err(error));
+        }
+        if self.current.is_none() {
+            self.check_queue();
+        }
+    }
+
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let processid = cntr.processid();
+            let command = cntr.enter(|app, _| app.pending.take());
+            if let Some(command) = command {
+                if let Err(error) = self.start(processid, command) {
+                    self.upcall(processid, Err(error));
+                }
+                if self.current.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Runs `command` now if the filesystem is free, or queues it.
+    fn enqueue(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        if self.current.is_some() {
+            self.apps.enter(processid, |app, _| {
+                if app.pending.is_some() {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    app.pending = Some(command);
+                    Ok(())
+                }
+            })?
+        } else {
+            self.start(processid, command)
+        }
+    }
+
+    fn seek(&self, processid: ProcessId, fd: usize, position: usize) -> Result<(), ErrorCode> {
+        let open = self.open_file(processid, fd)?;
+        let (_, size) = self.fs.stat(open.file)?;
+        if position > size {
+            return Err(ErrorCode::INVAL);
+        }
+        self.set_position(processid, fd, position);
+        Ok(())
+    }
+
+    fn close(&self, processid: ProcessId, fd: usize) -> Result<(), ErrorCode> {
+        self.apps
+            .enter(processid, |app, _| match app.files.get_mut(fd) {
+                Some(file) if file.is_some() => {
+                    *file = None;
+                    Ok(())
+                }
+                _ => Err(ErrorCode::INVAL),
+            })?
+    }
+
+    // Copies the name of entry `index` of the directory at `PATH` to the
+    // `BUFFER`, returning the length of the name and whether it is a
+    // directory.
+    fn read_dir(&self, processid: ProcessId, index: usize) -> Result<(usize, bool), ErrorCode> {
+        let perms = processid
+            .get_storage_permissions()
+            .ok_or(ErrorCode::INVAL)?;
+        self.apps.enter(processid, |_, kernel_data| {
+            let mut path = [0; MAX_PATH];
+            let len = read_path(kernel_data, &mut path)?;
+            let mut name = [0; NAME_LENGTH];
+            let (name_len, kind) = self.fs.read_dir(&path[..len], index, &mut name, &perms)?;
+
+            kernel_data
+                .get_readwrite_processbuffer(rw_allow::BUFFER)
+                .and_then(|buffer| {
+                    buffer.mut_enter(|buffer| {
+                        let len = name_len.min(buffer.len());
+                        buffer[..len].copy_from_slice(&name[..len]);
+                    })
+                })
+                .map_err(ErrorCode::from)?;
+            Ok((name_len, kind == Kind::Directory))
+        })?
+    }
+}
+
+impl<'a, F: Flash> FlashFsClient for FlashFsDriver<'a, F> {
+    fn mount_complete(&self, _result: Result<(), ErrorCode>) {}
+
+    fn create_complete(&self, result: Result<FileId, ErrorCode>) {
+        self.current.take().map(|(processid, command)| {
+            let result = match command {
+                Command::Open { .. } => result.and_then(|file| {
+                    self.apps
+                        .enter(processid, |app, _| {
+                            let fd = app
+                                .files
+                                .iter()
+                                .position(Option::is_none)
+                                .ok_or(ErrorCode::NOMEM)?;
+                            app.files[fd] = Some(OpenFile { file, position: 0 });
+                            Ok(fd)
+                        })
+                        .unwrap_or(Err(ErrorCode::FAIL))
+                }),
+                _ => result.map(|_| 0),
+            };
+            self.upcall(processid, result);
+        });
+        self.check_queue();
+    }
+
+    fn unlink_complete(&self, result: Result<(), ErrorCode>) {
+        let unlinked = self.unlinked.take();
+        if let (Ok(()), Some(unlinked)) = (result, unlinked) {
+            // The id may be reused, so close the file everywhere
+            for cntr in self.apps.iter() {
+                cntr.enter(|app, _| {
+                    for file in app.files.iter_mut() {
+                        if file.map_or(false, |file| file.file == unlinked) {
+                            *file = None;
+                        }
+                    }
+                });
+            }
+        }
+
+        self.current.take().map(|(processid, _)| {
+            self.upcall(processid, result.map(|()| 0));
+        });
+        self.check_queue();
+    }
+
+    fn read_complete(&self, result: Result<usize, ErrorCode>, buffer: &'static mut [u8]) {
+        self.current.take().map(|(processid, command)| {
+            let result = result.and_then(|len| {
+                let done = self.done.get();
+                self.apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readwrite_processbuffer(rw_allow::BUFFER)
+                            .and_then(|app_buffer| {
+                                app_buffer.mut_enter(|app_buffer| {
+                                    if let Some(dest) = app_buffer.get(done..done + len) {
+                                        dest.copy_from_slice(&buffer[..len]);
+                                    }
+                                })
+                            })
+                    })
+                    .map_err(ErrorCode::from)?
+                    .map_err(ErrorCode::from)?;
+
+                if let Command::Read { fd, .. } = command {
+                    let position = self.open_file(processid, fd)?.position;
+                    self.set_position(processid, fd, position + len);
+                }
+                self.done.set(done + len);
+                Ok(())
+            });
+            self.buffer.replace(buffer);
+            self.next(processid, command, result);
+        });
+    }
+
+    fn write_complete(&self, result: Result<usize, ErrorCode>, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.current.take().map(|(processid, command)| {
+            let result = result.and_then(|len| {
+                if let Command::Write { fd, .. } = command {
+                    let position = self.open_file(processid, fd)?.position;
+                    self.set_position(processid, fd, position + len);
+                }
+                self.done.set(self.done.get() + len);
+                Ok(())
+            });
+            self.next(processid, command, result);
+        });
+    }
+}
+
+impl<'a, F: Flash> SyscallDriver for FlashFsDriver<'a, F> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Open the file at `PATH`, creating it if `data1` is `CREATE`.
+    ///   The upcall gives the file descriptor.
+    /// - `2`: Read up to `data2` bytes from file descriptor `data1` into
+    ///   `BUFFER`. The upcall gives the number of bytes read, which is less
+    ///   at the end of the file.
+    /// - `3`: Write `data2` bytes of `DATA` to file descriptor `data1`. The
+    ///   upcall gives the number of bytes written.
+    /// - `4`: Move file descriptor `data1` to offset `data2`, which may be at
+    ///   most the size of the file.
+    /// - `5`: Close file descriptor `data1`.
+    /// - `6`: Remove the file, or empty directory, at `PATH`.
+    /// - `7`: Copy the name of entry `data1` of the directory at `PATH` to
+    ///   `BUFFER`. Returns the length of the name and 1 if the entry is a
+    ///   directory, or `NOSUPPORT` after the last entry.
+    /// - `8`: Make a directory at `PATH`.
+    /// - `9`: Return the size of the file of file descriptor `data1`.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let command = match command_num {
+            0 => return CommandReturn::success(),
+            1 => Command::Open {
+                create: data1 & CREATE != 0,
+            },
+            2 => Command::Read {
+                fd: data1,
+                len: data2,
+            },
+            3 => Command::Write {
+                fd: data1,
+                len: data2,
+            },
+            4 => return self.seek(processid, data1, data2).into(),
+            5 => return self.close(processid, data1).into(),
+            6 => Command::Unlink,
+            7 => {
+                return match self.read_dir(processid, data1) {
+                    Ok((len, is_directory)) => {
+                        CommandReturn::success_u32_u32(len as u32, is_directory as u32)
+                    }
+                    Err(error) => CommandReturn::failure(error),
+                }
+            }
+            8 => Command::Mkdir,
+            9 => {
+                return match self
+                    .open_file(processid, data1)
+                    .and_then(|open| self.fs.stat(open.file))
+                {
+                    Ok((_, size)) => CommandReturn::success_u32(size as u32),
+                    Err(error) => CommandReturn::failure(error),
+                }
+            }
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+        self.enqueue(processid, command).into()
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
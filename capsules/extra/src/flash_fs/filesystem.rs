@@ -0,0 +1,887 @@
+//! The filesystem: files and directories in the pages of a `hil::flash`.
+//!
+//! Nothing is ever modified in place. Writing to a file writes the new
+//! contents of the page to a free page, and then writes a new copy of the
+//! metadata, with a higher revision, to another free page. Only once the new
+//! metadata is written does the change take effect, so losing power at any
+//! point leaves the filesystem as it was either before or after the change.
+//! Mounting reads every page and uses the valid metadata with the highest
+//! revision.
+//!
+//! Free pages are handed out in turn from a cursor which goes round the whole
+//! filesystem, so erases are spread over every page, including the pages
+//! the metadata moves between.
+//!
+//! Data pages start with a zero word, so no file can be mistaken for
+//! metadata when mounting.
+//!
+//! Each app has a directory in the root named with its `write_id`, as eight
+//! hexadecimal digits, which is created along with the first file the app
+//! creates. Relative paths are in the app's directory, and absolute paths
+//! (`/0000002a/log`) name files of other apps. An app may only read, or
+//! write, the files of apps it has read, or write, permission for.
+//!
+//! Errors are reported as:
+//!
+//! - `NOSUPPORT`: the file or directory does not exist.
+//! - `ALREADY`: the file or directory already exists.
+//! - `NOMEM`: there are no free pages or entries left.
+//! - `INVAL`: the path, or offset, is invalid, or the directory to remove is
+//!   not empty.
+//! - `FAIL`: the app does not have permission, or the flash failed.
+//! - `BUSY`: another operation is in progress.
+//! - `OFF`: the filesystem is not mounted.
+
+use core::cell::Cell;
+
+use kernel::hil::flash::{self, Flash};
+use kernel::storage_permissions::StoragePermissions;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::metadata::{self, Entry, Kind, Metadata, ROOT};
+
+/// Identifies a file or directory while the filesystem is mounted.
+pub type FileId = u8;
+
+/// The length of the header of each data page.
+const DATA_OFFSET: usize = 4;
+
+/// The storage permissions of the user of the filesystem.
+pub trait Permissions {
+    fn check_read_permission(&self, owner: u32) -> bool;
+    fn check_write_permission(&self, owner: u32) -> bool;
+    fn get_write_id(&self) -> Option<u32>;
+}
+
+impl Permissions for StoragePermissions {
+    fn check_read_permission(&self, owner: u32) -> bool {
+        StoragePermissions::check_read_permission(self, owner)
+    }
+
+    fn check_write_permission(&self, owner: u32) -> bool {
+        StoragePermissions::check_write_permission(self, owner)
+    }
+
+    fn get_write_id(&self) -> Option<u32> {
+        StoragePermissions::get_write_id(self)
+    }
+}
+
+fn may_read(perms: &dyn Permissions, owner: u32) -> bool {
+    perms.get_write_id() == Some(owner) || perms.check_read_permission(owner)
+}
+
+fn may_write(perms: &dyn Permissions, owner: u32) -> bool {
+    perms.get_write_id() == Some(owner) || perms.check_write_permission(owner)
+}
+
+/// The name of the directory of the app with `write_id`.
+fn app_directory(write_id: u32) -> [u8; 8] {
+    let mut name = [0; 8];
+    for (i, digit) in name.iter_mut().enumerate() {
+        let nibble = (write_id >> (28 - i * 4)) & 0xF;
+        *digit = b"0123456789abcdef"[nibble as usize];
+    }
+    name
+}
+
+/// The components of `path`, starting with `app_directory` if the path is
+/// relative.
+fn components<'p>(path: &'p [u8], app_directory: &'p [u8]) -> impl Iterator<Item = &'p [u8]> {
+    is_relative(path)
+        .then_some(app_directory)
+        .into_iter()
+        .chain(path.split(|b| *b == b'/').filter(|c| !c.is_empty()))
+}
+
+fn is_relative(path: &[u8]) -> bool {
+    path.first() != Some(&b'/')
+}
+
+pub trait FlashFsClient {
+    fn mount_complete(&self, result: Result<(), ErrorCode>);
+    /// A file or directory was created, with the given id.
+    fn create_complete(&self, result: Result<FileId, ErrorCode>);
+    fn unlink_complete(&self, result: Result<(), ErrorCode>);
+    /// Returns the buffer and the number of bytes read into it.
+    fn read_complete(&self, result: Result<usize, ErrorCode>, buffer: &'static mut [u8]);
+    /// Returns the buffer and the number of bytes written from it.
+    fn write_complete(&self, result: Result<usize, ErrorCode>, buffer: &'static mut [u8]);
+}
+
+/// The operation completed once the metadata is written.
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Format,
+    Create(FileId),
+    Unlink,
+    Write(usize),
+}
+
+/// A write to one page of a file.
+#[derive(Clone, Copy, PartialEq)]
+struct Write {
+    file: FileId,
+    /// The page of the file
+    n: usize,
+    /// The offset in the page
+    offset: usize,
+    len: usize,
+    /// The free pages the data and metadata are written to
+    page: usize,
+    metadata_page: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Mounting, reading each page in turn
+    Scan(usize),
+    Read {
+        offset: usize,
+        len: usize,
+    },
+    /// Reading the page the write replaces, to keep the rest of it
+    WriteRead(Write),
+    WriteErase(Write),
+    WriteData(Write),
+    CommitErase(Op, usize),
+    CommitWrite(Op, usize),
+    /// Reading back the metadata after a failed change
+    Restore(Option<Op>, ErrorCode),
+}
+
+pub struct FlashFs<'a, F: Flash + 'static> {
+    flash: &'a F,
+    /// The first flash page of the filesystem
+    start: usize,
+    /// The number of flash pages of the filesystem
+    pages: usize,
+    page_size: usize,
+    client: OptionalCell<&'a dyn FlashFsClient>,
+    state: Cell<State>,
+    mounted: Cell<bool>,
+    /// The page holding the current metadata
+    metadata_page: Cell<usize>,
+    /// The next page to consider allocating
+    next_page: Cell<usize>,
+    /// The page and revision of the newest metadata found while mounting
+    found: OptionalCell<(usize, u32)>,
+    metadata: TakeCell<'static, F::Page>,
+    data: TakeCell<'static, F::Page>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, F: Flash> FlashFs<'a, F> {
+    /// The filesystem uses `pages` flash pages starting at page `start`.
+    pub fn new(
+        flash: &'a F,
+        start: usize,
+        pages: usize,
+        metadata: &'static mut F::Page,
+        data: &'static mut F::Page,
+    ) -> Self {
+        Self {
+            flash,
+            start,
+            pages,
+            page_size: metadata.as_mut().len(),
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            mounted: Cell::new(false),
+            metadata_page: Cell::new(0),
+            next_page: Cell::new(0),
+            found: OptionalCell::empty(),
+            metadata: TakeCell::new(metadata),
+            data: TakeCell::new(data),
+            buffer: TakeCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn FlashFsClient) {
+        self.client.set(client);
+    }
+
+    /// The number of bytes of a file each page holds.
+    fn capacity(&self) -> usize {
+        self.page_size - DATA_OFFSET
+    }
+
+    fn with_metadata<R>(
+        &self,
+        f: impl FnOnce(&mut Metadata<&mut [u8]>) -> Result<R, ErrorCode>,
+    ) -> Result<R, ErrorCode> {
+        if !self.mounted.get() {
+            return Err(ErrorCode::OFF);
+        }
+        self.metadata
+            .map(|page| f(&mut Metadata::new(page.as_mut())))
+            .unwrap_or(Err(ErrorCode::BUSY))
+    }
+
+    fn ready(&self) -> Result<(), ErrorCode> {
+        if !self.mounted.get() {
+            Err(ErrorCode::OFF)
+        } else if self.state.get() != State::Idle {
+            Err(ErrorCode::BUSY)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take a free page which is not `exclude`.
+    fn allocate(&self, metadata: &Metadata<&mut [u8]>, exclude: Option<usize>) -> Option<usize> {
+        let next = self.next_page.get();
+        let page = (0..self.pages)
+            .map(|i| (next + i) % self.pages)
+            .find(|page| {
+                *page != self.metadata_page.get()
+                    && Some(*page) != exclude
+                    && !metadata.is_referenced(*page)
+            })?;
+        self.next_page.set((page + 1) % self.pages);
+        Some(page)
+    }
+
+    /// Split `path` into the directory holding it and its name.
+    fn resolve<'p>(
+        &self,
+        metadata: &Metadata<&mut [u8]>,
+        path: &'p [u8],
+        app_directory: &'p [u8],
+    ) -> Result<(u8, &'p [u8]), ErrorCode> {
+        let mut components = components(path, app_directory);
+        let mut parent = ROOT;
+        let mut name = components.next().ok_or(ErrorCode::INVAL)?;
+        for next in components {
+            let index = metadata.find(parent, name).ok_or(ErrorCode::NOSUPPORT)?;
+            match metadata.entry(index) {
+                Some(entry) if entry.kind == Kind::Directory => parent = index,
+                _ => return Err(ErrorCode::INVAL),
+            }
+            name = next;
+        }
+
+        if name.len() > metadata::NAME_LENGTH || name == b"." || name == b".." {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok((parent, name))
+    }
+
+    /// Find the entry at `path`, or `ROOT` for the root directory.
+    fn find(
+        &self,
+        metadata: &Metadata<&mut [u8]>,
+        path: &[u8],
+        perms: &dyn Permissions,
+    ) -> Result<u8, ErrorCode> {
+        let app_directory = app_directory(perms.get_write_id().unwrap_or(0));
+        if is_relative(path) && perms.get_write_id().is_none() {
+            return Err(ErrorCode::INVAL);
+        }
+        if components(path, &app_directory).next().is_none() {
+            return Ok(ROOT);
+        }
+
+        let (parent, name) = self.resolve(metadata, path, &app_directory)?;
+        let index = metadata.find(parent, name).ok_or(ErrorCode::NOSUPPORT)?;
+        match metadata.entry(index) {
+            Some(entry) if may_read(perms, entry.owner) => Ok(index),
+            _ => Err(ErrorCode::FAIL),
+        }
+    }
+
+    /// Mount the filesystem, formatting the flash if it holds no filesystem.
+    pub fn mount(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.mounted.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.found.clear();
+        self.scan(0)
+    }
+
+    fn scan(&self, page: usize) -> Result<(), ErrorCode> {
+        let data = self.data.take().ok_or(ErrorCode::BUSY)?;
+        self.state.set(State::Scan(page));
+        self.flash
+            .read_page(self.start + page, data)
+            .map_err(|(error, data)| {
+                self.data.replace(data);
+                self.state.set(State::Idle);
+                error
+            })
+    }
+
+    /// Find the file or directory at `path`.
+    pub fn lookup(&self, path: &[u8], perms: &dyn Permissions) -> Result<FileId, ErrorCode> {
+        self.with_metadata(|metadata| match self.find(metadata, path, perms)? {
+            ROOT => Err(ErrorCode::INVAL),
+            index => Ok(index),
+        })
+    }
+
+    /// The kind and size of `file`.
+    pub fn stat(&self, file: FileId) -> Result<(Kind, usize), ErrorCode> {
+        self.with_metadata(|metadata| {
+            let entry = metadata.entry(file).ok_or(ErrorCode::INVAL)?;
+            Ok((entry.kind, entry.size as usize))
+        })
+    }
+
+    /// Copy the name of entry `index` of the directory at `path` to `name`,
+    /// returning the length of the name and the kind of the entry. Only the
+    /// entries the app may read are listed.
+    pub fn read_dir(
+        &self,
+        path: &[u8],
+        index: usize,
+        name: &mut [u8],
+        perms: &dyn Permissions,
+    ) -> Result<(usize, Kind), ErrorCode> {
+        self.with_metadata(|metadata| {
+            let directory = self.find(metadata, path, perms)?;
+            if directory != ROOT
+                && metadata.entry(directory).map(|entry| entry.kind) != Some(Kind::Directory)
+            {
+                return Err(ErrorCode::INVAL);
+            }
+
+            let (_, entry) = metadata
+                .entries()
+                .filter(|(_, entry)| entry.parent == directory && may_read(perms, entry.owner))
+                .nth(index)
+                .ok_or(ErrorCode::NOSUPPORT)?;
+            let len = entry.name().len().min(name.len());
+            name[..len].copy_from_slice(&entry.name()[..len]);
+            Ok((entry.name().len(), entry.kind))
+        })
+    }
+
+    /// Create an empty file, or directory, at `path`.
+    pub fn create(
+        &self,
+        path: &[u8],
+        kind: Kind,
+        perms: &dyn Permissions,
+    ) -> Result<(), ErrorCode> {
+        self.ready()?;
+        let write_id = perms.get_write_id().ok_or(ErrorCode::FAIL)?;
+        let app_directory = app_directory(write_id);
+
+        let (index, page) = self.with_metadata(|metadata| {
+            let page = self.allocate(metadata, None).ok_or(ErrorCode::NOMEM)?;
+
+            // The directory of the app is created along with its first file
+            let mut created = None;
+            if is_relative(path) && metadata.find(ROOT, &app_directory).is_none() {
+                let index = metadata.free_entry().ok_or(ErrorCode::NOMEM)?;
+                let entry = Entry::new(Kind::Directory, ROOT, write_id, &app_directory);
+                metadata.set_entry(index, &entry);
+                created = Some(index);
+            }
+
+            self.create_entry(metadata, path, &app_directory, kind, write_id, perms)
+                .map(|index| (index, page))
+                .map_err(|error| {
+                    if let Some(index) = created {
+                        metadata.remove_entry(index);
+                    }
+                    error
+                })
+        })?;
+        self.commit(Op::Create(index), page).map_err(|error| {
+            self.restore(None, error);
+            error
+        })
+    }
+
+    fn create_entry(
+        &self,
+        metadata: &mut Metadata<&mut [u8]>,
+        path: &[u8],
+        app_directory: &[u8],
+        kind: Kind,
+        write_id: u32,
+        perms: &dyn Permissions,
+    ) -> Result<u8, ErrorCode> {
+        let (parent, name) = self.resolve(metadata, path, app_directory)?;
+        if metadata.find(parent, name).is_some() {
+            return Err(ErrorCode::ALREADY);
+        }
+
+        // The root only holds the directories of the apps
+        let permitted = match metadata.entry(parent) {
+            Some(entry) => may_write(perms, entry.owner),
+            None => kind == Kind::Directory && name == app_directory,
+        };
+        if !permitted {
+            return Err(ErrorCode::FAIL);
+        }
+
+        let index = metadata.free_entry().ok_or(ErrorCode::NOMEM)?;
+        metadata.set_entry(index, &Entry::new(kind, parent, write_id, name));
+        Ok(index)
+    }
+
+    /// Remove the file, or empty directory, at `path`.
+    pub fn unlink(&self, path: &[u8], perms: &dyn Permissions) -> Result<(), ErrorCode> {
+        self.ready()?;
+        let page = self.with_metadata(|metadata| {
+            let index = match self.find(metadata, path, perms)? {
+                ROOT => return Err(ErrorCode::INVAL),
+                index => index,
+            };
+            let entry = metadata.entry(index).ok_or(ErrorCode::NOSUPPORT)?;
+            if !may_write(perms, entry.owner) {
+                return Err(ErrorCode::FAIL);
+            }
+            if metadata.entries().any(|(_, child)| child.parent == index) {
+                return Err(ErrorCode::INVAL);
+            }
+
+            let page = self.allocate(metadata, None).ok_or(ErrorCode::NOMEM)?;
+            metadata.remove_entry(index);
+            Ok(page)
+        })?;
+        self.commit(Op::Unlink, page).map_err(|error| {
+            self.restore(None, error);
+            error
+        })
+    }
+
+    /// Read up to `len` bytes of `file` from `offset` into `buffer`. At most
+    /// one page is read at a time, so fewer bytes may be read. Returns `SIZE`
+    /// at the end of the file.
+    pub fn read(
+        &self,
+        file: FileId,
+        offset: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+        perms: &dyn Permissions,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let len = len.min(buffer.len());
+        let page = match self.ready().and_then(|()| {
+            self.with_metadata(|metadata| {
+                let entry = metadata.entry(file).ok_or(ErrorCode::INVAL)?;
+                if entry.kind != Kind::File {
+                    return Err(ErrorCode::INVAL);
+                }
+                if !may_read(perms, entry.owner) {
+                    return Err(ErrorCode::FAIL);
+                }
+                if offset >= entry.size as usize {
+                    return Err(ErrorCode::SIZE);
+                }
+                metadata
+                    .page(&entry, offset / self.capacity())
+                    .ok_or(ErrorCode::FAIL)
+                    .map(|page| (page, (entry.size as usize - offset).min(len)))
+            })
+        }) {
+            Ok(page) => page,
+            Err(error) => return Err((error, buffer)),
+        };
+
+        let (page, len) = page;
+        let offset = offset % self.capacity();
+        let len = len.min(self.capacity() - offset);
+        let data = match self.data.take() {
+            Some(data) => data,
+            None => return Err((ErrorCode::BUSY, buffer)),
+        };
+        self.state.set(State::Read { offset, len });
+        match self.flash.read_page(self.start + page, data) {
+            Ok(()) => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            Err((error, data)) => {
+                self.data.replace(data);
+                self.state.set(State::Idle);
+                Err((error, buffer))
+            }
+        }
+    }
+
+    /// Write up to `len` bytes of `buffer` to `file` at `offset`, which must
+    /// not be past the end of the file. At most one page is written at a
+    /// time, so fewer bytes may be written.
+    pub fn write(
+        &self,
+        file: FileId,
+        offset: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+        perms: &dyn Permissions,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let len = len.min(buffer.len());
+        let (write, old_page) = match self
+            .ready()
+            .and_then(|()| self.plan_write(file, offset, len, perms))
+        {
+            Ok(plan) => plan,
+            Err(error) => return Err((error, buffer)),
+        };
+        let data = match self.data.take() {
+            Some(data) => data,
+            None => return Err((ErrorCode::BUSY, buffer)),
+        };
+
+        let result = match old_page {
+            Some(old_page) => {
+                self.state.set(State::WriteRead(write));
+                self.flash
+                    .read_page(self.start + old_page, data)
+                    .map_err(|(error, data)| {
+                        self.data.replace(data);
+                        error
+                    })
+            }
+            None => {
+                data.as_mut().iter_mut().for_each(|b| *b = 0xFF);
+                self.fill(data.as_mut(), &write, buffer);
+                self.data.replace(data);
+                self.erase_data(write)
+            }
+        };
+        match result {
+            Ok(()) => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            Err(error) => {
+                self.state.set(State::Idle);
+                Err((error, buffer))
+            }
+        }
+    }
+
+    /// Work out which pages a write uses, and whether it keeps part of the
+    /// page it replaces.
+    fn plan_write(
+        &self,
+        file: FileId,
+        offset: usize,
+        len: usize,
+        perms: &dyn Permissions,
+    ) -> Result<(Write, Option<usize>), ErrorCode> {
+        self.with_metadata(|metadata| {
+            let entry = metadata.entry(file).ok_or(ErrorCode::INVAL)?;
+            if entry.kind != Kind::File {
+                return Err(ErrorCode::INVAL);
+            }
+            if !may_write(perms, entry.owner) {
+                return Err(ErrorCode::FAIL);
+            }
+            if offset > entry.size as usize {
+                return Err(ErrorCode::INVAL);
+            }
+
+            let n = offset / self.capacity();
+            let offset = offset % self.capacity();
+            let len = len.min(self.capacity() - offset);
+            if len == 0 {
+                return Err(ErrorCode::SIZE);
+            }
+            let old_page = metadata.page(&entry, n);
+            if old_page.is_none() && !metadata.has_free_ref() {
+                return Err(ErrorCode::NOMEM);
+            }
+
+            let page = self.allocate(metadata, None).ok_or(ErrorCode::NOMEM)?;
+            let metadata_page = self
+                .allocate(metadata, Some(page))
+                .ok_or(ErrorCode::NOMEM)?;
+
+            // The old page is only needed for the data the write keeps
+            let stored = (entry.size as usize)
+                .saturating_sub(n * self.capacity())
+                .min(self.capacity());
+            let old_page = old_page.filter(|_| offset > 0 || offset + len < stored);
+            let write = Write {
+                file,
+                n,
+                offset,
+                len,
+                page,
+                metadata_page,
+            };
+            Ok((write, old_page))
+        })
+    }
+
+    fn fill(&self, data: &mut [u8], write: &Write, buffer: &[u8]) {
+        data[..DATA_OFFSET].iter_mut().for_each(|b| *b = 0);
+        let offset = DATA_OFFSET + write.offset;
+        data[offset..offset + write.len].copy_from_slice(&buffer[..write.len]);
+    }
+
+    fn erase_data(&self, write: Write) -> Result<(), ErrorCode> {
+        self.state.set(State::WriteErase(write));
+        self.flash.erase_page(self.start + write.page)
+    }
+
+    /// Write the metadata, with the next revision, to `page`.
+    fn commit(&self, op: Op, page: usize) -> Result<(), ErrorCode> {
+        self.metadata.map(|metadata| {
+            let mut metadata = Metadata::new(metadata.as_mut());
+            let revision = metadata.revision();
+            metadata.seal(revision.wrapping_add(1));
+        });
+        self.state.set(State::CommitErase(op, page));
+        self.flash.erase_page(self.start + page)
+    }
+
+    /// Throw away the changes to the metadata by reading back the current
+    /// metadata, then report `error` for `op`, if there is one.
+    fn restore(&self, op: Option<Op>, error: ErrorCode) {
+        // There is nothing to read back when formatting
+        let result = if self.metadata_page.get() >= self.pages {
+            Err(())
+        } else {
+            self.state.set(State::Restore(op, error));
+            match self.metadata.take() {
+                Some(metadata) => self
+                    .flash
+                    .read_page(self.start + self.metadata_page.get(), metadata)
+                    .map_err(|(_, metadata)| {
+                        self.metadata.replace(metadata);
+                    }),
+                None => Err(()),
+            }
+        };
+
+        if result.is_err() {
+            // Without the metadata the filesystem has to be mounted again
+            self.mounted.set(false);
+            self.state.set(State::Idle);
+            if let Some(op) = op {
+                self.complete(op, Err(error));
+            }
+        }
+    }
+
+    fn complete(&self, op: Op, result: Result<(), ErrorCode>) {
+        self.state.set(State::Idle);
+        match op {
+            Op::Format => {
+                self.mounted.set(result.is_ok());
+                self.client.map(|client| client.mount_complete(result));
+            }
+            Op::Create(index) => {
+                self.client
+                    .map(|client| client.create_complete(result.map(|()| index)));
+            }
+            Op::Unlink => {
+                self.client.map(|client| client.unlink_complete(result));
+            }
+            Op::Write(len) => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(|client| client.write_complete(result.map(|()| len), buffer))
+                });
+            }
+        }
+    }
+
+    fn write_failed(&self, error: ErrorCode) {
+        self.state.set(State::Idle);
+        self.buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.write_complete(Err(error), buffer))
+        });
+    }
+
+    fn scan_complete(&self, page: usize, data: &'static mut F::Page, error: flash::Error) {
+        if error != flash::Error::CommandComplete {
+            self.data.replace(data);
+            self.state.set(State::Idle);
+            self.client
+                .map(|client| client.mount_complete(Err(ErrorCode::FAIL)));
+            return;
+        }
+
+        if let Some(revision) = metadata::check(data.as_mut()) {
+            if self.found.map_or(true, |(_, found)| revision > *found) {
+                self.metadata
+                    .map(|metadata| metadata.as_mut().copy_from_slice(data.as_mut()));
+                self.found.set((page, revision));
+            }
+        }
+        self.data.replace(data);
+
+        if page + 1 < self.pages {
+            if let Err(error) = self.scan(page + 1) {
+                self.client.map(|client| client.mount_complete(Err(error)));
+            }
+            return;
+        }
+
+        match self.found.take() {
+            Some((page, _)) => {
+                self.metadata_page.set(page);
+                self.next_page.set((page + 1) % self.pages);
+                self.mounted.set(true);
+                self.state.set(State::Idle);
+                self.client.map(|client| client.mount_complete(Ok(())));
+            }
+            None => {
+                self.metadata
+                    .map(|metadata| Metadata::new(metadata.as_mut()).format());
+                self.metadata_page.set(self.pages);
+                if let Err(error) = self.commit(Op::Format, 0) {
+                    self.restore(Some(Op::Format), error);
+                }
+            }
+        }
+    }
+
+    fn data_written(&self, write: Write) {
+        let result = self.with_metadata(|metadata| {
+            metadata.set_page(write.file, write.n, write.page)?;
+            let mut entry = metadata.entry(write.file).ok_or(ErrorCode::FAIL)?;
+            let end = write.n * self.capacity() + write.offset + write.len;
+            entry.size = entry.size.max(end as u32);
+            metadata.set_entry(write.file, &entry);
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                let op = Op::Write(write.len);
+                if let Err(error) = self.commit(op, write.metadata_page) {
+                    self.restore(Some(op), error);
+                }
+            }
+            Err(error) => self.write_failed(error),
+        }
+    }
+}
+
+impl<'a, F: Flash> flash::Client<F> for FlashFs<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: flash::Error) {
+        let ok = error == flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Scan(n) => self.scan_complete(n, page, error),
+            State::Read { offset, len } => {
+                self.state.set(State::Idle);
+                let mut buffer = self.buffer.take();
+                if let Some(buffer) = buffer.as_deref_mut() {
+                    let offset = DATA_OFFSET + offset;
+                    buffer[..len].copy_from_slice(&page.as_mut()[offset..offset + len]);
+                }
+                self.data.replace(page);
+                let result = if ok { Ok(len) } else { Err(ErrorCode::FAIL) };
+                buffer.map(|buffer| {
+                    self.client
+                        .map(|client| client.read_complete(result, buffer))
+                });
+            }
+            State::WriteRead(write) => {
+                if ok {
+                    self.buffer
+                        .map(|buffer| self.fill(page.as_mut(), &write, buffer));
+                }
+                self.data.replace(page);
+                let result = if ok {
+                    self.erase_data(write)
+                } else {
+                    Err(ErrorCode::FAIL)
+                };
+                if let Err(error) = result {
+                    self.write_failed(error);
+                }
+            }
+            State::Restore(op, error) => {
+                if !ok || metadata::check(page.as_mut()).is_none() {
+                    self.mounted.set(false);
+                }
+                self.metadata.replace(page);
+                self.state.set(State::Idle);
+                if let Some(op) = op {
+                    self.complete(op, Err(error));
+                }
+            }
+            _ => {
+                self.data.replace(page);
+            }
+        }
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: flash::Error) {
+        let ok = error == flash::Error::CommandComplete;
+        match self.state.get() {
+            State::WriteData(write) => {
+                self.data.replace(page);
+                if ok {
+                    self.data_written(write);
+                } else {
+                    self.write_failed(ErrorCode::FAIL);
+                }
+            }
+            State::CommitWrite(op, metadata_page) => {
+                self.metadata.replace(page);
+                if ok {
+                    self.metadata_page.set(metadata_page);
+                    self.complete(op, Ok(()));
+                } else {
+                    self.restore(Some(op), ErrorCode::FAIL);
+                }
+            }
+            _ => {
+                self.data.replace(page);
+            }
+        }
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        let ok = error == flash::Error::CommandComplete;
+        match self.state.get() {
+            State::WriteErase(write) => {
+                if !ok {
+                    return self.write_failed(ErrorCode::FAIL);
+                }
+                let result = match self.data.take() {
+                    Some(data) => {
+                        self.state.set(State::WriteData(write));
+                        self.flash
+                            .write_page(self.start + write.page, data)
+                            .map_err(|(error, data)| {
+                                self.data.replace(data);
+                                error
+                            })
+                    }
+                    None => Err(ErrorCode::FAIL),
+                };
+                if let Err(error) = result {
+                    self.write_failed(error);
+                }
+            }
+            State::CommitErase(op, metadata_page) => {
+                if !ok {
+                    return self.restore(Some(op), ErrorCode::FAIL);
+                }
+                let result = match self.metadata.take() {
+                    Some(metadata) => {
+                        self.state.set(State::CommitWrite(op, metadata_page));
+                        self.flash
+                            .write_page(self.start + metadata_page, metadata)
+                            .map_err(|(error, metadata)| {
+                                self.metadata.replace(metadata);
+                                error
+                            })
+                    }
+                    None => Err(ErrorCode::FAIL),
+                };
+                if let Err(error) = result {
+                    self.restore(Some(op), error);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! The metadata page of the flash filesystem.
+//!
+//! The metadata page describes every file and directory, and lists the
+//! flash pages storing the data of each file:
+//!
+//! ```text
+//! +-------+----------+-----+--------+----------+---------------+-----------+
+//! | magic | revision | CRC | # refs | reserved | entries       | page refs |
+//! | 4     | 4        | 4   | 2      | 2        | 32 bytes each | 2 each    |
+//! +-------+----------+-----+--------+----------+---------------+-----------+
+//! ```
+//!
+//! The CRC covers everything after it, so a metadata page that was only
+//! partly written is ignored.
+//!
+//! Each entry is
+//!
+//! ```text
+//! +------+--------+----------+----------+-------+------+-----------+--------+------+
+//! | kind | parent | name len | reserved | owner | size | first ref | # refs | name |
+//! | 1    | 1      | 1        | 1        | 4     | 4    | 2         | 2      | 16   |
+//! +------+--------+----------+----------+-------+------+-----------+--------+------+
+//! ```
+//!
+//! The pages of a file are the `# refs` page refs starting at `first ref`.
+//! The page refs of all of the files are kept packed together at the start
+//! of the page ref area.
+
+use kernel::ErrorCode;
+use tickv::crc32::Crc32;
+
+const MAGIC: u32 = 0x5346_4b54;
+const REVISION_OFFSET: usize = 4;
+const CRC_OFFSET: usize = 8;
+const REFS_OFFSET: usize = 12;
+const HEADER_LENGTH: usize = 16;
+
+const ENTRY_LENGTH: usize = 32;
+/// The longest name of a file or directory.
+pub const NAME_LENGTH: usize = 16;
+/// The parent of the entries in the root directory.
+pub const ROOT: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+    pub kind: Kind,
+    pub parent: u8,
+    /// The write_id of the app that created the entry
+    pub owner: u32,
+    pub size: u32,
+    first_ref: u16,
+    refs: u16,
+    name_length: u8,
+    name: [u8; NAME_LENGTH],
+}
+
+impl Entry {
+    /// Create a new, empty, entry. `name` must be at most `NAME_LENGTH`
+    /// bytes long.
+    pub fn new(kind: Kind, parent: u8, owner: u32, name: &[u8]) -> Self {
+        let mut entry = Self {
+            kind,
+            parent,
+            owner,
+            size: 0,
+            first_ref: 0,
+            refs: 0,
+            name_length: name.len() as u8,
+            name: [0; NAME_LENGTH],
+        };
+        entry.name[..name.len()].copy_from_slice(name);
+        entry
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_length as usize]
+    }
+
+    /// The number of pages storing the data of the file.
+    pub fn pages(&self) -> usize {
+        self.refs as usize
+    }
+}
+
+/// Check whether `page` holds valid metadata, returning its revision if it
+/// does.
+pub fn check(page: &[u8]) -> Option<u32> {
+    if page.len() < HEADER_LENGTH || read_u32(page, 0) != MAGIC {
+        return None;
+    }
+
+    let mut crc = Crc32::new();
+    crc.update(&page[REFS_OFFSET..]);
+    if crc.finalise() != read_u32(page, CRC_OFFSET) {
+        return None;
+    }
+
+    let metadata = Metadata { page };
+    if metadata.used_refs() > metadata.max_refs() {
+        return None;
+    }
+    Some(read_u32(page, REVISION_OFFSET))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// A view of a metadata page.
+pub struct Metadata<B> {
+    page: B,
+}
+
+impl<B: AsRef<[u8]>> Metadata<B> {
+    pub fn new(page: B) -> Self {
+        Self { page }
+    }
+
+    pub fn revision(&self) -> u32 {
+        read_u32(self.page.as_ref(), REVISION_OFFSET)
+    }
+
+    /// The number of entries the page has space for.
+    pub fn max_entries(&self) -> usize {
+        (self.page.as_ref().len() / 2 / ENTRY_LENGTH).min(ROOT as usize)
+    }
+
+    fn refs_offset(&self) -> usize {
+        HEADER_LENGTH + self.max_entries() * ENTRY_LENGTH
+    }
+
+    fn max_refs(&self) -> usize {
+        (self.page.as_ref().len() - self.refs_offset()) / 2
+    }
+
+    fn used_refs(&self) -> usize {
+        read_u16(self.page.as_ref(), REFS_OFFSET) as usize
+    }
+
+    /// Whether another page can be added to a file.
+    pub fn has_free_ref(&self) -> bool {
+        self.used_refs() < self.max_refs()
+    }
+
+    fn page_ref(&self, index: usize) -> usize {
+        read_u16(self.page.as_ref(), self.refs_offset() + index * 2) as usize
+    }
+
+    pub fn entry(&self, index: u8) -> Option<Entry> {
+        if index as usize >= self.max_entries() {
+            return None;
+        }
+        let buf = &self.page.as_ref()[HEADER_LENGTH + index as usize * ENTRY_LENGTH..];
+        let kind = match buf[0] {
+            1 => Kind::File,
+            2 => Kind::Directory,
+            _ => return None,
+        };
+
+        let mut name = [0; NAME_LENGTH];
+        name.copy_from_slice(&buf[16..16 + NAME_LENGTH]);
+        Some(Entry {
+            kind,
+            parent: buf[1],
+            name_length: buf[2].min(NAME_LENGTH as u8),
+            owner: read_u32(buf, 4),
+            size: read_u32(buf, 8),
+            first_ref: read_u16(buf, 12),
+            refs: read_u16(buf, 14),
+            name,
+        })
+    }
+
+    /// Iterate over the indices and entries in use.
+    pub fn entries(&self) -> impl Iterator<Item = (u8, Entry)> + '_ {
+        (0..self.max_entries() as u8).filter_map(|index| Some((index, self.entry(index)?)))
+    }
+
+    /// Find the entry called `name` in the directory `parent`.
+    pub fn find(&self, parent: u8, name: &[u8]) -> Option<u8> {
+        self.entries()
+            .find(|(_, entry)| entry.parent == parent && entry.name() == name)
+            .map(|(index, _)| index)
+    }
+
+    pub fn free_entry(&self) -> Option<u8> {
+        (0..self.max_entries() as u8).find(|index| self.entry(*index).is_none())
+    }
+
+    /// The flash page storing page `n` of `entry`, relative to the start of
+    /// the filesystem.
+    pub fn page(&self, entry: &Entry, n: usize) -> Option<usize> {
+        if n < entry.pages() {
+            Some(self.page_ref(entry.first_ref as usize + n))
+        } else {
+            None
+        }
+    }
+
+    /// Whether any file stores data in `page`.
+    pub fn is_referenced(&self, page: usize) -> bool {
+        (0..self.used_refs()).any(|index| self.page_ref(index) == page)
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Metadata<B> {
+    /// Clear the metadata, leaving an empty filesystem.
+    pub fn format(&mut self) {
+        let page = self.page.as_mut();
+        page.iter_mut().for_each(|b| *b = 0);
+        page[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    }
+
+    /// Set the revision and update the CRC, ready to write the page.
+    pub fn seal(&mut self, revision: u32) {
+        let page = self.page.as_mut();
+        page[REVISION_OFFSET..REVISION_OFFSET + 4].copy_from_slice(&revision.to_le_bytes());
+
+        let mut crc = Crc32::new();
+        crc.update(&page[REFS_OFFSET..]);
+        page[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.finalise().to_le_bytes());
+    }
+
+    fn set_used_refs(&mut self, refs: usize) {
+        self.page.as_mut()[REFS_OFFSET..REFS_OFFSET + 2]
+            .copy_from_slice(&(refs as u16).to_le_bytes());
+    }
+
+    fn set_page_ref(&mut self, index: usize, page: usize) {
+        let offset = self.refs_offset() + index * 2;
+        self.page.as_mut()[offset..offset + 2].copy_from_slice(&(page as u16).to_le_bytes());
+    }
+
+    pub fn set_entry(&mut self, index: u8, entry: &Entry) {
+        let buf = &mut self.page.as_mut()[HEADER_LENGTH + index as usize * ENTRY_LENGTH..];
+        buf[0] = match entry.kind {
+            Kind::File => 1,
+            Kind::Directory => 2,
+        };
+        buf[1] = entry.parent;
+        buf[2] = entry.name_length;
+        buf[3] = 0;
+        buf[4..8].copy_from_slice(&entry.owner.to_le_bytes());
+        buf[8..12].copy_from_slice(&entry.size.to_le_bytes());
+        buf[12..14].copy_from_slice(&entry.first_ref.to_le_bytes());
+        buf[14..16].copy_from_slice(&entry.refs.to_le_bytes());
+        buf[16..16 + NAME_LENGTH].copy_from_slice(&entry.name);
+    }
+
+    /// Move the first refs of every other file at or after `from` by
+    /// `delta` refs.
+    fn move_first_refs(&mut self, skip: u8, from: u16, grow: bool, delta: u16) {
+        for index in 0..self.max_entries() as u8 {
+            if let Some(mut entry) = self.entry(index) {
+                if index != skip && entry.refs > 0 && entry.first_ref >= from {
+                    if grow {
+                        entry.first_ref += delta;
+                    } else {
+                        entry.first_ref -= delta;
+                    }
+                    self.set_entry(index, &entry);
+                }
+            }
+        }
+    }
+
+    /// Store page `n` of the file at `index` in `page`, which either
+    /// replaces a page of the file or adds a page to the end of it.
+    pub fn set_page(&mut self, index: u8, n: usize, page: usize) -> Result<(), ErrorCode> {
+        let mut entry = self.entry(index).ok_or(ErrorCode::INVAL)?;
+
+        if n < entry.pages() {
+            self.set_page_ref(entry.first_ref as usize + n, page);
+            return Ok(());
+        }
+        if n != entry.pages() {
+            return Err(ErrorCode::INVAL);
+        }
+        if !self.has_free_ref() {
+            return Err(ErrorCode::NOMEM);
+        }
+
+        // Make space for the new ref after the existing ones
+        let used = self.used_refs();
+        let at = if entry.refs == 0 {
+            used
+        } else {
+            (entry.first_ref + entry.refs) as usize
+        };
+        for i in (at..used).rev() {
+            let moved = self.page_ref(i);
+            self.set_page_ref(i + 1, moved);
+        }
+        self.set_page_ref(at, page);
+        self.set_used_refs(used + 1);
+        self.move_first_refs(index, at as u16, true, 1);
+
+        if entry.refs == 0 {
+            entry.first_ref = at as u16;
+        }
+        entry.refs += 1;
+        self.set_entry(index, &entry);
+        Ok(())
+    }
+
+    /// Remove the entry at `index`, freeing the pages of the file.
+    pub fn remove_entry(&mut self, index: u8) {
+        if let Some(entry) = self.entry(index) {
+            if entry.refs > 0 {
+                let start = entry.first_ref as usize;
+                let end = start + entry.refs as usize;
+                let used = self.used_refs();
+                for i in end..used {
+                    let moved = self.page_ref(i);
+                    self.set_page_ref(i - entry.refs as usize, moved);
+                }
+                self.set_used_refs(used - entry.refs as usize);
+                self.move_first_refs(index, end as u16, false, entry.refs);
+            }
+
+            let offset = HEADER_LENGTH + index as usize * ENTRY_LENGTH;
+            self.page.as_mut()[offset..offset + ENTRY_LENGTH]
+                .iter_mut()
+                .for_each(|b| *b = 0);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! A power-fail safe, wear levelling, filesystem of files and directories in
+//! flash, and a syscall driver through which applications use it, each in a
+//! directory of its own.
+
+pub mod driver;
+pub mod filesystem;
+pub mod metadata;
+
+#[cfg(test)]
+mod tests;
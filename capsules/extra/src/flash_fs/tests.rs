@@ -0,0 +1,667 @@
+//! Tests of the filesystem over a simulated flash, which can lose power part
+//! of the way through writing or erasing a page.
+
+use core::cell::{Cell, RefCell};
+use std::boxed::Box;
+use std::format;
+use std::string::String;
+use std::vec;
+use std::vec::Vec;
+
+use kernel::hil::flash::{self, Flash};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::filesystem::{FileId, FlashFs, FlashFsClient, Permissions};
+use super::metadata::{self, Entry, Kind, Metadata, ROOT};
+
+const PAGE_SIZE: usize = 512;
+const PAGES: usize = 12;
+
+struct TestPage([u8; PAGE_SIZE]);
+
+impl Default for TestPage {
+    fn default() -> Self {
+        TestPage([0; PAGE_SIZE])
+    }
+}
+
+impl AsMut<[u8]> for TestPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+enum Pending {
+    Read(usize, &'static mut TestPage),
+    Write(usize, &'static mut TestPage),
+    Erase(usize),
+}
+
+/// A NOR flash whose operations the test completes. After `budget` more
+/// writes and erases, the next one only gets half way before the power is
+/// cut, and nothing completes after that.
+struct SimFlash {
+    client: OptionalCell<&'static dyn flash::Client<SimFlash>>,
+    storage: RefCell<Vec<[u8; PAGE_SIZE]>>,
+    pending: RefCell<Option<Pending>>,
+    budget: Cell<Option<usize>>,
+    powered: Cell<bool>,
+    /// The number of writes and erases
+    operations: Cell<usize>,
+    erases: RefCell<Vec<usize>>,
+}
+
+impl SimFlash {
+    fn new() -> &'static SimFlash {
+        Box::leak(Box::new(SimFlash {
+            client: OptionalCell::empty(),
+            storage: RefCell::new(vec![[0xFF; PAGE_SIZE]; PAGES]),
+            pending: RefCell::new(None),
+            budget: Cell::new(None),
+            powered: Cell::new(true),
+            operations: Cell::new(0),
+            erases: RefCell::new(vec![0; PAGES]),
+        }))
+    }
+
+    /// Turn the power back on, losing the operation in progress.
+    fn power_on(&self) {
+        self.pending.borrow_mut().take();
+        self.budget.set(None);
+        self.powered.set(true);
+    }
+
+    // Counts a write or erase, returning false if it is cut short.
+    fn spend(&self) -> bool {
+        self.operations.set(self.operations.get() + 1);
+        match self.budget.get() {
+            Some(0) => {
+                self.powered.set(false);
+                false
+            }
+            Some(budget) => {
+                self.budget.set(Some(budget - 1));
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Complete the operation in progress, returning false if there is none.
+    fn complete(&self) -> bool {
+        if !self.powered.get() {
+            return false;
+        }
+        let pending = self.pending.borrow_mut().take();
+        match pending {
+            Some(Pending::Read(page, buf)) => {
+                buf.0.copy_from_slice(&self.storage.borrow()[page]);
+                self.client
+                    .map(|client| client.read_complete(buf, flash::Error::CommandComplete));
+            }
+            Some(Pending::Write(page, buf)) => {
+                let whole = self.spend();
+                let end = if whole { PAGE_SIZE } else { PAGE_SIZE / 2 };
+                for (stored, new) in self.storage.borrow_mut()[page][..end]
+                    .iter_mut()
+                    .zip(buf.0.iter())
+                {
+                    *stored &= *new;
+                }
+                if whole {
+                    self.client
+                        .map(|client| client.write_complete(buf, flash::Error::CommandComplete));
+                }
+            }
+            Some(Pending::Erase(page)) => {
+                let whole = self.spend();
+                let end = if whole { PAGE_SIZE } else { PAGE_SIZE / 2 };
+                self.storage.borrow_mut()[page][..end]
+                    .iter_mut()
+                    .for_each(|b| *b = 0xFF);
+                self.erases.borrow_mut()[page] += 1;
+                if whole {
+                    self.client
+                        .map(|client| client.erase_complete(flash::Error::CommandComplete));
+                }
+            }
+            None => return false,
+        }
+        true
+    }
+
+    fn run(&self) {
+        while self.complete() {}
+    }
+
+    fn start(&self, pending: Pending) -> Result<(), Pending> {
+        let mut current = self.pending.borrow_mut();
+        if current.is_some() || !self.powered.get() {
+            return Err(pending);
+        }
+        *current = Some(pending);
+        Ok(())
+    }
+}
+
+impl Flash for SimFlash {
+    type Page = TestPage;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut TestPage,
+    ) -> Result<(), (ErrorCode, &'static mut TestPage)> {
+        assert!(page_number < PAGES);
+        self.start(Pending::Read(page_number, buf))
+            .map_err(|pending| match pending {
+                Pending::Read(_, buf) => (ErrorCode::BUSY, buf),
+                _ => unreachable!(),
+            })
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut TestPage,
+    ) -> Result<(), (ErrorCode, &'static mut TestPage)> {
+        assert!(page_number < PAGES);
+        self.start(Pending::Write(page_number, buf))
+            .map_err(|pending| match pending {
+                Pending::Write(_, buf) => (ErrorCode::BUSY, buf),
+                _ => unreachable!(),
+            })
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        assert!(page_number < PAGES);
+        self.start(Pending::Erase(page_number))
+            .map_err(|_| ErrorCode::BUSY)
+    }
+}
+
+struct Perms {
+    write_id: Option<u32>,
+    read: Vec<u32>,
+    write: Vec<u32>,
+}
+
+impl Perms {
+    fn app(write_id: u32) -> Perms {
+        Perms {
+            write_id: Some(write_id),
+            read: Vec::new(),
+            write: Vec::new(),
+        }
+    }
+
+    /// Reads the files of every app in the tests.
+    fn reader() -> Perms {
+        Perms {
+            write_id: None,
+            read: vec![1, 2],
+            write: Vec::new(),
+        }
+    }
+}
+
+impl Permissions for Perms {
+    fn check_read_permission(&self, owner: u32) -> bool {
+        self.read.contains(&owner)
+    }
+
+    fn check_write_permission(&self, owner: u32) -> bool {
+        self.write.contains(&owner)
+    }
+
+    fn get_write_id(&self) -> Option<u32> {
+        self.write_id
+    }
+}
+
+struct TestClient {
+    mounted: Cell<Option<Result<(), ErrorCode>>>,
+    created: Cell<Option<Result<FileId, ErrorCode>>>,
+    unlinked: Cell<Option<Result<(), ErrorCode>>>,
+    transferred: Cell<Option<Result<usize, ErrorCode>>>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl FlashFsClient for TestClient {
+    fn mount_complete(&self, result: Result<(), ErrorCode>) {
+        self.mounted.set(Some(result));
+    }
+
+    fn create_complete(&self, result: Result<FileId, ErrorCode>) {
+        self.created.set(Some(result));
+    }
+
+    fn unlink_complete(&self, result: Result<(), ErrorCode>) {
+        self.unlinked.set(Some(result));
+    }
+
+    fn read_complete(&self, result: Result<usize, ErrorCode>, buffer: &'static mut [u8]) {
+        self.transferred.set(Some(result));
+        self.buffer.replace(buffer);
+    }
+
+    fn write_complete(&self, result: Result<usize, ErrorCode>, buffer: &'static mut [u8]) {
+        self.transferred.set(Some(result));
+        self.buffer.replace(buffer);
+    }
+}
+
+/// The files and directories, by path, with the contents of the files.
+type Tree = Vec<(String, Option<Vec<u8>>)>;
+
+/// A filesystem over a `SimFlash`, which runs each operation to completion.
+/// Operations the power was cut during fail with `NODEVICE`. When recording,
+/// the tree is saved after each operation which changes it.
+struct TestFs {
+    flash: &'static SimFlash,
+    fs: &'static FlashFs<'static, SimFlash>,
+    client: &'static TestClient,
+    recording: Cell<bool>,
+    trees: RefCell<Vec<Tree>>,
+}
+
+impl TestFs {
+    fn new(flash: &'static SimFlash) -> TestFs {
+        let fs = Box::leak(Box::new(FlashFs::new(
+            flash,
+            0,
+            PAGES,
+            Box::leak(Box::new(TestPage::default())),
+            Box::leak(Box::new(TestPage::default())),
+        )));
+        let client = Box::leak(Box::new(TestClient {
+            mounted: Cell::new(None),
+            created: Cell::new(None),
+            unlinked: Cell::new(None),
+            transferred: Cell::new(None),
+            buffer: TakeCell::new(Box::leak(Box::new([0; 100]))),
+        }));
+        flash.client.set(fs);
+        fs.set_client(client);
+        TestFs {
+            flash,
+            fs,
+            client,
+            recording: Cell::new(false),
+            trees: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn finish<T>(&self, result: &Cell<Option<Result<T, ErrorCode>>>) -> Result<T, ErrorCode> {
+        self.flash.run();
+        let result = result.take().unwrap_or(Err(ErrorCode::NODEVICE));
+        // Reading the tree runs operations too, which are not recorded
+        if result.is_ok() && self.recording.replace(false) {
+            let tree = self.tree();
+            self.trees.borrow_mut().push(tree);
+            self.recording.set(true);
+        }
+        result
+    }
+
+    fn mount(&self) -> Result<(), ErrorCode> {
+        self.fs.mount()?;
+        self.finish(&self.client.mounted)
+    }
+
+    fn create(&self, path: &str, kind: Kind, perms: &Perms) -> Result<FileId, ErrorCode> {
+        self.fs.create(path.as_bytes(), kind, perms)?;
+        self.finish(&self.client.created)
+    }
+
+    fn unlink(&self, path: &str, perms: &Perms) -> Result<(), ErrorCode> {
+        self.fs.unlink(path.as_bytes(), perms)?;
+        self.finish(&self.client.unlinked)
+    }
+
+    fn write(
+        &self,
+        path: &str,
+        offset: usize,
+        data: &[u8],
+        perms: &Perms,
+    ) -> Result<(), ErrorCode> {
+        let file = self.fs.lookup(path.as_bytes(), perms)?;
+        let mut done = 0;
+        while done < data.len() {
+            let buffer = self.client.buffer.take().unwrap();
+            let len = (data.len() - done).min(buffer.len());
+            buffer[..len].copy_from_slice(&data[done..done + len]);
+            self.fs
+                .write(file, offset + done, buffer, len, perms)
+                .map_err(|(error, buffer)| {
+                    self.client.buffer.replace(buffer);
+                    error
+                })?;
+            done += self.finish(&self.client.transferred)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, path: &str, perms: &Perms) -> Result<Vec<u8>, ErrorCode> {
+        let file = self.fs.lookup(path.as_bytes(), perms)?;
+        let (_, size) = self.fs.stat(file)?;
+        let mut contents = Vec::new();
+        while contents.len() < size {
+            let buffer = self.client.buffer.take().unwrap();
+            let len = buffer.len();
+            self.fs
+                .read(file, contents.len(), buffer, len, perms)
+                .map_err(|(error, buffer)| {
+                    self.client.buffer.replace(buffer);
+                    error
+                })?;
+            let len = self.finish(&self.client.transferred)?;
+            self.client
+                .buffer
+                .map(|buffer| contents.extend_from_slice(&buffer[..len]));
+        }
+        Ok(contents)
+    }
+
+    fn list(&self, path: &str, perms: &Perms) -> Vec<(String, Kind)> {
+        let mut entries = Vec::new();
+        let mut name = [0; metadata::NAME_LENGTH];
+        while let Ok((len, kind)) =
+            self.fs
+                .read_dir(path.as_bytes(), entries.len(), &mut name, perms)
+        {
+            entries.push((String::from_utf8(name[..len].to_vec()).unwrap(), kind));
+        }
+        entries
+    }
+
+    fn tree(&self) -> Tree {
+        let mut tree = Vec::new();
+        self.walk(String::new(), &mut tree);
+        tree.sort();
+        tree
+    }
+
+    fn walk(&self, path: String, tree: &mut Tree) {
+        let perms = Perms::reader();
+        let directory = if path.is_empty() { "/" } else { &path };
+        for (name, kind) in self.list(directory, &perms) {
+            let path = format!("{}/{}", path, name);
+            match kind {
+                Kind::File => {
+                    let contents = self.read(&path, &perms).unwrap();
+                    tree.push((path, Some(contents)));
+                }
+                Kind::Directory => {
+                    tree.push((path.clone(), None));
+                    self.walk(path, tree);
+                }
+            }
+        }
+    }
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+}
+
+#[test]
+fn metadata_keeps_page_refs_packed() {
+    let mut page = [0; PAGE_SIZE];
+    let mut metadata = Metadata::new(&mut page[..]);
+    metadata.format();
+    for (index, name) in [b"a", b"b", b"c"].iter().enumerate() {
+        metadata.set_entry(index as u8, &Entry::new(Kind::File, ROOT, 1, *name));
+    }
+
+    // Interleave the pages of the files
+    for (index, page) in [(0, 10), (1, 20), (0, 11), (2, 30), (1, 21), (0, 12)] {
+        let n = metadata.entry(index).unwrap().pages();
+        metadata.set_page(index, n, page).unwrap();
+    }
+    let pages = |metadata: &Metadata<&mut [u8]>, index| {
+        let entry = metadata.entry(index).unwrap();
+        (0..entry.pages())
+            .map(|n| metadata.page(&entry, n).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(pages(&metadata, 0), [10, 11, 12]);
+    assert_eq!(pages(&metadata, 1), [20, 21]);
+    assert_eq!(pages(&metadata, 2), [30]);
+
+    // Replacing a page and removing a file leave the others alone
+    metadata.set_page(1, 0, 22).unwrap();
+    metadata.remove_entry(0);
+    assert_eq!(metadata.entry(0), None);
+    assert_eq!(pages(&metadata, 1), [22, 21]);
+    assert_eq!(pages(&metadata, 2), [30]);
+    assert!(!metadata.is_referenced(10));
+    assert!(!metadata.is_referenced(20));
+    assert!(metadata.is_referenced(21));
+    assert_eq!(metadata.set_page(2, 2, 31), Err(ErrorCode::INVAL));
+}
+
+#[test]
+fn metadata_is_checked() {
+    let mut page = [0; PAGE_SIZE];
+    assert_eq!(metadata::check(&page), None);
+
+    let mut metadata = Metadata::new(&mut page[..]);
+    metadata.format();
+    metadata.set_entry(0, &Entry::new(Kind::Directory, ROOT, 1, b"dir"));
+    metadata.seal(7);
+    assert_eq!(metadata::check(&page), Some(7));
+
+    page[PAGE_SIZE - 1] ^= 1;
+    assert_eq!(metadata::check(&page), None);
+}
+
+#[test]
+fn files_and_directories() {
+    let flash = SimFlash::new();
+    let fs = TestFs::new(flash);
+    let app = Perms::app(1);
+    assert_eq!(fs.fs.lookup(b"a", &app), Err(ErrorCode::OFF));
+    assert_eq!(fs.mount(), Ok(()));
+    assert_eq!(fs.mount(), Err(ErrorCode::ALREADY));
+
+    // The first file creates the app's directory
+    fs.create("a", Kind::File, &app).unwrap();
+    assert_eq!(
+        fs.list("/", &app),
+        [(String::from("00000001"), Kind::Directory)]
+    );
+    assert_eq!(fs.create("a", Kind::File, &app), Err(ErrorCode::ALREADY));
+
+    // Write across pages, then overwrite the middle and extend the end
+    let mut contents = pattern(600, 1);
+    fs.write("a", 0, &contents, &app).unwrap();
+    assert_eq!(fs.read("a", &app), Ok(contents.clone()));
+    let patch = pattern(300, 2);
+    contents[400..].copy_from_slice(&patch[..200]);
+    contents.extend_from_slice(&patch[200..]);
+    fs.write("/00000001/a", 400, &patch, &app).unwrap();
+    assert_eq!(fs.read("a", &app), Ok(contents.clone()));
+    assert_eq!(fs.write("a", 701, b"gap", &app), Err(ErrorCode::INVAL));
+
+    fs.create("logs", Kind::Directory, &app).unwrap();
+    fs.create("logs/today", Kind::File, &app).unwrap();
+    fs.write("logs/today", 0, b"hello", &app).unwrap();
+    assert_eq!(
+        fs.create("missing/file", Kind::File, &app),
+        Err(ErrorCode::NOSUPPORT)
+    );
+    assert_eq!(fs.create("a/file", Kind::File, &app), Err(ErrorCode::INVAL));
+    assert_eq!(
+        fs.create("a-name-longer-than-16", Kind::File, &app),
+        Err(ErrorCode::INVAL)
+    );
+    assert_eq!(fs.unlink("logs", &app), Err(ErrorCode::INVAL));
+    assert_eq!(
+        fs.list("", &app),
+        [
+            (String::from("a"), Kind::File),
+            (String::from("logs"), Kind::Directory)
+        ]
+    );
+
+    // Everything is still there after mounting again
+    let tree = fs.tree();
+    let remounted = TestFs::new(flash);
+    assert_eq!(remounted.mount(), Ok(()));
+    assert_eq!(remounted.tree(), tree);
+    assert_eq!(remounted.read("a", &app), Ok(contents));
+
+    // Removing files frees their pages for new ones
+    remounted.unlink("a", &app).unwrap();
+    assert_eq!(remounted.read("a", &app), Err(ErrorCode::NOSUPPORT));
+    for i in 0..3 {
+        let name = format!("logs/{}", i);
+        remounted.create(&name, Kind::File, &app).unwrap();
+        remounted.write(&name, 0, &pattern(500, i), &app).unwrap();
+    }
+    assert_eq!(remounted.read("logs/2", &app), Ok(pattern(500, 2)));
+}
+
+#[test]
+fn apps_are_kept_apart() {
+    let fs = TestFs::new(SimFlash::new());
+    fs.mount().unwrap();
+    let one = Perms::app(1);
+    fs.create("secret", Kind::File, &one).unwrap();
+    fs.write("secret", 0, b"one's", &one).unwrap();
+
+    // Another app without permission cannot see or change the file
+    let two = Perms::app(2);
+    assert_eq!(fs.read("/00000001/secret", &two), Err(ErrorCode::FAIL));
+    assert_eq!(fs.read("secret", &two), Err(ErrorCode::NOSUPPORT));
+    assert_eq!(fs.unlink("/00000001/secret", &two), Err(ErrorCode::FAIL));
+    assert_eq!(
+        fs.create("/00000001/mine", Kind::File, &two),
+        Err(ErrorCode::FAIL)
+    );
+    assert_eq!(
+        fs.create("/00000003", Kind::Directory, &two),
+        Err(ErrorCode::FAIL)
+    );
+    assert_eq!(fs.create("/file", Kind::File, &two), Err(ErrorCode::FAIL));
+    fs.create("secret", Kind::File, &two).unwrap();
+    assert_eq!(
+        fs.list("/", &two),
+        [(String::from("00000002"), Kind::Directory)]
+    );
+
+    // Read permission allows reading, but not writing
+    let reader = Perms {
+        write_id: Some(2),
+        read: vec![1],
+        write: Vec::new(),
+    };
+    assert_eq!(fs.read("/00000001/secret", &reader), Ok(b"one's".to_vec()));
+    assert_eq!(
+        fs.write("/00000001/secret", 0, b"two's", &reader),
+        Err(ErrorCode::FAIL)
+    );
+    assert_eq!(fs.list("/", &reader).len(), 2);
+
+    // An app without a write_id can only read
+    let anonymous = Perms::reader();
+    assert_eq!(
+        fs.create("file", Kind::File, &anonymous),
+        Err(ErrorCode::FAIL)
+    );
+    assert_eq!(fs.read("secret", &anonymous), Err(ErrorCode::INVAL));
+}
+
+#[test]
+fn erases_are_spread_over_the_flash() {
+    let flash = SimFlash::new();
+    let fs = TestFs::new(flash);
+    fs.mount().unwrap();
+    let app = Perms::app(1);
+    fs.create("counter", Kind::File, &app).unwrap();
+    for i in 0..200u32 {
+        fs.write("counter", 0, &i.to_le_bytes(), &app).unwrap();
+    }
+    assert_eq!(fs.read("counter", &app), Ok(199u32.to_le_bytes().to_vec()));
+
+    let erases = flash.erases.borrow();
+    let least = *erases.iter().min().unwrap();
+    let most = *erases.iter().max().unwrap();
+    assert!(least > 0);
+    assert!(most - least <= 2, "uneven erases {:?}", erases);
+}
+
+#[test]
+fn fills_up() {
+    let fs = TestFs::new(SimFlash::new());
+    fs.mount().unwrap();
+    let app = Perms::app(1);
+    fs.create("big", Kind::File, &app).unwrap();
+    let result = fs.write("big", 0, &pattern(PAGES * PAGE_SIZE, 0), &app);
+    assert_eq!(result, Err(ErrorCode::NOMEM));
+
+    // The pages written before running out are kept
+    let written = fs.read("big", &app).unwrap();
+    assert_eq!(written, pattern(written.len(), 0));
+    fs.unlink("big", &app).unwrap();
+    fs.create("small", Kind::File, &app).unwrap();
+    fs.write("small", 0, b"fits", &app).unwrap();
+}
+
+// The operations the power is cut during.
+fn scenario(fs: &TestFs) -> Result<(), ErrorCode> {
+    let app = Perms::app(1);
+    fs.mount()?;
+    fs.create("a", Kind::File, &app)?;
+    fs.write("a", 0, &pattern(600, 1), &app)?;
+    fs.write("a", 100, &pattern(300, 2), &app)?;
+    fs.create("d", Kind::Directory, &app)?;
+    fs.create("d/b", Kind::File, &app)?;
+    fs.write("d/b", 0, &pattern(80, 3), &app)?;
+    fs.unlink("a", &app)
+}
+
+#[test]
+fn power_cuts_leave_the_old_or_new_tree() {
+    let flash = SimFlash::new();
+    let fs = TestFs::new(flash);
+    fs.recording.set(true);
+    scenario(&fs).unwrap();
+    let trees = fs.trees.take();
+    let operations = flash.operations.get();
+
+    for budget in 0..operations {
+        let flash = SimFlash::new();
+        flash.budget.set(Some(budget));
+        let fs = TestFs::new(flash);
+        fs.recording.set(true);
+        assert_eq!(scenario(&fs), Err(ErrorCode::NODEVICE));
+        assert!(!flash.powered.get());
+
+        // Either the interrupted operation happened, or it did not. If
+        // mounting was interrupted, the filesystem is empty either way.
+        let completed = fs.trees.take();
+        let before = completed.len().saturating_sub(1);
+        assert_eq!(completed[..], trees[..completed.len()]);
+
+        flash.power_on();
+        let fs = TestFs::new(flash);
+        assert_eq!(fs.mount(), Ok(()), "mount after cut {}", budget);
+        let tree = fs.tree();
+        assert!(
+            tree == trees[before] || tree == trees[completed.len()],
+            "power cut {} left {:?}",
+            budget,
+            tree
+        );
+
+        // The filesystem still works
+        let app = Perms::app(1);
+        fs.create("after", Kind::File, &app).unwrap();
+        fs.write("after", 0, &pattern(300, 4), &app).unwrap();
+        assert_eq!(fs.read("after", &app), Ok(pattern(300, 4)));
+    }
+}
@@ -0,0 +1,306 @@
+//! Provides userspace with a USB HID keyboard, to type into the host.
+//!
+//! Applications send keyboard reports, either holding down a set of keys
+//! until the next report, or tapping a single key (pressing it, then
+//! releasing all keys). Keys are usage IDs of the HID keyboard usage page,
+//! and modifiers a bitmask of Left Control (bit 0), Left Shift, Left Alt,
+//! Left GUI, Right Control, Right Shift, Right Alt and Right GUI (bit 7).
+//! The driver also reports the state of the keyboard LEDs set by the host.
+//!
+//! Reports of different applications are sent in turn; each application may
+//! have one report pending.
+//!
+//! Setup
+//! -----
+//!
+//! You need a device that provides `hil::usb_hid::UsbHid` with 8-byte
+//! reports, such as `capsules_extra::usb::hid::BootHid`.
+//!
+//! ```rust
+//! let (keyboard, keyboard_driver) = components::usb_hid::UsbHidKeyboardComponent::new(
+//!     board_kernel,
+//!     capsules_extra::hid_keyboard::DRIVER_NUM,
+//!     &nrf52840_peripherals.usbd,
+//!     1,
+//! )
+//! .finalize(components::usb_hid_keyboard_component_static!(
+//!     nrf52840::usbd::Usbd
+//! ));
+//! ```
+
+use core::cell::Cell;
+
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::usb_hid;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::HidKeyboard as usize;
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A report was read by the host
+    pub const SENT: usize = 0;
+    /// The host set the keyboard LEDs
+    pub const LEDS: usize = 1;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
+/// Number of keys in a keyboard report.
+const KEYS: usize = 6;
+
+#[derive(Copy, Clone)]
+enum Report {
+    /// Holds down the modifiers and keys until the next report.
+    Hold { modifiers: u8, keys: [u8; KEYS] },
+    /// Presses the modifiers and the key, then releases everything.
+    Tap { modifiers: u8, key: u8 },
+}
+
+#[derive(Default)]
+pub struct App {
+    pending: Option<Report>,
+}
+
+pub struct HidKeyboardDriver<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> {
+    hid: &'a U,
+
+    apps: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    /// The application whose report is being sent.
+    processid: OptionalCell<ProcessId>,
+    /// Whether an empty report follows the one being sent, to release a
+    /// tapped key.
+    release: Cell<bool>,
+
+    /// The last LED state set by the host.
+    leds: Cell<u8>,
+
+    send_buffer: TakeCell<'static, [u8; 8]>,
+    recv_buffer: TakeCell<'static, [u8; 8]>,
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> HidKeyboardDriver<'a, U> {
+    pub fn new(
+        hid: &'a U,
+        send_buffer: &'static mut [u8; 8],
+        recv_buffer: &'static mut [u8; 8],
+        grant: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    ) -> HidKeyboardDriver<'a, U> {
+        HidKeyboardDriver {
+            hid: hid,
+            apps: grant,
+            processid: OptionalCell::empty(),
+            release: Cell::new(false),
+            leds: Cell::new(0),
+            send_buffer: TakeCell::new(send_buffer),
+            recv_buffer: TakeCell::new(recv_buffer),
+        }
+    }
+
+    /// Starts listening for changes of the LEDs. Must be called once the
+    /// driver is the client of the HID device.
+    pub fn start_receive(&self) -> Result<(), ErrorCode> {
+        let buf = self.recv_buffer.take().ok_or(ErrorCode::ALREADY)?;
+        self.hid.receive_buffer(buf).map_err(|(err, buf)| {
+            self.recv_buffer.replace(buf);
+            err
+        })
+    }
+
+    /// Sends a report with `modifiers` and `keys` pressed.
+    fn send_report(&self, modifiers: u8, keys: &[u8]) -> Result<(), ErrorCode> {
+        let buf = self.send_buffer.take().ok_or(ErrorCode::BUSY)?;
+        buf.iter_mut().for_each(|b| *b = 0);
+        buf[0] = modifiers;
+        buf[2..2 + keys.len()].copy_from_slice(keys);
+        self.hid.send_buffer(buf).map(|_| ()).map_err(|(err, buf)| {
+            self.send_buffer.replace(buf);
+            err
+        })
+    }
+
+    fn start(&self, report: Report) -> Result<(), ErrorCode> {
+        match report {
+            Report::Hold { modifiers, keys } => {
+                self.release.set(false);
+                self.send_report(modifiers, &keys)
+            }
+            Report::Tap { modifiers, key } => {
+                self.release.set(true);
+                self.send_report(modifiers, &[key])
+            }
+        }
+    }
+
+    /// Notifies the application whose report was sent, then starts the next
+    /// pending report.
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        self.processid.take().map(|processid| {
+            let _ = self.apps.enter(processid, |_, kernel_data| {
+                kernel_data
+                    .schedule_upcall(upcall::SENT, (into_statuscode(result), 0, 0))
+                    .ok();
+            });
+        });
+        self.check_queue();
+    }
+
+    fn check_queue(&self) {
+        for appiter in self.apps.iter() {
+            if self.processid.is_some() {
+                break;
+            }
+            let processid = appiter.processid();
+            appiter.enter(|app, kernel_data| {
+                app.pending.take().map(|report| {
+                    match self.start(report) {
+                        Ok(()) => self.processid.set(processid),
+                        Err(err) => {
+                            kernel_data
+                                .schedule_upcall(upcall::SENT, (into_statuscode(Err(err)), 0, 0))
+                                .ok();
+                        }
+                    };
+                });
+            });
+        }
+    }
+
+    /// Sends the report now if the keyboard is free, or queues it.
+    fn enqueue(&self, processid: ProcessId, report: Report) -> CommandReturn {
+        if self.processid.contains(&processid) {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+        self.apps
+            .enter(processid, |app, _| {
+                if app.pending.is_some() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                if self.processid.is_some() {
+                    app.pending = Some(report);
+                    return CommandReturn::success();
+                }
+                match self.start(report) {
+                    Ok(()) => {
+                        self.processid.set(processid);
+                        CommandReturn::success()
+                    }
+                    Err(err) => CommandReturn::failure(err),
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> usb_hid::Client<'a, [u8; 8]>
+    for HidKeyboardDriver<'a, U>
+{
+    fn packet_received(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 8],
+        _endpoint: usize,
+    ) {
+        if result.is_ok() {
+            let leds = buffer[0];
+            self.leds.set(leds);
+            self.apps.each(|_, _, kernel_data| {
+                kernel_data
+                    .schedule_upcall(upcall::LEDS, (leds as usize, 0, 0))
+                    .ok();
+            });
+        }
+        self.recv_buffer.replace(buffer);
+        let _ = self.start_receive();
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 8],
+        _endpoint: usize,
+    ) {
+        self.send_buffer.replace(buffer);
+        if result.is_ok() && self.release.take() {
+            if let Err(err) = self.send_report(0, &[]) {
+                self.finish(Err(err));
+            }
+        } else {
+            self.finish(result);
+        }
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.recv_buffer.is_some()
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> SyscallDriver for HidKeyboardDriver<'a, U> {
+    /// Control the keyboard.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver existence check.
+    /// - `1`: Hold down keys until the next report. `data1` holds the
+    ///        modifiers in its lowest byte, followed by up to three keys,
+    ///        and `data2` up to three more keys in its lowest bytes. Unused
+    ///        keys are 0.
+    /// - `2`: Tap the key `data1` with the modifiers `data2`, then release
+    ///        all keys.
+    /// - `3`: Return the state of the LEDs set by the host.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => {
+                let keys = [
+                    (data1 >> 8) as u8,
+                    (data1 >> 16) as u8,
+                    (data1 >> 24) as u8,
+                    data2 as u8,
+                    (data2 >> 8) as u8,
+                    (data2 >> 16) as u8,
+                ];
+                self.enqueue(
+                    processid,
+                    Report::Hold {
+                        modifiers: data1 as u8,
+                        keys: keys,
+                    },
+                )
+            }
+
+            2 => {
+                if data1 > u8::MAX as usize || data2 > u8::MAX as usize {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.enqueue(
+                    processid,
+                    Report::Tap {
+                        modifiers: data2 as u8,
+                        key: data1 as u8,
+                    },
+                )
+            }
+
+            3 => CommandReturn::success_u32(self.leds.get() as u32),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,207 @@
+//! Provides userspace with a USB HID mouse, to move the pointer of the host.
+//!
+//! Applications send mouse reports, each with the state of the buttons and
+//! a relative movement of the pointer and of the wheel. The buttons stay in
+//! the same state until the next report. Reports of different applications
+//! are sent in turn; each application may have one report pending.
+//!
+//! Setup
+//! -----
+//!
+//! You need a device that provides `hil::usb_hid::UsbHid` with 8-byte
+//! reports, such as `capsules_extra::usb::hid::BootHid`.
+//!
+//! ```rust
+//! let (mouse, mouse_driver) = components::usb_hid::UsbHidMouseComponent::new(
+//!     board_kernel,
+//!     capsules_extra::hid_mouse::DRIVER_NUM,
+//!     &nrf52840_peripherals.usbd,
+//!     2,
+//! )
+//! .finalize(components::usb_hid_mouse_component_static!(
+//!     nrf52840::usbd::Usbd
+//! ));
+//! ```
+
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::usb_hid;
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::HidMouse as usize;
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A report was read by the host
+    pub const SENT: usize = 0;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// A mouse report: buttons, x, y and wheel.
+type Report = [u8; 4];
+
+#[derive(Default)]
+pub struct App {
+    pending: Option<Report>,
+}
+
+pub struct HidMouseDriver<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> {
+    hid: &'a U,
+
+    apps: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    /// The application whose report is being sent.
+    processid: OptionalCell<ProcessId>,
+
+    send_buffer: TakeCell<'static, [u8; 8]>,
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> HidMouseDriver<'a, U> {
+    pub fn new(
+        hid: &'a U,
+        send_buffer: &'static mut [u8; 8],
+        grant: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    ) -> HidMouseDriver<'a, U> {
+        HidMouseDriver {
+            hid: hid,
+            apps: grant,
+            processid: OptionalCell::empty(),
+            send_buffer: TakeCell::new(send_buffer),
+        }
+    }
+
+    fn send_report(&self, report: Report) -> Result<(), ErrorCode> {
+        let buf = self.send_buffer.take().ok_or(ErrorCode::BUSY)?;
+        buf.iter_mut().for_each(|b| *b = 0);
+        buf[..report.len()].copy_from_slice(&report);
+        self.hid.send_buffer(buf).map(|_| ()).map_err(|(err, buf)| {
+            self.send_buffer.replace(buf);
+            err
+        })
+    }
+
+    /// Notifies the application whose report was sent, then starts the next
+    /// pending report.
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        self.processid.take().map(|processid| {
+            let _ = self.apps.enter(processid, |_, kernel_data| {
+                kernel_data
+                    .schedule_upcall(upcall::SENT, (into_statuscode(result), 0, 0))
+                    .ok();
+            });
+        });
+        self.check_queue();
+    }
+
+    fn check_queue(&self) {
+        for appiter in self.apps.iter() {
+            if self.processid.is_some() {
+                break;
+            }
+            let processid = appiter.processid();
+            appiter.enter(|app, kernel_data| {
+                app.pending.take().map(|report| {
+                    match self.send_report(report) {
+                        Ok(()) => self.processid.set(processid),
+                        Err(err) => {
+                            kernel_data
+                                .schedule_upcall(upcall::SENT, (into_statuscode(Err(err)), 0, 0))
+                                .ok();
+                        }
+                    };
+                });
+            });
+        }
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> usb_hid::Client<'a, [u8; 8]> for HidMouseDriver<'a, U> {
+    fn packet_received(
+        &'a self,
+        _result: Result<(), ErrorCode>,
+        _buffer: &'static mut [u8; 8],
+        _endpoint: usize,
+    ) {
+        // Mice have no output reports, so no receive buffer is ever given
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 8],
+        _endpoint: usize,
+    ) {
+        self.send_buffer.replace(buffer);
+        self.finish(result);
+    }
+
+    fn can_receive(&'a self) -> bool {
+        false
+    }
+}
+
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 8]>> SyscallDriver for HidMouseDriver<'a, U> {
+    /// Control the mouse.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver existence check.
+    /// - `1`: Send a report. `data1` holds the signed movements along x, y
+    ///        and of the wheel in its three lowest bytes, and `data2` the
+    ///        buttons, with the left button in bit 0, the right button in
+    ///        bit 1 and the middle button in bit 2.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => {
+                if data2 > 0b111 {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                let report = [
+                    data2 as u8,
+                    data1 as u8,
+                    (data1 >> 8) as u8,
+                    (data1 >> 16) as u8,
+                ];
+                if self.processid.contains(&processid) {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                self.apps
+                    .enter(processid, |app, _| {
+                        if app.pending.is_some() {
+                            return CommandReturn::failure(ErrorCode::BUSY);
+                        }
+                        if self.processid.is_some() {
+                            app.pending = Some(report);
+                            return CommandReturn::success();
+                        }
+                        match self.send_report(report) {
+                            Ok(()) => {
+                                self.processid.set(processid);
+                                CommandReturn::success()
+                            }
+                            Err(err) => CommandReturn::failure(err),
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
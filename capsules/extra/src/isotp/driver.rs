@@ -0,0 +1,314 @@
+//! Provides userspace with ISO-TP messages over CAN, for diagnostic protocols
+//! such as UDS.
+//!
+//! Each application opens one channel, given the identifier it sends with
+//! and the identifier it receives with. It sends a message from its
+//! read-only buffer, and receives messages into its read-write buffer: a
+//! message longer than that buffer is refused, which tells the sender it
+//! overflows. Up to `MAX_CHANNELS` applications use the bus at once.
+//!
+//! Setup
+//! -----
+//!
+//! You need a CAN controller that provides `hil::can::Can`, and an alarm.
+//!
+//! ```rust
+//! let isotp = components::isotp::IsoTpComponent::new(
+//!     board_kernel,
+//!     capsules_extra::isotp::driver::DRIVER_NUM,
+//!     &peripherals.can1,
+//!     mux_alarm,
+//!     500_000,
+//! )
+//! .finalize(components::isotp_component_static!(
+//!     stm32f429zi::can::Can<'static>,
+//!     stm32f429zi::tim2::Tim2
+//! ));
+//! ```
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::can::{self, STANDARD_CAN_PACKET_SIZE};
+use kernel::hil::time::Alarm;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+
+use super::frame::MAX_MESSAGE_LEN;
+use super::transport::{IsoTp, IsoTpClient, MAX_CHANNELS};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::IsoTp as usize;
+
+/// Marks an identifier as extended (29 bits) rather than standard (11 bits).
+pub const EXTENDED_ID: usize = 1 << 31;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// The message to send
+    pub const SEND: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Receives the messages
+    pub const RECEIVE: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A message was sent, or failed
+    pub const SENT: usize = 0;
+    /// A message was received, or failed
+    pub const RECEIVED: usize = 1;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
+#[derive(Default)]
+pub struct App {
+    channel: Option<usize>,
+}
+
+pub struct IsoTpDriver<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    isotp: &'a IsoTp<'a, C, A>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The application of each channel.
+    owners: [OptionalCell<ProcessId>; MAX_CHANNELS],
+}
+
+impl<'a, C, A> IsoTpDriver<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    pub fn new(
+        isotp: &'a IsoTp<'a, C, A>,
+        apps: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> IsoTpDriver<'a, C, A> {
+        IsoTpDriver {
+            isotp,
+            apps,
+            owners: Default::default(),
+        }
+    }
+
+    // Closes the channels of applications that no longer exist.
+    fn reclaim_channels(&self) {
+        for (channel, owner) in self.owners.iter().enumerate() {
+            let gone = owner.map_or(false, |processid| {
+                self.apps.enter(*processid, |_, _| ()).is_err()
+            });
+            if gone {
+                self.isotp.close(channel);
+                owner.clear();
+            }
+        }
+    }
+
+    fn open(&self, processid: ProcessId, tx_id: usize, rx_id: usize) -> Result<(), ErrorCode> {
+        let tx_id = decode_id(tx_id)?;
+        let rx_id = decode_id(rx_id)?;
+        if self.apps.enter(processid, |app, _| app.channel.is_some())? {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.reclaim_channels();
+        let channel = self.isotp.open(tx_id, rx_id)?;
+        self.owners[channel].set(processid);
+        self.apps
+            .enter(processid, |app, _| app.channel = Some(channel))?;
+        Ok(())
+    }
+
+    fn close(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        let channel = self
+            .apps
+            .enter(processid, |app, _| app.channel.take())?
+            .ok_or(ErrorCode::INVAL)?;
+        self.isotp.close(channel);
+        self.owners[channel].clear();
+        Ok(())
+    }
+
+    fn send(&self, processid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        let channel = self.apps.enter(processid, |app, kernel_data| {
+            let available = kernel_data
+                .get_readonly_processbuffer(ro_allow::SEND)
+                .map_or(0, |buffer| buffer.len());
+            if len > available || len > MAX_MESSAGE_LEN {
+                return Err(ErrorCode::SIZE);
+            }
+            app.channel.ok_or(ErrorCode::INVAL)
+        })??;
+        self.isotp.send(channel, len)
+    }
+
+    // Runs `f` in the grant of the application of `channel`.
+    fn with_owner<R>(
+        &self,
+        channel: usize,
+        f: impl FnOnce(&kernel::grant::GrantKernelData) -> R,
+    ) -> Option<R> {
+        self.owners.get(channel)?.map_or(None, |processid| {
+            self.apps
+                .enter(*processid, |_, kernel_data| f(kernel_data))
+                .ok()
+        })
+    }
+}
+
+// Decodes an identifier from userspace, extended if `EXTENDED_ID` is set.
+fn decode_id(id: usize) -> Result<can::Id, ErrorCode> {
+    if id & EXTENDED_ID != 0 {
+        let id = id & !EXTENDED_ID;
+        if id < 1 << 29 {
+            return Ok(can::Id::Extended(id as u32));
+        }
+    } else if id < 1 << 11 {
+        return Ok(can::Id::Standard(id as u16));
+    }
+    Err(ErrorCode::INVAL)
+}
+
+impl<'a, C, A> IsoTpClient for IsoTpDriver<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    fn fill(&self, channel: usize, offset: usize, dest: &mut [u8]) {
+        self.with_owner(channel, |kernel_data| {
+            let _ = kernel_data
+                .get_readonly_processbuffer(ro_allow::SEND)
+                .and_then(|buffer| {
+                    buffer.enter(|message| {
+                        if let Some(src) = message.get(offset..offset + dest.len()) {
+                            src.copy_to_slice(dest);
+                        }
+                    })
+                });
+        });
+    }
+
+    fn send_done(&self, channel: usize, result: Result<(), ErrorCode>) {
+        self.with_owner(channel, |kernel_data| {
+            let _ = kernel_data.schedule_upcall(
+                upcall::SENT,
+                (kernel::errorcode::into_statuscode(result), 0, 0),
+            );
+        });
+    }
+
+    fn receive_start(&self, channel: usize, len: usize) -> Result<(), ErrorCode> {
+        self.with_owner(channel, |kernel_data| {
+            let available = kernel_data
+                .get_readwrite_processbuffer(rw_allow::RECEIVE)
+                .map_or(0, |buffer| buffer.len());
+            if len <= available {
+                Ok(())
+            } else {
+                Err(ErrorCode::SIZE)
+            }
+        })
+        .unwrap_or(Err(ErrorCode::FAIL))
+    }
+
+    fn receive_data(&self, channel: usize, offset: usize, data: &[u8]) {
+        self.with_owner(channel, |kernel_data| {
+            let _ = kernel_data
+                .get_readwrite_processbuffer(rw_allow::RECEIVE)
+                .and_then(|buffer| {
+                    buffer.mut_enter(|message| {
+                        if let Some(dest) = message.get(offset..offset + data.len()) {
+                            dest.copy_from_slice(data);
+                        }
+                    })
+                });
+        });
+    }
+
+    fn receive_done(&self, channel: usize, result: Result<usize, ErrorCode>) {
+        self.with_owner(channel, |kernel_data| {
+            let (status, len) = match result {
+                Ok(len) => (kernel::errorcode::into_statuscode(Ok(())), len),
+                Err(err) => (kernel::errorcode::into_statuscode(Err(err)), 0),
+            };
+            let _ = kernel_data.schedule_upcall(upcall::RECEIVED, (status, len, 0));
+        });
+    }
+}
+
+impl<'a, C, A> SyscallDriver for IsoTpDriver<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver existence check.
+    /// - `1`: Open a channel sending with the identifier `data1` and
+    ///        receiving with the identifier `data2`. Identifiers with
+    ///        `EXTENDED_ID` set are extended.
+    /// - `2`: Set the block size (`data1`) and separation time (`data2`)
+    ///        the senders of the messages received must follow.
+    /// - `3`: Send the first `data1` bytes of the read-only buffer.
+    /// - `4`: Close the channel.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => self.open(processid, data1, data2).into(),
+
+            2 => {
+                if data1 > u8::MAX as usize || data2 > u8::MAX as usize {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.apps
+                    .enter(processid, |app, _| app.channel)
+                    .map_err(ErrorCode::from)
+                    .and_then(|channel| {
+                        let channel = channel.ok_or(ErrorCode::INVAL)?;
+                        self.isotp
+                            .set_flow_control(channel, data1 as u8, data2 as u8)
+                    })
+                    .into()
+            }
+
+            3 => self.send(processid, data1).into(),
+
+            4 => self.close(processid).into(),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
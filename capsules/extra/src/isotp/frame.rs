@@ -0,0 +1,156 @@
+//! ISO-TP frames (ISO 15765-2), with normal addressing on classic CAN.
+//!
+//! The first nibble of each frame, its protocol control information, gives
+//! its type:
+//!
+//! - a single frame holds a whole message of up to 7 bytes, after its length
+//!   in the low nibble;
+//! - a first frame starts a longer message, with its 12-bit length and the
+//!   first 6 bytes;
+//! - consecutive frames hold 7 more bytes each, numbered modulo 16;
+//! - flow control frames, from the receiver, let the sender go on with a
+//!   number of consecutive frames (the block size) and the minimum time to
+//!   wait between them (the separation time).
+
+use kernel::hil::can::STANDARD_CAN_PACKET_SIZE;
+
+/// Largest message, which fits the 12-bit length of a first frame.
+pub const MAX_MESSAGE_LEN: usize = 4095;
+
+/// Bytes of a message in a single frame.
+pub const SINGLE_FRAME_DATA_LEN: usize = STANDARD_CAN_PACKET_SIZE - 1;
+/// Bytes of a message in a first frame.
+pub const FIRST_FRAME_DATA_LEN: usize = STANDARD_CAN_PACKET_SIZE - 2;
+/// Bytes of a message in a consecutive frame.
+pub const CONSECUTIVE_FRAME_DATA_LEN: usize = STANDARD_CAN_PACKET_SIZE - 1;
+
+/// Value of the unused bytes of frames, which are always sent whole.
+pub const PADDING: u8 = 0xcc;
+
+const SINGLE_FRAME: u8 = 0x0;
+const FIRST_FRAME: u8 = 0x1;
+const CONSECUTIVE_FRAME: u8 = 0x2;
+const FLOW_CONTROL: u8 = 0x3;
+
+/// What a receiver tells the sender in a flow control frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlowStatus {
+    /// Send the next block.
+    ContinueToSend = 0,
+    /// Wait for another flow control frame.
+    Wait = 1,
+    /// The message is too long for the receiver, give up.
+    Overflow = 2,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Single {
+        data: &'a [u8],
+    },
+    First {
+        len: usize,
+        data: &'a [u8],
+    },
+    Consecutive {
+        sequence_number: u8,
+        data: &'a [u8],
+    },
+    FlowControl {
+        status: FlowStatus,
+        block_size: u8,
+        separation_time: u8,
+    },
+}
+
+impl<'a> Frame<'a> {
+    /// Parses a received frame, or returns `None` if it is malformed.
+    pub fn parse(frame: &'a [u8]) -> Option<Frame<'a>> {
+        let pci = *frame.first()?;
+        match pci >> 4 {
+            SINGLE_FRAME => {
+                let len = (pci & 0xf) as usize;
+                if len == 0 || len > SINGLE_FRAME_DATA_LEN {
+                    return None;
+                }
+                frame.get(1..1 + len).map(|data| Frame::Single { data })
+            }
+            FIRST_FRAME => {
+                let len = ((pci as usize & 0xf) << 8) | *frame.get(1)? as usize;
+                // Shorter messages go in a single frame
+                if len <= SINGLE_FRAME_DATA_LEN || frame.len() < STANDARD_CAN_PACKET_SIZE {
+                    return None;
+                }
+                Some(Frame::First {
+                    len,
+                    data: &frame[2..],
+                })
+            }
+            CONSECUTIVE_FRAME => Some(Frame::Consecutive {
+                sequence_number: pci & 0xf,
+                data: &frame[1..],
+            }),
+            FLOW_CONTROL => {
+                let status = match pci & 0xf {
+                    0 => FlowStatus::ContinueToSend,
+                    1 => FlowStatus::Wait,
+                    2 => FlowStatus::Overflow,
+                    _ => return None,
+                };
+                Some(Frame::FlowControl {
+                    status,
+                    block_size: *frame.get(1)?,
+                    separation_time: *frame.get(2)?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Writes the protocol control information of a single frame for a message
+/// of `len` bytes, and returns its length.
+pub fn write_single_frame(frame: &mut [u8], len: usize) -> usize {
+    frame[0] = (SINGLE_FRAME << 4) | len as u8;
+    1
+}
+
+/// Writes the protocol control information of a first frame for a message of
+/// `len` bytes, and returns its length.
+pub fn write_first_frame(frame: &mut [u8], len: usize) -> usize {
+    frame[0] = (FIRST_FRAME << 4) | (len >> 8) as u8;
+    frame[1] = len as u8;
+    2
+}
+
+/// Writes the protocol control information of a consecutive frame, and
+/// returns its length.
+pub fn write_consecutive_frame(frame: &mut [u8], sequence_number: u8) -> usize {
+    frame[0] = (CONSECUTIVE_FRAME << 4) | (sequence_number & 0xf);
+    1
+}
+
+/// Writes a whole flow control frame, and returns its length.
+pub fn write_flow_control(
+    frame: &mut [u8],
+    status: FlowStatus,
+    block_size: u8,
+    separation_time: u8,
+) -> usize {
+    frame[0] = (FLOW_CONTROL << 4) | status as u8;
+    frame[1] = block_size;
+    frame[2] = separation_time;
+    3
+}
+
+/// The separation time encoded as `raw`, in microseconds.
+///
+/// Values of 0 to 127 are milliseconds, and 0xf1 to 0xf9 are 100 to 900
+/// microseconds. Reserved values stand for the longest time, 127 ms.
+pub fn separation_time_us(raw: u8) -> u32 {
+    match raw {
+        0x00..=0x7f => raw as u32 * 1000,
+        0xf1..=0xf9 => (raw - 0xf0) as u32 * 100,
+        _ => 127_000,
+    }
+}
@@ -0,0 +1,10 @@
+//! ISO-TP (ISO 15765-2) over CAN: messages of up to 4095 bytes, segmented
+//! into frames with flow control, on several addressed channels at once, and
+//! a syscall driver through which applications exchange them.
+
+pub mod driver;
+pub mod frame;
+pub mod transport;
+
+#[cfg(test)]
+mod tests;
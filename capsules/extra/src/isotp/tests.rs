@@ -0,0 +1,513 @@
+//! Tests of the ISO-TP transport over a simulated CAN controller, with the
+//! test playing the other end of each channel.
+
+use core::cell::{Cell, RefCell};
+use std::boxed::Box;
+use std::vec::Vec;
+
+use kernel::hil::can::{
+    Id, Receive, ReceiveClient, Transmit, TransmitClient, STANDARD_CAN_PACKET_SIZE,
+};
+use kernel::hil::time::{Alarm, Freq1MHz};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::frame::{self, FlowStatus, Frame};
+use super::transport::{IsoTp, IsoTpClient, MAX_CHANNELS};
+use crate::testing::alarm::MockAlarm;
+
+const TESTER: Id = Id::Standard(0x7e0);
+const ECU: Id = Id::Standard(0x7e8);
+
+type Packet = [u8; STANDARD_CAN_PACKET_SIZE];
+
+/// A CAN controller that records the frames sent, which the test completes,
+/// and receives the frames the test delivers.
+struct SimCan {
+    tx_client: OptionalCell<&'static dyn TransmitClient<STANDARD_CAN_PACKET_SIZE>>,
+    rx_client: OptionalCell<&'static dyn ReceiveClient<STANDARD_CAN_PACKET_SIZE>>,
+    sending: TakeCell<'static, Packet>,
+    receiving: TakeCell<'static, Packet>,
+    sent: RefCell<Vec<(Id, Vec<u8>)>>,
+}
+
+impl SimCan {
+    fn new() -> SimCan {
+        SimCan {
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            sending: TakeCell::empty(),
+            receiving: TakeCell::empty(),
+            sent: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn is_sending(&self) -> bool {
+        self.sending.is_some()
+    }
+
+    /// Ends the transmission in progress, and returns the frame sent.
+    fn complete(&self) -> (Id, Vec<u8>) {
+        let buffer = self.sending.take().expect("no frame on the bus");
+        let frame = self.sent.borrow().last().unwrap().clone();
+        self.tx_client
+            .map(|client| client.transmit_complete(Ok(()), buffer));
+        frame
+    }
+
+    fn deliver(&self, id: Id, frame: &[u8]) {
+        let mut packet = [0; STANDARD_CAN_PACKET_SIZE];
+        packet[..frame.len()].copy_from_slice(frame);
+        assert!(self.receiving.is_some(), "not receiving");
+        self.rx_client
+            .map(|client| client.message_received(id, &mut packet, frame.len(), Ok(())));
+    }
+}
+
+impl Transmit<STANDARD_CAN_PACKET_SIZE> for SimCan {
+    fn set_client(&self, client: Option<&'static dyn TransmitClient<STANDARD_CAN_PACKET_SIZE>>) {
+        self.tx_client.insert(client);
+    }
+
+    fn send(
+        &self,
+        id: Id,
+        buffer: &'static mut Packet,
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut Packet)> {
+        if self.sending.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        self.sent.borrow_mut().push((id, buffer[..len].to_vec()));
+        self.sending.replace(buffer);
+        Ok(())
+    }
+}
+
+impl Receive<STANDARD_CAN_PACKET_SIZE> for SimCan {
+    fn set_client(&self, client: Option<&'static dyn ReceiveClient<STANDARD_CAN_PACKET_SIZE>>) {
+        self.rx_client.insert(client);
+    }
+
+    fn start_receive_process(
+        &self,
+        buffer: &'static mut Packet,
+    ) -> Result<(), (ErrorCode, &'static mut Packet)> {
+        self.receiving.replace(buffer);
+        Ok(())
+    }
+
+    fn stop_receive(&self) -> Result<(), ErrorCode> {
+        let buffer = self.receiving.take().ok_or(ErrorCode::OFF)?;
+        self.rx_client.map(|client| client.stopped(buffer));
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Sent(usize, Result<(), ErrorCode>),
+    Received(usize, Result<Vec<u8>, ErrorCode>),
+}
+
+/// Sends the same message on every channel, and records what arrives.
+struct Recorder {
+    message: RefCell<Vec<u8>>,
+    /// The longest message accepted.
+    limit: Cell<usize>,
+    received: RefCell<Vec<Vec<u8>>>,
+    events: RefCell<Vec<Event>>,
+}
+
+impl Recorder {
+    fn take_events(&self) -> Vec<Event> {
+        self.events.replace(Vec::new())
+    }
+}
+
+impl IsoTpClient for Recorder {
+    fn fill(&self, _channel: usize, offset: usize, dest: &mut [u8]) {
+        dest.copy_from_slice(&self.message.borrow()[offset..offset + dest.len()]);
+    }
+
+    fn send_done(&self, channel: usize, result: Result<(), ErrorCode>) {
+        self.events.borrow_mut().push(Event::Sent(channel, result));
+    }
+
+    fn receive_start(&self, channel: usize, len: usize) -> Result<(), ErrorCode> {
+        if len > self.limit.get() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.received.borrow_mut()[channel] = std::vec![0; len];
+        Ok(())
+    }
+
+    fn receive_data(&self, channel: usize, offset: usize, data: &[u8]) {
+        self.received.borrow_mut()[channel][offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn receive_done(&self, channel: usize, result: Result<usize, ErrorCode>) {
+        let result = result.map(|len| {
+            assert_eq!(len, self.received.borrow()[channel].len());
+            self.received.borrow()[channel].clone()
+        });
+        self.events
+            .borrow_mut()
+            .push(Event::Received(channel, result));
+    }
+}
+
+struct Setup {
+    can: &'static SimCan,
+    alarm: &'static MockAlarm<'static, Freq1MHz>,
+    isotp: &'static IsoTp<'static, SimCan, MockAlarm<'static, Freq1MHz>>,
+    recorder: &'static Recorder,
+}
+
+fn setup() -> Setup {
+    let can = Box::leak(Box::new(SimCan::new()));
+    let alarm = Box::leak(Box::new(MockAlarm::new()));
+    let isotp = Box::leak(Box::new(IsoTp::new(
+        can,
+        alarm,
+        Box::leak(Box::new([0; STANDARD_CAN_PACKET_SIZE])),
+        Box::leak(Box::new([0; STANDARD_CAN_PACKET_SIZE])),
+    )));
+    let recorder = Box::leak(Box::new(Recorder {
+        message: RefCell::new(Vec::new()),
+        limit: Cell::new(frame::MAX_MESSAGE_LEN),
+        received: RefCell::new(std::vec![Vec::new(); MAX_CHANNELS]),
+        events: RefCell::new(Vec::new()),
+    }));
+    Transmit::set_client(can, Some(isotp));
+    Receive::set_client(can, Some(isotp));
+    alarm.set_alarm_client(isotp);
+    isotp.set_client(recorder);
+    isotp.start().unwrap();
+    Setup {
+        can,
+        alarm,
+        isotp,
+        recorder,
+    }
+}
+
+fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+fn padded(frame: &[u8]) -> Vec<u8> {
+    let mut padded = frame.to_vec();
+    padded.resize(STANDARD_CAN_PACKET_SIZE, frame::PADDING);
+    padded
+}
+
+#[test]
+fn sends_and_receives_single_frames() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+    setup.recorder.message.replace(std::vec![0x22, 0xf1, 0x90]);
+
+    setup.isotp.send(channel, 3).unwrap();
+    assert_eq!(setup.isotp.send(channel, 3), Err(ErrorCode::BUSY));
+    assert_eq!(
+        setup.can.complete(),
+        (ECU, padded(&[0x03, 0x22, 0xf1, 0x90]))
+    );
+    assert_eq!(setup.recorder.take_events(), [Event::Sent(channel, Ok(()))]);
+
+    setup.can.deliver(TESTER, &[0x02, 0x10, 0x03, 0xcc, 0xcc]);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Received(channel, Ok(std::vec![0x10, 0x03]))]
+    );
+    // Frames for other identifiers are ignored
+    setup.can.deliver(Id::Standard(0x7df), &[0x02, 0x10, 0x03]);
+    assert!(setup.recorder.take_events().is_empty());
+    assert!(!setup.can.is_sending());
+}
+
+#[test]
+fn sends_blocks_as_flow_control_allows() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+    setup.recorder.message.replace(message(30));
+    let data = message(30);
+
+    setup.isotp.send(channel, 30).unwrap();
+    let (_, first) = setup.can.complete();
+    assert_eq!(
+        Frame::parse(&first),
+        Some(Frame::First {
+            len: 30,
+            data: &data[..6]
+        })
+    );
+    assert!(!setup.can.is_sending());
+
+    // Two frames, then wait for flow control again
+    setup.can.deliver(TESTER, &[0x30, 2, 0]);
+    assert_eq!(
+        setup.can.complete().1,
+        padded(&[&[0x21][..], &data[6..13]].concat())
+    );
+    assert_eq!(
+        setup.can.complete().1,
+        padded(&[&[0x22][..], &data[13..20]].concat())
+    );
+    assert!(!setup.can.is_sending());
+
+    setup.can.deliver(TESTER, &[0x30, 0, 0]);
+    assert_eq!(
+        setup.can.complete().1,
+        padded(&[&[0x23][..], &data[20..27]].concat())
+    );
+    assert_eq!(
+        setup.can.complete().1,
+        padded(&[&[0x24][..], &data[27..30]].concat())
+    );
+    assert_eq!(setup.recorder.take_events(), [Event::Sent(channel, Ok(()))]);
+}
+
+#[test]
+fn waits_for_the_separation_time() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+    setup.recorder.message.replace(message(20));
+
+    setup.isotp.send(channel, 20).unwrap();
+    setup.can.complete();
+    // 0xf3 is 300 microseconds
+    setup.can.deliver(TESTER, &[0x30, 0, 0xf3]);
+    setup.can.complete();
+    assert!(!setup.can.is_sending());
+    assert_eq!(setup.alarm.remaining(), Some(300));
+    setup.alarm.advance(299);
+    assert!(!setup.can.is_sending());
+    setup.alarm.advance(1);
+    assert_eq!(setup.can.complete().1[0], 0x22);
+    assert_eq!(setup.recorder.take_events(), [Event::Sent(channel, Ok(()))]);
+}
+
+#[test]
+fn reports_refusals_and_timeouts_of_the_receiver() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+    setup.recorder.message.replace(message(100));
+
+    setup.isotp.send(channel, 100).unwrap();
+    setup.can.complete();
+    setup.can.deliver(TESTER, &[0x32, 0, 0]);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Sent(channel, Err(ErrorCode::SIZE))]
+    );
+
+    setup.isotp.send(channel, 100).unwrap();
+    setup.can.complete();
+    // Waiting extends the timeout
+    setup.alarm.advance(900_000);
+    setup.can.deliver(TESTER, &[0x31, 0, 0]);
+    setup.alarm.advance(900_000);
+    assert!(setup.recorder.take_events().is_empty());
+    setup.alarm.advance(100_000);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Sent(channel, Err(ErrorCode::NOACK))]
+    );
+    assert_eq!(setup.alarm.remaining(), None);
+}
+
+#[test]
+fn receives_in_blocks() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+    setup.isotp.set_flow_control(channel, 2, 0x05).unwrap();
+    let data = message(27);
+
+    setup
+        .can
+        .deliver(TESTER, &[&[0x10, 27][..], &data[..6]].concat());
+    assert_eq!(setup.can.complete(), (ECU, padded(&[0x30, 2, 0x05])));
+    setup
+        .can
+        .deliver(TESTER, &[&[0x21][..], &data[6..13]].concat());
+    assert!(!setup.can.is_sending());
+    setup
+        .can
+        .deliver(TESTER, &[&[0x22][..], &data[13..20]].concat());
+    assert_eq!(setup.can.complete().1, padded(&[0x30, 2, 0x05]));
+    setup
+        .can
+        .deliver(TESTER, &[&[0x23][..], &data[20..27]].concat());
+    assert!(!setup.can.is_sending());
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Received(channel, Ok(data))]
+    );
+}
+
+#[test]
+fn refuses_messages_too_long_for_the_client() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+    setup.recorder.limit.set(16);
+
+    setup.can.deliver(TESTER, &[0x10, 17, 1, 2, 3, 4, 5, 6]);
+    assert_eq!(setup.can.complete().1, padded(&[0x32, 0, 0]));
+    setup.can.deliver(TESTER, &[0x21, 7, 8, 9, 10, 11, 12, 13]);
+    assert!(setup.recorder.take_events().is_empty());
+
+    setup.can.deliver(TESTER, &[0x01, 0x3e]);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Received(channel, Ok(std::vec![0x3e]))]
+    );
+}
+
+#[test]
+fn abandons_messages_with_lost_frames() {
+    let setup = setup();
+    let channel = setup.isotp.open(ECU, TESTER).unwrap();
+
+    setup.can.deliver(TESTER, &[0x10, 20, 1, 2, 3, 4, 5, 6]);
+    setup.can.complete();
+    setup.can.deliver(TESTER, &[0x22, 7, 8, 9, 10, 11, 12, 13]);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Received(channel, Err(ErrorCode::FAIL))]
+    );
+
+    // A new message replaces the one being received
+    setup.can.deliver(TESTER, &[0x10, 20, 1, 2, 3, 4, 5, 6]);
+    setup.can.complete();
+    setup.can.deliver(TESTER, &[0x01, 0x3e]);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [
+            Event::Received(channel, Err(ErrorCode::CANCEL)),
+            Event::Received(channel, Ok(std::vec![0x3e]))
+        ]
+    );
+
+    // And a sender that stops times out
+    setup.can.deliver(TESTER, &[0x10, 20, 1, 2, 3, 4, 5, 6]);
+    setup.can.complete();
+    setup.alarm.advance(1_000_000);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Received(channel, Err(ErrorCode::NOACK))]
+    );
+}
+
+#[test]
+fn channels_take_turns_on_the_bus() {
+    let setup = setup();
+    let first = setup
+        .isotp
+        .open(Id::Standard(0x7e8), Id::Standard(0x7e0))
+        .unwrap();
+    let second = setup
+        .isotp
+        .open(Id::Extended(0x18daf110), Id::Extended(0x18da10f1))
+        .unwrap();
+    assert_eq!(
+        setup.isotp.open(Id::Standard(0x7e9), Id::Standard(0x7e0)),
+        Err(ErrorCode::INVAL)
+    );
+    setup.recorder.message.replace(message(20));
+
+    setup.isotp.send(first, 20).unwrap();
+    setup.isotp.send(second, 20).unwrap();
+    assert_eq!(setup.can.complete().0, Id::Standard(0x7e8));
+    assert_eq!(setup.can.complete().0, Id::Extended(0x18daf110));
+    setup.can.deliver(Id::Standard(0x7e0), &[0x30, 0, 0]);
+    setup.can.deliver(Id::Extended(0x18da10f1), &[0x30, 0, 0]);
+    let ids: Vec<Id> = (0..4).map(|_| setup.can.complete().0).collect();
+    assert_eq!(
+        ids,
+        [
+            Id::Standard(0x7e8),
+            Id::Extended(0x18daf110),
+            Id::Standard(0x7e8),
+            Id::Extended(0x18daf110)
+        ]
+    );
+    assert_eq!(
+        setup.recorder.take_events(),
+        [Event::Sent(first, Ok(())), Event::Sent(second, Ok(()))]
+    );
+
+    setup.isotp.close(first);
+    setup.can.deliver(Id::Standard(0x7e0), &[0x01, 0x3e]);
+    assert!(setup.recorder.take_events().is_empty());
+    assert_eq!(setup.isotp.open(ECU, TESTER), Ok(first));
+}
+
+#[test]
+fn carries_the_longest_message_between_two_channels() {
+    let setup = setup();
+    // Each channel receives what the other sends
+    let sender = setup.isotp.open(ECU, TESTER).unwrap();
+    let receiver = setup.isotp.open(TESTER, ECU).unwrap();
+    setup.isotp.set_flow_control(receiver, 8, 0xf1).unwrap();
+    setup
+        .recorder
+        .message
+        .replace(message(frame::MAX_MESSAGE_LEN));
+    assert_eq!(
+        setup.isotp.send(sender, frame::MAX_MESSAGE_LEN + 1),
+        Err(ErrorCode::SIZE)
+    );
+
+    setup.isotp.send(sender, frame::MAX_MESSAGE_LEN).unwrap();
+    let mut frames = 0;
+    while setup.recorder.events.borrow().len() < 2 {
+        if setup.can.is_sending() {
+            let (id, frame) = setup.can.complete();
+            setup.can.deliver(id, &frame);
+            frames += 1;
+        } else {
+            assert!(setup.alarm.fire(), "stalled");
+        }
+    }
+    // A first frame, 585 consecutive frames and flow control every 8 of them
+    assert_eq!(frames, 1 + 585 + 74);
+    assert_eq!(
+        setup.recorder.take_events(),
+        [
+            Event::Sent(sender, Ok(())),
+            Event::Received(receiver, Ok(message(frame::MAX_MESSAGE_LEN)))
+        ]
+    );
+}
+
+#[test]
+fn parses_frames() {
+    assert_eq!(Frame::parse(&[0x00, 1]), None);
+    assert_eq!(Frame::parse(&[0x08, 1, 2, 3, 4, 5, 6, 7]), None);
+    assert_eq!(Frame::parse(&[0x03, 1, 2]), None);
+    // A first frame for a message fitting a single frame
+    assert_eq!(Frame::parse(&[0x10, 7, 1, 2, 3, 4, 5, 6]), None);
+    assert_eq!(
+        Frame::parse(&[0x1f, 0xff, 1, 2, 3, 4, 5, 6]),
+        Some(Frame::First {
+            len: 4095,
+            data: &[1, 2, 3, 4, 5, 6]
+        })
+    );
+    assert_eq!(
+        Frame::parse(&[0x31, 0, 0]),
+        Some(Frame::FlowControl {
+            status: FlowStatus::Wait,
+            block_size: 0,
+            separation_time: 0
+        })
+    );
+    assert_eq!(Frame::parse(&[0x33, 0, 0]), None);
+    assert_eq!(Frame::parse(&[0x40]), None);
+
+    assert_eq!(frame::separation_time_us(0x7f), 127_000);
+    assert_eq!(frame::separation_time_us(0xf9), 900);
+    assert_eq!(frame::separation_time_us(0x80), 127_000);
+}
@@ -0,0 +1,690 @@
+//! The ISO-TP transport: segments messages into CAN frames and reassembles
+//! the frames received, on several channels at once.
+//!
+//! A channel sends with one CAN identifier and receives with another, as a
+//! diagnostic tester and an ECU do. Channels take turns on the bus, one frame
+//! at a time, so a long message on one channel does not hold back the others.
+//!
+//! Messages are not buffered: the client provides the bytes of the message
+//! it sends as each frame is built, and gets the bytes of the message it
+//! receives as each frame arrives. It accepts a message when its first frame
+//! arrives, or refuses it, which tells the sender the message overflows.
+//!
+//! As the sender, the transport waits for flow control after the first frame
+//! and after each block, and waits at least the separation time between
+//! consecutive frames. As the receiver, it sends flow control with the block
+//! size and separation time configured for the channel. Waiting for flow
+//! control (N_Bs) or for a consecutive frame (N_Cr) times out after a second.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil::can::{self, STANDARD_CAN_PACKET_SIZE};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Ticks};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use super::frame::{self, FlowStatus, Frame};
+
+/// Number of channels open at once.
+pub const MAX_CHANNELS: usize = 4;
+
+/// How long to wait for flow control or a consecutive frame.
+const TIMEOUT_MS: u32 = 1000;
+
+/// Flow control frames asking to wait a sender accepts in a row (N_WFTmax).
+const MAX_WAIT_FRAMES: u8 = 10;
+
+pub trait IsoTpClient {
+    /// Copies the bytes of the message being sent on `channel`, from
+    /// `offset`, into `dest`.
+    fn fill(&self, channel: usize, offset: usize, dest: &mut [u8]);
+
+    /// The message sent on `channel` was sent whole, or failed.
+    ///
+    /// Errors are `SIZE` if the receiver refused it, `NOACK` if the receiver
+    /// stopped answering, and `FAIL` if a frame could not be sent.
+    fn send_done(&self, channel: usize, result: Result<(), ErrorCode>);
+
+    /// A message of `len` bytes starts arriving on `channel`. Returning an
+    /// error refuses it.
+    fn receive_start(&self, channel: usize, len: usize) -> Result<(), ErrorCode>;
+
+    /// `data` of the message received on `channel` arrived, at `offset`.
+    fn receive_data(&self, channel: usize, offset: usize, data: &[u8]);
+
+    /// The message received on `channel` arrived whole, with its length, or
+    /// was abandoned.
+    ///
+    /// Errors are `NOACK` if the sender stopped sending, `FAIL` if a frame
+    /// was lost, and `CANCEL` if the sender started another message.
+    fn receive_done(&self, channel: usize, result: Result<usize, ErrorCode>);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum TxState {
+    Idle,
+    /// The next frame is waiting for the bus.
+    Ready,
+    /// A frame is on the bus.
+    Sending,
+    AwaitingFlowControl,
+    /// Waiting for the separation time before the next consecutive frame.
+    Separating,
+}
+
+#[derive(Copy, Clone)]
+struct Tx {
+    state: TxState,
+    len: usize,
+    /// Bytes put in frames so far.
+    offset: usize,
+    sequence_number: u8,
+    /// Consecutive frames left in the block, or 0 if the block is unlimited.
+    block_remaining: u8,
+    separation_us: u32,
+    wait_frames: u8,
+    /// When waiting ends, as an alarm reference and interval.
+    deadline: Option<(u32, u32)>,
+}
+
+#[derive(Copy, Clone)]
+struct Rx {
+    receiving: bool,
+    len: usize,
+    /// Bytes received so far.
+    offset: usize,
+    sequence_number: u8,
+    /// Consecutive frames left before the next flow control, if blocks are
+    /// limited.
+    block_remaining: u8,
+    deadline: Option<(u32, u32)>,
+    /// A flow control frame waiting for the bus.
+    flow_control: Option<FlowStatus>,
+}
+
+#[derive(Copy, Clone)]
+struct Channel {
+    tx_id: can::Id,
+    rx_id: can::Id,
+    /// Flow control parameters sent to the other end.
+    block_size: u8,
+    separation_time: u8,
+    tx: Tx,
+    rx: Rx,
+}
+
+/// The frame on the bus, and its channel.
+#[derive(Copy, Clone)]
+enum InFlight {
+    FirstFrame(usize),
+    /// A single or consecutive frame.
+    Data(usize),
+    FlowControl(usize),
+}
+
+pub struct IsoTp<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    can: &'a C,
+    alarm: &'a A,
+    channels: [Cell<Option<Channel>>; MAX_CHANNELS],
+    client: OptionalCell<&'a dyn IsoTpClient>,
+    tx_buffer: TakeCell<'static, [u8; STANDARD_CAN_PACKET_SIZE]>,
+    rx_buffer: TakeCell<'static, [u8; STANDARD_CAN_PACKET_SIZE]>,
+    in_flight: OptionalCell<InFlight>,
+    /// The channel to consider first for the next frame.
+    next_channel: Cell<usize>,
+}
+
+impl<'a, C, A> IsoTp<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    pub fn new(
+        can: &'a C,
+        alarm: &'a A,
+        tx_buffer: &'static mut [u8; STANDARD_CAN_PACKET_SIZE],
+        rx_buffer: &'static mut [u8; STANDARD_CAN_PACKET_SIZE],
+    ) -> IsoTp<'a, C, A> {
+        IsoTp {
+            can,
+            alarm,
+            channels: Default::default(),
+            client: OptionalCell::empty(),
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            in_flight: OptionalCell::empty(),
+            next_channel: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn IsoTpClient) {
+        self.client.set(client);
+    }
+
+    /// Starts receiving frames, once the controller is enabled.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        let buffer = self.rx_buffer.take().ok_or(ErrorCode::ALREADY)?;
+        self.can
+            .start_receive_process(buffer)
+            .map_err(|(err, buffer)| {
+                self.rx_buffer.replace(buffer);
+                err
+            })
+    }
+
+    /// Opens a channel sending with `tx_id` and receiving with `rx_id`, and
+    /// returns its number.
+    ///
+    /// Received messages are not limited in blocks, nor separated in time,
+    /// until `set_flow_control` says otherwise.
+    pub fn open(&self, tx_id: can::Id, rx_id: can::Id) -> Result<usize, ErrorCode> {
+        let taken = self.channels.iter().any(|channel| {
+            channel
+                .get()
+                .map_or(false, |channel| channel.rx_id == rx_id)
+        });
+        if taken {
+            return Err(ErrorCode::INVAL);
+        }
+        let index = self
+            .channels
+            .iter()
+            .position(|channel| channel.get().is_none())
+            .ok_or(ErrorCode::NOMEM)?;
+        self.channels[index].set(Some(Channel {
+            tx_id,
+            rx_id,
+            block_size: 0,
+            separation_time: 0,
+            tx: Tx {
+                state: TxState::Idle,
+                len: 0,
+                offset: 0,
+                sequence_number: 0,
+                block_remaining: 0,
+                separation_us: 0,
+                wait_frames: 0,
+                deadline: None,
+            },
+            rx: Rx {
+                receiving: false,
+                len: 0,
+                offset: 0,
+                sequence_number: 0,
+                block_remaining: 0,
+                deadline: None,
+                flow_control: None,
+            },
+        }));
+        Ok(index)
+    }
+
+    /// Closes `channel`, abandoning its messages without telling the client.
+    pub fn close(&self, channel: usize) {
+        if let Some(slot) = self.channels.get(channel) {
+            slot.set(None);
+        }
+        self.reset_alarm();
+    }
+
+    /// Sets the block size and separation time the sender of the messages
+    /// received on `channel` must follow, from its next message.
+    pub fn set_flow_control(
+        &self,
+        channel: usize,
+        block_size: u8,
+        separation_time: u8,
+    ) -> Result<(), ErrorCode> {
+        self.update(channel, |channel| {
+            channel.block_size = block_size;
+            channel.separation_time = separation_time;
+        })
+    }
+
+    /// Sends a message of `len` bytes on `channel`, which the client
+    /// provides as it is sent.
+    pub fn send(&self, channel: usize, len: usize) -> Result<(), ErrorCode> {
+        if len == 0 || len > frame::MAX_MESSAGE_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        self.update(channel, |channel| {
+            if channel.tx.state != TxState::Idle {
+                return Err(ErrorCode::BUSY);
+            }
+            channel.tx.state = TxState::Ready;
+            channel.tx.len = len;
+            channel.tx.offset = 0;
+            channel.tx.sequence_number = 1;
+            channel.tx.block_remaining = 0;
+            channel.tx.separation_us = 0;
+            channel.tx.wait_frames = 0;
+            channel.tx.deadline = None;
+            Ok(())
+        })??;
+        self.transmit_next();
+        Ok(())
+    }
+
+    // Applies `f` to the state of an open channel.
+    fn update<R>(&self, channel: usize, f: impl FnOnce(&mut Channel) -> R) -> Result<R, ErrorCode> {
+        let slot = self.channels.get(channel).ok_or(ErrorCode::INVAL)?;
+        let mut state = slot.get().ok_or(ErrorCode::INVAL)?;
+        let result = f(&mut state);
+        slot.set(Some(state));
+        Ok(result)
+    }
+
+    fn deadline(&self, ms: u32) -> Option<(u32, u32)> {
+        Some((
+            self.alarm.now().into_u32(),
+            self.alarm.ticks_from_ms(ms).into_u32(),
+        ))
+    }
+
+    fn deadline_us(&self, us: u32) -> Option<(u32, u32)> {
+        Some((
+            self.alarm.now().into_u32(),
+            self.alarm.ticks_from_us(us).into_u32(),
+        ))
+    }
+
+    fn expired(&self, deadline: Option<(u32, u32)>) -> bool {
+        deadline.map_or(false, |(reference, dt)| {
+            let start = A::Ticks::from(reference);
+            let end = start.wrapping_add(A::Ticks::from(dt));
+            !self.alarm.now().within_range(start, end)
+        })
+    }
+
+    // Sets the alarm to the earliest deadline of the channels.
+    fn reset_alarm(&self) {
+        let now = self.alarm.now().into_u32();
+        let earliest = self
+            .channels
+            .iter()
+            .filter_map(|channel| channel.get())
+            .flat_map(|channel| [channel.tx.deadline, channel.rx.deadline])
+            .flatten()
+            .min_by_key(|(reference, dt)| reference.wrapping_add(*dt).wrapping_sub(now));
+        match earliest {
+            Some((reference, dt)) => self
+                .alarm
+                .set_alarm(A::Ticks::from(reference), A::Ticks::from(dt)),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+
+    fn finish_send(&self, channel: usize, result: Result<(), ErrorCode>) {
+        let _ = self.update(channel, |channel| {
+            channel.tx.state = TxState::Idle;
+            channel.tx.deadline = None;
+        });
+        self.client.map(|client| client.send_done(channel, result));
+    }
+
+    fn finish_receive(&self, channel: usize, result: Result<usize, ErrorCode>) {
+        let _ = self.update(channel, |channel| {
+            channel.rx.receiving = false;
+            channel.rx.deadline = None;
+        });
+        self.client
+            .map(|client| client.receive_done(channel, result));
+    }
+
+    // Sends the next frame waiting for the bus, taking the channels in turn.
+    fn transmit_next(&self) {
+        if self.in_flight.is_some() {
+            return;
+        }
+        let first = self.next_channel.get();
+        for index in (0..MAX_CHANNELS).map(|i| (first + i) % MAX_CHANNELS) {
+            let channel = match self.channels[index].get() {
+                Some(channel) => channel,
+                None => continue,
+            };
+            if channel.rx.flow_control.is_some() || channel.tx.state == TxState::Ready {
+                self.next_channel.set((index + 1) % MAX_CHANNELS);
+                self.transmit(index, channel);
+                return;
+            }
+        }
+    }
+
+    // Sends the next frame of `channel`: its flow control, or else the next
+    // frame of its message.
+    fn transmit(&self, index: usize, mut channel: Channel) {
+        let buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        buffer.fill(frame::PADDING);
+
+        let in_flight = if let Some(status) = channel.rx.flow_control.take() {
+            frame::write_flow_control(buffer, status, channel.block_size, channel.separation_time);
+            InFlight::FlowControl(index)
+        } else {
+            let tx = &mut channel.tx;
+            let remaining = tx.len - tx.offset;
+            let (pci_len, data_len, in_flight) =
+                if tx.offset == 0 && tx.len <= frame::SINGLE_FRAME_DATA_LEN {
+                    (
+                        frame::write_single_frame(buffer, tx.len),
+                        tx.len,
+                        InFlight::Data(index),
+                    )
+                } else if tx.offset == 0 {
+                    (
+                        frame::write_first_frame(buffer, tx.len),
+                        frame::FIRST_FRAME_DATA_LEN,
+                        InFlight::FirstFrame(index),
+                    )
+                } else {
+                    let pci_len = frame::write_consecutive_frame(buffer, tx.sequence_number);
+                    tx.sequence_number = (tx.sequence_number + 1) & 0xf;
+                    (
+                        pci_len,
+                        cmp::min(remaining, frame::CONSECUTIVE_FRAME_DATA_LEN),
+                        InFlight::Data(index),
+                    )
+                };
+            let offset = tx.offset;
+            tx.offset += data_len;
+            tx.state = TxState::Sending;
+            self.client
+                .map(|client| client.fill(index, offset, &mut buffer[pci_len..pci_len + data_len]));
+            in_flight
+        };
+        let id = channel.tx_id;
+        self.channels[index].set(Some(channel));
+
+        match self.can.send(id, buffer, STANDARD_CAN_PACKET_SIZE) {
+            Ok(()) => self.in_flight.set(in_flight),
+            Err((err, buffer)) => {
+                self.tx_buffer.replace(buffer);
+                match in_flight {
+                    InFlight::FlowControl(_) => {}
+                    InFlight::FirstFrame(index) | InFlight::Data(index) => {
+                        self.finish_send(index, Err(err))
+                    }
+                }
+            }
+        }
+    }
+
+    // Moves the message sent on `channel` on after one of its frames left.
+    fn frame_sent(&self, index: usize, first_frame: bool) {
+        let complete = self.update(index, |channel| {
+            let tx = &mut channel.tx;
+            if tx.state != TxState::Sending {
+                return false;
+            }
+            if tx.offset >= tx.len {
+                return true;
+            }
+            if first_frame {
+                tx.state = TxState::AwaitingFlowControl;
+                tx.deadline = self.deadline(TIMEOUT_MS);
+            } else if tx.block_remaining == 1 {
+                tx.block_remaining = 0;
+                tx.state = TxState::AwaitingFlowControl;
+                tx.deadline = self.deadline(TIMEOUT_MS);
+            } else {
+                tx.block_remaining = tx.block_remaining.saturating_sub(1);
+                if tx.separation_us > 0 {
+                    tx.state = TxState::Separating;
+                    tx.deadline = self.deadline_us(tx.separation_us);
+                } else {
+                    tx.state = TxState::Ready;
+                }
+            }
+            false
+        });
+        if let Ok(true) = complete {
+            self.finish_send(index, Ok(()));
+        }
+    }
+
+    fn flow_control_received(
+        &self,
+        index: usize,
+        status: FlowStatus,
+        block_size: u8,
+        separation_time: u8,
+    ) {
+        let failure = self.update(index, |channel| {
+            let tx = &mut channel.tx;
+            if tx.state != TxState::AwaitingFlowControl {
+                return None;
+            }
+            match status {
+                FlowStatus::ContinueToSend => {
+                    tx.state = TxState::Ready;
+                    tx.block_remaining = block_size;
+                    tx.separation_us = frame::separation_time_us(separation_time);
+                    tx.wait_frames = 0;
+                    tx.deadline = None;
+                    None
+                }
+                FlowStatus::Wait if tx.wait_frames < MAX_WAIT_FRAMES => {
+                    tx.wait_frames += 1;
+                    tx.deadline = self.deadline(TIMEOUT_MS);
+                    None
+                }
+                FlowStatus::Wait => Some(ErrorCode::NOACK),
+                FlowStatus::Overflow => Some(ErrorCode::SIZE),
+            }
+        });
+        if let Ok(Some(err)) = failure {
+            self.finish_send(index, Err(err));
+        }
+    }
+
+    // Starts receiving a message of `len` bytes, beginning with `data`.
+    fn first_received(&self, index: usize, len: usize, data: &[u8], single: bool) {
+        let receiving = self
+            .channels
+            .get(index)
+            .and_then(|channel| channel.get())
+            .map_or(false, |channel| channel.rx.receiving);
+        if receiving {
+            self.finish_receive(index, Err(ErrorCode::CANCEL));
+        }
+
+        let accepted = self
+            .client
+            .map_or(Err(ErrorCode::FAIL), |client| {
+                client.receive_start(index, len)
+            })
+            .is_ok();
+        if single {
+            if accepted {
+                self.client.map(|client| {
+                    client.receive_data(index, 0, data);
+                    client.receive_done(index, Ok(len));
+                });
+            }
+            return;
+        }
+
+        let _ = self.update(index, |channel| {
+            let rx = &mut channel.rx;
+            if accepted {
+                rx.receiving = true;
+                rx.len = len;
+                rx.offset = data.len();
+                rx.sequence_number = 1;
+                rx.block_remaining = channel.block_size;
+                rx.deadline = self.deadline(TIMEOUT_MS);
+                rx.flow_control = Some(FlowStatus::ContinueToSend);
+            } else {
+                rx.flow_control = Some(FlowStatus::Overflow);
+            }
+        });
+        if accepted {
+            self.client
+                .map(|client| client.receive_data(index, 0, data));
+        }
+    }
+
+    fn consecutive_received(&self, index: usize, sequence_number: u8, data: &[u8]) {
+        let channel = match self.channels.get(index).and_then(|channel| channel.get()) {
+            Some(channel) => channel,
+            None => return,
+        };
+        let rx = channel.rx;
+        if !rx.receiving {
+            return;
+        }
+        let len = cmp::min(rx.len - rx.offset, frame::CONSECUTIVE_FRAME_DATA_LEN);
+        if sequence_number != rx.sequence_number || data.len() < len {
+            self.finish_receive(index, Err(ErrorCode::FAIL));
+            return;
+        }
+
+        let complete = rx.offset + len == rx.len;
+        let _ = self.update(index, |channel| {
+            let rx = &mut channel.rx;
+            rx.offset += len;
+            rx.sequence_number = (rx.sequence_number + 1) & 0xf;
+            rx.deadline = self.deadline(TIMEOUT_MS);
+            if !complete && channel.block_size > 0 {
+                rx.block_remaining -= 1;
+                if rx.block_remaining == 0 {
+                    rx.block_remaining = channel.block_size;
+                    rx.flow_control = Some(FlowStatus::ContinueToSend);
+                }
+            }
+        });
+        self.client
+            .map(|client| client.receive_data(index, rx.offset, &data[..len]));
+        if complete {
+            self.finish_receive(index, Ok(rx.len));
+        }
+    }
+}
+
+impl<'a, C, A> can::TransmitClient<STANDARD_CAN_PACKET_SIZE> for IsoTp<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    fn transmit_complete(
+        &self,
+        status: Result<(), can::Error>,
+        buffer: &'static mut [u8; STANDARD_CAN_PACKET_SIZE],
+    ) {
+        self.tx_buffer.replace(buffer);
+        match (self.in_flight.take(), status) {
+            // The receiving end times out if flow control is lost
+            (Some(InFlight::FlowControl(_)), _) | (None, _) => {}
+            (Some(InFlight::FirstFrame(index)), Ok(())) => self.frame_sent(index, true),
+            (Some(InFlight::Data(index)), Ok(())) => self.frame_sent(index, false),
+            (Some(InFlight::FirstFrame(index)), Err(_)) | (Some(InFlight::Data(index)), Err(_)) => {
+                self.finish_send(index, Err(ErrorCode::FAIL))
+            }
+        }
+        self.transmit_next();
+        self.reset_alarm();
+    }
+}
+
+impl<'a, C, A> can::ReceiveClient<STANDARD_CAN_PACKET_SIZE> for IsoTp<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    fn message_received(
+        &self,
+        id: can::Id,
+        buffer: &mut [u8; STANDARD_CAN_PACKET_SIZE],
+        len: usize,
+        status: Result<(), can::Error>,
+    ) {
+        if status.is_err() {
+            return;
+        }
+        let index = match self
+            .channels
+            .iter()
+            .position(|channel| channel.get().map_or(false, |channel| channel.rx_id == id))
+        {
+            Some(index) => index,
+            None => return,
+        };
+
+        match Frame::parse(&buffer[..cmp::min(len, STANDARD_CAN_PACKET_SIZE)]) {
+            Some(Frame::Single { data }) => self.first_received(index, data.len(), data, true),
+            Some(Frame::First { len, data }) => self.first_received(index, len, data, false),
+            Some(Frame::Consecutive {
+                sequence_number,
+                data,
+            }) => self.consecutive_received(index, sequence_number, data),
+            Some(Frame::FlowControl {
+                status,
+                block_size,
+                separation_time,
+            }) => self.flow_control_received(index, status, block_size, separation_time),
+            None => {}
+        }
+        self.transmit_next();
+        self.reset_alarm();
+    }
+
+    fn stopped(&self, buffer: &'static mut [u8; STANDARD_CAN_PACKET_SIZE]) {
+        self.rx_buffer.replace(buffer);
+    }
+}
+
+impl<'a, C, A> can::ControllerClient for IsoTp<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    fn state_changed(&self, _state: can::State) {}
+
+    fn enabled(&self, status: Result<(), ErrorCode>) {
+        if status.is_ok() {
+            let _ = self.start();
+        }
+    }
+
+    fn disabled(&self, _status: Result<(), ErrorCode>) {}
+}
+
+impl<'a, C, A> AlarmClient for IsoTp<'a, C, A>
+where
+    C: can::Transmit<STANDARD_CAN_PACKET_SIZE> + can::Receive<STANDARD_CAN_PACKET_SIZE>,
+    A: Alarm<'a>,
+{
+    fn alarm(&self) {
+        for index in 0..MAX_CHANNELS {
+            let channel = match self.channels[index].get() {
+                Some(channel) => channel,
+                None => continue,
+            };
+            if self.expired(channel.tx.deadline) {
+                match channel.tx.state {
+                    TxState::Separating => {
+                        let _ = self.update(index, |channel| {
+                            channel.tx.state = TxState::Ready;
+                            channel.tx.deadline = None;
+                        });
+                    }
+                    _ => self.finish_send(index, Err(ErrorCode::NOACK)),
+                }
+            }
+            if channel.rx.receiving && self.expired(channel.rx.deadline) {
+                self.finish_receive(index, Err(ErrorCode::NOACK));
+            }
+        }
+        self.transmit_next();
+        self.reset_alarm();
+    }
+}
@@ -224,6 +224,51 @@ impl<'a, K: kv_system::KVSystem<'a, K = T>, T: kv_system::KeyType> KVSystemDrive
                                     return e;
                                 }
                             }
+                            UserSpaceOp::Enumerate => {
+                                let perms = processid
+                                    .get_storage_permissions()
+                                    .ok_or(ErrorCode::INVAL)?;
+
+                                let prefix_length = app.prefix_length.get();
+                                if prefix_length > 0 {
+                                    kernel_data
+                                        .get_readonly_processbuffer(ro_allow::UNHASHED_KEY)
+                                        .and_then(|buffer| {
+                                            buffer.enter(|prefix| {
+                                                self.data_buffer.map_or(
+                                                    Err(ErrorCode::NOMEM),
+                                                    |buf| {
+                                                        if prefix_length > prefix.len()
+                                                            || prefix_length > buf.len()
+                                                        {
+                                                            return Err(ErrorCode::SIZE);
+                                                        }
+                                                        prefix[..prefix_length].copy_to_slice(
+                                                            &mut buf[..prefix_length],
+                                                        );
+                                                        Ok(())
+                                                    },
+                                                )
+                                            })
+                                        })
+                                        .unwrap_or(Err(ErrorCode::RESERVE))?;
+                                }
+
+                                if let Some(Err(e)) = self.data_buffer.take().map(|data_buffer| {
+                                    if let Err((data, e)) = self.kv.enumerate(
+                                        app.cursor.get(),
+                                        data_buffer,
+                                        prefix_length,
+                                        perms,
+                                    ) {
+                                        self.data_buffer.replace(data);
+                                        return e;
+                                    }
+                                    Ok(())
+                                }) {
+                                    return Err(e);
+                                }
+                            }
                         }
                     }
 
@@ -368,6 +413,54 @@ impl<'a, K: kv_system::KVSystem<'a, K = T>, T: kv_system::KeyType> kv_system::St
             })
         });
     }
+
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut [u8]) {
+        self.processid.take().map(|id| {
+            self.apps.enter(id, |app, upcalls| {
+                if app.op.get() == Some(UserSpaceOp::Enumerate) {
+                    let ret = result.and_then(|cursor| {
+                        upcalls
+                            .get_readwrite_processbuffer(rw_allow::VALUE)
+                            .and_then(|buffer| {
+                                buffer.mut_enter(|data| {
+                                    // Copy the hashed key, keys are arrays of bytes
+                                    let len =
+                                        data.len().min(key.len()).min(core::mem::size_of::<T>());
+                                    data[..len].copy_from_slice(&key[..len]);
+                                })
+                            })
+                            .map_err(|_| ErrorCode::RESERVE)?;
+                        Ok(cursor)
+                    });
+
+                    match ret {
+                        Ok(cursor) => {
+                            upcalls.schedule_upcall(upcalls::VALUE, (0, cursor, 0)).ok();
+                        }
+                        Err(e) => {
+                            upcalls
+                                .schedule_upcall(
+                                    upcalls::VALUE,
+                                    (kernel::errorcode::into_statuscode(Err(e)), 0, 0),
+                                )
+                                .ok();
+                        }
+                    }
+                }
+            })
+        });
+        self.data_buffer.replace(key);
+
+        self.check_queue();
+    }
+
+    // Apps can't start transactions, so these are never called
+
+    fn start_transaction_complete(&self, _result: Result<(), ErrorCode>) {}
+
+    fn commit_transaction_complete(&self, _result: Result<(), ErrorCode>) {}
+
+    fn abort_transaction_complete(&self, _result: Result<(), ErrorCode>) {}
 }
 
 impl<'a, K: kv_system::KVSystem<'a, K = T>, T: kv_system::KeyType> SyscallDriver
@@ -376,8 +469,8 @@ impl<'a, K: kv_system::KVSystem<'a, K = T>, T: kv_system::KeyType> SyscallDriver
     fn command(
         &self,
         command_num: usize,
-        _data1: usize,
-        _data2: usize,
+        data1: usize,
+        data2: usize,
         processid: ProcessId,
     ) -> CommandReturn {
         let match_or_empty_or_nonexistant = self.processid.map_or(true, |owning_app| {
@@ -408,14 +501,27 @@ impl<'a, K: kv_system::KVSystem<'a, K = T>, T: kv_system::KeyType> SyscallDriver
             // check if present
             0 => CommandReturn::success(),
 
-            // get, set, delete
-            1 | 2 | 3 => {
+            // get, set, delete, enumerate
+            //
+            // Enumerate finds the first key written by the app at or after
+            // the cursor in data1, 0 to start with the first key, that starts
+            // with the first data2 bytes of the read-only key buffer. The
+            // hashed key is copied to the read-write buffer, and the upcall
+            // returns the cursor of the next key. NOSUPPORT is returned when
+            // there are no more keys, and SIZE when the prefix is longer than
+            // `kv_store::KEY_PREFIX_LENGTH`.
+            1 | 2 | 3 | 4 => {
                 if match_or_empty_or_nonexistant {
                     self.processid.set(processid);
                     let _ = self.apps.enter(processid, |app, _| match command_num {
                         1 => app.op.set(Some(UserSpaceOp::Get)),
                         2 => app.op.set(Some(UserSpaceOp::Set)),
                         3 => app.op.set(Some(UserSpaceOp::Delete)),
+                        4 => {
+                            app.op.set(Some(UserSpaceOp::Enumerate));
+                            app.cursor.set(data1);
+                            app.prefix_length.set(data2);
+                        }
                         _ => {}
                     });
                     let ret = self.run();
@@ -443,6 +549,11 @@ impl<'a, K: kv_system::KVSystem<'a, K = T>, T: kv_system::KeyType> SyscallDriver
                                     1 => app.op.set(Some(UserSpaceOp::Get)),
                                     2 => app.op.set(Some(UserSpaceOp::Set)),
                                     3 => app.op.set(Some(UserSpaceOp::Delete)),
+                                    4 => {
+                                        app.op.set(Some(UserSpaceOp::Enumerate));
+                                        app.cursor.set(data1);
+                                        app.prefix_length.set(data2);
+                                    }
                                     _ => {}
                                 }
                                 CommandReturn::success()
@@ -467,10 +578,13 @@ enum UserSpaceOp {
     Get,
     Set,
     Delete,
+    Enumerate,
 }
 
 #[derive(Default)]
 pub struct App {
     pending_run_app: Option<ProcessId>,
     op: Cell<Option<UserSpaceOp>>,
+    cursor: Cell<usize>,
+    prefix_length: Cell<usize>,
 }
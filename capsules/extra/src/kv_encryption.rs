@@ -0,0 +1,1111 @@
+//! Encrypted-at-rest layer for the Tock Key-Value store.
+//!
+//! This capsule sits between the `KVStore` and a `hil::kv_system`
+//! implementation, such as TicKV, and encrypts and authenticates every
+//! value with AES-128-CCM before it is stored in flash.
+//!
+//! ```
+//! +-----------------------+
+//! |                       |
+//! |  K-V store            |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_system
+//!
+//! +-----------------------+
+//! |                       |
+//! | K-V encryption (this) |
+//! |                       |
+//! +-----------------------+
+//!
+//!    hil::kv_system
+//!
+//! +-----------------------+
+//! |                       |
+//! |  K-V library          |
+//! |                       |
+//! +-----------------------+
+//! ```
+//!
+//! Every value is stored as
+//!
+//! ```text
+//! | hashed key | KVStore header | version | nonce | encrypted value | MIC |
+//! ```
+//!
+//! The hashed key, the `KVStore` header, which holds the write_id of the
+//! app that wrote the value, the version and the nonce are the associated
+//! data of the encryption: they are stored in the clear but are
+//! authenticated. When a value is read, the stored hashed key is replaced by
+//! the key being read before the MIC is checked. A record copied to another
+//! key, or a record whose write_id or value was changed, therefore fails to
+//! authenticate, and reading it returns `FAIL`.
+//!
+//! Every value written to a key gets a higher version than the one before,
+//! and the version of the latest value of each key is kept in a board
+//! provided `VersionStore`, outside of the flash the records are stored in.
+//! A value whose version is lower than the one in the store was rolled back,
+//! by writing an older record of the key, or a deleted one, back to flash,
+//! and reading it returns `FAIL` as well. The store is only updated once a
+//! value is written, so the value written last before a reset is still read
+//! if the reset came before the store was updated.
+//!
+//! Values are encrypted with a device key from a board provided
+//! `KeySource`, and a new random nonce is used for every value written.
+//!
+//! The buffer given to this capsule holds a whole record, so it must be
+//! `record_length()` bytes long for the longest value written. Every value
+//! is padded to that length, which keeps the lengths of the values secret.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let device_key = static_init!([u8; 16], [...]);
+//!
+//! let kv_encryption =
+//!     components::kv_system::KVEncryptionComponent::new(
+//!         tickv,
+//!         ccm_client,
+//!         rng,
+//!         device_key,
+//!         version_store,
+//!     )
+//!     .finalize(components::kv_encryption_component_static!(
+//!         capsules_extra::tickv::TicKVStore<...>,
+//!         capsules_extra::tickv::TicKVKeyType,
+//!         capsules_core::virtualizers::virtual_aes_ccm::VirtualAES128CCM<...>,
+//!         capsules_core::rng::Entropy32ToRandom<'static>,
+//!         [u8; 16],
+//!         BoardVersionStore,
+//!         48, // The length of the values written by the KVStore
+//!     ));
+//!
+//! let mux_kv = components::kv_system::KVStoreMuxComponent::new(kv_encryption).finalize(...);
+//! ```
+
+use crate::kv_store::HEADER_LENGTH;
+use core::cell::Cell;
+use core::mem;
+use kernel::hil::kv_system::{self, KVSystem, KeyType, Statistics};
+use kernel::hil::rng::{self, Rng};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE, CCM_NONCE_LENGTH};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// The length of the MIC authenticating each value.
+pub const MIC_LENGTH: usize = 16;
+
+/// The length of the version of each value.
+pub const VERSION_LENGTH: usize = 4;
+
+/// The number of bytes stored for a value of `value_length` bytes, written
+/// by a `KVStore`, with a key of `key_length` bytes.
+pub const fn record_length(key_length: usize, value_length: usize) -> usize {
+    key_length + value_length + VERSION_LENGTH + CCM_NONCE_LENGTH + MIC_LENGTH
+}
+
+/// A source of the device key that values are encrypted with.
+///
+/// Boards implement this to provide a key unique to the device, for example
+/// one read from OTP memory or derived from a hardware unique key.
+pub trait KeySource {
+    /// Copy the device key to `key`.
+    fn device_key(&self, key: &mut [u8; AES128_KEY_SIZE]) -> Result<(), ErrorCode>;
+}
+
+impl KeySource for [u8; AES128_KEY_SIZE] {
+    fn device_key(&self, key: &mut [u8; AES128_KEY_SIZE]) -> Result<(), ErrorCode> {
+        key.copy_from_slice(self);
+        Ok(())
+    }
+}
+
+/// A store of the version of the latest value written to each key.
+///
+/// Boards implement this with storage that can't be rolled back together
+/// with the flash the values are stored in, for example monotonic counters
+/// or a secure element. Versions only ever increase.
+pub trait VersionStore {
+    /// The version of the latest value written to `key`, or 0 if no value
+    /// was.
+    fn version(&self, key: &[u8]) -> Result<u32, ErrorCode>;
+
+    /// Record that `version` is the version of the latest value written to
+    /// `key`.
+    ///
+    /// Versions set while a transaction is open only take effect once it is
+    /// committed, and must be forgotten if it is aborted or the device
+    /// resets first, as the values written in it are.
+    fn set_version(&self, key: &[u8], version: u32) -> Result<(), ErrorCode>;
+
+    /// A transaction of the KV system was started.
+    fn start_transaction(&self);
+
+    /// The open transaction was committed, keep the versions set in it.
+    fn commit_transaction(&self) -> Result<(), ErrorCode>;
+
+    /// The open transaction was aborted, forget the versions set in it.
+    fn abort_transaction(&self);
+}
+
+/// Where each part of a record is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Layout {
+    key_length: usize,
+    version_offset: usize,
+    nonce_offset: usize,
+    message_offset: usize,
+    message_length: usize,
+}
+
+impl Layout {
+    /// The layout of a record stored in `record_length` bytes, or `None` if
+    /// no value fits.
+    fn new(key_length: usize, record_length: usize) -> Option<Self> {
+        let version_offset = key_length + HEADER_LENGTH;
+        let nonce_offset = version_offset + VERSION_LENGTH;
+        let message_offset = nonce_offset + CCM_NONCE_LENGTH;
+        let message_length = record_length.checked_sub(message_offset + MIC_LENGTH)?;
+
+        Some(Self {
+            key_length,
+            version_offset,
+            nonce_offset,
+            message_offset,
+            message_length,
+        })
+    }
+
+    /// Copy the key, `version` and `value` to `record`, padding the value
+    /// with zeros.
+    fn fill(
+        &self,
+        record: &mut [u8],
+        key: &[u8],
+        version: u32,
+        value: &[u8],
+    ) -> Result<(), ErrorCode> {
+        if value.len() < HEADER_LENGTH || value.len() - HEADER_LENGTH > self.message_length {
+            return Err(ErrorCode::SIZE);
+        }
+
+        record[..self.key_length].copy_from_slice(key);
+        record[self.key_length..self.version_offset].copy_from_slice(&value[..HEADER_LENGTH]);
+        record[self.version_offset..self.nonce_offset].copy_from_slice(&version.to_le_bytes());
+
+        let message = &mut record[self.message_offset..self.message_offset + self.message_length];
+        let (data, padding) = message.split_at_mut(value.len() - HEADER_LENGTH);
+        data.copy_from_slice(&value[HEADER_LENGTH..]);
+        padding.iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+
+    /// The version of the value in `record`.
+    fn version(&self, record: &[u8]) -> u32 {
+        let mut version = [0; VERSION_LENGTH];
+        version.copy_from_slice(&record[self.version_offset..self.nonce_offset]);
+        u32::from_le_bytes(version)
+    }
+
+    /// Copy as much of the header and value in `record` as fits to
+    /// `ret_buf`.
+    fn read(&self, record: &[u8], ret_buf: &mut [u8]) {
+        let header_length = ret_buf.len().min(HEADER_LENGTH);
+        ret_buf[..header_length]
+            .copy_from_slice(&record[self.key_length..self.key_length + header_length]);
+
+        let length = (ret_buf.len() - header_length).min(self.message_length);
+        ret_buf[header_length..header_length + length]
+            .copy_from_slice(&record[self.message_offset..self.message_offset + length]);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    AppendKey,
+    GetValue,
+}
+
+pub struct KVEncryption<
+    'a,
+    KV: KVSystem<'a, K = T>,
+    T: 'static + KeyType,
+    A: AES128CCM<'a>,
+    R: Rng<'a>,
+    S: KeySource,
+    V: VersionStore,
+> {
+    kv: &'a KV,
+    ccm: &'a A,
+    rng: &'a R,
+    key_source: &'a S,
+    version_store: &'a V,
+
+    operation: Cell<Operation>,
+    /// The version of the value being appended
+    version: Cell<u32>,
+    /// The number of bytes of the nonce filled in from the RNG
+    nonce_filled: Cell<usize>,
+
+    record: TakeCell<'static, [u8]>,
+    key: TakeCell<'static, T>,
+    /// The value being appended, or the buffer the value read is returned in
+    value: TakeCell<'static, [u8]>,
+
+    client: OptionalCell<&'a dyn kv_system::Client<T>>,
+}
+
+impl<
+        'a,
+        KV: KVSystem<'a, K = T>,
+        T: KeyType,
+        A: AES128CCM<'a>,
+        R: Rng<'a>,
+        S: KeySource,
+        V: VersionStore,
+    > KVEncryption<'a, KV, T, A, R, S, V>
+{
+    pub fn new(
+        kv: &'a KV,
+        ccm: &'a A,
+        rng: &'a R,
+        key_source: &'a S,
+        version_store: &'a V,
+        record: &'static mut [u8],
+    ) -> KVEncryption<'a, KV, T, A, R, S, V> {
+        Self {
+            kv,
+            ccm,
+            rng,
+            key_source,
+            version_store,
+            operation: Cell::new(Operation::None),
+            version: Cell::new(0),
+            nonce_filled: Cell::new(0),
+            record: TakeCell::new(record),
+            key: TakeCell::empty(),
+            value: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn layout(&self, record_length: usize) -> Result<Layout, ErrorCode> {
+        Layout::new(mem::size_of::<T>(), record_length).ok_or(ErrorCode::SIZE)
+    }
+
+    /// Encrypt or decrypt the record, with the nonce stored in it.
+    fn crypt(&self, encrypting: bool) -> Result<(), ErrorCode> {
+        let mut device_key = [0; AES128_KEY_SIZE];
+        self.key_source.device_key(&mut device_key)?;
+        self.ccm.set_key(&device_key)?;
+
+        let record = self.record.take().ok_or(ErrorCode::NOMEM)?;
+        let layout = match self.layout(record.len()) {
+            Ok(layout) => layout,
+            Err(e) => {
+                self.record.replace(record);
+                return Err(e);
+            }
+        };
+        if let Err(e) = self
+            .ccm
+            .set_nonce(&record[layout.nonce_offset..layout.message_offset])
+        {
+            self.record.replace(record);
+            return Err(e);
+        }
+
+        self.ccm
+            .crypt(
+                record,
+                0,
+                layout.message_offset,
+                layout.message_length,
+                MIC_LENGTH,
+                true,
+                encrypting,
+            )
+            .map_err(|(e, record)| {
+                self.record.replace(record);
+                e
+            })
+    }
+
+    fn append_key_done(&self, result: Result<(), ErrorCode>) {
+        self.operation.set(Operation::None);
+        self.key.take().map(|key| {
+            self.value.take().map(|value| {
+                self.client.map(move |cb| {
+                    cb.append_key_complete(result, key, value);
+                });
+            });
+        });
+    }
+
+    fn get_value_done(&self, result: Result<(), ErrorCode>) {
+        self.operation.set(Operation::None);
+        self.key.take().map(|key| {
+            self.value.take().map(|ret_buf| {
+                if result.is_err() {
+                    // Don't return anything that wasn't authenticated
+                    ret_buf.iter_mut().for_each(|b| *b = 0);
+                }
+                self.client.map(move |cb| {
+                    cb.get_value_complete(result, key, ret_buf);
+                });
+            });
+        });
+    }
+}
+
+impl<
+        'a,
+        KV: KVSystem<'a, K = T>,
+        T: KeyType,
+        A: AES128CCM<'a>,
+        R: Rng<'a>,
+        S: KeySource,
+        V: VersionStore,
+    > rng::Client for KVEncryption<'a, KV, T, A, R, S, V>
+{
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        if self.operation.get() != Operation::AppendKey {
+            return rng::Continue::Done;
+        }
+        if let Err(e) = error {
+            self.append_key_done(Err(e));
+            return rng::Continue::Done;
+        }
+
+        let filled = self.record.map_or(Err(ErrorCode::NOMEM), |record| {
+            let layout = self.layout(record.len())?;
+            let nonce = &mut record[layout.nonce_offset..layout.message_offset];
+
+            for chunk in nonce[self.nonce_filled.get()..].chunks_mut(4) {
+                match randomness.next() {
+                    Some(random) => {
+                        chunk.copy_from_slice(&random.to_le_bytes()[..chunk.len()]);
+                        self.nonce_filled.set(self.nonce_filled.get() + chunk.len());
+                    }
+                    None => return Ok(false),
+                }
+            }
+            Ok(true)
+        });
+
+        match filled {
+            Ok(false) => rng::Continue::More,
+            Ok(true) => {
+                if let Err(e) = self.crypt(true) {
+                    self.append_key_done(Err(e));
+                }
+                rng::Continue::Done
+            }
+            Err(e) => {
+                self.append_key_done(Err(e));
+                rng::Continue::Done
+            }
+        }
+    }
+}
+
+impl<
+        'a,
+        KV: KVSystem<'a, K = T>,
+        T: KeyType,
+        A: AES128CCM<'a>,
+        R: Rng<'a>,
+        S: KeySource,
+        V: VersionStore,
+    > CCMClient for KVEncryption<'a, KV, T, A, R, S, V>
+{
+    fn crypt_done(
+        &self,
+        record: &'static mut [u8],
+        res: Result<(), ErrorCode>,
+        tag_is_valid: bool,
+    ) {
+        match self.operation.get() {
+            Operation::AppendKey => {
+                if res.is_err() {
+                    self.record.replace(record);
+                    self.append_key_done(res);
+                    return;
+                }
+
+                match self.key.take() {
+                    Some(key) => {
+                        if let Err((key, record, e)) = self.kv.append_key(key, record) {
+                            self.record.replace(record);
+                            self.key.replace(key);
+                            self.append_key_done(e.and(Err(ErrorCode::FAIL)));
+                        }
+                    }
+                    None => {
+                        self.record.replace(record);
+                        self.append_key_done(Err(ErrorCode::FAIL));
+                    }
+                }
+            }
+            Operation::GetValue => {
+                let result = if res.is_ok() && tag_is_valid {
+                    self.layout(record.len()).and_then(|layout| {
+                        // Values older than the latest one written were
+                        // rolled back
+                        let latest = self.key.map_or(Err(ErrorCode::FAIL), |key| {
+                            self.version_store.version(key.as_ref())
+                        })?;
+                        if layout.version(record) < latest {
+                            return Err(ErrorCode::FAIL);
+                        }
+                        self.value.map(|ret_buf| layout.read(record, ret_buf));
+                        Ok(())
+                    })
+                } else {
+                    Err(ErrorCode::FAIL)
+                };
+
+                self.record.replace(record);
+                self.get_value_done(result);
+            }
+            Operation::None => {
+                self.record.replace(record);
+            }
+        }
+    }
+}
+
+impl<
+        'a,
+        KV: KVSystem<'a, K = T>,
+        T: KeyType,
+        A: AES128CCM<'a>,
+        R: Rng<'a>,
+        S: KeySource,
+        V: VersionStore,
+    > kv_system::Client<T> for KVEncryption<'a, KV, T, A, R, S, V>
+{
+    fn generate_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut T,
+    ) {
+        self.client.map(move |cb| {
+            cb.generate_key_complete(result, unhashed_key, key_buf);
+        });
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        record: &'static mut [u8],
+    ) {
+        let result = result.and_then(|()| {
+            self.version_store
+                .set_version(key.as_ref(), self.version.get())
+        });
+        self.record.replace(record);
+        self.key.replace(key);
+        self.append_key_done(result);
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut T,
+        record: &'static mut [u8],
+    ) {
+        if result.is_ok() {
+            // Authenticate the value for the key read, so that values moved
+            // to another key fail
+            record[..key.as_ref().len()].copy_from_slice(key.as_ref());
+        }
+        self.record.replace(record);
+        self.key.replace(key);
+
+        match result.and_then(|()| self.crypt(false)) {
+            Ok(()) => {}
+            Err(e) => self.get_value_done(Err(e)),
+        }
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ErrorCode>, key: &'static mut T) {
+        // A deleted value written back to flash is older than the latest one
+        let result = result.and_then(|()| {
+            let version = self.version_store.version(key.as_ref())?;
+            self.version_store
+                .set_version(key.as_ref(), version.checked_add(1).ok_or(ErrorCode::FAIL)?)
+        });
+        self.client.map(move |cb| {
+            cb.invalidate_key_complete(result, key);
+        });
+    }
+
+    fn garbage_collect_complete(&self, result: Result<(), ErrorCode>) {
+        self.client.map(move |cb| {
+            cb.garbage_collect_complete(result);
+        });
+    }
+
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut T) {
+        self.client.map(move |cb| {
+            cb.enumerate_complete(result, key);
+        });
+    }
+
+    fn statistics_complete(&self, result: Result<Statistics, ErrorCode>) {
+        self.client.map(move |cb| {
+            cb.statistics_complete(result);
+        });
+    }
+
+    fn start_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        if result.is_ok() {
+            self.version_store.start_transaction();
+        }
+        self.client.map(move |cb| {
+            cb.start_transaction_complete(result);
+        });
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        let result = result.and_then(|()| self.version_store.commit_transaction());
+        self.client.map(move |cb| {
+            cb.commit_transaction_complete(result);
+        });
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        if result.is_ok() {
+            self.version_store.abort_transaction();
+        }
+        self.client.map(move |cb| {
+            cb.abort_transaction_complete(result);
+        });
+    }
+}
+
+impl<
+        'a,
+        KV: KVSystem<'a, K = T>,
+        T: KeyType,
+        A: AES128CCM<'a>,
+        R: Rng<'a>,
+        S: KeySource,
+        V: VersionStore,
+    > KVSystem<'a> for KVEncryption<'a, KV, T, A, R, S, V>
+{
+    type K = T;
+
+    fn set_client(&self, client: &'a dyn kv_system::Client<Self::K>) {
+        self.client.set(client);
+    }
+
+    fn generate_key(
+        &self,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut Self::K,
+    ) -> Result<
+        (),
+        (
+            &'static mut [u8],
+            &'static mut Self::K,
+            Result<(), ErrorCode>,
+        ),
+    > {
+        self.kv.generate_key(unhashed_key, key_buf)
+    }
+
+    fn append_key(
+        &self,
+        key: &'static mut Self::K,
+        value: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut Self::K,
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    > {
+        if self.operation.get() != Operation::None {
+            return Err((key, value, Err(ErrorCode::BUSY)));
+        }
+
+        // Every value gets a higher version than the latest one written
+        let filled = self.version_store.version(key.as_ref()).and_then(|latest| {
+            let version = latest.checked_add(1).ok_or(ErrorCode::FAIL)?;
+            self.record.map_or(Err(ErrorCode::NOMEM), |record| {
+                self.layout(record.len())?
+                    .fill(record, key.as_ref(), version, value)
+            })?;
+            self.version.set(version);
+            Ok(())
+        });
+        if let Err(e) = filled {
+            return Err((key, value, Err(e)));
+        }
+
+        // The value is encrypted once the RNG provides the nonce
+        self.operation.set(Operation::AppendKey);
+        self.nonce_filled.set(0);
+        self.key.replace(key);
+        self.value.replace(value);
+        if let Err(e) = self.rng.get() {
+            self.operation.set(Operation::None);
+            return Err((self.key.take().unwrap(), self.value.take().unwrap(), Err(e)));
+        }
+        Ok(())
+    }
+
+    fn get_value(
+        &self,
+        key: &'static mut Self::K,
+        ret_buf: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut Self::K,
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    > {
+        if self.operation.get() != Operation::None {
+            return Err((key, ret_buf, Err(ErrorCode::BUSY)));
+        }
+        let record = match self.record.take() {
+            Some(record) => record,
+            None => return Err((key, ret_buf, Err(ErrorCode::NOMEM))),
+        };
+
+        self.operation.set(Operation::GetValue);
+        self.value.replace(ret_buf);
+        if let Err((key, record, e)) = self.kv.get_value(key, record) {
+            self.operation.set(Operation::None);
+            self.record.replace(record);
+            return Err((key, self.value.take().unwrap(), e));
+        }
+        Ok(())
+    }
+
+    fn invalidate_key(
+        &self,
+        key: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, Result<(), ErrorCode>)> {
+        self.kv.invalidate_key(key)
+    }
+
+    fn garbage_collect(&self) -> Result<usize, Result<(), ErrorCode>> {
+        self.kv.garbage_collect()
+    }
+
+    fn garbage_collect_region(&self, region: usize) -> Result<(), ErrorCode> {
+        self.kv.garbage_collect_region(region)
+    }
+
+    fn statistics(&self) -> Result<(), ErrorCode> {
+        self.kv.statistics()
+    }
+
+    fn enumerate(
+        &self,
+        cursor: usize,
+        key: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, Result<(), ErrorCode>)> {
+        self.kv.enumerate(cursor, key)
+    }
+
+    fn start_transaction(&self) -> Result<(), ErrorCode> {
+        self.kv.start_transaction()
+    }
+
+    fn commit_transaction(&self) -> Result<(), ErrorCode> {
+        self.kv.commit_transaction()
+    }
+
+    fn abort_transaction(&self) -> Result<(), ErrorCode> {
+        self.kv.abort_transaction()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ccm::MockCCM;
+    use crate::testing::kv::MockKV;
+    use crate::testing::rng::MockRng;
+    use core::cell::RefCell;
+    use std::boxed::Box;
+    use std::collections::BTreeMap;
+    use std::vec;
+    use std::vec::Vec;
+
+    const KEY: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    // A KVStore header, followed by a 3 byte value
+    const VALUE: [u8; 12] = [0, 3, 0, 0, 0, 0x10, 0, 0, 0, 0xAA, 0xBB, 0xCC];
+
+    #[test]
+    fn record_layout() {
+        let layout = Layout::new(8, record_length(8, 16)).unwrap();
+        assert_eq!(layout.version_offset, 8 + HEADER_LENGTH);
+        assert_eq!(layout.nonce_offset, 8 + HEADER_LENGTH + VERSION_LENGTH);
+        assert_eq!(
+            layout.message_offset,
+            8 + HEADER_LENGTH + VERSION_LENGTH + CCM_NONCE_LENGTH
+        );
+        assert_eq!(layout.message_length, 16 - HEADER_LENGTH);
+
+        // There has to be space for the header, nonce and MIC
+        assert!(Layout::new(8, record_length(8, HEADER_LENGTH) - 1).is_none());
+    }
+
+    #[test]
+    fn fills_and_reads_records() {
+        let layout = Layout::new(8, record_length(8, 16)).unwrap();
+        let mut record = [0xFF; record_length(8, 16)];
+        layout.fill(&mut record, &KEY, 0x01020304, &VALUE).unwrap();
+
+        // The key, header and version are associated data in front of the
+        // nonce
+        assert_eq!(record[..8], KEY);
+        assert_eq!(record[8..layout.version_offset], VALUE[..HEADER_LENGTH]);
+        assert_eq!(
+            record[layout.version_offset..layout.nonce_offset],
+            [4, 3, 2, 1]
+        );
+        assert_eq!(layout.version(&record), 0x01020304);
+        // The value is padded with zeros
+        let message = &record[layout.message_offset..layout.message_offset + 7];
+        assert_eq!(message, [0xAA, 0xBB, 0xCC, 0, 0, 0, 0]);
+
+        let mut ret_buf = [0; 12];
+        layout.read(&record, &mut ret_buf);
+        assert_eq!(ret_buf, VALUE);
+
+        // Reading only the header, as the KVStore does to check permissions
+        let mut header = [0; HEADER_LENGTH];
+        layout.read(&record, &mut header);
+        assert_eq!(header, VALUE[..HEADER_LENGTH]);
+    }
+
+    #[test]
+    fn rejects_values_that_do_not_fit() {
+        let layout = Layout::new(8, record_length(8, 11)).unwrap();
+        let mut record = [0; record_length(8, 11)];
+        assert_eq!(
+            layout.fill(&mut record, &KEY, 1, &VALUE),
+            Err(ErrorCode::SIZE)
+        );
+        assert_eq!(
+            layout.fill(&mut record, &KEY, 1, &VALUE[..HEADER_LENGTH - 1]),
+            Err(ErrorCode::SIZE)
+        );
+    }
+
+    /// Keeps the versions in memory, and those set in a transaction apart
+    /// until it is committed.
+    #[derive(Default)]
+    struct Versions {
+        committed: RefCell<BTreeMap<Vec<u8>, u32>>,
+        open: RefCell<Option<BTreeMap<Vec<u8>, u32>>>,
+    }
+
+    impl VersionStore for Versions {
+        fn version(&self, key: &[u8]) -> Result<u32, ErrorCode> {
+            let versions = match &*self.open.borrow() {
+                Some(open) => open.get(key).copied(),
+                None => self.committed.borrow().get(key).copied(),
+            };
+            Ok(versions.unwrap_or(0))
+        }
+
+        fn set_version(&self, key: &[u8], version: u32) -> Result<(), ErrorCode> {
+            match &mut *self.open.borrow_mut() {
+                Some(open) => open.insert(key.to_vec(), version),
+                None => self.committed.borrow_mut().insert(key.to_vec(), version),
+            };
+            Ok(())
+        }
+
+        fn start_transaction(&self) {
+            *self.open.borrow_mut() = Some(self.committed.borrow().clone());
+        }
+
+        fn commit_transaction(&self) -> Result<(), ErrorCode> {
+            if let Some(open) = self.open.take() {
+                *self.committed.borrow_mut() = open;
+            }
+            Ok(())
+        }
+
+        fn abort_transaction(&self) {
+            self.open.take();
+        }
+    }
+
+    /// Records the results of the operations.
+    #[derive(Default)]
+    struct Client {
+        appended: Cell<Option<Result<(), ErrorCode>>>,
+        read: RefCell<Option<(Result<(), ErrorCode>, Vec<u8>)>>,
+        invalidated: Cell<Option<Result<(), ErrorCode>>>,
+        transaction: Cell<Option<Result<(), ErrorCode>>>,
+    }
+
+    impl kv_system::Client<[u8; 8]> for Client {
+        fn generate_key_complete(
+            &self,
+            _result: Result<(), ErrorCode>,
+            _unhashed_key: &'static mut [u8],
+            _key_buf: &'static mut [u8; 8],
+        ) {
+        }
+
+        fn append_key_complete(
+            &self,
+            result: Result<(), ErrorCode>,
+            _key: &'static mut [u8; 8],
+            _value: &'static mut [u8],
+        ) {
+            self.appended.set(Some(result));
+        }
+
+        fn get_value_complete(
+            &self,
+            result: Result<(), ErrorCode>,
+            _key: &'static mut [u8; 8],
+            ret_buf: &'static mut [u8],
+        ) {
+            *self.read.borrow_mut() = Some((result, ret_buf.to_vec()));
+        }
+
+        fn invalidate_key_complete(
+            &self,
+            result: Result<(), ErrorCode>,
+            _key: &'static mut [u8; 8],
+        ) {
+            self.invalidated.set(Some(result));
+        }
+        fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {}
+        fn enumerate_complete(
+            &self,
+            _result: Result<usize, ErrorCode>,
+            _key: &'static mut [u8; 8],
+        ) {
+        }
+        fn statistics_complete(&self, _result: Result<Statistics, ErrorCode>) {}
+        fn start_transaction_complete(&self, result: Result<(), ErrorCode>) {
+            self.transaction.set(Some(result));
+        }
+        fn commit_transaction_complete(&self, result: Result<(), ErrorCode>) {
+            self.transaction.set(Some(result));
+        }
+        fn abort_transaction_complete(&self, result: Result<(), ErrorCode>) {
+            self.transaction.set(Some(result));
+        }
+    }
+
+    type Encryption = KVEncryption<
+        'static,
+        MockKV<'static>,
+        [u8; 8],
+        MockCCM<'static>,
+        MockRng<'static>,
+        [u8; AES128_KEY_SIZE],
+        Versions,
+    >;
+
+    struct Harness {
+        kv: &'static MockKV<'static>,
+        rng: &'static MockRng<'static>,
+        ccm: &'static MockCCM<'static>,
+        encryption: &'static Encryption,
+        client: &'static Client,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let kv = Box::leak(Box::new(MockKV::new(1, 0)));
+            let rng = Box::leak(Box::new(MockRng::new()));
+            let ccm = Box::leak(Box::new(MockCCM::new()));
+            let encryption = Box::leak(Box::new(KVEncryption::new(
+                kv,
+                ccm,
+                rng,
+                Box::leak(Box::new([0x5A; AES128_KEY_SIZE])),
+                Box::leak(Box::new(Versions::default())),
+                vec![0; record_length(8, VALUE.len())].leak(),
+            )));
+            kv.set_client(encryption);
+            ccm.set_client(encryption);
+            rng.set_client(encryption);
+            let client = Box::leak(Box::new(Client::default()));
+            encryption.set_client(client);
+
+            Harness {
+                kv,
+                rng,
+                ccm,
+                encryption,
+                client,
+            }
+        }
+
+        fn append(&self, key: [u8; 8], value: &[u8]) -> Result<(), ErrorCode> {
+            self.encryption
+                .append_key(Box::leak(Box::new(key)), value.to_vec().leak())
+                .map_err(|(_, _, e)| e.unwrap_err())?;
+            self.rng.supply();
+            self.ccm.complete();
+            self.kv.complete();
+            self.client.appended.take().unwrap()
+        }
+
+        fn get(&self, key: [u8; 8]) -> (Result<(), ErrorCode>, Vec<u8>) {
+            self.encryption
+                .get_value(Box::leak(Box::new(key)), vec![0xFF; VALUE.len()].leak())
+                .map_err(|(_, _, e)| e)
+                .unwrap();
+            self.kv.complete();
+            self.ccm.complete();
+            self.client.read.take().unwrap()
+        }
+
+        fn invalidate(&self, key: [u8; 8]) -> Result<(), ErrorCode> {
+            self.encryption
+                .invalidate_key(Box::leak(Box::new(key)))
+                .map_err(|(_, e)| e.unwrap_err())?;
+            self.kv.complete();
+            self.client.invalidated.take().unwrap()
+        }
+
+        fn transaction(&self, start: fn(&Encryption) -> Result<(), ErrorCode>) {
+            start(self.encryption).unwrap();
+            self.kv.complete();
+            assert_eq!(self.client.transaction.take(), Some(Ok(())));
+        }
+    }
+
+    #[test]
+    fn stores_values_encrypted() {
+        let harness = Harness::new();
+        harness.append(KEY, &VALUE).unwrap();
+
+        // The key and header are stored in the clear, the value isn't
+        let layout = Layout::new(8, record_length(8, VALUE.len())).unwrap();
+        let record = harness.kv.record(KEY);
+        assert_eq!(record[..8], KEY);
+        assert_eq!(record[8..layout.version_offset], VALUE[..HEADER_LENGTH]);
+        assert_ne!(
+            record[layout.message_offset..layout.message_offset + 3],
+            VALUE[HEADER_LENGTH..]
+        );
+
+        assert_eq!(harness.get(KEY), (Ok(()), VALUE.to_vec()));
+    }
+
+    #[test]
+    fn rejects_tampered_records() {
+        let layout = Layout::new(8, record_length(8, VALUE.len())).unwrap();
+
+        // The write_id in the header, the version, the nonce, the value and
+        // the MIC
+        for offset in [
+            8 + 5,
+            layout.version_offset,
+            layout.nonce_offset,
+            layout.message_offset,
+            layout.message_offset + layout.message_length,
+        ] {
+            let harness = Harness::new();
+            harness.append(KEY, &VALUE).unwrap();
+
+            let mut record = harness.kv.record(KEY);
+            record[offset] ^= 1;
+            harness.kv.set_record(KEY, record);
+
+            // Nothing that wasn't authenticated is returned
+            assert_eq!(
+                harness.get(KEY),
+                (Err(ErrorCode::FAIL), vec![0; VALUE.len()])
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_records_moved_to_another_key() {
+        const OTHER_KEY: [u8; 8] = [8, 7, 6, 5, 4, 3, 2, 1];
+        let harness = Harness::new();
+        harness.append(KEY, &VALUE).unwrap();
+        harness.append(OTHER_KEY, &VALUE[..HEADER_LENGTH]).unwrap();
+
+        // Swap the records, including the hashed keys stored in them
+        let record = harness.kv.record(KEY);
+        let other_record = harness.kv.record(OTHER_KEY);
+        harness.kv.set_record(KEY, other_record);
+        harness.kv.set_record(OTHER_KEY, record);
+
+        assert_eq!(harness.get(KEY).0, Err(ErrorCode::FAIL));
+        assert_eq!(harness.get(OTHER_KEY).0, Err(ErrorCode::FAIL));
+    }
+
+    #[test]
+    fn rejects_rolled_back_records() {
+        let harness = Harness::new();
+        harness.append(KEY, &VALUE).unwrap();
+        let old_record = harness.kv.record(KEY);
+        harness.append(KEY, &VALUE[..HEADER_LENGTH]).unwrap();
+
+        // The older record authenticates, but isn't the latest
+        harness.kv.set_record(KEY, old_record);
+        assert_eq!(
+            harness.get(KEY),
+            (Err(ErrorCode::FAIL), vec![0; VALUE.len()])
+        );
+    }
+
+    #[test]
+    fn rejects_deleted_records_written_back() {
+        let harness = Harness::new();
+        harness.append(KEY, &VALUE).unwrap();
+        let record = harness.kv.record(KEY);
+        harness.invalidate(KEY).unwrap();
+
+        harness.kv.set_record(KEY, record);
+        assert_eq!(harness.get(KEY).0, Err(ErrorCode::FAIL));
+
+        // A value written after the deletion is read
+        harness.append(KEY, &VALUE).unwrap();
+        assert_eq!(harness.get(KEY), (Ok(()), VALUE.to_vec()));
+    }
+
+    #[test]
+    fn keeps_versions_of_committed_transactions() {
+        let harness = Harness::new();
+        harness.append(KEY, &VALUE).unwrap();
+        let old_record = harness.kv.record(KEY);
+
+        // An aborted transaction rolls the record back, which isn't an attack
+        harness.transaction(Encryption::start_transaction);
+        harness.append(KEY, &VALUE[..HEADER_LENGTH]).unwrap();
+        harness.transaction(Encryption::abort_transaction);
+        harness.kv.set_record(KEY, old_record.clone());
+        assert_eq!(harness.get(KEY), (Ok(()), VALUE.to_vec()));
+
+        // Once a transaction is committed its values are the latest ones
+        harness.transaction(Encryption::start_transaction);
+        harness.append(KEY, &VALUE[..HEADER_LENGTH]).unwrap();
+        harness.transaction(Encryption::commit_transaction);
+        harness.kv.set_record(KEY, old_record);
+        assert_eq!(harness.get(KEY).0, Err(ErrorCode::FAIL));
+    }
+}
@@ -28,10 +28,32 @@
 //!
 //!    hil::flash
 //! ```
+//!
+//! Only the hashes of the keys are stored by the KV system, so the store
+//! keeps the first `KEY_PREFIX_LENGTH` bytes of each key in front of its
+//! value. This lets an app enumerate its keys that start with a prefix:
+//!
+//! ```text
+//! | header | prefix length | key prefix | value |
+//! ```
+//!
+//! Keys invalidated by deleting or replacing them still take up space until
+//! their region is garbage collected. The store does that on its own, one
+//! region at a time so that users' operations don't wait long. By default it
+//! collects when no user has an operation waiting, and a `GcPolicy` can also
+//! make it collect in between users' operations while the free space is
+//! low:
+//!
+//! ```rust,ignore
+//! mux_kv.set_gc_policy(GcPolicy {
+//!     when_idle: true,
+//!     free_threshold: 4096,
+//! });
+//! ```
 
 use core::cell::Cell;
 use kernel::collections::list::{List, ListLink, ListNode};
-use kernel::hil::kv_system::{self, KVSystem};
+use kernel::hil::kv_system::{self, KVSystem, Statistics};
 use kernel::storage_permissions::StoragePermissions;
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::ErrorCode;
@@ -41,10 +63,43 @@ enum Operation {
     Get,
     Set,
     Delete,
+    Enumerate,
+    Transaction,
+    /// Garbage collecting a region, which the mux does on its own
+    GarbageCollect,
+    /// Reading the statistics, which the mux does on its own
+    Statistics,
 }
 
-const HEADER_VERSION: u8 = 0;
-const HEADER_LENGTH: usize = 9;
+/// When `MuxKVStore` garbage collects without being asked to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GcPolicy {
+    /// Collect while no user has an operation waiting, once keys have been
+    /// invalidated
+    pub when_idle: bool,
+    /// Collect a region before each user's operation while fewer than this
+    /// many bytes are free. 0 never does.
+    pub free_threshold: usize,
+}
+
+impl GcPolicy {
+    /// Only collect while no user has an operation waiting
+    pub const IDLE: Self = Self {
+        when_idle: true,
+        free_threshold: 0,
+    };
+}
+
+/// The version of the headers written. Values with a version 0 header,
+/// written before key prefixes were stored, are still read.
+const HEADER_VERSION: u8 = 1;
+/// The length of the header stored in front of every value
+pub const HEADER_LENGTH: usize = 9;
+/// The number of bytes at the start of each key stored with its value, and
+/// so the longest prefix keys can be enumerated by
+pub const KEY_PREFIX_LENGTH: usize = 8;
+/// The length of the header and key prefix stored in front of every value
+pub const METADATA_LENGTH: usize = HEADER_LENGTH + 1 + KEY_PREFIX_LENGTH;
 
 /// This is the header used for KV stores
 struct KeyHeader {
@@ -63,16 +118,38 @@ impl KeyHeader {
         }
     }
 
-    /// Get the length of `KeyHeader`
-    fn len(&self) -> usize {
-        HEADER_LENGTH
+    /// Where the value starts in a buffer beginning with this header, or
+    /// `None` if the header isn't valid
+    fn value_offset(&self) -> Option<usize> {
+        match self.version {
+            0 => Some(HEADER_LENGTH),
+            HEADER_VERSION => Some(METADATA_LENGTH),
+            _ => None,
+        }
     }
 
-    /// Copy the header to `buf`
-    fn copy_to_buf(&self, buf: &mut [u8]) {
+    /// Whether the key prefix stored after this header in `buf` starts with
+    /// `prefix`. Version 0 headers have no key prefix after them, so only
+    /// match an empty prefix.
+    fn key_starts_with(&self, buf: &[u8], prefix: &[u8]) -> bool {
+        if self.version != HEADER_VERSION || buf.len() < METADATA_LENGTH {
+            return prefix.is_empty();
+        }
+        let length = (buf[HEADER_LENGTH] as usize).min(KEY_PREFIX_LENGTH);
+        buf[HEADER_LENGTH + 1..HEADER_LENGTH + 1 + length].starts_with(prefix)
+    }
+
+    /// Copy the header, followed by the prefix of `key`, to `buf`
+    fn copy_to_buf(&self, key: &[u8], buf: &mut [u8]) {
         buf[0] = self.version;
         buf[1..5].copy_from_slice(&self.length.to_le_bytes());
         buf[5..9].copy_from_slice(&self.write_id.to_le_bytes());
+
+        let prefix = &key[..key.len().min(KEY_PREFIX_LENGTH)];
+        buf[HEADER_LENGTH] = prefix.len() as u8;
+        let stored_prefix = &mut buf[HEADER_LENGTH + 1..METADATA_LENGTH];
+        stored_prefix.iter_mut().for_each(|b| *b = 0);
+        stored_prefix[..prefix.len()].copy_from_slice(prefix);
     }
 }
 
@@ -88,9 +165,16 @@ pub struct KVStore<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_sy
     unhashed_key: TakeCell<'static, [u8]>,
     value: TakeCell<'static, [u8]>,
     header_value: TakeCell<'static, [u8]>,
+    cursor: Cell<usize>,
+    /// The length of the prefix at the start of the key buffer that the
+    /// keys enumerated start with
+    prefix_length: Cell<usize>,
 
     valid_ids: OptionalCell<StoragePermissions>,
     next_valid_ids: OptionalCell<StoragePermissions>,
+
+    /// This user has a transaction open
+    in_transaction: Cell<bool>,
 }
 
 impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> ListNode<'a, KVStore<'a, K, T>>
@@ -105,7 +189,7 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> KVStore<'a, K, T> {
     pub fn new(
         mux_kv: &'a MuxKVStore<'a, K, T>,
         key: &'static mut T,
-        header_value: &'static mut [u8; HEADER_LENGTH],
+        header_value: &'static mut [u8; METADATA_LENGTH],
     ) -> KVStore<'a, K, T> {
         Self {
             mux_kv,
@@ -116,11 +200,20 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> KVStore<'a, K, T> {
             unhashed_key: TakeCell::empty(),
             value: TakeCell::empty(),
             header_value: TakeCell::new(header_value),
+            cursor: Cell::new(0),
+            prefix_length: Cell::new(0),
             valid_ids: OptionalCell::empty(),
             next_valid_ids: OptionalCell::empty(),
+            in_transaction: Cell::new(false),
         }
     }
 
+    /// Adds this user to the users of the mux, so that its operations are
+    /// queued while another user's run.
+    pub fn setup(&'a self) {
+        self.mux_kv.users.push_head(self);
+    }
+
     pub fn set_client(&self, client: &'a dyn kv_system::StoreClient<T>) {
         self.client.set(client);
     }
@@ -178,6 +271,10 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> KVStore<'a, K, T> {
         length: usize,
         perms: StoragePermissions,
     ) -> Result<(), (&'static mut [u8], &'static mut [u8], Result<(), ErrorCode>)> {
+        if !self.can_write() {
+            return Err((unhashed_key, value, Err(ErrorCode::BUSY)));
+        }
+
         let write_id = match perms.get_write_id() {
             Some(write_id) => write_id,
             None => return Err((unhashed_key, value, Err(ErrorCode::INVAL))),
@@ -189,13 +286,13 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> KVStore<'a, K, T> {
             length: length as u32,
             write_id,
         };
-        if length + header.len() > value.len() {
+        if length + METADATA_LENGTH > value.len() {
             return Err((unhashed_key, value, Err(ErrorCode::SIZE)));
         }
 
-        // Move the value to make space for the header
-        value.copy_within(0..length, header.len());
-        header.copy_to_buf(value);
+        // Move the value to make space for the header and key prefix
+        value.copy_within(0..length, METADATA_LENGTH);
+        header.copy_to_buf(unhashed_key, value);
 
         if self.mux_kv.operation.is_none() {
             // Make sure we have the hashed_key buffer
@@ -239,6 +336,10 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> KVStore<'a, K, T> {
         unhashed_key: &'static mut [u8],
         perms: StoragePermissions,
     ) -> Result<(), (&'static mut [u8], Result<(), ErrorCode>)> {
+        if !self.can_write() {
+            return Err((unhashed_key, Err(ErrorCode::BUSY)));
+        }
+
         if self.mux_kv.operation.is_none() {
             if self.hashed_key.is_none() {
                 return Err((unhashed_key, Err(ErrorCode::NOMEM)));
@@ -276,6 +377,177 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType> KVStore<'a, K, T> {
             }
         }
     }
+
+    /// Finds the first key stored at or after `cursor` that was written with
+    /// the write ID of `perms`, so that an app only finds its own keys, and
+    /// that starts with the first `prefix_length` bytes of `key`.
+    ///
+    /// The hashed key found is copied to `key`, and `enumerate_complete()`
+    /// returns the cursor to find the next key with. `NOSUPPORT` is reported
+    /// when there are no more keys.
+    ///
+    /// Prefixes can be up to `KEY_PREFIX_LENGTH` bytes long. Values written
+    /// before key prefixes were stored only match an empty prefix.
+    pub fn enumerate(
+        &self,
+        cursor: usize,
+        key: &'static mut [u8],
+        prefix_length: usize,
+        perms: StoragePermissions,
+    ) -> Result<(), (&'static mut [u8], Result<(), ErrorCode>)> {
+        if perms.get_write_id().is_none() {
+            return Err((key, Err(ErrorCode::INVAL)));
+        }
+        if prefix_length > KEY_PREFIX_LENGTH || prefix_length > key.len() {
+            return Err((key, Err(ErrorCode::SIZE)));
+        }
+
+        if self.mux_kv.operation.is_none() {
+            let hashed_key = match self.hashed_key.take() {
+                Some(hashed_key) => hashed_key,
+                None => return Err((key, Err(ErrorCode::NOMEM))),
+            };
+
+            self.valid_ids.set(perms);
+            self.prefix_length.set(prefix_length);
+
+            self.mux_kv.operation.set(Operation::Enumerate);
+
+            if let Err((hashed_key, e)) = self.mux_kv.kv.enumerate(cursor, hashed_key) {
+                self.hashed_key.replace(hashed_key);
+                self.mux_kv.operation.clear();
+                return Err((key, e));
+            }
+
+            // The key buffer is kept where the other operations keep the
+            // unhashed key
+            self.unhashed_key.replace(key);
+            Ok(())
+        } else {
+            // Another app is already running, queue this app as long as we
+            // don't already have data queued.
+            if self.next_operation.is_none() {
+                self.next_operation.set(Operation::Enumerate);
+                self.unhashed_key.replace(key);
+                self.cursor.set(cursor);
+                self.prefix_length.set(prefix_length);
+                self.next_valid_ids.set(perms);
+
+                Ok(())
+            } else {
+                Err((key, Err(ErrorCode::BUSY)))
+            }
+        }
+    }
+
+    /// Starts a transaction. Until it is committed with
+    /// `commit_transaction()` or aborted with `abort_transaction()`, the keys
+    /// set and deleted by this user are only changed when the transaction
+    /// commits, all of them at once. Other users can't set or delete keys
+    /// while the transaction is open.
+    ///
+    /// Unlike the other operations transactions aren't queued, `BUSY` is
+    /// returned while another operation is in progress.
+    pub fn start_transaction(&self) -> Result<(), ErrorCode> {
+        if self.in_transaction.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        if self.mux_kv.transaction.get() || self.mux_kv.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.mux_kv.operation.set(Operation::Transaction);
+        self.mux_kv.kv.start_transaction().map_err(|e| {
+            self.mux_kv.operation.clear();
+            e
+        })
+    }
+
+    /// Commits the transaction opened by `start_transaction()`.
+    pub fn commit_transaction(&self) -> Result<(), ErrorCode> {
+        self.end_transaction(|kv| kv.commit_transaction())
+    }
+
+    /// Aborts the transaction opened by `start_transaction()`, leaving the
+    /// keys it set and deleted unchanged.
+    pub fn abort_transaction(&self) -> Result<(), ErrorCode> {
+        self.end_transaction(|kv| kv.abort_transaction())
+    }
+
+    fn end_transaction(
+        &self,
+        op: impl FnOnce(&K) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        if !self.in_transaction.get() {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.mux_kv.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.mux_kv.operation.set(Operation::Transaction);
+        op(self.mux_kv.kv).map_err(|e| {
+            self.mux_kv.operation.clear();
+            e
+        })
+    }
+
+    /// Keys can't be changed while another user has a transaction open.
+    fn can_write(&self) -> bool {
+        !self.mux_kv.transaction.get() || self.in_transaction.get()
+    }
+
+    /// Report the end of the transaction to the client with `complete`.
+    fn transaction_ended(&self, complete: impl FnOnce(&dyn kv_system::StoreClient<T>)) {
+        if self.mux_kv.operation.contains(&Operation::Transaction) {
+            self.in_transaction.set(false);
+            self.mux_kv.transaction.set(false);
+            self.mux_kv.operation.clear();
+            self.client.map(|cb| complete(*cb));
+
+            // Committing can invalidate keys
+            self.mux_kv.perform_cleanup.set(true);
+        }
+
+        self.mux_kv.do_next_op();
+    }
+
+    /// Read the header of the key found while enumerating, to check who
+    /// wrote it.
+    fn read_enumerated_header(&self, key: &'static mut T) {
+        match self.header_value.take() {
+            Some(header_value) => {
+                // Make sure a failed read doesn't leave a valid header
+                header_value.iter_mut().for_each(|m| *m = 0xFF);
+
+                if let Err((key, header_value, e)) = self.mux_kv.kv.get_value(key, header_value) {
+                    self.hashed_key.replace(key);
+                    self.header_value.replace(header_value);
+                    self.complete_enumerate(e.and(Err(ErrorCode::FAIL)));
+                }
+            }
+            None => {
+                self.hashed_key.replace(key);
+                self.complete_enumerate(Err(ErrorCode::NOMEM));
+            }
+        }
+    }
+
+    fn complete_enumerate(&self, result: Result<usize, ErrorCode>) {
+        self.unhashed_key.take().map(|key| {
+            if result.is_ok() {
+                self.hashed_key.map(|hashed_key| {
+                    let len = key.len().min(hashed_key.as_ref().len());
+                    key[..len].copy_from_slice(&hashed_key.as_ref()[..len]);
+                });
+            }
+
+            self.client.map(move |cb| {
+                cb.enumerate_complete(result, key);
+            });
+        });
+        self.mux_kv.operation.clear();
+    }
 }
 
 impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_system::Client<T>
@@ -313,6 +585,10 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
                             cb.delete_complete(result, unhashed_key);
                         });
                     }
+                    Operation::Enumerate
+                    | Operation::Transaction
+                    | Operation::GarbageCollect
+                    | Operation::Statistics => {}
                 });
             } else {
                 match op {
@@ -358,6 +634,10 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
                             }
                         });
                     }
+                    Operation::Enumerate
+                    | Operation::Transaction
+                    | Operation::GarbageCollect
+                    | Operation::Statistics => {}
                 }
             }
         });
@@ -375,8 +655,16 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
         self.value.replace(value);
 
         self.mux_kv.operation.map(|op| match op {
-            Operation::Get | Operation::Delete => {}
+            Operation::Get
+            | Operation::Delete
+            | Operation::Enumerate
+            | Operation::Transaction
+            | Operation::GarbageCollect
+            | Operation::Statistics => {}
             Operation::Set => {
+                if result.is_ok() {
+                    self.value.map(|value| self.mux_kv.allocated(value.len()));
+                }
                 self.unhashed_key.take().map(|unhashed_key| {
                     self.value.take().map(|value| {
                         self.client.map(move |cb| {
@@ -400,13 +688,16 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
         self.hashed_key.replace(key);
 
         self.mux_kv.operation.map(|op| match op {
-            Operation::Set => {}
+            Operation::Set
+            | Operation::Transaction
+            | Operation::GarbageCollect
+            | Operation::Statistics => {}
             Operation::Delete => {
                 let mut access_allowed = false;
 
                 let header = KeyHeader::new_from_buf(ret_buf);
 
-                if header.version == HEADER_VERSION {
+                if header.value_offset().is_some() {
                     self.valid_ids.map(|perms| {
                         access_allowed = perms.check_write_permission(header.write_id);
                     });
@@ -433,22 +724,51 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
                     });
                 }
             }
+            Operation::Enumerate => {
+                let mut owned = false;
+
+                let header = KeyHeader::new_from_buf(ret_buf);
+
+                if header.value_offset().is_some() {
+                    self.valid_ids.map(|perms| {
+                        owned = perms.get_write_id() == Some(header.write_id);
+                    });
+                }
+
+                // The key buffer holds the prefix the key has to start with
+                owned &= self.unhashed_key.map_or(false, |key| {
+                    header.key_starts_with(ret_buf, &key[..self.prefix_length.get()])
+                });
+
+                self.header_value.replace(ret_buf);
+
+                if owned {
+                    self.complete_enumerate(Ok(self.cursor.get()));
+                } else {
+                    // Someone else wrote this key, look for the next one
+                    self.hashed_key.take().map(|hashed_key| {
+                        if let Err((key, e)) =
+                            self.mux_kv.kv.enumerate(self.cursor.get(), hashed_key)
+                        {
+                            self.hashed_key.replace(key);
+                            self.complete_enumerate(e.and(Err(ErrorCode::FAIL)));
+                        }
+                    });
+                }
+            }
             Operation::Get => {
                 let mut read_allowed = false;
 
                 if result.is_ok() {
                     let header = KeyHeader::new_from_buf(ret_buf);
 
-                    if header.version == HEADER_VERSION {
+                    if let Some(offset) = header.value_offset() {
                         self.valid_ids.map(|perms| {
                             read_allowed = perms.check_read_permission(header.write_id);
                         });
 
                         if read_allowed {
-                            ret_buf.copy_within(
-                                HEADER_LENGTH..(HEADER_LENGTH + header.length as usize),
-                                0,
-                            );
+                            ret_buf.copy_within(offset..(offset + header.length as usize), 0);
                         }
                     }
                 }
@@ -480,7 +800,12 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
         self.hashed_key.replace(key);
 
         self.mux_kv.operation.map(|op| match op {
-            Operation::Set | Operation::Get => {}
+            Operation::Set
+            | Operation::Get
+            | Operation::Enumerate
+            | Operation::Transaction
+            | Operation::GarbageCollect
+            | Operation::Statistics => {}
             Operation::Delete => {
                 self.unhashed_key.take().map(|unhashed_key| {
                     self.client.map(move |cb| {
@@ -495,17 +820,76 @@ impl<'a, K: KVSystem<'a, K = T>, T: kv_system::KeyType + core::fmt::Debug> kv_sy
         self.mux_kv.do_next_op();
     }
 
-    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {
-        self.mux_kv.perform_cleanup.set(false);
+    fn garbage_collect_complete(&self, result: Result<(), ErrorCode>) {
+        self.mux_kv.collect_done(result);
+    }
+
+    fn statistics_complete(&self, result: Result<Statistics, ErrorCode>) {
+        self.mux_kv.statistics_done(result);
+    }
+
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut T) {
+        if self.mux_kv.operation.contains(&Operation::Enumerate) {
+            match result {
+                Ok(cursor) => {
+                    self.cursor.set(cursor);
+                    self.read_enumerated_header(key);
+                }
+                Err(e) => {
+                    self.hashed_key.replace(key);
+                    self.complete_enumerate(Err(e));
+                }
+            }
+        } else {
+            self.hashed_key.replace(key);
+        }
+
         self.mux_kv.do_next_op();
     }
+
+    fn start_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        if self.mux_kv.operation.contains(&Operation::Transaction) {
+            if result.is_ok() {
+                self.in_transaction.set(true);
+                self.mux_kv.transaction.set(true);
+            }
+            self.mux_kv.operation.clear();
+            self.client.map(|cb| {
+                cb.start_transaction_complete(result);
+            });
+        }
+
+        self.mux_kv.do_next_op();
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        self.transaction_ended(|cb| cb.commit_transaction_complete(result));
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        self.transaction_ended(|cb| cb.abort_transaction_complete(result));
+    }
 }
 
 pub struct MuxKVStore<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_system::KeyType> {
     kv: &'a K,
     operation: OptionalCell<Operation>,
+    /// Keys have been invalidated since the last garbage collection started
     perform_cleanup: Cell<bool>,
+    /// A user has a transaction open
+    transaction: Cell<bool>,
     users: List<'a, KVStore<'a, K, T>>,
+
+    gc_policy: Cell<GcPolicy>,
+    /// The region to garbage collect next, 0 when no collection is in
+    /// progress
+    gc_region: Cell<usize>,
+    /// The number of regions, 0 until the statistics have been read
+    regions: Cell<usize>,
+    /// The free bytes, from the last statistics less the values set since
+    free: OptionalCell<usize>,
+    /// The last operation was garbage collecting
+    collected_last: Cell<bool>,
 }
 
 impl<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_system::KeyType>
@@ -516,8 +900,113 @@ impl<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_system::KeyType>
             kv,
             operation: OptionalCell::empty(),
             perform_cleanup: Cell::new(false),
+            transaction: Cell::new(false),
             users: List::new(),
+            gc_policy: Cell::new(GcPolicy::IDLE),
+            gc_region: Cell::new(0),
+            regions: Cell::new(0),
+            free: OptionalCell::empty(),
+            collected_last: Cell::new(false),
+        }
+    }
+
+    /// Sets when the store garbage collects on its own.
+    pub fn set_gc_policy(&self, gc_policy: GcPolicy) {
+        self.gc_policy.set(gc_policy);
+    }
+
+    /// There are invalidated keys that garbage collection might free, and
+    /// no transaction whose keys it could discard is open.
+    fn collecting(&self) -> bool {
+        (self.perform_cleanup.get() || self.gc_region.get() != 0) && !self.transaction.get()
+    }
+
+    fn free_space_low(&self) -> bool {
+        self.collecting()
+            && self
+                .free
+                .map_or(false, |free| *free < self.gc_policy.get().free_threshold)
+    }
+
+    /// A value of `length` bytes has been set.
+    fn allocated(&self, length: usize) {
+        if let Some(free) = self.free.extract() {
+            self.free.set(free.saturating_sub(length));
+        }
+    }
+
+    /// Start garbage collecting the next region.
+    ///
+    /// Returns false if nothing was started.
+    fn collect_step(&self) -> bool {
+        if self.regions.get() == 0 {
+            // The statistics say how many regions there are
+            return self.read_statistics();
         }
+
+        if self.gc_region.get() == 0 {
+            // Keys invalidated from now on need another collection
+            self.perform_cleanup.set(false);
+        }
+        self.operation.set(Operation::GarbageCollect);
+        self.collected_last.set(true);
+        if self
+            .kv
+            .garbage_collect_region(self.gc_region.get())
+            .is_err()
+        {
+            self.operation.clear();
+            self.gc_region.set(0);
+            return false;
+        }
+        true
+    }
+
+    fn collect_done(&self, result: Result<(), ErrorCode>) {
+        if self.operation.contains(&Operation::GarbageCollect) {
+            self.operation.clear();
+
+            let next = self.gc_region.get() + 1;
+            if next >= self.regions.get() || result.is_err() {
+                // Read the statistics again to find what was freed
+                self.gc_region.set(0);
+                self.free.clear();
+            } else {
+                self.gc_region.set(next);
+            }
+        }
+
+        self.do_next_op();
+    }
+
+    /// Returns false if the statistics couldn't be read.
+    fn read_statistics(&self) -> bool {
+        self.operation.set(Operation::Statistics);
+        if self.kv.statistics().is_err() {
+            self.operation.clear();
+            return false;
+        }
+        true
+    }
+
+    fn statistics_done(&self, result: Result<Statistics, ErrorCode>) {
+        if self.operation.contains(&Operation::Statistics) {
+            self.operation.clear();
+
+            match result {
+                Ok(statistics) => {
+                    self.regions.set(statistics.regions.max(1));
+                    self.free.set(statistics.free);
+                }
+                Err(_) => {
+                    // Don't try again until more keys are invalidated
+                    self.perform_cleanup.set(false);
+                    self.free.set(usize::MAX);
+                }
+            }
+        }
+
+        self.do_next_op();
     }
 
     fn do_next_op(&self) {
@@ -525,10 +1014,17 @@ impl<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_system::KeyType>
             return;
         }
 
+        // While there is little free space, collect a region in between
+        // users' operations
+        if self.free_space_low() && !self.collected_last.get() && self.collect_step() {
+            return;
+        }
+        self.collected_last.set(false);
+
         let mnode = self.users.iter().find(|node| node.next_operation.is_some());
 
         let ret = mnode.map_or(Err(ErrorCode::NODEVICE), |node| {
-            node.next_operation.map(|op| {
+            node.next_operation.take().map(|op| {
                 self.operation.set(op.clone());
 
                 node.unhashed_key.take().map(|unhashed_key| {
@@ -571,6 +1067,29 @@ impl<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_system::KeyType>
                                     });
                                 }
                             }
+                            Operation::Enumerate => {
+                                node.valid_ids.insert(node.next_valid_ids.take());
+                                node.next_valid_ids.clear();
+
+                                if let Err((hashed_key, e)) =
+                                    self.kv.enumerate(node.cursor.get(), hashed_key)
+                                {
+                                    node.hashed_key.replace(hashed_key);
+                                    node.client.map(move |cb| {
+                                        cb.enumerate_complete(
+                                            e.and(Err(ErrorCode::FAIL)),
+                                            unhashed_key,
+                                        );
+                                    });
+                                } else {
+                                    node.unhashed_key.replace(unhashed_key);
+                                }
+                            }
+                            // Transactions aren't queued, and the mux
+                            // collects on its own
+                            Operation::Transaction
+                            | Operation::GarbageCollect
+                            | Operation::Statistics => {}
                         };
                     });
                 });
@@ -578,11 +1097,155 @@ impl<'a, K: KVSystem<'a> + KVSystem<'a, K = T>, T: 'static + kv_system::KeyType>
             Ok(())
         });
 
-        // If we have nothing scheduled, run a garbage collect
-        if ret == Err(ErrorCode::NODEVICE) && self.perform_cleanup.get() {
-            // We have no way to report this error, and even if we could, what
-            // would a user do?
-            let _ = self.kv.garbage_collect();
+        // If we have nothing scheduled, garbage collect in the background.
+        // There is no way to report errors, and even if there was, what
+        // would a user do?
+        if ret == Err(ErrorCode::NODEVICE) {
+            let gc_policy = self.gc_policy.get();
+            if self.collecting() && (gc_policy.when_idle || self.free_space_low()) {
+                self.collect_step();
+            } else if self.free.is_none() && gc_policy.free_threshold > 0 && !self.transaction.get()
+            {
+                self.read_statistics();
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_flash::SimFlash;
+    use crate::testing::tickv::{self, new_flash, Client, SimTicKV, REGION_SIZE};
+    use std::boxed::Box;
+
+    const REGIONS: usize = 4;
+
+    type TestStore = KVStore<'static, SimTicKV, [u8; 8]>;
+
+    /// Starts TicKV on `flash` and invalidates a key in it, so that a region
+    /// can be collected.
+    fn invalidated_key(flash: &'static SimFlash<'static, REGION_SIZE>) -> &'static SimTicKV {
+        let client = Client::new();
+        let kv = tickv::start(flash, client);
+        let key = client.key.take().unwrap();
+        *key = [1; 8];
+        assert!(kv.append_key(key, client.value.take().unwrap()).is_ok());
+        flash.run();
+        assert_eq!(client.result.take(), Some(Ok(())));
+        assert!(kv.invalidate_key(client.key.take().unwrap()).is_ok());
+        flash.run();
+        assert_eq!(client.result.take(), Some(Ok(())));
+        kv
+    }
+
+    /// Sets up a store on `kv` over `flash`, and commits an empty
+    /// transaction so that the store expects keys to have been invalidated.
+    fn commit_transaction(
+        flash: &'static SimFlash<'static, REGION_SIZE>,
+        kv: &'static SimTicKV,
+        gc_policy: GcPolicy,
+    ) -> &'static TestStore {
+        let mux = Box::leak(Box::new(MuxKVStore::new(kv)));
+        mux.set_gc_policy(gc_policy);
+        let store = Box::leak(Box::new(KVStore::new(
+            mux,
+            Box::leak(Box::new([0; 8])),
+            Box::leak(Box::new([0; METADATA_LENGTH])),
+        )));
+        store.setup();
+        kv.set_client(store);
+
+        store.start_transaction().unwrap();
+        flash.run();
+        store.commit_transaction().unwrap();
+        flash.run();
+        store
+    }
+
+    #[test]
+    fn stores_key_prefixes_in_front_of_values() {
+        let header = KeyHeader {
+            version: HEADER_VERSION,
+            length: 3,
+            write_id: 7,
+        };
+        let mut buf = [0xFF; METADATA_LENGTH + 3];
+        header.copy_to_buf(b"settings/volume", &mut buf);
+        assert_eq!(buf[HEADER_LENGTH], KEY_PREFIX_LENGTH as u8);
+        assert_eq!(&buf[HEADER_LENGTH + 1..METADATA_LENGTH], b"settings");
+
+        let header = KeyHeader::new_from_buf(&buf);
+        assert_eq!(header.value_offset(), Some(METADATA_LENGTH));
+        assert_eq!(header.write_id, 7);
+        assert!(header.key_starts_with(&buf, b""));
+        assert!(header.key_starts_with(&buf, b"set"));
+        assert!(header.key_starts_with(&buf, b"settings"));
+        assert!(!header.key_starts_with(&buf, b"sensors"));
+
+        // Short keys aren't matched by their padding
+        header.copy_to_buf(b"ab", &mut buf);
+        assert_eq!(buf[HEADER_LENGTH + 1..METADATA_LENGTH], *b"ab\0\0\0\0\0\0");
+        assert!(header.key_starts_with(&buf, b"ab"));
+        assert!(!header.key_starts_with(&buf, b"ab\0"));
+    }
+
+    #[test]
+    fn reads_values_without_key_prefixes() {
+        // A version 0 header, followed by the value
+        let buf = [0, 3, 0, 0, 0, 7, 0, 0, 0, 0xAA, 0xBB, 0xCC];
+        let header = KeyHeader::new_from_buf(&buf);
+        assert_eq!(header.value_offset(), Some(HEADER_LENGTH));
+        assert!(header.key_starts_with(&buf, b""));
+        assert!(!header.key_starts_with(&buf, &[0xAA]));
+
+        // Unknown versions aren't read at all
+        let header = KeyHeader::new_from_buf(&[2; METADATA_LENGTH]);
+        assert_eq!(header.value_offset(), None);
+    }
+
+    /// The number of erases of the flash.
+    fn erases(flash: &SimFlash<'static, REGION_SIZE>) -> usize {
+        flash.counts().2
+    }
+
+    #[test]
+    fn collects_each_region_when_idle() {
+        let flash = new_flash(REGIONS);
+        let kv = invalidated_key(flash);
+        let erased = erases(flash);
+        let store = commit_transaction(flash, kv, GcPolicy::IDLE);
+
+        // The region holding the invalidated key is erased once the
+        // transaction is committed
+        assert_eq!(erases(flash), erased + 1);
+        assert!(!store.mux_kv.collecting());
+    }
+
+    #[test]
+    fn collects_while_free_space_is_low() {
+        // There is enough free space
+        let flash = new_flash(REGIONS);
+        let kv = invalidated_key(flash);
+        let erased = erases(flash);
+        let policy = GcPolicy {
+            when_idle: false,
+            free_threshold: 50,
+        };
+        let store = commit_transaction(flash, kv, policy);
+        assert_eq!(erases(flash), erased);
+        assert!(store.mux_kv.collecting());
+
+        // Less space is free than the threshold
+        let flash = new_flash(REGIONS);
+        let kv = invalidated_key(flash);
+        let erased = erases(flash);
+        let policy = GcPolicy {
+            when_idle: false,
+            free_threshold: REGIONS * REGION_SIZE,
+        };
+        let store = commit_transaction(flash, kv, policy);
+        assert_eq!(erases(flash), erased + 1);
+        assert!(!store.mux_kv.collecting());
+    }
+}
@@ -1,7 +1,13 @@
 #![forbid(unsafe_code)]
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 pub mod test;
+#[cfg(test)]
+mod testing;
 
 #[macro_use]
 pub mod net;
@@ -13,6 +19,7 @@ pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod ble;
 pub mod ble_advertising_driver;
 pub mod bme280;
 pub mod bmp280;
@@ -25,21 +32,29 @@ pub mod crc;
 pub mod ctap;
 pub mod dac;
 pub mod debug_process_restart;
+pub mod fat32;
+pub mod flash_fs;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
 pub mod gpio_async;
 pub mod hd44780;
+pub mod hid_keyboard;
+pub mod hid_mouse;
 pub mod hmac;
 pub mod hts221;
 pub mod humidity;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod isotp;
 pub mod kv_driver;
+pub mod kv_encryption;
 pub mod kv_store;
 pub mod l3gd20;
 pub mod led_matrix;
 pub mod log;
+pub mod log_driver;
+pub mod log_mux;
 pub mod lpm013m126;
 pub mod lps25hb;
 pub mod lsm303agr;
@@ -71,6 +86,7 @@ pub mod sha;
 pub mod sha256;
 pub mod sht3x;
 pub mod si7021;
+pub mod sim_flash;
 pub mod sip_hash;
 pub mod sound_pressure;
 pub mod st77xx;
@@ -80,6 +96,7 @@ pub mod temperature_rp2040;
 pub mod temperature_stm;
 pub mod text_screen;
 pub mod tickv;
+pub mod timestamped_log;
 pub mod touch;
 pub mod tsl2561;
 pub mod usb;
@@ -67,7 +67,6 @@
 //! ```
 
 use core::cell::Cell;
-use core::convert::TryFrom;
 use core::mem::size_of;
 use core::unreachable;
 
@@ -90,6 +89,33 @@ pub const ENTRY_HEADER_SIZE: usize = size_of::<usize>();
 /// Byte used to pad the end of a page.
 const PAD_BYTE: u8 = 0xFF;
 
+/// The memory a log reads its entries from. This is the flash the log writes
+/// to, as it is mapped into the address space.
+pub trait LogVolume {
+    /// The address of the start of the volume, from which the numbers of the
+    /// flash pages of the volume are found.
+    fn address(&self) -> usize;
+    /// The length of the volume in bytes.
+    fn len(&self) -> usize;
+    /// Copies the bytes of the volume from `offset` into `buf`.
+    fn read(&self, offset: usize, buf: &mut [u8]);
+}
+
+/// A volume declared with `storage_volume!`, which is read directly.
+impl LogVolume for &'static [u8] {
+    fn address(&self) -> usize {
+        self.as_ptr() as usize
+    }
+
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self[offset..offset + buf.len()]);
+    }
+}
+
 /// Log state keeps track of any in-progress asynchronous operations.
 #[derive(Clone, Copy, PartialEq)]
 enum State {
@@ -101,9 +127,9 @@ enum State {
     Erase,
 }
 
-pub struct Log<'a, F: Flash + 'static> {
+pub struct Log<'a, F: Flash + 'static, V: LogVolume = &'static [u8]> {
     /// Underlying storage volume.
-    volume: &'static [u8],
+    volume: V,
     /// Capacity of log in bytes.
     capacity: usize,
     /// Flash interface.
@@ -144,18 +170,18 @@ pub struct Log<'a, F: Flash + 'static> {
     error: Cell<Result<(), ErrorCode>>,
 }
 
-impl<'a, F: Flash + 'static> Log<'a, F> {
+impl<'a, F: Flash + 'static, V: LogVolume> Log<'a, F, V> {
     pub fn new(
-        volume: &'static [u8],
+        volume: V,
         driver: &'a F,
         pagebuffer: &'static mut F::Page,
         deferred_caller: &'a DynamicDeferredCall,
         circular: bool,
-    ) -> Log<'a, F> {
+    ) -> Log<'a, F, V> {
         let page_size = pagebuffer.as_mut().len();
         let capacity = volume.len() - PAGE_HEADER_SIZE * (volume.len() / page_size);
 
-        let log: Log<'a, F> = Log {
+        let log: Log<'a, F, V> = Log {
             volume,
             capacity,
             driver,
@@ -182,33 +208,34 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
 
     /// Returns the page number of the page containing the entry with the given ID.
     fn page_number(&self, entry_id: EntryID) -> usize {
-        (self.volume.as_ptr() as usize + entry_id % self.volume.len()) / self.page_size
+        (self.volume.address() + entry_id % self.volume.len()) / self.page_size
     }
 
-    /// Gets the buffer containing the byte at the given position in the log.
-    fn get_buffer<'b>(&self, pos: usize, pagebuffer: &'b mut F::Page) -> &'b [u8] {
+    /// Whether the byte at the given position in the log is in the pagebuffer rather than in
+    /// the volume.
+    fn in_pagebuffer(&self, pos: usize) -> bool {
         // Subtract 1 from append entry ID to get position of last bit written. This is needed
         // because the pagebuffer always contains the last written bit, but not necessarily the
         // position represented by the append entry ID (i.e. the pagebuffer isn't flushed yet when
         // `append_entry_id % page_size == 0`).
-        if pos / self.page_size == (self.append_entry_id.get() - 1) / self.page_size {
-            pagebuffer.as_mut()
-        } else {
-            self.volume
-        }
+        pos / self.page_size == (self.append_entry_id.get() - 1) / self.page_size
     }
 
     /// Gets the byte at the given position in the log.
     fn get_byte(&self, pos: usize, pagebuffer: &mut F::Page) -> u8 {
-        let buffer = self.get_buffer(pos, pagebuffer);
-        buffer[pos % buffer.len()]
+        let mut byte = [0];
+        self.get_bytes(pos, &mut byte, pagebuffer);
+        byte[0]
     }
 
-    /// Gets a `num_bytes` long slice of bytes starting from a position within the log.
-    fn get_bytes<'b>(&self, pos: usize, num_bytes: usize, pagebuffer: &'b mut F::Page) -> &'b [u8] {
-        let buffer = self.get_buffer(pos, pagebuffer);
-        let offset = pos % buffer.len();
-        &buffer[offset..offset + num_bytes]
+    /// Copies bytes starting from a position within the log into `buf`.
+    fn get_bytes(&self, pos: usize, buf: &mut [u8], pagebuffer: &mut F::Page) {
+        if self.in_pagebuffer(pos) {
+            let offset = pos % self.page_size;
+            buf.copy_from_slice(&pagebuffer.as_mut()[offset..offset + buf.len()]);
+        } else {
+            self.volume.read(pos % self.volume.len(), buf);
+        }
     }
 
     /// Resets a log back to an empty log. Returns whether or not the log was reset successfully.
@@ -232,9 +259,8 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         let mut newest_page_id: EntryID = 0;
         for header_pos in (0..self.volume.len()).step_by(self.page_size) {
             let page_id = {
-                const ID_SIZE: usize = size_of::<EntryID>();
-                let id_bytes = &self.volume[header_pos..header_pos + ID_SIZE];
-                let id_bytes = <[u8; ID_SIZE]>::try_from(id_bytes).unwrap();
+                let mut id_bytes = [0; size_of::<EntryID>()];
+                self.volume.read(header_pos, &mut id_bytes);
                 usize::from_ne_bytes(id_bytes)
             };
 
@@ -257,15 +283,16 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             loop {
                 // Check if next byte is start of valid entry.
                 let volume_offset = newest_page_id % self.volume.len() + last_page_len;
-                if self.volume[volume_offset] == 0 || self.volume[volume_offset] == PAD_BYTE {
+                let mut first_byte = [0];
+                self.volume.read(volume_offset, &mut first_byte);
+                if first_byte[0] == 0 || first_byte[0] == PAD_BYTE {
                     break;
                 }
 
                 // Get next entry length.
                 let entry_length = {
-                    const LENGTH_SIZE: usize = size_of::<usize>();
-                    let length_bytes = &self.volume[volume_offset..volume_offset + LENGTH_SIZE];
-                    let length_bytes = <[u8; LENGTH_SIZE]>::try_from(length_bytes).unwrap();
+                    let mut length_bytes = [0; size_of::<usize>()];
+                    self.volume.read(volume_offset, &mut length_bytes);
                     usize::from_ne_bytes(length_bytes)
                 } + ENTRY_HEADER_SIZE;
 
@@ -297,10 +324,10 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
                     }
                     if copy_pagebuffer {
                         // Copy last page into pagebuffer.
-                        for i in 0..self.page_size {
-                            pagebuffer.as_mut()[i] =
-                                self.volume[newest_page_id % self.volume.len() + i];
-                        }
+                        self.volume.read(
+                            newest_page_id % self.volume.len(),
+                            &mut pagebuffer.as_mut()[..self.page_size],
+                        );
                     }
                     self.pagebuffer.replace(pagebuffer);
                 })
@@ -348,9 +375,8 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             .take()
             .map_or(Err(Err(ErrorCode::RESERVE)), move |pagebuffer| {
                 // Get length.
-                const LENGTH_SIZE: usize = size_of::<usize>();
-                let length_bytes = self.get_bytes(entry_id, LENGTH_SIZE, pagebuffer);
-                let length_bytes = <[u8; LENGTH_SIZE]>::try_from(length_bytes).unwrap();
+                let mut length_bytes = [0; size_of::<usize>()];
+                self.get_bytes(entry_id, &mut length_bytes, pagebuffer);
                 let length = usize::from_ne_bytes(length_bytes);
 
                 // Return length of next entry.
@@ -386,10 +412,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
                 let entry_id = entry_id + ENTRY_HEADER_SIZE;
 
                 // Copy data into client buffer.
-                let data = self.get_bytes(entry_id, entry_length, pagebuffer);
-                for i in 0..entry_length {
-                    buffer[i] = data[i];
-                }
+                self.get_bytes(entry_id, &mut buffer[..entry_length], pagebuffer);
 
                 // Update read entry ID and return number of bytes read.
                 self.read_entry_id.set(entry_id + entry_length);
@@ -409,7 +432,8 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     }
 
     /// Appends data from a buffer onto the end of the log. Requires that there is enough space
-    /// remaining in the pagebuffer for the entry (including metadata).
+    /// remaining in the pagebuffer for the entry (including metadata). The caller makes the client
+    /// callback.
     fn append_entry(
         &self,
         buffer: &'static mut [u8],
@@ -439,7 +463,6 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         self.records_lost
             .set(self.oldest_entry_id.get() != PAGE_HEADER_SIZE);
         self.error.set(Ok(()));
-        self.client_callback();
     }
 
     /// Flushes the pagebuffer to flash. Log state must be non-idle before calling, else data races
@@ -460,11 +483,14 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         // padding pointer points to start of the page following the one we want to flush after the
         // padding operation.
         let page_number = self.page_number(pad_ptr - self.page_size);
-        let overwritten_page = (pad_ptr - self.volume.len() - self.page_size) / self.page_size;
+        // No log page is overwritten until the log has wrapped around the volume.
+        let overwritten_page = (pad_ptr - self.page_size)
+            .checked_sub(self.volume.len())
+            .map(|entry_id| entry_id / self.page_size);
 
         // Advance read and oldest entry IDs, if within flash page being overwritten.
         let read_entry_id = self.read_entry_id.get();
-        if read_entry_id / self.page_size == overwritten_page {
+        if Some(read_entry_id / self.page_size) == overwritten_page {
             // Move read entry ID to start of next page.
             self.read_entry_id.set(
                 read_entry_id + self.page_size + PAGE_HEADER_SIZE - read_entry_id % self.page_size,
@@ -472,7 +498,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
 
         let oldest_entry_id = self.oldest_entry_id.get();
-        if oldest_entry_id / self.page_size == overwritten_page {
+        if Some(oldest_entry_id / self.page_size) == overwritten_page {
             self.oldest_entry_id.set(oldest_entry_id + self.page_size);
         }
 
@@ -522,6 +548,12 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             .erase_page(self.page_number(self.oldest_entry_id.get()))
     }
 
+    /// Whether an operation is in progress.
+    #[cfg(test)]
+    pub(crate) fn busy(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+
     /// Initializes a callback handle for deferred callbacks.
     pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
         self.handle.replace(handle);
@@ -580,7 +612,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> LogRead<'a> for Log<'a, F> {
+impl<'a, F: Flash + 'static, V: LogVolume> LogRead<'a> for Log<'a, F, V> {
     type EntryID = EntryID;
 
     /// Set the client for read operation callbacks.
@@ -679,7 +711,7 @@ impl<'a, F: Flash + 'static> LogRead<'a> for Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
+impl<'a, F: Flash + 'static, V: LogVolume> LogWrite<'a> for Log<'a, F, V> {
     /// Set the client for append operation callbacks.
     fn set_append_client(&self, append_client: &'a dyn LogWriteClient) {
         self.append_client.set(append_client);
@@ -737,6 +769,7 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
                 if !flush_prev_page && entry_size <= space_remaining {
                     // Entry fits, append it.
                     self.append_entry(buffer, length, pagebuffer);
+                    self.deferred_client_callback();
                     Ok(())
                 } else {
                     // Need to sync pagebuffer first, then append to new page.
@@ -766,13 +799,18 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     /// Result<(), ErrorCode>s used in sync_done callback:
     ///     * Ok(()): append succeeded.
     ///     * FAIL: write failed due to flash error.
+    /// The sync_done callback is issued after every successful sync, including
+    /// when the pagebuffer is empty and there is nothing to flush.
     fn sync(&self) -> Result<(), ErrorCode> {
-        if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
-            // Pagebuffer empty, don't need to flush.
-            return Ok(());
-        } else if self.state.get() != State::Idle {
+        if self.state.get() != State::Idle {
             // Log busy, try appending again later.
             return Err(ErrorCode::BUSY);
+        } else if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
+            // Pagebuffer empty, don't need to flush, but still tell the client.
+            self.state.set(State::Sync);
+            self.error.set(Ok(()));
+            self.deferred_client_callback();
+            return Ok(());
         }
 
         self.pagebuffer
@@ -805,7 +843,7 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
+impl<'a, F: Flash + 'static, V: LogVolume> flash::Client<F> for Log<'a, F, V> {
     fn read_complete(&self, _read_buffer: &'static mut F::Page, _error: flash::Error) {
         // Reads are made directly from the storage volume, not through the flash interface.
         unreachable!();
@@ -826,6 +864,7 @@ impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
                                     self.append_entry(buffer, self.length.get(), pagebuffer);
                                 })
                                 .unwrap();
+                            self.client_callback();
                         } else {
                             self.pagebuffer.replace(pagebuffer);
                             self.length.set(0);
@@ -903,8 +942,215 @@ impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
     }
 }
 
-impl<'a, F: Flash + 'static> DynamicDeferredCallClient for Log<'a, F> {
+impl<'a, F: Flash + 'static, V: LogVolume> DynamicDeferredCallClient for Log<'a, F, V> {
     fn call(&self, _handle: DeferredCallHandle) {
         self.client_callback();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_flash::Fault;
+    use crate::testing::log::SimLog;
+    use core::cell::RefCell;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    // Each page holds four entries of four bytes.
+    const PAGE_SIZE: usize = 64;
+
+    struct Client {
+        /// The data and result of the last read
+        read: RefCell<Option<Result<Vec<u8>, ErrorCode>>>,
+        /// The records_lost and result of the last append
+        appended: Cell<Option<Result<bool, ErrorCode>>>,
+        synced: Cell<Option<Result<(), ErrorCode>>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl LogReadClient for Client {
+        fn read_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.read
+                .replace(Some(result.map(|()| buffer[..length].to_vec())));
+            self.buffer.replace(buffer);
+        }
+
+        fn seek_done(&self, _result: Result<(), ErrorCode>) {}
+    }
+
+    impl LogWriteClient for Client {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            _length: usize,
+            records_lost: bool,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.appended.set(Some(result.map(|()| records_lost)));
+            self.buffer.replace(buffer);
+        }
+
+        fn sync_done(&self, result: Result<(), ErrorCode>) {
+            self.synced.set(Some(result));
+        }
+
+        fn erase_done(&self, _result: Result<(), ErrorCode>) {}
+    }
+
+    struct Test {
+        log: SimLog<PAGE_SIZE>,
+        client: &'static Client,
+    }
+
+    impl Test {
+        fn new(pages: usize, circular: bool) -> Test {
+            Test::over(SimLog::new(pages, circular))
+        }
+
+        fn over(log: SimLog<PAGE_SIZE>) -> Test {
+            let client = Box::leak(Box::new(Client {
+                read: RefCell::new(None),
+                appended: Cell::new(None),
+                synced: Cell::new(None),
+                buffer: TakeCell::new(Box::leak(vec![0; 32].into_boxed_slice())),
+            }));
+            log.log.set_read_client(client);
+            log.log.set_append_client(client);
+            Test { log, client }
+        }
+
+        /// A new log over the flash, as after a reboot.
+        fn restart(&self, circular: bool) -> Test {
+            Test::over(self.log.restart(circular))
+        }
+
+        fn append(&self, data: &[u8]) -> Result<bool, ErrorCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            if let Err((error, buffer)) = self.log.log.append(buffer, data.len()) {
+                self.client.buffer.replace(buffer);
+                return Err(error);
+            }
+            // The client is called back once the append completes, not from
+            // within it.
+            assert_eq!(self.client.appended.get(), None);
+            self.log.run();
+            self.client.appended.take().unwrap()
+        }
+
+        fn sync(&self) -> Result<(), ErrorCode> {
+            self.log.log.sync()?;
+            self.log.run();
+            self.client.synced.take().unwrap()
+        }
+
+        fn read(&self) -> Result<Vec<u8>, ErrorCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            if let Err((error, buffer)) = self.log.log.read(buffer, 32) {
+                self.client.buffer.replace(buffer);
+                return Err(error);
+            }
+            self.log.run();
+            self.client.read.take().unwrap()
+        }
+    }
+
+    #[test]
+    fn entries_are_read_back_after_a_reboot() {
+        let test = Test::new(4, false);
+        assert_eq!(test.append(b"one"), Ok(false));
+        assert_eq!(test.append(b"two"), Ok(false));
+        assert_eq!(test.read().as_deref(), Ok(&b"one"[..]));
+        assert_eq!(test.read().as_deref(), Ok(&b"two"[..]));
+        assert_eq!(test.read(), Err(ErrorCode::FAIL));
+        assert_eq!(test.sync(), Ok(()));
+
+        let test = test.restart(false);
+        assert_eq!(test.log.log.log_start(), PAGE_HEADER_SIZE);
+        assert_eq!(test.read().as_deref(), Ok(&b"one"[..]));
+        assert_eq!(test.read().as_deref(), Ok(&b"two"[..]));
+        assert_eq!(test.read(), Err(ErrorCode::FAIL));
+
+        // Entries are appended after those found.
+        assert_eq!(test.append(b"three"), Ok(false));
+        assert_eq!(test.read().as_deref(), Ok(&b"three"[..]));
+    }
+
+    #[test]
+    fn syncing_an_empty_pagebuffer_calls_back() {
+        let test = Test::new(4, false);
+        assert_eq!(test.sync(), Ok(()));
+        assert_eq!(test.log.flash.counts(), (0, 0, 0));
+
+        assert_eq!(test.append(b"one"), Ok(false));
+        assert_eq!(test.sync(), Ok(()));
+        assert_eq!(test.log.flash.counts(), (0, 1, 0));
+    }
+
+    #[test]
+    fn linear_logs_fill_up() {
+        let test = Test::new(2, false);
+        for _ in 0..8 {
+            assert_eq!(test.append(b"1234"), Ok(false));
+        }
+        assert_eq!(test.append(b"1234"), Err(ErrorCode::FAIL));
+        assert_eq!(test.read().as_deref(), Ok(&b"1234"[..]));
+    }
+
+    #[test]
+    fn circular_logs_overwrite_the_oldest_page() {
+        let test = Test::new(2, true);
+        let entry = |index: u8| [b'a' + index; 4];
+        assert_eq!(test.append(&entry(0)), Ok(false));
+        assert_eq!(test.read().as_deref(), Ok(&entry(0)[..]));
+        // The third page is buffered over the first until it is full.
+        for index in 1..12 {
+            assert_eq!(test.append(&entry(index)), Ok(false));
+        }
+
+        // Writing the third page over the first moves the start of the log
+        // and the next entry to read to the second page.
+        assert_eq!(test.append(&entry(12)), Ok(true));
+        assert_eq!(test.log.log.log_start(), PAGE_SIZE + PAGE_HEADER_SIZE);
+        assert_eq!(test.read().as_deref(), Ok(&entry(4)[..]));
+
+        // Syncing writes the fourth page over the second.
+        assert_eq!(test.sync(), Ok(()));
+        let test = test.restart(true);
+        assert_eq!(test.log.log.log_start(), 2 * PAGE_SIZE + PAGE_HEADER_SIZE);
+        for index in 8..13 {
+            assert_eq!(test.read().as_deref(), Ok(&entry(index)[..]));
+        }
+        assert_eq!(test.read(), Err(ErrorCode::FAIL));
+    }
+
+    #[test]
+    fn torn_writes_keep_the_synced_entries() {
+        let test = Test::new(4, false);
+        assert_eq!(test.append(b"kept"), Ok(false));
+        assert_eq!(test.sync(), Ok(()));
+
+        // Power is lost part of the way through the header of the new entry
+        // as the page is written again.
+        assert_eq!(test.append(b"torn"), Ok(false));
+        let torn_at = PAGE_HEADER_SIZE + ENTRY_HEADER_SIZE + 4 + ENTRY_HEADER_SIZE / 2;
+        test.log.flash.inject(Fault::TornWrite(torn_at), 0);
+        assert!(test.log.log.sync().is_ok());
+        test.log.run();
+        assert!(!test.log.flash.powered());
+        assert_eq!(test.client.synced.take(), None);
+
+        let test = test.restart(false);
+        assert_eq!(test.read().as_deref(), Ok(&b"kept"[..]));
+        assert_eq!(test.read(), Err(ErrorCode::FAIL));
+        assert_eq!(test.append(b"next"), Ok(false));
+        assert_eq!(test.read().as_deref(), Ok(&b"next"[..]));
+    }
+}
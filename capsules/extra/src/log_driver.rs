@@ -0,0 +1,431 @@
+//! Provides userspace with a log of its own, in which to append and read
+//! back entries.
+//!
+//! The logs of all apps share the flash region of one `Log` through a
+//! `MuxLog`, and each app can keep up to a quota of bytes in it. An app's log
+//! is identified by the app's ShortID, or, for apps without a fixed ShortID,
+//! by the `write_id` of its persistent ACL, so the app finds its entries
+//! again after a reboot or an update. Logs found by ShortID and by `write_id`
+//! are kept apart, so a ShortID and a `write_id` with the same value are
+//! different logs. Apps identified by neither have no log.
+//!
+//! Entries are read in order from a position the driver keeps for each app,
+//! which starts at the oldest entry. Each read gives the position after the
+//! entry read, and seeking to a position given by a read, or to 0 for the
+//! oldest entry, reads from there again. Appends are not in flash until the
+//! log is synced or a flash page fills up.
+//!
+//! Appending, reading and syncing complete with an upcall of the status and,
+//! for appending, the length of the entry and whether old entries were
+//! overwritten, or, for reading, the length of the entry and the position
+//! after it. Reading fails with `FAIL` at the end of the log, and appending
+//! with `NOMEM` if the app is over its quota.
+//!
+//! Setup
+//! -----
+//!
+//! ```rust
+//! let log = components::log::LogComponent::new(
+//!     board_kernel,
+//!     capsules_extra::log_driver::DRIVER_NUM,
+//!     &peripherals.nvmc,
+//!     &LOG_VOLUME,
+//!     true,
+//!     1024,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::log_component_static!(nrf52840::nvmc::Nvmc));
+//! ```
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::log::{LogRead, LogWrite};
+use kernel::process::ShortID;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+use crate::log_mux::{MuxLog, MuxLogClient, Owner};
+
+/// Syscall driver number.
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Log as usize;
+
+/// The length of the buffer entries are moved through, which limits the
+/// size of an entry.
+pub const BUF_LEN: usize = 256;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// The entry to append
+    pub const DATA: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Receives the entry read
+    pub const BUFFER: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for subscribe upcalls
+mod upcall {
+    /// A command completed, or failed
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// The kinds of owners of the logs of apps
+mod owner_kind {
+    /// The app's fixed ShortID
+    pub const SHORT_ID: u8 = 1;
+    /// The `write_id` of the app's persistent ACL
+    pub const WRITE_ID: u8 = 2;
+}
+
+#[derive(Clone, Copy)]
+enum Command {
+    Append { len: usize },
+    Read,
+    Sync,
+}
+
+#[derive(Default)]
+pub struct App {
+    /// Where to read the next entry from
+    position: usize,
+    /// A command waiting for the log
+    pending: Option<Command>,
+}
+
+/// The identifier of the log of an app.
+fn owner(processid: ProcessId) -> Result<Owner, ErrorCode> {
+    owner_of(
+        processid.short_app_id(),
+        processid
+            .get_storage_permissions()
+            .and_then(|permissions| permissions.get_write_id()),
+    )
+}
+
+/// The identifier of the log of an app with `short_id`, and `write_id` if it
+/// has a persistent ACL.
+fn owner_of(short_id: ShortID, write_id: Option<u32>) -> Result<Owner, ErrorCode> {
+    match short_id {
+        ShortID::Fixed(id) => Ok(Owner {
+            kind: owner_kind::SHORT_ID,
+            id: id.get(),
+        }),
+        ShortID::LocallyUnique => write_id
+            .filter(|write_id| *write_id != 0)
+            .map(|write_id| Owner {
+                kind: owner_kind::WRITE_ID,
+                id: write_id,
+            })
+            .ok_or(ErrorCode::NOSUPPORT),
+    }
+}
+
+/// The arguments of the upcall for a command which completed with `result`:
+/// the status, and then the two values of the command, or 0 if it failed.
+fn upcall_args(result: Result<(usize, usize), ErrorCode>) -> (usize, usize, usize) {
+    match result {
+        Ok((value, extra)) => (kernel::errorcode::into_statuscode(Ok(())), value, extra),
+        Err(error) => (kernel::errorcode::into_statuscode(Err(error)), 0, 0),
+    }
+}
+
+pub struct LogDriver<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    mux: &'a MuxLog<'a, L>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The bytes of the log each app may have
+    quota: usize,
+    /// The app the log is working for, and its command
+    current: OptionalCell<(ProcessId, Command)>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogDriver<'a, L> {
+    pub fn new(
+        mux: &'a MuxLog<'a, L>,
+        apps: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+        quota: usize,
+        buffer: &'static mut [u8],
+    ) -> LogDriver<'a, L> {
+        LogDriver {
+            mux,
+            apps,
+            quota,
+            current: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    fn upcall(&self, processid: ProcessId, result: Result<(usize, usize), ErrorCode>) {
+        let _ = self.apps.enter(processid, |_, kernel_data| {
+            kernel_data
+                .schedule_upcall(upcall::DONE, upcall_args(result))
+                .ok();
+        });
+    }
+
+    fn start(&self, processid: ProcessId, command: Command) -> Result<(), ErrorCode> {
+        match command {
+            Command::Append { len } => {
+                let owner = owner(processid)?;
+                let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+                let copied = self
+                    .apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::DATA)
+                            .and_then(|data| {
+                                data.enter(|data| {
+                                    if len > data.len() || len > buffer.len() {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    data[..len].copy_to_slice(&mut buffer[..len]);
+                                    Ok(())
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    })
+                    .map_err(ErrorCode::from)
+                    .and_then(|result| result);
+                if let Err(error) = copied {
+                    self.buffer.replace(buffer);
+                    return Err(error);
+                }
+                self.mux
+                    .append(owner, self.quota, buffer, len)
+                    .map_err(|(error, buffer)| {
+                        self.buffer.replace(buffer);
+                        error
+                    })?;
+            }
+            Command::Read => {
+                let owner = owner(processid)?;
+                let position = self.apps.enter(processid, |app, _| app.position)?;
+                let buffer = self.buffer.take().ok_or(ErrorCode::BUSY)?;
+                self.mux
+                    .read(owner, position, buffer)
+                    .map_err(|(error, buffer)| {
+                        self.buffer.replace(buffer);
+                        error
+                    })?;
+            }
+            Command::Sync => {
+                owner(processid)?;
+                self.mux.sync()?;
+            }
+        }
+        self.current.set((processid, command));
+        Ok(())
+    }
+
+    fn enqueue(&self, processid: ProcessId, command: Command) -> CommandReturn {
+        if self.current.is_some() {
+            let queued = self.apps.enter(processid, |app, _| {
+                if app.pending.is_some() {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    app.pending = Some(command);
+                    Ok(())
+                }
+            });
+            match queued {
+                Ok(result) => CommandReturn::from(result),
+                Err(error) => CommandReturn::failure(error.into()),
+            }
+        } else {
+            CommandReturn::from(self.start(processid, command))
+        }
+    }
+
+    // Starts the next command an app is waiting to run.
+    fn run_next(&self) {
+        for cntr in self.apps.iter() {
+            let processid = cntr.processid();
+            let command = cntr.enter(|app, _| app.pending.take());
+            if let Some(command) = command {
+                match self.start(processid, command) {
+                    Ok(()) => return,
+                    Err(error) => self.upcall(processid, Err(error)),
+                }
+            }
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> MuxLogClient for LogDriver<'a, L> {
+    fn read_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        next: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        if let Some((processid, _)) = self.current.take() {
+            let result = self
+                .apps
+                .enter(processid, |app, kernel_data| {
+                    let copied = result.and_then(|()| {
+                        kernel_data
+                            .get_readwrite_processbuffer(rw_allow::BUFFER)
+                            .and_then(|app_buffer| {
+                                app_buffer.mut_enter(|app_buffer| {
+                                    if length > app_buffer.len() {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    app_buffer[..length].copy_from_slice(&buffer[..length]);
+                                    Ok(())
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    });
+                    // An entry that does not fit is read again next time.
+                    copied.map(|()| {
+                        app.position = next;
+                        (length, next)
+                    })
+                })
+                .unwrap_or_else(|error| Err(error.into()));
+            self.upcall(processid, result);
+        }
+        self.buffer.replace(buffer);
+        self.run_next();
+    }
+
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        if let Some((processid, _)) = self.current.take() {
+            self.upcall(processid, result.map(|()| (length, records_lost as usize)));
+        }
+        self.run_next();
+    }
+
+    fn sync_done(&self, result: Result<(), ErrorCode>) {
+        if let Some((processid, _)) = self.current.take() {
+            self.upcall(processid, result.map(|()| (0, 0)));
+        }
+        self.run_next();
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> SyscallDriver for LogDriver<'a, L> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Append the first `arg1` bytes of the `DATA` buffer as an entry.
+    /// - `2`: Read the next entry into the `BUFFER` buffer.
+    /// - `3`: Read from position `arg1`, or from the oldest entry if it is 0.
+    /// - `4`: Sync the log to flash.
+    /// - `5`: Return the bytes of the log each app may have.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        _arg2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => self.enqueue(processid, Command::Append { len: arg1 }),
+            2 => self.enqueue(processid, Command::Read),
+            3 => {
+                if arg1 > self.mux.log_end() {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                let result = self.apps.enter(processid, |app, _| app.position = arg1);
+                CommandReturn::from(result.map_err(ErrorCode::from))
+            }
+            4 => self.enqueue(processid, Command::Sync),
+            5 => CommandReturn::success_u32(self.quota as u32),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU32;
+
+    fn short_id(id: u32) -> ShortID {
+        ShortID::Fixed(NonZeroU32::new(id).unwrap())
+    }
+
+    #[test]
+    fn apps_are_found_by_short_id_first() {
+        assert_eq!(
+            owner_of(short_id(5), Some(7)),
+            Ok(Owner {
+                kind: owner_kind::SHORT_ID,
+                id: 5,
+            })
+        );
+        assert_eq!(
+            owner_of(ShortID::LocallyUnique, Some(7)),
+            Ok(Owner {
+                kind: owner_kind::WRITE_ID,
+                id: 7,
+            })
+        );
+    }
+
+    #[test]
+    fn short_ids_and_write_ids_are_different_logs() {
+        assert_ne!(
+            owner_of(short_id(7), None),
+            owner_of(ShortID::LocallyUnique, Some(7))
+        );
+    }
+
+    #[test]
+    fn apps_without_an_identifier_have_no_log() {
+        assert_eq!(
+            owner_of(ShortID::LocallyUnique, None),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        // A write_id of 0 does not identify an app.
+        assert_eq!(
+            owner_of(ShortID::LocallyUnique, Some(0)),
+            Err(ErrorCode::NOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn upcalls_carry_the_status_and_values() {
+        assert_eq!(upcall_args(Ok((12, 1))), (0, 12, 1));
+        assert_eq!(
+            upcall_args(Err(ErrorCode::NOMEM)),
+            (ErrorCode::NOMEM as usize, 0, 0)
+        );
+    }
+}
@@ -0,0 +1,725 @@
+//! Shares one log between several owners, so that the logs of several owners
+//! live in the flash region of a single `Log`.
+//!
+//! Each entry starts with the kind and identifier of its owner, and owners
+//! only read their own entries: the entries of other owners are skipped. Every owner has
+//! a quota of bytes of the log, counting the headers of its entries, and
+//! appending an entry which would take the owner over its quota fails with
+//! `NOMEM`.
+//!
+//! The bytes each owner has in the log are counted by reading the whole log
+//! before the first append, and again once a circular log has overwritten
+//! old entries. Up to `MAX_OWNERS` owners can be counted.
+//!
+//! Positions in the log are entry IDs of the underlying log. Reading from a
+//! position which is no longer in the log reads from the oldest entry.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let log_mux = static_init!(
+//!     capsules_extra::log_mux::MuxLog<'static, Log>,
+//!     capsules_extra::log_mux::MuxLog::new(log, &mut LOG_MUX_BUFFER)
+//! );
+//! log.set_read_client(log_mux);
+//! log.set_append_client(log_mux);
+//! log_mux.set_client(log_driver);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::log::ENTRY_HEADER_SIZE;
+
+/// The bytes at the start of each entry which identify its owner: the kind
+/// of the owner and then its identifier.
+pub const OWNER_SIZE: usize = 5;
+/// The number of owners whose use of the log can be counted.
+pub const MAX_OWNERS: usize = 8;
+
+/// Receives the results of the operations of a `MuxLog`.
+pub trait MuxLogClient {
+    /// The next entry of the owner was read into `buffer`, or failed with
+    /// `FAIL` at the end of the log, or `SIZE` if `buffer` is too small for
+    /// the entry. `next` is the position to read the owner's next entry from,
+    /// which is the entry that did not fit after `SIZE`.
+    fn read_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        next: usize,
+        result: Result<(), ErrorCode>,
+    );
+
+    /// An entry of `length` bytes was appended, or not. `records_lost` is
+    /// whether appending it overwrote old entries.
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        result: Result<(), ErrorCode>,
+    );
+
+    /// The log was synced to flash, or not.
+    fn sync_done(&self, result: Result<(), ErrorCode>);
+}
+
+/// The owner of entries. The kind is what the identifier is, so that owners
+/// identified in different ways cannot have the same identifier and share
+/// entries.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Owner {
+    pub kind: u8,
+    pub id: u32,
+}
+
+impl Owner {
+    fn from_bytes(bytes: &[u8]) -> Owner {
+        Owner {
+            kind: bytes[0],
+            id: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; OWNER_SIZE] {
+        let id = self.id.to_le_bytes();
+        [self.kind, id[0], id[1], id[2], id[3]]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Going to where to read the entries of `owner` from
+    Seek {
+        owner: Owner,
+    },
+    /// Reading entries until one of `owner`'s
+    Read {
+        owner: Owner,
+    },
+    /// Going to the start of the log, to count what each owner has in it
+    /// before appending
+    CountSeek {
+        append: Append,
+    },
+    /// Reading every entry, to count what each owner has in the log
+    Count {
+        append: Append,
+    },
+    Append {
+        append: Append,
+    },
+    Sync,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Append {
+    owner: Owner,
+    quota: usize,
+    length: usize,
+}
+
+/// The bytes of the log an owner has, with `bytes` of zero for unused slots.
+#[derive(Clone, Copy)]
+struct Usage {
+    owner: Owner,
+    bytes: usize,
+}
+
+const UNUSED: Usage = Usage {
+    owner: Owner { kind: 0, id: 0 },
+    bytes: 0,
+};
+
+pub struct MuxLog<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    log: &'a L,
+    client: OptionalCell<&'a dyn MuxLogClient>,
+    state: Cell<State>,
+    /// Holds each entry, with its owner, as it is read or appended
+    entry: TakeCell<'static, [u8]>,
+    /// The buffer of the client
+    buffer: TakeCell<'static, [u8]>,
+    /// Where the entry being read starts
+    position: Cell<usize>,
+    usage: Cell<[Usage; MAX_OWNERS]>,
+    /// The start of the log when the usage was counted, if it has been
+    counted: OptionalCell<usize>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> MuxLog<'a, L> {
+    /// `entry` must hold the largest entry of the log.
+    pub fn new(log: &'a L, entry: &'static mut [u8]) -> MuxLog<'a, L> {
+        MuxLog {
+            log,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            entry: TakeCell::new(entry),
+            buffer: TakeCell::empty(),
+            position: Cell::new(0),
+            usage: Cell::new([UNUSED; MAX_OWNERS]),
+            counted: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn MuxLogClient) {
+        self.client.set(client);
+    }
+
+    /// The position of the oldest entry in the log.
+    pub fn log_start(&self) -> usize {
+        self.log.log_start()
+    }
+
+    /// The position the next entry will be appended at.
+    pub fn log_end(&self) -> usize {
+        self.log.log_end()
+    }
+
+    /// The bytes of the log `owner` has, if they have been counted.
+    pub fn used(&self, owner: Owner) -> Option<usize> {
+        if self.counted.contains(&self.log.log_start()) {
+            Some(self.owner_usage(owner).map_or(0, |usage| usage.bytes))
+        } else {
+            None
+        }
+    }
+
+    fn owner_usage(&self, owner: Owner) -> Option<Usage> {
+        self.usage
+            .get()
+            .iter()
+            .find(|usage| usage.bytes > 0 && usage.owner == owner)
+            .copied()
+    }
+
+    // Adds to the bytes `owner` has, returning false if there is no slot to
+    // count them in.
+    fn add_usage(&self, owner: Owner, bytes: usize) -> bool {
+        let mut usage = self.usage.get();
+        let slot = usage
+            .iter()
+            .position(|usage| usage.bytes > 0 && usage.owner == owner)
+            .or_else(|| usage.iter().position(|usage| usage.bytes == 0));
+        match slot {
+            Some(slot) => {
+                usage[slot] = Usage {
+                    owner,
+                    bytes: usage[slot].bytes + bytes,
+                };
+                self.usage.set(usage);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Read the next entry of `owner` at or after the position `from`.
+    pub fn read(
+        &self,
+        owner: Owner,
+        from: usize,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        // Anything before the start of the log has been overwritten.
+        let from = from.max(self.log.log_start());
+        if let Err(error) = self.log.seek(from) {
+            return Err((error, buffer));
+        }
+        self.buffer.replace(buffer);
+        self.state.set(State::Seek { owner });
+        Ok(())
+    }
+
+    /// Append an entry for `owner`, if it leaves the owner with no more than
+    /// `quota` bytes of the log.
+    pub fn append(
+        &self,
+        owner: Owner,
+        quota: usize,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if length == 0 || length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        if length + OWNER_SIZE > self.entry.map_or(0, |entry| entry.len()) {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        let append = Append {
+            owner,
+            quota,
+            length,
+        };
+        self.buffer.replace(buffer);
+        let result = if self.used(owner).is_some() {
+            self.append_entry(append)
+        } else {
+            // Count again from the start of the log.
+            self.usage.set([UNUSED; MAX_OWNERS]);
+            self.counted.clear();
+            self.log
+                .seek(self.log.log_start())
+                .map(|()| self.state.set(State::CountSeek { append }))
+        };
+        result.map_err(|error| (error, self.buffer.take().unwrap()))
+    }
+
+    /// Sync the log to flash.
+    pub fn sync(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.log.sync()?;
+        self.state.set(State::Sync);
+        Ok(())
+    }
+
+    fn append_entry(&self, append: Append) -> Result<(), ErrorCode> {
+        let bytes = append.length + OWNER_SIZE + ENTRY_HEADER_SIZE;
+        let used = self.owner_usage(append.owner);
+        if used.map_or(0, |usage| usage.bytes) + bytes > append.quota {
+            return Err(ErrorCode::NOMEM);
+        }
+        if used.is_none() && self.usage.get().iter().all(|usage| usage.bytes > 0) {
+            return Err(ErrorCode::NOMEM);
+        }
+
+        let entry = self.entry.take().ok_or(ErrorCode::RESERVE)?;
+        entry[..OWNER_SIZE].copy_from_slice(&append.owner.to_bytes());
+        self.buffer.map(|buffer| {
+            entry[OWNER_SIZE..OWNER_SIZE + append.length].copy_from_slice(&buffer[..append.length])
+        });
+        match self.log.append(entry, append.length + OWNER_SIZE) {
+            Ok(()) => {
+                self.state.set(State::Append { append });
+                Ok(())
+            }
+            Err((error, entry)) => {
+                self.entry.replace(entry);
+                Err(error)
+            }
+        }
+    }
+
+    // Starts reading the entry after the last one read.
+    fn read_entry(&self) -> Result<(), ErrorCode> {
+        let entry = self.entry.take().ok_or(ErrorCode::RESERVE)?;
+        let length = entry.len();
+        self.position.set(self.log.next_read_entry_id());
+        self.log.read(entry, length).map_err(|(error, entry)| {
+            self.entry.replace(entry);
+            error
+        })
+    }
+
+    fn read_finished(&self, length: usize, result: Result<(), ErrorCode>) {
+        self.state.set(State::Idle);
+        let next = match result {
+            Err(ErrorCode::SIZE) => self.position.get(),
+            _ => self.log.next_read_entry_id(),
+        };
+        self.buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.read_done(buffer, length, next, result))
+        });
+    }
+
+    // Reports an append which failed before the entry was appended.
+    fn append_failed(&self, error: ErrorCode) {
+        self.state.set(State::Idle);
+        self.buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.append_done(buffer, 0, false, Err(error)))
+        });
+    }
+
+    // Reads the next entry to count, or appends once every entry is counted.
+    fn count_next(&self, append: Append) {
+        let result = match self.read_entry() {
+            Ok(()) => {
+                self.state.set(State::Count { append });
+                return;
+            }
+            Err(ErrorCode::FAIL) => {
+                // The end of the log.
+                self.counted.set(self.log.log_start());
+                self.append_entry(append)
+            }
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            self.append_failed(error);
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogReadClient for MuxLog<'a, L> {
+    fn read_done(&self, entry: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        let owner = (length >= OWNER_SIZE).then(|| Owner::from_bytes(entry));
+
+        match self.state.get() {
+            State::Read { owner: reading } => {
+                if result.is_err() {
+                    self.entry.replace(entry);
+                    self.read_finished(0, result);
+                } else if owner == Some(reading) {
+                    let data = &entry[OWNER_SIZE..length];
+                    let result =
+                        self.buffer.map_or(Err(ErrorCode::RESERVE), |buffer| {
+                            match buffer.get_mut(..data.len()) {
+                                Some(buffer) => {
+                                    buffer.copy_from_slice(data);
+                                    Ok(())
+                                }
+                                None => Err(ErrorCode::SIZE),
+                            }
+                        });
+                    let length = if result.is_ok() { data.len() } else { 0 };
+                    self.entry.replace(entry);
+                    self.read_finished(length, result);
+                } else {
+                    self.entry.replace(entry);
+                    if let Err(error) = self.read_entry() {
+                        self.read_finished(0, Err(error));
+                    }
+                }
+            }
+            State::Count { append } => {
+                self.entry.replace(entry);
+                match (result, owner) {
+                    (Ok(()), Some(owner)) => {
+                        // Owners beyond what can be counted cannot append,
+                        // so their entries need not be counted.
+                        let _ = self.add_usage(owner, length + ENTRY_HEADER_SIZE);
+                        self.count_next(append);
+                    }
+                    (Ok(()), None) => self.count_next(append),
+                    (Err(error), _) => self.append_failed(error),
+                }
+            }
+            _ => {
+                self.entry.replace(entry);
+            }
+        }
+    }
+
+    fn seek_done(&self, result: Result<(), ErrorCode>) {
+        match self.state.get() {
+            State::Seek { owner } => match result.and_then(|()| self.read_entry()) {
+                Ok(()) => self.state.set(State::Read { owner }),
+                Err(error) => self.read_finished(0, Err(error)),
+            },
+            State::CountSeek { append } => match result {
+                Ok(()) => self.count_next(append),
+                Err(error) => self.append_failed(error),
+            },
+            _ => {}
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogWriteClient for MuxLog<'a, L> {
+    fn append_done(
+        &self,
+        entry: &'static mut [u8],
+        _length: usize,
+        records_lost: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.entry.replace(entry);
+        if let State::Append { append } = self.state.get() {
+            if result.is_ok() {
+                self.add_usage(append.owner, append.length + OWNER_SIZE + ENTRY_HEADER_SIZE);
+            }
+            self.state.set(State::Idle);
+            self.buffer.take().map(|buffer| {
+                self.client.map(|client| {
+                    let length = if result.is_ok() { append.length } else { 0 };
+                    client.append_done(buffer, length, records_lost, result)
+                })
+            });
+        }
+    }
+
+    fn sync_done(&self, result: Result<(), ErrorCode>) {
+        if self.state.get() == State::Sync {
+            self.state.set(State::Idle);
+            self.client.map(|client| client.sync_done(result));
+        }
+    }
+
+    fn erase_done(&self, _result: Result<(), ErrorCode>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::log::{FlashLog, SimLog};
+    use core::cell::RefCell;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    struct Client {
+        /// The data, position after it and result of the last read
+        read: RefCell<Option<(Vec<u8>, usize, Result<(), ErrorCode>)>>,
+        /// The length, records_lost and result of the last append
+        appended: Cell<Option<(usize, bool, Result<(), ErrorCode>)>>,
+        synced: Cell<Option<Result<(), ErrorCode>>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl MuxLogClient for Client {
+        fn read_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            next: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.read
+                .replace(Some((buffer[..length].to_vec(), next, result)));
+            self.buffer.replace(buffer);
+        }
+
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            records_lost: bool,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.appended.set(Some((length, records_lost, result)));
+            self.buffer.replace(buffer);
+        }
+
+        fn sync_done(&self, result: Result<(), ErrorCode>) {
+            self.synced.set(Some(result));
+        }
+    }
+
+    const PAGE_SIZE: usize = 64;
+
+    struct Test {
+        log: SimLog<PAGE_SIZE>,
+        mux: &'static MuxLog<'static, FlashLog<PAGE_SIZE>>,
+        client: &'static Client,
+    }
+
+    const fn owner(id: u32) -> Owner {
+        Owner { kind: 0, id }
+    }
+
+    // The bytes an entry of `length` bytes takes in the log.
+    const fn entry_bytes(length: usize) -> usize {
+        length + OWNER_SIZE + ENTRY_HEADER_SIZE
+    }
+
+    impl Test {
+        /// A mux over a circular log of `pages` pages.
+        fn new(pages: usize) -> Test {
+            let log = SimLog::new(pages, true);
+            let entry = Box::leak(vec![0; 64].into_boxed_slice());
+            let mux = Box::leak(Box::new(MuxLog::new(log.log, entry)));
+            let client = Box::leak(Box::new(Client {
+                read: RefCell::new(None),
+                appended: Cell::new(None),
+                synced: Cell::new(None),
+                buffer: TakeCell::empty(),
+            }));
+            client
+                .buffer
+                .replace(Box::leak(vec![0; 32].into_boxed_slice()));
+            log.log.set_read_client(mux);
+            log.log.set_append_client(mux);
+            mux.set_client(client);
+            Test { log, mux, client }
+        }
+
+        fn run(&self) {
+            self.log.run();
+        }
+
+        fn append(&self, owner: Owner, quota: usize, data: &[u8]) -> Result<bool, ErrorCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            if let Err((error, buffer)) = self.mux.append(owner, quota, buffer, data.len()) {
+                self.client.buffer.replace(buffer);
+                return Err(error);
+            }
+            self.run();
+            let (length, records_lost, result) = self.client.appended.take().unwrap();
+            result.map(|()| {
+                assert_eq!(length, data.len());
+                records_lost
+            })
+        }
+
+        fn read_into(
+            &self,
+            owner: Owner,
+            from: usize,
+            size: usize,
+        ) -> (Result<Vec<u8>, ErrorCode>, usize) {
+            let full = self.client.buffer.take().unwrap();
+            let buffer = Box::leak(vec![0; size].into_boxed_slice());
+            if let Err((error, _)) = self.mux.read(owner, from, buffer) {
+                panic!("read failed to start: {:?}", error);
+            }
+            self.run();
+            let (data, next, result) = self.client.read.take().unwrap();
+            self.client.buffer.replace(full);
+            (result.map(|()| data), next)
+        }
+
+        fn read(&self, owner: Owner, from: usize) -> (Result<Vec<u8>, ErrorCode>, usize) {
+            self.read_into(owner, from, 32)
+        }
+    }
+
+    #[test]
+    fn owners_read_their_own_entries() {
+        let test = Test::new(16);
+        assert_eq!(test.append(owner(1), 100, b"one"), Ok(false));
+        assert_eq!(test.append(owner(2), 100, b"two"), Ok(false));
+        assert_eq!(test.append(owner(1), 100, b"three"), Ok(false));
+
+        // Reading from 0 reads from the start of the log.
+        let (data, next) = test.read(owner(1), 0);
+        assert_eq!(data.as_deref(), Ok(&b"one"[..]));
+        // The entry of the other owner is skipped.
+        let (data, next) = test.read(owner(1), next);
+        assert_eq!(data.as_deref(), Ok(&b"three"[..]));
+        assert_eq!(test.read(owner(1), next).0, Err(ErrorCode::FAIL));
+
+        let (data, next) = test.read(owner(2), 0);
+        assert_eq!(data.as_deref(), Ok(&b"two"[..]));
+        assert_eq!(test.read(owner(2), next).0, Err(ErrorCode::FAIL));
+        assert_eq!(test.read(owner(3), 0).0, Err(ErrorCode::FAIL));
+
+        assert!(test.mux.sync().is_ok());
+        test.run();
+        assert_eq!(test.client.synced.take(), Some(Ok(())));
+    }
+
+    #[test]
+    fn entries_too_large_for_the_buffer_are_read_again() {
+        let test = Test::new(16);
+        assert_eq!(test.append(owner(1), 100, b"a longer entry"), Ok(false));
+
+        let (data, next) = test.read_into(owner(1), 0, 4);
+        assert_eq!(data, Err(ErrorCode::SIZE));
+        assert_eq!(next, test.mux.log_start());
+
+        let (data, _) = test.read(owner(1), next);
+        assert_eq!(data.as_deref(), Ok(&b"a longer entry"[..]));
+    }
+
+    #[test]
+    fn owners_are_held_to_their_quota() {
+        let test = Test::new(16);
+        let quota = 2 * entry_bytes(4);
+        assert_eq!(test.mux.used(owner(1)), None);
+        assert_eq!(test.append(owner(1), quota, b"1234"), Ok(false));
+        assert_eq!(test.mux.used(owner(1)), Some(entry_bytes(4)));
+        assert_eq!(test.append(owner(1), quota, b"5678"), Ok(false));
+        assert_eq!(test.append(owner(1), quota, b"9"), Err(ErrorCode::NOMEM));
+
+        // Other owners have a quota of their own.
+        assert_eq!(test.append(owner(2), quota, b"1234"), Ok(false));
+        assert_eq!(test.mux.used(owner(1)), Some(quota));
+        assert_eq!(test.mux.used(owner(2)), Some(entry_bytes(4)));
+    }
+
+    #[test]
+    fn usage_is_counted_from_the_log() {
+        let test = Test::new(16);
+        assert_eq!(test.append(owner(1), 100, b"1234"), Ok(false));
+        assert_eq!(test.append(owner(2), 100, b"1234"), Ok(false));
+
+        assert!(test.mux.sync().is_ok());
+        test.run();
+        assert_eq!(test.client.synced.take(), Some(Ok(())));
+
+        // A new mux over the log after a reboot counts what each owner has
+        // before appending.
+        let log = test.log.restart(true);
+        let entry = Box::leak(vec![0; 64].into_boxed_slice());
+        let mux = Box::leak(Box::new(MuxLog::new(log.log, entry)));
+        log.log.set_read_client(mux);
+        log.log.set_append_client(mux);
+        mux.set_client(test.client);
+        let test = Test {
+            log,
+            mux,
+            client: test.client,
+        };
+
+        assert_eq!(test.mux.used(owner(1)), None);
+        let quota = 2 * entry_bytes(4);
+        assert_eq!(test.append(owner(1), quota, b"5678"), Ok(false));
+        assert_eq!(test.mux.used(owner(1)), Some(quota));
+        assert_eq!(test.mux.used(owner(2)), Some(entry_bytes(4)));
+        assert_eq!(test.append(owner(1), quota, b"9"), Err(ErrorCode::NOMEM));
+    }
+
+    #[test]
+    fn overwritten_entries_are_not_counted() {
+        // Each of the two pages holds three entries.
+        let test = Test::new(2);
+        let quota = 2 * entry_bytes(4);
+        assert_eq!(test.append(owner(1), quota, b"1111"), Ok(false));
+        assert_eq!(test.append(owner(1), quota, b"2222"), Ok(false));
+        assert_eq!(test.append(owner(1), quota, b"3333"), Err(ErrorCode::NOMEM));
+
+        // Filling the log with entries of owner 2 overwrites the first page
+        // once the third page is written over it.
+        let quota = 8 * entry_bytes(4);
+        for data in [
+            b"aaaa", b"bbbb", b"cccc", b"dddd", b"eeee", b"ffff", b"gggg",
+        ] {
+            assert_eq!(test.append(owner(2), quota, data), Ok(false));
+        }
+        let log_start = test.mux.log_start();
+        assert_eq!(test.append(owner(2), quota, b"hhhh"), Ok(true));
+        assert!(test.mux.log_start() > log_start);
+
+        // The start of the log moved, so the usage is counted again.
+        assert_eq!(test.mux.used(owner(1)), None);
+        assert_eq!(test.append(owner(1), 2 * entry_bytes(4), b"3333"), Ok(true));
+        assert_eq!(test.mux.used(owner(1)), Some(entry_bytes(4)));
+
+        // The older entries of owner 1 were overwritten.
+        let (data, next) = test.read(owner(1), 0);
+        assert_eq!(data.as_deref(), Ok(&b"3333"[..]));
+        assert_eq!(test.read(owner(1), next).0, Err(ErrorCode::FAIL));
+    }
+
+    #[test]
+    fn owners_of_other_kinds_are_apart() {
+        let test = Test::new(16);
+        let other = Owner { kind: 1, id: 1 };
+        let quota = entry_bytes(4);
+        assert_eq!(test.append(owner(1), quota, b"1234"), Ok(false));
+        assert_eq!(test.append(other, quota, b"abcd"), Ok(false));
+        assert_eq!(test.mux.used(owner(1)), Some(quota));
+        assert_eq!(test.mux.used(other), Some(quota));
+
+        let (data, next) = test.read(other, 0);
+        assert_eq!(data.as_deref(), Ok(&b"abcd"[..]));
+        assert_eq!(test.read(other, next).0, Err(ErrorCode::FAIL));
+    }
+}
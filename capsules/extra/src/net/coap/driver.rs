@@ -0,0 +1,605 @@
+//! CoAP userspace interface for issuing requests and serving resources.
+//!
+//! Processes use this driver to send CoAP requests to other endpoints, and to
+//! register resources that are served by the kernel CoAP endpoint on behalf
+//! of the process. Requests from all processes are issued one at a time
+//! through the shared endpoint.
+//!
+//! A resource is backed by a read-write buffer shared by the process: GET
+//! requests are answered with the first `len` bytes of the buffer, where `len`
+//! is set by the process (command 3), and PUT and POST requests overwrite the
+//! buffer with their payload and notify the process through an upcall. Since
+//! the kernel answers requests without involving the process, the process
+//! must keep the buffer allowed for as long as the resource is registered.
+//! Block-wise transfers are handled by the endpoint, so representations and
+//! payloads may be larger than a single message.
+
+use crate::net::coap::endpoint::{CoapClient, CoapEndpoint, CoapRequest, CoapResourceId};
+use crate::net::coap::endpoint::{CoapServer, MAX_URI_LEN};
+use crate::net::coap::message::{CoapCode, CoapMessage};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::util::host_slice_to_u16;
+
+use core::cmp;
+use core::mem::size_of;
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
+use kernel::hil::time;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// Number of resources each process can register.
+pub const MAX_RESOURCES: usize = 4;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// Payload of the next request
+    pub const PAYLOAD: usize = 0;
+    /// URI of the next request, or path of the resource to register
+    pub const URI: usize = 1;
+    /// Address and port of the destination of the next request
+    pub const DESTINATION: usize = 2;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 3;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Payload of the response to the last request
+    pub const RESPONSE: usize = 0;
+    /// Representation of the first resource, followed by those of the other
+    /// resources
+    pub const RESOURCE_BASE: usize = 1;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1 + super::MAX_RESOURCES as u8;
+}
+
+/// Ids for upcalls
+mod upcall {
+    /// A request completed
+    pub const RESPONSE: usize = 0;
+    /// A resource was modified by a request
+    pub const RESOURCE: usize = 1;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
+/// Flag of the request command selecting non-confirmable requests.
+const NON_CONFIRMABLE: usize = 1 << 8;
+
+/// Returns the bit of the methods mask of resources corresponding to the
+/// request method `method` (GET = 1, POST = 2, PUT = 4, DELETE = 8).
+fn method_bit(method: CoapCode) -> u8 {
+    match method {
+        CoapCode::GET | CoapCode::POST | CoapCode::PUT | CoapCode::DELETE => 1 << (method.0 - 1),
+        _ => 0,
+    }
+}
+
+/// Returns whether the `/` separated paths `a` and `b` are the same, ignoring
+/// empty segments.
+fn same_path(a: &[u8], b: &[u8]) -> bool {
+    let segments_a = a.split(|c| *c == b'/').filter(|s| !s.is_empty());
+    let segments_b = b.split(|c| *c == b'/').filter(|s| !s.is_empty());
+    segments_a.eq(segments_b)
+}
+
+#[derive(Copy, Clone, Default)]
+struct Resource {
+    registered: bool,
+    methods: u8,
+    path: [u8; MAX_URI_LEN],
+    path_len: usize,
+    /// Length of the representation in the resource buffer
+    len: usize,
+    /// Offset of the next expected block of a block-wise PUT or POST
+    rx_offset: usize,
+}
+
+#[derive(Default)]
+pub struct App {
+    resources: [Resource; MAX_RESOURCES],
+    pending_request: Option<CoapRequest>,
+    uri: [u8; MAX_URI_LEN],
+    uri_len: usize,
+    response_code: u8,
+    response_len: usize,
+}
+
+pub struct CoapDriver<'a, A: time::Alarm<'a>> {
+    endpoint: &'a CoapEndpoint<'a, A>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// Process whose request is outstanding
+    current_app: OptionalCell<ProcessId>,
+}
+
+impl<'a, A: time::Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(
+        endpoint: &'a CoapEndpoint<'a, A>,
+        grant: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> CoapDriver<'a, A> {
+        CoapDriver {
+            endpoint: endpoint,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Copies the contents of the URI buffer of the process.
+    fn read_uri(kernel_data: &GrantKernelData) -> Result<([u8; MAX_URI_LEN], usize), ErrorCode> {
+        kernel_data
+            .get_readonly_processbuffer(ro_allow::URI)
+            .and_then(|uri| {
+                uri.enter(|uri| {
+                    if uri.len() > MAX_URI_LEN {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    let mut buf = [0; MAX_URI_LEN];
+                    uri.copy_to_slice(&mut buf[..uri.len()]);
+                    Ok((buf, uri.len()))
+                })
+            })
+            .unwrap_or(Err(ErrorCode::INVAL))
+    }
+
+    /// Parses the destination buffer of the process, which holds an IPv6
+    /// address followed by a port (in host byte order).
+    fn read_destination(kernel_data: &GrantKernelData) -> Result<(IPAddr, u16), ErrorCode> {
+        kernel_data
+            .get_readonly_processbuffer(ro_allow::DESTINATION)
+            .and_then(|destination| {
+                destination.enter(|destination| {
+                    const LEN: usize = size_of::<IPAddr>() + size_of::<u16>();
+                    if destination.len() != LEN {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    let mut buf = [0; LEN];
+                    destination.copy_to_slice(&mut buf);
+                    let mut addr = IPAddr::new();
+                    addr.0.copy_from_slice(&buf[..size_of::<IPAddr>()]);
+                    Ok((addr, host_slice_to_u16(&buf[size_of::<IPAddr>()..])))
+                })
+            })
+            .unwrap_or(Err(ErrorCode::INVAL))
+    }
+
+    fn register_resource(
+        &self,
+        processid: ProcessId,
+        index: usize,
+        methods: usize,
+    ) -> Result<(), ErrorCode> {
+        if index >= MAX_RESOURCES || methods == 0 || methods > 0x0f {
+            return Err(ErrorCode::INVAL);
+        }
+        let (path, path_len) = self
+            .apps
+            .enter(processid, |_, kernel_data| Self::read_uri(kernel_data))??;
+        let path_slice = &path[..path_len];
+        if path_slice.iter().all(|c| *c == b'/') {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Paths must be unique across all processes
+        let mut taken = false;
+        for app in self.apps.iter() {
+            let owner = app.processid();
+            app.enter(|app, _| {
+                for (i, resource) in app.resources.iter().enumerate() {
+                    if resource.registered
+                        && same_path(&resource.path[..resource.path_len], path_slice)
+                        && !(owner == processid && i == index)
+                    {
+                        taken = true;
+                    }
+                }
+            });
+        }
+        if taken {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.apps.enter(processid, |app, _| {
+            app.resources[index] = Resource {
+                registered: true,
+                methods: methods as u8,
+                path: path,
+                path_len: path_len,
+                len: 0,
+                rx_offset: 0,
+            };
+        })?;
+        Ok(())
+    }
+
+    fn set_resource_len(
+        &self,
+        processid: ProcessId,
+        index: usize,
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        if index >= MAX_RESOURCES {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps.enter(processid, |app, kernel_data| {
+            let buf_len = kernel_data
+                .get_readwrite_processbuffer(rw_allow::RESOURCE_BASE + index)
+                .map_or(0, |buf| buf.len());
+            let resource = &mut app.resources[index];
+            if !resource.registered {
+                Err(ErrorCode::RESERVE)
+            } else if len > buf_len {
+                Err(ErrorCode::SIZE)
+            } else {
+                resource.len = len;
+                Ok(())
+            }
+        })?
+    }
+
+    fn queue_request(
+        &self,
+        processid: ProcessId,
+        flags: usize,
+        payload_len: usize,
+    ) -> Result<(), ErrorCode> {
+        let method = CoapCode((flags & 0xff) as u8);
+        if method_bit(method) == 0 || flags & !(0xff | NON_CONFIRMABLE) != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        self.apps.enter(processid, |app, kernel_data| {
+            if app.pending_request.is_some() || self.current_app.contains(&processid) {
+                return Err(ErrorCode::BUSY);
+            }
+            let available = kernel_data
+                .get_readonly_processbuffer(ro_allow::PAYLOAD)
+                .map_or(0, |buf| buf.len());
+            if payload_len > available {
+                return Err(ErrorCode::SIZE);
+            }
+            let (dest, dst_port) = Self::read_destination(kernel_data)?;
+            let (uri, uri_len) = Self::read_uri(kernel_data)?;
+            app.uri = uri;
+            app.uri_len = uri_len;
+            app.pending_request = Some(CoapRequest {
+                dest: dest,
+                dst_port: dst_port,
+                method: method,
+                confirmable: flags & NON_CONFIRMABLE == 0,
+                payload_len: payload_len,
+            });
+            Ok(())
+        })??;
+        self.do_next_request();
+        Ok(())
+    }
+
+    /// If no request is outstanding, issues the next pending request of a
+    /// process. Failures to issue a request are reported through the
+    /// response upcall.
+    fn do_next_request(&self) {
+        while self.current_app.is_none() {
+            let mut next = None;
+            for app in self.apps.iter() {
+                let processid = app.processid();
+                app.enter(|app, _| {
+                    if let Some(request) = app.pending_request.take() {
+                        app.response_code = 0;
+                        app.response_len = 0;
+                        next = Some((processid, request, app.uri, app.uri_len));
+                    }
+                });
+                if next.is_some() {
+                    break;
+                }
+            }
+            let (processid, request, uri, uri_len) = match next {
+                Some(next) => next,
+                None => return,
+            };
+
+            self.current_app.set(processid);
+            if let Err(e) = self.endpoint.request(request, &uri[..uri_len]) {
+                self.current_app.clear();
+                let _ = self.apps.enter(processid, |_, kernel_data| {
+                    kernel_data
+                        .schedule_upcall(
+                            upcall::RESPONSE,
+                            (kernel::errorcode::into_statuscode(Err(e)), 0, 0),
+                        )
+                        .ok();
+                });
+            }
+        }
+    }
+
+    /// Runs `fun` on the resource identified by `resource`, if it is still
+    /// registered.
+    fn enter_resource<F, R>(&self, resource: CoapResourceId, fun: F) -> Option<R>
+    where
+        F: FnOnce(&mut Resource, &GrantKernelData) -> R,
+    {
+        let mut result = None;
+        for app in self.apps.iter() {
+            if app.processid().id() == resource.owner {
+                app.enter(|app, kernel_data| {
+                    let entry = &mut app.resources[resource.index];
+                    if entry.registered {
+                        result = Some(fun(entry, kernel_data));
+                    }
+                });
+                break;
+            }
+        }
+        result
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> SyscallDriver for CoapDriver<'a, A> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num` (read-only)
+    ///
+    /// - `0`: Payload of the next request.
+    /// - `1`: URI of the next request (e.g. `sensors/temp?unit=c`), or path
+    ///        of the resource to register.
+    /// - `2`: Destination of the next request: a 16-byte IPv6 address
+    ///        followed by the port, in host byte order.
+    ///
+    /// ### `allow_num` (read-write)
+    ///
+    /// - `0`: Receives the payload of the response to the last request.
+    /// - `1` to `4`: Representation of resource `0` to `3`.
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: A request completed. The upcall carries the status of the
+    //        request, the response code (e.g. 69 for 2.05 Content) and the
+    //        length of the response payload. The status is NOACK if the
+    //        request was not answered in time, and FAIL if it was rejected.
+    //        SIZE indicates that the response did not fit in the buffer and
+    //        was truncated.
+    // - `1`: A resource was modified by a request. The upcall carries the
+    //        index of the resource, the request method and the new length
+    //        of the representation.
+
+    /// CoAP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register the resource at the path in the URI buffer as resource
+    ///        `arg1`. `arg2` is the mask of allowed methods (GET = 1,
+    ///        POST = 2, PUT = 4, DELETE = 8). Returns BUSY if another
+    ///        resource is registered at the same path.
+    /// - `2`: Unregister resource `arg1`.
+    /// - `3`: Set the length of the representation of resource `arg1` to
+    ///        `arg2`. Returns SIZE if the resource buffer is shorter.
+    /// - `4`: Send a request. The low byte of `arg1` is the method code
+    ///        (GET = 1, POST = 2, PUT = 3, DELETE = 4), and bit 8 selects a
+    ///        non-confirmable request. `arg2` is the length of the payload.
+    ///        Returns BUSY if the process already has a request outstanding.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => self.register_resource(processid, arg1, arg2).into(),
+            2 => {
+                if arg1 >= MAX_RESOURCES {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                self.apps
+                    .enter(processid, |app, _| {
+                        app.resources[arg1] = Resource::default();
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+            }
+            3 => self.set_resource_len(processid, arg1, arg2).into(),
+            4 => self.queue_request(processid, arg1, arg2).into(),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> CoapClient for CoapDriver<'a, A> {
+    fn request_payload(&self, offset: usize, buf: &mut [u8]) -> usize {
+        self.current_app.map_or(0, |processid| {
+            self.apps
+                .enter(*processid, |_, kernel_data| {
+                    kernel_data
+                        .get_readonly_processbuffer(ro_allow::PAYLOAD)
+                        .and_then(|payload| {
+                            payload.enter(|payload| {
+                                if offset + buf.len() > payload.len() {
+                                    return 0;
+                                }
+                                payload[offset..offset + buf.len()].copy_to_slice(buf);
+                                buf.len()
+                            })
+                        })
+                        .unwrap_or(0)
+                })
+                .unwrap_or(0)
+        })
+    }
+
+    fn response_received(&self, response: &CoapMessage, offset: usize, _more: bool) {
+        self.current_app.map(|processid| {
+            let _ = self.apps.enter(*processid, |app, kernel_data| {
+                app.response_code = response.header.code.0;
+                let copied = kernel_data
+                    .get_readwrite_processbuffer(rw_allow::RESPONSE)
+                    .and_then(|buf| {
+                        buf.mut_enter(|buf| {
+                            let end = cmp::min(offset + response.payload.len(), buf.len());
+                            if offset < end {
+                                buf[offset..end].copy_from_slice(&response.payload[..end - offset]);
+                                end - offset
+                            } else {
+                                0
+                            }
+                        })
+                    })
+                    .unwrap_or(0);
+                // A shorter length than the response payload tells the
+                // process that the response was truncated.
+                if copied == response.payload.len() {
+                    app.response_len = cmp::max(app.response_len, offset + copied);
+                }
+            });
+        });
+    }
+
+    fn request_done(&self, result: Result<(), ErrorCode>) {
+        if let Some(processid) = self.current_app.take() {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                kernel_data
+                    .schedule_upcall(
+                        upcall::RESPONSE,
+                        (
+                            kernel::errorcode::into_statuscode(result),
+                            app.response_code as usize,
+                            app.response_len,
+                        ),
+                    )
+                    .ok();
+            });
+        }
+        self.do_next_request();
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> CoapServer for CoapDriver<'a, A> {
+    fn find_resource(&self, request: &CoapMessage) -> Result<CoapResourceId, CoapCode> {
+        let method = method_bit(request.header.code);
+        let mut found = Err(CoapCode::NOT_FOUND);
+        for app in self.apps.iter() {
+            let owner = app.processid().id();
+            app.enter(|app, _| {
+                for (index, resource) in app.resources.iter().enumerate() {
+                    if resource.registered
+                        && request.uri_path_matches(&resource.path[..resource.path_len])
+                    {
+                        found = if resource.methods & method != 0 {
+                            Ok(CoapResourceId { owner, index })
+                        } else {
+                            Err(CoapCode::METHOD_NOT_ALLOWED)
+                        };
+                    }
+                }
+            });
+            if found != Err(CoapCode::NOT_FOUND) {
+                break;
+            }
+        }
+        found
+    }
+
+    fn resource_len(&self, resource: CoapResourceId) -> usize {
+        self.enter_resource(resource, |resource, _| resource.len)
+            .unwrap_or(0)
+    }
+
+    fn read_resource(&self, resource: CoapResourceId, offset: usize, buf: &mut [u8]) -> usize {
+        let index = resource.index;
+        self.enter_resource(resource, |resource, kernel_data| {
+            kernel_data
+                .get_readwrite_processbuffer(rw_allow::RESOURCE_BASE + index)
+                .and_then(|representation| {
+                    representation.enter(|representation| {
+                        let end = cmp::min(
+                            cmp::min(offset + buf.len(), resource.len),
+                            representation.len(),
+                        );
+                        if offset >= end {
+                            return 0;
+                        }
+                        representation[offset..end].copy_to_slice(&mut buf[..end - offset]);
+                        end - offset
+                    })
+                })
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
+    }
+
+    fn write_resource(
+        &self,
+        resource: CoapResourceId,
+        method: CoapCode,
+        offset: usize,
+        data: &[u8],
+        more: bool,
+    ) -> Result<CoapCode, CoapCode> {
+        let index = resource.index;
+        self.enter_resource(resource, |resource, kernel_data| {
+            if method == CoapCode::DELETE {
+                resource.len = 0;
+                resource.rx_offset = 0;
+                kernel_data
+                    .schedule_upcall(upcall::RESOURCE, (index, method.0 as usize, 0))
+                    .ok();
+                return Ok(CoapCode::DELETED);
+            }
+
+            // Blocks must arrive in order, a transfer restarts at offset 0
+            if offset != 0 && offset != resource.rx_offset {
+                return Err(CoapCode::REQUEST_ENTITY_INCOMPLETE);
+            }
+            let written = kernel_data
+                .get_readwrite_processbuffer(rw_allow::RESOURCE_BASE + index)
+                .and_then(|representation| {
+                    representation.mut_enter(|representation| {
+                        if offset + data.len() > representation.len() {
+                            return false;
+                        }
+                        representation[offset..offset + data.len()].copy_from_slice(data);
+                        true
+                    })
+                })
+                .unwrap_or(false);
+            if !written {
+                resource.rx_offset = 0;
+                return Err(CoapCode::REQUEST_ENTITY_TOO_LARGE);
+            }
+
+            resource.rx_offset = offset + data.len();
+            if !more {
+                resource.len = resource.rx_offset;
+                resource.rx_offset = 0;
+                kernel_data
+                    .schedule_upcall(upcall::RESOURCE, (index, method.0 as usize, resource.len))
+                    .ok();
+            }
+            Ok(CoapCode::CHANGED)
+        })
+        .unwrap_or(Err(CoapCode::NOT_FOUND))
+    }
+}
@@ -0,0 +1,910 @@
+//! This file contains a CoAP (RFC 7252) endpoint, which acts both as a client
+//! issuing requests to other endpoints and as a server exposing resources,
+//! over a single bound UDP port.
+//!
+//! As a client, the endpoint handles a single outstanding request at a time
+//! (NSTART = 1). Confirmable requests are retransmitted with exponential
+//! back-off until they are acknowledged, and responses are matched to the
+//! request through the token and the address of the peer. Separate responses
+//! (an empty acknowledgement followed by the response) are supported. Request
+//! payloads that do not fit in a single message are sent block-wise with the
+//! Block1 option, and block-wise responses to GET requests (Block2 option)
+//! are retrieved block by block, each block being passed to the client as it
+//! arrives (RFC 7959).
+//!
+//! As a server, the endpoint looks up the resource targeted by a request
+//! through its `CoapServer`, and answers with a piggybacked response for
+//! confirmable requests. GET responses are served in blocks if the
+//! representation does not fit in a single message, and block-wise PUT and
+//! POST payloads are passed to the server block by block. Duplicate
+//! confirmable requests are answered by retransmitting the last response.
+//!
+//! ```txt
+//!   CoapClient      CoapServer
+//!        \             /
+//!         CoapEndpoint  <--- Alarm (retransmissions and response timeout)
+//!               |
+//!   UDPSender / UDPReceiver
+//! ```
+//!
+//! The endpoint needs two buffers: one holding the current request, which is
+//! kept around for retransmissions, and one for responses and empty
+//! messages. The endpoint must be bound to a UDP port before use; see
+//! `components::coap` for how this is done.
+
+// Known Problems and Remaining Work
+// ---------------------------------
+// There is no random number generator available to the endpoint, so the
+// initial retransmission timeout is spread over [ACK_TIMEOUT,
+// ACK_TIMEOUT * ACK_RANDOM_FACTOR) based on the message ID, and tokens are
+// derived from a counter and the current time. Observe (RFC 7641), multicast
+// requests and proxying are not supported.
+
+use crate::net::coap::message::{option, BlockOption, CoapCode, CoapHeader, CoapMessage};
+use crate::net::coap::message::{CoapToken, CoapType, OptionEncoder};
+use crate::net::coap::message::{COAP_HDR_LEN, MAX_BLOCK_SZX, PAYLOAD_MARKER};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::stream::encode_u8;
+use crate::net::stream::SResult;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil::time::{self, ConvertTicks, Ticks};
+use kernel::utilities::cells::{MapCell, OptionalCell};
+use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
+use kernel::ErrorCode;
+
+/// Initial retransmission timeout of confirmable messages in milliseconds
+/// (ACK_TIMEOUT in RFC 7252).
+pub const ACK_TIMEOUT_MS: u32 = 2000;
+
+/// Range the initial retransmission timeout is spread over, i.e.
+/// ACK_TIMEOUT * (ACK_RANDOM_FACTOR - 1).
+const ACK_TIMEOUT_RANGE_MS: u32 = 1000;
+
+/// Number of retransmissions of a confirmable message before giving up
+/// (MAX_RETRANSMIT in RFC 7252).
+pub const MAX_RETRANSMIT: u8 = 4;
+
+/// Time to wait for a response once a request has been acknowledged, or
+/// since a non-confirmable request has been sent, in milliseconds.
+pub const RESPONSE_TIMEOUT_MS: u32 = 30000;
+
+/// Maximum length of the URI (path and query) of a request, and of the path
+/// of a resource.
+pub const MAX_URI_LEN: usize = 32;
+
+/// Space reserved in the request buffer for the header, token and options
+/// when choosing the size of request payload blocks.
+const REQUEST_OVERHEAD: usize = 80;
+
+/// Space reserved in the response buffer for the header, token and options
+/// when choosing the size of response payload blocks.
+const RESPONSE_OVERHEAD: usize = 32;
+
+/// Critical options understood by the endpoint. Requests and responses
+/// carrying other critical options are rejected.
+const RECOGNIZED_OPTIONS: [u16; 7] = [
+    option::URI_HOST,
+    option::URI_PORT,
+    option::URI_PATH,
+    option::URI_QUERY,
+    option::ACCEPT,
+    option::BLOCK2,
+    option::BLOCK1,
+];
+
+/// Returns the largest block size exponent such that a block and `overhead`
+/// bytes fit into a buffer of length `buf_len`.
+fn block_szx(buf_len: usize, overhead: usize) -> u8 {
+    let mut szx = MAX_BLOCK_SZX;
+    while szx > 0 && (16 << szx) + overhead > buf_len {
+        szx -= 1;
+    }
+    szx
+}
+
+/// A request to issue through `CoapEndpoint::request`. The payload is not
+/// part of the request: it is read from the `CoapClient` block by block.
+#[derive(Copy, Clone)]
+pub struct CoapRequest {
+    pub dest: IPAddr,
+    pub dst_port: u16,
+    pub method: CoapCode,
+    pub confirmable: bool,
+    pub payload_len: usize,
+}
+
+/// Client of the requests issued by a `CoapEndpoint`.
+pub trait CoapClient {
+    /// Called when the endpoint encodes a request, to copy the part of the
+    /// request payload starting at `offset` into `buf`. Returns the number of
+    /// bytes copied, which must be the length of `buf`.
+    fn request_payload(&self, offset: usize, buf: &mut [u8]) -> usize;
+
+    /// Called for each response to the outstanding request. `offset` is the
+    /// offset of the response payload in the whole representation, and
+    /// `more` indicates whether the endpoint retrieves further blocks of it.
+    fn response_received(&self, response: &CoapMessage, offset: usize, more: bool);
+
+    /// Called once the request completes, after the last response has been
+    /// passed to `response_received`. Returns `NOACK` if the request was not
+    /// acknowledged or answered in time, and `FAIL` if it was rejected by
+    /// the peer.
+    fn request_done(&self, result: Result<(), ErrorCode>);
+}
+
+/// Identifies a resource of a `CoapServer`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CoapResourceId {
+    pub owner: usize,
+    pub index: usize,
+}
+
+/// The resources served by a `CoapEndpoint`.
+pub trait CoapServer {
+    /// Returns the resource targeted by `request`, or the response code to
+    /// answer the request with (e.g. `NOT_FOUND` or `METHOD_NOT_ALLOWED`).
+    fn find_resource(&self, request: &CoapMessage) -> Result<CoapResourceId, CoapCode>;
+
+    /// Returns the length of the current representation of `resource`.
+    fn resource_len(&self, resource: CoapResourceId) -> usize;
+
+    /// Copies the representation of `resource` starting at `offset` into
+    /// `buf`, and returns the number of bytes copied.
+    fn read_resource(&self, resource: CoapResourceId, offset: usize, buf: &mut [u8]) -> usize;
+
+    /// Applies a PUT, POST or DELETE request to `resource`. `data` is the
+    /// part of the request payload starting at `offset`, and `more` is set
+    /// if further blocks of the payload follow. Returns the response code.
+    fn write_resource(
+        &self,
+        resource: CoapResourceId,
+        method: CoapCode,
+        offset: usize,
+        data: &[u8],
+        more: bool,
+    ) -> Result<CoapCode, CoapCode>;
+}
+
+/// The buffer currently passed to the UDP sender.
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Idle,
+    Request,
+    Response,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum RequestState {
+    Idle,
+    AwaitingAck,
+    AwaitingResponse,
+}
+
+/// Pending operation on the request buffer.
+#[derive(Copy, Clone, PartialEq)]
+enum RequestAction {
+    None,
+    // Encode the next message of the request into the request buffer
+    Build,
+    // Transmit the message in the request buffer
+    Transmit,
+}
+
+/// What to answer a request with, determined before the response is
+/// encoded.
+struct Response {
+    code: CoapCode,
+    block1: Option<BlockOption>,
+    block2: Option<BlockOption>,
+    size2: Option<usize>,
+    // Resource, offset and length of the payload
+    content: Option<(CoapResourceId, usize, usize)>,
+}
+
+impl Response {
+    fn new(code: CoapCode) -> Response {
+        Response {
+            code: code,
+            block1: None,
+            block2: None,
+            size2: None,
+            content: None,
+        }
+    }
+}
+
+pub struct CoapEndpoint<'a, A: time::Alarm<'a>> {
+    sender: &'a dyn UDPSender<'a>,
+    alarm: &'a A,
+    net_cap: &'static NetworkCapability,
+    client: OptionalCell<&'a dyn CoapClient>,
+    server: OptionalCell<&'a dyn CoapServer>,
+    in_flight: Cell<InFlight>,
+
+    // State of the outstanding request
+    request_buf: MapCell<LeasableMutableBuffer<'static, u8>>,
+    request_len: Cell<usize>,
+    // Size exponent of the blocks fitting into the request buffer
+    request_szx: u8,
+    request: OptionalCell<CoapRequest>,
+    uri: Cell<[u8; MAX_URI_LEN]>,
+    uri_len: Cell<usize>,
+    request_state: Cell<RequestState>,
+    request_action: Cell<RequestAction>,
+    message_id: Cell<u16>,
+    token: Cell<CoapToken>,
+    token_counter: Cell<u32>,
+    retransmissions: Cell<u8>,
+    timeout_ms: Cell<u32>,
+    // Offset, length and size exponent of the current block of the payload
+    block1_offset: Cell<usize>,
+    block1_len: Cell<usize>,
+    block1_szx: Cell<u8>,
+    // Number and size exponent of the response block to request next
+    block2_num: Cell<u32>,
+    block2_szx: Cell<u8>,
+
+    // Responses and empty messages
+    response_buf: MapCell<LeasableMutableBuffer<'static, u8>>,
+    response_len: Cell<usize>,
+    response_pending: Cell<bool>,
+    response_dest: Cell<(IPAddr, u16)>,
+    // Source and message ID of the confirmable request answered by the
+    // message in `response_buf`, to detect duplicates
+    last_request: Cell<Option<(IPAddr, u16, u16)>>,
+}
+
+impl<'a, A: time::Alarm<'a>> CoapEndpoint<'a, A> {
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        alarm: &'a A,
+        net_cap: &'static NetworkCapability,
+        request_buf: &'static mut [u8],
+        response_buf: &'static mut [u8],
+    ) -> CoapEndpoint<'a, A> {
+        CoapEndpoint {
+            sender: sender,
+            alarm: alarm,
+            net_cap: net_cap,
+            client: OptionalCell::empty(),
+            server: OptionalCell::empty(),
+            in_flight: Cell::new(InFlight::Idle),
+            request_szx: block_szx(request_buf.len(), REQUEST_OVERHEAD),
+            request_buf: MapCell::new(LeasableMutableBuffer::new(request_buf)),
+            request_len: Cell::new(0),
+            request: OptionalCell::empty(),
+            uri: Cell::new([0; MAX_URI_LEN]),
+            uri_len: Cell::new(0),
+            request_state: Cell::new(RequestState::Idle),
+            request_action: Cell::new(RequestAction::None),
+            message_id: Cell::new(alarm.now().into_u32() as u16),
+            token: Cell::new(CoapToken::EMPTY),
+            token_counter: Cell::new(0),
+            retransmissions: Cell::new(0),
+            timeout_ms: Cell::new(ACK_TIMEOUT_MS),
+            block1_offset: Cell::new(0),
+            block1_len: Cell::new(0),
+            block1_szx: Cell::new(0),
+            block2_num: Cell::new(0),
+            block2_szx: Cell::new(0),
+            response_buf: MapCell::new(LeasableMutableBuffer::new(response_buf)),
+            response_len: Cell::new(0),
+            response_pending: Cell::new(false),
+            response_dest: Cell::new((IPAddr::new(), 0)),
+            last_request: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn CoapClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_server(&self, server: &'a dyn CoapServer) {
+        self.server.set(server);
+    }
+
+    /// Returns whether a request is outstanding.
+    pub fn is_busy(&self) -> bool {
+        self.request.is_some()
+    }
+
+    /// Issues a request to the resource at `uri` (a path such as
+    /// `sensors/temp`, optionally followed by a `?` and `&` separated query)
+    /// on the endpoint at `request.dest`. The outcome is reported through the
+    /// `CoapClient`.
+    ///
+    /// Returns `BUSY` if a request is already outstanding, `INVAL` if the
+    /// method is not a request method, and `SIZE` if `uri` is longer than
+    /// `MAX_URI_LEN`.
+    pub fn request(&self, request: CoapRequest, uri: &[u8]) -> Result<(), ErrorCode> {
+        if self.request.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if !request.method.is_request() {
+            return Err(ErrorCode::INVAL);
+        }
+        if uri.len() > MAX_URI_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let mut uri_buf = [0; MAX_URI_LEN];
+        uri_buf[..uri.len()].copy_from_slice(uri);
+        self.uri.set(uri_buf);
+        self.uri_len.set(uri.len());
+
+        self.block1_offset.set(0);
+        self.block1_len.set(0);
+        self.block1_szx.set(self.request_szx);
+        self.block2_num.set(0);
+        self.block2_szx.set(self.request_szx);
+        self.token.set(self.next_token());
+
+        self.request.set(request);
+        self.request_action.set(RequestAction::Build);
+        self.transmit_next();
+        Ok(())
+    }
+
+    fn next_message_id(&self) -> u16 {
+        let message_id = self.message_id.get().wrapping_add(1);
+        self.message_id.set(message_id);
+        message_id
+    }
+
+    fn next_token(&self) -> CoapToken {
+        let counter = self.token_counter.get().wrapping_add(1);
+        self.token_counter.set(counter);
+        let value = counter.wrapping_mul(0x9e37_79b9) ^ self.alarm.now().into_u32();
+        CoapToken::new(&value.to_be_bytes()).unwrap_or(CoapToken::EMPTY)
+    }
+
+    fn set_timer(&self, ms: u32) {
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(ms));
+    }
+
+    /// Completes the outstanding request.
+    fn request_done(&self, result: Result<(), ErrorCode>) {
+        let _ = self.alarm.disarm();
+        self.request_state.set(RequestState::Idle);
+        self.request_action.set(RequestAction::None);
+        self.request.clear();
+        self.client.map(|client| client.request_done(result));
+    }
+
+    /// Passes the next pending message to the UDP sender, giving priority to
+    /// responses and empty messages over requests.
+    fn transmit_next(&self) {
+        if self.in_flight.get() != InFlight::Idle {
+            return;
+        }
+
+        if self.response_pending.get() {
+            self.response_pending.set(false);
+            let (dest, dst_port) = self.response_dest.get();
+            if let Some(mut buf) = self.response_buf.take() {
+                buf.slice(0..self.response_len.get());
+                match self.sender.send_to(dest, dst_port, buf, self.net_cap) {
+                    Ok(()) => {
+                        self.in_flight.set(InFlight::Response);
+                        return;
+                    }
+                    Err(mut buf) => {
+                        // Responses are not retransmitted: the peer repeats
+                        // confirmable requests it does not get an answer to.
+                        buf.reset();
+                        self.response_buf.replace(buf);
+                    }
+                }
+            }
+        }
+
+        if self.request_action.get() == RequestAction::Build {
+            if let Err(e) = self.build_request() {
+                self.request_done(Err(e));
+                return;
+            }
+        }
+
+        if self.request_action.get() == RequestAction::Transmit {
+            let request = match self.request.extract() {
+                Some(request) => request,
+                None => return,
+            };
+            if let Some(mut buf) = self.request_buf.take() {
+                buf.slice(0..self.request_len.get());
+                match self
+                    .sender
+                    .send_to(request.dest, request.dst_port, buf, self.net_cap)
+                {
+                    Ok(()) => {
+                        self.in_flight.set(InFlight::Request);
+                        self.request_action.set(RequestAction::None);
+                    }
+                    Err(mut buf) => {
+                        // Retried when the retransmission timer fires
+                        buf.reset();
+                        self.request_buf.replace(buf);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Encodes the next message of the outstanding request into the request
+    /// buffer, and starts waiting for its acknowledgement or response.
+    fn build_request(&self) -> Result<(), ErrorCode> {
+        let request = self.request.extract().ok_or(ErrorCode::FAIL)?;
+
+        let block_size = 16 << self.block1_szx.get();
+        let offset = self.block1_offset.get();
+        let use_block1 = request.payload_len > block_size || offset > 0;
+        let chunk = cmp::min(request.payload_len - offset, block_size);
+        let block1 = if use_block1 {
+            Some(BlockOption::new(
+                (offset / block_size) as u32,
+                offset + chunk < request.payload_len,
+                self.block1_szx.get(),
+            ))
+        } else {
+            None
+        };
+        let block2 = match self.block2_num.get() {
+            0 => None,
+            num => Some(BlockOption::new(num, false, self.block2_szx.get())),
+        };
+
+        let msg_type = if request.confirmable {
+            CoapType::Confirmable
+        } else {
+            CoapType::NonConfirmable
+        };
+        let header = CoapHeader::new(
+            msg_type,
+            request.method,
+            self.next_message_id(),
+            self.token.get(),
+        );
+
+        let len = self
+            .request_buf
+            .map_or(None, |buf| {
+                buf.reset();
+                self.encode_request(
+                    &mut buf[..],
+                    &header,
+                    &request,
+                    block1,
+                    block2,
+                    offset,
+                    chunk,
+                )
+                .done()
+                .map(|(len, _)| len)
+            })
+            .ok_or(ErrorCode::SIZE)?;
+        self.request_len.set(len);
+        self.block1_len.set(chunk);
+        self.request_action.set(RequestAction::Transmit);
+
+        self.retransmissions.set(0);
+        if request.confirmable {
+            self.request_state.set(RequestState::AwaitingAck);
+            let timeout = ACK_TIMEOUT_MS + header.message_id as u32 % ACK_TIMEOUT_RANGE_MS;
+            self.timeout_ms.set(timeout);
+            self.set_timer(timeout);
+        } else {
+            self.request_state.set(RequestState::AwaitingResponse);
+            self.set_timer(RESPONSE_TIMEOUT_MS);
+        }
+        Ok(())
+    }
+
+    fn encode_request(
+        &self,
+        buf: &mut [u8],
+        header: &CoapHeader,
+        request: &CoapRequest,
+        block1: Option<BlockOption>,
+        block2: Option<BlockOption>,
+        offset: usize,
+        chunk: usize,
+    ) -> SResult<usize> {
+        let uri = self.uri.get();
+        let uri = &uri[..self.uri_len.get()];
+        let (path, query) = match uri.iter().position(|b| *b == b'?') {
+            Some(pos) => (&uri[..pos], &uri[pos + 1..]),
+            None => (uri, &uri[uri.len()..]),
+        };
+
+        let mut off = enc_consume!(buf, 0; header; encode);
+        let mut options = OptionEncoder::new();
+        off = enc_consume!(buf, off; options; encode_segments, option::URI_PATH, path, b'/');
+        off = enc_consume!(buf, off; options; encode_segments, option::URI_QUERY, query, b'&');
+        if let Some(block2) = block2 {
+            off = enc_consume!(buf, off; options; encode_uint, option::BLOCK2, block2.value());
+        }
+        if let Some(block1) = block1 {
+            off = enc_consume!(buf, off; options; encode_uint, option::BLOCK1, block1.value());
+            if block1.num == 0 {
+                off = enc_consume!(buf, off; options; encode_uint, option::SIZE1,
+                                   request.payload_len as u32);
+            }
+        }
+        if chunk > 0 {
+            off = enc_consume!(buf, off; encode_u8, PAYLOAD_MARKER);
+            stream_len_cond!(buf, off + chunk);
+            let copied = self.client.map_or(0, |client| {
+                client.request_payload(offset, &mut buf[off..off + chunk])
+            });
+            stream_cond!(copied == chunk);
+            off += chunk;
+        }
+        stream_done!(off, off);
+    }
+
+    /// Queues an empty message (an acknowledgement or a reset) in the
+    /// response buffer. The message is dropped if the buffer is in use.
+    fn send_empty(&self, msg_type: CoapType, message_id: u16, dest: IPAddr, dst_port: u16) {
+        if self.in_flight.get() == InFlight::Response || self.response_pending.get() {
+            return;
+        }
+        let header = CoapHeader::new(msg_type, CoapCode::EMPTY, message_id, CoapToken::EMPTY);
+        let encoded = self.response_buf.map_or(false, |buf| {
+            buf.reset();
+            header.encode(&mut buf[..]).is_done()
+        });
+        if encoded {
+            self.response_len.set(COAP_HDR_LEN);
+            self.response_dest.set((dest, dst_port));
+            self.last_request.set(None);
+            self.response_pending.set(true);
+            self.transmit_next();
+        }
+    }
+
+    /// Handles an acknowledgement, reset or response addressed to the client
+    /// side of the endpoint.
+    fn receive_response(&self, src_addr: IPAddr, src_port: u16, message: &CoapMessage) {
+        let header = message.header;
+        let confirmable = header.msg_type == CoapType::Confirmable;
+        let request = match self.request.extract() {
+            Some(request)
+                if request.dest == src_addr
+                    && request.dst_port == src_port
+                    && self.request_state.get() != RequestState::Idle =>
+            {
+                request
+            }
+            _ => {
+                if confirmable {
+                    self.send_empty(CoapType::Reset, header.message_id, src_addr, src_port);
+                }
+                return;
+            }
+        };
+
+        match header.msg_type {
+            CoapType::Reset => {
+                if header.message_id == self.message_id.get() {
+                    self.request_done(Err(ErrorCode::FAIL));
+                }
+                return;
+            }
+            CoapType::Acknowledgement => {
+                if header.message_id != self.message_id.get() {
+                    return;
+                }
+                if header.code.is_empty() {
+                    // The response will follow separately
+                    if self.request_state.get() == RequestState::AwaitingAck {
+                        self.request_state.set(RequestState::AwaitingResponse);
+                        self.set_timer(RESPONSE_TIMEOUT_MS);
+                    }
+                    return;
+                }
+            }
+            _ => {}
+        }
+
+        if header.token != self.token.get() {
+            if confirmable {
+                self.send_empty(CoapType::Reset, header.message_id, src_addr, src_port);
+            }
+            return;
+        }
+        if message
+            .unrecognized_critical_option(&RECOGNIZED_OPTIONS)
+            .is_some()
+        {
+            if confirmable {
+                self.send_empty(CoapType::Reset, header.message_id, src_addr, src_port);
+            }
+            self.request_done(Err(ErrorCode::FAIL));
+            return;
+        }
+        if confirmable {
+            self.send_empty(
+                CoapType::Acknowledgement,
+                header.message_id,
+                src_addr,
+                src_port,
+            );
+        }
+        let _ = self.alarm.disarm();
+        self.process_response(&request, message);
+    }
+
+    /// Passes a response to the client, and continues block-wise transfers.
+    fn process_response(&self, request: &CoapRequest, message: &CoapMessage) {
+        if message.header.code == CoapCode::CONTINUE {
+            // The server asks for the next block of the request payload, and
+            // may ask for smaller blocks.
+            let next = self.block1_offset.get() + self.block1_len.get();
+            match message.block1() {
+                Some(block1) if next < request.payload_len => {
+                    self.block1_offset.set(next);
+                    self.block1_szx
+                        .set(cmp::min(self.block1_szx.get(), block1.szx));
+                    self.request_action.set(RequestAction::Build);
+                    self.transmit_next();
+                }
+                _ => self.request_done(Err(ErrorCode::FAIL)),
+            }
+            return;
+        }
+
+        let block2 = message.block2();
+        let (offset, more) = block2.map_or((0, false), |block2| (block2.offset(), block2.more));
+        self.client
+            .map(|client| client.response_received(message, offset, more));
+
+        match block2 {
+            Some(block2) if block2.more && request.method == CoapCode::GET => {
+                self.block2_num.set(block2.num + 1);
+                self.block2_szx.set(block2.szx);
+                self.request_action.set(RequestAction::Build);
+                self.transmit_next();
+            }
+            _ => self.request_done(Ok(())),
+        }
+    }
+
+    /// Handles a request addressed to the server side of the endpoint.
+    fn receive_request(&self, src_addr: IPAddr, src_port: u16, message: &CoapMessage) {
+        let header = message.header;
+        if self.in_flight.get() == InFlight::Response || self.response_pending.get() {
+            // Confirmable requests will be retransmitted by the peer
+            return;
+        }
+
+        let confirmable = header.msg_type == CoapType::Confirmable;
+        if confirmable && self.last_request.get() == Some((src_addr, src_port, header.message_id)) {
+            // Duplicate of the request answered last, send the same response
+            self.response_dest.set((src_addr, src_port));
+            self.response_pending.set(true);
+            self.transmit_next();
+            return;
+        }
+
+        let (msg_type, message_id) = if confirmable {
+            (CoapType::Acknowledgement, header.message_id)
+        } else {
+            (CoapType::NonConfirmable, self.next_message_id())
+        };
+        let len = self.response_buf.map_or(None, |buf| {
+            buf.reset();
+            let response = self.serve(message, block_szx(buf.len(), RESPONSE_OVERHEAD));
+            let response_header =
+                CoapHeader::new(msg_type, response.code, message_id, header.token);
+            match self
+                .encode_response(&mut buf[..], &response_header, &response)
+                .done()
+            {
+                Some((len, _)) => Some(len),
+                None => {
+                    let error_header = CoapHeader::new(
+                        msg_type,
+                        CoapCode::INTERNAL_SERVER_ERROR,
+                        message_id,
+                        header.token,
+                    );
+                    error_header.encode(&mut buf[..]).done().map(|(len, _)| len)
+                }
+            }
+        });
+
+        if let Some(len) = len {
+            self.response_len.set(len);
+            self.response_dest.set((src_addr, src_port));
+            self.last_request.set(if confirmable {
+                Some((src_addr, src_port, header.message_id))
+            } else {
+                None
+            });
+            self.response_pending.set(true);
+            self.transmit_next();
+        }
+    }
+
+    /// Determines the response to a request, applying the request to the
+    /// targeted resource if it modifies it.
+    fn serve(&self, request: &CoapMessage, max_szx: u8) -> Response {
+        if request
+            .unrecognized_critical_option(&RECOGNIZED_OPTIONS)
+            .is_some()
+        {
+            return Response::new(CoapCode::BAD_OPTION);
+        }
+        let server = match self.server.extract() {
+            Some(server) => server,
+            None => return Response::new(CoapCode::NOT_FOUND),
+        };
+        let resource = match server.find_resource(request) {
+            Ok(resource) => resource,
+            Err(code) => return Response::new(code),
+        };
+
+        let method = request.header.code;
+        if method == CoapCode::GET {
+            let total = server.resource_len(resource);
+            let requested = request.block2();
+            let szx = requested.map_or(max_szx, |block2| cmp::min(block2.szx, max_szx));
+            let num = requested.map_or(0, |block2| block2.offset() / (16 << szx));
+            let offset = num * (16 << szx);
+            if offset > total || (offset == total && offset != 0) {
+                return Response::new(CoapCode::BAD_REQUEST);
+            }
+            let len = cmp::min(16 << szx, total - offset);
+            let more = offset + len < total;
+
+            let mut response = Response::new(CoapCode::CONTENT);
+            if requested.is_some() || more {
+                response.block2 = Some(BlockOption::new(num as u32, more, szx));
+                if num == 0 {
+                    response.size2 = Some(total);
+                }
+            }
+            response.content = Some((resource, offset, len));
+            response
+        } else {
+            let block1 = request.block1();
+            let (offset, more) = block1.map_or((0, false), |block1| (block1.offset(), block1.more));
+            match server.write_resource(resource, method, offset, request.payload, more) {
+                Ok(code) => {
+                    let mut response = Response::new(if more { CoapCode::CONTINUE } else { code });
+                    // Larger blocks than ours are accepted, but we ask the
+                    // client to continue with our block size.
+                    response.block1 = block1.map(|block1| {
+                        BlockOption::new(block1.num, block1.more, cmp::min(block1.szx, max_szx))
+                    });
+                    response
+                }
+                Err(code) => Response::new(code),
+            }
+        }
+    }
+
+    fn encode_response(
+        &self,
+        buf: &mut [u8],
+        header: &CoapHeader,
+        response: &Response,
+    ) -> SResult<usize> {
+        let mut off = enc_consume!(buf, 0; header; encode);
+        let mut options = OptionEncoder::new();
+        if let Some(block2) = response.block2 {
+            off = enc_consume!(buf, off; options; encode_uint, option::BLOCK2, block2.value());
+        }
+        if let Some(block1) = response.block1 {
+            off = enc_consume!(buf, off; options; encode_uint, option::BLOCK1, block1.value());
+        }
+        if let Some(size2) = response.size2 {
+            off = enc_consume!(buf, off; options; encode_uint, option::SIZE2, size2 as u32);
+        }
+        match response.content {
+            Some((resource, offset, len)) if len > 0 => {
+                off = enc_consume!(buf, off; encode_u8, PAYLOAD_MARKER);
+                stream_len_cond!(buf, off + len);
+                let read = self.server.map_or(0, |server| {
+                    server.read_resource(resource, offset, &mut buf[off..off + len])
+                });
+                stream_cond!(read == len);
+                off += len;
+            }
+            _ => {}
+        }
+        stream_done!(off, off);
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> time::AlarmClient for CoapEndpoint<'a, A> {
+    fn alarm(&self) {
+        match self.request_state.get() {
+            RequestState::Idle => {}
+            RequestState::AwaitingAck => {
+                if self.retransmissions.get() >= MAX_RETRANSMIT {
+                    self.request_done(Err(ErrorCode::NOACK));
+                } else {
+                    self.retransmissions.set(self.retransmissions.get() + 1);
+                    self.timeout_ms.set(self.timeout_ms.get() * 2);
+                    self.set_timer(self.timeout_ms.get());
+                    self.request_action.set(RequestAction::Transmit);
+                    self.transmit_next();
+                }
+            }
+            RequestState::AwaitingResponse => self.request_done(Err(ErrorCode::NOACK)),
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> UDPSendClient for CoapEndpoint<'a, A> {
+    fn send_done(
+        &self,
+        result: Result<(), ErrorCode>,
+        mut dgram: LeasableMutableBuffer<'static, u8>,
+    ) {
+        dgram.reset();
+        let in_flight = self.in_flight.get();
+        self.in_flight.set(InFlight::Idle);
+        match in_flight {
+            InFlight::Request => {
+                self.request_buf.replace(dgram);
+                // Confirmable requests are retransmitted when the timer
+                // fires, but nothing will come of a lost non-confirmable one.
+                let confirmable = self.request.map_or(true, |request| request.confirmable);
+                if result.is_err() && !confirmable {
+                    self.request_done(result);
+                }
+            }
+            InFlight::Response | InFlight::Idle => {
+                self.response_buf.replace(dgram);
+            }
+        }
+        self.transmit_next();
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> UDPRecvClient for CoapEndpoint<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let message = match CoapMessage::decode(payload).done() {
+            Some((_, message)) => message,
+            None => {
+                // Confirmable messages with a format error are rejected
+                if let Some((_, header)) = CoapHeader::decode(payload).done() {
+                    if header.msg_type == CoapType::Confirmable {
+                        self.send_empty(CoapType::Reset, header.message_id, src_addr, src_port);
+                    }
+                }
+                return;
+            }
+        };
+
+        let header = message.header;
+        match header.msg_type {
+            CoapType::Acknowledgement | CoapType::Reset => {
+                self.receive_response(src_addr, src_port, &message)
+            }
+            _ if header.code.is_request() => self.receive_request(src_addr, src_port, &message),
+            _ if header.code.is_response() => self.receive_response(src_addr, src_port, &message),
+            CoapType::Confirmable => {
+                // Empty confirmable messages ("pings") and messages with
+                // reserved codes are answered with a reset
+                self.send_empty(CoapType::Reset, header.message_id, src_addr, src_port);
+            }
+            CoapType::NonConfirmable => {}
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! This file contains the types and functions used to encode and decode CoAP
+//! (RFC 7252) messages, as well as the Block1 and Block2 options used for
+//! block-wise transfers (RFC 7959).
+//!
+//! A CoAP message has the following layout:
+//!
+//! ```txt
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |Ver| T |  TKL  |      Code     |          Message ID           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Token (if any, TKL bytes) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Options (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |1 1 1 1 1 1 1 1|    Payload (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! Messages are encoded in place: the `CoapHeader` is encoded first, followed
+//! by the options through an `OptionEncoder` (which keeps track of the option
+//! number deltas), and finally the payload marker and the payload. Received
+//! messages are decoded into a `CoapMessage`, which validates the options and
+//! refers to the options and the payload in the received buffer.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+pub const COAP_VERSION: u8 = 1;
+pub const COAP_HDR_LEN: usize = 4;
+pub const COAP_DEFAULT_PORT: u16 = 5683;
+pub const MAX_TOKEN_LEN: usize = 8;
+pub const PAYLOAD_MARKER: u8 = 0xff;
+
+/// The largest block size exponent (SZX) a Block1 or Block2 option can
+/// carry, corresponding to 1024 byte blocks.
+pub const MAX_BLOCK_SZX: u8 = 6;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CoapType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl CoapType {
+    fn from_bits(bits: u8) -> CoapType {
+        match bits & 0b11 {
+            0 => CoapType::Confirmable,
+            1 => CoapType::NonConfirmable,
+            2 => CoapType::Acknowledgement,
+            _ => CoapType::Reset,
+        }
+    }
+}
+
+/// A CoAP message code, split into a 3-bit class and a 5-bit detail, and
+/// written as `class.detail` (e.g. 2.05 Content).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CoapCode(pub u8);
+
+impl CoapCode {
+    pub const EMPTY: CoapCode = CoapCode::new(0, 0);
+
+    // Request methods
+    pub const GET: CoapCode = CoapCode::new(0, 1);
+    pub const POST: CoapCode = CoapCode::new(0, 2);
+    pub const PUT: CoapCode = CoapCode::new(0, 3);
+    pub const DELETE: CoapCode = CoapCode::new(0, 4);
+
+    // Success responses
+    pub const CREATED: CoapCode = CoapCode::new(2, 1);
+    pub const DELETED: CoapCode = CoapCode::new(2, 2);
+    pub const VALID: CoapCode = CoapCode::new(2, 3);
+    pub const CHANGED: CoapCode = CoapCode::new(2, 4);
+    pub const CONTENT: CoapCode = CoapCode::new(2, 5);
+    pub const CONTINUE: CoapCode = CoapCode::new(2, 31);
+
+    // Client error responses
+    pub const BAD_REQUEST: CoapCode = CoapCode::new(4, 0);
+    pub const BAD_OPTION: CoapCode = CoapCode::new(4, 2);
+    pub const NOT_FOUND: CoapCode = CoapCode::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: CoapCode = CoapCode::new(4, 5);
+    pub const REQUEST_ENTITY_INCOMPLETE: CoapCode = CoapCode::new(4, 8);
+    pub const REQUEST_ENTITY_TOO_LARGE: CoapCode = CoapCode::new(4, 13);
+
+    // Server error responses
+    pub const INTERNAL_SERVER_ERROR: CoapCode = CoapCode::new(5, 0);
+    pub const NOT_IMPLEMENTED: CoapCode = CoapCode::new(5, 1);
+    pub const SERVICE_UNAVAILABLE: CoapCode = CoapCode::new(5, 3);
+
+    pub const fn new(class: u8, detail: u8) -> CoapCode {
+        CoapCode((class << 5) | (detail & 0x1f))
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(&self) -> u8 {
+        self.0 & 0x1f
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.class() == 0 && !self.is_empty()
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.class() >= 2 && self.class() <= 5
+    }
+}
+
+/// CoAP option numbers.
+pub mod option {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const IF_NONE_MATCH: u16 = 5;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const LOCATION_QUERY: u16 = 20;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const PROXY_URI: u16 = 35;
+    pub const PROXY_SCHEME: u16 = 39;
+    pub const SIZE1: u16 = 60;
+
+    /// Options with an odd number are critical: an endpoint that does not
+    /// understand them must reject the message carrying them.
+    pub fn is_critical(number: u16) -> bool {
+        number & 1 != 0
+    }
+}
+
+/// The token of a CoAP message, between 0 and 8 bytes long. Tokens are used
+/// to match responses to requests.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CoapToken {
+    len: u8,
+    bytes: [u8; MAX_TOKEN_LEN],
+}
+
+impl CoapToken {
+    pub const EMPTY: CoapToken = CoapToken {
+        len: 0,
+        bytes: [0; MAX_TOKEN_LEN],
+    };
+
+    /// Returns a token with the contents of `token`, or `None` if it is
+    /// longer than `MAX_TOKEN_LEN` bytes.
+    pub fn new(token: &[u8]) -> Option<CoapToken> {
+        if token.len() > MAX_TOKEN_LEN {
+            return None;
+        }
+        let mut bytes = [0; MAX_TOKEN_LEN];
+        bytes[..token.len()].copy_from_slice(token);
+        Some(CoapToken {
+            len: token.len() as u8,
+            bytes: bytes,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// The fixed header and the token of a CoAP message.
+#[derive(Copy, Clone, Debug)]
+pub struct CoapHeader {
+    pub msg_type: CoapType,
+    pub code: CoapCode,
+    pub message_id: u16,
+    pub token: CoapToken,
+}
+
+impl CoapHeader {
+    pub fn new(msg_type: CoapType, code: CoapCode, message_id: u16, token: CoapToken) -> Self {
+        CoapHeader {
+            msg_type: msg_type,
+            code: code,
+            message_id: message_id,
+            token: token,
+        }
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        COAP_HDR_LEN + self.token.len()
+    }
+
+    /// This function serializes the `CoapHeader` (including the token) into
+    /// the provided buffer, and returns the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size());
+
+        let first = (COAP_VERSION << 6) | ((self.msg_type as u8) << 4) | (self.token.len as u8);
+        let mut off = enc_consume!(buf, 0; encode_u8, first);
+        off = enc_consume!(buf, off; encode_u8, self.code.0);
+        off = enc_consume!(buf, off; encode_u16, self.message_id);
+        off = enc_consume!(buf, off; encode_bytes, self.token.as_slice());
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes a `CoapHeader` (including the token) from
+    /// the provided buffer. Messages with an unknown version or a token
+    /// longer than 8 bytes are rejected.
+    pub fn decode(buf: &[u8]) -> SResult<CoapHeader> {
+        stream_len_cond!(buf, COAP_HDR_LEN);
+
+        let (off, first) = dec_try!(buf; decode_u8);
+        stream_cond!(first >> 6 == COAP_VERSION);
+        let token_len = (first & 0x0f) as usize;
+        stream_cond!(token_len <= MAX_TOKEN_LEN);
+        let (off, code) = dec_try!(buf, off; decode_u8);
+        let (off, message_id) = dec_try!(buf, off; decode_u16);
+
+        let mut token = CoapToken::EMPTY;
+        token.len = token_len as u8;
+        let off = dec_consume!(buf, off; decode_bytes, &mut token.bytes[..token_len]);
+        stream_done!(
+            off,
+            CoapHeader::new(
+                CoapType::from_bits(first >> 4),
+                CoapCode(code),
+                message_id,
+                token
+            )
+        );
+    }
+}
+
+/// Encodes the value of an uint option in the minimal number of bytes, and
+/// returns the bytes in the returned array to use as the option value.
+fn uint_option_value(value: u32, out: &mut [u8; 4]) -> &[u8] {
+    *out = value.to_be_bytes();
+    let leading_zero_bytes = (value.leading_zeros() / 8) as usize;
+    &out[leading_zero_bytes..]
+}
+
+fn decode_uint_option_value(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, b| (acc << 8) | (*b as u32)))
+}
+
+/// Encodes the option delta or length nibble, and the extended bytes that
+/// follow the option header for values of 13 and above.
+fn option_nibble(value: u16) -> (u8, usize, u16) {
+    if value < 13 {
+        (value as u8, 0, 0)
+    } else if value < 269 {
+        (13, 1, value - 13)
+    } else {
+        (14, 2, value - 269)
+    }
+}
+
+/// Serializes options into a message. Options must be encoded in increasing
+/// option number order, since each option is encoded as the delta from the
+/// number of the previous one.
+pub struct OptionEncoder {
+    last_number: u16,
+}
+
+impl OptionEncoder {
+    pub fn new() -> OptionEncoder {
+        OptionEncoder { last_number: 0 }
+    }
+
+    /// Encodes an option with the given number and value, and returns the
+    /// number of bytes written.
+    pub fn encode(&mut self, buf: &mut [u8], number: u16, value: &[u8]) -> SResult<usize> {
+        stream_cond!(number >= self.last_number);
+        stream_cond!(value.len() <= u16::MAX as usize);
+        let (delta_nibble, delta_ext_len, delta_ext) = option_nibble(number - self.last_number);
+        let (len_nibble, len_ext_len, len_ext) = option_nibble(value.len() as u16);
+        stream_len_cond!(buf, 1 + delta_ext_len + len_ext_len + value.len());
+
+        let mut off = enc_consume!(buf, 0; encode_u8, (delta_nibble << 4) | len_nibble);
+        match delta_ext_len {
+            1 => off = enc_consume!(buf, off; encode_u8, delta_ext as u8),
+            2 => off = enc_consume!(buf, off; encode_u16, delta_ext),
+            _ => {}
+        }
+        match len_ext_len {
+            1 => off = enc_consume!(buf, off; encode_u8, len_ext as u8),
+            2 => off = enc_consume!(buf, off; encode_u16, len_ext),
+            _ => {}
+        }
+        off = enc_consume!(buf, off; encode_bytes, value);
+        self.last_number = number;
+        stream_done!(off, off);
+    }
+
+    /// Encodes an option carrying an unsigned integer.
+    pub fn encode_uint(&mut self, buf: &mut [u8], number: u16, value: u32) -> SResult<usize> {
+        let mut out = [0; 4];
+        self.encode(buf, number, uint_option_value(value, &mut out))
+    }
+
+    /// Encodes `path` as a sequence of options with the given number, one for
+    /// each `separator` delimited segment. Empty segments are skipped, so
+    /// `/sensors/temp` and `sensors/temp` are encoded identically.
+    pub fn encode_segments(
+        &mut self,
+        buf: &mut [u8],
+        number: u16,
+        path: &[u8],
+        separator: u8,
+    ) -> SResult<usize> {
+        let mut off = 0;
+        for segment in path.split(|b| *b == separator) {
+            if !segment.is_empty() {
+                off = enc_consume!(buf, off; self; encode, number, segment);
+            }
+        }
+        stream_done!(off, off);
+    }
+}
+
+/// Iterates over the `(number, value)` pairs of the options of a decoded
+/// `CoapMessage`.
+pub struct CoapOptions<'a> {
+    buf: &'a [u8],
+    last_number: u16,
+}
+
+impl<'a> CoapOptions<'a> {
+    /// Decodes the extended bytes of an option delta or length.
+    fn decode_nibble(buf: &[u8], nibble: u8) -> SResult<u16> {
+        match nibble {
+            13 => {
+                let (off, ext) = dec_try!(buf; decode_u8);
+                stream_done!(off, ext as u16 + 13);
+            }
+            14 => {
+                let (off, ext) = dec_try!(buf; decode_u16);
+                stream_cond!(ext <= u16::MAX - 269);
+                stream_done!(off, ext + 269);
+            }
+            15 => stream_err!(),
+            _ => stream_done!(0, nibble as u16),
+        }
+    }
+
+    /// Decodes the option at the start of `buf`, returning its number and
+    /// value.
+    fn decode_option(buf: &'a [u8], last_number: u16) -> SResult<(u16, &'a [u8])> {
+        let (off, first) = dec_try!(buf; decode_u8);
+        // The payload marker is not an option
+        stream_cond!(first != PAYLOAD_MARKER);
+        let (off, delta) = dec_try!(buf, off; Self::decode_nibble, first >> 4);
+        let (off, len) = dec_try!(buf, off; Self::decode_nibble, first & 0x0f);
+        let number = stream_from_option!(last_number.checked_add(delta));
+        let end = off + len as usize;
+        stream_len_cond!(buf, end);
+        stream_done!(end, (number, &buf[off..end]));
+    }
+}
+
+impl<'a> Iterator for CoapOptions<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        // Options have been validated when the message was decoded
+        let (off, (number, value)) = Self::decode_option(self.buf, self.last_number).done()?;
+        self.buf = &self.buf[off..];
+        self.last_number = number;
+        Some((number, value))
+    }
+}
+
+/// The contents of a Block1 or Block2 option: the block number, whether
+/// more blocks follow, and the block size exponent (the block size is
+/// `2 ** (szx + 4)` bytes).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BlockOption {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl BlockOption {
+    pub fn new(num: u32, more: bool, szx: u8) -> BlockOption {
+        BlockOption {
+            num: num,
+            more: more,
+            szx: szx,
+        }
+    }
+
+    /// Size of a block in bytes.
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// Offset of the block in the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Returns the value of the option as an unsigned integer.
+    pub fn value(&self) -> u32 {
+        (self.num << 4) | ((self.more as u32) << 3) | self.szx as u32
+    }
+
+    /// Parses the value of a Block1 or Block2 option. The reserved block
+    /// size exponent of 7 and block numbers larger than 20 bits are
+    /// rejected.
+    pub fn from_value(value: &[u8]) -> Option<BlockOption> {
+        if value.len() > 3 {
+            return None;
+        }
+        let value = decode_uint_option_value(value)?;
+        let szx = (value & 0x07) as u8;
+        if szx > MAX_BLOCK_SZX {
+            return None;
+        }
+        Some(BlockOption::new(value >> 4, value & 0x08 != 0, szx))
+    }
+}
+
+/// A decoded CoAP message. The options and the payload refer to the buffer
+/// the message was decoded from.
+pub struct CoapMessage<'a> {
+    pub header: CoapHeader,
+    options: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> CoapMessage<'a> {
+    /// Decodes a message from `buf`, validating the encoding of all of its
+    /// options. An empty message (code 0.00) must consist of the header
+    /// only.
+    pub fn decode(buf: &'a [u8]) -> SResult<CoapMessage<'a>> {
+        let (options_start, header) = dec_try!(buf; CoapHeader::decode);
+        if header.code.is_empty() {
+            stream_cond!(buf.len() == COAP_HDR_LEN);
+            stream_done!(
+                buf.len(),
+                CoapMessage {
+                    header: header,
+                    options: &[],
+                    payload: &[],
+                }
+            );
+        }
+
+        let mut off = options_start;
+        let mut last_number = 0;
+        while off < buf.len() && buf[off] != PAYLOAD_MARKER {
+            let (next, (number, _)) = dec_try!(buf, off; CoapOptions::decode_option, last_number);
+            last_number = number;
+            off = next;
+        }
+        let options = &buf[options_start..off];
+        let payload = if off < buf.len() {
+            // A payload marker followed by a zero-length payload is a
+            // message format error
+            stream_cond!(off + 1 < buf.len());
+            &buf[off + 1..]
+        } else {
+            &[]
+        };
+        stream_done!(
+            buf.len(),
+            CoapMessage {
+                header: header,
+                options: options,
+                payload: payload,
+            }
+        );
+    }
+
+    pub fn options(&self) -> CoapOptions<'a> {
+        CoapOptions {
+            buf: self.options,
+            last_number: 0,
+        }
+    }
+
+    /// Returns the value of the first option with the given number.
+    pub fn option(&self, number: u16) -> Option<&'a [u8]> {
+        self.options()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value of the first option with the given number,
+    /// interpreted as an unsigned integer.
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.option(number).and_then(decode_uint_option_value)
+    }
+
+    pub fn block1(&self) -> Option<BlockOption> {
+        self.option(option::BLOCK1)
+            .and_then(BlockOption::from_value)
+    }
+
+    pub fn block2(&self) -> Option<BlockOption> {
+        self.option(option::BLOCK2)
+            .and_then(BlockOption::from_value)
+    }
+
+    /// Returns the number of the first critical option that is not in
+    /// `recognized`, if any.
+    pub fn unrecognized_critical_option(&self, recognized: &[u16]) -> Option<u16> {
+        self.options()
+            .map(|(number, _)| number)
+            .find(|number| option::is_critical(*number) && !recognized.contains(number))
+    }
+
+    /// Returns whether the Uri-Path options of the message match `path`, a
+    /// `/` separated path such as `sensors/temp`. Empty segments of `path`
+    /// are ignored, as they are when encoding it.
+    pub fn uri_path_matches(&self, path: &[u8]) -> bool {
+        let mut segments = path.split(|b| *b == b'/').filter(|s| !s.is_empty());
+        let mut options = self.options().filter(|(n, _)| *n == option::URI_PATH);
+        loop {
+            match (segments.next(), options.next()) {
+                (None, None) => return true,
+                (Some(segment), Some((_, value))) if segment == value => {}
+                _ => return false,
+            }
+        }
+    }
+}
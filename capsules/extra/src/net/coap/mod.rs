@@ -0,0 +1,9 @@
+pub mod driver;
+pub mod endpoint;
+pub mod message;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::driver::CoapDriver;
+pub use self::driver::DRIVER_NUM;
@@ -0,0 +1,633 @@
+//! Tests of the decoding of CoAP messages and options, and of an endpoint
+//! exchanging messages with a peer over a simulated link: retransmissions of
+//! confirmable requests, and block-wise transfers on the client and server
+//! sides.
+
+use crate::net::coap::endpoint::{CoapClient, CoapEndpoint, CoapRequest, CoapResourceId};
+use crate::net::coap::endpoint::{CoapServer, MAX_RETRANSMIT, RESPONSE_TIMEOUT_MS};
+use crate::net::coap::message::{option, BlockOption, CoapCode, CoapHeader, CoapMessage};
+use crate::net::coap::message::{CoapToken, CoapType, OptionEncoder, PAYLOAD_MARKER};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::{AddrRange, NetworkCapability};
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::UDPSender;
+use crate::testing::alarm::MockAlarm;
+use crate::testing::udp::MockUdpSender;
+use core::cell::{Cell, RefCell};
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::ErrorCode;
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+
+/// Encodes a message with a Uri-Path, the given uint options (which must
+/// come after Uri-Path, in increasing number order) and a payload.
+fn encode(header: CoapHeader, path: &[u8], options: &[(u16, u32)], payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 512];
+    let mut off = header.encode(&mut buf).done().unwrap().0;
+    let mut encoder = OptionEncoder::new();
+    off += encoder
+        .encode_segments(&mut buf[off..], option::URI_PATH, path, b'/')
+        .done()
+        .unwrap()
+        .0;
+    for (number, value) in options {
+        off += encoder
+            .encode_uint(&mut buf[off..], *number, *value)
+            .done()
+            .unwrap()
+            .0;
+    }
+    if !payload.is_empty() {
+        buf[off] = PAYLOAD_MARKER;
+        buf[off + 1..off + 1 + payload.len()].copy_from_slice(payload);
+        off += 1 + payload.len();
+    }
+    buf.truncate(off);
+    buf
+}
+
+fn decode(buf: &[u8]) -> Option<CoapMessage> {
+    CoapMessage::decode(buf).done().map(|(_, message)| message)
+}
+
+/// A message of code 0.01 (GET) with message ID 1 and no token, followed by
+/// `rest`.
+fn get(rest: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0x40, 0x01, 0x00, 0x01];
+    buf.extend_from_slice(rest);
+    buf
+}
+
+// Messages
+
+#[test]
+fn header_roundtrip() {
+    let token = CoapToken::new(&[0xde, 0xad, 0xbe, 0xef]).unwrap();
+    let header = CoapHeader::new(CoapType::NonConfirmable, CoapCode::PUT, 0x1234, token);
+    let mut buf = [0; 8];
+    assert_eq!(header.encode(&mut buf).done().map(|(len, _)| len), Some(8));
+    assert_eq!(buf, [0x54, 0x03, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef]);
+
+    let (len, decoded) = CoapHeader::decode(&buf).done().unwrap();
+    assert_eq!(len, 8);
+    assert_eq!(decoded.msg_type, CoapType::NonConfirmable);
+    assert_eq!(decoded.code, CoapCode::PUT);
+    assert_eq!(decoded.message_id, 0x1234);
+    assert_eq!(decoded.token, token);
+}
+
+#[test]
+fn malformed_headers_are_rejected() {
+    // Too short
+    assert!(decode(&[0x40, 0x01, 0x00]).is_none());
+    // Version 2
+    assert!(decode(&[0x80, 0x01, 0x00, 0x01]).is_none());
+    // Token lengths 9 to 15 are reserved
+    assert!(decode(&[0x49, 0x01, 0x00, 0x01, 1, 2, 3, 4, 5, 6, 7, 8, 9]).is_none());
+    // Truncated token
+    assert!(decode(&[0x42, 0x01, 0x00, 0x01, 1]).is_none());
+}
+
+#[test]
+fn empty_messages_consist_of_the_header_only() {
+    let ack = decode(&[0x60, 0x00, 0x00, 0x01]).unwrap();
+    assert_eq!(ack.header.msg_type, CoapType::Acknowledgement);
+    assert!(ack.header.code.is_empty());
+    // With a token, an option or a payload
+    assert!(decode(&[0x61, 0x00, 0x00, 0x01, 1]).is_none());
+    assert!(decode(&[0x60, 0x00, 0x00, 0x01, 0xb1, b'a']).is_none());
+    assert!(decode(&[0x60, 0x00, 0x00, 0x01, 0xff, 1]).is_none());
+}
+
+#[test]
+fn payload_marker_must_be_followed_by_a_payload() {
+    assert_eq!(decode(&get(&[0xff, 1, 2])).unwrap().payload, &[1, 2]);
+    assert!(decode(&get(&[0xff])).is_none());
+    assert!(decode(&get(&[])).unwrap().payload.is_empty());
+}
+
+#[test]
+fn option_deltas_and_lengths_use_extended_bytes() {
+    let long = [0xaa; 300];
+    let mut buf = [0; 600];
+    let mut encoder = OptionEncoder::new();
+    let mut off = 0;
+    // Delta 12 and length 0 fit in the nibbles
+    off += encoder.encode(&mut buf[off..], 12, &[]).done().unwrap().0;
+    // Delta 13 and length 13 take one extended byte each
+    off += encoder
+        .encode(&mut buf[off..], 25, &long[..13])
+        .done()
+        .unwrap()
+        .0;
+    // Delta 269 and length 269 take two extended bytes each
+    off += encoder
+        .encode(&mut buf[off..], 294, &long[..269])
+        .done()
+        .unwrap()
+        .0;
+    // Delta 0, for a repeated option, and length 268 with one extended byte
+    let end = off
+        + encoder
+            .encode(&mut buf[off..], 294, &long[..268])
+            .done()
+            .unwrap()
+            .0;
+    assert_eq!(buf[0], 0xc0);
+    assert_eq!(buf[1..4], [0xdd, 0x00, 0x00]);
+    assert_eq!(buf[17..22], [0xee, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(buf[off..off + 2], [0x0d, 0xff]);
+    // Options must be encoded in increasing number order
+    assert!(encoder.encode(&mut buf[end..], 293, &[]).done().is_none());
+
+    let message = get(&buf[..end]);
+    let options: Vec<(u16, usize)> = decode(&message)
+        .unwrap()
+        .options()
+        .map(|(number, value)| (number, value.len()))
+        .collect();
+    assert_eq!(options, [(12, 0), (25, 13), (294, 269), (294, 268)]);
+}
+
+#[test]
+fn reserved_option_nibbles_are_rejected() {
+    // Delta nibble 15 is only valid as part of the payload marker
+    assert!(decode(&get(&[0xf0])).is_none());
+    // Length nibble 15
+    assert!(decode(&get(&[0x1f])).is_none());
+}
+
+#[test]
+fn overflowing_option_numbers_are_rejected() {
+    // The largest delta, to option number 65535
+    let message = get(&[0xe0, 0xfe, 0xf2]);
+    assert_eq!(decode(&message).unwrap().option(65535), Some(&[][..]));
+    // A delta beyond 65535
+    assert!(decode(&get(&[0xe0, 0xfe, 0xf3])).is_none());
+    // An option after option 65535
+    assert!(decode(&get(&[0xe0, 0xfe, 0xf2, 0x10])).is_none());
+}
+
+#[test]
+fn truncated_options_are_rejected() {
+    // Value shorter than its length
+    assert!(decode(&get(&[0xb4, b'a', b'b'])).is_none());
+    // Missing extended delta and length bytes
+    assert!(decode(&get(&[0xd0])).is_none());
+    assert!(decode(&get(&[0xe0, 0x01])).is_none());
+    assert!(decode(&get(&[0x1d])).is_none());
+}
+
+#[test]
+fn uri_path_matches_segments() {
+    let message = encode(
+        CoapHeader::new(CoapType::Confirmable, CoapCode::GET, 1, CoapToken::EMPTY),
+        b"/sensors/temp",
+        &[],
+        &[],
+    );
+    let message = decode(&message).unwrap();
+    assert!(message.uri_path_matches(b"sensors/temp"));
+    assert!(message.uri_path_matches(b"/sensors//temp/"));
+    assert!(!message.uri_path_matches(b"sensors"));
+    assert!(!message.uri_path_matches(b"sensors/temp/max"));
+}
+
+#[test]
+fn block_options_roundtrip() {
+    let block = BlockOption::new(5, true, 2);
+    assert_eq!(block.value(), 0x5a);
+    assert_eq!(block.size(), 64);
+    assert_eq!(block.offset(), 320);
+    assert_eq!(BlockOption::from_value(&[0x5a]), Some(block));
+    assert_eq!(
+        BlockOption::from_value(&[0x12, 0x34, 0x56]),
+        Some(BlockOption::new(0x1234_5, false, 6))
+    );
+    // An empty value is block 0 of 16 bytes
+    assert_eq!(
+        BlockOption::from_value(&[]),
+        Some(BlockOption::new(0, false, 0))
+    );
+    // Size exponent 7 is reserved, and block numbers have 20 bits
+    assert_eq!(BlockOption::from_value(&[0x07]), None);
+    assert_eq!(BlockOption::from_value(&[0, 0, 0, 0]), None);
+}
+
+// Endpoint
+
+const LOCAL_ADDR: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+const PEER_ADDR: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+const PORT: u16 = 5683;
+const PEER_PORT: u16 = 49152;
+
+/// Request payloads are sent in 16 byte blocks.
+const REQUEST_BUF_LEN: usize = 96;
+/// Resources are served in 32 byte blocks.
+const RESPONSE_BUF_LEN: usize = 64;
+
+/// Records the callbacks of the client of the endpoint, and provides the
+/// payload of its requests.
+struct Client {
+    payload: Vec<u8>,
+    /// The code, offset, payload and `more` of each response
+    responses: RefCell<Vec<(CoapCode, usize, Vec<u8>, bool)>>,
+    done: Cell<Option<Result<(), ErrorCode>>>,
+}
+
+impl CoapClient for Client {
+    fn request_payload(&self, offset: usize, buf: &mut [u8]) -> usize {
+        buf.copy_from_slice(&self.payload[offset..offset + buf.len()]);
+        buf.len()
+    }
+
+    fn response_received(&self, response: &CoapMessage, offset: usize, more: bool) {
+        self.responses.borrow_mut().push((
+            response.header.code,
+            offset,
+            response.payload.to_vec(),
+            more,
+        ));
+    }
+
+    fn request_done(&self, result: Result<(), ErrorCode>) {
+        self.done.set(Some(result));
+    }
+}
+
+/// Serves a single resource at `data`, and records the writes to it.
+struct Resource {
+    data: Vec<u8>,
+    /// The offset, data and `more` of each write
+    writes: RefCell<Vec<(usize, Vec<u8>, bool)>>,
+}
+
+const RESOURCE: CoapResourceId = CoapResourceId { owner: 0, index: 0 };
+
+impl CoapServer for Resource {
+    fn find_resource(&self, request: &CoapMessage) -> Result<CoapResourceId, CoapCode> {
+        if request.uri_path_matches(b"data") {
+            Ok(RESOURCE)
+        } else {
+            Err(CoapCode::NOT_FOUND)
+        }
+    }
+
+    fn resource_len(&self, _resource: CoapResourceId) -> usize {
+        self.data.len()
+    }
+
+    fn read_resource(&self, _resource: CoapResourceId, offset: usize, buf: &mut [u8]) -> usize {
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        buf.len()
+    }
+
+    fn write_resource(
+        &self,
+        _resource: CoapResourceId,
+        _method: CoapCode,
+        offset: usize,
+        data: &[u8],
+        more: bool,
+    ) -> Result<CoapCode, CoapCode> {
+        self.writes.borrow_mut().push((offset, data.to_vec(), more));
+        Ok(CoapCode::CHANGED)
+    }
+}
+
+type Endpoint = CoapEndpoint<'static, MockAlarm<'static, Freq1KHz>>;
+
+/// An endpoint talking to a peer played by the test.
+struct Test {
+    endpoint: &'static Endpoint,
+    link: &'static MockUdpSender,
+    alarm: &'static MockAlarm<'static, Freq1KHz>,
+    client: &'static Client,
+    resource: &'static Resource,
+}
+
+impl Test {
+    /// An endpoint whose requests carry `payload`, serving `data`.
+    fn new(payload: &[u8], data: &[u8]) -> Test {
+        let link = Box::leak(Box::new(MockUdpSender::new()));
+        let alarm = Box::leak(Box::new(MockAlarm::new()));
+        let net_cap = Box::leak(Box::new(NetworkCapability::new_for_test(AddrRange::Any)));
+        let endpoint = Box::leak(Box::new(CoapEndpoint::new(
+            link,
+            alarm,
+            net_cap,
+            Box::leak(vec![0; REQUEST_BUF_LEN].into_boxed_slice()),
+            Box::leak(vec![0; RESPONSE_BUF_LEN].into_boxed_slice()),
+        )));
+        link.set_client(endpoint);
+        alarm.set_alarm_client(endpoint);
+        let client = Box::leak(Box::new(Client {
+            payload: payload.to_vec(),
+            responses: RefCell::new(Vec::new()),
+            done: Cell::new(None),
+        }));
+        endpoint.set_client(client);
+        let resource = Box::leak(Box::new(Resource {
+            data: data.to_vec(),
+            writes: RefCell::new(Vec::new()),
+        }));
+        endpoint.set_server(resource);
+        Test {
+            endpoint,
+            link,
+            alarm,
+            client,
+            resource,
+        }
+    }
+
+    /// Issues a confirmable request to `data` on the peer.
+    fn request(&self, method: CoapCode) {
+        let request = CoapRequest {
+            dest: PEER_ADDR,
+            dst_port: PEER_PORT,
+            method: method,
+            confirmable: true,
+            payload_len: self.client.payload.len(),
+        };
+        assert_eq!(self.endpoint.request(request, b"data"), Ok(()));
+    }
+
+    /// Delivers a datagram from the peer.
+    fn receive(&self, datagram: &[u8]) {
+        self.endpoint
+            .receive(PEER_ADDR, LOCAL_ADDR, PEER_PORT, PORT, datagram);
+    }
+
+    /// Completes sending the datagram in flight, which must be addressed to
+    /// the peer, and returns it.
+    fn sent(&self) -> Vec<u8> {
+        assert_eq!(self.link.last_destination(), (PEER_ADDR, PEER_PORT));
+        self.link.complete().unwrap().unwrap()
+    }
+}
+
+/// An acknowledgement of `request` from the peer, carrying a response.
+fn ack(request: &CoapMessage, code: CoapCode, options: &[(u16, u32)], payload: &[u8]) -> Vec<u8> {
+    let header = CoapHeader::new(
+        CoapType::Acknowledgement,
+        code,
+        request.header.message_id,
+        request.header.token,
+    );
+    encode(header, b"", options, payload)
+}
+
+#[test]
+fn malformed_confirmable_messages_are_reset() {
+    let test = Test::new(b"", b"");
+    test.receive(&[0x40, 0x01, 0x12, 0x34, 0xf0]);
+    let reset = test.sent();
+    assert_eq!(reset, [0x70, 0x00, 0x12, 0x34]);
+
+    // Non-confirmable ones are silently dropped
+    test.receive(&[0x50, 0x01, 0x12, 0x35, 0xf0]);
+    assert_eq!(test.link.sent(), 1);
+    assert!(test.link.complete().is_none());
+}
+
+#[test]
+fn confirmable_requests_back_off_exponentially() {
+    let test = Test::new(b"", b"");
+    test.request(CoapCode::GET);
+    let request = test.sent();
+    let message_id = decode(&request).unwrap().header.message_id;
+    let mut timeout = 2000 + message_id as u32 % 1000;
+    for retransmission in 1..=MAX_RETRANSMIT as usize {
+        assert_eq!(test.alarm.remaining(), Some(timeout));
+        assert!(test.alarm.fire());
+        assert_eq!(test.link.sent(), 1 + retransmission);
+        assert_eq!(test.sent(), request);
+        timeout *= 2;
+    }
+    assert_eq!(test.alarm.remaining(), Some(timeout));
+    assert!(test.alarm.fire());
+    assert_eq!(test.link.sent(), 1 + MAX_RETRANSMIT as usize);
+    assert_eq!(test.client.done.get(), Some(Err(ErrorCode::NOACK)));
+    assert!(!test.endpoint.is_busy());
+}
+
+#[test]
+fn acknowledged_requests_wait_for_a_separate_response() {
+    let test = Test::new(b"", b"");
+    test.request(CoapCode::GET);
+    let request = test.sent();
+    let request = decode(&request).unwrap();
+    let empty_ack = CoapHeader::new(
+        CoapType::Acknowledgement,
+        CoapCode::EMPTY,
+        request.header.message_id,
+        CoapToken::EMPTY,
+    );
+    test.receive(&encode(empty_ack, b"", &[], &[]));
+    assert_eq!(test.alarm.remaining(), Some(RESPONSE_TIMEOUT_MS));
+
+    let header = CoapHeader::new(
+        CoapType::Confirmable,
+        CoapCode::CONTENT,
+        0x4321,
+        request.header.token,
+    );
+    test.receive(&encode(header, b"", &[], b"42"));
+    assert_eq!(test.sent(), [0x60, 0x00, 0x43, 0x21]);
+    assert_eq!(
+        *test.client.responses.borrow(),
+        [(CoapCode::CONTENT, 0, b"42".to_vec(), false)]
+    );
+    assert_eq!(test.client.done.get(), Some(Ok(())));
+    assert_eq!(test.alarm.remaining(), None);
+}
+
+#[test]
+fn request_payloads_are_sent_block_wise() {
+    let payload: Vec<u8> = (0..40).collect();
+    let test = Test::new(&payload, b"");
+    test.request(CoapCode::PUT);
+
+    for (num, (start, end)) in [(0, 16), (16, 32), (32, 40)].iter().enumerate() {
+        let more = *end < payload.len();
+        let request = test.sent();
+        let request = decode(&request).unwrap();
+        assert_eq!(request.header.code, CoapCode::PUT);
+        assert!(request.uri_path_matches(b"data"));
+        assert_eq!(
+            request.block1(),
+            Some(BlockOption::new(num as u32, more, 0))
+        );
+        // The total size is only sent with the first block
+        let size1 = if num == 0 { Some(40) } else { None };
+        assert_eq!(request.uint_option(option::SIZE1), size1);
+        assert_eq!(request.payload, &payload[*start..*end]);
+
+        let block1 = BlockOption::new(num as u32, more, 0).value();
+        let code = if more {
+            CoapCode::CONTINUE
+        } else {
+            CoapCode::CHANGED
+        };
+        test.receive(&ack(&request, code, &[(option::BLOCK1, block1)], &[]));
+    }
+    assert_eq!(
+        *test.client.responses.borrow(),
+        [(CoapCode::CHANGED, 0, Vec::new(), false)]
+    );
+    assert_eq!(test.client.done.get(), Some(Ok(())));
+}
+
+#[test]
+fn block_wise_responses_are_retrieved_block_by_block() {
+    let test = Test::new(b"", b"");
+    test.request(CoapCode::GET);
+    let request = test.sent();
+    let request = decode(&request).unwrap();
+    assert_eq!(request.block2(), None);
+    let block2 = BlockOption::new(0, true, 0).value();
+    test.receive(&ack(
+        &request,
+        CoapCode::CONTENT,
+        &[(option::BLOCK2, block2)],
+        &[1; 16],
+    ));
+
+    // The next block is requested with a new message ID
+    let next = test.sent();
+    let next = decode(&next).unwrap();
+    assert_eq!(next.block2(), Some(BlockOption::new(1, false, 0)));
+    assert_ne!(next.header.message_id, request.header.message_id);
+    assert!(next.uri_path_matches(b"data"));
+    let block2 = BlockOption::new(1, false, 0).value();
+    test.receive(&ack(
+        &next,
+        CoapCode::CONTENT,
+        &[(option::BLOCK2, block2)],
+        &[2; 5],
+    ));
+
+    assert_eq!(
+        *test.client.responses.borrow(),
+        [
+            (CoapCode::CONTENT, 0, vec![1; 16], true),
+            (CoapCode::CONTENT, 16, vec![2; 5], false),
+        ]
+    );
+    assert_eq!(test.client.done.get(), Some(Ok(())));
+}
+
+/// A confirmable request from the peer to `path`.
+fn peer_request(
+    code: CoapCode,
+    message_id: u16,
+    path: &[u8],
+    options: &[(u16, u32)],
+    payload: &[u8],
+) -> Vec<u8> {
+    let token = CoapToken::new(&[7, 7]).unwrap();
+    let header = CoapHeader::new(CoapType::Confirmable, code, message_id, token);
+    encode(header, path, options, payload)
+}
+
+#[test]
+fn resources_are_served_block_wise() {
+    let data: Vec<u8> = (0..80).collect();
+    let test = Test::new(b"", &data);
+
+    test.receive(&peer_request(CoapCode::GET, 1, b"data", &[], &[]));
+    let response = test.sent();
+    let response = decode(&response).unwrap();
+    assert_eq!(response.header.msg_type, CoapType::Acknowledgement);
+    assert_eq!(response.header.message_id, 1);
+    assert_eq!(response.header.token.as_slice(), [7, 7]);
+    assert_eq!(response.header.code, CoapCode::CONTENT);
+    assert_eq!(response.block2(), Some(BlockOption::new(0, true, 1)));
+    assert_eq!(response.uint_option(option::SIZE2), Some(80));
+    assert_eq!(response.payload, &data[..32]);
+
+    // Larger blocks than fit into the response buffer are served as
+    // several smaller ones
+    let block2 = BlockOption::new(1, false, 2).value();
+    test.receive(&peer_request(
+        CoapCode::GET,
+        2,
+        b"data",
+        &[(option::BLOCK2, block2)],
+        &[],
+    ));
+    let response = test.sent();
+    let response = decode(&response).unwrap();
+    assert_eq!(response.block2(), Some(BlockOption::new(2, false, 1)));
+    assert_eq!(response.uint_option(option::SIZE2), None);
+    assert_eq!(response.payload, &data[64..]);
+
+    // Blocks past the end of the resource
+    let block2 = BlockOption::new(3, false, 1).value();
+    test.receive(&peer_request(
+        CoapCode::GET,
+        3,
+        b"data",
+        &[(option::BLOCK2, block2)],
+        &[],
+    ));
+    let response = test.sent();
+    assert_eq!(
+        decode(&response).unwrap().header.code,
+        CoapCode::BAD_REQUEST
+    );
+
+    test.receive(&peer_request(CoapCode::GET, 4, b"other", &[], &[]));
+    let response = test.sent();
+    assert_eq!(decode(&response).unwrap().header.code, CoapCode::NOT_FOUND);
+}
+
+#[test]
+fn block_wise_payloads_are_written_block_by_block() {
+    let test = Test::new(b"", b"");
+
+    // The server asks for blocks that fit into its response buffer
+    let block1 = BlockOption::new(0, true, 2).value();
+    test.receive(&peer_request(
+        CoapCode::PUT,
+        1,
+        b"data",
+        &[(option::BLOCK1, block1)],
+        &[1; 64],
+    ));
+    let response = test.sent();
+    let response = decode(&response).unwrap();
+    assert_eq!(response.header.code, CoapCode::CONTINUE);
+    assert_eq!(response.block1(), Some(BlockOption::new(0, true, 1)));
+
+    let block1 = BlockOption::new(2, false, 1).value();
+    test.receive(&peer_request(
+        CoapCode::PUT,
+        2,
+        b"data",
+        &[(option::BLOCK1, block1)],
+        &[2; 10],
+    ));
+    let response = test.sent();
+    let response = decode(&response).unwrap();
+    assert_eq!(response.header.code, CoapCode::CHANGED);
+    assert_eq!(response.block1(), Some(BlockOption::new(2, false, 1)));
+
+    assert_eq!(
+        *test.resource.writes.borrow(),
+        [(0, vec![1; 64], true), (64, vec![2; 10], false)]
+    );
+}
+
+#[test]
+fn duplicate_requests_get_the_same_response() {
+    let test = Test::new(b"", b"resource");
+    let request = peer_request(CoapCode::GET, 9, b"data", &[], &[]);
+    test.receive(&request);
+    let response = test.sent();
+    test.receive(&request);
+    assert_eq!(test.sent(), response);
+    assert_eq!(decode(&response).unwrap().payload, b"resource");
+}
@@ -0,0 +1,333 @@
+//! Secure UDP userspace interface, over a DTLS session.
+//!
+//! A process uses this driver to set up a DTLS session, either connecting to
+//! a server or waiting for a client, and then exchange datagrams of
+//! application data with the peer. The pre-shared key and identity of the
+//! session are configured by the board, so that processes never see them.
+//!
+//! The kernel has a single session, which belongs to the process that
+//! started it until it closes or fails. Other processes get BUSY in the
+//! meantime. Application data received while the process has no receive
+//! buffer allowed is dropped.
+
+use crate::net::dtls::prf::SHA256_LEN;
+use crate::net::dtls::session::{DtlsClient, DtlsSession, SessionState};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::util::host_slice_to_u16;
+
+use core::cmp;
+use core::mem::size_of;
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
+use kernel::hil::digest;
+use kernel::hil::symmetric_encryption::AES128CCM;
+use kernel::hil::time;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::OptionalCell;
+use kernel::{ErrorCode, ProcessId};
+
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SecureUdp as usize;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// Application data to send
+    pub const PAYLOAD: usize = 0;
+    /// Address and port of the server to connect to
+    pub const DESTINATION: usize = 1;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Application data received
+    pub const RECEIVE: usize = 0;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 1;
+}
+
+/// Ids for upcalls
+mod upcall {
+    /// The handshake completed
+    pub const CONNECTED: usize = 0;
+    /// Application data was sent
+    pub const SEND_DONE: usize = 1;
+    /// Application data was received
+    pub const RECEIVED: usize = 2;
+    /// The session closed
+    pub const CLOSED: usize = 3;
+    /// The number of upcalls the kernel stores for this grant
+    pub const COUNT: u8 = 4;
+}
+
+#[derive(Default)]
+pub struct App {}
+
+pub struct SecureUdpDriver<
+    'a,
+    A: time::Alarm<'a>,
+    D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+    C: AES128CCM<'a>,
+> {
+    session: &'a DtlsSession<'a, A, D, C>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// Process the session belongs to
+    owner: OptionalCell<ProcessId>,
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > SecureUdpDriver<'a, A, D, C>
+{
+    pub fn new(
+        session: &'a DtlsSession<'a, A, D, C>,
+        grant: Grant<
+            App,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> SecureUdpDriver<'a, A, D, C> {
+        SecureUdpDriver {
+            session: session,
+            apps: grant,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    /// Parses the destination buffer of the process, which holds an IPv6
+    /// address followed by a port (in host byte order).
+    fn read_destination(kernel_data: &GrantKernelData) -> Result<(IPAddr, u16), ErrorCode> {
+        kernel_data
+            .get_readonly_processbuffer(ro_allow::DESTINATION)
+            .and_then(|destination| {
+                destination.enter(|destination| {
+                    const LEN: usize = size_of::<IPAddr>() + size_of::<u16>();
+                    if destination.len() != LEN {
+                        return Err(ErrorCode::INVAL);
+                    }
+                    let mut buf = [0; LEN];
+                    destination.copy_to_slice(&mut buf);
+                    let mut addr = IPAddr::new();
+                    addr.0.copy_from_slice(&buf[..size_of::<IPAddr>()]);
+                    Ok((addr, host_slice_to_u16(&buf[size_of::<IPAddr>()..])))
+                })
+            })
+            .unwrap_or(Err(ErrorCode::INVAL))
+    }
+
+    /// Returns whether `processid` may use the session, taking it over if
+    /// its owner no longer exists.
+    fn owns_session(&self, processid: ProcessId) -> bool {
+        let owner_alive = self.owner.map_or(false, |owner| {
+            *owner == processid || self.apps.enter(*owner, |_, _| ()).is_ok()
+        });
+        if !owner_alive {
+            // The previous owner exited, so its session is dropped
+            if self.owner.is_some() {
+                let _ = self.session.close();
+            }
+            self.owner.clear();
+        }
+        self.owner.map_or(true, |owner| *owner == processid)
+    }
+
+    /// Starts a session as a client (`connect`) or as a server, on behalf of
+    /// `processid`.
+    fn start(&self, processid: ProcessId, connect: bool) -> Result<(), ErrorCode> {
+        if !self.owns_session(processid) {
+            return Err(ErrorCode::BUSY);
+        }
+        let result = if connect {
+            let (dest, dst_port) = self.apps.enter(processid, |_, kernel_data| {
+                Self::read_destination(kernel_data)
+            })??;
+            self.session.connect(dest, dst_port)
+        } else {
+            self.session.listen()
+        };
+        if result.is_ok() {
+            self.owner.set(processid);
+        }
+        result
+    }
+
+    fn send(&self, processid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        if !self.owner.contains(&processid) {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.apps.enter(processid, |_, kernel_data| {
+            kernel_data
+                .get_readonly_processbuffer(ro_allow::PAYLOAD)
+                .and_then(|payload| {
+                    payload.enter(|payload| {
+                        if len > payload.len() {
+                            return Err(ErrorCode::SIZE);
+                        }
+                        self.session.send_with(len, |buf| {
+                            payload[..len].copy_to_slice(buf);
+                            Ok(())
+                        })
+                    })
+                })
+                .unwrap_or(Err(ErrorCode::INVAL))
+        })?
+    }
+
+    fn close(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        if !self.owner.contains(&processid) {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.session.close()?;
+        if self.session.state() == SessionState::Idle {
+            // An aborted handshake has no callback
+            self.owner.clear();
+        }
+        Ok(())
+    }
+
+    fn schedule_upcall(&self, upcall_num: usize, data: (usize, usize, usize)) {
+        self.owner.map(|processid| {
+            let _ = self.apps.enter(*processid, |_, kernel_data| {
+                kernel_data.schedule_upcall(upcall_num, data).ok();
+            });
+        });
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > SyscallDriver for SecureUdpDriver<'a, A, D, C>
+{
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num` (read-only)
+    ///
+    /// - `0`: Application data to send.
+    /// - `1`: Server to connect to: a 16-byte IPv6 address followed by the
+    ///        port, in host byte order.
+    ///
+    /// ### `allow_num` (read-write)
+    ///
+    /// - `0`: Receives the application data sent by the peer.
+
+    // Setup callbacks.
+    //
+    // ### `subscribe_num`
+    //
+    // - `0`: The handshake completed. The upcall carries the status of the
+    //        handshake: NOACK if the peer did not answer, NOSUPPORT if it does
+    //        not support the cipher suite and FAIL if authentication failed.
+    // - `1`: Application data was sent. The upcall carries the status.
+    // - `2`: Application data was received. The upcall carries the length of
+    //        the data, which is truncated to the length of the buffer.
+    // - `3`: The session closed. The upcall carries SUCCESS if it was closed
+    //        by either side, and FAIL if it failed.
+
+    /// Secure UDP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Connect to the server in the destination buffer.
+    /// - `2`: Wait for a client to connect.
+    /// - `3`: Send the first `arg1` bytes of the payload buffer. Returns OFF if
+    ///        the session is not connected, and BUSY if a record is being
+    ///        sent or received.
+    /// - `4`: Close the session. A handshake in progress is aborted
+    ///        immediately, without upcall.
+    /// - `5`: Get the maximum length of the data sent at once.
+    ///
+    /// Commands 1 and 2 return BUSY if the session belongs to another
+    /// process or is already started, and commands 3 and 4 return RESERVE if
+    /// the process did not start the session.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        _arg2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            1 => self.start(processid, true).into(),
+            2 => self.start(processid, false).into(),
+            3 => self.send(processid, arg1).into(),
+            4 => self.close(processid).into(),
+            5 => CommandReturn::success_u32(self.session.max_payload_len() as u32),
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > DtlsClient for SecureUdpDriver<'a, A, D, C>
+{
+    fn connected(&self, result: Result<(), ErrorCode>) {
+        self.schedule_upcall(
+            upcall::CONNECTED,
+            (kernel::errorcode::into_statuscode(result), 0, 0),
+        );
+        if result.is_err() {
+            self.owner.clear();
+        }
+    }
+
+    fn received(&self, data: &[u8]) {
+        self.owner.map(|processid| {
+            let _ = self.apps.enter(*processid, |_, kernel_data| {
+                let copied = kernel_data
+                    .get_readwrite_processbuffer(rw_allow::RECEIVE)
+                    .and_then(|buf| {
+                        buf.mut_enter(|buf| {
+                            let len = cmp::min(data.len(), buf.len());
+                            buf[..len].copy_from_slice(&data[..len]);
+                            len
+                        })
+                    })
+                    .unwrap_or(0);
+                if copied > 0 || data.is_empty() {
+                    kernel_data
+                        .schedule_upcall(upcall::RECEIVED, (copied, 0, 0))
+                        .ok();
+                }
+            });
+        });
+    }
+
+    fn send_done(&self, result: Result<(), ErrorCode>) {
+        self.schedule_upcall(
+            upcall::SEND_DONE,
+            (kernel::errorcode::into_statuscode(result), 0, 0),
+        );
+    }
+
+    fn closed(&self, result: Result<(), ErrorCode>) {
+        self.schedule_upcall(
+            upcall::CLOSED,
+            (kernel::errorcode::into_statuscode(result), 0, 0),
+        );
+        self.owner.clear();
+    }
+}
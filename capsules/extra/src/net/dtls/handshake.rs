@@ -0,0 +1,284 @@
+//! Messages of the DTLS 1.2 handshake (RFC 6347, section 4.2, and RFC 5246,
+//! section 7.4) used with the pre-shared key cipher suites of RFC 4279.
+//!
+//! Only the subset of the protocol needed by `TLS_PSK_WITH_AES_128_CCM_8`
+//! is supported: sessions are never resumed, no extensions are sent, and
+//! extensions of received hellos are ignored. Every handshake message starts
+//! with a 12 byte header, which allows messages to be fragmented across
+//! records; fragmented messages are not supported.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+use super::record::DTLS_1_2;
+
+/// Length of the header of handshake messages.
+pub const HANDSHAKE_HDR_LEN: usize = 12;
+
+/// The only cipher suite supported (RFC 6655).
+pub const TLS_PSK_WITH_AES_128_CCM_8: u16 = 0xc0a8;
+
+/// Length of the client and server randoms.
+pub const RANDOM_LEN: usize = 32;
+
+/// Maximum length of cookies (RFC 6347, section 4.2.1).
+pub const MAX_COOKIE_LEN: usize = 255;
+
+/// Length of the verify data of Finished messages.
+pub const VERIFY_DATA_LEN: usize = 12;
+
+/// Length of the master secret.
+pub const MASTER_SECRET_LEN: usize = 48;
+
+/// Length of the AES-128 keys of the cipher suite.
+pub const KEY_LEN: usize = 16;
+
+/// Length of the key block: the client and server write keys followed by
+/// the client and server implicit nonces.
+pub const KEY_BLOCK_LEN: usize = 2 * KEY_LEN + 2 * 4;
+
+pub const MASTER_SECRET_LABEL: &[u8] = b"master secret";
+pub const KEY_EXPANSION_LABEL: &[u8] = b"key expansion";
+pub const CLIENT_FINISHED_LABEL: &[u8] = b"client finished";
+pub const SERVER_FINISHED_LABEL: &[u8] = b"server finished";
+
+/// Handshake message types.
+pub mod handshake_type {
+    pub const HELLO_REQUEST: u8 = 0;
+    pub const CLIENT_HELLO: u8 = 1;
+    pub const SERVER_HELLO: u8 = 2;
+    pub const HELLO_VERIFY_REQUEST: u8 = 3;
+    pub const SERVER_KEY_EXCHANGE: u8 = 12;
+    pub const SERVER_HELLO_DONE: u8 = 14;
+    pub const CLIENT_KEY_EXCHANGE: u8 = 16;
+    pub const FINISHED: u8 = 20;
+}
+
+fn encode_u24(buf: &mut [u8], value: u32) -> SResult {
+    stream_len_cond!(buf, 3);
+    stream_cond!(value >> 24 == 0);
+    buf[..3].copy_from_slice(&value.to_be_bytes()[1..]);
+    stream_done!(3);
+}
+
+fn decode_u24(buf: &[u8]) -> SResult<u32> {
+    stream_len_cond!(buf, 3);
+    stream_done!(
+        3,
+        ((buf[0] as u32) << 16) | ((buf[1] as u32) << 8) | buf[2] as u32
+    );
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct HandshakeHeader {
+    pub msg_type: u8,
+    pub length: u32,
+    pub message_seq: u16,
+    pub fragment_offset: u32,
+    pub fragment_length: u32,
+}
+
+impl HandshakeHeader {
+    /// Creates the header of an unfragmented message with a body of `length`
+    /// bytes.
+    pub fn new(msg_type: u8, length: usize, message_seq: u16) -> HandshakeHeader {
+        HandshakeHeader {
+            msg_type: msg_type,
+            length: length as u32,
+            message_seq: message_seq,
+            fragment_offset: 0,
+            fragment_length: length as u32,
+        }
+    }
+
+    pub fn is_fragmented(&self) -> bool {
+        self.fragment_offset != 0 || self.fragment_length != self.length
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, HANDSHAKE_HDR_LEN);
+        let mut off = enc_consume!(buf, 0; encode_u8, self.msg_type);
+        off = enc_consume!(buf, off; encode_u24, self.length);
+        off = enc_consume!(buf, off; encode_u16, self.message_seq);
+        off = enc_consume!(buf, off; encode_u24, self.fragment_offset);
+        off = enc_consume!(buf, off; encode_u24, self.fragment_length);
+        stream_done!(off, off);
+    }
+
+    /// Deserializes a handshake header. The fragment must fit in `buf`.
+    pub fn decode(buf: &[u8]) -> SResult<HandshakeHeader> {
+        stream_len_cond!(buf, HANDSHAKE_HDR_LEN);
+        let (off, msg_type) = dec_try!(buf; decode_u8);
+        let (off, length) = dec_try!(buf, off; decode_u24);
+        let (off, message_seq) = dec_try!(buf, off; decode_u16);
+        let (off, fragment_offset) = dec_try!(buf, off; decode_u24);
+        let (off, fragment_length) = dec_try!(buf, off; decode_u24);
+        stream_cond!(fragment_offset + fragment_length <= length);
+        stream_len_cond!(buf, off + fragment_length as usize);
+        stream_done!(
+            off,
+            HandshakeHeader {
+                msg_type: msg_type,
+                length: length,
+                message_seq: message_seq,
+                fragment_offset: fragment_offset,
+                fragment_length: fragment_length,
+            }
+        );
+    }
+}
+
+/// Serializes the body of a ClientHello offering only
+/// `TLS_PSK_WITH_AES_128_CCM_8` and no compression.
+pub fn encode_client_hello(buf: &mut [u8], random: &[u8; RANDOM_LEN], cookie: &[u8]) -> SResult {
+    stream_cond!(cookie.len() <= MAX_COOKIE_LEN);
+    let mut off = enc_consume!(buf, 0; encode_u16, DTLS_1_2);
+    off = enc_consume!(buf, off; encode_bytes, random);
+    // Empty session ID
+    off = enc_consume!(buf, off; encode_u8, 0);
+    off = enc_consume!(buf, off; encode_u8, cookie.len() as u8);
+    off = enc_consume!(buf, off; encode_bytes, cookie);
+    off = enc_consume!(buf, off; encode_u16, 2);
+    off = enc_consume!(buf, off; encode_u16, TLS_PSK_WITH_AES_128_CCM_8);
+    // Only the null compression method
+    off = enc_consume!(buf, off; encode_u8, 1);
+    off = enc_consume!(buf, off; encode_u8, 0);
+    stream_done!(off);
+}
+
+pub struct ClientHello<'b> {
+    pub random: [u8; RANDOM_LEN],
+    pub cookie: &'b [u8],
+    /// Whether the client offers `TLS_PSK_WITH_AES_128_CCM_8` without
+    /// compression
+    pub acceptable: bool,
+}
+
+impl<'b> ClientHello<'b> {
+    pub fn decode(buf: &'b [u8]) -> SResult<ClientHello<'b>> {
+        let (off, version) = dec_try!(buf; decode_u16);
+        // DTLS versions count down
+        stream_cond!(version <= DTLS_1_2);
+        let mut random = [0; RANDOM_LEN];
+        let off = dec_consume!(buf, off; decode_bytes, &mut random);
+        let (off, session_id_len) = dec_try!(buf, off; decode_u8);
+        let off = off + session_id_len as usize;
+        let (off, cookie_len) = dec_try!(buf, off; decode_u8);
+        let cookie_end = off + cookie_len as usize;
+        stream_len_cond!(buf, cookie_end);
+        let cookie = &buf[off..cookie_end];
+
+        let (off, suites_len) = dec_try!(buf, cookie_end; decode_u16);
+        let suites_end = off + suites_len as usize;
+        stream_len_cond!(buf, suites_end);
+        let suite_offered = buf[off..suites_end]
+            .chunks_exact(2)
+            .any(|suite| suite == TLS_PSK_WITH_AES_128_CCM_8.to_be_bytes());
+
+        let (off, compressions_len) = dec_try!(buf, suites_end; decode_u8);
+        let compressions_end = off + compressions_len as usize;
+        stream_len_cond!(buf, compressions_end);
+        let null_offered = buf[off..compressions_end].contains(&0);
+
+        // Extensions are ignored
+        stream_done!(
+            compressions_end,
+            ClientHello {
+                random: random,
+                cookie: cookie,
+                acceptable: version == DTLS_1_2 && suite_offered && null_offered,
+            }
+        );
+    }
+}
+
+/// Serializes the body of a HelloVerifyRequest.
+pub fn encode_hello_verify_request(buf: &mut [u8], cookie: &[u8]) -> SResult {
+    stream_cond!(cookie.len() <= MAX_COOKIE_LEN);
+    let mut off = enc_consume!(buf, 0; encode_u16, DTLS_1_2);
+    off = enc_consume!(buf, off; encode_u8, cookie.len() as u8);
+    off = enc_consume!(buf, off; encode_bytes, cookie);
+    stream_done!(off);
+}
+
+/// Deserializes the body of a HelloVerifyRequest, returning the cookie.
+pub fn decode_hello_verify_request(buf: &[u8]) -> SResult<&[u8]> {
+    let (off, _version) = dec_try!(buf; decode_u16);
+    let (off, cookie_len) = dec_try!(buf, off; decode_u8);
+    let end = off + cookie_len as usize;
+    stream_len_cond!(buf, end);
+    stream_done!(end, &buf[off..end]);
+}
+
+/// Serializes the body of a ServerHello selecting
+/// `TLS_PSK_WITH_AES_128_CCM_8`.
+pub fn encode_server_hello(buf: &mut [u8], random: &[u8; RANDOM_LEN]) -> SResult {
+    let mut off = enc_consume!(buf, 0; encode_u16, DTLS_1_2);
+    off = enc_consume!(buf, off; encode_bytes, random);
+    // Sessions are not resumable, so no session ID is given
+    off = enc_consume!(buf, off; encode_u8, 0);
+    off = enc_consume!(buf, off; encode_u16, TLS_PSK_WITH_AES_128_CCM_8);
+    off = enc_consume!(buf, off; encode_u8, 0);
+    stream_done!(off);
+}
+
+pub struct ServerHello {
+    pub version: u16,
+    pub random: [u8; RANDOM_LEN],
+    pub cipher_suite: u16,
+    pub compression_method: u8,
+}
+
+impl ServerHello {
+    pub fn decode(buf: &[u8]) -> SResult<ServerHello> {
+        let (off, version) = dec_try!(buf; decode_u16);
+        let mut random = [0; RANDOM_LEN];
+        let off = dec_consume!(buf, off; decode_bytes, &mut random);
+        let (off, session_id_len) = dec_try!(buf, off; decode_u8);
+        let off = off + session_id_len as usize;
+        let (off, cipher_suite) = dec_try!(buf, off; decode_u16);
+        let (off, compression_method) = dec_try!(buf, off; decode_u8);
+        // Extensions are ignored
+        stream_done!(
+            off,
+            ServerHello {
+                version: version,
+                random: random,
+                cipher_suite: cipher_suite,
+                compression_method: compression_method,
+            }
+        );
+    }
+}
+
+/// Serializes the body of a ClientKeyExchange, which only holds the PSK
+/// identity (RFC 4279, section 2).
+pub fn encode_client_key_exchange(buf: &mut [u8], identity: &[u8]) -> SResult {
+    stream_cond!(identity.len() <= u16::MAX as usize);
+    let off = enc_consume!(buf, 0; encode_u16, identity.len() as u16);
+    let off = enc_consume!(buf, off; encode_bytes, identity);
+    stream_done!(off);
+}
+
+/// Deserializes the body of a ClientKeyExchange, returning the PSK identity.
+pub fn decode_client_key_exchange(buf: &[u8]) -> SResult<&[u8]> {
+    let (off, identity_len) = dec_try!(buf; decode_u16);
+    let end = off + identity_len as usize;
+    stream_len_cond!(buf, end);
+    stream_done!(end, &buf[off..end]);
+}
+
+/// Writes the premaster secret derived from `psk` into `buf`, and returns its
+/// length: the length of the key, as many zeros, the length of the key again
+/// and the key itself (RFC 4279, section 2).
+pub fn encode_premaster_secret(buf: &mut [u8], psk: &[u8]) -> SResult {
+    let len = psk.len();
+    stream_cond!(len <= u16::MAX as usize);
+    stream_len_cond!(buf, 4 + 2 * len);
+    let off = enc_consume!(buf, 0; encode_u16, len as u16);
+    buf[off..off + len].iter_mut().for_each(|b| *b = 0);
+    let off = enc_consume!(buf, off + len; encode_u16, len as u16);
+    let off = enc_consume!(buf, off; encode_bytes, psk);
+    stream_done!(off);
+}
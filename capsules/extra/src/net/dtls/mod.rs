@@ -0,0 +1,11 @@
+pub mod driver;
+pub mod handshake;
+pub mod prf;
+pub mod record;
+pub mod session;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::driver::SecureUdpDriver;
+pub use self::driver::DRIVER_NUM;
@@ -0,0 +1,357 @@
+//! The pseudorandom function of TLS 1.2 (RFC 5246, section 5), computed with
+//! HMAC-SHA256 over a SHA-256 digest engine.
+//!
+//! `PRF(secret, label, seed)` is `P_SHA256(secret, label || seed)`, where
+//!
+//! ```txt
+//! P_SHA256(secret, seed) = HMAC(secret, A(1) || seed) ||
+//!                          HMAC(secret, A(2) || seed) || ...
+//! A(0) = seed
+//! A(i) = HMAC(secret, A(i - 1))
+//! HMAC(K, m) = SHA256((K ^ opad) || SHA256((K ^ ipad) || m))
+//! ```
+//!
+//! HMAC is built from plain SHA-256 hashes, so that any SHA-256 engine can be
+//! used, including `Sha256Software`. Each hash is computed over a single
+//! buffer, which therefore needs to hold the padded key followed by the
+//! longest message, or the transcript hashed by `prf_with_hash`.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil::digest;
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::{LeasableBuffer, LeasableMutableBuffer};
+use kernel::ErrorCode;
+
+/// Length of a SHA-256 digest.
+pub const SHA256_LEN: usize = 32;
+
+/// Length of a SHA-256 block, and of the padded HMAC key.
+const SHA256_BLOCK_LEN: usize = 64;
+
+/// Maximum length of a secret. Longer HMAC keys would have to be hashed
+/// first, which TLS does not need.
+pub const MAX_SECRET_LEN: usize = SHA256_BLOCK_LEN;
+
+/// Maximum length of the seed, including the label: enough for a label
+/// followed by the client and server randoms.
+pub const MAX_SEED_LEN: usize = 16 + 2 * 32;
+
+/// Maximum number of bytes produced by a single computation.
+pub const MAX_OUTPUT_LEN: usize = 48;
+
+/// Minimum length of the buffer used to compute hashes.
+pub const MIN_BUF_LEN: usize = SHA256_BLOCK_LEN + SHA256_LEN + MAX_SEED_LEN;
+
+pub trait PrfClient {
+    /// Called when a computation completes. `output` is empty on failure.
+    fn prf_done(&self, result: Result<(), ErrorCode>, output: &[u8]);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Step {
+    Idle,
+    /// Hashing the transcript appended to the seed
+    Transcript,
+    /// Computing A(i)
+    InnerA,
+    OuterA,
+    /// Computing HMAC(secret, A(i) || seed)
+    InnerP,
+    OuterP,
+}
+
+impl Step {
+    fn is_inner(self) -> bool {
+        self == Step::InnerA || self == Step::InnerP
+    }
+}
+
+struct PrfState {
+    secret: [u8; MAX_SECRET_LEN],
+    seed: [u8; MAX_SEED_LEN],
+    seed_len: usize,
+    /// Last value of A(i)
+    a: [u8; SHA256_LEN],
+    /// Result of the last inner hash of HMAC
+    inner: [u8; SHA256_LEN],
+    output: [u8; MAX_OUTPUT_LEN],
+    output_len: usize,
+    produced: usize,
+}
+
+pub struct Prf<'a, D: digest::Digest<'a, SHA256_LEN> + digest::Sha256> {
+    digest: &'a D,
+    client: OptionalCell<&'a dyn PrfClient>,
+    buf: TakeCell<'static, [u8]>,
+    digest_buf: TakeCell<'static, [u8; SHA256_LEN]>,
+    step: Cell<Step>,
+    state: MapCell<PrfState>,
+}
+
+impl<'a, D: digest::Digest<'a, SHA256_LEN> + digest::Sha256> Prf<'a, D> {
+    pub fn new(
+        digest: &'a D,
+        buf: &'static mut [u8],
+        digest_buf: &'static mut [u8; SHA256_LEN],
+    ) -> Prf<'a, D> {
+        Prf {
+            digest: digest,
+            client: OptionalCell::empty(),
+            buf: TakeCell::new(buf),
+            digest_buf: TakeCell::new(digest_buf),
+            step: Cell::new(Step::Idle),
+            state: MapCell::new(PrfState {
+                secret: [0; MAX_SECRET_LEN],
+                seed: [0; MAX_SEED_LEN],
+                seed_len: 0,
+                a: [0; SHA256_LEN],
+                inner: [0; SHA256_LEN],
+                output: [0; MAX_OUTPUT_LEN],
+                output_len: 0,
+                produced: 0,
+            }),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn PrfClient) {
+        self.client.set(client);
+    }
+
+    pub fn busy(&self) -> bool {
+        self.step.get() != Step::Idle
+    }
+
+    /// Computes `output_len` bytes of `PRF(secret, label, seed_a || seed_b)`.
+    pub fn prf(
+        &self,
+        secret: &[u8],
+        label: &[u8],
+        seed_a: &[u8],
+        seed_b: &[u8],
+        output_len: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        if label.len() + seed_a.len() + seed_b.len() > MAX_SEED_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        self.setup(secret, label, output_len)?;
+        self.state.map(|state| {
+            let mut off = state.seed_len;
+            for part in [seed_a, seed_b] {
+                state.seed[off..off + part.len()].copy_from_slice(part);
+                off += part.len();
+            }
+            state.seed_len = off;
+        });
+        self.start_hmac(Step::InnerA)
+    }
+
+    /// Computes `output_len` bytes of `PRF(secret, label, SHA256(data))`, as
+    /// used for the verify data of Finished messages.
+    pub fn prf_with_hash(
+        &self,
+        secret: &[u8],
+        label: &[u8],
+        data: &[u8],
+        output_len: usize,
+    ) -> Result<(), ErrorCode> {
+        if self.busy() {
+            return Err(ErrorCode::BUSY);
+        }
+        if label.len() + SHA256_LEN > MAX_SEED_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.buf.map_or(0, |buf| buf.len()) < data.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.setup(secret, label, output_len)?;
+        self.buf.map(|buf| buf[..data.len()].copy_from_slice(data));
+        self.hash(Step::Transcript, data.len())
+    }
+
+    /// Stores the secret and the label, which starts the seed.
+    fn setup(&self, secret: &[u8], label: &[u8], output_len: usize) -> Result<(), ErrorCode> {
+        if secret.len() > MAX_SECRET_LEN
+            || output_len > MAX_OUTPUT_LEN
+            || self.buf.map_or(0, |buf| buf.len()) < MIN_BUF_LEN
+        {
+            return Err(ErrorCode::SIZE);
+        }
+        self.state.map_or(Err(ErrorCode::FAIL), |state| {
+            state.secret = [0; MAX_SECRET_LEN];
+            state.secret[..secret.len()].copy_from_slice(secret);
+            state.seed[..label.len()].copy_from_slice(label);
+            state.seed_len = label.len();
+            state.output_len = output_len;
+            state.produced = 0;
+            Ok(())
+        })
+    }
+
+    /// Fills the hash buffer with the input of the hash of `step`, and starts
+    /// hashing it.
+    fn start_hmac(&self, step: Step) -> Result<(), ErrorCode> {
+        let len = self.buf.map_or(0, |buf| {
+            self.state.map_or(0, |state| {
+                let pad = if step.is_inner() { 0x36 } else { 0x5c };
+                for (b, k) in buf.iter_mut().zip(state.secret.iter()) {
+                    *b = k ^ pad;
+                }
+                let mut off = SHA256_BLOCK_LEN;
+                let mut append = |data: &[u8]| {
+                    buf[off..off + data.len()].copy_from_slice(data);
+                    off += data.len();
+                };
+                match step {
+                    // A(1) is computed from the seed, the following values
+                    // from the previous one
+                    Step::InnerA if state.produced == 0 => append(&state.seed[..state.seed_len]),
+                    Step::InnerA => append(&state.a),
+                    Step::InnerP => {
+                        append(&state.a);
+                        append(&state.seed[..state.seed_len]);
+                    }
+                    _ => append(&state.inner),
+                }
+                off
+            })
+        });
+        if len < SHA256_BLOCK_LEN {
+            return Err(ErrorCode::NOMEM);
+        }
+        self.hash(step, len)
+    }
+
+    /// Hashes the first `len` bytes of the hash buffer.
+    fn hash(&self, step: Step, len: usize) -> Result<(), ErrorCode> {
+        let buf = self.buf.take().ok_or(ErrorCode::NOMEM)?;
+        if let Err(e) = self.digest.set_mode_sha256() {
+            self.buf.replace(buf);
+            return Err(e);
+        }
+        let mut lease = LeasableMutableBuffer::new(buf);
+        lease.slice(..len);
+        self.step.set(step);
+        self.digest.add_mut_data(lease).map_err(|(e, lease)| {
+            self.step.set(Step::Idle);
+            self.buf.replace(lease.take());
+            e
+        })
+    }
+
+    /// Moves on to the next step once the hash of the current one is known.
+    fn advance(&self, hash: &[u8; SHA256_LEN]) -> Result<(), ErrorCode> {
+        let step = self.step.get();
+        let done = self.state.map_or(Err(ErrorCode::FAIL), |state| {
+            match step {
+                Step::Transcript => {
+                    state.seed[state.seed_len..state.seed_len + SHA256_LEN].copy_from_slice(hash);
+                    state.seed_len += SHA256_LEN;
+                }
+                Step::InnerA | Step::InnerP => state.inner = *hash,
+                Step::OuterA => state.a = *hash,
+                Step::OuterP => {
+                    let len = cmp::min(SHA256_LEN, state.output_len - state.produced);
+                    state.output[state.produced..state.produced + len]
+                        .copy_from_slice(&hash[..len]);
+                    state.produced += len;
+                    return Ok(state.produced == state.output_len);
+                }
+                Step::Idle => return Err(ErrorCode::FAIL),
+            }
+            Ok(false)
+        })?;
+
+        if done {
+            self.finish();
+            return Ok(());
+        }
+        let next = match step {
+            Step::Transcript | Step::OuterP => Step::InnerA,
+            Step::InnerA => Step::OuterA,
+            Step::OuterA => Step::InnerP,
+            _ => Step::OuterP,
+        };
+        self.start_hmac(next)
+    }
+
+    fn finish(&self) {
+        let mut output = [0; MAX_OUTPUT_LEN];
+        let len = self.state.map_or(0, |state| {
+            state.secret = [0; MAX_SECRET_LEN];
+            output.copy_from_slice(&state.output);
+            state.output_len
+        });
+        self.step.set(Step::Idle);
+        self.client
+            .map(|client| client.prf_done(Ok(()), &output[..len]));
+    }
+
+    fn fail(&self, error: ErrorCode) {
+        self.state.map(|state| state.secret = [0; MAX_SECRET_LEN]);
+        self.step.set(Step::Idle);
+        self.client.map(|client| client.prf_done(Err(error), &[]));
+    }
+}
+
+impl<'a, D: digest::Digest<'a, SHA256_LEN> + digest::Sha256> digest::ClientData<SHA256_LEN>
+    for Prf<'a, D>
+{
+    fn add_data_done(&self, _result: Result<(), ErrorCode>, _data: LeasableBuffer<'static, u8>) {}
+
+    fn add_mut_data_done(
+        &self,
+        result: Result<(), ErrorCode>,
+        data: LeasableMutableBuffer<'static, u8>,
+    ) {
+        if result.is_ok() && data.len() != 0 {
+            // The engine did not consume all the data yet
+            if let Err((e, data)) = self.digest.add_mut_data(data) {
+                self.buf.replace(data.take());
+                self.fail(e);
+            }
+            return;
+        }
+        self.buf.replace(data.take());
+        if let Err(e) = result {
+            self.fail(e);
+            return;
+        }
+        match self.digest_buf.take() {
+            Some(digest_buf) => {
+                if let Err((e, digest_buf)) = self.digest.run(digest_buf) {
+                    self.digest_buf.replace(digest_buf);
+                    self.fail(e);
+                }
+            }
+            None => self.fail(ErrorCode::NOMEM),
+        }
+    }
+}
+
+impl<'a, D: digest::Digest<'a, SHA256_LEN> + digest::Sha256> digest::ClientHash<SHA256_LEN>
+    for Prf<'a, D>
+{
+    fn hash_done(&self, result: Result<(), ErrorCode>, digest: &'static mut [u8; SHA256_LEN]) {
+        let hash = *digest;
+        self.digest_buf.replace(digest);
+        if let Err(e) = result.and_then(|()| self.advance(&hash)) {
+            self.fail(e);
+        }
+    }
+}
+
+impl<'a, D: digest::Digest<'a, SHA256_LEN> + digest::Sha256> digest::ClientVerify<SHA256_LEN>
+    for Prf<'a, D>
+{
+    fn verification_done(
+        &self,
+        _result: Result<bool, ErrorCode>,
+        _compare: &'static mut [u8; SHA256_LEN],
+    ) {
+    }
+}
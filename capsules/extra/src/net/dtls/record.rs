@@ -0,0 +1,217 @@
+//! The DTLS 1.2 record layer (RFC 6347, section 4.1), and the AES-128-CCM-8
+//! record protection of RFC 6655.
+//!
+//! Every record starts with a 13 byte header:
+//!
+//! ```txt
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |     Type      |            Version            |     Epoch     |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |     Epoch     |            Sequence number (48 bits)          |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                 Sequence number               |     Length    |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |     Length    |   Fragment...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! Records of epoch 0 are sent in the clear. Once keys are established, the
+//! fragment of protected records is the explicit part of the nonce (8 bytes,
+//! the epoch and sequence number of the record), followed by the ciphertext
+//! and an 8 byte MIC. The CCM nonce is the 4 byte implicit IV derived with the
+//! keys, followed by the explicit nonce, and the additional data is the epoch,
+//! sequence number, type, version and length of the plaintext.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u32, decode_u8};
+use crate::net::stream::{encode_u16, encode_u32, encode_u8};
+
+/// Length of the record header.
+pub const RECORD_HDR_LEN: usize = 13;
+
+/// Version field of DTLS 1.2 records.
+pub const DTLS_1_2: u16 = 0xfefd;
+
+/// Version field of DTLS 1.0 records, which may carry a ClientHello.
+pub const DTLS_1_0: u16 = 0xfeff;
+
+/// Length of the explicit part of the nonce sent with protected records.
+pub const EXPLICIT_NONCE_LEN: usize = 8;
+
+/// Length of the implicit part of the nonce, derived with the keys.
+pub const IMPLICIT_NONCE_LEN: usize = 4;
+
+/// Length of the CCM nonce.
+pub const NONCE_LEN: usize = IMPLICIT_NONCE_LEN + EXPLICIT_NONCE_LEN;
+
+/// Length of the MIC of AES-128-CCM-8.
+pub const MIC_LEN: usize = 8;
+
+/// Length of the additional data authenticated with protected records.
+pub const AAD_LEN: usize = 13;
+
+/// Bytes added to the plaintext of protected records.
+pub const PROTECTION_OVERHEAD: usize = EXPLICIT_NONCE_LEN + MIC_LEN;
+
+/// Largest sequence number of an epoch.
+pub const MAX_SEQUENCE_NUMBER: u64 = (1 << 48) - 1;
+
+/// Record content types.
+pub mod content_type {
+    pub const CHANGE_CIPHER_SPEC: u8 = 20;
+    pub const ALERT: u8 = 21;
+    pub const HANDSHAKE: u8 = 22;
+    pub const APPLICATION_DATA: u8 = 23;
+}
+
+/// Alert levels and the alert descriptions used by the session.
+pub mod alert {
+    pub const WARNING: u8 = 1;
+    pub const FATAL: u8 = 2;
+
+    pub const CLOSE_NOTIFY: u8 = 0;
+    pub const UNEXPECTED_MESSAGE: u8 = 10;
+    pub const BAD_RECORD_MAC: u8 = 20;
+    pub const HANDSHAKE_FAILURE: u8 = 40;
+    pub const DECODE_ERROR: u8 = 50;
+    pub const DECRYPT_ERROR: u8 = 51;
+    pub const UNKNOWN_PSK_IDENTITY: u8 = 115;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RecordHeader {
+    pub content_type: u8,
+    pub version: u16,
+    pub epoch: u16,
+    pub sequence_number: u64,
+    /// Length of the fragment following the header
+    pub length: u16,
+}
+
+impl RecordHeader {
+    pub fn new(content_type: u8, epoch: u16, sequence_number: u64, length: u16) -> RecordHeader {
+        RecordHeader {
+            content_type: content_type,
+            version: DTLS_1_2,
+            epoch: epoch,
+            sequence_number: sequence_number,
+            length: length,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, RECORD_HDR_LEN);
+        stream_cond!(self.sequence_number <= MAX_SEQUENCE_NUMBER);
+
+        let mut off = enc_consume!(buf, 0; encode_u8, self.content_type);
+        off = enc_consume!(buf, off; encode_u16, self.version);
+        off = enc_consume!(buf, off; encode_u16, self.epoch);
+        off = enc_consume!(buf, off; encode_u16, (self.sequence_number >> 32) as u16);
+        off = enc_consume!(buf, off; encode_u32, self.sequence_number as u32);
+        off = enc_consume!(buf, off; encode_u16, self.length);
+        stream_done!(off, off);
+    }
+
+    /// Deserializes a record header. Records whose fragment does not fit in
+    /// `buf`, or whose version is neither DTLS 1.0 nor DTLS 1.2, are rejected.
+    pub fn decode(buf: &[u8]) -> SResult<RecordHeader> {
+        stream_len_cond!(buf, RECORD_HDR_LEN);
+
+        let (off, content_type) = dec_try!(buf; decode_u8);
+        let (off, version) = dec_try!(buf, off; decode_u16);
+        stream_cond!(version == DTLS_1_2 || version == DTLS_1_0);
+        let (off, epoch) = dec_try!(buf, off; decode_u16);
+        let (off, seq_high) = dec_try!(buf, off; decode_u16);
+        let (off, seq_low) = dec_try!(buf, off; decode_u32);
+        let (off, length) = dec_try!(buf, off; decode_u16);
+        stream_len_cond!(buf, off + length as usize);
+        stream_done!(
+            off,
+            RecordHeader {
+                content_type: content_type,
+                version: version,
+                epoch: epoch,
+                sequence_number: ((seq_high as u64) << 32) | seq_low as u64,
+                length: length,
+            }
+        );
+    }
+
+    /// Returns the epoch and sequence number as sent in the record header,
+    /// which is also the explicit nonce of protected records.
+    pub fn explicit_nonce(&self) -> [u8; EXPLICIT_NONCE_LEN] {
+        let mut nonce = [0; EXPLICIT_NONCE_LEN];
+        nonce[..2].copy_from_slice(&self.epoch.to_be_bytes());
+        nonce[2..].copy_from_slice(&self.sequence_number.to_be_bytes()[2..]);
+        nonce
+    }
+
+    /// Returns the additional data of a protected record with this header,
+    /// carrying `plaintext_len` bytes of plaintext.
+    pub fn additional_data(&self, plaintext_len: usize) -> [u8; AAD_LEN] {
+        let mut aad = [0; AAD_LEN];
+        aad[..EXPLICIT_NONCE_LEN].copy_from_slice(&self.explicit_nonce());
+        aad[8] = self.content_type;
+        aad[9..11].copy_from_slice(&self.version.to_be_bytes());
+        aad[11..13].copy_from_slice(&(plaintext_len as u16).to_be_bytes());
+        aad
+    }
+}
+
+/// Returns the CCM nonce of a protected record.
+pub fn ccm_nonce(
+    implicit: &[u8; IMPLICIT_NONCE_LEN],
+    explicit: &[u8; EXPLICIT_NONCE_LEN],
+) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..IMPLICIT_NONCE_LEN].copy_from_slice(implicit);
+    nonce[IMPLICIT_NONCE_LEN..].copy_from_slice(explicit);
+    nonce
+}
+
+/// Anti-replay window over the sequence numbers of an epoch (RFC 6347,
+/// section 4.1.2.6). Records older than the 64 latest sequence numbers are
+/// rejected, as are those already received.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReplayWindow {
+    /// Highest sequence number received plus one, 0 if none was
+    next: u64,
+    /// Bit `i` is set if `next - 1 - i` was received
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub const SIZE: u64 = 64;
+
+    pub fn new() -> ReplayWindow {
+        ReplayWindow { next: 0, bitmap: 0 }
+    }
+
+    /// Returns whether a record with sequence number `seq` may be accepted.
+    pub fn check(&self, seq: u64) -> bool {
+        if seq >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - seq;
+        age < Self::SIZE && self.bitmap & (1 << age) == 0
+    }
+
+    /// Marks `seq` as received. Must only be called for records that passed
+    /// `check` and whose MIC was verified.
+    pub fn update(&mut self, seq: u64) {
+        if seq >= self.next {
+            let shift = seq + 1 - self.next;
+            self.bitmap = if shift >= Self::SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.next = seq + 1;
+        } else {
+            self.bitmap |= 1 << (self.next - 1 - seq);
+        }
+    }
+}
@@ -0,0 +1,1459 @@
+//! A DTLS 1.2 session (RFC 6347) secured with a pre-shared key and the
+//! `TLS_PSK_WITH_AES_128_CCM_8` cipher suite (RFC 4279, RFC 6655), over a
+//! bound UDP port.
+//!
+//! The session acts either as a client, which connects to a peer, or as a
+//! server, which waits for a ClientHello from any peer and then serves that
+//! peer only until the session is closed. The handshake is the abbreviated
+//! PSK handshake:
+//!
+//! ```txt
+//!   Client                                   Server
+//!   ------                                   ------
+//!   ClientHello              -------->
+//!                            <--------       HelloVerifyRequest (optional)
+//!   ClientHello (with cookie) ------->
+//!                                            ServerHello
+//!                                            ServerKeyExchange (optional)
+//!                            <--------       ServerHelloDone
+//!   ClientKeyExchange
+//!   [ChangeCipherSpec]
+//!   Finished                 -------->
+//!                                            [ChangeCipherSpec]
+//!                            <--------       Finished
+//!   Application Data         <------->       Application Data
+//! ```
+//!
+//! Flights that expect an answer are retransmitted with an exponential
+//! back-off, and a flight is also retransmitted when the peer retransmits its
+//! previous flight. Once connected, application data is protected with
+//! AES-128-CCM-8, and replayed records are discarded.
+//!
+//! ```txt
+//!            DtlsClient
+//!                |
+//!           DtlsSession  <--- Alarm (retransmissions)
+//!          /     |     \
+//!      Rng      Prf     AES128CCM
+//!                |
+//!             SHA-256
+//!                |
+//!   UDPSender / UDPReceiver
+//! ```
+//!
+//! The session needs two buffers as long as the largest datagram: one for
+//! the flight or record being sent, which is kept around for
+//! retransmissions, and one for the datagram being received, which is
+//! decrypted in place. The CCM engine should be dedicated to the session,
+//! since the session changes its key and nonce before every record. See
+//! `components::dtls` for how the session is set up.
+
+// Known Problems and Remaining Work
+// ---------------------------------
+// The server does not send HelloVerifyRequests, so it keeps state for
+// unverified clients; the client supports them. Flights are retransmitted
+// unchanged, with the sequence numbers of the first transmission, which
+// peers accept since replay detection only applies to protected records.
+// Handshake messages must not be fragmented, and messages following one
+// that starts a cryptographic operation in the same record are dropped.
+// Once application data has been sent, the final flight of the server can
+// no longer be retransmitted. No alerts are sent on handshake failures,
+// renegotiation is not supported, and the epoch is never incremented past 1.
+
+use crate::net::dtls::handshake::{self, handshake_type, ClientHello, HandshakeHeader};
+use crate::net::dtls::handshake::{ServerHello, HANDSHAKE_HDR_LEN, TLS_PSK_WITH_AES_128_CCM_8};
+use crate::net::dtls::handshake::{KEY_BLOCK_LEN, KEY_LEN, MASTER_SECRET_LEN, RANDOM_LEN};
+use crate::net::dtls::handshake::{MASTER_SECRET_LABEL, VERIFY_DATA_LEN};
+use crate::net::dtls::prf::{Prf, PrfClient, MAX_SECRET_LEN, SHA256_LEN};
+use crate::net::dtls::record::{alert, ccm_nonce, content_type, RecordHeader, ReplayWindow};
+use crate::net::dtls::record::{DTLS_1_2, EXPLICIT_NONCE_LEN, IMPLICIT_NONCE_LEN, MIC_LEN};
+use crate::net::dtls::record::{PROTECTION_OVERHEAD, RECORD_HDR_LEN};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::stream::{encode_bytes, SResult};
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil::digest;
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM};
+use kernel::hil::time::{self, ConvertTicks};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
+use kernel::ErrorCode;
+
+/// Space for the handshake messages hashed into the Finished messages.
+pub const TRANSCRIPT_LEN: usize = 512;
+
+/// Maximum length of the pre-shared key, so that the premaster secret fits
+/// in a single HMAC block.
+pub const MAX_PSK_LEN: usize = (MAX_SECRET_LEN - 4) / 2;
+
+/// Maximum length of the PSK identity.
+pub const MAX_IDENTITY_LEN: usize = 32;
+
+/// Maximum length of the cookie of a HelloVerifyRequest accepted by the
+/// client.
+const MAX_STORED_COOKIE_LEN: usize = 32;
+
+/// Bytes added to application data when it is sent in a protected record.
+pub const RECORD_OVERHEAD: usize = RECORD_HDR_LEN + PROTECTION_OVERHEAD;
+
+/// Initial retransmission timeout of flights, in milliseconds.
+pub const INITIAL_TIMEOUT_MS: u32 = 1000;
+
+/// Upper bound of the retransmission timeout, in milliseconds.
+pub const MAX_TIMEOUT_MS: u32 = 60000;
+
+/// Number of retransmissions of a flight before giving up on the handshake.
+pub const MAX_RETRANSMIT: u8 = 6;
+
+/// Client of a `DtlsSession`.
+pub trait DtlsClient {
+    /// Called when the handshake completes. Returns `NOACK` if the peer did
+    /// not answer, `NOSUPPORT` if it does not support the cipher suite,
+    /// `SIZE` if the handshake messages do not fit in the buffers, and `FAIL`
+    /// if the handshake failed otherwise (including unknown identities and
+    /// wrong keys).
+    fn connected(&self, result: Result<(), ErrorCode>);
+
+    /// Called for each record of application data received from the peer.
+    fn received(&self, data: &[u8]);
+
+    /// Called when the data passed to `send` has been sent.
+    fn send_done(&self, result: Result<(), ErrorCode>);
+
+    /// Called when a connected session closes, either because `close` was
+    /// called or the peer closed it (`Ok`), or because of a fatal alert
+    /// (`FAIL`).
+    fn closed(&self, result: Result<(), ErrorCode>);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DtlsRole {
+    Client,
+    Server,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SessionState {
+    Idle,
+    /// Client: waiting for a HelloVerifyRequest or the ServerHello
+    WaitServerHello,
+    /// Client: waiting for the rest of the flight of the server
+    WaitServerHelloDone,
+    /// Client: final flight sent, waiting for the Finished of the server
+    WaitServerFinished,
+    /// Server: waiting for a ClientHello
+    Listening,
+    /// Server: ServerHello flight sent, waiting for the ClientKeyExchange
+    WaitClientKeyExchange,
+    /// Server: waiting for the ChangeCipherSpec and Finished of the client
+    WaitClientFinished,
+    Connected,
+    /// Sending a close_notify alert
+    Closing,
+}
+
+/// Asynchronous operation in progress. Only one is run at a time, and
+/// received records are only processed while none is.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Op {
+    None,
+    Random,
+    MasterSecret,
+    KeyBlock,
+    OwnFinished,
+    PeerFinished,
+    EncryptFlight,
+    EncryptData,
+    EncryptAlert,
+    Decrypt,
+}
+
+/// What the transmit buffer holds while it is being sent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum TxKind {
+    Flight,
+    Data,
+    Alert,
+}
+
+/// Handshake messages of the current handshake, in the order they were sent
+/// or received.
+struct Transcript {
+    buf: [u8; TRANSCRIPT_LEN],
+    len: usize,
+    /// Offset of the first message of the last flight sent
+    flight_start: usize,
+}
+
+struct Secrets {
+    client_random: [u8; RANDOM_LEN],
+    server_random: [u8; RANDOM_LEN],
+    cookie: [u8; MAX_STORED_COOKIE_LEN],
+    cookie_len: usize,
+    master_secret: [u8; MASTER_SECRET_LEN],
+    key_block: [u8; KEY_BLOCK_LEN],
+    /// Verify data of the Finished message received from the peer
+    peer_verify_data: [u8; VERIFY_DATA_LEN],
+}
+
+impl Secrets {
+    fn clear(&mut self) {
+        self.cookie_len = 0;
+        self.master_secret = [0; MASTER_SECRET_LEN];
+        self.key_block = [0; KEY_BLOCK_LEN];
+    }
+}
+
+pub struct DtlsSession<
+    'a,
+    A: time::Alarm<'a>,
+    D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+    C: AES128CCM<'a>,
+> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    net_cap: &'static NetworkCapability,
+    alarm: &'a A,
+    rng: &'a dyn rng::Rng<'a>,
+    ccm: &'a C,
+    prf: &'a Prf<'a, D>,
+    client: OptionalCell<&'a dyn DtlsClient>,
+    identity: &'static [u8],
+    psk: &'static [u8],
+
+    role: Cell<DtlsRole>,
+    state: Cell<SessionState>,
+    op: Cell<Op>,
+    peer: Cell<(IPAddr, u16)>,
+    transcript: MapCell<Transcript>,
+    secrets: MapCell<Secrets>,
+    keys_ready: Cell<bool>,
+    next_send_msg_seq: Cell<u16>,
+    next_recv_msg_seq: Cell<u16>,
+    /// Sequence number of the first message from the peer that the last
+    /// flight sent does not answer
+    flight_answered_seq: Cell<u16>,
+    tx_epoch: Cell<u16>,
+    tx_seq: Cell<u64>,
+    rx_epoch: Cell<u16>,
+    replay: Cell<ReplayWindow>,
+    /// Offset and header of the record being encrypted or decrypted
+    crypt_record: Cell<(usize, RecordHeader)>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_capacity: usize,
+    tx_len: Cell<usize>,
+    tx_kind: Cell<TxKind>,
+    /// Whether the transmit buffer holds the last flight sent
+    flight_valid: Cell<bool>,
+    /// Whether a flight is waiting for the transmit buffer to be sent
+    flight_pending: Cell<bool>,
+    timeout_ms: Cell<u32>,
+    retransmissions: Cell<u8>,
+
+    rx_buf: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    /// Offset of the next record to process in the received datagram
+    rx_off: Cell<usize>,
+    rx_busy: Cell<bool>,
+    rx_src: Cell<(IPAddr, u16)>,
+    /// Whether the received datagram already triggered a retransmission
+    retransmitted: Cell<bool>,
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > DtlsSession<'a, A, D, C>
+{
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        net_cap: &'static NetworkCapability,
+        alarm: &'a A,
+        rng: &'a dyn rng::Rng<'a>,
+        ccm: &'a C,
+        prf: &'a Prf<'a, D>,
+        identity: &'static [u8],
+        psk: &'static [u8],
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+    ) -> DtlsSession<'a, A, D, C> {
+        DtlsSession {
+            udp_sender: udp_sender,
+            net_cap: net_cap,
+            alarm: alarm,
+            rng: rng,
+            ccm: ccm,
+            prf: prf,
+            client: OptionalCell::empty(),
+            identity: identity,
+            psk: psk,
+            role: Cell::new(DtlsRole::Client),
+            state: Cell::new(SessionState::Idle),
+            op: Cell::new(Op::None),
+            peer: Cell::new((IPAddr::new(), 0)),
+            transcript: MapCell::new(Transcript {
+                buf: [0; TRANSCRIPT_LEN],
+                len: 0,
+                flight_start: 0,
+            }),
+            secrets: MapCell::new(Secrets {
+                client_random: [0; RANDOM_LEN],
+                server_random: [0; RANDOM_LEN],
+                cookie: [0; MAX_STORED_COOKIE_LEN],
+                cookie_len: 0,
+                master_secret: [0; MASTER_SECRET_LEN],
+                key_block: [0; KEY_BLOCK_LEN],
+                peer_verify_data: [0; VERIFY_DATA_LEN],
+            }),
+            keys_ready: Cell::new(false),
+            next_send_msg_seq: Cell::new(0),
+            next_recv_msg_seq: Cell::new(0),
+            flight_answered_seq: Cell::new(0),
+            tx_epoch: Cell::new(0),
+            tx_seq: Cell::new(0),
+            rx_epoch: Cell::new(0),
+            replay: Cell::new(ReplayWindow::new()),
+            crypt_record: Cell::new((0, RecordHeader::new(0, 0, 0, 0))),
+            tx_capacity: tx_buf.len(),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_len: Cell::new(0),
+            tx_kind: Cell::new(TxKind::Flight),
+            flight_valid: Cell::new(false),
+            flight_pending: Cell::new(false),
+            timeout_ms: Cell::new(INITIAL_TIMEOUT_MS),
+            retransmissions: Cell::new(0),
+            rx_buf: TakeCell::new(rx_buf),
+            rx_len: Cell::new(0),
+            rx_off: Cell::new(0),
+            rx_busy: Cell::new(false),
+            rx_src: Cell::new((IPAddr::new(), 0)),
+            retransmitted: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn DtlsClient) {
+        self.client.set(client);
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state.get()
+    }
+
+    pub fn role(&self) -> DtlsRole {
+        self.role.get()
+    }
+
+    /// Returns the address and port of the peer of the current session.
+    pub fn peer(&self) -> (IPAddr, u16) {
+        self.peer.get()
+    }
+
+    /// Largest amount of application data that can be sent in one record.
+    pub fn max_payload_len(&self) -> usize {
+        self.tx_capacity.saturating_sub(RECORD_OVERHEAD)
+    }
+
+    /// Starts a handshake with the server at `dest`:`dst_port`. The outcome
+    /// is reported through `DtlsClient::connected`.
+    pub fn connect(&self, dest: IPAddr, dst_port: u16) -> Result<(), ErrorCode> {
+        self.start(DtlsRole::Client)?;
+        self.peer.set((dest, dst_port));
+        self.state.set(SessionState::WaitServerHello);
+        self.op.set(Op::Random);
+        self.rng.get().map_err(|e| {
+            self.op.set(Op::None);
+            self.teardown();
+            e
+        })
+    }
+
+    /// Waits for a client to start a handshake. The outcome is reported
+    /// through `DtlsClient::connected`.
+    pub fn listen(&self) -> Result<(), ErrorCode> {
+        self.start(DtlsRole::Server)?;
+        self.state.set(SessionState::Listening);
+        Ok(())
+    }
+
+    /// Sends `data` to the peer in a protected record. Returns `OFF` if the
+    /// session is not connected, and `BUSY` if a record is being sent or
+    /// received. `DtlsClient::send_done` is called once it has been sent.
+    pub fn send(&self, data: &[u8]) -> Result<(), ErrorCode> {
+        self.send_with(data.len(), |buf| {
+            buf.copy_from_slice(data);
+            Ok(())
+        })
+    }
+
+    /// Same as `send`, with `fill` writing the `len` bytes of data to send
+    /// into the buffer it is passed.
+    pub fn send_with<F: FnOnce(&mut [u8]) -> Result<(), ErrorCode>>(
+        &self,
+        len: usize,
+        fill: F,
+    ) -> Result<(), ErrorCode> {
+        if self.state.get() != SessionState::Connected {
+            return Err(ErrorCode::OFF);
+        }
+        if len > self.max_payload_len() {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.op.get() != Op::None {
+            return Err(ErrorCode::BUSY);
+        }
+        let buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+        let start = RECORD_HDR_LEN + EXPLICIT_NONCE_LEN;
+        if let Err(e) = fill(&mut buf[start..start + len]) {
+            self.tx_buf.replace(buf);
+            return Err(e);
+        }
+        self.flight_valid.set(false);
+        self.tx_len.set(RECORD_OVERHEAD + len);
+        self.protect(buf, 0, content_type::APPLICATION_DATA, len, Op::EncryptData)
+    }
+
+    /// Closes the session. A connected session sends a close_notify alert to
+    /// the peer, and `DtlsClient::closed` is called once it has been sent. A
+    /// handshake in progress is aborted without any callback.
+    pub fn close(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            SessionState::Idle | SessionState::Closing => Err(ErrorCode::ALREADY),
+            SessionState::Connected => {
+                if self.op.get() != Op::None {
+                    return Err(ErrorCode::BUSY);
+                }
+                let buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+                let start = RECORD_HDR_LEN + EXPLICIT_NONCE_LEN;
+                buf[start] = alert::WARNING;
+                buf[start + 1] = alert::CLOSE_NOTIFY;
+                self.flight_valid.set(false);
+                self.tx_len.set(RECORD_OVERHEAD + 2);
+                self.protect(buf, 0, content_type::ALERT, 2, Op::EncryptAlert)?;
+                self.state.set(SessionState::Closing);
+                Ok(())
+            }
+            _ => {
+                self.teardown();
+                Ok(())
+            }
+        }
+    }
+
+    /// Resets the state of the session for a new handshake.
+    fn start(&self, role: DtlsRole) -> Result<(), ErrorCode> {
+        if self.state.get() != SessionState::Idle || self.op.get() != Op::None {
+            return Err(ErrorCode::BUSY);
+        }
+        if self.identity.len() > MAX_IDENTITY_LEN || self.psk.len() > MAX_PSK_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        self.role.set(role);
+        self.keys_ready.set(false);
+        self.next_send_msg_seq.set(0);
+        self.next_recv_msg_seq.set(0);
+        self.flight_answered_seq.set(0);
+        self.tx_epoch.set(0);
+        self.tx_seq.set(0);
+        self.rx_epoch.set(0);
+        self.replay.set(ReplayWindow::new());
+        self.flight_valid.set(false);
+        self.flight_pending.set(false);
+        self.transcript.map(|transcript| {
+            transcript.len = 0;
+            transcript.flight_start = 0;
+        });
+        self.secrets.map(|secrets| secrets.clear());
+        Ok(())
+    }
+
+    /// Forgets the session. Operations in progress complete, but their
+    /// results are ignored.
+    fn teardown(&self) {
+        let _ = self.alarm.disarm();
+        self.state.set(SessionState::Idle);
+        self.keys_ready.set(false);
+        self.flight_valid.set(false);
+        self.flight_pending.set(false);
+        self.secrets.map(|secrets| secrets.clear());
+    }
+
+    /// Ends the session because of `error`, and notifies the client.
+    fn fail(&self, error: ErrorCode) {
+        let state = self.state.get();
+        self.teardown();
+        match state {
+            SessionState::Idle => {}
+            SessionState::Connected | SessionState::Closing => {
+                self.client.map(|client| client.closed(Err(error)));
+            }
+            _ => {
+                self.client.map(|client| client.connected(Err(error)));
+            }
+        }
+    }
+
+    fn finish_close(&self) {
+        self.teardown();
+        self.client.map(|client| client.closed(Ok(())));
+    }
+
+    fn arm_timer(&self) {
+        let now = self.alarm.now();
+        self.alarm
+            .set_alarm(now, self.alarm.ticks_from_ms(self.timeout_ms.get()));
+    }
+
+    /// Appends a handshake message sent by the session to the transcript,
+    /// with `body` encoding the body of the message.
+    fn append_own_message<F: FnOnce(&mut [u8]) -> SResult>(
+        &self,
+        msg_type: u8,
+        body: F,
+    ) -> Result<(), ErrorCode> {
+        let message_seq = self.next_send_msg_seq.get();
+        self.transcript.map_or(Err(ErrorCode::FAIL), |transcript| {
+            let start = transcript.len;
+            let body_start = start + HANDSHAKE_HDR_LEN;
+            if body_start > TRANSCRIPT_LEN {
+                return Err(ErrorCode::SIZE);
+            }
+            let (body_len, ()) = body(&mut transcript.buf[body_start..])
+                .done()
+                .ok_or(ErrorCode::SIZE)?;
+            HandshakeHeader::new(msg_type, body_len, message_seq)
+                .encode(&mut transcript.buf[start..])
+                .done()
+                .ok_or(ErrorCode::SIZE)?;
+            transcript.len = body_start + body_len;
+            Ok(())
+        })?;
+        self.next_send_msg_seq.set(message_seq.wrapping_add(1));
+        Ok(())
+    }
+
+    /// Appends a handshake message received from the peer to the transcript.
+    fn append_received_message(&self, msg: &[u8]) -> Result<(), ErrorCode> {
+        self.transcript.map_or(Err(ErrorCode::FAIL), |transcript| {
+            let end = transcript.len + msg.len();
+            if end > TRANSCRIPT_LEN {
+                return Err(ErrorCode::SIZE);
+            }
+            transcript.buf[transcript.len..end].copy_from_slice(msg);
+            transcript.len = end;
+            Ok(())
+        })
+    }
+
+    /// Marks the messages appended to the transcript from now on as the next
+    /// flight to send.
+    fn start_flight(&self) {
+        self.transcript
+            .map(|transcript| transcript.flight_start = transcript.len);
+        self.flight_answered_seq.set(self.next_recv_msg_seq.get());
+    }
+
+    /// Writes a record of the current epoch with `data` in the clear at
+    /// `off` in `buf`, and returns the offset following it.
+    fn write_plaintext_record(
+        &self,
+        buf: &mut [u8],
+        off: usize,
+        content_type: u8,
+        data: &[u8],
+    ) -> Result<usize, ErrorCode> {
+        let end = off + RECORD_HDR_LEN + data.len();
+        if end > buf.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        let seq = self.tx_seq.get();
+        RecordHeader::new(content_type, self.tx_epoch.get(), seq, data.len() as u16)
+            .encode(&mut buf[off..])
+            .done()
+            .ok_or(ErrorCode::FAIL)?;
+        self.tx_seq.set(seq + 1);
+        buf[off + RECORD_HDR_LEN..end].copy_from_slice(data);
+        Ok(end)
+    }
+
+    /// Writes the messages of the next flight into `buf`, each in its own
+    /// record, and returns the length of the flight. A Finished message is
+    /// preceded by a ChangeCipherSpec, and its offset and length are also
+    /// returned since it still needs to be encrypted.
+    fn compose_flight(&self, buf: &mut [u8]) -> Result<(usize, Option<(usize, usize)>), ErrorCode> {
+        self.transcript.map_or(Err(ErrorCode::FAIL), |transcript| {
+            let mut off = 0;
+            let mut finished = None;
+            let mut pos = transcript.flight_start;
+            while pos < transcript.len {
+                let (_, header) = HandshakeHeader::decode(&transcript.buf[pos..transcript.len])
+                    .done()
+                    .ok_or(ErrorCode::FAIL)?;
+                let msg = &transcript.buf[pos..pos + HANDSHAKE_HDR_LEN + header.length as usize];
+                pos += msg.len();
+                if header.msg_type == handshake_type::FINISHED {
+                    off = self.write_plaintext_record(
+                        buf,
+                        off,
+                        content_type::CHANGE_CIPHER_SPEC,
+                        &[1],
+                    )?;
+                    self.tx_epoch.set(1);
+                    self.tx_seq.set(0);
+                    let data_start = off + RECORD_HDR_LEN + EXPLICIT_NONCE_LEN;
+                    let end = data_start + msg.len() + MIC_LEN;
+                    if end > buf.len() {
+                        return Err(ErrorCode::SIZE);
+                    }
+                    buf[data_start..data_start + msg.len()].copy_from_slice(msg);
+                    finished = Some((off, msg.len()));
+                    off = end;
+                } else {
+                    off = self.write_plaintext_record(buf, off, content_type::HANDSHAKE, msg)?;
+                }
+            }
+            Ok((off, finished))
+        })
+    }
+
+    /// Sends the messages appended to the transcript since the last call to
+    /// `start_flight`, once the transmit buffer is available.
+    fn send_flight(&self) -> Result<(), ErrorCode> {
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => {
+                self.flight_pending.set(true);
+                return Ok(());
+            }
+        };
+        self.flight_valid.set(false);
+        self.retransmissions.set(0);
+        self.timeout_ms.set(INITIAL_TIMEOUT_MS);
+        match self.compose_flight(buf) {
+            Ok((len, None)) => {
+                self.tx_buf.replace(buf);
+                self.tx_len.set(len);
+                self.flight_sent();
+                Ok(())
+            }
+            Ok((len, Some((start, msg_len)))) => {
+                self.tx_len.set(len);
+                self.protect(
+                    buf,
+                    start,
+                    content_type::HANDSHAKE,
+                    msg_len,
+                    Op::EncryptFlight,
+                )
+            }
+            Err(e) => {
+                self.tx_buf.replace(buf);
+                Err(e)
+            }
+        }
+    }
+
+    /// Transmits the flight in the transmit buffer, and waits for an answer
+    /// if one is expected.
+    fn flight_sent(&self) {
+        self.flight_valid.set(true);
+        let _ = self.transmit(TxKind::Flight);
+        if self.role.get() == DtlsRole::Client
+            || self.state.get() == SessionState::WaitClientKeyExchange
+        {
+            self.arm_timer();
+        } else if self.state.get() == SessionState::WaitClientFinished {
+            // The final flight of the server completes the handshake
+            self.state.set(SessionState::Connected);
+            self.client.map(|client| client.connected(Ok(())));
+        }
+    }
+
+    /// Retransmits the last flight, unless the transmit buffer is in use.
+    fn retransmit(&self) {
+        if self.flight_valid.get() && self.tx_buf.is_some() {
+            let _ = self.transmit(TxKind::Flight);
+        }
+    }
+
+    fn transmit(&self, kind: TxKind) -> Result<(), ErrorCode> {
+        let buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+        let mut lease = LeasableMutableBuffer::new(buf);
+        lease.slice(..self.tx_len.get());
+        let (dest, dst_port) = self.peer.get();
+        self.tx_kind.set(kind);
+        self.udp_sender
+            .send_to(dest, dst_port, lease, self.net_cap)
+            .map_err(|lease| {
+                self.tx_buf.replace(lease.take());
+                ErrorCode::FAIL
+            })
+    }
+
+    /// Sets the key and nonce of the CCM engine for a record sent by the
+    /// session (`own`) or by the peer.
+    fn set_record_keys(
+        &self,
+        own: bool,
+        explicit_nonce: &[u8; EXPLICIT_NONCE_LEN],
+    ) -> Result<(), ErrorCode> {
+        // The key block holds the client write key, the server write key, and
+        // the client and server implicit nonces
+        let client_keys = own == (self.role.get() == DtlsRole::Client);
+        let (key, iv) = if client_keys {
+            (0, 2 * KEY_LEN)
+        } else {
+            (KEY_LEN, 2 * KEY_LEN + IMPLICIT_NONCE_LEN)
+        };
+        self.secrets.map_or(Err(ErrorCode::FAIL), |secrets| {
+            let mut implicit = [0; IMPLICIT_NONCE_LEN];
+            implicit.copy_from_slice(&secrets.key_block[iv..iv + IMPLICIT_NONCE_LEN]);
+            self.ccm.set_key(&secrets.key_block[key..key + KEY_LEN])?;
+            self.ccm.set_nonce(&ccm_nonce(&implicit, explicit_nonce))
+        })
+    }
+
+    /// Encrypts the `plaintext_len` bytes of the record at `start` in `buf`,
+    /// which start after the space left for the record header and explicit
+    /// nonce. The additional data is placed right before the plaintext, and
+    /// replaced by the header once encryption completes.
+    fn protect(
+        &self,
+        buf: &'static mut [u8],
+        start: usize,
+        content_type: u8,
+        plaintext_len: usize,
+        op: Op,
+    ) -> Result<(), ErrorCode> {
+        let data_start = start + RECORD_HDR_LEN + EXPLICIT_NONCE_LEN;
+        if data_start + plaintext_len + MIC_LEN > buf.len() {
+            self.tx_buf.replace(buf);
+            return Err(ErrorCode::SIZE);
+        }
+        let seq = self.tx_seq.get();
+        let header = RecordHeader::new(
+            content_type,
+            self.tx_epoch.get(),
+            seq,
+            (plaintext_len + PROTECTION_OVERHEAD) as u16,
+        );
+        if let Err(e) = self.set_record_keys(true, &header.explicit_nonce()) {
+            self.tx_buf.replace(buf);
+            return Err(e);
+        }
+        self.tx_seq.set(seq + 1);
+        buf[start + EXPLICIT_NONCE_LEN..data_start]
+            .copy_from_slice(&header.additional_data(plaintext_len));
+        self.crypt_record.set((start, header));
+        self.op.set(op);
+        self.ccm
+            .crypt(
+                buf,
+                start + EXPLICIT_NONCE_LEN,
+                data_start,
+                plaintext_len,
+                MIC_LEN,
+                true,
+                true,
+            )
+            .map_err(|(e, buf)| {
+                self.op.set(Op::None);
+                self.tx_buf.replace(buf);
+                e
+            })
+    }
+
+    /// Decrypts the protected record at `start` in `buf`, in place.
+    fn unprotect(
+        &self,
+        buf: &'static mut [u8],
+        start: usize,
+        header: RecordHeader,
+    ) -> Result<(), ErrorCode> {
+        let plaintext_len = header.length as usize - PROTECTION_OVERHEAD;
+        let data_start = start + RECORD_HDR_LEN + EXPLICIT_NONCE_LEN;
+        let mut explicit_nonce = [0; EXPLICIT_NONCE_LEN];
+        explicit_nonce.copy_from_slice(&buf[start + RECORD_HDR_LEN..data_start]);
+        if let Err(e) = self.set_record_keys(false, &explicit_nonce) {
+            self.rx_buf.replace(buf);
+            return Err(e);
+        }
+        buf[start + EXPLICIT_NONCE_LEN..data_start]
+            .copy_from_slice(&header.additional_data(plaintext_len));
+        self.crypt_record.set((start, header));
+        self.op.set(Op::Decrypt);
+        self.ccm
+            .crypt(
+                buf,
+                start + EXPLICIT_NONCE_LEN,
+                data_start,
+                plaintext_len,
+                MIC_LEN,
+                true,
+                false,
+            )
+            .map_err(|(e, buf)| {
+                self.op.set(Op::None);
+                self.rx_buf.replace(buf);
+                e
+            })
+    }
+
+    /// Starts the PRF computation of `op` from the secrets of the session.
+    fn run_prf<F: FnOnce(&mut Secrets) -> Result<(), ErrorCode>>(
+        &self,
+        op: Op,
+        compute: F,
+    ) -> Result<(), ErrorCode> {
+        self.op.set(op);
+        self.secrets
+            .map_or(Err(ErrorCode::FAIL), compute)
+            .map_err(|e| {
+                self.op.set(Op::None);
+                e
+            })
+    }
+
+    /// Computes the verify data of a Finished message over the transcript.
+    fn run_finished_prf(&self, op: Op, label: &[u8]) -> Result<(), ErrorCode> {
+        self.run_prf(op, |secrets| {
+            self.transcript.map_or(Err(ErrorCode::FAIL), |transcript| {
+                self.prf.prf_with_hash(
+                    &secrets.master_secret,
+                    label,
+                    &transcript.buf[..transcript.len],
+                    VERIFY_DATA_LEN,
+                )
+            })
+        })
+    }
+
+    fn derive_master_secret(&self) -> Result<(), ErrorCode> {
+        let mut premaster = [0; MAX_SECRET_LEN];
+        let (len, ()) = handshake::encode_premaster_secret(&mut premaster, self.psk)
+            .done()
+            .ok_or(ErrorCode::SIZE)?;
+        let result = self.run_prf(Op::MasterSecret, |secrets| {
+            self.prf.prf(
+                &premaster[..len],
+                MASTER_SECRET_LABEL,
+                &secrets.client_random,
+                &secrets.server_random,
+                MASTER_SECRET_LEN,
+            )
+        });
+        premaster.iter_mut().for_each(|b| *b = 0);
+        result
+    }
+
+    fn send_client_hello(&self) -> Result<(), ErrorCode> {
+        let (random, cookie, cookie_len) = self.secrets.map_or(
+            ([0; RANDOM_LEN], [0; MAX_STORED_COOKIE_LEN], 0),
+            |secrets| (secrets.client_random, secrets.cookie, secrets.cookie_len),
+        );
+        // A ClientHello answered by a HelloVerifyRequest is not part of the
+        // handshake
+        self.transcript.map(|transcript| transcript.len = 0);
+        self.start_flight();
+        self.append_own_message(handshake_type::CLIENT_HELLO, |buf| {
+            handshake::encode_client_hello(buf, &random, &cookie[..cookie_len])
+        })?;
+        self.send_flight()
+    }
+
+    fn send_server_hello(&self) -> Result<(), ErrorCode> {
+        let random = self
+            .secrets
+            .map_or([0; RANDOM_LEN], |secrets| secrets.server_random);
+        self.start_flight();
+        self.append_own_message(handshake_type::SERVER_HELLO, |buf| {
+            handshake::encode_server_hello(buf, &random)
+        })?;
+        self.append_own_message(handshake_type::SERVER_HELLO_DONE, |_| SResult::Done(0, ()))?;
+        self.send_flight()
+    }
+
+    /// Processes the records of the received datagram, until one starts an
+    /// asynchronous operation. Processing resumes once it completes.
+    fn process_records(&self) {
+        while self.rx_busy.get() && self.op.get() == Op::None {
+            let off = self.rx_off.get();
+            let len = self.rx_len.get();
+            if off >= len || self.state.get() == SessionState::Idle {
+                self.rx_busy.set(false);
+                break;
+            }
+            let header = self.rx_buf.map_or(None, |buf| {
+                RecordHeader::decode(&buf[off..len])
+                    .done()
+                    .map(|(_, header)| header)
+            });
+            let header = match header {
+                Some(header) => header,
+                None => {
+                    // The rest of the datagram cannot be parsed
+                    self.rx_busy.set(false);
+                    break;
+                }
+            };
+            let data_start = off + RECORD_HDR_LEN;
+            let end = data_start + header.length as usize;
+            self.rx_off.set(end);
+
+            if header.epoch == 0 {
+                self.rx_buf.map(|buf| {
+                    self.handle_record(header.content_type, &buf[data_start..end], false)
+                });
+            } else if header.epoch == self.rx_epoch.get()
+                && header.length as usize >= PROTECTION_OVERHEAD
+                && self.replay.get().check(header.sequence_number)
+            {
+                if let Some(buf) = self.rx_buf.take() {
+                    // Records that cannot be decrypted are dropped
+                    let _ = self.unprotect(buf, off, header);
+                }
+            }
+        }
+    }
+
+    /// Handles the (decrypted) contents of a record.
+    fn handle_record(&self, content_type: u8, data: &[u8], protected: bool) {
+        match content_type {
+            content_type::HANDSHAKE => self.handle_handshake(data, protected),
+            content_type::CHANGE_CIPHER_SPEC => {
+                let expected = match (self.role.get(), self.state.get()) {
+                    (DtlsRole::Client, SessionState::WaitServerFinished) => true,
+                    (DtlsRole::Server, SessionState::WaitClientFinished) => self.keys_ready.get(),
+                    _ => false,
+                };
+                if expected && !protected && data == [1] && self.rx_epoch.get() == 0 {
+                    self.rx_epoch.set(1);
+                    self.replay.set(ReplayWindow::new());
+                }
+            }
+            content_type::ALERT if data.len() == 2 => {
+                self.handle_alert(data[0], data[1], protected)
+            }
+            content_type::APPLICATION_DATA => {
+                if protected && self.state.get() == SessionState::Connected {
+                    self.client.map(|client| client.received(data));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_alert(&self, level: u8, description: u8, protected: bool) {
+        match self.state.get() {
+            SessionState::Connected | SessionState::Closing => {
+                // Alerts in the clear could be forged by anyone
+                if !protected {
+                    return;
+                }
+                if description == alert::CLOSE_NOTIFY {
+                    self.finish_close();
+                } else if level == alert::FATAL {
+                    self.fail(ErrorCode::FAIL);
+                }
+            }
+            SessionState::Listening | SessionState::Idle => {}
+            _ => {
+                if level == alert::FATAL || description == alert::CLOSE_NOTIFY {
+                    self.fail(ErrorCode::FAIL);
+                }
+            }
+        }
+    }
+
+    /// Handles the handshake messages of a record.
+    fn handle_handshake(&self, data: &[u8], protected: bool) {
+        let mut off = 0;
+        while off < data.len()
+            && self.op.get() == Op::None
+            && self.state.get() != SessionState::Idle
+        {
+            let header = match HandshakeHeader::decode(&data[off..]).done() {
+                Some((_, header)) => header,
+                None => return,
+            };
+            let msg = &data[off..off + HANDSHAKE_HDR_LEN + header.fragment_length as usize];
+            off += msg.len();
+            if header.is_fragmented() {
+                continue;
+            }
+
+            // The first message from the peer may have any sequence number
+            let opening = match self.state.get() {
+                SessionState::WaitServerHello | SessionState::Listening => true,
+                _ => false,
+            };
+            let expected = self.next_recv_msg_seq.get();
+            if header.message_seq < expected {
+                // The peer retransmitted the flight our last flight answers,
+                // so ours was lost. A retransmission of the flight the peer
+                // sent since does not mean that, and answering it would make
+                // both sides retransmit forever.
+                if !opening
+                    && header.message_seq < self.flight_answered_seq.get()
+                    && !self.retransmitted.get()
+                {
+                    self.retransmitted.set(true);
+                    self.retransmit();
+                }
+                continue;
+            }
+            if header.message_seq > expected && !opening {
+                continue;
+            }
+            self.next_recv_msg_seq
+                .set(header.message_seq.wrapping_add(1));
+            if let Err(e) = self.handle_message(&header, msg, protected) {
+                self.fail(e);
+                return;
+            }
+        }
+    }
+
+    fn handle_message(
+        &self,
+        header: &HandshakeHeader,
+        msg: &[u8],
+        protected: bool,
+    ) -> Result<(), ErrorCode> {
+        use handshake_type::{
+            CLIENT_HELLO, CLIENT_KEY_EXCHANGE, FINISHED, HELLO_VERIFY_REQUEST, SERVER_HELLO,
+            SERVER_HELLO_DONE, SERVER_KEY_EXCHANGE,
+        };
+
+        let body = &msg[HANDSHAKE_HDR_LEN..];
+        // Only the Finished messages are sent after a ChangeCipherSpec
+        if protected != (header.msg_type == FINISHED) {
+            return Err(ErrorCode::FAIL);
+        }
+        match (self.role.get(), self.state.get(), header.msg_type) {
+            (DtlsRole::Client, SessionState::WaitServerHello, HELLO_VERIFY_REQUEST) => {
+                self.hello_verify_request(body)
+            }
+            (DtlsRole::Client, SessionState::WaitServerHello, SERVER_HELLO) => {
+                self.server_hello(msg, body)
+            }
+            (DtlsRole::Client, SessionState::WaitServerHelloDone, SERVER_KEY_EXCHANGE) => {
+                // The PSK identity hint is ignored
+                self.append_received_message(msg)
+            }
+            (DtlsRole::Client, SessionState::WaitServerHelloDone, SERVER_HELLO_DONE) => {
+                self.server_hello_done(msg)
+            }
+            (DtlsRole::Client, SessionState::WaitServerFinished, FINISHED) => {
+                self.peer_finished(msg, body)
+            }
+            (DtlsRole::Server, SessionState::Listening, CLIENT_HELLO) => {
+                self.client_hello(msg, body)
+            }
+            (DtlsRole::Server, SessionState::Listening, _) => {
+                self.next_recv_msg_seq.set(0);
+                Ok(())
+            }
+            (DtlsRole::Server, SessionState::WaitClientKeyExchange, CLIENT_KEY_EXCHANGE) => {
+                self.client_key_exchange(msg, body)
+            }
+            (DtlsRole::Server, SessionState::WaitClientFinished, FINISHED) => {
+                self.peer_finished(msg, body)
+            }
+            // Renegotiation is not supported
+            (_, SessionState::Connected, _) | (_, SessionState::Closing, _) => Ok(()),
+            _ => Err(ErrorCode::FAIL),
+        }
+    }
+
+    fn hello_verify_request(&self, body: &[u8]) -> Result<(), ErrorCode> {
+        let (_, cookie) = handshake::decode_hello_verify_request(body)
+            .done()
+            .ok_or(ErrorCode::FAIL)?;
+        if cookie.len() > MAX_STORED_COOKIE_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        self.secrets.map(|secrets| {
+            secrets.cookie[..cookie.len()].copy_from_slice(cookie);
+            secrets.cookie_len = cookie.len();
+        });
+        let _ = self.alarm.disarm();
+        self.send_client_hello()
+    }
+
+    fn server_hello(&self, msg: &[u8], body: &[u8]) -> Result<(), ErrorCode> {
+        let (_, hello) = ServerHello::decode(body).done().ok_or(ErrorCode::FAIL)?;
+        if hello.version != DTLS_1_2
+            || hello.cipher_suite != TLS_PSK_WITH_AES_128_CCM_8
+            || hello.compression_method != 0
+        {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        self.secrets
+            .map(|secrets| secrets.server_random = hello.random);
+        self.append_received_message(msg)?;
+        self.state.set(SessionState::WaitServerHelloDone);
+        Ok(())
+    }
+
+    fn server_hello_done(&self, msg: &[u8]) -> Result<(), ErrorCode> {
+        self.append_received_message(msg)?;
+        let _ = self.alarm.disarm();
+        self.start_flight();
+        self.append_own_message(handshake_type::CLIENT_KEY_EXCHANGE, |buf| {
+            handshake::encode_client_key_exchange(buf, self.identity)
+        })?;
+        self.derive_master_secret()
+    }
+
+    fn client_hello(&self, msg: &[u8], body: &[u8]) -> Result<(), ErrorCode> {
+        let hello = match ClientHello::decode(body).done() {
+            Some((_, hello)) if hello.acceptable => hello,
+            _ => {
+                // Keep listening for other clients
+                self.next_recv_msg_seq.set(0);
+                return Ok(());
+            }
+        };
+        // The server mirrors the sequence number of the ClientHello
+        self.next_send_msg_seq
+            .set(self.next_recv_msg_seq.get().wrapping_sub(1));
+        self.peer.set(self.rx_src.get());
+        self.secrets
+            .map(|secrets| secrets.client_random = hello.random);
+        self.transcript.map(|transcript| transcript.len = 0);
+        self.append_received_message(msg)?;
+        self.state.set(SessionState::WaitClientKeyExchange);
+        self.op.set(Op::Random);
+        self.rng.get().map_err(|e| {
+            self.op.set(Op::None);
+            e
+        })
+    }
+
+    fn client_key_exchange(&self, msg: &[u8], body: &[u8]) -> Result<(), ErrorCode> {
+        let (_, identity) = handshake::decode_client_key_exchange(body)
+            .done()
+            .ok_or(ErrorCode::FAIL)?;
+        if identity != self.identity {
+            return Err(ErrorCode::FAIL);
+        }
+        self.append_received_message(msg)?;
+        let _ = self.alarm.disarm();
+        self.state.set(SessionState::WaitClientFinished);
+        self.derive_master_secret()
+    }
+
+    fn peer_finished(&self, msg: &[u8], body: &[u8]) -> Result<(), ErrorCode> {
+        if body.len() != VERIFY_DATA_LEN {
+            return Err(ErrorCode::FAIL);
+        }
+        self.secrets
+            .map(|secrets| secrets.peer_verify_data.copy_from_slice(body));
+        let label = match self.role.get() {
+            DtlsRole::Client => handshake::SERVER_FINISHED_LABEL,
+            DtlsRole::Server => handshake::CLIENT_FINISHED_LABEL,
+        };
+        self.run_finished_prf(Op::PeerFinished, label)?;
+        // The Finished of the server covers the Finished of the client
+        if self.role.get() == DtlsRole::Server {
+            self.append_received_message(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Continues the handshake once the PRF computation of `op` completes.
+    fn prf_computed(&self, op: Op, output: &[u8]) -> Result<(), ErrorCode> {
+        match op {
+            Op::MasterSecret => {
+                self.secrets
+                    .map(|secrets| secrets.master_secret.copy_from_slice(output));
+                self.run_prf(Op::KeyBlock, |secrets| {
+                    self.prf.prf(
+                        &secrets.master_secret,
+                        handshake::KEY_EXPANSION_LABEL,
+                        &secrets.server_random,
+                        &secrets.client_random,
+                        KEY_BLOCK_LEN,
+                    )
+                })
+            }
+            Op::KeyBlock => {
+                self.secrets
+                    .map(|secrets| secrets.key_block.copy_from_slice(output));
+                self.keys_ready.set(true);
+                match self.role.get() {
+                    DtlsRole::Client => {
+                        self.run_finished_prf(Op::OwnFinished, handshake::CLIENT_FINISHED_LABEL)
+                    }
+                    // Wait for the ChangeCipherSpec and Finished of the client
+                    DtlsRole::Server => Ok(()),
+                }
+            }
+            Op::OwnFinished => {
+                self.append_own_message(handshake_type::FINISHED, |buf| encode_bytes(buf, output))?;
+                if self.role.get() == DtlsRole::Client {
+                    self.state.set(SessionState::WaitServerFinished);
+                }
+                self.send_flight()
+            }
+            Op::PeerFinished => {
+                let valid = self.secrets.map_or(false, |secrets| {
+                    output.len() == VERIFY_DATA_LEN
+                        && secrets
+                            .peer_verify_data
+                            .iter()
+                            .zip(output.iter())
+                            .fold(0, |acc, (a, b)| acc | (a ^ b))
+                            == 0
+                });
+                if !valid {
+                    return Err(ErrorCode::FAIL);
+                }
+                match self.role.get() {
+                    DtlsRole::Client => {
+                        let _ = self.alarm.disarm();
+                        self.state.set(SessionState::Connected);
+                        self.client.map(|client| client.connected(Ok(())));
+                        Ok(())
+                    }
+                    DtlsRole::Server => {
+                        self.start_flight();
+                        self.run_finished_prf(Op::OwnFinished, handshake::SERVER_FINISHED_LABEL)
+                    }
+                }
+            }
+            _ => Err(ErrorCode::FAIL),
+        }
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > PrfClient for DtlsSession<'a, A, D, C>
+{
+    fn prf_done(&self, result: Result<(), ErrorCode>, output: &[u8]) {
+        let op = self.op.get();
+        self.op.set(Op::None);
+        if self.state.get() != SessionState::Idle {
+            if let Err(e) = result.and_then(|()| self.prf_computed(op, output)) {
+                self.fail(e);
+            }
+        }
+        self.process_records();
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > rng::Client for DtlsSession<'a, A, D, C>
+{
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        if self.op.get() != Op::Random {
+            return rng::Continue::Done;
+        }
+        let mut random = [0; RANDOM_LEN];
+        if error.is_ok() {
+            for chunk in random.chunks_mut(4) {
+                match randomness.next() {
+                    Some(word) => chunk.copy_from_slice(&word.to_le_bytes()),
+                    None => return rng::Continue::More,
+                }
+            }
+        }
+        self.op.set(Op::None);
+        if self.state.get() != SessionState::Idle {
+            let result = error.and_then(|()| match self.role.get() {
+                DtlsRole::Client => {
+                    self.secrets.map(|secrets| secrets.client_random = random);
+                    self.send_client_hello()
+                }
+                DtlsRole::Server => {
+                    self.secrets.map(|secrets| secrets.server_random = random);
+                    self.send_server_hello()
+                }
+            });
+            if let Err(e) = result {
+                self.fail(e);
+            }
+        }
+        self.process_records();
+        rng::Continue::Done
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > CCMClient for DtlsSession<'a, A, D, C>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        let op = self.op.get();
+        self.op.set(Op::None);
+        let (start, header) = self.crypt_record.get();
+        let active = self.state.get() != SessionState::Idle;
+
+        if op == Op::Decrypt {
+            self.rx_buf.replace(buf);
+            if active && res.is_ok() && tag_is_valid {
+                let mut replay = self.replay.get();
+                replay.update(header.sequence_number);
+                self.replay.set(replay);
+                let data_start = start + RECORD_HDR_LEN + EXPLICIT_NONCE_LEN;
+                let data_end = data_start + header.length as usize - PROTECTION_OVERHEAD;
+                self.rx_buf.map(|buf| {
+                    self.handle_record(header.content_type, &buf[data_start..data_end], true)
+                });
+            }
+            self.process_records();
+            return;
+        }
+
+        // Put the header and explicit nonce back over the additional data
+        let _ = header.encode(&mut buf[start..]);
+        buf[start + RECORD_HDR_LEN..start + RECORD_HDR_LEN + EXPLICIT_NONCE_LEN]
+            .copy_from_slice(&header.explicit_nonce());
+        self.tx_buf.replace(buf);
+        let result = res.and_then(|()| {
+            if active {
+                Ok(())
+            } else {
+                Err(ErrorCode::CANCEL)
+            }
+        });
+        match op {
+            Op::EncryptFlight => match result {
+                Ok(()) => self.flight_sent(),
+                Err(e) => self.fail(e),
+            },
+            Op::EncryptData => {
+                if let Err(e) = result.and_then(|()| self.transmit(TxKind::Data)) {
+                    self.client.map(|client| client.send_done(Err(e)));
+                }
+            }
+            Op::EncryptAlert => {
+                if result.and_then(|()| self.transmit(TxKind::Alert)).is_err() && active {
+                    self.finish_close();
+                }
+            }
+            _ => {}
+        }
+        self.process_records();
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > UDPSendClient for DtlsSession<'a, A, D, C>
+{
+    fn send_done(&self, result: Result<(), ErrorCode>, dgram: LeasableMutableBuffer<'static, u8>) {
+        self.tx_buf.replace(dgram.take());
+        match self.tx_kind.get() {
+            TxKind::Flight => {}
+            TxKind::Data => {
+                self.client.map(|client| client.send_done(result));
+            }
+            TxKind::Alert => {
+                if self.state.get() == SessionState::Closing {
+                    self.finish_close();
+                }
+            }
+        }
+        if self.flight_pending.get() {
+            self.flight_pending.set(false);
+            if let Err(e) = self.send_flight() {
+                self.fail(e);
+            }
+        }
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > UDPRecvClient for DtlsSession<'a, A, D, C>
+{
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let state = self.state.get();
+        if state == SessionState::Idle || self.rx_busy.get() {
+            return;
+        }
+        if state != SessionState::Listening && self.peer.get() != (src_addr, src_port) {
+            return;
+        }
+        let copied = self.rx_buf.map_or(false, |buf| {
+            if payload.len() > buf.len() {
+                return false;
+            }
+            buf[..payload.len()].copy_from_slice(payload);
+            true
+        });
+        if !copied {
+            return;
+        }
+        self.rx_src.set((src_addr, src_port));
+        self.rx_len.set(payload.len());
+        self.rx_off.set(0);
+        self.rx_busy.set(true);
+        self.retransmitted.set(false);
+        self.process_records();
+    }
+}
+
+impl<
+        'a,
+        A: time::Alarm<'a>,
+        D: digest::Digest<'a, SHA256_LEN> + digest::Sha256,
+        C: AES128CCM<'a>,
+    > time::AlarmClient for DtlsSession<'a, A, D, C>
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            SessionState::WaitServerHello
+            | SessionState::WaitServerHelloDone
+            | SessionState::WaitServerFinished
+            | SessionState::WaitClientKeyExchange => {}
+            _ => return,
+        }
+        if self.retransmissions.get() >= MAX_RETRANSMIT {
+            self.fail(ErrorCode::NOACK);
+            return;
+        }
+        self.retransmissions.set(self.retransmissions.get() + 1);
+        self.timeout_ms
+            .set(cmp::min(self.timeout_ms.get() * 2, MAX_TIMEOUT_MS));
+        self.retransmit();
+        self.arm_timer();
+    }
+}
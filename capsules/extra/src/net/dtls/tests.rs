@@ -0,0 +1,666 @@
+//! Tests of the DTLS record and handshake encodings, of the replay window,
+//! and of the PRF, which is run over `Sha256Software`, and of pairs of
+//! sessions exchanging datagrams over a simulated link.
+
+use crate::net::dtls::handshake::VERIFY_DATA_LEN;
+use crate::net::dtls::handshake::{decode_client_key_exchange, decode_hello_verify_request};
+use crate::net::dtls::handshake::{encode_client_hello, encode_client_key_exchange};
+use crate::net::dtls::handshake::{encode_hello_verify_request, encode_premaster_secret};
+use crate::net::dtls::handshake::{encode_server_hello, handshake_type, ClientHello};
+use crate::net::dtls::handshake::{HandshakeHeader, ServerHello, HANDSHAKE_HDR_LEN};
+use crate::net::dtls::handshake::{MAX_COOKIE_LEN, RANDOM_LEN, TLS_PSK_WITH_AES_128_CCM_8};
+use crate::net::dtls::prf::{Prf, PrfClient, MIN_BUF_LEN, SHA256_LEN};
+use crate::net::dtls::record::EXPLICIT_NONCE_LEN;
+use crate::net::dtls::record::{ccm_nonce, content_type, RecordHeader, ReplayWindow};
+use crate::net::dtls::record::{DTLS_1_0, DTLS_1_2, MAX_SEQUENCE_NUMBER, RECORD_HDR_LEN};
+use crate::net::dtls::session::{DtlsClient, DtlsSession, SessionState};
+use crate::net::dtls::session::{MAX_RETRANSMIT, RECORD_OVERHEAD};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::{AddrRange, NetworkCapability};
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::UDPSender;
+use crate::sha256::Sha256Software;
+use crate::testing::alarm::MockAlarm;
+use crate::testing::ccm::MockCCM;
+use crate::testing::rng::MockRng;
+use crate::testing::udp::MockUdpSender;
+use core::cell::{Cell, RefCell};
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+    DynamicDeferredCallClientState,
+};
+use kernel::hil::digest::Digest;
+use kernel::hil::rng::Rng;
+use kernel::hil::symmetric_encryption::AES128CCM;
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::ErrorCode;
+use std::boxed::Box;
+use std::vec::Vec;
+
+#[test]
+fn record_header_roundtrip() {
+    let header = RecordHeader::new(content_type::HANDSHAKE, 1, 0x0102_0304_0506, 3);
+    let mut buf = [0; RECORD_HDR_LEN + 3];
+    assert_eq!(
+        header.encode(&mut buf).done(),
+        Some((RECORD_HDR_LEN, RECORD_HDR_LEN))
+    );
+    assert_eq!(
+        buf[..RECORD_HDR_LEN],
+        [22, 0xfe, 0xfd, 0, 1, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0, 3]
+    );
+    assert_eq!(
+        RecordHeader::decode(&buf).done(),
+        Some((RECORD_HDR_LEN, header))
+    );
+    assert_eq!(header.explicit_nonce(), [0, 1, 1, 2, 3, 4, 5, 6]);
+    assert_eq!(
+        header.additional_data(0x1234),
+        [0, 1, 1, 2, 3, 4, 5, 6, 22, 0xfe, 0xfd, 0x12, 0x34]
+    );
+}
+
+#[test]
+fn record_header_rejects() {
+    let mut buf = [0; RECORD_HDR_LEN + 4];
+    let header = RecordHeader::new(content_type::APPLICATION_DATA, 0, 0, 4);
+    header.encode(&mut buf).done().unwrap();
+
+    // Fragment longer than the datagram
+    assert!(RecordHeader::decode(&buf[..RECORD_HDR_LEN + 3])
+        .done()
+        .is_none());
+    // Unknown version
+    buf[2] = 0xfc;
+    assert!(RecordHeader::decode(&buf).done().is_none());
+    // DTLS 1.0 is accepted for hellos
+    buf[2] = 0xff;
+    assert_eq!(
+        RecordHeader::decode(&buf).done().unwrap().1.version,
+        DTLS_1_0
+    );
+    // Sequence numbers are 48 bits long
+    let header = RecordHeader::new(content_type::ALERT, 0, MAX_SEQUENCE_NUMBER + 1, 0);
+    assert!(header.encode(&mut buf).done().is_none());
+}
+
+#[test]
+fn ccm_nonce_layout() {
+    assert_eq!(
+        ccm_nonce(&[1, 2, 3, 4], &[5, 6, 7, 8, 9, 10, 11, 12]),
+        [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]
+    );
+}
+
+#[test]
+fn replay_window() {
+    let mut window = ReplayWindow::new();
+    assert!(window.check(0));
+    window.update(0);
+    assert!(!window.check(0));
+
+    // Out of order records within the window are accepted once
+    window.update(10);
+    assert!(window.check(5));
+    window.update(5);
+    assert!(!window.check(5));
+    assert!(!window.check(10));
+    assert!(window.check(9));
+
+    // Records older than the window are dropped
+    window.update(100);
+    assert!(!window.check(100 - ReplayWindow::SIZE));
+    assert!(window.check(100 - ReplayWindow::SIZE + 1));
+    assert!(window.check(101));
+
+    // Jumping further than the window forgets everything
+    window.update(1000);
+    assert!(!window.check(1000));
+    assert!(window.check(999));
+    assert!(!window.check(100));
+}
+
+#[test]
+fn handshake_header_roundtrip() {
+    let header = HandshakeHeader::new(handshake_type::FINISHED, VERIFY_DATA_LEN, 5);
+    assert!(!header.is_fragmented());
+    let mut buf = [0; HANDSHAKE_HDR_LEN + VERIFY_DATA_LEN];
+    assert_eq!(
+        header.encode(&mut buf).done(),
+        Some((HANDSHAKE_HDR_LEN, HANDSHAKE_HDR_LEN))
+    );
+    assert_eq!(
+        buf[..HANDSHAKE_HDR_LEN],
+        [20, 0, 0, 12, 0, 5, 0, 0, 0, 0, 0, 12]
+    );
+    assert_eq!(
+        HandshakeHeader::decode(&buf).done(),
+        Some((HANDSHAKE_HDR_LEN, header))
+    );
+    // The body must be present
+    assert!(HandshakeHeader::decode(&buf[..HANDSHAKE_HDR_LEN + 11])
+        .done()
+        .is_none());
+
+    // Fragments past the end of the message are rejected
+    let fragment = HandshakeHeader {
+        fragment_offset: 4,
+        ..header
+    };
+    assert!(fragment.is_fragmented());
+    fragment.encode(&mut buf).done().unwrap();
+    assert!(HandshakeHeader::decode(&buf).done().is_none());
+}
+
+#[test]
+fn client_hello_roundtrip() {
+    let random = [0x5a; RANDOM_LEN];
+    let cookie = [1, 2, 3];
+    let mut buf = [0; 64];
+    let (len, _) = encode_client_hello(&mut buf, &random, &cookie)
+        .done()
+        .unwrap();
+    assert_eq!(len, 2 + RANDOM_LEN + 1 + 1 + cookie.len() + 4 + 2);
+
+    let (off, hello) = ClientHello::decode(&buf[..len]).done().unwrap();
+    assert_eq!(off, len);
+    assert_eq!(hello.random, random);
+    assert_eq!(hello.cookie, cookie);
+    assert!(hello.acceptable);
+
+    // Another cipher suite only
+    let mut other = buf;
+    other[len - 4] = 0x00;
+    other[len - 3] = 0x8c;
+    assert!(
+        !ClientHello::decode(&other[..len])
+            .done()
+            .unwrap()
+            .1
+            .acceptable
+    );
+
+    // Truncated in the cipher suites
+    assert!(ClientHello::decode(&buf[..len - 3]).done().is_none());
+}
+
+#[test]
+fn client_hello_skips_extensions() {
+    let random = [7; RANDOM_LEN];
+    let mut buf = [0; 64];
+    let (len, _) = encode_client_hello(&mut buf, &random, &[]).done().unwrap();
+    // An empty extensions block
+    buf[len..len + 2].copy_from_slice(&[0, 0]);
+    let (off, hello) = ClientHello::decode(&buf[..len + 2]).done().unwrap();
+    assert_eq!(off, len);
+    assert!(hello.cookie.is_empty());
+    assert!(hello.acceptable);
+}
+
+#[test]
+fn hello_verify_request_roundtrip() {
+    let cookie = [9; 20];
+    let mut buf = [0; 32];
+    let (len, _) = encode_hello_verify_request(&mut buf, &cookie)
+        .done()
+        .unwrap();
+    assert_eq!(len, 3 + cookie.len());
+    assert_eq!(
+        decode_hello_verify_request(&buf[..len]).done(),
+        Some((len, &cookie[..]))
+    );
+    assert!(decode_hello_verify_request(&buf[..len - 1])
+        .done()
+        .is_none());
+    assert!(
+        encode_hello_verify_request(&mut [0; 512], &[0; MAX_COOKIE_LEN + 1])
+            .done()
+            .is_none()
+    );
+}
+
+#[test]
+fn server_hello_roundtrip() {
+    let random = [0xa5; RANDOM_LEN];
+    let mut buf = [0; 48];
+    let (len, _) = encode_server_hello(&mut buf, &random).done().unwrap();
+    let (off, hello) = ServerHello::decode(&buf[..len]).done().unwrap();
+    assert_eq!(off, len);
+    assert_eq!(hello.version, DTLS_1_2);
+    assert_eq!(hello.random, random);
+    assert_eq!(hello.cipher_suite, TLS_PSK_WITH_AES_128_CCM_8);
+    assert_eq!(hello.compression_method, 0);
+}
+
+#[test]
+fn client_key_exchange_roundtrip() {
+    let mut buf = [0; 16];
+    let (len, _) = encode_client_key_exchange(&mut buf, b"device")
+        .done()
+        .unwrap();
+    assert_eq!(buf[..len], *b"\x00\x06device");
+    assert_eq!(
+        decode_client_key_exchange(&buf[..len]).done(),
+        Some((len, &b"device"[..]))
+    );
+    assert!(decode_client_key_exchange(&buf[..len - 1]).done().is_none());
+}
+
+#[test]
+fn premaster_secret_format() {
+    let mut buf = [0xff; 16];
+    let (len, _) = encode_premaster_secret(&mut buf, &[0xaa, 0xbb])
+        .done()
+        .unwrap();
+    assert_eq!(buf[..len], [0, 2, 0, 0, 0, 2, 0xaa, 0xbb]);
+    assert!(encode_premaster_secret(&mut buf[..7], &[0xaa, 0xbb])
+        .done()
+        .is_none());
+}
+
+/// Records the output of the PRF.
+struct PrfRecorder {
+    output: RefCell<Option<Result<Vec<u8>, ErrorCode>>>,
+}
+
+impl PrfClient for PrfRecorder {
+    fn prf_done(&self, result: Result<(), ErrorCode>, output: &[u8]) {
+        *self.output.borrow_mut() = Some(result.map(|()| output.to_vec()));
+    }
+}
+
+struct PrfHarness {
+    sha: &'static Sha256Software<'static>,
+    handle: DeferredCallHandle,
+    prf: &'static Prf<'static, Sha256Software<'static>>,
+    recorder: &'static PrfRecorder,
+}
+
+impl PrfHarness {
+    fn new() -> PrfHarness {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let sha = Box::leak(Box::new(Sha256Software::new(ddc)));
+        let handle = ddc.register(sha).unwrap();
+        sha.initialize_callback_handle(handle);
+        let buf = Box::leak(Box::new([0; MIN_BUF_LEN]));
+        let digest_buf = Box::leak(Box::new([0; SHA256_LEN]));
+        let prf = Box::leak(Box::new(Prf::new(sha, buf, digest_buf)));
+        sha.set_client(prf);
+        let recorder = Box::leak(Box::new(PrfRecorder {
+            output: RefCell::new(None),
+        }));
+        prf.set_client(recorder);
+        PrfHarness {
+            sha: sha,
+            handle: handle,
+            prf: prf,
+            recorder: recorder,
+        }
+    }
+
+    /// Delivers the deferred calls of the hash engine until the PRF is done.
+    fn run(&self) -> Result<Vec<u8>, ErrorCode> {
+        while self.sha.busy() {
+            self.sha.call(self.handle);
+        }
+        assert!(!self.prf.busy());
+        self.recorder.output.borrow_mut().take().unwrap()
+    }
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+const TEST_SECRET: &str = "9bbe436ba940f017b17652849a71db35";
+const TEST_SEED: &str = "a0ba9f936cda311827a6f796ffd5198c";
+
+#[test]
+fn prf_sha256_vector() {
+    // The widely published TLS 1.2 PRF test vector
+    let harness = PrfHarness::new();
+    let seed = from_hex(TEST_SEED);
+    harness
+        .prf
+        .prf(
+            &from_hex(TEST_SECRET),
+            b"test label",
+            &seed[..8],
+            &seed[8..],
+            48,
+        )
+        .unwrap();
+    assert_eq!(
+        harness.run().unwrap(),
+        from_hex(
+            "e3f229ba727be17b8d122620557cd453c2aab21d07c3d495329b52d4e61edb5a\
+             6b301791e90d35c9c9a46b4e14baf9af"
+        )
+    );
+
+    // The engine can be reused
+    harness
+        .prf
+        .prf(&from_hex(TEST_SECRET), b"test label", &seed, &[], 16)
+        .unwrap();
+    assert_eq!(
+        harness.run().unwrap(),
+        from_hex("e3f229ba727be17b8d122620557cd453")
+    );
+}
+
+#[test]
+fn prf_with_hash_vector() {
+    // PRF(secret, "test label", SHA256("abc")), as in Finished messages
+    let harness = PrfHarness::new();
+    harness
+        .prf
+        .prf_with_hash(
+            &from_hex(TEST_SECRET),
+            b"test label",
+            b"abc",
+            VERIFY_DATA_LEN,
+        )
+        .unwrap();
+    assert_eq!(harness.run().unwrap(), from_hex("a0e02d95340eaf6d098d62ef"));
+}
+
+#[test]
+fn prf_rejects() {
+    let harness = PrfHarness::new();
+    let secret = from_hex(TEST_SECRET);
+    // Seed too long
+    assert_eq!(
+        harness
+            .prf
+            .prf(&secret, b"test label", &[0; 64], &[0; 16], 12),
+        Err(ErrorCode::SIZE)
+    );
+    // Transcript longer than the buffer
+    assert_eq!(
+        harness
+            .prf
+            .prf_with_hash(&secret, b"test label", &[0; MIN_BUF_LEN + 1], 12),
+        Err(ErrorCode::SIZE)
+    );
+
+    harness
+        .prf
+        .prf(&secret, b"test label", &[], &[], 12)
+        .unwrap();
+    assert_eq!(
+        harness.prf.prf(&secret, b"test label", &[], &[], 12),
+        Err(ErrorCode::BUSY)
+    );
+    assert_eq!(harness.run().map(|output| output.len()), Ok(12));
+}
+
+// Sessions
+
+const CLIENT_ADDR: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+const SERVER_ADDR: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+const PORT: u16 = 5684;
+const IDENTITY: &[u8] = b"client";
+const PSK: &[u8] = b"0123456789abcdef";
+
+/// Records the callbacks of a session.
+#[derive(Default)]
+struct Events {
+    connected: Cell<Option<Result<(), ErrorCode>>>,
+    received: RefCell<Vec<Vec<u8>>>,
+    sent: Cell<Option<Result<(), ErrorCode>>>,
+    closed: Cell<Option<Result<(), ErrorCode>>>,
+}
+
+impl DtlsClient for Events {
+    fn connected(&self, result: Result<(), ErrorCode>) {
+        self.connected.set(Some(result));
+    }
+
+    fn received(&self, data: &[u8]) {
+        self.received.borrow_mut().push(data.to_vec());
+    }
+
+    fn send_done(&self, result: Result<(), ErrorCode>) {
+        self.sent.set(Some(result));
+    }
+
+    fn closed(&self, result: Result<(), ErrorCode>) {
+        self.closed.set(Some(result));
+    }
+}
+
+type Session =
+    DtlsSession<'static, MockAlarm<'static, Freq1KHz>, Sha256Software<'static>, MockCCM<'static>>;
+
+/// A session with its own hash engine, alarm, RNG and CCM engine.
+struct Endpoint {
+    addr: IPAddr,
+    sha: &'static Sha256Software<'static>,
+    handle: DeferredCallHandle,
+    alarm: &'static MockAlarm<'static, Freq1KHz>,
+    rng: &'static MockRng<'static>,
+    ccm: &'static MockCCM<'static>,
+    link: &'static MockUdpSender,
+    session: &'static Session,
+    events: &'static Events,
+}
+
+impl Endpoint {
+    fn new(addr: IPAddr, psk: &'static [u8]) -> Endpoint {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let sha = Box::leak(Box::new(Sha256Software::new(ddc)));
+        let handle = ddc.register(sha).unwrap();
+        sha.initialize_callback_handle(handle);
+        let prf = Box::leak(Box::new(Prf::new(
+            sha,
+            Box::leak(Box::new([0; MIN_BUF_LEN])),
+            Box::leak(Box::new([0; SHA256_LEN])),
+        )));
+        sha.set_client(prf);
+
+        let alarm = Box::leak(Box::new(MockAlarm::new()));
+        let rng = Box::leak(Box::new(MockRng::new()));
+        let ccm = Box::leak(Box::new(MockCCM::new()));
+        let link = Box::leak(Box::new(MockUdpSender::new()));
+        let session: &'static Session = Box::leak(Box::new(DtlsSession::new(
+            link,
+            Box::leak(Box::new(NetworkCapability::new_for_test(AddrRange::Any))),
+            alarm,
+            rng,
+            ccm,
+            prf,
+            IDENTITY,
+            psk,
+            Box::leak(Box::new([0; 256])),
+            Box::leak(Box::new([0; 256])),
+        )));
+        prf.set_client(session);
+        ccm.set_client(session);
+        rng.set_client(session);
+        alarm.set_alarm_client(session);
+        link.set_client(session);
+        let events = Box::leak(Box::new(Events::default()));
+        session.set_client(events);
+
+        Endpoint {
+            addr,
+            sha,
+            handle,
+            alarm,
+            rng,
+            ccm,
+            link,
+            session,
+            events,
+        }
+    }
+
+    /// Completes the hash, RNG and CCM operations in progress, and returns
+    /// whether there were any.
+    fn step(&self) -> bool {
+        let mut progress = false;
+        while self.sha.busy() {
+            self.sha.call(self.handle);
+            progress = true;
+        }
+        progress |= self.rng.supply();
+        progress |= self.ccm.complete();
+        progress
+    }
+
+    fn receive(&self, from: &Endpoint, dgram: &[u8]) {
+        self.session
+            .receive(from.addr, self.addr, PORT, PORT, dgram);
+    }
+}
+
+struct Sessions {
+    client: Endpoint,
+    server: Endpoint,
+}
+
+impl Sessions {
+    fn new(client_psk: &'static [u8], server_psk: &'static [u8]) -> Sessions {
+        Sessions {
+            client: Endpoint::new(CLIENT_ADDR, client_psk),
+            server: Endpoint::new(SERVER_ADDR, server_psk),
+        }
+    }
+
+    /// Runs both sessions, delivering the datagrams they send to each other,
+    /// until both are waiting for an alarm or the test.
+    fn run(&self) {
+        for _ in 0..1000 {
+            let mut progress = self.client.step() | self.server.step();
+            if let Some(dgram) = self.client.link.complete() {
+                dgram.map(|dgram| self.server.receive(&self.client, &dgram));
+                progress = true;
+            }
+            if let Some(dgram) = self.server.link.complete() {
+                dgram.map(|dgram| self.client.receive(&self.server, &dgram));
+                progress = true;
+            }
+            if !progress {
+                return;
+            }
+        }
+        panic!("The sessions do not settle");
+    }
+
+    fn connect(&self) {
+        self.server.session.listen().unwrap();
+        self.client.session.connect(SERVER_ADDR, PORT).unwrap();
+        self.run();
+    }
+}
+
+#[test]
+fn sessions_handshake_and_exchange_records() {
+    let sessions = Sessions::new(PSK, PSK);
+    let (client, server) = (&sessions.client, &sessions.server);
+    sessions.connect();
+    assert_eq!(client.events.connected.take(), Some(Ok(())));
+    assert_eq!(server.events.connected.take(), Some(Ok(())));
+    assert_eq!(client.session.state(), SessionState::Connected);
+    assert_eq!(server.session.state(), SessionState::Connected);
+    assert_eq!(server.session.peer(), (CLIENT_ADDR, PORT));
+
+    client.session.send(b"hello server").unwrap();
+    sessions.run();
+    assert_eq!(client.events.sent.take(), Some(Ok(())));
+    assert_eq!(*server.events.received.borrow(), [b"hello server".to_vec()]);
+
+    // The record is protected
+    let record = client.link.last_sent();
+    assert_eq!(record.len(), RECORD_OVERHEAD + 12);
+    assert_eq!(record[0], content_type::APPLICATION_DATA);
+    assert!(!record.windows(5).any(|window| window == b"hello"));
+
+    server.session.send(b"hello client").unwrap();
+    sessions.run();
+    assert_eq!(server.events.sent.take(), Some(Ok(())));
+    assert_eq!(*client.events.received.borrow(), [b"hello client".to_vec()]);
+
+    client.session.close().unwrap();
+    sessions.run();
+    assert_eq!(client.events.closed.take(), Some(Ok(())));
+    assert_eq!(server.events.closed.take(), Some(Ok(())));
+    assert_eq!(client.session.state(), SessionState::Idle);
+    assert_eq!(server.session.state(), SessionState::Idle);
+}
+
+#[test]
+fn sessions_drop_replayed_and_modified_records() {
+    let sessions = Sessions::new(PSK, PSK);
+    let (client, server) = (&sessions.client, &sessions.server);
+    sessions.connect();
+    client.session.send(b"once").unwrap();
+    sessions.run();
+    assert_eq!(server.events.received.borrow().len(), 1);
+
+    let record = client.link.last_sent();
+    server.receive(client, &record);
+    sessions.run();
+    assert_eq!(server.events.received.borrow().len(), 1);
+
+    // A new sequence number passes the replay check, but not the MIC, which
+    // covers the record header
+    let mut modified = record.clone();
+    modified[10] ^= 0x80;
+    server.receive(client, &modified);
+    sessions.run();
+    assert_eq!(server.events.received.borrow().len(), 1);
+
+    // Neither does a modified ciphertext
+    let mut modified = record;
+    modified[10] ^= 0x40;
+    modified[RECORD_HDR_LEN + EXPLICIT_NONCE_LEN] ^= 1;
+    server.receive(client, &modified);
+    sessions.run();
+    assert_eq!(server.events.received.borrow().len(), 1);
+    assert_eq!(server.session.state(), SessionState::Connected);
+}
+
+#[test]
+fn sessions_recover_lost_flights() {
+    let sessions = Sessions::new(PSK, PSK);
+    let (client, server) = (&sessions.client, &sessions.server);
+    server.link.lose_next();
+    sessions.connect();
+    assert_eq!(client.session.state(), SessionState::WaitServerHello);
+    assert_eq!(server.link.sent(), 1);
+
+    // The client retransmits its ClientHello, which makes the server
+    // retransmit the lost flight
+    assert!(client.alarm.fire());
+    sessions.run();
+    assert_eq!(client.events.connected.take(), Some(Ok(())));
+    assert_eq!(server.events.connected.take(), Some(Ok(())));
+    assert_eq!(server.link.sent(), 3);
+}
+
+#[test]
+fn sessions_with_different_keys_do_not_connect() {
+    let sessions = Sessions::new(PSK, b"fedcba9876543210");
+    let (client, server) = (&sessions.client, &sessions.server);
+    sessions.connect();
+
+    // The server cannot decrypt the Finished of the client, so the client
+    // gives up after retransmitting it
+    for _ in 0..=MAX_RETRANSMIT {
+        assert!(client.alarm.fire());
+        sessions.run();
+    }
+    assert_eq!(client.events.connected.take(), Some(Err(ErrorCode::NOACK)));
+    assert_eq!(client.session.state(), SessionState::Idle);
+    assert_eq!(server.events.connected.take(), None);
+    assert_eq!(server.session.state(), SessionState::WaitClientFinished);
+}
@@ -0,0 +1,157 @@
+//! Implements Ethernet II header encoding and decoding.
+//!
+//! An Ethernet II frame, as passed through `kernel::hil::ethernet`, has the
+//! following layout (the frame check sequence is handled by the adapter):
+//!
+//! ```txt
+//!  0                   6                  12        14
+//!  +-------------------+-------------------+---------+-------------
+//!  |  destination MAC  |     source MAC    |EtherType| payload ...
+//!  +-------------------+-------------------+---------+-------------
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16};
+use crate::net::stream::{encode_bytes, encode_u16};
+
+/// Length of an Ethernet II header.
+pub const ETHERNET_HDR_LEN: usize = 14;
+
+/// Minimum length of an Ethernet frame, excluding the frame check sequence.
+/// Shorter frames must be padded before transmission.
+pub const ETHERNET_MIN_FRAME_LEN: usize = 60;
+
+/// Maximum payload length of a standard Ethernet frame.
+pub const ETHERNET_MTU: usize = 1500;
+
+pub mod ethertype {
+    pub const IPV4: u16 = 0x0800;
+    pub const ARP: u16 = 0x0806;
+    pub const IPV6: u16 = 0x86DD;
+}
+
+/// A 48-bit IEEE 802 MAC address.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EthernetAddress(pub [u8; 6]);
+
+impl EthernetAddress {
+    pub const BROADCAST: EthernetAddress = EthernetAddress([0xff; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    /// Returns the multicast MAC address an IPv6 multicast address maps to,
+    /// as defined in RFC 2464, section 7.
+    pub fn from_ipv6_multicast(addr: &IPAddr) -> EthernetAddress {
+        EthernetAddress([0x33, 0x33, addr.0[12], addr.0[13], addr.0[14], addr.0[15]])
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        encode_bytes(buf, &self.0)
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<EthernetAddress> {
+        let mut addr = [0u8; 6];
+        let off = dec_consume!(buf; decode_bytes, &mut addr);
+        stream_done!(off, EthernetAddress(addr));
+    }
+}
+
+/// An Ethernet II frame header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EthernetHeader {
+    pub dst_addr: EthernetAddress,
+    pub src_addr: EthernetAddress,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    pub fn new(dst_addr: EthernetAddress, src_addr: EthernetAddress, ethertype: u16) -> Self {
+        EthernetHeader {
+            dst_addr,
+            src_addr,
+            ethertype,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, ETHERNET_HDR_LEN);
+
+        let mut off = enc_consume!(buf, 0; self.dst_addr; encode);
+        off = enc_consume!(buf, off; self.src_addr; encode);
+        off = enc_consume!(buf, off; encode_u16, self.ethertype);
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<EthernetHeader> {
+        stream_len_cond!(buf, ETHERNET_HDR_LEN);
+
+        let (off, dst_addr) = dec_try!(buf, 0; EthernetAddress::decode);
+        let (off, src_addr) = dec_try!(buf, off; EthernetAddress::decode);
+        let (off, ethertype) = dec_try!(buf, off; decode_u16);
+        stream_done!(off, EthernetHeader::new(dst_addr, src_addr, ethertype));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: [u8; ETHERNET_HDR_LEN] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, // destination
+        0x52, 0x54, 0x00, 0xab, 0xcd, 0xef, // source
+        0x86, 0xdd, // IPv6
+    ];
+
+    #[test]
+    fn header_round_trip() {
+        let header = EthernetHeader::new(
+            EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+            EthernetAddress([0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]),
+            ethertype::IPV6,
+        );
+
+        let mut buf = [0; ETHERNET_HDR_LEN];
+        assert_eq!(
+            header.encode(&mut buf).done(),
+            Some((ETHERNET_HDR_LEN, ETHERNET_HDR_LEN))
+        );
+        assert_eq!(buf, FRAME);
+        assert_eq!(
+            EthernetHeader::decode(&FRAME).done(),
+            Some((ETHERNET_HDR_LEN, header))
+        );
+    }
+
+    #[test]
+    fn short_headers_are_rejected() {
+        let mut buf = [0; ETHERNET_HDR_LEN - 1];
+        assert!(EthernetHeader::decode(&FRAME[..ETHERNET_HDR_LEN - 1])
+            .done()
+            .is_none());
+        let header = EthernetHeader::decode(&FRAME).done().unwrap().1;
+        assert!(header.encode(&mut buf).done().is_none());
+    }
+
+    #[test]
+    fn ipv6_multicast_mapping() {
+        // The solicited-node address ff02::1:ff12:3456 (RFC 2464, section 7)
+        let addr = IPAddr([
+            0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0x12, 0x34, 0x56,
+        ]);
+        let mac = EthernetAddress::from_ipv6_multicast(&addr);
+        assert_eq!(mac, EthernetAddress([0x33, 0x33, 0xff, 0x12, 0x34, 0x56]));
+        assert!(mac.is_multicast());
+        assert!(!mac.is_broadcast());
+
+        assert!(EthernetAddress::BROADCAST.is_multicast());
+        assert!(EthernetAddress::BROADCAST.is_broadcast());
+        assert!(!EthernetAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]).is_multicast());
+    }
+}
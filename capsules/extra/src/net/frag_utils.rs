@@ -1,5 +1,9 @@
 const BITMAP_SIZE: usize = 20;
 
+/// Number of bits in a `Bitmap`. When tracking the 8-byte blocks of a
+/// fragmented datagram, this covers datagrams of up to 1280 bytes.
+pub const BITMAP_BITS: usize = BITMAP_SIZE * 8;
+
 pub struct Bitmap {
     map: [u8; BITMAP_SIZE],
 }
@@ -27,53 +31,44 @@ impl Bitmap {
         self.map[map_idx] |= 1 << (idx % 8);
     }
 
+    pub fn get_bit(&self, idx: usize) -> bool {
+        self.map[idx / 8] & (1 << (idx % 8)) != 0
+    }
+
     // Sets bits from start_idx (inclusive) to end_idx (exclusive).
     // Returns false if any bits set overlap with already set bits,
     // true otherwise.
 
     // Returns true if successfully set bits, returns false if the bits
-    // overlapped with already set bits
+    // overlapped with already set bits, or if the range is invalid.
     // Note that each bit represents a multiple of 8 bytes (as everything
     // must be in 8-byte groups), and thus we can store 8*8 = 64 "bytes" per
     // byte in the bitmap.
     pub fn set_bits(&mut self, start_idx: usize, end_idx: usize) -> bool {
-        if start_idx > end_idx {
+        if start_idx > end_idx || end_idx > BITMAP_BITS {
             return false;
         }
-        let start_byte_idx = start_idx / 8;
-        let end_byte_idx = end_idx / 8;
-        let first = 0xff << (start_idx % 8);
-        let second = if end_idx % 8 == 0 {
-            0x00
-        } else {
-            0xff >> (8 - (end_idx % 8))
-        };
-        if start_byte_idx == end_byte_idx {
-            let result = (self.map[start_byte_idx] & (first & second)) == 0;
-            self.map[start_byte_idx] |= first & second;
-            result
-        } else {
-            let mut result = (self.map[start_byte_idx] & first) == 0;
-            result = result && ((self.map[end_byte_idx] & second) == 0);
-            self.map[start_byte_idx] |= first;
-            self.map[end_byte_idx] |= second;
-            // Set all bytes between start and end bytes.
-            for i in start_byte_idx + 1..end_byte_idx {
-                result = result && (self.map[i] == 0);
-                self.map[i] = 0xff;
-            }
-            result
+        let result = self.is_clear(start_idx, end_idx);
+        for idx in start_idx..end_idx {
+            self.set_bit(idx);
         }
+        result
     }
 
-    pub fn is_complete(&self, total_length: usize) -> bool {
-        let mut result = true;
-        for i in 0..total_length / 8 {
-            result = result && (self.map[i] == 0xff);
-        }
-        // Check last byte.
-        let mask = 0xff >> (8 - (total_length % 8));
-        result = result && (self.map[total_length / 8] == mask);
-        result
+    /// Returns true if all bits from `start_idx` (inclusive) to `end_idx`
+    /// (exclusive) are set.
+    pub fn is_set(&self, start_idx: usize, end_idx: usize) -> bool {
+        end_idx <= BITMAP_BITS && (start_idx..end_idx).all(|idx| self.get_bit(idx))
+    }
+
+    /// Returns true if none of the bits from `start_idx` (inclusive) to
+    /// `end_idx` (exclusive) are set.
+    pub fn is_clear(&self, start_idx: usize, end_idx: usize) -> bool {
+        end_idx <= BITMAP_BITS && (start_idx..end_idx).all(|idx| !self.get_bit(idx))
+    }
+
+    /// Returns true if exactly the first `num_bits` bits are set.
+    pub fn is_complete(&self, num_bits: usize) -> bool {
+        self.is_set(0, num_bits) && self.is_clear(num_bits, BITMAP_BITS)
     }
 }
@@ -1,4 +1,5 @@
 pub mod icmpv6_send;
+pub mod ndp;
 
 // Reexport the exports of the [`icmpv6`] module, to avoid redundant
 // module paths (e.g. `capsules::net::icmpv6::icmpv6::ICMP6Header`)
@@ -6,3 +7,6 @@ mod icmpv6;
 pub use icmpv6::ICMP6Header;
 pub use icmpv6::ICMP6HeaderOptions;
 pub use icmpv6::ICMP6Type;
+
+#[cfg(test)]
+mod tests;
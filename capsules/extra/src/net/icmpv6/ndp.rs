@@ -0,0 +1,144 @@
+//! This file contains the types and helper functions for the subset of the
+//! IPv6 Neighbor Discovery Protocol (RFC 4861) required to resolve the link
+//! layer address of on-link neighbors, namely Neighbor Solicitation and
+//! Neighbor Advertisement messages carrying link-layer address options.
+//!
+//! The ICMPv6 header of these messages is represented by `ICMP6Header` with
+//! the `Type135` and `Type136` options, while the message body (the target
+//! address and the options) is encoded and decoded by the functions in this
+//! file. The `NeighborCache` maps IPv6 addresses of neighbors to their
+//! Ethernet addresses.
+
+use crate::net::ethernet::EthernetAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u8};
+
+use core::cell::Cell;
+
+/// Length of a Neighbor Solicitation or Advertisement body (target address
+/// and a single link-layer address option), excluding the ICMPv6 header.
+pub const NDP_BODY_LEN: usize = 24;
+
+/// NDP option types.
+pub mod ndp_option {
+    pub const SOURCE_LINK_LAYER_ADDR: u8 = 1;
+    pub const TARGET_LINK_LAYER_ADDR: u8 = 2;
+}
+
+/// Flags of a Neighbor Advertisement message.
+pub mod na_flags {
+    pub const ROUTER: u32 = 1 << 31;
+    pub const SOLICITED: u32 = 1 << 30;
+    pub const OVERRIDE: u32 = 1 << 29;
+}
+
+/// Returns the solicited-node multicast address of `addr` (ff02::1:ffXX:XXXX).
+pub fn solicited_node_multicast(addr: &IPAddr) -> IPAddr {
+    let mut mcast = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0, 0, 0]);
+    mcast.0[13..16].copy_from_slice(&addr.0[13..16]);
+    mcast
+}
+
+/// Encodes the body of a Neighbor Solicitation or Advertisement.
+///
+/// # Arguments
+///
+/// `buf` - Buffer to serialize the body into
+/// `target` - The target address of the solicitation or advertisement
+/// `option_type` - The type of the link-layer address option
+/// `ll_addr` - The link-layer address carried in the option
+pub fn encode_ndp_body(
+    buf: &mut [u8],
+    target: &IPAddr,
+    option_type: u8,
+    ll_addr: &EthernetAddress,
+) -> SResult<usize> {
+    stream_len_cond!(buf, NDP_BODY_LEN);
+
+    let mut off = enc_consume!(buf, 0; encode_bytes, &target.0);
+    off = enc_consume!(buf, off; encode_u8, option_type);
+    // Option length is in units of 8 octets
+    off = enc_consume!(buf, off; encode_u8, 1);
+    off = enc_consume!(buf, off; ll_addr; encode);
+    stream_done!(off, off);
+}
+
+/// Decodes the body of a Neighbor Solicitation or Advertisement.
+///
+/// Returns the target address and the link-layer address carried in the
+/// first option of type `option_type`, if there is one. Options of other
+/// types are skipped.
+pub fn decode_ndp_body(buf: &[u8], option_type: u8) -> SResult<(IPAddr, Option<EthernetAddress>)> {
+    let mut target = IPAddr::new();
+    let mut off = dec_consume!(buf; decode_bytes, &mut target.0);
+
+    let mut ll_addr = None;
+    while off + 2 <= buf.len() {
+        let (opt_off, opt_type) = dec_try!(buf, off; decode_u8);
+        let (opt_off, opt_len) = dec_try!(buf, opt_off; decode_u8);
+        // A zero option length is invalid and the message must be dropped
+        stream_cond!(opt_len != 0);
+        let opt_end = off + (opt_len as usize) * 8;
+        stream_len_cond!(buf, opt_end);
+        if opt_type == option_type && ll_addr.is_none() {
+            let (_, addr) = dec_try!(buf, opt_off; EthernetAddress::decode);
+            ll_addr = Some(addr);
+        }
+        off = opt_end;
+    }
+    stream_done!(off, (target, ll_addr));
+}
+
+/// Number of entries of a `NeighborCache`.
+pub const NEIGHBOR_CACHE_SIZE: usize = 8;
+
+/// A fixed-size cache mapping IPv6 addresses of on-link neighbors to their
+/// Ethernet addresses. When the cache is full, entries are replaced in a
+/// round-robin fashion.
+pub struct NeighborCache {
+    entries: [Cell<Option<(IPAddr, EthernetAddress)>>; NEIGHBOR_CACHE_SIZE],
+    next: Cell<usize>,
+}
+
+impl NeighborCache {
+    pub fn new() -> NeighborCache {
+        NeighborCache {
+            entries: Default::default(),
+            next: Cell::new(0),
+        }
+    }
+
+    /// Looks up the Ethernet address of the neighbor with address `addr`.
+    pub fn lookup(&self, addr: &IPAddr) -> Option<EthernetAddress> {
+        self.entries.iter().find_map(|entry| match entry.get() {
+            Some((ip, mac)) if ip == *addr => Some(mac),
+            _ => None,
+        })
+    }
+
+    /// Inserts or updates the Ethernet address of the neighbor with address
+    /// `addr`.
+    pub fn insert(&self, addr: IPAddr, mac: EthernetAddress) {
+        let existing = self
+            .entries
+            .iter()
+            .find(|entry| entry.get().map_or(false, |(ip, _)| ip == addr));
+        match existing {
+            Some(entry) => entry.set(Some((addr, mac))),
+            None => {
+                let idx = self.next.get();
+                self.entries[idx].set(Some((addr, mac)));
+                self.next.set((idx + 1) % NEIGHBOR_CACHE_SIZE);
+            }
+        }
+    }
+
+    /// Removes all entries from the cache.
+    pub fn clear(&self) {
+        for entry in self.entries.iter() {
+            entry.set(None);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Tests of the Neighbor Discovery message bodies and the neighbor cache.
+
+use crate::net::ethernet::EthernetAddress;
+use crate::net::icmpv6::ndp::{
+    decode_ndp_body, encode_ndp_body, ndp_option, solicited_node_multicast, NeighborCache,
+    NDP_BODY_LEN, NEIGHBOR_CACHE_SIZE,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+
+const TARGET: IPAddr = IPAddr([
+    0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x50, 0x54, 0, 0xff, 0xfe, 0x12, 0x34, 0x56,
+]);
+const MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+fn neighbor(id: u8) -> (IPAddr, EthernetAddress) {
+    let mut addr = TARGET;
+    addr.0[15] = id;
+    (addr, EthernetAddress([0x52, 0x54, 0, 0, 0, id]))
+}
+
+#[test]
+fn body_round_trip() {
+    let mut buf = [0; NDP_BODY_LEN];
+    let off = encode_ndp_body(&mut buf, &TARGET, ndp_option::TARGET_LINK_LAYER_ADDR, &MAC)
+        .done()
+        .unwrap()
+        .0;
+    assert_eq!(off, NDP_BODY_LEN);
+    assert_eq!(buf[..16], TARGET.0);
+    // A target link-layer address option, 8 bytes long
+    assert_eq!(buf[16..18], [2, 1]);
+    assert_eq!(buf[18..], MAC.0);
+
+    let (_, (target, ll_addr)) = decode_ndp_body(&buf, ndp_option::TARGET_LINK_LAYER_ADDR)
+        .done()
+        .unwrap();
+    assert_eq!(target, TARGET);
+    assert_eq!(ll_addr, Some(MAC));
+
+    // The option isn't the one looked for
+    let (_, (_, ll_addr)) = decode_ndp_body(&buf, ndp_option::SOURCE_LINK_LAYER_ADDR)
+        .done()
+        .unwrap();
+    assert_eq!(ll_addr, None);
+}
+
+#[test]
+fn other_options_are_skipped() {
+    // A 16 byte option of type 14 (nonce) in front of the source link-layer
+    // address option
+    let mut buf = [0; 16 + 16 + 8];
+    buf[..16].copy_from_slice(&TARGET.0);
+    buf[16..18].copy_from_slice(&[14, 2]);
+    buf[32..34].copy_from_slice(&[ndp_option::SOURCE_LINK_LAYER_ADDR, 1]);
+    buf[34..].copy_from_slice(&MAC.0);
+
+    let (off, (target, ll_addr)) = decode_ndp_body(&buf, ndp_option::SOURCE_LINK_LAYER_ADDR)
+        .done()
+        .unwrap();
+    assert_eq!(off, buf.len());
+    assert_eq!(target, TARGET);
+    assert_eq!(ll_addr, Some(MAC));
+
+    // Without options there is no link-layer address
+    let (_, (target, ll_addr)) = decode_ndp_body(&buf[..16], ndp_option::SOURCE_LINK_LAYER_ADDR)
+        .done()
+        .unwrap();
+    assert_eq!(target, TARGET);
+    assert_eq!(ll_addr, None);
+}
+
+#[test]
+fn malformed_bodies_are_rejected() {
+    let mut buf = [0; NDP_BODY_LEN];
+    encode_ndp_body(&mut buf, &TARGET, ndp_option::SOURCE_LINK_LAYER_ADDR, &MAC);
+
+    // The target address is cut short
+    assert!(
+        decode_ndp_body(&buf[..15], ndp_option::SOURCE_LINK_LAYER_ADDR)
+            .done()
+            .is_none()
+    );
+    // The option is longer than the message
+    assert!(
+        decode_ndp_body(&buf[..NDP_BODY_LEN - 1], ndp_option::SOURCE_LINK_LAYER_ADDR)
+            .done()
+            .is_none()
+    );
+    // Options can't have a length of zero (RFC 4861, section 4.6)
+    buf[17] = 0;
+    assert!(decode_ndp_body(&buf, ndp_option::SOURCE_LINK_LAYER_ADDR)
+        .done()
+        .is_none());
+}
+
+#[test]
+fn solicited_node_address() {
+    assert_eq!(
+        solicited_node_multicast(&TARGET),
+        IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0x12, 0x34, 0x56])
+    );
+}
+
+#[test]
+fn neighbor_cache_updates_entries() {
+    let cache = NeighborCache::new();
+    let (addr, mac) = neighbor(1);
+    assert_eq!(cache.lookup(&addr), None);
+
+    cache.insert(addr, mac);
+    assert_eq!(cache.lookup(&addr), Some(mac));
+
+    // Updating an entry doesn't take another one
+    let (_, other_mac) = neighbor(2);
+    cache.insert(addr, other_mac);
+    assert_eq!(cache.lookup(&addr), Some(other_mac));
+    for id in 2..=NEIGHBOR_CACHE_SIZE as u8 {
+        let (addr, mac) = neighbor(id);
+        cache.insert(addr, mac);
+    }
+    assert_eq!(cache.lookup(&addr), Some(other_mac));
+
+    cache.clear();
+    assert_eq!(cache.lookup(&addr), None);
+}
+
+#[test]
+fn neighbor_cache_replaces_oldest_entry() {
+    let cache = NeighborCache::new();
+    for id in 0..NEIGHBOR_CACHE_SIZE as u8 + 2 {
+        let (addr, mac) = neighbor(id);
+        cache.insert(addr, mac);
+    }
+
+    // The first two entries were replaced, in the order they were added
+    for id in 0..2 {
+        assert_eq!(cache.lookup(&neighbor(id).0), None);
+    }
+    for id in 2..NEIGHBOR_CACHE_SIZE as u8 + 2 {
+        let (addr, mac) = neighbor(id);
+        assert_eq!(cache.lookup(&addr), Some(mac));
+    }
+}
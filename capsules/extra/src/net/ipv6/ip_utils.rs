@@ -2,6 +2,7 @@
 //! of the IP stack. Note that this file also contains the definition for the
 //! [IPAddr](struct.IPAddr.html) struct and associated helper functions.
 
+use crate::net::ethernet::EthernetAddress;
 use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions};
 use crate::net::ieee802154::MacAddress;
 use crate::net::ipv6::IP6Header;
@@ -74,6 +75,22 @@ impl IPAddr {
         ip_addr
     }
 
+    /// Method for generating a new ipv6 link local address from an Ethernet
+    /// MAC address, using the modified EUI-64 format of RFC 4291, appendix A
+    pub fn generate_from_ethernet(mac_addr: EthernetAddress) -> IPAddr {
+        let mut ip_addr = IPAddr([0; 16]);
+        ip_addr.set_unicast_link_local();
+        ip_addr.0[8] = mac_addr.0[0] ^ 0b00000010;
+        ip_addr.0[9] = mac_addr.0[1];
+        ip_addr.0[10] = mac_addr.0[2];
+        ip_addr.0[11] = 0xff;
+        ip_addr.0[12] = 0xfe;
+        ip_addr.0[13] = mac_addr.0[3];
+        ip_addr.0[14] = mac_addr.0[4];
+        ip_addr.0[15] = mac_addr.0[5];
+        ip_addr
+    }
+
     pub fn is_unspecified(&self) -> bool {
         self.0.iter().all(|&b| b == 0)
     }
@@ -197,7 +214,11 @@ pub fn compute_icmp_checksum(
 
     // add options
     match icmp_header.get_options() {
-        ICMP6HeaderOptions::Type1 { unused } | ICMP6HeaderOptions::Type3 { unused } => {
+        ICMP6HeaderOptions::Type1 { unused }
+        | ICMP6HeaderOptions::Type3 { unused }
+        | ICMP6HeaderOptions::Type135 { reserved: unused }
+        | ICMP6HeaderOptions::Type136 { flags: unused }
+        | ICMP6HeaderOptions::Type155 { base: unused } => {
             sum += unused >> 16; // upper 16 bits
             sum += unused & 0xffff; // lower 16 bits
         }
@@ -215,7 +236,7 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
     }
 
     sum = !sum;
@@ -224,6 +245,16 @@ pub fn compute_icmp_checksum(
     sum as u16
 }
 
+/// Verifies the checksum of a received ICMPv6 message, including the IPv6
+/// pseudo-header.
+pub fn icmp_checksum_valid(ip6_header: &IP6Header, icmp: &[u8]) -> bool {
+    let mut sum = compute_ipv6_ph_sum(ip6_header) + compute_sum(icmp, icmp.len() as u16);
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    sum == 0xffff
+}
+
 pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
     let mut sum: u32 = 0;
 
@@ -241,22 +272,28 @@ pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
         i += 2;
     }
 
-    sum += ip6_header.payload_len as u32;
+    sum += ip6_header.get_payload_len() as u32;
     sum += ip6_header.next_header as u32;
 
     sum
 }
 
+/// Sums the first `len` bytes of `buf` as 16-bit big-endian words. An odd
+/// trailing byte is padded with a zero byte (RFC 1071).
 pub fn compute_sum(buf: &[u8], len: u16) -> u32 {
     let mut sum: u32 = 0;
 
+    let len = len as usize;
     let mut i: usize = 0;
-    while i < (len as usize) {
+    while i + 1 < len {
         let msb = (buf[i] as u32) << 8;
         let lsb = buf[i + 1] as u32;
         sum += msb + lsb;
         i += 2;
     }
+    if i < len {
+        sum += (buf[i] as u32) << 8;
+    }
 
     sum
 }
@@ -0,0 +1,567 @@
+//! This file contains an implementation of the `IP6Sender` and `IP6Receiver`
+//! traits which transmits and receives IPv6 packets over an Ethernet link
+//! (RFC 2464), using any adapter implementing `hil::ethernet::EthernetAdapter`.
+//!
+//! Unlike the 6LoWPAN implementation in `ipv6_send`, packets are never
+//! fragmented: the complete IPv6 packet is serialized after an Ethernet II
+//! header into a single frame. The link-layer address of the destination is
+//! resolved through the Neighbor Discovery Protocol (RFC 4861): unicast
+//! destinations not present in the neighbor cache are resolved by multicasting
+//! Neighbor Solicitations, and Neighbor Solicitations for the local address
+//! are answered with a Neighbor Advertisement. Multicast destinations are
+//! mapped to Ethernet multicast addresses directly.
+//!
+//! The stack on top of this layer is the same as for 6LoWPAN, so a
+//! `MuxUdpSender` and `MuxUdpReceiver` can be layered on top of an
+//! `IP6EthernetStruct` to run the UDP stack over Ethernet.
+//!
+//! ```txt
+//!   MuxUdpSender / MuxUdpReceiver
+//!               |
+//!       IP6EthernetStruct  <--- NeighborCache
+//!               |
+//!        EthernetAdapter (e.g. VirtIONet)
+//! ```
+
+// Known Problems and Remaining Work
+// ---------------------------------
+// All destinations are assumed to be on-link, as there is no support for
+// Router Advertisements or a routing table yet. Neighbor cache entries never
+// expire, and there is no Neighbor Unreachability Detection.
+
+use crate::net::ethernet::{ethertype, EthernetAddress, EthernetHeader};
+use crate::net::ethernet::{ETHERNET_HDR_LEN, ETHERNET_MIN_FRAME_LEN};
+use crate::net::icmpv6::ndp::{self, na_flags, ndp_option, NeighborCache, NDP_BODY_LEN};
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{compute_icmp_checksum, icmp_checksum_valid, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvClient};
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader, ICMP_HDR_LEN};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+
+use core::cell::Cell;
+
+use kernel::debug;
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
+use kernel::hil::time::{self, ConvertTicks};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
+use kernel::ErrorCode;
+
+/// Length of the frames carrying Neighbor Solicitations and Advertisements,
+/// and therefore the minimum length of the NDP frame buffer.
+pub const NDP_FRAME_LEN: usize = ETHERNET_HDR_LEN + 40 + ICMP_HDR_LEN + NDP_BODY_LEN;
+
+/// Number of Neighbor Solicitations sent before address resolution fails
+/// (MAX_MULTICAST_SOLICIT in RFC 4861).
+const MAX_MULTICAST_SOLICIT: u8 = 3;
+
+/// Time between retransmissions of Neighbor Solicitations in milliseconds
+/// (RETRANS_TIMER in RFC 4861).
+const RETRANS_TIMER_MS: u32 = 1000;
+
+/// The all-nodes multicast address (ff02::1).
+const ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+/// The frame currently handed to the Ethernet adapter.
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Idle,
+    Data,
+    Ndp,
+}
+
+pub struct IP6EthernetStruct<'a, A: time::Alarm<'a>> {
+    ip6_packet: TakeCell<'static, IP6Packet<'static>>,
+    alarm: &'a A, // Alarm used to retransmit Neighbor Solicitations
+    adapter: &'a dyn EthernetAdapter<'a>,
+    src_addr: Cell<IPAddr>,
+    src_mac_addr: EthernetAddress,
+    // Buffer holding the frame of the packet passed to `send_to`
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    // Buffer for Neighbor Solicitations and Advertisements, such that we can
+    // resolve addresses while a packet is waiting in `tx_buf`
+    ndp_buf: TakeCell<'static, [u8]>,
+    neighbors: NeighborCache,
+    in_flight: Cell<InFlight>,
+    // Whether a packet passed to `send_to` has not completed yet
+    sending: Cell<bool>,
+    // Whether the frame in `tx_buf` is complete and awaiting transmission
+    data_ready: Cell<bool>,
+    // The address being resolved for the packet in `tx_buf`
+    resolving: OptionalCell<IPAddr>,
+    solicitations: Cell<u8>,
+    solicitation_pending: Cell<bool>,
+    // Destination of a pending Neighbor Advertisement
+    advertisement_pending: OptionalCell<(IPAddr, EthernetAddress)>,
+    client: OptionalCell<&'a dyn IP6SendClient>,
+    rx_client: OptionalCell<&'a dyn IP6RecvClient>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6EthernetStruct<'a, A> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.client.set(client);
+    }
+
+    fn set_addr(&self, src_addr: IPAddr) {
+        self.src_addr.set(src_addr);
+    }
+
+    /// Link-layer addresses of next hops are resolved through the neighbor
+    /// cache, so the gateway address is ignored.
+    fn set_gateway(&self, _gateway: MacAddress) {}
+
+    fn set_header(&mut self, ip6_header: IP6Header) {
+        self.ip6_packet
+            .map(|ip6_packet| ip6_packet.header = ip6_header);
+    }
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableMutableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return Err(ErrorCode::FAIL);
+        }
+        if self.sending.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.init_packet(dst, transport_header, payload);
+        let len = self.encode_packet()?;
+        self.tx_len.set(len);
+        self.sending.set(true);
+
+        if dst.is_multicast() {
+            self.set_frame_dst(EthernetAddress::from_ipv6_multicast(&dst));
+        } else {
+            match self.neighbors.lookup(&dst) {
+                Some(mac) => self.set_frame_dst(mac),
+                None => {
+                    self.resolving.set(dst);
+                    self.solicitations.set(1);
+                    self.solicitation_pending.set(true);
+                    self.alarm
+                        .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(RETRANS_TIMER_MS));
+                }
+            }
+        }
+
+        self.transmit_next().map_err(|ecode| {
+            self.sending.set(false);
+            ecode
+        })
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> IP6Receiver<'a> for IP6EthernetStruct<'a, A> {
+    fn set_client(&self, client: &'a dyn IP6RecvClient) {
+        self.rx_client.set(client);
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> IP6EthernetStruct<'a, A> {
+    pub fn new(
+        ip6_packet: &'static mut IP6Packet<'static>,
+        alarm: &'a A,
+        tx_buf: &'static mut [u8],
+        ndp_buf: &'static mut [u8],
+        adapter: &'a dyn EthernetAdapter<'a>,
+        src_mac_addr: EthernetAddress,
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> IP6EthernetStruct<'a, A> {
+        assert!(ndp_buf.len() >= NDP_FRAME_LEN);
+        IP6EthernetStruct {
+            ip6_packet: TakeCell::new(ip6_packet),
+            alarm: alarm,
+            adapter: adapter,
+            src_addr: Cell::new(IPAddr::new()),
+            src_mac_addr: src_mac_addr,
+            tx_buf: TakeCell::new(tx_buf),
+            tx_len: Cell::new(0),
+            ndp_buf: TakeCell::new(ndp_buf),
+            neighbors: NeighborCache::new(),
+            in_flight: Cell::new(InFlight::Idle),
+            sending: Cell::new(false),
+            data_ready: Cell::new(false),
+            resolving: OptionalCell::empty(),
+            solicitations: Cell::new(0),
+            solicitation_pending: Cell::new(false),
+            advertisement_pending: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+
+    /// Returns the neighbor cache used to resolve link-layer addresses, for
+    /// instance to add static entries.
+    pub fn neighbor_cache(&self) -> &NeighborCache {
+        &self.neighbors
+    }
+
+    fn init_packet(
+        &self,
+        dst_addr: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableMutableBuffer<'static, u8>,
+    ) {
+        self.ip6_packet.map_or_else(
+            || {
+                debug!("init packet failed.");
+            },
+            |ip6_packet| {
+                ip6_packet.header = IP6Header::default();
+                ip6_packet.header.src_addr = self.src_addr.get();
+                ip6_packet.header.dst_addr = dst_addr;
+                ip6_packet.set_payload(transport_header, payload);
+                ip6_packet.set_transport_checksum();
+            },
+        );
+    }
+
+    /// Serializes the IPv6 packet after the (not yet written) Ethernet header
+    /// into `tx_buf`, and returns the length of the resulting frame.
+    fn encode_packet(&self) -> Result<usize, ErrorCode> {
+        let ip6_packet = self.ip6_packet.take().ok_or(ErrorCode::NOMEM)?;
+        let result = self.tx_buf.map_or(Err(ErrorCode::BUSY), |tx_buf| {
+            let ip_len = ip6_packet.get_total_len() as usize;
+            if ETHERNET_HDR_LEN + ip_len > tx_buf.len() {
+                return Err(ErrorCode::SIZE);
+            }
+            ip6_packet
+                .encode(&mut tx_buf[ETHERNET_HDR_LEN..])
+                .done()
+                .ok_or(ErrorCode::FAIL)?;
+            Ok(pad_frame(tx_buf, ETHERNET_HDR_LEN + ip_len))
+        });
+        self.ip6_packet.replace(ip6_packet);
+        result
+    }
+
+    /// Writes the Ethernet header of the frame in `tx_buf` and marks it
+    /// ready for transmission.
+    fn set_frame_dst(&self, dst_mac: EthernetAddress) {
+        self.tx_buf.map(|tx_buf| {
+            let header = EthernetHeader::new(dst_mac, self.src_mac_addr, ethertype::IPV6);
+            let _ = header.encode(tx_buf);
+        });
+        self.data_ready.set(true);
+    }
+
+    /// Starts the next pending transmission, if the adapter is not busy.
+    /// Neighbor Discovery messages take priority over the data frame. Returns
+    /// an error if the data frame could not be handed to the adapter.
+    fn transmit_next(&self) -> Result<(), ErrorCode> {
+        if self.in_flight.get() != InFlight::Idle {
+            return Ok(());
+        }
+
+        if let Some((dst_addr, dst_mac)) = self.advertisement_pending.take() {
+            if self.send_advertisement(dst_addr, dst_mac).is_ok() {
+                return Ok(());
+            }
+        }
+
+        if self.solicitation_pending.get() {
+            self.solicitation_pending.set(false);
+            if let Some(target) = self.resolving.extract() {
+                if self.send_solicitation(target).is_ok() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if self.data_ready.get() {
+            self.data_ready.set(false);
+            let tx_buf = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+            match self.adapter.transmit(tx_buf, self.tx_len.get()) {
+                Ok(()) => self.in_flight.set(InFlight::Data),
+                Err((ecode, tx_buf)) => {
+                    self.tx_buf.replace(tx_buf);
+                    return Err(ecode);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_solicitation(&self, target: IPAddr) -> Result<(), ErrorCode> {
+        let dst_addr = ndp::solicited_node_multicast(&target);
+        self.send_ndp(
+            ICMP6HeaderOptions::Type135 { reserved: 0 },
+            dst_addr,
+            EthernetAddress::from_ipv6_multicast(&dst_addr),
+            target,
+            ndp_option::SOURCE_LINK_LAYER_ADDR,
+        )
+    }
+
+    fn send_advertisement(
+        &self,
+        dst_addr: IPAddr,
+        dst_mac: EthernetAddress,
+    ) -> Result<(), ErrorCode> {
+        // Unsolicited advertisements (in reply to a solicitation from the
+        // unspecified address) must not have the solicited flag set
+        let flags = if dst_addr == ALL_NODES {
+            na_flags::OVERRIDE
+        } else {
+            na_flags::SOLICITED | na_flags::OVERRIDE
+        };
+        self.send_ndp(
+            ICMP6HeaderOptions::Type136 { flags },
+            dst_addr,
+            dst_mac,
+            self.src_addr.get(),
+            ndp_option::TARGET_LINK_LAYER_ADDR,
+        )
+    }
+
+    fn send_ndp(
+        &self,
+        options: ICMP6HeaderOptions,
+        dst_addr: IPAddr,
+        dst_mac: EthernetAddress,
+        target: IPAddr,
+        option_type: u8,
+    ) -> Result<(), ErrorCode> {
+        let ndp_buf = self.ndp_buf.take().ok_or(ErrorCode::BUSY)?;
+
+        let mut ip6_header = IP6Header::default();
+        ip6_header.set_next_header(ip6_nh::ICMP);
+        ip6_header.set_payload_len((ICMP_HDR_LEN + NDP_BODY_LEN) as u16);
+        ip6_header.src_addr = self.src_addr.get();
+        ip6_header.dst_addr = dst_addr;
+
+        let icmp_type = match options {
+            ICMP6HeaderOptions::Type135 { .. } => ICMP6Type::Type135,
+            _ => ICMP6Type::Type136,
+        };
+        let mut icmp_header = ICMP6Header::new(icmp_type);
+        icmp_header.set_options(options);
+        icmp_header.set_len((ICMP_HDR_LEN + NDP_BODY_LEN) as u16);
+
+        let eth_header = EthernetHeader::new(dst_mac, self.src_mac_addr, ethertype::IPV6);
+        let body_off = ETHERNET_HDR_LEN + 40 + ICMP_HDR_LEN;
+        let encoded = eth_header
+            .encode(ndp_buf)
+            .done()
+            .and_then(|(off, _)| ip6_header.encode(&mut ndp_buf[off..]).done())
+            .and_then(|_| {
+                ndp::encode_ndp_body(
+                    &mut ndp_buf[body_off..],
+                    &target,
+                    option_type,
+                    &self.src_mac_addr,
+                )
+                .done()
+            })
+            .and_then(|_| {
+                let cksum = compute_icmp_checksum(
+                    &ip6_header,
+                    &icmp_header,
+                    &ndp_buf[body_off..NDP_FRAME_LEN],
+                );
+                icmp_header.set_cksum(cksum);
+                icmp_header.encode(ndp_buf, ETHERNET_HDR_LEN + 40).done()
+            });
+        if encoded.is_none() {
+            self.ndp_buf.replace(ndp_buf);
+            return Err(ErrorCode::FAIL);
+        }
+
+        let len = pad_frame(ndp_buf, NDP_FRAME_LEN);
+        match self.adapter.transmit(ndp_buf, len) {
+            Ok(()) => {
+                self.in_flight.set(InFlight::Ndp);
+                Ok(())
+            }
+            Err((ecode, ndp_buf)) => {
+                self.ndp_buf.replace(ndp_buf);
+                Err(ecode)
+            }
+        }
+    }
+
+    /// Records the link-layer address of a neighbor, and sends the pending
+    /// packet if it was waiting for this address to be resolved.
+    fn neighbor_resolved(&self, addr: IPAddr, mac: EthernetAddress) {
+        self.neighbors.insert(addr, mac);
+        if self.resolving.map_or(false, |target| *target == addr) {
+            self.resolving.clear();
+            self.solicitation_pending.set(false);
+            let _ = self.alarm.disarm();
+            self.set_frame_dst(mac);
+        }
+    }
+
+    /// Handles a received ICMPv6 message if it is a Neighbor Solicitation or
+    /// Advertisement. Returns whether the message was consumed.
+    fn receive_ndp(&self, ip6_header: &IP6Header, icmp: &[u8], src_mac: EthernetAddress) -> bool {
+        let icmp_type = match ICMP6Header::decode(icmp).done() {
+            Some((_, icmp_header)) => icmp_header.get_type(),
+            None => return false,
+        };
+        let option_type = match icmp_type {
+            ICMP6Type::Type135 => ndp_option::SOURCE_LINK_LAYER_ADDR,
+            ICMP6Type::Type136 => ndp_option::TARGET_LINK_LAYER_ADDR,
+            _ => return false,
+        };
+
+        // Neighbor Discovery messages must not have been forwarded by a
+        // router and must carry a valid checksum (RFC 4861, section 7.1)
+        if ip6_header.get_hop_limit() != 255 || !icmp_checksum_valid(ip6_header, icmp) {
+            return true;
+        }
+        let (target, ll_addr) =
+            match ndp::decode_ndp_body(&icmp[ICMP_HDR_LEN..], option_type).done() {
+                Some((_, body)) => body,
+                None => return true,
+            };
+
+        match icmp_type {
+            ICMP6Type::Type135 => {
+                if target != self.src_addr.get() {
+                    return true;
+                }
+                let src_addr = ip6_header.get_src_addr();
+                if src_addr.is_unspecified() {
+                    // Duplicate address detection, reply to all nodes
+                    self.advertisement_pending
+                        .set((ALL_NODES, EthernetAddress::from_ipv6_multicast(&ALL_NODES)));
+                } else {
+                    let src_mac = ll_addr.unwrap_or(src_mac);
+                    self.neighbor_resolved(src_addr, src_mac);
+                    self.advertisement_pending.set((src_addr, src_mac));
+                }
+            }
+            _ => {
+                if let Some(mac) = ll_addr {
+                    self.neighbor_resolved(target, mac);
+                }
+            }
+        }
+
+        if let Err(ecode) = self.transmit_next() {
+            self.send_completed(Err(ecode));
+        }
+        true
+    }
+
+    fn send_completed(&self, result: Result<(), ErrorCode>) {
+        self.sending.set(false);
+        self.client.map(move |client| {
+            client.send_done(result);
+        });
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> time::AlarmClient for IP6EthernetStruct<'a, A> {
+    fn alarm(&self) {
+        if self.resolving.is_none() {
+            return;
+        }
+        if self.solicitations.get() >= MAX_MULTICAST_SOLICIT {
+            // Address resolution failed, drop the packet
+            self.resolving.clear();
+            self.solicitation_pending.set(false);
+            self.send_completed(Err(ErrorCode::FAIL));
+            return;
+        }
+        self.solicitations.set(self.solicitations.get() + 1);
+        self.solicitation_pending.set(true);
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(RETRANS_TIMER_MS));
+        let _ = self.transmit_next();
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> EthernetAdapterClient for IP6EthernetStruct<'a, A> {
+    fn tx_done(&self, result: Result<(), ErrorCode>, frame: &'static mut [u8], _len: usize) {
+        match self.in_flight.get() {
+            InFlight::Data => {
+                self.tx_buf.replace(frame);
+                self.in_flight.set(InFlight::Idle);
+                // Send any pending advertisement before handing control to
+                // the client, which may immediately send the next packet
+                let _ = self.transmit_next();
+                if result != Ok(()) {
+                    debug!("Send Failed: {:?}", result);
+                }
+                self.send_completed(result);
+            }
+            InFlight::Ndp => {
+                self.ndp_buf.replace(frame);
+                self.in_flight.set(InFlight::Idle);
+                if let Err(ecode) = self.transmit_next() {
+                    self.send_completed(Err(ecode));
+                }
+            }
+            InFlight::Idle => {
+                debug!("Unexpected Ethernet tx_done");
+            }
+        }
+    }
+
+    fn rx_frame(&self, frame: &[u8]) {
+        let (off, eth_header) = match EthernetHeader::decode(frame).done() {
+            Some(header) => header,
+            None => return,
+        };
+        if eth_header.ethertype != ethertype::IPV6
+            || (eth_header.dst_addr != self.src_mac_addr && !eth_header.dst_addr.is_multicast())
+        {
+            return;
+        }
+
+        let buf = &frame[off..];
+        let (ip_off, ip6_header) = match IP6Header::decode(buf).done() {
+            Some(header) => header,
+            None => {
+                debug!("failed to decode ipv6 header");
+                return;
+            }
+        };
+        // Ethernet frames may be padded, so the length is determined by the
+        // IPv6 header
+        let len = ip_off + ip6_header.get_payload_len() as usize;
+        if len > buf.len() {
+            return;
+        }
+        let dst_addr = ip6_header.get_dst_addr();
+        if dst_addr != self.src_addr.get() && !dst_addr.is_multicast() {
+            return;
+        }
+
+        let payload = &buf[ip_off..len];
+        if ip6_header.get_next_header() == ip6_nh::ICMP
+            && self.receive_ndp(&ip6_header, payload, eth_header.src_addr)
+        {
+            return;
+        }
+
+        let checksum_result = ip6_header.check_transport_checksum(payload);
+        if checksum_result == Err(ErrorCode::FAIL) {
+            debug!("cksum fail!: {:?}", checksum_result);
+            return; //Dropped.
+        }
+        self.rx_client
+            .map(|client| client.receive(ip6_header, payload));
+    }
+}
+
+/// Zero-pads a frame of `len` bytes to the minimum Ethernet frame length, if
+/// the buffer permits, and returns the padded length.
+fn pad_frame(buf: &mut [u8], len: usize) -> usize {
+    let padded_len = core::cmp::max(len, core::cmp::min(ETHERNET_MIN_FRAME_LEN, buf.len()));
+    for byte in buf[len..padded_len].iter_mut() {
+        *byte = 0;
+    }
+    padded_len
+}
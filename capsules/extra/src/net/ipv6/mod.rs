@@ -1,4 +1,5 @@
 pub mod ip_utils;
+pub mod ipv6_ethernet;
 pub mod ipv6_recv;
 pub mod ipv6_send;
 
@@ -11,3 +12,6 @@ pub use ipv6::IPPayload;
 pub use ipv6::TransportHeader;
 pub use ipv6::ICMP_HDR_LEN;
 pub use ipv6::UDP_HDR_LEN;
+
+#[cfg(test)]
+mod tests;
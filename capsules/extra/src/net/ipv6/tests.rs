@@ -0,0 +1,513 @@
+//! Tests of the IPv6 layer: checksums against known answers, and IPv6 over
+//! a simulated Ethernet adapter, with the test playing the neighbor.
+
+use crate::net::ethernet::{ethertype, EthernetAddress, EthernetHeader, ETHERNET_HDR_LEN};
+use crate::net::icmpv6::ndp::{self, na_flags, ndp_option, NDP_BODY_LEN};
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ipv6::ip_utils::{compute_icmp_checksum, icmp_checksum_valid, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_ethernet::{IP6EthernetStruct, NDP_FRAME_LEN};
+use crate::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvClient};
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, IP6Packet, IPPayload, TransportHeader, ICMP_HDR_LEN};
+use crate::net::network_capabilities::{AddrRange, IpVisibilityCapability, NetworkCapability};
+use crate::net::udp::UDPHeader;
+use crate::testing::alarm::MockAlarm;
+use core::cell::{Cell, RefCell};
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
+use kernel::ErrorCode;
+use std::boxed::Box;
+use std::vec::Vec;
+
+const SRC: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+const DST: IPAddr = IPAddr([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+/// An Echo Request from fe80::1 to fe80::2 with identifier 0x1234, sequence
+/// number 1 and the payload "abcd". Its checksum is 0xabb8.
+const ECHO_PAYLOAD: &[u8] = b"abcd";
+const ECHO_CHECKSUM: u16 = 0xabb8;
+
+fn echo_request() -> (IP6Header, ICMP6Header) {
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_next_header(ip6_nh::ICMP);
+    ip6_header.set_payload_len(12);
+    ip6_header.src_addr = SRC;
+    ip6_header.dst_addr = DST;
+
+    let mut icmp_header = ICMP6Header::new(ICMP6Type::Type128);
+    icmp_header.set_options(ICMP6HeaderOptions::Type128 {
+        id: 0x1234,
+        seqno: 1,
+    });
+    icmp_header.set_len(12);
+
+    (ip6_header, icmp_header)
+}
+
+#[test]
+fn icmp_checksum_known_answer() {
+    // The sum of the addresses overflows 16 bits, so the carries have to be
+    // folded, and the pseudo-header holds the payload length in host order
+    let (ip6_header, icmp_header) = echo_request();
+    assert_eq!(
+        compute_icmp_checksum(&ip6_header, &icmp_header, ECHO_PAYLOAD),
+        ECHO_CHECKSUM
+    );
+}
+
+#[test]
+fn icmp_checksum_is_verified() {
+    let (ip6_header, mut icmp_header) = echo_request();
+    icmp_header.set_cksum(ECHO_CHECKSUM);
+
+    let mut message = [0; 12];
+    let off = icmp_header.encode(&mut message, 0).done().unwrap().0;
+    message[off..].copy_from_slice(ECHO_PAYLOAD);
+    assert!(icmp_checksum_valid(&ip6_header, &message));
+
+    message[11] ^= 1;
+    assert!(!icmp_checksum_valid(&ip6_header, &message));
+}
+
+#[test]
+fn icmp_checksum_pads_odd_lengths() {
+    // The same Echo Request with the payload "abc" has the checksum 0xac1d.
+    // The byte following the message must not be summed.
+    let (mut ip6_header, mut icmp_header) = echo_request();
+    ip6_header.set_payload_len(11);
+    icmp_header.set_len(11);
+    assert_eq!(
+        compute_icmp_checksum(&ip6_header, &icmp_header, b"abcX"),
+        0xac1d
+    );
+
+    icmp_header.set_cksum(0xac1d);
+    let mut message = [0; 11];
+    let off = icmp_header.encode(&mut message, 0).done().unwrap().0;
+    message[off..].copy_from_slice(b"abc");
+    assert!(icmp_checksum_valid(&ip6_header, &message));
+
+    message[10] ^= 1;
+    assert!(!icmp_checksum_valid(&ip6_header, &message));
+}
+
+// IPv6 over Ethernet
+
+const MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+const PEER_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]);
+
+/// Offsets of the IPv6 header and the ICMPv6 message in a frame.
+const IP6_OFF: usize = ETHERNET_HDR_LEN;
+const ICMP_OFF: usize = IP6_OFF + 40;
+
+fn leak_buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(std::vec![0; len].into_boxed_slice())
+}
+
+/// An Ethernet adapter that records the frames sent. The test completes each
+/// transmission, and delivers frames to the client directly.
+struct SimEthernet {
+    sent: RefCell<Vec<Vec<u8>>>,
+    transmitting: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    client: OptionalCell<&'static dyn EthernetAdapterClient>,
+}
+
+impl SimEthernet {
+    fn new() -> SimEthernet {
+        SimEthernet {
+            sent: RefCell::new(Vec::new()),
+            transmitting: TakeCell::empty(),
+            len: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Completes the transmission in progress, if there is one.
+    fn complete(&self) -> bool {
+        match self.transmitting.take() {
+            Some(frame) => {
+                self.client
+                    .map(|client| client.tx_done(Ok(()), frame, self.len.get()));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl EthernetAdapter<'static> for SimEthernet {
+    fn set_client(&self, client: &'static dyn EthernetAdapterClient) {
+        self.client.set(client);
+    }
+
+    fn enable_receive(&self) {}
+
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.transmitting.is_some() {
+            return Err((ErrorCode::BUSY, frame));
+        }
+        self.sent.borrow_mut().push(frame[..len].to_vec());
+        self.len.set(len);
+        self.transmitting.replace(frame);
+        Ok(())
+    }
+}
+
+/// Records the results of sends and the packets received.
+#[derive(Default)]
+struct Client {
+    sent: Cell<Option<Result<(), ErrorCode>>>,
+    received: RefCell<Vec<Vec<u8>>>,
+}
+
+impl IP6SendClient for Client {
+    fn send_done(&self, result: Result<(), ErrorCode>) {
+        self.sent.set(Some(result));
+    }
+}
+
+impl IP6RecvClient for Client {
+    fn receive(&self, _header: IP6Header, payload: &[u8]) {
+        self.received.borrow_mut().push(payload.to_vec());
+    }
+}
+
+type Ip6Ethernet = IP6EthernetStruct<'static, MockAlarm<'static, Freq1KHz>>;
+
+struct EthernetHarness {
+    alarm: &'static MockAlarm<'static, Freq1KHz>,
+    ethernet: &'static SimEthernet,
+    ip: &'static Ip6Ethernet,
+    client: &'static Client,
+}
+
+impl EthernetHarness {
+    fn new() -> EthernetHarness {
+        let alarm = Box::leak(Box::new(MockAlarm::new()));
+        let ethernet = Box::leak(Box::new(SimEthernet::new()));
+        let ip6_packet = Box::leak(Box::new(IP6Packet::new(IPPayload::new(
+            TransportHeader::UDP(UDPHeader::new()),
+            leak_buffer(200),
+        ))));
+        let ip = Box::leak(Box::new(IP6EthernetStruct::new(
+            ip6_packet,
+            alarm,
+            leak_buffer(300),
+            leak_buffer(NDP_FRAME_LEN),
+            ethernet,
+            MAC,
+            Box::leak(Box::new(IpVisibilityCapability::new_for_test())),
+        )));
+        let client = Box::leak(Box::new(Client::default()));
+        ip.set_addr(SRC);
+        IP6Sender::set_client(ip, client);
+        IP6Receiver::set_client(ip, client);
+        ethernet.set_client(ip);
+        alarm.set_alarm_client(ip);
+
+        EthernetHarness {
+            alarm,
+            ethernet,
+            ip,
+            client,
+        }
+    }
+
+    /// Sends a UDP datagram carrying "tock" to `dst`.
+    fn send(&self, dst: IPAddr) -> Result<(), ErrorCode> {
+        let mut udp_header = UDPHeader::new();
+        udp_header.set_src_port(1000);
+        udp_header.set_dst_port(2000);
+        udp_header.set_len(8 + 4);
+        let payload = LeasableMutableBuffer::new(b"tock".to_vec().leak());
+        let net_cap = Box::leak(Box::new(NetworkCapability::new_for_test(AddrRange::Any)));
+        self.ip
+            .send_to(dst, TransportHeader::UDP(udp_header), &payload, net_cap)
+    }
+
+    /// Takes the frames sent so far.
+    fn sent(&self) -> Vec<Vec<u8>> {
+        self.ethernet.sent.take()
+    }
+}
+
+/// An NDP frame from the peer, with a link-layer address option.
+fn ndp_frame(
+    options: ICMP6HeaderOptions,
+    src_addr: IPAddr,
+    dst_addr: IPAddr,
+    dst_mac: EthernetAddress,
+    target: IPAddr,
+    option_type: u8,
+) -> Vec<u8> {
+    let mut frame = std::vec![0; NDP_FRAME_LEN];
+    let eth_header = EthernetHeader::new(dst_mac, PEER_MAC, ethertype::IPV6);
+    eth_header.encode(&mut frame).done().unwrap();
+
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_next_header(ip6_nh::ICMP);
+    ip6_header.set_payload_len((ICMP_HDR_LEN + NDP_BODY_LEN) as u16);
+    ip6_header.src_addr = src_addr;
+    ip6_header.dst_addr = dst_addr;
+    ip6_header.encode(&mut frame[IP6_OFF..]).done().unwrap();
+
+    let body_off = ICMP_OFF + ICMP_HDR_LEN;
+    ndp::encode_ndp_body(&mut frame[body_off..], &target, option_type, &PEER_MAC)
+        .done()
+        .unwrap();
+
+    let icmp_type = match options {
+        ICMP6HeaderOptions::Type135 { .. } => ICMP6Type::Type135,
+        _ => ICMP6Type::Type136,
+    };
+    let mut icmp_header = ICMP6Header::new(icmp_type);
+    icmp_header.set_options(options);
+    icmp_header.set_len((ICMP_HDR_LEN + NDP_BODY_LEN) as u16);
+    icmp_header.set_cksum(compute_icmp_checksum(
+        &ip6_header,
+        &icmp_header,
+        &frame[body_off..],
+    ));
+    icmp_header.encode(&mut frame, ICMP_OFF).done().unwrap();
+    frame
+}
+
+/// Decodes an NDP frame sent, checking its checksum, and returns its
+/// Ethernet header, ICMPv6 header, target and link-layer address.
+fn decode_ndp(
+    frame: &[u8],
+    option_type: u8,
+) -> (
+    EthernetHeader,
+    IP6Header,
+    ICMP6Header,
+    IPAddr,
+    EthernetAddress,
+) {
+    assert_eq!(frame.len(), NDP_FRAME_LEN);
+    let eth_header = EthernetHeader::decode(frame).done().unwrap().1;
+    assert_eq!(eth_header.ethertype, ethertype::IPV6);
+    let ip6_header = IP6Header::decode(&frame[IP6_OFF..]).done().unwrap().1;
+    assert_eq!(ip6_header.get_hop_limit(), 255);
+    assert!(icmp_checksum_valid(&ip6_header, &frame[ICMP_OFF..]));
+
+    let icmp_header = ICMP6Header::decode(&frame[ICMP_OFF..]).done().unwrap().1;
+    let (target, ll_addr) = ndp::decode_ndp_body(&frame[ICMP_OFF + ICMP_HDR_LEN..], option_type)
+        .done()
+        .unwrap()
+        .1;
+    (
+        eth_header,
+        ip6_header,
+        icmp_header,
+        target,
+        ll_addr.unwrap(),
+    )
+}
+
+#[test]
+fn answers_neighbor_solicitations() {
+    let h = EthernetHarness::new();
+    let solicited_node = ndp::solicited_node_multicast(&SRC);
+    h.ip.rx_frame(&ndp_frame(
+        ICMP6HeaderOptions::Type135 { reserved: 0 },
+        DST,
+        solicited_node,
+        EthernetAddress::from_ipv6_multicast(&solicited_node),
+        SRC,
+        ndp_option::SOURCE_LINK_LAYER_ADDR,
+    ));
+
+    let sent = h.sent();
+    assert_eq!(sent.len(), 1);
+    let (eth_header, ip6_header, icmp_header, target, ll_addr) =
+        decode_ndp(&sent[0], ndp_option::TARGET_LINK_LAYER_ADDR);
+    assert_eq!(eth_header.dst_addr, PEER_MAC);
+    assert_eq!(eth_header.src_addr, MAC);
+    assert_eq!((ip6_header.src_addr, ip6_header.dst_addr), (SRC, DST));
+    assert!(matches!(
+        icmp_header.get_options(),
+        ICMP6HeaderOptions::Type136 { flags } if flags == na_flags::SOLICITED | na_flags::OVERRIDE
+    ));
+    assert_eq!((target, ll_addr), (SRC, MAC));
+
+    // The peer was added to the neighbor cache
+    assert_eq!(h.ip.neighbor_cache().lookup(&DST), Some(PEER_MAC));
+    assert!(h.ethernet.complete());
+    assert!(h.sent().is_empty());
+}
+
+#[test]
+fn ignores_solicitations_for_other_addresses() {
+    let h = EthernetHarness::new();
+    let mut frame = ndp_frame(
+        ICMP6HeaderOptions::Type135 { reserved: 0 },
+        DST,
+        SRC,
+        MAC,
+        DST,
+        ndp_option::SOURCE_LINK_LAYER_ADDR,
+    );
+    h.ip.rx_frame(&frame);
+    assert!(h.sent().is_empty());
+
+    // A solicitation for our address with a bad checksum
+    frame = ndp_frame(
+        ICMP6HeaderOptions::Type135 { reserved: 0 },
+        DST,
+        SRC,
+        MAC,
+        SRC,
+        ndp_option::SOURCE_LINK_LAYER_ADDR,
+    );
+    frame[NDP_FRAME_LEN - 1] ^= 1;
+    h.ip.rx_frame(&frame);
+    assert!(h.sent().is_empty());
+    assert_eq!(h.ip.neighbor_cache().lookup(&DST), None);
+}
+
+#[test]
+fn resolves_destinations_before_sending() {
+    let h = EthernetHarness::new();
+    h.send(DST).unwrap();
+
+    // A solicitation is multicast to the solicited-node address of the
+    // destination
+    let sent = h.sent();
+    assert_eq!(sent.len(), 1);
+    let (eth_header, ip6_header, icmp_header, target, ll_addr) =
+        decode_ndp(&sent[0], ndp_option::SOURCE_LINK_LAYER_ADDR);
+    let solicited_node = ndp::solicited_node_multicast(&DST);
+    assert_eq!(
+        eth_header.dst_addr,
+        EthernetAddress::from_ipv6_multicast(&solicited_node)
+    );
+    assert_eq!(ip6_header.dst_addr, solicited_node);
+    assert_eq!(icmp_header.get_type_as_int(), 135);
+    assert_eq!((target, ll_addr), (DST, MAC));
+    assert!(h.ethernet.complete());
+    assert!(h.sent().is_empty());
+
+    // Once the peer advertises its address, the datagram is sent to it
+    h.ip.rx_frame(&ndp_frame(
+        ICMP6HeaderOptions::Type136 {
+            flags: na_flags::SOLICITED | na_flags::OVERRIDE,
+        },
+        DST,
+        SRC,
+        MAC,
+        DST,
+        ndp_option::TARGET_LINK_LAYER_ADDR,
+    ));
+    let sent = h.sent();
+    assert_eq!(sent.len(), 1);
+    let frame = &sent[0];
+    let eth_header = EthernetHeader::decode(frame).done().unwrap().1;
+    assert_eq!(
+        eth_header,
+        EthernetHeader::new(PEER_MAC, MAC, ethertype::IPV6)
+    );
+    let ip6_header = IP6Header::decode(&frame[IP6_OFF..]).done().unwrap().1;
+    assert_eq!((ip6_header.src_addr, ip6_header.dst_addr), (SRC, DST));
+    assert_eq!(ip6_header.get_next_header(), ip6_nh::UDP);
+    assert_eq!(ip6_header.get_payload_len(), 12);
+    let udp = &frame[ICMP_OFF..ICMP_OFF + 12];
+    assert_eq!(ip6_header.check_transport_checksum(udp), Ok(()));
+    assert_eq!(&udp[8..], b"tock");
+    // Short frames are padded to the minimum Ethernet frame length
+    assert_eq!(frame.len(), 60.max(ICMP_OFF + 12));
+
+    assert_eq!(h.client.sent.get(), None);
+    assert!(h.ethernet.complete());
+    assert_eq!(h.client.sent.take(), Some(Ok(())));
+
+    // The address is cached for the next datagram
+    h.send(DST).unwrap();
+    let sent = h.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        EthernetHeader::decode(&sent[0]).done().unwrap().1.dst_addr,
+        PEER_MAC
+    );
+}
+
+#[test]
+fn fails_when_destinations_do_not_answer() {
+    let h = EthernetHarness::new();
+    h.send(DST).unwrap();
+    assert_eq!(h.send(DST), Err(ErrorCode::BUSY));
+
+    // Three solicitations are sent, a second apart
+    for _ in 0..3 {
+        let sent = h.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][ICMP_OFF], 135);
+        assert!(h.ethernet.complete());
+        assert_eq!(h.client.sent.get(), None);
+        h.alarm.advance(1000);
+    }
+
+    assert!(h.sent().is_empty());
+    assert_eq!(h.client.sent.take(), Some(Err(ErrorCode::FAIL)));
+    assert!(!h.alarm.is_armed());
+}
+
+#[test]
+fn sends_multicast_without_resolution() {
+    let h = EthernetHarness::new();
+    let all_nodes = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    h.send(all_nodes).unwrap();
+
+    let sent = h.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(
+        EthernetHeader::decode(&sent[0]).done().unwrap().1.dst_addr,
+        EthernetAddress([0x33, 0x33, 0, 0, 0, 1])
+    );
+    assert!(h.ethernet.complete());
+    assert_eq!(h.client.sent.take(), Some(Ok(())));
+}
+
+#[test]
+fn delivers_received_packets() {
+    let h = EthernetHarness::new();
+
+    // The echo request, in a frame padded to the minimum length
+    let (mut ip6_header, mut icmp_header) = echo_request();
+    ip6_header.src_addr = DST;
+    ip6_header.dst_addr = SRC;
+    icmp_header.set_cksum(compute_icmp_checksum(
+        &ip6_header,
+        &icmp_header,
+        ECHO_PAYLOAD,
+    ));
+    let mut frame = std::vec![0; 60.max(ICMP_OFF + 12)];
+    EthernetHeader::new(MAC, PEER_MAC, ethertype::IPV6)
+        .encode(&mut frame)
+        .done()
+        .unwrap();
+    ip6_header.encode(&mut frame[IP6_OFF..]).done().unwrap();
+    icmp_header.encode(&mut frame, ICMP_OFF).done().unwrap();
+    frame[ICMP_OFF + ICMP_HDR_LEN..ICMP_OFF + 12].copy_from_slice(ECHO_PAYLOAD);
+
+    h.ip.rx_frame(&frame);
+    let received = h.client.received.take();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0], frame[ICMP_OFF..ICMP_OFF + 12]);
+
+    // Frames for another MAC address are dropped. Packets with a bad
+    // checksum are too, but that is reported with `debug!`, which needs the
+    // debug writer of a board.
+    let mut other = frame.clone();
+    other[5] ^= 1;
+    h.ip.rx_frame(&other);
+    assert!(h.client.received.take().is_empty());
+    assert!(h.sent().is_empty());
+}
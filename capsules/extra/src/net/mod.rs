@@ -1,14 +1,18 @@
-//! Modules for IPv6 over 6LoWPAN stack
+//! Modules for IPv6 over 6LoWPAN and Ethernet stack
 
 pub mod frag_utils;
 pub mod sixlowpan;
 pub mod util;
 #[macro_use]
 pub mod stream;
+pub mod coap;
+pub mod dtls;
+pub mod ethernet;
 pub mod icmpv6;
 pub mod ieee802154;
 pub mod ipv6;
 pub mod network_capabilities;
+pub mod rpl;
 pub mod tcp;
 pub mod thread;
 pub mod udp;
@@ -106,6 +106,15 @@ impl IpVisibilityCapability {
     }
 }
 
+// Tests can't implement `NetworkCapabilityCreationCapability`, which is an
+// unsafe trait, so they create capabilities directly.
+#[cfg(test)]
+impl IpVisibilityCapability {
+    pub(crate) fn new_for_test() -> IpVisibilityCapability {
+        IpVisibilityCapability { _priv: () }
+    }
+}
+
 /// The NetworkCapability specifies access to network resourcess across the UDP
 /// and IP layers. Access to layer-specific information is mediated by the
 /// UdpVsibilityCapability and the IpVisibilityCapability.
@@ -130,6 +139,15 @@ impl NetworkCapability {
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn new_for_test(remote_addrs: AddrRange) -> NetworkCapability {
+        NetworkCapability {
+            remote_addrs: remote_addrs,
+            remote_ports: PortRange::Any,
+            local_ports: PortRange::Any,
+        }
+    }
+
     pub fn get_range(&self, _ip_cap: &'static IpVisibilityCapability) -> AddrRange {
         self.remote_addrs
     }
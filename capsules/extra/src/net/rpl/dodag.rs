@@ -0,0 +1,960 @@
+//! This file implements the state machine of an RPL (RFC 6550) node in
+//! non-storing mode: joining a DODAG through DIOs, maintaining the parent
+//! set, advertising the node to the root through DAOs, and (on the root)
+//! maintaining the source routes to the nodes of the DODAG.
+//!
+//! `RplState` is independent of the networking stack. It is driven by the
+//! received control messages, which are handed to `receive_message`, and by
+//! its timers, whose next deadline is reported by `next_deadline` and which
+//! are processed by `timer_fired`. Timestamps are wrapping millisecond
+//! counters. Messages the node has to send are produced one at a time by
+//! `next_message`, and the result of unicast transmissions is fed back
+//! through `link_result` to estimate the ETX of links.
+//!
+//! A node has one of three roles:
+//!
+//! - The root creates the DODAG. It advertises rank `MinHopRankIncrease` and
+//!   stores a route for every target advertised in a DAO.
+//! - Routers join the DODAG and advertise it to other nodes with DIOs.
+//! - Leaves join the DODAG but do not send DIOs, so no node can select them
+//!   as parent.
+//!
+//! Nodes other than the root form their global address from the /64 prefix
+//! of the DODAG ID and their link-local interface identifier, and derive the
+//! global address of their parents the same way (the root's global address
+//! is the DODAG ID). These addresses appear in the Target and Transit
+//! Information options of the DAOs, from which the root builds the source
+//! routes.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::rpl::message::{encode_dis, encode_padding, rpl_code, RplOption, RplOptions};
+use crate::net::rpl::message::{mop, validate_options, DAO_ACK_ACCEPTED, DAO_ACK_NO_ROOM};
+use crate::net::rpl::message::{Dao, DaoAck, Dio, DodagConfig, Target, Transit};
+use crate::net::rpl::message::{INFINITE_RANK, RPL_ALL_NODES};
+use crate::net::rpl::objective::{self, ObjectiveFunction, ETX_DIVISOR, MAX_LINK_METRIC};
+use crate::net::rpl::trickle::{reached, Trickle};
+use crate::net::stream::SResult;
+
+/// Maximum number of candidate parents a node keeps track of.
+pub const MAX_PARENTS: usize = 3;
+/// Maximum number of source routes the root can store.
+pub const MAX_ROUTES: usize = 16;
+/// Maximum number of hops of a source route.
+pub const MAX_ROUTE_HOPS: usize = 8;
+/// Maximum number of targets of a DAO the root processes.
+const MAX_DAO_TARGETS: usize = 4;
+/// Size of the buffer required to encode any message by `next_message`.
+pub const RPL_MESSAGE_BUF_LEN: usize = 64;
+
+/// ETX assumed for a link to a new candidate parent.
+pub const INITIAL_LINK_METRIC: u16 = 2 * ETX_DIVISOR;
+
+/// Delay before sending a DAO after the preferred parent changed, allowing
+/// the parent selection to settle.
+const DAO_DELAY_MS: u32 = 1000;
+/// Time to wait for a DAO-ACK before retransmitting the DAO.
+const DAO_ACK_TIMEOUT_MS: u32 = 4000;
+const MAX_DAO_RETRANSMISSIONS: u8 = 3;
+/// Interval between DIS messages while the node is not part of a DODAG.
+const DIS_INTERVAL_MS: u32 = 10000;
+/// Upper bound of timer durations derived from route lifetimes, keeping
+/// deadlines comparable on wrapping timestamps.
+const MAX_TIMER_MS: u32 = 1 << 30;
+
+/// Initial value of lollipop counters (RFC 6550, section 7.2).
+const LOLLIPOP_INIT: u8 = 240;
+const LOLLIPOP_CIRCULAR_REGION: u8 = 127;
+const LOLLIPOP_SEQUENCE_WINDOW: u8 = 16;
+
+fn lollipop_increment(counter: u8) -> u8 {
+    if counter > LOLLIPOP_CIRCULAR_REGION {
+        counter.wrapping_add(1)
+    } else {
+        (counter + 1) & LOLLIPOP_CIRCULAR_REGION
+    }
+}
+
+/// Whether lollipop counter `a` is more recent than `b`.
+fn lollipop_greater(a: u8, b: u8) -> bool {
+    let greater_in_region = |a: u8, b: u8| {
+        (a < b
+            && a as u16 + LOLLIPOP_CIRCULAR_REGION as u16 + 1 - (b as u16)
+                < LOLLIPOP_SEQUENCE_WINDOW as u16)
+            || (a > b && a - b < LOLLIPOP_SEQUENCE_WINDOW)
+    };
+    match (a > LOLLIPOP_CIRCULAR_REGION, b > LOLLIPOP_CIRCULAR_REGION) {
+        (true, true) => greater_in_region(a, b),
+        (true, false) => false,
+        (false, true) => true,
+        (false, false) => greater_in_region(a, b),
+    }
+}
+
+/// Returns the address with the /64 prefix of `prefix` and the interface
+/// identifier of `iid`.
+fn with_prefix(prefix: &IPAddr, iid: &IPAddr) -> IPAddr {
+    let mut addr = *iid;
+    addr.0[..8].copy_from_slice(&prefix.0[..8]);
+    addr
+}
+
+/// Returns the link-local address with the interface identifier of `addr`.
+pub fn link_local_from(addr: &IPAddr) -> IPAddr {
+    let mut link_local = *addr;
+    link_local.set_unicast_link_local();
+    link_local
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RplRole {
+    Root,
+    Router,
+    Leaf,
+}
+
+/// A candidate parent, identified by its link-local address.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Parent {
+    pub addr: IPAddr,
+    pub rank: u16,
+    pub dtsn: u8,
+    /// ETX of the link to the parent, in units of `1 / ETX_DIVISOR`
+    pub link_metric: u16,
+}
+
+/// A route stored by the root: `target` is reachable through `parent`.
+#[derive(Copy, Clone, Debug)]
+struct Route {
+    target: IPAddr,
+    parent: IPAddr,
+    path_sequence: u8,
+    expires: u32,
+}
+
+pub struct RplState {
+    role: RplRole,
+    link_local: IPAddr,
+    joined: bool,
+    instance_id: u8,
+    dodag_id: IPAddr,
+    version: u8,
+    grounded: bool,
+    prf: u8,
+    dtsn: u8,
+    rank: u16,
+    config: DodagConfig,
+    objective: Option<&'static dyn ObjectiveFunction>,
+    parents: [Option<Parent>; MAX_PARENTS],
+    preferred: Option<usize>,
+    trickle: Trickle,
+
+    dao_sequence: u8,
+    path_sequence: u8,
+    dao_deadline: Option<u32>,
+    dao_retransmissions: u8,
+    dao_acked: bool,
+    dis_deadline: Option<u32>,
+
+    routes: [Option<Route>; MAX_ROUTES],
+
+    // Messages waiting to be sent
+    send_dio: bool,
+    send_dio_to: Option<IPAddr>,
+    send_dis: bool,
+    send_dao: bool,
+    send_dao_ack: Option<(IPAddr, u8, u8)>,
+
+    rand: u32,
+}
+
+impl RplState {
+    /// Creates the state of a node with the given role and link-local
+    /// address. The node does not take part in RPL until it is started.
+    pub fn new(role: RplRole, link_local: IPAddr) -> RplState {
+        // Seed the pseudo-random number generator with the interface
+        // identifier, so neighbors pick different Trickle timings
+        let seed = link_local.0[8..]
+            .iter()
+            .fold(0x2545_f491u32, |acc, &b| acc.rotate_left(5) ^ b as u32);
+        RplState {
+            role: role,
+            link_local: link_local,
+            joined: false,
+            instance_id: 0,
+            dodag_id: IPAddr::new(),
+            version: LOLLIPOP_INIT,
+            grounded: false,
+            prf: 0,
+            dtsn: LOLLIPOP_INIT,
+            rank: INFINITE_RANK,
+            config: DodagConfig::default(),
+            objective: None,
+            parents: [None; MAX_PARENTS],
+            preferred: None,
+            trickle: Trickle::new(),
+            dao_sequence: LOLLIPOP_INIT,
+            path_sequence: LOLLIPOP_INIT,
+            dao_deadline: None,
+            dao_retransmissions: 0,
+            dao_acked: false,
+            dis_deadline: None,
+            routes: [None; MAX_ROUTES],
+            send_dio: false,
+            send_dio_to: None,
+            send_dis: false,
+            send_dao: false,
+            send_dao_ack: None,
+            rand: seed | 1,
+        }
+    }
+
+    /// Creates a DODAG, if the node is the root, or starts soliciting DIOs
+    /// to join one otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// `now` - The current time in milliseconds
+    /// `instance_id` - The RPL instance of the DODAG, used by the root only
+    /// `dodag_id` - The global address of the root, used by the root only
+    /// `config` - The DODAG configuration, used by the root only
+    pub fn start(&mut self, now: u32, instance_id: u8, dodag_id: IPAddr, config: DodagConfig) {
+        if self.role == RplRole::Root {
+            self.instance_id = instance_id;
+            self.dodag_id = dodag_id;
+            self.config = config;
+            self.objective = objective::find(config.ocp);
+            self.grounded = true;
+            self.rank = config.min_hop_rank_increase;
+            self.joined = true;
+            self.start_trickle(now);
+        } else {
+            self.send_dis = true;
+            self.dis_deadline = Some(now.wrapping_add(DIS_INTERVAL_MS));
+        }
+    }
+
+    pub fn role(&self) -> RplRole {
+        self.role
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.joined
+    }
+
+    pub fn rank(&self) -> u16 {
+        self.rank
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn dodag_id(&self) -> Option<IPAddr> {
+        if self.joined {
+            Some(self.dodag_id)
+        } else {
+            None
+        }
+    }
+
+    pub fn config(&self) -> &DodagConfig {
+        &self.config
+    }
+
+    pub fn link_local(&self) -> IPAddr {
+        self.link_local
+    }
+
+    /// The global address of the node, once it joined a DODAG.
+    pub fn global_addr(&self) -> Option<IPAddr> {
+        if !self.joined {
+            None
+        } else if self.role == RplRole::Root {
+            Some(self.dodag_id)
+        } else {
+            Some(with_prefix(&self.dodag_id, &self.link_local))
+        }
+    }
+
+    /// Whether the root has acknowledged the current path of the node.
+    pub fn dao_acked(&self) -> bool {
+        self.dao_acked
+    }
+
+    pub fn preferred_parent(&self) -> Option<Parent> {
+        self.preferred.and_then(|i| self.parents[i])
+    }
+
+    pub fn parent(&self, addr: &IPAddr) -> Option<Parent> {
+        self.parents
+            .iter()
+            .flatten()
+            .find(|parent| parent.addr == *addr)
+            .copied()
+    }
+
+    fn next_rand(&mut self) -> u32 {
+        // xorshift32
+        self.rand ^= self.rand << 13;
+        self.rand ^= self.rand >> 17;
+        self.rand ^= self.rand << 5;
+        self.rand
+    }
+
+    fn dag_rank(&self, rank: u16) -> u16 {
+        rank / self.config.min_hop_rank_increase
+    }
+
+    /// Global address of the candidate parent `parent`.
+    fn parent_global_addr(&self, parent: &Parent) -> IPAddr {
+        if parent.rank == self.config.min_hop_rank_increase {
+            self.dodag_id
+        } else {
+            with_prefix(&self.dodag_id, &parent.addr)
+        }
+    }
+
+    fn route_lifetime_ms(&self) -> u32 {
+        self.config
+            .route_lifetime_s()
+            .saturating_mul(1000)
+            .min(MAX_TIMER_MS)
+    }
+
+    fn start_trickle(&mut self, now: u32) {
+        if self.role == RplRole::Leaf {
+            return;
+        }
+        let rand = self.next_rand();
+        self.trickle.start(
+            now,
+            self.config.dio_interval_min,
+            self.config.dio_interval_doublings,
+            self.config.dio_redundancy,
+            rand,
+        );
+    }
+
+    fn reset_trickle(&mut self, now: u32) {
+        let rand = self.next_rand();
+        self.trickle.inconsistent(now, rand);
+    }
+
+    /// Schedules the transmission of a DAO advertising a new path.
+    fn schedule_dao(&mut self, now: u32) {
+        if self.role == RplRole::Root {
+            return;
+        }
+        self.path_sequence = lollipop_increment(self.path_sequence);
+        self.dao_acked = false;
+        self.dao_retransmissions = 0;
+        let jitter = self.next_rand() % DAO_DELAY_MS;
+        self.dao_deadline = Some(now.wrapping_add(DAO_DELAY_MS + jitter));
+    }
+
+    /// Leaves the current DODAG, poisoning the routes through this node and
+    /// soliciting DIOs to find a new DODAG.
+    fn leave(&mut self, now: u32) {
+        self.joined = false;
+        self.preferred = None;
+        self.parents = [None; MAX_PARENTS];
+        self.rank = INFINITE_RANK;
+        self.dao_deadline = None;
+        self.dao_acked = false;
+        self.send_dao = false;
+        if self.trickle.is_running() {
+            // Advertise the infinite rank once, so children detach
+            self.send_dio = true;
+            self.trickle.stop();
+        }
+        self.send_dis = true;
+        self.dis_deadline = Some(now.wrapping_add(DIS_INTERVAL_MS));
+    }
+
+    /// Selects the preferred parent among the candidate parents and updates
+    /// the rank of the node accordingly.
+    fn select_parent(&mut self, now: u32) {
+        let of = match self.objective {
+            Some(of) => of,
+            None => return,
+        };
+        let config = self.config;
+
+        let current = self
+            .preferred
+            .filter(|&i| self.parents[i].map_or(false, |p| of.acceptable(&p, &config)));
+        let mut best = current;
+        for i in 0..MAX_PARENTS {
+            let candidate = match self.parents[i] {
+                Some(candidate) if of.acceptable(&candidate, &config) => candidate,
+                _ => continue,
+            };
+            match best.and_then(|b| self.parents[b]) {
+                Some(best_parent) => {
+                    if Some(i) != best
+                        && of.prefer(&candidate, &best_parent, best == current, &config)
+                    {
+                        best = Some(i);
+                    }
+                }
+                None => best = Some(i),
+            }
+        }
+
+        let parent = match best.and_then(|i| self.parents[i]) {
+            Some(parent) => parent,
+            None => {
+                self.leave(now);
+                return;
+            }
+        };
+        let rank = of.rank_via(&parent, &config);
+        let old_rank = self.rank;
+        let changed = best != self.preferred;
+        self.preferred = best;
+        self.rank = rank;
+
+        if changed {
+            self.schedule_dao(now);
+        }
+        if changed || self.dag_rank(rank) != self.dag_rank(old_rank) {
+            self.reset_trickle(now);
+        }
+    }
+
+    /// Adds or updates the candidate parent with link-local address `addr`.
+    /// Returns whether the parent is in the parent set.
+    fn update_parent(&mut self, addr: IPAddr, rank: u16, dtsn: u8) -> bool {
+        if let Some(parent) = self.parents.iter_mut().flatten().find(|p| p.addr == addr) {
+            parent.rank = rank;
+            parent.dtsn = dtsn;
+            return true;
+        }
+        // Only nodes with a lower rank can become parents, which prevents
+        // loops
+        if rank == INFINITE_RANK
+            || (self.preferred.is_some() && self.dag_rank(rank) >= self.dag_rank(self.rank))
+        {
+            return false;
+        }
+        let new_parent = Parent {
+            addr: addr,
+            rank: rank,
+            dtsn: dtsn,
+            link_metric: INITIAL_LINK_METRIC,
+        };
+        if let Some(slot) = self.parents.iter_mut().find(|p| p.is_none()) {
+            *slot = Some(new_parent);
+            return true;
+        }
+        // Replace the worst candidate that is not the preferred parent
+        let preferred = self.preferred;
+        let worst = (0..MAX_PARENTS)
+            .filter(|&i| Some(i) != preferred)
+            .max_by_key(|&i| self.parents[i].map_or(0, |p| p.rank as u32 + p.link_metric as u32));
+        match worst {
+            Some(i) if self.parents[i].map_or(false, |p| p.rank > rank) => {
+                self.parents[i] = Some(new_parent);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove_parent(&mut self, addr: &IPAddr) {
+        for parent in self.parents.iter_mut() {
+            if parent.map_or(false, |p| p.addr == *addr) {
+                *parent = None;
+            }
+        }
+    }
+
+    /// Handles a received RPL control message.
+    ///
+    /// # Arguments
+    ///
+    /// `now` - The current time in milliseconds
+    /// `src` - The source address of the message
+    /// `dst` - The destination address of the message
+    /// `code` - The ICMPv6 code of the message
+    /// `body` - The message base and options
+    pub fn receive_message(&mut self, now: u32, src: IPAddr, dst: IPAddr, code: u8, body: &[u8]) {
+        match code {
+            rpl_code::DIS => self.receive_dis(now, src, dst),
+            rpl_code::DIO => {
+                if let Some((off, dio)) = Dio::decode(body).done() {
+                    if validate_options(&body[off..]) {
+                        self.receive_dio(now, src, dio, &body[off..]);
+                    }
+                }
+            }
+            rpl_code::DAO => {
+                if let Some((off, dao)) = Dao::decode(body).done() {
+                    if validate_options(&body[off..]) {
+                        self.receive_dao(now, src, dao, &body[off..]);
+                    }
+                }
+            }
+            rpl_code::DAO_ACK => {
+                if let Some((_, ack)) = DaoAck::decode(body).done() {
+                    self.receive_dao_ack(now, ack);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_dis(&mut self, now: u32, src: IPAddr, dst: IPAddr) {
+        if !self.joined || self.role == RplRole::Leaf {
+            return;
+        }
+        if dst.is_multicast() {
+            self.reset_trickle(now);
+        } else {
+            self.send_dio_to = Some(src);
+        }
+    }
+
+    fn receive_dio(&mut self, now: u32, src: IPAddr, dio: Dio, options: &[u8]) {
+        if dio.mop != mop::NON_STORING {
+            return;
+        }
+        if self.role == RplRole::Root {
+            if dio.instance_id == self.instance_id
+                && dio.dodag_id == self.dodag_id
+                && dio.version == self.version
+            {
+                self.trickle.consistent();
+            }
+            return;
+        }
+        let config = RplOptions::new(options).find_map(|option| match option {
+            RplOption::DodagConfig(config) => Some(config),
+            _ => None,
+        });
+
+        let same_dodag =
+            self.joined && dio.instance_id == self.instance_id && dio.dodag_id == self.dodag_id;
+        if self.joined && !same_dodag {
+            // Only a single DODAG is supported
+            return;
+        }
+        if same_dodag && lollipop_greater(self.version, dio.version) {
+            // Outdated DIO
+            return;
+        }
+        let new_version = same_dodag && dio.version != self.version;
+
+        if !same_dodag || new_version {
+            // Join the DODAG, or rejoin it after a global repair
+            if dio.rank == INFINITE_RANK {
+                return;
+            }
+            let config = match config.or(if new_version { Some(self.config) } else { None }) {
+                Some(config) => config,
+                None => return,
+            };
+            let of = match objective::find(config.ocp) {
+                Some(of) => of,
+                None => return,
+            };
+            self.instance_id = dio.instance_id;
+            self.dodag_id = dio.dodag_id;
+            self.version = dio.version;
+            self.grounded = dio.grounded;
+            self.prf = dio.prf;
+            self.config = config;
+            self.objective = Some(of);
+            self.parents = [None; MAX_PARENTS];
+            self.preferred = None;
+            self.rank = INFINITE_RANK;
+            self.joined = true;
+            self.dis_deadline = None;
+            self.send_dis = false;
+            self.update_parent(src, dio.rank, dio.dtsn);
+            self.select_parent(now);
+            if self.joined {
+                self.start_trickle(now);
+            }
+            return;
+        }
+
+        if dio.rank == INFINITE_RANK {
+            // The neighbor detached from the DODAG
+            if self.parent(&src).is_some() {
+                self.remove_parent(&src);
+                self.select_parent(now);
+            }
+            return;
+        }
+        self.trickle.consistent();
+
+        let old_dtsn = self.parent(&src).map(|p| p.dtsn);
+        let was_preferred = self.preferred_parent().map_or(false, |p| p.addr == src);
+        if !self.update_parent(src, dio.rank, dio.dtsn) {
+            return;
+        }
+        self.select_parent(now);
+
+        // In non-storing mode, a DTSN increment of the preferred parent
+        // requests a new DAO, and is propagated to the sub-DODAG
+        if was_preferred
+            && self.preferred_parent().map_or(false, |p| p.addr == src)
+            && old_dtsn.map_or(false, |dtsn| lollipop_greater(dio.dtsn, dtsn))
+        {
+            self.dtsn = lollipop_increment(self.dtsn);
+            self.schedule_dao(now);
+        }
+    }
+
+    fn receive_dao(&mut self, now: u32, src: IPAddr, dao: Dao, options: &[u8]) {
+        if self.role != RplRole::Root || dao.instance_id != self.instance_id {
+            return;
+        }
+        if dao.dodag_id.map_or(false, |id| id != self.dodag_id) {
+            return;
+        }
+
+        // Each Transit Information option applies to the targets preceding
+        // it
+        let mut status = DAO_ACK_ACCEPTED;
+        let mut targets = [None; MAX_DAO_TARGETS];
+        let mut num_targets = 0;
+        for option in RplOptions::new(options) {
+            match option {
+                RplOption::Target(target) => {
+                    if num_targets < MAX_DAO_TARGETS {
+                        targets[num_targets] = Some(target);
+                        num_targets += 1;
+                    }
+                }
+                RplOption::Transit(transit) => {
+                    for target in targets[..num_targets].iter().flatten() {
+                        if !self.update_route(now, target, &transit) {
+                            status = DAO_ACK_NO_ROOM;
+                        }
+                    }
+                    num_targets = 0;
+                }
+                _ => {}
+            }
+        }
+
+        if dao.ack_requested {
+            self.send_dao_ack = Some((src, dao.sequence, status));
+        }
+    }
+
+    /// Updates the route to `target` from a DAO. Returns false if there is
+    /// no room to store the route.
+    fn update_route(&mut self, now: u32, target: &Target, transit: &Transit) -> bool {
+        let parent = match transit.parent {
+            Some(parent) if target.prefix_len == 128 => parent,
+            _ => return true,
+        };
+        let existing = self
+            .routes
+            .iter_mut()
+            .flatten()
+            .find(|r| r.target == target.prefix);
+        if let Some(route) = existing {
+            if lollipop_greater(route.path_sequence, transit.path_sequence) {
+                // Outdated DAO
+                return true;
+            }
+        }
+        // Routes with the same target are replaced, and expired routes may
+        // be reused
+        let slot = self
+            .routes
+            .iter()
+            .position(|r| r.map_or(false, |r| r.target == target.prefix))
+            .or_else(|| {
+                self.routes
+                    .iter()
+                    .position(|r| r.map_or(true, |r| reached(now, r.expires)))
+            });
+        if transit.path_lifetime == 0 {
+            if let Some(i) = slot {
+                if self.routes[i].map_or(false, |r| r.target == target.prefix) {
+                    self.routes[i] = None;
+                }
+            }
+            return true;
+        }
+        let lifetime_ms = (transit.path_lifetime as u32 * self.config.lifetime_unit as u32)
+            .saturating_mul(1000)
+            .min(MAX_TIMER_MS);
+        match slot {
+            Some(i) => {
+                self.routes[i] = Some(Route {
+                    target: target.prefix,
+                    parent: parent,
+                    path_sequence: transit.path_sequence,
+                    expires: now.wrapping_add(lifetime_ms),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn receive_dao_ack(&mut self, now: u32, ack: DaoAck) {
+        if !self.joined
+            || ack.instance_id != self.instance_id
+            || ack.sequence != self.dao_sequence
+            || self.dao_acked
+        {
+            return;
+        }
+        if ack.status < DAO_ACK_NO_ROOM {
+            self.dao_acked = true;
+            self.dao_retransmissions = 0;
+            // Refresh the route before it expires
+            let refresh = self.route_lifetime_ms() / 2;
+            self.dao_deadline = Some(now.wrapping_add(refresh));
+        }
+    }
+
+    /// Updates the ETX estimate of the link to `neighbor` after a unicast
+    /// transmission to it.
+    pub fn link_result(&mut self, now: u32, neighbor: &IPAddr, acked: bool) {
+        let sample = if acked {
+            ETX_DIVISOR as u32
+        } else {
+            MAX_LINK_METRIC as u32 + ETX_DIVISOR as u32
+        };
+        let mut updated = false;
+        for parent in self.parents.iter_mut().flatten() {
+            if parent.addr == *neighbor {
+                // Exponentially weighted moving average with a weight of 0.8
+                let etx = (parent.link_metric as u32 * 8 + sample * 2) / 10;
+                parent.link_metric = etx.min(u16::MAX as u32) as u16;
+                updated = true;
+            }
+        }
+        if updated {
+            self.select_parent(now);
+        }
+    }
+
+    /// Starts a global repair, rebuilding the DODAG with a new version.
+    /// Only the root can start a global repair.
+    pub fn global_repair(&mut self, now: u32) {
+        if self.role == RplRole::Root && self.joined {
+            self.version = lollipop_increment(self.version);
+            self.reset_trickle(now);
+        }
+    }
+
+    /// Requests all nodes to send a new DAO by incrementing the DTSN. Only
+    /// the root can request DAOs.
+    pub fn request_daos(&mut self, now: u32) {
+        if self.role == RplRole::Root && self.joined {
+            self.dtsn = lollipop_increment(self.dtsn);
+            self.reset_trickle(now);
+        }
+    }
+
+    /// The next timestamp at which `timer_fired` must be called.
+    pub fn next_deadline(&self, now: u32) -> Option<u32> {
+        [
+            self.trickle.next_deadline(),
+            self.dao_deadline,
+            self.dis_deadline,
+        ]
+        .iter()
+        .flatten()
+        .min_by_key(|&&deadline| deadline.wrapping_sub(now) as i32)
+        .copied()
+    }
+
+    /// Processes the timers that expired at `now`.
+    pub fn timer_fired(&mut self, now: u32) {
+        let rand = self.next_rand();
+        if self.trickle.fire(now, rand) {
+            self.send_dio = true;
+        }
+        if let Some(deadline) = self.dis_deadline {
+            if reached(now, deadline) {
+                self.send_dis = true;
+                self.dis_deadline = Some(now.wrapping_add(DIS_INTERVAL_MS));
+            }
+        }
+        if let Some(deadline) = self.dao_deadline {
+            if reached(now, deadline) {
+                self.dao_deadline = None;
+                if self.joined && self.preferred.is_some() {
+                    self.send_dao = true;
+                }
+            }
+        }
+    }
+
+    /// Whether a message is waiting to be sent.
+    pub fn has_message(&self) -> bool {
+        self.send_dao_ack.is_some()
+            || self.send_dio_to.is_some()
+            || self.send_dio
+            || self.send_dao
+            || self.send_dis
+    }
+
+    /// Encodes the next message waiting to be sent into `buf`, which should
+    /// be at least `RPL_MESSAGE_BUF_LEN` bytes long.
+    ///
+    /// # Return Value
+    ///
+    /// The destination, the ICMPv6 code and the length of the message body,
+    /// or `None` if there is no message to send.
+    pub fn next_message(&mut self, now: u32, buf: &mut [u8]) -> Option<(IPAddr, u8, usize)> {
+        if let Some((dst, sequence, status)) = self.send_dao_ack.take() {
+            let ack = DaoAck {
+                instance_id: self.instance_id,
+                sequence: sequence,
+                status: status,
+                dodag_id: Some(self.dodag_id),
+            };
+            return ack
+                .encode(buf)
+                .done()
+                .map(|(len, _)| (dst, rpl_code::DAO_ACK, len));
+        }
+        if let Some(dst) = self.send_dio_to.take() {
+            return self.encode_dio(buf).map(|len| (dst, rpl_code::DIO, len));
+        }
+        if self.send_dio {
+            self.send_dio = false;
+            return self
+                .encode_dio(buf)
+                .map(|len| (RPL_ALL_NODES, rpl_code::DIO, len));
+        }
+        if self.send_dao {
+            self.send_dao = false;
+            return self
+                .encode_dao(now, buf)
+                .map(|len| (self.dodag_id, rpl_code::DAO, len));
+        }
+        if self.send_dis {
+            self.send_dis = false;
+            // The padding makes the body fill the four bytes of the ICMPv6
+            // header options
+            let len = match encode_dis(buf) {
+                SResult::Done(off, _) => off + encode_padding(&mut buf[off..], 2).done()?.0,
+                _ => return None,
+            };
+            return Some((RPL_ALL_NODES, rpl_code::DIS, len));
+        }
+        None
+    }
+
+    fn encode_dio(&self, buf: &mut [u8]) -> Option<usize> {
+        let dio = Dio {
+            instance_id: self.instance_id,
+            version: self.version,
+            rank: self.rank,
+            grounded: self.grounded,
+            mop: mop::NON_STORING,
+            prf: self.prf,
+            dtsn: self.dtsn,
+            dodag_id: self.dodag_id,
+        };
+        let (off, _) = dio.encode(buf).done()?;
+        let (len, _) = self.config.encode(&mut buf[off..]).done()?;
+        Some(off + len)
+    }
+
+    fn encode_dao(&mut self, now: u32, buf: &mut [u8]) -> Option<usize> {
+        let parent = self.preferred_parent()?;
+        let target = Target::new(self.global_addr()?);
+        let transit = Transit {
+            external: false,
+            path_control: 0,
+            path_sequence: self.path_sequence,
+            path_lifetime: self.config.default_lifetime,
+            parent: Some(self.parent_global_addr(&parent)),
+        };
+
+        self.dao_sequence = lollipop_increment(self.dao_sequence);
+        // Retransmit the DAO until it is acknowledged, and retry later once
+        // the retransmissions are exhausted
+        if self.dao_retransmissions < MAX_DAO_RETRANSMISSIONS {
+            self.dao_retransmissions += 1;
+            self.dao_deadline = Some(now.wrapping_add(DAO_ACK_TIMEOUT_MS));
+        } else {
+            self.dao_retransmissions = 0;
+            let retry = self.route_lifetime_ms() / 4;
+            self.dao_deadline = Some(now.wrapping_add(retry));
+        }
+
+        let dao = Dao {
+            instance_id: self.instance_id,
+            ack_requested: true,
+            sequence: self.dao_sequence,
+            dodag_id: Some(self.dodag_id),
+        };
+        let (off, _) = dao.encode(buf).done()?;
+        let (len, _) = target.encode(&mut buf[off..]).done()?;
+        let off = off + len;
+        let (len, _) = transit.encode(&mut buf[off..]).done()?;
+        Some(off + len)
+    }
+
+    /// Computes the source route from the root to `dst`, writing the global
+    /// addresses of the hops (ending with `dst`) to `hops`. Only the root
+    /// stores routes.
+    ///
+    /// # Return Value
+    ///
+    /// The number of hops, or `None` if there is no route to `dst`.
+    pub fn source_route(&self, now: u32, dst: &IPAddr, hops: &mut [IPAddr]) -> Option<usize> {
+        if self.role != RplRole::Root {
+            return None;
+        }
+        // Walk up the parents from the destination to the root, then
+        // reverse the path
+        let mut count = 0;
+        let mut node = *dst;
+        while node != self.dodag_id {
+            let route = self
+                .routes
+                .iter()
+                .flatten()
+                .find(|r| r.target == node && !reached(now, r.expires))?;
+            if count == hops.len() {
+                // The route is too long, or contains a loop
+                return None;
+            }
+            hops[count] = node;
+            count += 1;
+            node = route.parent;
+        }
+        hops[..count].reverse();
+        Some(count)
+    }
+
+    /// Returns the link-local address of the neighbor to which a packet to
+    /// `dst` has to be sent, or `None` if there is no route to `dst`.
+    /// Link-local destinations are considered on-link, while the root
+    /// routes through the first hop of the source route and other nodes
+    /// through their preferred parent.
+    pub fn next_hop(&self, now: u32, dst: &IPAddr) -> Option<IPAddr> {
+        if dst.is_multicast() {
+            return None;
+        }
+        if dst.is_unicast_link_local() {
+            return Some(*dst);
+        }
+        if self.role == RplRole::Root {
+            let mut hops = [IPAddr::new(); MAX_ROUTE_HOPS];
+            return self
+                .source_route(now, dst, &mut hops)
+                .map(|_| link_local_from(&hops[0]));
+        }
+        if self.joined {
+            self.preferred_parent().map(|parent| parent.addr)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! This file contains the types and functions used to encode and decode the
+//! RPL (RFC 6550) control messages and their options.
+//!
+//! RPL control messages are ICMPv6 messages of type 155, where the ICMPv6
+//! code identifies the message (DIS, DIO, DAO or DAO-ACK). Each message
+//! consists of a fixed message base followed by a sequence of options:
+//!
+//! ```txt
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Type (155)  |     Code      |           Checksum            |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                        Message Base ...                       |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                          Options ...                          |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! The encoders and decoders in this file operate on the message body (the
+//! message base and the options), which starts right after the checksum.
+//! When the message is sent through the IPv6 layer, the first four bytes of
+//! the body are carried in the `Type155` options of the `ICMP6Header`.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// ICMPv6 type of RPL control messages.
+pub const ICMP_TYPE_RPL: u8 = 155;
+
+/// The all-RPL-nodes link-local multicast address (ff02::1a).
+pub const RPL_ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1a]);
+
+/// A rank that is larger than the rank of any node, used to advertise that
+/// a node has no route to the root.
+pub const INFINITE_RANK: u16 = 0xffff;
+
+pub const DIS_BASE_LEN: usize = 2;
+pub const DIO_BASE_LEN: usize = 24;
+pub const DAO_BASE_LEN: usize = 4;
+pub const DAO_ACK_BASE_LEN: usize = 4;
+
+/// ICMPv6 codes of the RPL control messages.
+pub mod rpl_code {
+    pub const DIS: u8 = 0x00;
+    pub const DIO: u8 = 0x01;
+    pub const DAO: u8 = 0x02;
+    pub const DAO_ACK: u8 = 0x03;
+}
+
+/// RPL control message option types.
+pub mod rpl_option {
+    pub const PAD1: u8 = 0x00;
+    pub const PADN: u8 = 0x01;
+    pub const DAG_METRIC_CONTAINER: u8 = 0x02;
+    pub const ROUTE_INFO: u8 = 0x03;
+    pub const DODAG_CONFIG: u8 = 0x04;
+    pub const RPL_TARGET: u8 = 0x05;
+    pub const TRANSIT_INFO: u8 = 0x06;
+    pub const SOLICITED_INFO: u8 = 0x07;
+    pub const PREFIX_INFO: u8 = 0x08;
+}
+
+/// Modes of Operation advertised in DIOs.
+pub mod mop {
+    pub const NO_DOWNWARD_ROUTES: u8 = 0;
+    pub const NON_STORING: u8 = 1;
+    pub const STORING_NO_MULTICAST: u8 = 2;
+    pub const STORING_MULTICAST: u8 = 3;
+}
+
+/// Objective Code Points identifying the objective function of a DODAG.
+pub mod ocp {
+    /// Objective Function Zero (RFC 6552)
+    pub const OF0: u16 = 0;
+    /// Minimum Rank with Hysteresis Objective Function (RFC 6719)
+    pub const MRHOF: u16 = 1;
+}
+
+/// DAO-ACK status indicating that the DAO was accepted.
+pub const DAO_ACK_ACCEPTED: u8 = 0;
+/// DAO-ACK status indicating that the root has no room for the route.
+pub const DAO_ACK_NO_ROOM: u8 = 128;
+
+/// A DODAG Information Object, advertising a DODAG and the rank of the
+/// sender within it.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Dio {
+    pub instance_id: u8,
+    pub version: u8,
+    pub rank: u16,
+    pub grounded: bool,
+    pub mop: u8,
+    pub prf: u8,
+    pub dtsn: u8,
+    pub dodag_id: IPAddr,
+}
+
+impl Dio {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, DIO_BASE_LEN);
+
+        let flags = (if self.grounded { 0x80 } else { 0 }) | (self.mop & 0x7) << 3 | self.prf & 0x7;
+        let mut off = enc_consume!(buf, 0; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, self.version);
+        off = enc_consume!(buf, off; encode_u16, self.rank);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.dtsn);
+        // Flags and reserved bytes
+        off = enc_consume!(buf, off; encode_u16, 0);
+        off = enc_consume!(buf, off; encode_bytes, &self.dodag_id.0);
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<Dio> {
+        stream_len_cond!(buf, DIO_BASE_LEN);
+
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, version) = dec_try!(buf, off; decode_u8);
+        let (off, rank) = dec_try!(buf, off; decode_u16);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, dtsn) = dec_try!(buf, off; decode_u8);
+        let off = off + 2;
+        let mut dodag_id = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut dodag_id.0);
+        stream_done!(
+            off,
+            Dio {
+                instance_id: instance_id,
+                version: version,
+                rank: rank,
+                grounded: flags & 0x80 != 0,
+                mop: (flags >> 3) & 0x7,
+                prf: flags & 0x7,
+                dtsn: dtsn,
+                dodag_id: dodag_id,
+            }
+        );
+    }
+}
+
+/// Encodes the base of a DODAG Information Solicitation, which only
+/// consists of flags and a reserved field.
+pub fn encode_dis(buf: &mut [u8]) -> SResult<usize> {
+    let off = enc_consume!(buf, 0; encode_u16, 0);
+    stream_done!(off, off);
+}
+
+/// A Destination Advertisement Object, sent by a node to the root (in
+/// non-storing mode) to advertise the targets reachable through it.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Dao {
+    pub instance_id: u8,
+    /// Whether the recipient is expected to send a DAO-ACK
+    pub ack_requested: bool,
+    pub sequence: u8,
+    pub dodag_id: Option<IPAddr>,
+}
+
+impl Dao {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        let flags = (if self.ack_requested { 0x80 } else { 0 })
+            | (if self.dodag_id.is_some() { 0x40 } else { 0 });
+        let mut off = enc_consume!(buf, 0; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.sequence);
+        if let Some(dodag_id) = self.dodag_id {
+            off = enc_consume!(buf, off; encode_bytes, &dodag_id.0);
+        }
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<Dao> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, _reserved) = dec_try!(buf, off; decode_u8);
+        let (off, sequence) = dec_try!(buf, off; decode_u8);
+        let (off, dodag_id) = dec_try!(buf, off; decode_dodag_id, flags & 0x40 != 0);
+        stream_done!(
+            off,
+            Dao {
+                instance_id: instance_id,
+                ack_requested: flags & 0x80 != 0,
+                sequence: sequence,
+                dodag_id: dodag_id,
+            }
+        );
+    }
+}
+
+/// An acknowledgement of a DAO, sent by the root (in non-storing mode).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DaoAck {
+    pub instance_id: u8,
+    pub sequence: u8,
+    pub status: u8,
+    pub dodag_id: Option<IPAddr>,
+}
+
+impl DaoAck {
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        let flags = if self.dodag_id.is_some() { 0x80 } else { 0 };
+        let mut off = enc_consume!(buf, 0; encode_u8, self.instance_id);
+        off = enc_consume!(buf, off; encode_u8, flags);
+        off = enc_consume!(buf, off; encode_u8, self.sequence);
+        off = enc_consume!(buf, off; encode_u8, self.status);
+        if let Some(dodag_id) = self.dodag_id {
+            off = enc_consume!(buf, off; encode_bytes, &dodag_id.0);
+        }
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DaoAck> {
+        let (off, instance_id) = dec_try!(buf; decode_u8);
+        let (off, flags) = dec_try!(buf, off; decode_u8);
+        let (off, sequence) = dec_try!(buf, off; decode_u8);
+        let (off, status) = dec_try!(buf, off; decode_u8);
+        let (off, dodag_id) = dec_try!(buf, off; decode_dodag_id, flags & 0x80 != 0);
+        stream_done!(
+            off,
+            DaoAck {
+                instance_id: instance_id,
+                sequence: sequence,
+                status: status,
+                dodag_id: dodag_id,
+            }
+        );
+    }
+}
+
+fn decode_dodag_id(buf: &[u8], present: bool) -> SResult<Option<IPAddr>> {
+    if !present {
+        stream_done!(0, None);
+    }
+    let mut dodag_id = IPAddr::new();
+    let off = dec_consume!(buf; decode_bytes, &mut dodag_id.0);
+    stream_done!(off, Some(dodag_id));
+}
+
+/// The DODAG Configuration option, carrying the parameters shared by all
+/// nodes of a DODAG.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DodagConfig {
+    pub path_control_size: u8,
+    /// Number of times the Trickle interval of DIOs may double
+    pub dio_interval_doublings: u8,
+    /// The minimum Trickle interval of DIOs is `2 ** dio_interval_min` ms
+    pub dio_interval_min: u8,
+    /// Trickle redundancy constant of DIOs
+    pub dio_redundancy: u8,
+    pub max_rank_increase: u16,
+    pub min_hop_rank_increase: u16,
+    pub ocp: u16,
+    /// Lifetime of routes, in units of `lifetime_unit` seconds
+    pub default_lifetime: u8,
+    pub lifetime_unit: u16,
+}
+
+pub const DODAG_CONFIG_LEN: usize = 14;
+
+impl Default for DodagConfig {
+    fn default() -> DodagConfig {
+        DodagConfig {
+            path_control_size: 0,
+            dio_interval_doublings: 8,
+            dio_interval_min: 12,
+            dio_redundancy: 10,
+            max_rank_increase: 7 * 256,
+            min_hop_rank_increase: 256,
+            ocp: ocp::MRHOF,
+            default_lifetime: 30,
+            lifetime_unit: 60,
+        }
+    }
+}
+
+impl DodagConfig {
+    /// Encodes the option, including its type and length.
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, 2 + DODAG_CONFIG_LEN);
+
+        let mut off = enc_consume!(buf, 0; encode_u8, rpl_option::DODAG_CONFIG);
+        off = enc_consume!(buf, off; encode_u8, DODAG_CONFIG_LEN as u8);
+        off = enc_consume!(buf, off; encode_u8, self.path_control_size & 0x7);
+        off = enc_consume!(buf, off; encode_u8, self.dio_interval_doublings);
+        off = enc_consume!(buf, off; encode_u8, self.dio_interval_min);
+        off = enc_consume!(buf, off; encode_u8, self.dio_redundancy);
+        off = enc_consume!(buf, off; encode_u16, self.max_rank_increase);
+        off = enc_consume!(buf, off; encode_u16, self.min_hop_rank_increase);
+        off = enc_consume!(buf, off; encode_u16, self.ocp);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.default_lifetime);
+        off = enc_consume!(buf, off; encode_u16, self.lifetime_unit);
+        stream_done!(off, off);
+    }
+
+    /// Decodes the value of the option.
+    fn decode(buf: &[u8]) -> SResult<DodagConfig> {
+        stream_len_cond!(buf, DODAG_CONFIG_LEN);
+
+        let (off, flags) = dec_try!(buf; decode_u8);
+        let (off, dio_interval_doublings) = dec_try!(buf, off; decode_u8);
+        let (off, dio_interval_min) = dec_try!(buf, off; decode_u8);
+        let (off, dio_redundancy) = dec_try!(buf, off; decode_u8);
+        let (off, max_rank_increase) = dec_try!(buf, off; decode_u16);
+        let (off, min_hop_rank_increase) = dec_try!(buf, off; decode_u16);
+        let (off, ocp) = dec_try!(buf, off; decode_u16);
+        let (off, _reserved) = dec_try!(buf, off; decode_u8);
+        let (off, default_lifetime) = dec_try!(buf, off; decode_u8);
+        let (off, lifetime_unit) = dec_try!(buf, off; decode_u16);
+        // A zero MinHopRankIncrease would let a node pick the rank of its
+        // parent, and DIOIntervalMin must keep the Trickle interval within
+        // 32 bits
+        stream_cond!(min_hop_rank_increase != 0);
+        stream_cond!((dio_interval_min as u32 + dio_interval_doublings as u32) < 32);
+        stream_done!(
+            off,
+            DodagConfig {
+                path_control_size: flags & 0x7,
+                dio_interval_doublings: dio_interval_doublings,
+                dio_interval_min: dio_interval_min,
+                dio_redundancy: dio_redundancy,
+                max_rank_increase: max_rank_increase,
+                min_hop_rank_increase: min_hop_rank_increase,
+                ocp: ocp,
+                default_lifetime: default_lifetime,
+                lifetime_unit: lifetime_unit,
+            }
+        );
+    }
+
+    /// Lifetime of routes in seconds.
+    pub fn route_lifetime_s(&self) -> u32 {
+        self.default_lifetime as u32 * self.lifetime_unit as u32
+    }
+}
+
+/// The RPL Target option, identifying an address (or prefix) reachable
+/// through the sender of a DAO.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Target {
+    pub prefix_len: u8,
+    pub prefix: IPAddr,
+}
+
+impl Target {
+    pub fn new(addr: IPAddr) -> Target {
+        Target {
+            prefix_len: 128,
+            prefix: addr,
+        }
+    }
+
+    /// Encodes the option, including its type and length.
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        let prefix_bytes = (self.prefix_len as usize + 7) / 8;
+        let mut off = enc_consume!(buf, 0; encode_u8, rpl_option::RPL_TARGET);
+        off = enc_consume!(buf, off; encode_u8, 2 + prefix_bytes as u8);
+        off = enc_consume!(buf, off; encode_u8, 0);
+        off = enc_consume!(buf, off; encode_u8, self.prefix_len);
+        off = enc_consume!(buf, off; encode_bytes, &self.prefix.0[..prefix_bytes]);
+        stream_done!(off, off);
+    }
+
+    /// Decodes the value of the option.
+    fn decode(buf: &[u8]) -> SResult<Target> {
+        let (off, _flags) = dec_try!(buf; decode_u8);
+        let (off, prefix_len) = dec_try!(buf, off; decode_u8);
+        stream_cond!(prefix_len <= 128);
+        let prefix_bytes = (prefix_len as usize + 7) / 8;
+        let mut prefix = IPAddr::new();
+        let off = dec_consume!(buf, off; decode_bytes, &mut prefix.0[..prefix_bytes]);
+        stream_done!(
+            off,
+            Target {
+                prefix_len: prefix_len,
+                prefix: prefix,
+            }
+        );
+    }
+}
+
+/// The Transit Information option, which (in non-storing mode) carries the
+/// address of a parent of the targets preceding it in a DAO.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Transit {
+    pub external: bool,
+    pub path_control: u8,
+    pub path_sequence: u8,
+    /// Lifetime of the route, in units of the DODAG lifetime unit. Zero
+    /// removes the route.
+    pub path_lifetime: u8,
+    pub parent: Option<IPAddr>,
+}
+
+impl Transit {
+    /// Encodes the option, including its type and length.
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        let len = if self.parent.is_some() { 20 } else { 4 };
+        let mut off = enc_consume!(buf, 0; encode_u8, rpl_option::TRANSIT_INFO);
+        off = enc_consume!(buf, off; encode_u8, len);
+        off = enc_consume!(buf, off; encode_u8, if self.external { 0x80 } else { 0 });
+        off = enc_consume!(buf, off; encode_u8, self.path_control);
+        off = enc_consume!(buf, off; encode_u8, self.path_sequence);
+        off = enc_consume!(buf, off; encode_u8, self.path_lifetime);
+        if let Some(parent) = self.parent {
+            off = enc_consume!(buf, off; encode_bytes, &parent.0);
+        }
+        stream_done!(off, off);
+    }
+
+    /// Decodes the value of the option.
+    fn decode(buf: &[u8]) -> SResult<Transit> {
+        let (off, flags) = dec_try!(buf; decode_u8);
+        let (off, path_control) = dec_try!(buf, off; decode_u8);
+        let (off, path_sequence) = dec_try!(buf, off; decode_u8);
+        let (off, path_lifetime) = dec_try!(buf, off; decode_u8);
+        let (off, parent) = dec_try!(buf, off; decode_dodag_id, buf.len() >= off + 16);
+        stream_done!(
+            off,
+            Transit {
+                external: flags & 0x80 != 0,
+                path_control: path_control,
+                path_sequence: path_sequence,
+                path_lifetime: path_lifetime,
+                parent: parent,
+            }
+        );
+    }
+}
+
+/// Encodes a PadN option (or a Pad1 option if `len` is 1) of `len` bytes.
+pub fn encode_padding(buf: &mut [u8], len: usize) -> SResult<usize> {
+    stream_len_cond!(buf, len);
+    match len {
+        0 => {}
+        1 => buf[0] = rpl_option::PAD1,
+        _ => {
+            buf[0] = rpl_option::PADN;
+            buf[1] = (len - 2) as u8;
+            for byte in buf[2..len].iter_mut() {
+                *byte = 0;
+            }
+        }
+    }
+    stream_done!(len, len);
+}
+
+/// The RPL options this implementation understands. Other options are
+/// skipped when decoding.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RplOption {
+    DodagConfig(DodagConfig),
+    Target(Target),
+    Transit(Transit),
+    Other(u8),
+}
+
+impl RplOption {
+    /// Decodes the option at the start of `buf`, returning `None` for
+    /// padding.
+    fn decode(buf: &[u8]) -> SResult<Option<RplOption>> {
+        let (off, option_type) = dec_try!(buf; decode_u8);
+        if option_type == rpl_option::PAD1 {
+            stream_done!(off, None);
+        }
+        let (off, len) = dec_try!(buf, off; decode_u8);
+        let end = off + len as usize;
+        stream_len_cond!(buf, end);
+        let value = &buf[off..end];
+        let option = match option_type {
+            rpl_option::PADN => None,
+            rpl_option::DODAG_CONFIG => Some(RplOption::DodagConfig(
+                dec_try!(value; DodagConfig::decode).1,
+            )),
+            rpl_option::RPL_TARGET => Some(RplOption::Target(dec_try!(value; Target::decode).1)),
+            rpl_option::TRANSIT_INFO => {
+                Some(RplOption::Transit(dec_try!(value; Transit::decode).1))
+            }
+            _ => Some(RplOption::Other(option_type)),
+        };
+        stream_done!(end, option);
+    }
+}
+
+/// An iterator over the options of an RPL control message. Options must be
+/// validated with `validate_options` first, as malformed options end the
+/// iteration.
+pub struct RplOptions<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> RplOptions<'a> {
+    pub fn new(buf: &'a [u8]) -> RplOptions<'a> {
+        RplOptions { buf: buf }
+    }
+}
+
+impl<'a> Iterator for RplOptions<'a> {
+    type Item = RplOption;
+
+    fn next(&mut self) -> Option<RplOption> {
+        while !self.buf.is_empty() {
+            let (off, option) = RplOption::decode(self.buf).done()?;
+            self.buf = &self.buf[off..];
+            if option.is_some() {
+                return option;
+            }
+        }
+        None
+    }
+}
+
+/// Checks that the options of an RPL control message are well formed.
+pub fn validate_options(mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        match RplOption::decode(buf).done() {
+            Some((off, _)) => buf = &buf[off..],
+            None => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,21 @@
+//! An implementation of the Routing Protocol for Low-Power and Lossy
+//! Networks (RPL, RFC 6550) in non-storing mode, which routes packets in
+//! multi-hop 6LoWPAN meshes.
+//!
+//! - `message` encodes and decodes the RPL control messages and options.
+//! - `trickle` implements the Trickle timer scheduling DIOs.
+//! - `objective` implements the OF0 and MRHOF objective functions.
+//! - `dodag` contains the protocol state machine of a node, independent of
+//!   the networking stack.
+//! - `routing_header` implements the Source Routing Header (RFC 6554).
+//! - `router` runs a node on top of the IPv6 layer.
+
+pub mod dodag;
+pub mod message;
+pub mod objective;
+pub mod router;
+pub mod routing_header;
+pub mod trickle;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,161 @@
+//! This file implements the RPL objective functions, which translate the
+//! rank advertised by a candidate parent and the quality of the link to it
+//! into the rank of the node, and select the preferred parent.
+//!
+//! Two objective functions are supported, selected by the Objective Code
+//! Point of the DODAG Configuration option:
+//!
+//! - Objective Function Zero (OF0, RFC 6552), which increases the rank by a
+//!   step between 1 and 9 times MinHopRankIncrease depending on the link
+//!   quality, and always picks the parent yielding the lowest rank.
+//! - The Minimum Rank with Hysteresis Objective Function (MRHOF, RFC 6719)
+//!   using the ETX metric, which minimizes the path cost and only switches
+//!   parents if the path cost improves by a threshold.
+//!
+//! Link quality is measured as the expected transmission count (ETX) in
+//! units of `1 / ETX_DIVISOR` transmissions.
+
+use crate::net::rpl::dodag::Parent;
+use crate::net::rpl::message::{ocp, DodagConfig, INFINITE_RANK};
+
+/// Scale of the ETX link metric.
+pub const ETX_DIVISOR: u16 = 128;
+
+/// Links with a larger ETX are not acceptable to MRHOF.
+pub const MAX_LINK_METRIC: u16 = 4 * ETX_DIVISOR;
+/// Paths with a larger cost are not acceptable to MRHOF.
+pub const MAX_PATH_COST: u32 = 32768;
+/// MRHOF only switches to a parent whose path cost is lower by this amount
+/// than the path cost through the current preferred parent.
+pub const PARENT_SWITCH_THRESHOLD: u32 = 192;
+
+pub trait ObjectiveFunction {
+    /// The Objective Code Point of the objective function.
+    fn ocp(&self) -> u16;
+
+    /// Whether `parent` may be selected as preferred parent.
+    fn acceptable(&self, parent: &Parent, config: &DodagConfig) -> bool;
+
+    /// The rank of the node if `parent` is its preferred parent.
+    fn rank_via(&self, parent: &Parent, config: &DodagConfig) -> u16;
+
+    /// Whether `candidate` should be preferred over `best`, where
+    /// `best_is_preferred` indicates if `best` is the current preferred
+    /// parent.
+    fn prefer(
+        &self,
+        candidate: &Parent,
+        best: &Parent,
+        best_is_preferred: bool,
+        config: &DodagConfig,
+    ) -> bool;
+}
+
+/// Returns the objective function identified by `ocp`, if supported.
+pub fn find(ocp: u16) -> Option<&'static dyn ObjectiveFunction> {
+    match ocp {
+        ocp::OF0 => Some(&Of0),
+        ocp::MRHOF => Some(&Mrhof),
+        _ => None,
+    }
+}
+
+fn saturate_rank(rank: u32) -> u16 {
+    if rank >= INFINITE_RANK as u32 {
+        INFINITE_RANK
+    } else {
+        rank as u16
+    }
+}
+
+/// Objective Function Zero (RFC 6552).
+pub struct Of0;
+
+const OF0_RANK_FACTOR: u32 = 1;
+const OF0_RANK_STRETCH: u32 = 0;
+const OF0_MIN_STEP: u32 = 1;
+const OF0_MAX_STEP: u32 = 9;
+
+impl Of0 {
+    /// Derives the step of rank from the ETX of the link to `parent`.
+    fn step_of_rank(parent: &Parent) -> u32 {
+        let step = (3 * parent.link_metric as u32 / ETX_DIVISOR as u32).saturating_sub(2);
+        step.max(OF0_MIN_STEP).min(OF0_MAX_STEP)
+    }
+}
+
+impl ObjectiveFunction for Of0 {
+    fn ocp(&self) -> u16 {
+        ocp::OF0
+    }
+
+    fn acceptable(&self, parent: &Parent, config: &DodagConfig) -> bool {
+        // OF0 leaves the decision of whether a link is usable to the
+        // implementation. Links are unusable with the same ETX as for MRHOF.
+        parent.link_metric <= MAX_LINK_METRIC && self.rank_via(parent, config) != INFINITE_RANK
+    }
+
+    fn rank_via(&self, parent: &Parent, config: &DodagConfig) -> u16 {
+        if parent.rank == INFINITE_RANK {
+            return INFINITE_RANK;
+        }
+        let increase = (OF0_RANK_FACTOR * Self::step_of_rank(parent) + OF0_RANK_STRETCH)
+            * config.min_hop_rank_increase as u32;
+        saturate_rank(parent.rank as u32 + increase)
+    }
+
+    fn prefer(
+        &self,
+        candidate: &Parent,
+        best: &Parent,
+        _best_is_preferred: bool,
+        config: &DodagConfig,
+    ) -> bool {
+        self.rank_via(candidate, config) < self.rank_via(best, config)
+    }
+}
+
+/// The Minimum Rank with Hysteresis Objective Function (RFC 6719), using
+/// the ETX of links as metric and the advertised rank as path cost.
+pub struct Mrhof;
+
+impl Mrhof {
+    fn path_cost(parent: &Parent) -> u32 {
+        parent.rank as u32 + parent.link_metric as u32
+    }
+}
+
+impl ObjectiveFunction for Mrhof {
+    fn ocp(&self) -> u16 {
+        ocp::MRHOF
+    }
+
+    fn acceptable(&self, parent: &Parent, _config: &DodagConfig) -> bool {
+        parent.rank != INFINITE_RANK
+            && parent.link_metric <= MAX_LINK_METRIC
+            && Self::path_cost(parent) <= MAX_PATH_COST
+    }
+
+    fn rank_via(&self, parent: &Parent, config: &DodagConfig) -> u16 {
+        if parent.rank == INFINITE_RANK {
+            return INFINITE_RANK;
+        }
+        let min_rank = parent.rank as u32 + config.min_hop_rank_increase as u32;
+        saturate_rank(Self::path_cost(parent).max(min_rank))
+    }
+
+    fn prefer(
+        &self,
+        candidate: &Parent,
+        best: &Parent,
+        best_is_preferred: bool,
+        _config: &DodagConfig,
+    ) -> bool {
+        let threshold = if best_is_preferred {
+            PARENT_SWITCH_THRESHOLD
+        } else {
+            0
+        };
+        Self::path_cost(candidate) + threshold < Self::path_cost(best)
+    }
+}
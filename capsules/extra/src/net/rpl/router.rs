@@ -0,0 +1,418 @@
+//! This file contains `RplRouter`, which runs an RPL node (`RplState`) on top
+//! of the IPv6 stack. It sits between the IPv6 layer (e.g. `IP6SendStruct`
+//! and `IP6RecvStruct` over 6LoWPAN) and the transport layer muxes:
+//!
+//! ```txt
+//!   MuxUdpSender / MuxUdpReceiver
+//!               |
+//!           RplRouter  <--- RplState
+//!               |
+//!   IP6SendStruct / IP6RecvStruct
+//! ```
+//!
+//! On the send side, it selects the link-layer next hop of every packet from
+//! the RPL state (the preferred parent for global destinations, or the
+//! neighbor itself for link-local ones) and sets it as gateway of the lower
+//! layer, and sends the RPL control messages. Unicast transmission results are
+//! fed back to the RPL state to estimate the quality of the links to the
+//! parents.
+//!
+//! On the receive side, it consumes the RPL control messages (ICMPv6 type
+//! 155), strips the Source Routing Header (RFC 6554) of packets for which
+//! the node is the final destination, and passes all other packets up.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let rpl_router = static_init!(
+//!     RplRouter<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!         VirtualMuxAlarm<'static, Rtc>>,
+//!     RplRouter::new(
+//!         ip_send,
+//!         rpl_alarm,
+//!         RplState::new(RplRole::Router, link_local_addr),
+//!         rpl_buf,
+//!         rpl_net_cap,
+//!     )
+//! );
+//! ip_send.set_client(rpl_router);
+//! ip_receive.set_client(rpl_router);
+//! rpl_alarm.set_alarm_client(rpl_router);
+//! rpl_router.start(0, IPAddr::new(), DodagConfig::default());
+//! ```
+
+// Known Problems and Remaining Work
+// ---------------------------------
+// The router only acts as a leaf or router in a non-storing DODAG: the IPv6
+// send path cannot insert extension headers or send packets on behalf of
+// another source, so packets with a Source Routing Header that still has
+// segments left are dropped rather than forwarded, and a node running as root
+// can only reach its direct neighbors. Packets from the node's children are
+// also not forwarded upwards for the same reason.
+
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvClient};
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, TransportHeader};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::rpl::dodag::{RplState, RPL_MESSAGE_BUF_LEN};
+use crate::net::rpl::message::{DodagConfig, ICMP_TYPE_RPL};
+use crate::net::rpl::routing_header::SourceRoutingHeader;
+
+use core::cell::Cell;
+
+use kernel::debug;
+use kernel::hil::time::{self, ConvertTicks, Ticks};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
+use kernel::ErrorCode;
+
+/// Length of the buffer used to send RPL control messages.
+pub const RPL_BUF_LEN: usize = RPL_MESSAGE_BUF_LEN;
+
+/// The alarm fires at least this often, keeping the millisecond clock up to
+/// date across wraps of the alarm counter.
+const MAX_ALARM_MS: u32 = 60000;
+
+/// The 802.15.4 broadcast address, used for multicast destinations.
+const BROADCAST_MAC: MacAddress = MacAddress::Short(0xffff);
+
+/// Derives the 802.15.4 address of a neighbor from its link-local address,
+/// reversing `IPAddr::generate_from_mac`.
+pub fn mac_from_link_local(addr: &IPAddr) -> MacAddress {
+    let iid = &addr.0[8..];
+    if iid[..6] == [0x00, 0x00, 0x00, 0xff, 0xfe, 0x00] {
+        MacAddress::Short(u16::from_be_bytes([iid[6], iid[7]]))
+    } else {
+        let mut long_addr = [0; 8];
+        long_addr.copy_from_slice(iid);
+        long_addr[0] ^= 0b00000010;
+        MacAddress::Long(long_addr)
+    }
+}
+
+pub struct RplRouter<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> {
+    ip_sender: &'a S,
+    alarm: &'a A,
+    state: MapCell<RplState>,
+    buf: TakeCell<'static, [u8]>,
+    net_cap: &'static NetworkCapability,
+    src_addr: Cell<IPAddr>,
+
+    // Millisecond clock derived from the alarm
+    last_ticks: Cell<A::Ticks>,
+    now_ms: Cell<u32>,
+
+    busy: Cell<bool>,
+    sending_control: Cell<bool>,
+    // Neighbor of the unicast packet in flight
+    next_hop: OptionalCell<IPAddr>,
+
+    send_client: OptionalCell<&'a dyn IP6SendClient>,
+    recv_client: OptionalCell<&'a dyn IP6RecvClient>,
+}
+
+impl<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> RplRouter<'a, S, A> {
+    pub fn new(
+        ip_sender: &'a S,
+        alarm: &'a A,
+        state: RplState,
+        buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> RplRouter<'a, S, A> {
+        RplRouter {
+            ip_sender: ip_sender,
+            alarm: alarm,
+            state: MapCell::new(state),
+            buf: TakeCell::new(buf),
+            net_cap: net_cap,
+            src_addr: Cell::new(IPAddr::new()),
+            last_ticks: Cell::new(alarm.now()),
+            now_ms: Cell::new(0),
+            busy: Cell::new(false),
+            sending_control: Cell::new(false),
+            next_hop: OptionalCell::empty(),
+            send_client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Starts the RPL node. The arguments are only used if the node is the
+    /// root, see `RplState::start`.
+    pub fn start(&self, instance_id: u8, dodag_id: IPAddr, config: DodagConfig) {
+        let now = self.now_ms();
+        self.state
+            .map(|state| state.start(now, instance_id, dodag_id, config));
+        self.send_control();
+        self.schedule_timer();
+    }
+
+    /// Starts a global repair of the DODAG, if the node is the root.
+    pub fn global_repair(&self) {
+        let now = self.now_ms();
+        self.state.map(|state| state.global_repair(now));
+        self.send_control();
+        self.schedule_timer();
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.state.map_or(false, |state| state.is_joined())
+    }
+
+    pub fn rank(&self) -> Option<u16> {
+        self.state.map(|state| state.rank())
+    }
+
+    /// The global address of the node, once it joined a DODAG.
+    pub fn global_addr(&self) -> Option<IPAddr> {
+        self.state.and_then(|state| state.global_addr())
+    }
+
+    /// Advances the millisecond clock to the current time of the alarm,
+    /// carrying over the ticks that do not make up a whole millisecond.
+    fn now_ms(&self) -> u32 {
+        let elapsed = self.alarm.now().wrapping_sub(self.last_ticks.get());
+        let ms = self.alarm.ticks_to_ms(elapsed);
+        self.last_ticks.set(
+            self.last_ticks
+                .get()
+                .wrapping_add(self.alarm.ticks_from_ms(ms)),
+        );
+        self.now_ms.set(self.now_ms.get().wrapping_add(ms));
+        self.now_ms.get()
+    }
+
+    fn schedule_timer(&self) {
+        let now = self.now_ms();
+        match self.state.and_then(|state| state.next_deadline(now)) {
+            Some(deadline) => {
+                let delay = (deadline.wrapping_sub(now) as i32).max(0) as u32;
+                let delay = delay.min(MAX_ALARM_MS);
+                self.alarm
+                    .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(delay));
+            }
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+
+    /// The source address of packets to `dst`: the link-local address for
+    /// link-local and multicast destinations, and the global address
+    /// otherwise (or the configured address before the node joined).
+    fn source_for(&self, dst: &IPAddr) -> IPAddr {
+        self.state
+            .and_then(|state| {
+                if dst.is_multicast() || dst.is_unicast_link_local() {
+                    Some(state.link_local())
+                } else {
+                    state.global_addr()
+                }
+            })
+            .unwrap_or(self.src_addr.get())
+    }
+
+    /// Selects the link-layer next hop for `dst`, returning the neighbor the
+    /// packet is sent to if it is unicast.
+    fn route(&self, dst: &IPAddr) -> Result<Option<IPAddr>, ErrorCode> {
+        if dst.is_multicast() {
+            self.ip_sender.set_gateway(BROADCAST_MAC);
+            return Ok(None);
+        }
+        let now = self.now_ms();
+        let neighbor = self
+            .state
+            .and_then(|state| state.next_hop(now, dst))
+            .ok_or(ErrorCode::FAIL)?;
+        self.ip_sender.set_gateway(mac_from_link_local(&neighbor));
+        Ok(Some(neighbor))
+    }
+
+    fn send_packet(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableMutableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        let neighbor = self.route(&dst)?;
+        self.ip_sender.set_addr(self.source_for(&dst));
+        // The lower layer may report the completion synchronously
+        self.busy.set(true);
+        neighbor.map(|neighbor| self.next_hop.set(neighbor));
+        let result = self
+            .ip_sender
+            .send_to(dst, transport_header, payload, net_cap);
+        self.ip_sender.set_addr(self.src_addr.get());
+        if result.is_err() {
+            self.busy.set(false);
+            self.next_hop.clear();
+        }
+        result
+    }
+
+    /// Sends the next pending RPL control message, if the lower layer is
+    /// idle.
+    fn send_control(&self) {
+        while !self.busy.get() && self.state.map_or(false, |state| state.has_message()) {
+            let buf = match self.buf.take() {
+                Some(buf) => buf,
+                None => return,
+            };
+            let now = self.now_ms();
+            let message = self
+                .state
+                .and_then(|state| state.next_message(now, buf))
+                .filter(|&(_, _, len)| len >= 4);
+            let (dst, code, len) = match message {
+                Some(message) => message,
+                None => {
+                    self.buf.replace(buf);
+                    continue;
+                }
+            };
+
+            // The first four bytes of the message body are carried in the
+            // ICMPv6 header
+            let mut icmp_header = ICMP6Header::new(ICMP6Type::Type155);
+            icmp_header.set_code(code);
+            icmp_header.set_options(ICMP6HeaderOptions::Type155 {
+                base: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            });
+            let mut payload = LeasableMutableBuffer::new(buf);
+            payload.slice(4..len);
+
+            self.sending_control.set(true);
+            let result = self.send_packet(
+                dst,
+                TransportHeader::ICMP(icmp_header),
+                &payload,
+                self.net_cap,
+            );
+            self.buf.replace(payload.take());
+            if result.is_err() {
+                self.sending_control.set(false);
+                debug!("RPL: failed to send control message: {:?}", result);
+            }
+        }
+    }
+
+    /// Handles a packet carrying a Source Routing Header, passing the
+    /// encapsulated packet up if this node is its final destination.
+    fn receive_routed(&self, mut ip6_header: IP6Header, payload: &[u8]) {
+        let srh = match SourceRoutingHeader::decode(payload).done() {
+            Some((_, srh)) => srh,
+            None => return,
+        };
+        if srh.segments_left != 0 {
+            debug!("RPL: dropping packet with segments left, forwarding unsupported");
+            return;
+        }
+        let inner = &payload[srh.len()..];
+        ip6_header.set_next_header(srh.next_header);
+        ip6_header.set_payload_len(inner.len() as u16);
+        // The checksum of the transport layer could not be verified by the
+        // IPv6 layer, as the Routing header preceded it
+        if ip6_header.check_transport_checksum(inner) == Err(ErrorCode::FAIL) {
+            return;
+        }
+        self.recv_client
+            .map(|client| client.receive(ip6_header, inner));
+    }
+}
+
+impl<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> IP6Sender<'a> for RplRouter<'a, S, A> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.send_client.set(client);
+    }
+
+    fn set_addr(&self, src_addr: IPAddr) {
+        self.src_addr.set(src_addr);
+        self.ip_sender.set_addr(src_addr);
+    }
+
+    /// The gateway is selected by RPL for every packet.
+    fn set_gateway(&self, _gateway: MacAddress) {}
+
+    fn set_header(&mut self, _ip6_header: IP6Header) {}
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableMutableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        if self.busy.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.send_packet(dst, transport_header, payload, net_cap)
+    }
+}
+
+impl<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> IP6SendClient for RplRouter<'a, S, A> {
+    fn send_done(&self, result: Result<(), ErrorCode>) {
+        self.busy.set(false);
+        let now = self.now_ms();
+        self.next_hop.take().map(|neighbor| {
+            let acked = match result {
+                Ok(()) => Some(true),
+                Err(ErrorCode::NOACK) => Some(false),
+                Err(_) => None,
+            };
+            acked.map(|acked| {
+                self.state
+                    .map(|state| state.link_result(now, &neighbor, acked))
+            });
+        });
+        if !self.sending_control.replace(false) {
+            self.send_client.map(|client| client.send_done(result));
+        }
+        self.send_control();
+        self.schedule_timer();
+    }
+}
+
+impl<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> IP6Receiver<'a> for RplRouter<'a, S, A> {
+    fn set_client(&self, client: &'a dyn IP6RecvClient) {
+        self.recv_client.set(client);
+    }
+}
+
+impl<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> IP6RecvClient for RplRouter<'a, S, A> {
+    fn receive(&self, ip6_header: IP6Header, payload: &[u8]) {
+        match ip6_header.get_next_header() {
+            ip6_nh::ICMP if payload.len() >= 8 && payload[0] == ICMP_TYPE_RPL => {
+                let now = self.now_ms();
+                self.state.map(|state| {
+                    state.receive_message(
+                        now,
+                        ip6_header.get_src_addr(),
+                        ip6_header.get_dst_addr(),
+                        payload[1],
+                        &payload[4..],
+                    )
+                });
+                self.send_control();
+                self.schedule_timer();
+            }
+            ip6_nh::ROUTING => self.receive_routed(ip6_header, payload),
+            _ => {
+                self.recv_client
+                    .map(|client| client.receive(ip6_header, payload));
+            }
+        }
+    }
+}
+
+impl<'a, S: IP6Sender<'a>, A: time::Alarm<'a>> time::AlarmClient for RplRouter<'a, S, A> {
+    fn alarm(&self) {
+        let now = self.now_ms();
+        self.state.map(|state| state.timer_fired(now));
+        self.send_control();
+        self.schedule_timer();
+    }
+}
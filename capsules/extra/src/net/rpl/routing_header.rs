@@ -0,0 +1,230 @@
+//! This file implements the IPv6 Routing Header for Source Routes with RPL
+//! (RFC 6554), which the root of a non-storing DODAG uses to route packets
+//! downwards.
+//!
+//! The header carries the remaining hops of the route. Addresses are
+//! compressed by eliding the prefix they share with the IPv6 destination
+//! address: all addresses but the last elide `CmprI` bytes, and the last one
+//! elides `CmprE` bytes.
+//!
+//! ```txt
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |  Next Header  |  Hdr Ext Len  | Routing Type  | Segments Left |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! | CmprI | CmprE |  Pad  |               Reserved                |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |                        Addresses[1..n]                        |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! A node receiving a packet with this header processes it with `process`,
+//! which either hands the packet to the next header (if the node is the
+//! final destination), or swaps the next hop into the IPv6 destination
+//! address so the packet can be forwarded.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::IP6Header;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u8, encode_bytes, encode_u8};
+
+/// Routing type of the Source Routing Header.
+pub const SRH_ROUTING_TYPE: u8 = 3;
+/// Length of the fixed part of the header, preceding the addresses.
+pub const SRH_FIXED_LEN: usize = 8;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SourceRoutingHeader {
+    pub next_header: u8,
+    pub hdr_ext_len: u8,
+    pub segments_left: u8,
+    pub cmpr_i: u8,
+    pub cmpr_e: u8,
+    pub pad: u8,
+}
+
+/// The outcome of processing a Source Routing Header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SrhAction {
+    /// The node is the final destination. The next header, of type
+    /// `next_header`, starts `offset` bytes after the start of the header.
+    Deliver { next_header: u8, offset: usize },
+    /// The IPv6 destination address has been updated to the next hop, and
+    /// the packet must be forwarded to it.
+    Forward,
+    /// The packet is invalid and must be dropped.
+    Drop,
+}
+
+impl SourceRoutingHeader {
+    /// Total length of the header in bytes.
+    pub fn len(&self) -> usize {
+        (self.hdr_ext_len as usize + 1) * 8
+    }
+
+    /// The number of addresses in the header (`n` in RFC 6554).
+    pub fn num_addresses(&self) -> usize {
+        let addresses_len = self.hdr_ext_len as usize * 8 - self.pad as usize;
+        (addresses_len - (16 - self.cmpr_e as usize)) / (16 - self.cmpr_i as usize) + 1
+    }
+
+    /// Returns the offset from the start of the header and the number of
+    /// bytes elided of the `i`th address (1-based).
+    fn address_location(&self, i: usize) -> (usize, usize) {
+        let start = SRH_FIXED_LEN + (i - 1) * (16 - self.cmpr_i as usize);
+        if i == self.num_addresses() {
+            (start, self.cmpr_e as usize)
+        } else {
+            (start, self.cmpr_i as usize)
+        }
+    }
+
+    /// Reconstructs the `i`th address (1-based) of the header in `buf`,
+    /// taking the elided prefix from `dst`.
+    fn address(&self, buf: &[u8], i: usize, dst: &IPAddr) -> IPAddr {
+        let (start, elided) = self.address_location(i);
+        let mut addr = *dst;
+        addr.0[elided..].copy_from_slice(&buf[start..start + 16 - elided]);
+        addr
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<SourceRoutingHeader> {
+        let (off, next_header) = dec_try!(buf; decode_u8);
+        let (off, hdr_ext_len) = dec_try!(buf, off; decode_u8);
+        let (off, routing_type) = dec_try!(buf, off; decode_u8);
+        let (off, segments_left) = dec_try!(buf, off; decode_u8);
+        let (off, cmpr) = dec_try!(buf, off; decode_u8);
+        let (_, pad) = dec_try!(buf, off; decode_u8);
+        stream_cond!(routing_type == SRH_ROUTING_TYPE);
+
+        let srh = SourceRoutingHeader {
+            next_header: next_header,
+            hdr_ext_len: hdr_ext_len,
+            segments_left: segments_left,
+            cmpr_i: cmpr >> 4,
+            cmpr_e: cmpr & 0x0f,
+            pad: pad >> 4,
+        };
+        stream_len_cond!(buf, srh.len());
+        // The addresses must exactly fill the header, less the padding
+        let addresses_len = (hdr_ext_len as usize * 8).checked_sub(srh.pad as usize);
+        let last_len = 16 - srh.cmpr_e as usize;
+        let addresses_len =
+            stream_from_option!(addresses_len.and_then(|l| l.checked_sub(last_len)));
+        stream_cond!(addresses_len % (16 - srh.cmpr_i as usize) == 0);
+        stream_done!(srh.len(), srh);
+    }
+
+    /// Encodes a header routing a packet whose IPv6 destination address is
+    /// `dst` (the first hop) through `addresses`, the last of which is the
+    /// final destination.
+    pub fn encode(
+        buf: &mut [u8],
+        next_header: u8,
+        dst: &IPAddr,
+        addresses: &[IPAddr],
+    ) -> SResult<usize> {
+        stream_cond!(!addresses.is_empty());
+        let n = addresses.len();
+        let cmpr_i = addresses[..n - 1]
+            .iter()
+            .map(|addr| common_prefix_len(addr, dst))
+            .min()
+            .unwrap_or(0);
+        let cmpr_e = common_prefix_len(&addresses[n - 1], dst);
+        let addresses_len = (n - 1) * (16 - cmpr_i) + (16 - cmpr_e);
+        let pad = (8 - addresses_len % 8) % 8;
+        let hdr_ext_len = (addresses_len + pad) / 8;
+        stream_cond!(hdr_ext_len <= u8::MAX as usize && n <= u8::MAX as usize);
+        stream_len_cond!(buf, SRH_FIXED_LEN + addresses_len + pad);
+
+        let mut off = enc_consume!(buf, 0; encode_u8, next_header);
+        off = enc_consume!(buf, off; encode_u8, hdr_ext_len as u8);
+        off = enc_consume!(buf, off; encode_u8, SRH_ROUTING_TYPE);
+        off = enc_consume!(buf, off; encode_u8, n as u8);
+        off = enc_consume!(buf, off; encode_u8, (cmpr_i << 4 | cmpr_e) as u8);
+        off = enc_consume!(buf, off; encode_bytes, &[(pad << 4) as u8, 0, 0]);
+        for addr in addresses[..n - 1].iter() {
+            off = enc_consume!(buf, off; encode_bytes, &addr.0[cmpr_i..]);
+        }
+        off = enc_consume!(buf, off; encode_bytes, &addresses[n - 1].0[cmpr_e..]);
+        for byte in buf[off..off + pad].iter_mut() {
+            *byte = 0;
+        }
+        stream_done!(off + pad, off + pad);
+    }
+}
+
+/// Number of leading bytes shared by `a` and `b`, at most 15 (the largest
+/// number of bytes that can be elided).
+fn common_prefix_len(a: &IPAddr, b: &IPAddr) -> usize {
+    a.0.iter()
+        .zip(b.0.iter())
+        .take(15)
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Processes the Source Routing Header at the start of `buf`, following
+/// section 4.2 of RFC 6554. If the packet must be forwarded, the header and
+/// the destination address and hop limit of `ip6_header` are updated in
+/// place.
+///
+/// # Arguments
+///
+/// `ip6_header` - The IPv6 header of the received packet
+/// `buf` - The packet, starting at the routing header
+/// `local_addrs` - The addresses assigned to the receiving node
+pub fn process(ip6_header: &mut IP6Header, buf: &mut [u8], local_addrs: &[IPAddr]) -> SrhAction {
+    let srh = match SourceRoutingHeader::decode(buf).done() {
+        Some((_, srh)) => srh,
+        None => return SrhAction::Drop,
+    };
+    if srh.segments_left == 0 {
+        return SrhAction::Deliver {
+            next_header: srh.next_header,
+            offset: srh.len(),
+        };
+    }
+
+    let n = srh.num_addresses();
+    if srh.segments_left as usize > n {
+        return SrhAction::Drop;
+    }
+    let dst = ip6_header.get_dst_addr();
+    if dst.is_multicast() {
+        return SrhAction::Drop;
+    }
+
+    // Addresses assigned to this node separated by other addresses indicate
+    // a routing loop
+    let mut seen_local = false;
+    let mut left_local = false;
+    for i in 1..=n {
+        let addr = srh.address(buf, i, &dst);
+        if local_addrs.contains(&addr) {
+            if left_local {
+                return SrhAction::Drop;
+            }
+            seen_local = true;
+        } else if seen_local {
+            left_local = true;
+        }
+    }
+
+    let segments_left = srh.segments_left - 1;
+    let i = n - segments_left as usize;
+    let next_hop = srh.address(buf, i, &dst);
+    if next_hop.is_multicast() || ip6_header.get_hop_limit() <= 1 {
+        return SrhAction::Drop;
+    }
+
+    // Swap the next hop with the destination address
+    let (start, elided) = srh.address_location(i);
+    buf[3] = segments_left;
+    buf[start..start + 16 - elided].copy_from_slice(&dst.0[elided..]);
+    ip6_header.dst_addr = next_hop;
+    ip6_header.set_hop_limit(ip6_header.get_hop_limit() - 1);
+    SrhAction::Forward
+}
@@ -0,0 +1,616 @@
+//! Tests of the RPL implementation, including simulations of small meshes in
+//! which the nodes exchange their control messages in-process.
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::IP6Header;
+use crate::net::rpl::dodag::Parent;
+use crate::net::rpl::dodag::{link_local_from, RplRole, RplState};
+use crate::net::rpl::dodag::{MAX_ROUTE_HOPS, RPL_MESSAGE_BUF_LEN};
+use crate::net::rpl::message::{mop, RplOptions, Target, Transit, INFINITE_RANK};
+use crate::net::rpl::message::{ocp, Dao, DaoAck, Dio, DodagConfig, RplOption};
+use crate::net::rpl::objective::{self, ETX_DIVISOR};
+use crate::net::rpl::router::mac_from_link_local;
+use crate::net::rpl::routing_header::{process, SourceRoutingHeader, SrhAction};
+use crate::net::rpl::trickle::Trickle;
+use std::vec::Vec;
+
+const PREFIX: [u8; 8] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0];
+
+/// Simulation step in milliseconds.
+const STEP_MS: u32 = 10;
+
+fn link_local(id: u16) -> IPAddr {
+    IPAddr::generate_from_mac(MacAddress::Short(id))
+}
+
+fn global(id: u16) -> IPAddr {
+    let mut addr = link_local(id);
+    addr.0[..8].copy_from_slice(&PREFIX);
+    addr
+}
+
+/// A simulated mesh of nodes with ids `1..=n`, node 1 being the root.
+/// Frames sent over a link are either always received or always lost.
+struct Mesh {
+    nodes: Vec<RplState>,
+    links: Vec<Vec<bool>>,
+    now: u32,
+}
+
+impl Mesh {
+    fn new(roles: &[RplRole]) -> Mesh {
+        let nodes = roles
+            .iter()
+            .enumerate()
+            .map(|(i, &role)| RplState::new(role, link_local(i as u16 + 1)))
+            .collect::<Vec<_>>();
+        let n = nodes.len();
+        Mesh {
+            nodes: nodes,
+            links: vec![vec![false; n]; n],
+            now: 0,
+        }
+    }
+
+    fn state(&self, id: u16) -> &RplState {
+        &self.nodes[id as usize - 1]
+    }
+
+    fn set_link(&mut self, a: u16, b: u16, up: bool) {
+        self.links[a as usize - 1][b as usize - 1] = up;
+        self.links[b as usize - 1][a as usize - 1] = up;
+    }
+
+    fn start(&mut self, config: DodagConfig) {
+        let now = self.now;
+        for node in self.nodes.iter_mut() {
+            node.start(now, 1, global(1), config);
+        }
+        self.flush();
+    }
+
+    fn index_of(&self, addr: &IPAddr) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.link_local() == *addr || node.global_addr() == Some(*addr))
+    }
+
+    /// Transmits a frame from node `from` to the neighbor with link-local
+    /// address `to`, reporting the link result to the sender.
+    fn transmit(&mut self, from: usize, to: &IPAddr) -> Option<usize> {
+        let j = self.index_of(to)?;
+        let received = self.links[from][j];
+        let now = self.now;
+        self.nodes[from].link_result(now, to, received);
+        if received {
+            Some(j)
+        } else {
+            None
+        }
+    }
+
+    /// Routes a packet from node `from` to `dst`, returning the index of
+    /// the node that received it. Packets from the root carry a Source
+    /// Routing Header, while other nodes route through their parents.
+    fn route(&mut self, from: usize, dst: &IPAddr) -> Option<usize> {
+        let now = self.now;
+        if self.nodes[from].role() == RplRole::Root && !dst.is_unicast_link_local() {
+            let mut hops = [IPAddr::new(); MAX_ROUTE_HOPS];
+            let count = self.nodes[from].source_route(now, dst, &mut hops)?;
+            if count > 1 {
+                return self.route_source(from, &hops[..count]);
+            }
+        }
+        let mut at = from;
+        for _ in 0..self.nodes.len() {
+            if self.nodes[at].link_local() == *dst || self.nodes[at].global_addr() == Some(*dst) {
+                return Some(at);
+            }
+            let next_hop = self.nodes[at].next_hop(now, dst)?;
+            at = self.transmit(at, &next_hop)?;
+        }
+        None
+    }
+
+    /// Sends a packet along `hops` with a Source Routing Header, processing
+    /// it at every hop.
+    fn route_source(&mut self, from: usize, hops: &[IPAddr]) -> Option<usize> {
+        let mut ip6_header = IP6Header::new();
+        ip6_header.set_next_header(ip6_nh::ROUTING);
+        ip6_header.set_hop_limit(64);
+        ip6_header.src_addr = self.nodes[from].global_addr()?;
+        ip6_header.dst_addr = hops[0];
+        let mut buf = [0; 128];
+        SourceRoutingHeader::encode(&mut buf, ip6_nh::NO_NEXT, &hops[0], &hops[1..]).done()?;
+
+        let mut at = from;
+        loop {
+            let next_hop = link_local_from(&ip6_header.get_dst_addr());
+            at = self.transmit(at, &next_hop)?;
+            let node = &self.nodes[at];
+            let local_addrs = [node.link_local(), node.global_addr()?];
+            match process(&mut ip6_header, &mut buf, &local_addrs) {
+                SrhAction::Deliver { next_header, .. } => {
+                    assert_eq!(next_header, ip6_nh::NO_NEXT);
+                    assert_eq!(ip6_header.get_dst_addr(), *hops.last().unwrap());
+                    return Some(at);
+                }
+                SrhAction::Forward => {}
+                SrhAction::Drop => return None,
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, dst: IPAddr, code: u8, body: &[u8]) {
+        let now = self.now;
+        let src = if dst.is_multicast() || dst.is_unicast_link_local() {
+            self.nodes[from].link_local()
+        } else {
+            match self.nodes[from].global_addr() {
+                Some(addr) => addr,
+                None => return,
+            }
+        };
+        if dst.is_multicast() {
+            for j in 0..self.nodes.len() {
+                if self.links[from][j] {
+                    self.nodes[j].receive_message(now, src, dst, code, body);
+                }
+            }
+        } else if let Some(j) = self.route(from, &dst) {
+            self.nodes[j].receive_message(now, src, dst, code, body);
+        }
+    }
+
+    /// Sends all pending messages, including the ones triggered by the
+    /// messages sent.
+    fn flush(&mut self) {
+        let mut buf = [0; RPL_MESSAGE_BUF_LEN];
+        loop {
+            let mut sent = false;
+            for i in 0..self.nodes.len() {
+                let now = self.now;
+                while let Some((dst, code, len)) = self.nodes[i].next_message(now, &mut buf) {
+                    self.send(i, dst, code, &buf[..len]);
+                    sent = true;
+                }
+            }
+            if !sent {
+                break;
+            }
+        }
+    }
+
+    fn run(&mut self, ms: u32) {
+        for _ in 0..ms / STEP_MS {
+            self.now = self.now.wrapping_add(STEP_MS);
+            let now = self.now;
+            for node in self.nodes.iter_mut() {
+                node.timer_fired(now);
+            }
+            self.flush();
+        }
+    }
+
+    /// The source route from the root to node `id`, as node ids.
+    fn source_route(&self, id: u16) -> Option<Vec<u16>> {
+        let mut hops = [IPAddr::new(); MAX_ROUTE_HOPS];
+        let count = self.nodes[0].source_route(self.now, &global(id), &mut hops)?;
+        Some(
+            hops[..count]
+                .iter()
+                .map(|hop| self.index_of(hop).unwrap() as u16 + 1)
+                .collect(),
+        )
+    }
+}
+
+fn line_mesh(config: DodagConfig) -> Mesh {
+    let mut mesh = Mesh::new(&[
+        RplRole::Root,
+        RplRole::Router,
+        RplRole::Router,
+        RplRole::Router,
+    ]);
+    mesh.set_link(1, 2, true);
+    mesh.set_link(2, 3, true);
+    mesh.set_link(3, 4, true);
+    mesh.start(config);
+    mesh
+}
+
+/// Root 1, routers 2 and 3 in range of the root, and node 4 in range of
+/// both 2 and 3.
+fn diamond_mesh(config: DodagConfig, role4: RplRole) -> Mesh {
+    let mut mesh = Mesh::new(&[RplRole::Root, RplRole::Router, RplRole::Router, role4]);
+    mesh.set_link(1, 2, true);
+    mesh.set_link(1, 3, true);
+    mesh.set_link(2, 4, true);
+    mesh.set_link(3, 4, true);
+    mesh.start(config);
+    mesh
+}
+
+#[test]
+fn dio_round_trip() {
+    let dio = Dio {
+        instance_id: 7,
+        version: 241,
+        rank: 512,
+        grounded: true,
+        mop: mop::NON_STORING,
+        prf: 3,
+        dtsn: 9,
+        dodag_id: global(1),
+    };
+    let config = DodagConfig {
+        ocp: ocp::OF0,
+        ..DodagConfig::default()
+    };
+    let mut buf = [0; RPL_MESSAGE_BUF_LEN];
+    let (off, _) = dio.encode(&mut buf).done().unwrap();
+    let (len, _) = config.encode(&mut buf[off..]).done().unwrap();
+
+    let (dio_len, decoded) = Dio::decode(&buf[..off + len]).done().unwrap();
+    assert_eq!(dio_len, off);
+    assert_eq!(decoded, dio);
+    let mut options = RplOptions::new(&buf[off..off + len]);
+    assert_eq!(options.next(), Some(RplOption::DodagConfig(config)));
+    assert_eq!(options.next(), None);
+}
+
+#[test]
+fn dao_round_trip() {
+    let dao = Dao {
+        instance_id: 1,
+        ack_requested: true,
+        sequence: 250,
+        dodag_id: Some(global(1)),
+    };
+    let target = Target::new(global(4));
+    let transit = Transit {
+        external: false,
+        path_control: 0,
+        path_sequence: 12,
+        path_lifetime: 30,
+        parent: Some(global(3)),
+    };
+    let mut buf = [0; RPL_MESSAGE_BUF_LEN];
+    let (mut off, _) = dao.encode(&mut buf).done().unwrap();
+    off += target.encode(&mut buf[off..]).done().unwrap().0;
+    off += transit.encode(&mut buf[off..]).done().unwrap().0;
+
+    let (base_len, decoded) = Dao::decode(&buf[..off]).done().unwrap();
+    assert_eq!(decoded, dao);
+    let options = RplOptions::new(&buf[base_len..off]).collect::<Vec<_>>();
+    assert_eq!(
+        options,
+        vec![RplOption::Target(target), RplOption::Transit(transit)]
+    );
+
+    let ack = DaoAck {
+        instance_id: 1,
+        sequence: 250,
+        status: 0,
+        dodag_id: None,
+    };
+    let (len, _) = ack.encode(&mut buf).done().unwrap();
+    assert_eq!(DaoAck::decode(&buf[..len]).done(), Some((len, ack)));
+}
+
+#[test]
+fn truncated_messages_are_rejected() {
+    let dio = Dio {
+        instance_id: 1,
+        version: 240,
+        rank: 256,
+        grounded: true,
+        mop: mop::NON_STORING,
+        prf: 0,
+        dtsn: 240,
+        dodag_id: global(1),
+    };
+    let mut buf = [0; RPL_MESSAGE_BUF_LEN];
+    let (len, _) = dio.encode(&mut buf).done().unwrap();
+    assert!(Dio::decode(&buf[..len - 1]).done().is_none());
+
+    // A DODAG Configuration option whose length exceeds the message
+    let (off, _) = DodagConfig::default().encode(&mut buf).done().unwrap();
+    assert!(RplOptions::new(&buf[..off - 1]).next().is_none());
+}
+
+#[test]
+fn mac_from_link_local_round_trip() {
+    let short = MacAddress::Short(0x1234);
+    assert_eq!(
+        mac_from_link_local(&IPAddr::generate_from_mac(short)),
+        short
+    );
+    let long = MacAddress::Long([0x02, 1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(mac_from_link_local(&IPAddr::generate_from_mac(long)), long);
+}
+
+#[test]
+fn trickle_doubles_and_suppresses() {
+    let mut trickle = Trickle::new();
+    // Imin = 16 ms, Imax = 64 ms, k = 1
+    trickle.start(0, 4, 2, 1, 0);
+    let mut transmissions = 0;
+    let mut now = 0;
+    while now < 16 + 32 {
+        now += 1;
+        if trickle.fire(now, 0) {
+            transmissions += 1;
+        }
+    }
+    // One transmission in each of the 16 ms and 32 ms intervals
+    assert_eq!(transmissions, 2);
+
+    // A consistent message heard in the interval suppresses the
+    // transmission
+    trickle.consistent();
+    while now < 16 + 32 + 64 {
+        now += 1;
+        assert!(!trickle.fire(now, 0));
+    }
+
+    // An inconsistency restarts the timer at Imin
+    trickle.inconsistent(now, 0);
+    assert_eq!(trickle.next_deadline(), Some(now + 8));
+}
+
+#[test]
+fn of0_rank_steps() {
+    let config = DodagConfig::default();
+    let of0 = objective::find(ocp::OF0).unwrap();
+    let mut parent = Parent {
+        addr: link_local(1),
+        rank: 256,
+        dtsn: 0,
+        link_metric: ETX_DIVISOR,
+    };
+    // A perfect link increases the rank by a single MinHopRankIncrease
+    assert_eq!(of0.rank_via(&parent, &config), 512);
+    parent.link_metric = 4 * ETX_DIVISOR;
+    // The step of rank is capped at 9
+    assert_eq!(of0.rank_via(&parent, &config), 256 + 9 * 256);
+    // Links failing most transmissions are not usable
+    parent.link_metric = 5 * ETX_DIVISOR;
+    assert!(!of0.acceptable(&parent, &config));
+    parent.link_metric = ETX_DIVISOR;
+    parent.rank = INFINITE_RANK;
+    assert!(!of0.acceptable(&parent, &config));
+}
+
+#[test]
+fn srh_compression_round_trip() {
+    let hops = [global(2), global(3), global(4)];
+    let mut buf = [0; 64];
+    let len = SourceRoutingHeader::encode(&mut buf, ip6_nh::UDP, &hops[0], &hops[1..])
+        .done()
+        .unwrap()
+        .0;
+    // The addresses only differ from the destination in their last byte
+    let (_, srh) = SourceRoutingHeader::decode(&buf[..len]).done().unwrap();
+    assert_eq!(srh.cmpr_i, 15);
+    assert_eq!(srh.cmpr_e, 15);
+    assert_eq!(srh.segments_left, 2);
+    assert_eq!(srh.num_addresses(), 2);
+    assert_eq!(srh.len(), len);
+    assert_eq!(len % 8, 0);
+}
+
+#[test]
+fn srh_process_forwards_and_delivers() {
+    let hops = [global(2), global(3), global(4)];
+    let mut buf = [0; 64];
+    SourceRoutingHeader::encode(&mut buf, ip6_nh::UDP, &hops[0], &hops[1..])
+        .done()
+        .unwrap();
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_hop_limit(64);
+    ip6_header.dst_addr = hops[0];
+
+    for (i, hop) in hops.iter().enumerate() {
+        let local_addrs = [*hop];
+        let action = process(&mut ip6_header, &mut buf, &local_addrs);
+        if i + 1 < hops.len() {
+            assert_eq!(action, SrhAction::Forward);
+            assert_eq!(ip6_header.get_dst_addr(), hops[i + 1]);
+        } else {
+            assert_eq!(
+                action,
+                SrhAction::Deliver {
+                    next_header: ip6_nh::UDP,
+                    offset: 16
+                }
+            );
+        }
+    }
+    assert_eq!(ip6_header.get_hop_limit(), 62);
+}
+
+#[test]
+fn srh_process_drops_invalid() {
+    let mut buf = [0; 64];
+    let mut ip6_header = IP6Header::new();
+    ip6_header.set_hop_limit(64);
+    ip6_header.dst_addr = global(2);
+
+    // Segments left exceeding the number of addresses
+    SourceRoutingHeader::encode(&mut buf, ip6_nh::UDP, &global(2), &[global(3)])
+        .done()
+        .unwrap();
+    buf[3] = 2;
+    assert_eq!(
+        process(&mut ip6_header, &mut buf, &[global(2)]),
+        SrhAction::Drop
+    );
+
+    // The local address appears twice, separated by another address
+    SourceRoutingHeader::encode(
+        &mut buf,
+        ip6_nh::UDP,
+        &global(2),
+        &[global(3), global(4), global(3)],
+    )
+    .done()
+    .unwrap();
+    assert_eq!(
+        process(&mut ip6_header, &mut buf, &[global(3)]),
+        SrhAction::Drop
+    );
+
+    // Hop limit exhausted
+    SourceRoutingHeader::encode(&mut buf, ip6_nh::UDP, &global(2), &[global(3)])
+        .done()
+        .unwrap();
+    ip6_header.set_hop_limit(1);
+    assert_eq!(
+        process(&mut ip6_header, &mut buf, &[global(2)]),
+        SrhAction::Drop
+    );
+
+    // Wrong routing type
+    buf[2] = 0;
+    ip6_header.set_hop_limit(64);
+    assert_eq!(
+        process(&mut ip6_header, &mut buf, &[global(2)]),
+        SrhAction::Drop
+    );
+}
+
+#[test]
+fn line_converges() {
+    let mut mesh = line_mesh(DodagConfig::default());
+    mesh.run(60000);
+
+    let mut rank = 0;
+    for id in 2..=4 {
+        let node = mesh.state(id);
+        assert!(node.is_joined());
+        assert_eq!(node.dodag_id(), Some(global(1)));
+        assert_eq!(node.global_addr(), Some(global(id)));
+        assert_eq!(
+            node.preferred_parent().map(|p| p.addr),
+            Some(link_local(id - 1))
+        );
+        assert!(node.rank() > rank);
+        rank = node.rank();
+        assert!(node.dao_acked());
+    }
+    assert_eq!(mesh.state(1).rank(), 256);
+
+    assert_eq!(mesh.source_route(2), Some(vec![2]));
+    assert_eq!(mesh.source_route(4), Some(vec![2, 3, 4]));
+    assert_eq!(
+        mesh.state(1).next_hop(mesh.now, &global(4)),
+        Some(link_local(2))
+    );
+    assert_eq!(
+        mesh.state(4).next_hop(mesh.now, &global(1)),
+        Some(link_local(3))
+    );
+
+    // Data from the root reaches the end of the line through the source
+    // route, and data from the end of the line reaches the root
+    assert_eq!(mesh.route(0, &global(4)), Some(3));
+    assert_eq!(mesh.route(3, &global(1)), Some(0));
+}
+
+#[test]
+fn leaf_does_not_advertise() {
+    let mut mesh = Mesh::new(&[RplRole::Root, RplRole::Leaf, RplRole::Router]);
+    mesh.set_link(1, 2, true);
+    mesh.set_link(2, 3, true);
+    mesh.start(DodagConfig::default());
+    mesh.run(60000);
+
+    assert!(mesh.state(2).is_joined());
+    assert!(mesh.state(2).dao_acked());
+    assert_eq!(mesh.source_route(2), Some(vec![2]));
+    // Node 3 only hears the leaf, which never sends DIOs
+    assert!(!mesh.state(3).is_joined());
+    assert_eq!(mesh.source_route(3), None);
+}
+
+#[test]
+fn mrhof_switches_to_better_link() {
+    let mut mesh = diamond_mesh(DodagConfig::default(), RplRole::Router);
+    mesh.run(60000);
+
+    let first = mesh.state(4).preferred_parent().unwrap().addr;
+    let (first_id, other_id) = if first == link_local(2) {
+        (2, 3)
+    } else {
+        (3, 2)
+    };
+    assert_eq!(mesh.source_route(4), Some(vec![first_id, 4]));
+
+    // The link to the preferred parent degrades, which node 4 notices when
+    // sending data to the root
+    mesh.set_link(4, first_id, false);
+    for _ in 0..8 {
+        mesh.route(3, &global(1));
+    }
+    assert_eq!(
+        mesh.state(4).preferred_parent().map(|p| p.addr),
+        Some(link_local(other_id))
+    );
+
+    // The new path is advertised to the root
+    mesh.run(30000);
+    assert!(mesh.state(4).dao_acked());
+    assert_eq!(mesh.source_route(4), Some(vec![other_id, 4]));
+    assert_eq!(mesh.route(0, &global(4)), Some(3));
+}
+
+#[test]
+fn of0_recovers_from_parent_loss() {
+    let config = DodagConfig {
+        ocp: ocp::OF0,
+        ..DodagConfig::default()
+    };
+    let mut mesh = diamond_mesh(config, RplRole::Leaf);
+    mesh.run(60000);
+    assert!(mesh.state(4).dao_acked());
+
+    // The parent of node 4 loses its link to the root, and advertises an
+    // infinite rank once it has no parent left
+    let first = mesh.state(4).preferred_parent().unwrap().addr;
+    let first_id = if first == link_local(2) { 2 } else { 3 };
+    let other_id = 5 - first_id;
+    mesh.set_link(1, first_id, false);
+    for _ in 0..8 {
+        mesh.route(first_id as usize - 1, &global(1));
+    }
+    mesh.flush();
+    assert!(!mesh.state(first_id).is_joined());
+    assert_eq!(
+        mesh.state(4).preferred_parent().map(|p| p.addr),
+        Some(link_local(other_id))
+    );
+
+    mesh.run(30000);
+    assert_eq!(mesh.source_route(4), Some(vec![other_id, 4]));
+}
+
+#[test]
+fn global_repair_rebuilds_dodag() {
+    let mut mesh = line_mesh(DodagConfig::default());
+    mesh.run(60000);
+    let version = mesh.state(1).version();
+
+    let now = mesh.now;
+    mesh.nodes[0].global_repair(now);
+    mesh.flush();
+    mesh.run(30000);
+    assert_ne!(mesh.state(1).version(), version);
+    for id in 2..=4 {
+        assert_eq!(mesh.state(id).version(), mesh.state(1).version());
+        assert!(mesh.state(id).is_joined());
+        assert!(mesh.state(id).dao_acked());
+    }
+    assert_eq!(mesh.source_route(4), Some(vec![2, 3, 4]));
+}
@@ -0,0 +1,118 @@
+//! This file implements the Trickle algorithm (RFC 6206), which RPL uses to
+//! schedule the transmission of DIOs.
+//!
+//! Trickle divides time into intervals of length `I`, starting at `Imin` and
+//! doubling up to `Imax` while the network is consistent. In every interval
+//! a node transmits at a random time `t` in `[I/2, I)`, unless it has heard
+//! at least `k` consistent messages during the interval. Inconsistencies
+//! reset `I` to `Imin`, so changes propagate quickly.
+//!
+//! The timer does not own an alarm: it operates on timestamps in
+//! milliseconds and reports its next deadline, so that its user can
+//! multiplex it with other timers on a single alarm.
+
+/// Returns whether the wrapping millisecond timestamp `now` is at or past
+/// `deadline`.
+pub(crate) fn reached(now: u32, deadline: u32) -> bool {
+    now.wrapping_sub(deadline) < (1 << 31)
+}
+
+pub struct Trickle {
+    imin: u32,
+    imax: u32,
+    k: u8,
+    interval: u32,
+    interval_end: u32,
+    transmit_at: Option<u32>,
+    counter: u8,
+    running: bool,
+}
+
+impl Trickle {
+    pub fn new() -> Trickle {
+        Trickle {
+            imin: 0,
+            imax: 0,
+            k: 0,
+            interval: 0,
+            interval_end: 0,
+            transmit_at: None,
+            counter: 0,
+            running: false,
+        }
+    }
+
+    /// Starts the timer with `Imin = 2 ** imin_exp` ms and
+    /// `Imax = Imin * 2 ** doublings`. A redundancy constant `k` of zero
+    /// disables suppression.
+    pub fn start(&mut self, now: u32, imin_exp: u8, doublings: u8, k: u8, rand: u32) {
+        self.imin = 1 << imin_exp;
+        self.imax = self.imin << doublings;
+        self.k = k;
+        self.running = true;
+        self.start_interval(now, self.imin, rand);
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.transmit_at = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Records a consistent transmission heard from a neighbor.
+    pub fn consistent(&mut self) {
+        self.counter = self.counter.saturating_add(1);
+    }
+
+    /// Records an inconsistency, restarting the timer at `Imin` unless the
+    /// current interval already is `Imin`.
+    pub fn inconsistent(&mut self, now: u32, rand: u32) {
+        if self.running && self.interval != self.imin {
+            self.start_interval(now, self.imin, rand);
+        }
+    }
+
+    /// Processes the deadlines reached at `now`, returning whether a
+    /// message should be transmitted.
+    pub fn fire(&mut self, now: u32, rand: u32) -> bool {
+        if !self.running {
+            return false;
+        }
+        let mut transmit = false;
+        if let Some(t) = self.transmit_at {
+            if reached(now, t) {
+                self.transmit_at = None;
+                transmit = self.k == 0 || self.counter < self.k;
+            }
+        }
+        if self.transmit_at.is_none() && reached(now, self.interval_end) {
+            let interval = if self.interval >= self.imax / 2 {
+                self.imax
+            } else {
+                self.interval * 2
+            };
+            self.start_interval(self.interval_end, interval, rand);
+        }
+        transmit
+    }
+
+    /// The next timestamp at which `fire` must be called.
+    pub fn next_deadline(&self) -> Option<u32> {
+        if !self.running {
+            None
+        } else {
+            Some(self.transmit_at.unwrap_or(self.interval_end))
+        }
+    }
+
+    fn start_interval(&mut self, start: u32, interval: u32, rand: u32) {
+        let half = interval / 2;
+        self.interval = interval;
+        self.interval_end = start.wrapping_add(interval);
+        self.transmit_at = Some(start.wrapping_add(half + rand % half.max(1)));
+        self.counter = 0;
+    }
+}
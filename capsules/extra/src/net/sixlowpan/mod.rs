@@ -1,2 +1,5 @@
 pub mod sixlowpan_compression;
 pub mod sixlowpan_state;
+
+#[cfg(test)]
+mod tests;
@@ -695,7 +695,7 @@ pub fn decompress(
                 // UDP length includes UDP header and data in bytes
                 // Below line works bc udp nh must be last nh per 6282
                 let mut udp_length = if is_fragment {
-                    dgram_size - written as u16
+                    dgram_size.checked_sub(written as u16).ok_or(())?
                 } else {
                     buf.len() as u16 - consumed as u16
                 };
@@ -810,7 +810,9 @@ pub fn decompress(
     // including extension headers. This is thus the uncompressed
     // size of the IPv6 packet - the fixed IPv6 header.
     let payload_len = if is_fragment {
-        (dgram_size as usize) - mem::size_of::<IP6Header>()
+        (dgram_size as usize)
+            .checked_sub(mem::size_of::<IP6Header>())
+            .ok_or(())?
     } else {
         written + (buf.len() - consumed) - mem::size_of::<IP6Header>()
     };
@@ -226,7 +226,7 @@
 
 use crate::ieee802154::device::{MacDevice, RxClient};
 use crate::ieee802154::framer::Frame;
-use crate::net::frag_utils::Bitmap;
+use crate::net::frag_utils::{Bitmap, BITMAP_BITS};
 use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
 use crate::net::ipv6::IP6Packet;
 use crate::net::sixlowpan::sixlowpan_compression;
@@ -239,7 +239,7 @@ use core::cmp::min;
 use kernel::collections::list::{List, ListLink, ListNode};
 use kernel::hil::radio;
 use kernel::hil::time;
-use kernel::hil::time::{Frequency, Ticks};
+use kernel::hil::time::{ConvertTicks, Ticks};
 use kernel::utilities::cells::{MapCell, TakeCell};
 use kernel::ErrorCode;
 
@@ -302,6 +302,8 @@ pub trait SixlowpanState<'a> {
     fn get_ctx_store(&self) -> &dyn ContextStore;
     fn add_rx_state(&self, rx_state: &'a RxState<'a>);
     fn set_rx_client(&'a self, client: &'a dyn SixlowpanRxClient);
+    /// Returns the counters of the receive path.
+    fn reassembly_stats(&self) -> ReassemblyStats;
 }
 
 /// Tracks the compression state for a single IPv6 packet.
@@ -635,7 +637,11 @@ pub struct RxState<'a> {
     // Marks if this instance is being used for a packet reassembly or if it is
     // free to use for a new packet.
     busy: Cell<bool>,
-    // The time when packet reassembly started for the current packet.
+    // Whether the first fragment, which is the only compressed one, has been
+    // received.
+    frag1_received: Cell<bool>,
+    // The time when packet reassembly started for the current packet, in
+    // ticks of the `Sixlowpan` alarm.
     start_time: Cell<u32>,
 
     next: ListLink<'a, RxState<'a>>,
@@ -647,6 +653,29 @@ impl<'a> ListNode<'a, RxState<'a>> for RxState<'a> {
     }
 }
 
+/// The outcome of adding a fragment to a reassembly.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum FragmentStatus {
+    Incomplete,
+    Complete,
+    /// The fragment was already received, and was ignored.
+    Duplicate,
+}
+
+/// Errors that abort a reassembly.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum FragmentError {
+    /// The fragment partially overlaps previously received fragments.
+    Overlap,
+    /// The fragment is malformed or inconsistent with the datagram.
+    Invalid,
+}
+
+/// Number of 8-byte blocks covering `len` bytes.
+fn blocks(len: usize) -> usize {
+    (len + 7) / 8
+}
+
 impl<'a> RxState<'a> {
     /// Creates a new `RxState`
     ///
@@ -663,6 +692,7 @@ impl<'a> RxState<'a> {
             dgram_tag: Cell::new(0),
             dgram_size: Cell::new(0),
             busy: Cell::new(false),
+            frag1_received: Cell::new(false),
             start_time: Cell::new(0),
             next: ListLink::empty(),
         }
@@ -682,16 +712,6 @@ impl<'a> RxState<'a> {
             && (self.dst_mac_addr.get() == dst_mac_addr)
     }
 
-    // Checks if a given RxState is free or expired (and thus, can be freed).
-    // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
-        let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
-        if expired {
-            self.end_receive(None, Err(ErrorCode::FAIL));
-        }
-        self.busy.get()
-    }
-
     fn start_receive(
         &self,
         src_mac_addr: MacAddress,
@@ -705,54 +725,120 @@ impl<'a> RxState<'a> {
         self.dgram_tag.set(dgram_tag);
         self.dgram_size.set(dgram_size);
         self.busy.set(true);
+        self.frag1_received.set(false);
         self.bitmap.map(|bitmap| bitmap.clear());
         self.start_time.set(current_tics);
     }
 
     // This function assumes that the payload is a slice starting from the
-    // actual payload (no 802.15.4 headers, no fragmentation headers), and
-    // returns true if the packet is completely reassembled.
+    // actual payload (no 802.15.4 headers, no fragmentation headers).
+    // Duplicated fragments are ignored, while fragments overlapping
+    // previously received fragments abort the reassembly (RFC 4944,
+    // section 5.3).
     fn receive_next_frame(
         &self,
         payload: &[u8],
-        payload_len: usize,
-        dgram_size: u16,
         dgram_offset: usize,
+        is_frag1: bool,
         ctx_store: &dyn ContextStore,
-    ) -> Result<bool, Result<(), ErrorCode>> {
-        let mut packet = self.packet.take().ok_or(Err(ErrorCode::NOMEM))?;
-        let uncompressed_len = if dgram_offset == 0 {
-            let (consumed, written) = sixlowpan_compression::decompress(
-                ctx_store,
-                &payload[0..payload_len as usize],
-                self.src_mac_addr.get(),
-                self.dst_mac_addr.get(),
-                &mut packet,
-                dgram_size,
-                true,
-            )
-            .map_err(|_| Err(ErrorCode::FAIL))?;
-            let remaining = payload_len - consumed;
-            packet[written..written + remaining]
-                .copy_from_slice(&payload[consumed..consumed + remaining]);
-            written + remaining
+    ) -> Result<FragmentStatus, FragmentError> {
+        let dgram_size = self.dgram_size.get() as usize;
+        if is_frag1 && self.frag1_received.get() {
+            return Ok(FragmentStatus::Duplicate);
+        }
+        if !is_frag1 {
+            // Only the last fragment may have a length which is not a
+            // multiple of 8
+            let end = dgram_offset + payload.len();
+            if dgram_offset == 0
+                || payload.is_empty()
+                || end > dgram_size
+                || (end < dgram_size && payload.len() % 8 != 0)
+            {
+                return Err(FragmentError::Invalid);
+            }
+            let (start_block, end_block) = (dgram_offset / 8, blocks(end));
+            if self
+                .bitmap
+                .map_or(false, |bitmap| bitmap.is_set(start_block, end_block))
+            {
+                return Ok(FragmentStatus::Duplicate);
+            }
+        }
+
+        let mut packet = self.packet.take().ok_or(FragmentError::Invalid)?;
+        let result = if is_frag1 {
+            self.receive_first_fragment(&mut packet, payload, dgram_size, ctx_store)
         } else {
-            packet[dgram_offset..dgram_offset + payload_len]
-                .copy_from_slice(&payload[0..payload_len]);
-            payload_len
+            self.receive_subsequent_fragment(&mut packet, payload, dgram_offset)
         };
         self.packet.replace(packet);
+        result?;
+
+        if self
+            .bitmap
+            .map_or(false, |bitmap| bitmap.is_complete(blocks(dgram_size)))
+        {
+            Ok(FragmentStatus::Complete)
+        } else {
+            Ok(FragmentStatus::Incomplete)
+        }
+    }
+
+    fn receive_first_fragment(
+        &self,
+        packet: &mut [u8],
+        payload: &[u8],
+        dgram_size: usize,
+        ctx_store: &dyn ContextStore,
+    ) -> Result<(), FragmentError> {
+        // Only the first fragment is compressed
+        if payload.len() < 2 || !is_lowpan(payload) {
+            return Err(FragmentError::Invalid);
+        }
+        let (consumed, written) = sixlowpan_compression::decompress(
+            ctx_store,
+            payload,
+            self.src_mac_addr.get(),
+            self.dst_mac_addr.get(),
+            packet,
+            dgram_size as u16,
+            true,
+        )
+        .map_err(|_| FragmentError::Invalid)?;
+        let remaining = payload
+            .len()
+            .checked_sub(consumed)
+            .ok_or(FragmentError::Invalid)?;
+        let len = written + remaining;
+        if len > dgram_size || (len < dgram_size && len % 8 != 0) {
+            return Err(FragmentError::Invalid);
+        }
+        if !self
+            .bitmap
+            .map_or(false, |bitmap| bitmap.set_bits(0, blocks(len)))
+        {
+            return Err(FragmentError::Overlap);
+        }
+        packet[written..len].copy_from_slice(&payload[consumed..]);
+        self.frag1_received.set(true);
+        Ok(())
+    }
+
+    fn receive_subsequent_fragment(
+        &self,
+        packet: &mut [u8],
+        payload: &[u8],
+        dgram_offset: usize,
+    ) -> Result<(), FragmentError> {
+        let end = dgram_offset + payload.len();
         if !self.bitmap.map_or(false, |bitmap| {
-            bitmap.set_bits(dgram_offset / 8, (dgram_offset + uncompressed_len) / 8)
+            bitmap.set_bits(dgram_offset / 8, blocks(end))
         }) {
-            // If this fails, we received an overlapping fragment. We can simply
-            // drop the packet in this case.
-            Err(Err(ErrorCode::FAIL))
-        } else {
-            self.bitmap
-                .map(|bitmap| bitmap.is_complete((dgram_size as usize) / 8))
-                .ok_or(Err(ErrorCode::FAIL))
+            return Err(FragmentError::Overlap);
         }
+        packet[dgram_offset..end].copy_from_slice(payload);
+        Ok(())
     }
 
     fn end_receive(
@@ -777,6 +863,45 @@ impl<'a> RxState<'a> {
     }
 }
 
+/// Counters of the receive path of [Sixlowpan](struct.Sixlowpan.html),
+/// which wrap around on overflow.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct ReassemblyStats {
+    /// Packets passed to the client, whether they were fragmented or not
+    pub packets_received: u32,
+    /// Fragmented packets successfully reassembled
+    pub reassembled: u32,
+    /// Reassemblies discarded because not all fragments arrived in time
+    pub timeouts: u32,
+    /// Fragments ignored because they were already received
+    pub duplicates: u32,
+    /// Reassemblies discarded because of overlapping fragments
+    pub overlaps: u32,
+    /// Frames dropped because all reassembly contexts were busy
+    pub no_context: u32,
+    /// Frames dropped because they were malformed
+    pub invalid: u32,
+}
+
+impl ReassemblyStats {
+    /// Number of counters, which are numbered in the order of the fields.
+    pub const COUNT: usize = 7;
+
+    /// Returns the counter with index `idx`.
+    pub fn get(&self, idx: usize) -> Option<u32> {
+        match idx {
+            0 => Some(self.packets_received),
+            1 => Some(self.reassembled),
+            2 => Some(self.timeouts),
+            3 => Some(self.duplicates),
+            4 => Some(self.overlaps),
+            5 => Some(self.no_context),
+            6 => Some(self.invalid),
+            _ => None,
+        }
+    }
+}
+
 /// Sends a receives IPv6 packets via 6loWPAN compression and fragmentation.
 ///
 /// # Initialization
@@ -787,6 +912,10 @@ impl<'a> RxState<'a> {
 /// [RxState](struct.RxState.html)s allow the `Sixlowpan` to receive more
 /// packets concurrently.
 ///
+/// Reassemblies which do not complete within `FRAG_TIMEOUT` seconds are
+/// discarded when the alarm fires, so `Sixlowpan` must be set as the client
+/// of its alarm.
+///
 /// Finally, `set_client` controls the client that will receive transmission
 /// completion and reception callbacks.
 pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
@@ -797,6 +926,7 @@ pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
 
     // Receive state
     rx_states: List<'a, RxState<'a>>,
+    stats: Cell<ReassemblyStats>,
 }
 
 // This function is called after receiving a frame
@@ -809,15 +939,18 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
         let src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
         let dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
 
-        let (rx_state, returncode) = self.receive_frame(
+        self.receive_payload(
             &buf[data_offset..data_offset + data_len],
-            data_len,
             src_mac_addr,
             dst_mac_addr,
         );
-        // Reception completed if rx_state is not None. Note that this can
-        // also occur for some fail states (e.g. dropping an invalid packet)
-        rx_state.map(|state| state.end_receive(self.rx_client.get(), returncode));
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, C: ContextStore> time::AlarmClient for Sixlowpan<'a, A, C> {
+    fn alarm(&self) {
+        self.expire_rx_states();
+        self.schedule_timeout();
     }
 }
 
@@ -850,6 +983,10 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> SixlowpanState<'a> for Sixlowpan<'
     fn set_rx_client(&'a self, client: &'a dyn SixlowpanRxClient) {
         self.rx_client.set(Some(client));
     }
+
+    fn reassembly_stats(&self) -> ReassemblyStats {
+        self.stats.get()
+    }
 }
 
 impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
@@ -859,13 +996,10 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
     ///
     /// * `ctx_store` - Stores IPv6 address nextwork context mappings
     ///
-    /// * `tx_buf` - A buffer used for storing individual fragments of a packet
-    /// in transmission. This buffer must be at least the length of an 802.15.4
-    /// frame.
-    ///
     /// * `clock` - A implementation of `Alarm` used for tracking the timing of
-    /// frame arrival. The clock should be continue running during sleep and
-    /// have an accuracy of at least 60 seconds.
+    /// frame arrival and expiring reassemblies. The clock should be continue
+    /// running during sleep and have an accuracy of at least 60 seconds. The
+    /// alarm must not be shared with other clients.
     pub fn new(ctx_store: C, clock: &'a A) -> Sixlowpan<'a, A, C> {
         Sixlowpan {
             ctx_store: ctx_store,
@@ -874,9 +1008,96 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
             rx_client: Cell::new(None),
 
             rx_states: List::new(),
+            stats: Cell::new(ReassemblyStats::default()),
         }
     }
 
+    /// Processes the payload of a received frame, following the 802.15.4
+    /// header. This is called by the MAC layer through `RxClient`.
+    pub(crate) fn receive_payload(
+        &self,
+        payload: &[u8],
+        src_mac_addr: MacAddress,
+        dst_mac_addr: MacAddress,
+    ) {
+        let (rx_state, returncode) =
+            self.receive_frame(payload, payload.len(), src_mac_addr, dst_mac_addr);
+        // Reception completed if rx_state is not None. Note that this can
+        // also occur for some fail states (e.g. dropping an invalid packet)
+        rx_state.map(|state| {
+            if returncode.is_ok() {
+                self.count(|stats| &mut stats.packets_received);
+            }
+            state.end_receive(self.rx_client.get(), returncode)
+        });
+        self.schedule_timeout();
+    }
+
+    fn count(&self, counter: fn(&mut ReassemblyStats) -> &mut u32) {
+        let mut stats = self.stats.get();
+        let value = counter(&mut stats);
+        *value = value.wrapping_add(1);
+        self.stats.set(stats);
+    }
+
+    fn now(&self) -> u32 {
+        self.clock.now().into_u32()
+    }
+
+    // Ticks elapsed since `state` started reassembling its packet
+    fn elapsed(&self, state: &RxState<'a>) -> A::Ticks {
+        self.clock
+            .now()
+            .wrapping_sub(A::Ticks::from(state.start_time.get()))
+    }
+
+    fn is_expired(&self, state: &RxState<'a>) -> bool {
+        state.busy.get() && self.elapsed(state) >= self.clock.ticks_from_seconds(FRAG_TIMEOUT)
+    }
+
+    // Discards the reassemblies that timed out.
+    fn expire_rx_states(&self) {
+        for state in self.rx_states.iter() {
+            if self.is_expired(state) {
+                state.end_receive(None, Err(ErrorCode::FAIL));
+                self.count(|stats| &mut stats.timeouts);
+            }
+        }
+    }
+
+    // Sets the alarm to the expiration time of the oldest reassembly in
+    // progress.
+    fn schedule_timeout(&self) {
+        let oldest = self
+            .rx_states
+            .iter()
+            .filter(|state| state.busy.get())
+            .max_by_key(|state| self.elapsed(state));
+        match oldest {
+            Some(state) => self.clock.set_alarm(
+                A::Ticks::from(state.start_time.get()),
+                self.clock.ticks_from_seconds(FRAG_TIMEOUT),
+            ),
+            None => {
+                let _ = self.clock.disarm();
+            }
+        }
+    }
+
+    // Returns a free RxState, expiring timed out reassemblies if needed.
+    fn free_rx_state(&self) -> Option<&RxState<'a>> {
+        let free = self.rx_states.iter().find(|state| !state.busy.get());
+        if free.is_some() {
+            return free;
+        }
+        self.expire_rx_states();
+        let free = self.rx_states.iter().find(|state| !state.busy.get());
+        if free.is_none() {
+            self.count(|stats| &mut stats.no_context);
+        }
+        free
+    }
+
     fn receive_frame(
         &self,
         packet: &[u8],
@@ -884,21 +1105,31 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, Result<(), ErrorCode>) {
+        if packet_len == 0 || packet_len > packet.len() {
+            self.count(|stats| &mut stats.invalid);
+            return (None, Err(ErrorCode::SIZE));
+        }
         if is_fragment(packet) {
-            let (is_frag1, dgram_size, dgram_tag, dgram_offset) = get_frag_hdr(&packet[0..5]);
-            let offset_to_payload = if is_frag1 {
+            let offset_to_payload = if packet[0] & lowpan_frag::FRAGN_HDR == lowpan_frag::FRAG1_HDR
+            {
                 lowpan_frag::FRAG1_HDR_SIZE
             } else {
                 lowpan_frag::FRAGN_HDR_SIZE
             };
+            if packet_len < offset_to_payload {
+                self.count(|stats| &mut stats.invalid);
+                return (None, Err(ErrorCode::SIZE));
+            }
+            let (is_frag1, dgram_size, dgram_tag, dgram_offset) =
+                get_frag_hdr(&packet[0..offset_to_payload]);
             self.receive_fragment(
-                &packet[offset_to_payload..],
-                packet_len - offset_to_payload,
+                &packet[offset_to_payload..packet_len],
                 src_mac_addr,
                 dst_mac_addr,
                 dgram_size,
                 dgram_tag,
                 dgram_offset,
+                is_frag1,
             )
         } else {
             self.receive_single_packet(&packet, packet_len, src_mac_addr, dst_mac_addr)
@@ -912,24 +1143,21 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, Result<(), ErrorCode>) {
-        let rx_state = self
-            .rx_states
-            .iter()
-            .find(|state| !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency()));
+        let rx_state = self.free_rx_state();
         rx_state.map_or((None, Err(ErrorCode::NOMEM)), |state| {
             state.start_receive(
                 src_mac_addr,
                 dst_mac_addr,
                 payload_len as u16,
                 0,
-                self.clock.now().into_u32(),
+                self.now(),
             );
             // The packet buffer should *always* be there; in particular,
             // since this state is not busy, it must have the packet buffer.
             // Otherwise, we are in an inconsistent state and can fail.
             let mut packet = state.packet.take().unwrap();
-            if is_lowpan(payload) {
-                let decompressed = sixlowpan_compression::decompress(
+            let result = if is_lowpan(payload) && payload_len >= 2 {
+                sixlowpan_compression::decompress(
                     &self.ctx_store,
                     &payload[0..payload_len as usize],
                     src_mac_addr,
@@ -937,39 +1165,49 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                     &mut packet,
                     0,
                     false,
-                );
-                match decompressed {
-                    Ok((consumed, written)) => {
-                        let remaining = payload_len - consumed;
-                        packet[written..written + remaining]
-                            .copy_from_slice(&payload[consumed..consumed + remaining]);
-                        // Want dgram_size to contain decompressed size of packet
-                        state.dgram_size.set((written + remaining) as u16);
-                    }
-                    Err(_) => {
-                        return (None, Err(ErrorCode::FAIL));
+                )
+                .ok()
+                .and_then(|(consumed, written)| {
+                    let remaining = payload_len - consumed;
+                    if written + remaining > packet.len() {
+                        return None;
                     }
-                }
-            } else {
+                    packet[written..written + remaining]
+                        .copy_from_slice(&payload[consumed..consumed + remaining]);
+                    // Want dgram_size to contain decompressed size of packet
+                    state.dgram_size.set((written + remaining) as u16);
+                    Some(())
+                })
+            } else if payload_len <= packet.len() {
                 packet[0..payload_len].copy_from_slice(&payload[0..payload_len]);
-            }
+                Some(())
+            } else {
+                None
+            };
             state.packet.replace(packet);
-            (Some(state), Ok(()))
+            match result {
+                Some(()) => (Some(state), Ok(())),
+                None => {
+                    state.end_receive(None, Err(ErrorCode::FAIL));
+                    self.count(|stats| &mut stats.invalid);
+                    (None, Err(ErrorCode::FAIL))
+                }
+            }
         })
     }
 
-    // This function returns an Err if an error occurred, returns Ok(Some(RxState))
-    // if the packet has been fully reassembled, or returns Ok(None) if there
-    // are still pending fragments
+    // This function returns the RxState to complete if the packet has been
+    // fully reassembled or the reassembly failed, and None if there are still
+    // pending fragments
     fn receive_fragment(
         &self,
         frag_payload: &[u8],
-        payload_len: usize,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
         dgram_size: u16,
         dgram_tag: u16,
         dgram_offset: usize,
+        is_frag1: bool,
     ) -> (Option<&RxState<'a>>, Result<(), ErrorCode>) {
         // First try to find an rx_state in the middle of assembly
         let mut rx_state = self
@@ -979,9 +1217,16 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
 
         // Else find a free state
         if rx_state.is_none() {
-            rx_state = self.rx_states.iter().find(|state| {
-                !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency())
+            let fits = self.rx_states.iter().next().map_or(false, |state| {
+                state.packet.map_or(false, |packet| {
+                    dgram_size as usize <= packet.len().min(BITMAP_BITS * 8)
+                })
             });
+            if dgram_size == 0 || !fits {
+                self.count(|stats| &mut stats.invalid);
+                return (None, Err(ErrorCode::SIZE));
+            }
+            rx_state = self.free_rx_state();
             // Initialize new state
             rx_state.map(|state| {
                 state.start_receive(
@@ -989,7 +1234,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                     dst_mac_addr,
                     dgram_size,
                     dgram_tag,
-                    self.clock.now().into_u32(),
+                    self.now(),
                 )
             });
             if rx_state.is_none() {
@@ -997,25 +1242,24 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
             }
         }
         rx_state.map_or((None, Err(ErrorCode::NOMEM)), |state| {
-            // Returns true if the full packet is reassembled
-            let res = state.receive_next_frame(
-                frag_payload,
-                payload_len,
-                dgram_size,
-                dgram_offset,
-                &self.ctx_store,
-            );
-            match res {
-                // Some error occurred
-                Err(_) => (Some(state), Err(ErrorCode::FAIL)),
-                Ok(complete) => {
-                    if complete {
-                        // Packet fully reassembled
-                        (Some(state), Ok(()))
-                    } else {
-                        // Packet not fully reassembled
-                        (None, Ok(()))
-                    }
+            match state.receive_next_frame(frag_payload, dgram_offset, is_frag1, &self.ctx_store) {
+                Ok(FragmentStatus::Complete) => {
+                    // Packet fully reassembled
+                    self.count(|stats| &mut stats.reassembled);
+                    (Some(state), Ok(()))
+                }
+                // Packet not fully reassembled
+                Ok(FragmentStatus::Incomplete) => (None, Ok(())),
+                Ok(FragmentStatus::Duplicate) => {
+                    self.count(|stats| &mut stats.duplicates);
+                    (None, Ok(()))
+                }
+                Err(error) => {
+                    self.count(match error {
+                        FragmentError::Overlap => |stats| &mut stats.overlaps,
+                        FragmentError::Invalid => |stats| &mut stats.invalid,
+                    });
+                    (Some(state), Err(ErrorCode::FAIL))
                 }
             }
         })
@@ -0,0 +1,421 @@
+//! Tests of 6LoWPAN reassembly, which feed shuffled, duplicated, truncated
+//! and interleaved fragment streams to the receive path of `Sixlowpan`.
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::sixlowpan::sixlowpan_compression::Context;
+use crate::net::sixlowpan::sixlowpan_state::lowpan_frag;
+use crate::net::sixlowpan::sixlowpan_state::{
+    ReassemblyStats, RxState, Sixlowpan, SixlowpanRxClient, SixlowpanState,
+};
+use crate::testing::alarm::MockAlarm;
+use core::cell::RefCell;
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::ErrorCode;
+use std::boxed::Box;
+use std::vec::Vec;
+
+const SRC: MacAddress = MacAddress::Short(0x1234);
+const DST: MacAddress = MacAddress::Short(0x5678);
+
+/// LOWPAN_IPHC header eliding the traffic class, flow label, hop limit and
+/// both (link-local) addresses, followed by an inline "No Next Header".
+const IPHC_HEADER: [u8; 3] = [0x7a, 0x33, 59];
+const IP6_HEADER_LEN: usize = 40;
+
+/// Size of the IPv6 data carried by each subsequent fragment.
+const FRAGMENT_LEN: usize = 48;
+
+/// Reassembly timeout, in milliseconds.
+const TIMEOUT_MS: u32 = 60_000;
+
+/// Records the packets handed up by `Sixlowpan`.
+struct Receiver {
+    packets: RefCell<Vec<(Vec<u8>, Result<(), ErrorCode>)>>,
+}
+
+impl SixlowpanRxClient for Receiver {
+    fn receive(&self, buf: &[u8], len: usize, result: Result<(), ErrorCode>) {
+        let len = len.min(buf.len());
+        self.packets
+            .borrow_mut()
+            .push((buf[..len].to_vec(), result));
+    }
+}
+
+impl Receiver {
+    fn delivered(&self) -> Vec<Vec<u8>> {
+        self.packets
+            .borrow()
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .map(|(packet, _)| packet.clone())
+            .collect()
+    }
+
+    fn failed(&self) -> usize {
+        self.packets
+            .borrow()
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count()
+    }
+}
+
+type TestSixlowpan = Sixlowpan<'static, MockAlarm<'static, Freq1KHz>, Context>;
+
+struct Harness {
+    alarm: &'static MockAlarm<'static, Freq1KHz>,
+    sixlowpan: &'static TestSixlowpan,
+    receiver: &'static Receiver,
+}
+
+impl Harness {
+    fn new(contexts: usize) -> Harness {
+        let alarm: &'static MockAlarm<Freq1KHz> = Box::leak(Box::new(MockAlarm::new()));
+        let ctx = Context {
+            prefix: [0; 16],
+            prefix_len: 0,
+            id: 0,
+            compress: false,
+        };
+        let sixlowpan: &'static TestSixlowpan = Box::leak(Box::new(Sixlowpan::new(ctx, alarm)));
+        alarm.set_alarm_client(sixlowpan);
+        for _ in 0..contexts {
+            let buf: &'static mut [u8] = Box::leak(vec![0; 1280].into_boxed_slice());
+            sixlowpan.add_rx_state(Box::leak(Box::new(RxState::new(buf))));
+        }
+        let receiver: &'static Receiver = Box::leak(Box::new(Receiver {
+            packets: RefCell::new(Vec::new()),
+        }));
+        sixlowpan.set_rx_client(receiver);
+        Harness {
+            alarm: alarm,
+            sixlowpan: sixlowpan,
+            receiver: receiver,
+        }
+    }
+
+    fn feed(&self, frame: &[u8]) {
+        self.sixlowpan.receive_payload(frame, SRC, DST);
+    }
+
+    fn stats(&self) -> ReassemblyStats {
+        self.sixlowpan.reassembly_stats()
+    }
+}
+
+/// A deterministic xorshift generator, so failures can be reproduced.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.next() as usize % n
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+/// Returns an IPv6 payload of `len` bytes.
+fn payload(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+}
+
+/// Returns the IPv6 packet that `Sixlowpan` reconstructs from an
+/// unfragmented frame carrying `payload`.
+fn expected_packet(payload: &[u8]) -> Vec<u8> {
+    let harness = Harness::new(1);
+    let mut frame = IPHC_HEADER.to_vec();
+    frame.extend_from_slice(payload);
+    harness.feed(&frame);
+    let delivered = harness.receiver.delivered();
+    assert_eq!(delivered.len(), 1);
+    delivered[0].clone()
+}
+
+/// Fragments an IPv6 packet carrying `payload`, returning the FRAG1 frame
+/// followed by the FRAGN frames in order.
+fn fragment(payload: &[u8], tag: u16) -> Vec<Vec<u8>> {
+    let dgram_size = (IP6_HEADER_LEN + payload.len()) as u16;
+    let mut frames = Vec::new();
+
+    let first_end = IP6_HEADER_LEN + FRAGMENT_LEN;
+    let mut frag1 = vec![
+        lowpan_frag::FRAG1_HDR | (dgram_size >> 8) as u8,
+        dgram_size as u8,
+        (tag >> 8) as u8,
+        tag as u8,
+    ];
+    frag1.extend_from_slice(&IPHC_HEADER);
+    frag1.extend_from_slice(&payload[..first_end - IP6_HEADER_LEN]);
+    frames.push(frag1);
+
+    let mut offset = first_end;
+    while offset < dgram_size as usize {
+        let end = (offset + FRAGMENT_LEN).min(dgram_size as usize);
+        let mut fragn = vec![
+            lowpan_frag::FRAGN_HDR | (dgram_size >> 8) as u8,
+            dgram_size as u8,
+            (tag >> 8) as u8,
+            tag as u8,
+            (offset / 8) as u8,
+        ];
+        fragn.extend_from_slice(&payload[offset - IP6_HEADER_LEN..end - IP6_HEADER_LEN]);
+        frames.push(fragn);
+        offset = end;
+    }
+    frames
+}
+
+#[test]
+fn in_order() {
+    let payload = payload(300, 1);
+    let harness = Harness::new(1);
+    for frame in fragment(&payload, 1) {
+        harness.feed(&frame);
+    }
+    assert_eq!(
+        harness.receiver.delivered(),
+        vec![expected_packet(&payload)]
+    );
+    assert_eq!(harness.stats().reassembled, 1);
+    assert_eq!(harness.stats().packets_received, 1);
+    assert!(!harness.alarm.is_armed());
+}
+
+#[test]
+fn shuffled_and_duplicated() {
+    let mut rng = Rng(0x2545_f491);
+    for _ in 0..200 {
+        let payload = payload(FRAGMENT_LEN + rng.below(1190), rng.next() as u8);
+        let expected = expected_packet(&payload);
+        let harness = Harness::new(2);
+        let mut frames = fragment(&payload, 7);
+        rng.shuffle(&mut frames);
+        // Duplicates arrive before the last fragment, so they are part of
+        // the same reassembly
+        let last = frames.pop().unwrap();
+        let duplicates = if frames.is_empty() { 0 } else { rng.below(4) };
+        for _ in 0..duplicates {
+            let duplicate = frames[rng.below(frames.len())].clone();
+            frames.push(duplicate);
+        }
+        rng.shuffle(&mut frames);
+        frames.push(last);
+
+        for frame in frames.iter() {
+            harness.feed(frame);
+        }
+        assert_eq!(harness.receiver.delivered(), vec![expected]);
+        assert_eq!(harness.receiver.failed(), 0);
+        assert_eq!(harness.stats().duplicates as usize, duplicates);
+        assert_eq!(harness.stats().reassembled, 1);
+    }
+}
+
+#[test]
+fn late_duplicate_times_out() {
+    let payload = payload(200, 3);
+    let harness = Harness::new(1);
+    let frames = fragment(&payload, 9);
+    for frame in frames.iter() {
+        harness.feed(frame);
+    }
+    // A duplicate of the last fragment starts a new reassembly, which never
+    // completes
+    harness.feed(frames.last().unwrap());
+    assert_eq!(harness.receiver.delivered().len(), 1);
+    assert!(harness.alarm.is_armed());
+    harness.alarm.advance(TIMEOUT_MS);
+    assert_eq!(harness.stats().timeouts, 1);
+    assert!(!harness.alarm.is_armed());
+}
+
+#[test]
+fn truncated_streams() {
+    let mut rng = Rng(0x1234_5678);
+    for _ in 0..300 {
+        let payload = payload(FRAGMENT_LEN + rng.below(1190), rng.next() as u8);
+        let expected = expected_packet(&payload);
+        let harness = Harness::new(2);
+        let mut frames = fragment(&payload, rng.below(0xffff) as u16);
+        match rng.below(3) {
+            // Lose a fragment
+            0 => {
+                let i = rng.below(frames.len());
+                frames.remove(i);
+            }
+            // Cut the end of the stream
+            1 => {
+                let len = rng.below(frames.len());
+                frames.truncate(len);
+            }
+            // Truncate a frame, keeping the compressed header of the first
+            // fragment intact
+            _ => {
+                let i = rng.below(frames.len());
+                let min = if i == 0 {
+                    lowpan_frag::FRAG1_HDR_SIZE + IPHC_HEADER.len()
+                } else {
+                    0
+                };
+                let len = min + rng.below(frames[i].len() - min);
+                frames[i].truncate(len);
+            }
+        }
+        rng.shuffle(&mut frames);
+        for frame in frames.iter() {
+            harness.feed(frame);
+        }
+        // A truncated last fragment may still be well formed, but the
+        // reassembly is then incomplete
+        assert!(harness.receiver.delivered().is_empty());
+        assert_eq!(harness.stats().reassembled, 0);
+
+        // Incomplete reassemblies time out, freeing the contexts for the
+        // retransmission of the datagram
+        harness.alarm.advance(TIMEOUT_MS);
+        assert!(!harness.alarm.is_armed());
+        for frame in fragment(&payload, 0xffff).iter() {
+            harness.feed(frame);
+        }
+        assert_eq!(harness.receiver.delivered(), vec![expected]);
+        assert_eq!(harness.stats().no_context, 0);
+    }
+}
+
+#[test]
+fn interleaved_datagrams() {
+    let (payload_a, payload_b) = (payload(400, 4), payload(250, 5));
+    let harness = Harness::new(2);
+    let frames_a = fragment(&payload_a, 1);
+    let frames_b = fragment(&payload_b, 2);
+    for i in 0..frames_a.len().max(frames_b.len()) {
+        frames_a.get(i).map(|frame| harness.feed(frame));
+        frames_b.get(i).map(|frame| harness.feed(frame));
+    }
+    let delivered = harness.receiver.delivered();
+    assert_eq!(delivered.len(), 2);
+    assert!(delivered.contains(&expected_packet(&payload_a)));
+    assert!(delivered.contains(&expected_packet(&payload_b)));
+    assert_eq!(harness.stats().no_context, 0);
+}
+
+#[test]
+fn no_free_context() {
+    let harness = Harness::new(1);
+    let frames_a = fragment(&payload(200, 6), 1);
+    let frames_b = fragment(&payload(200, 7), 2);
+    harness.feed(&frames_a[0]);
+    harness.feed(&frames_b[0]);
+    assert_eq!(harness.stats().no_context, 1);
+
+    // Once the first reassembly times out, the context can be reused
+    harness.alarm.advance(TIMEOUT_MS);
+    assert_eq!(harness.stats().timeouts, 1);
+    for frame in frames_b.iter() {
+        harness.feed(frame);
+    }
+    assert_eq!(harness.receiver.delivered().len(), 1);
+}
+
+#[test]
+fn timeout() {
+    let harness = Harness::new(1);
+    let frames = fragment(&payload(300, 8), 3);
+    harness.feed(&frames[0]);
+    harness.alarm.advance(TIMEOUT_MS - 1);
+    assert_eq!(harness.stats().timeouts, 0);
+    // The remaining fragments arrive too late
+    harness.alarm.advance(1);
+    assert_eq!(harness.stats().timeouts, 1);
+    assert!(!harness.alarm.is_armed());
+    for frame in frames[1..].iter() {
+        harness.feed(frame);
+    }
+    assert!(harness.receiver.delivered().is_empty());
+}
+
+#[test]
+fn timeout_tracks_oldest_reassembly() {
+    let harness = Harness::new(2);
+    let frames_a = fragment(&payload(300, 9), 1);
+    let frames_b = fragment(&payload(300, 10), 2);
+    harness.feed(&frames_a[0]);
+    harness.alarm.advance(TIMEOUT_MS / 2);
+    harness.feed(&frames_b[0]);
+    harness.alarm.advance(TIMEOUT_MS / 2);
+    assert_eq!(harness.stats().timeouts, 1);
+    assert!(harness.alarm.is_armed());
+    for frame in frames_b[1..].iter() {
+        harness.feed(frame);
+    }
+    assert_eq!(harness.receiver.delivered().len(), 1);
+    assert!(!harness.alarm.is_armed());
+}
+
+#[test]
+fn overlapping_fragments() {
+    let payload = payload(300, 11);
+    let harness = Harness::new(1);
+    let frames = fragment(&payload, 4);
+    harness.feed(&frames[0]);
+    harness.feed(&frames[1]);
+    // A fragment covering the second half of one fragment and the first
+    // half of the next one
+    let mut overlapping = frames[2].clone();
+    overlapping[4] -= (FRAGMENT_LEN / 2 / 8) as u8;
+    harness.feed(&overlapping);
+    assert_eq!(harness.stats().overlaps, 1);
+    assert_eq!(harness.receiver.failed(), 1);
+    assert!(harness.receiver.delivered().is_empty());
+    assert!(!harness.alarm.is_armed());
+}
+
+#[test]
+fn invalid_frames() {
+    let harness = Harness::new(1);
+    let frames = fragment(&payload(300, 12), 5);
+    // Empty frame, truncated fragment headers, and a datagram larger than
+    // the reassembly buffer
+    harness.feed(&[]);
+    harness.feed(&frames[1][..3]);
+    let mut too_large = frames[0].clone();
+    too_large[0] = lowpan_frag::FRAG1_HDR | 0x07;
+    harness.feed(&too_large);
+    assert_eq!(harness.stats().invalid, 3);
+    assert!(harness.receiver.packets.borrow().is_empty());
+
+    // A non-final fragment whose length is not a multiple of 8
+    harness.feed(&frames[0]);
+    harness.feed(&frames[1][..frames[1].len() - 1]);
+    assert_eq!(harness.stats().invalid, 4);
+    assert_eq!(harness.receiver.failed(), 1);
+}
+
+#[test]
+fn stats_indices() {
+    let stats = ReassemblyStats {
+        packets_received: 1,
+        reassembled: 2,
+        timeouts: 3,
+        duplicates: 4,
+        overlaps: 5,
+        no_context: 6,
+        invalid: 7,
+    };
+    for i in 0..ReassemblyStats::COUNT {
+        assert_eq!(stats.get(i), Some(i as u32 + 1));
+    }
+    assert_eq!(stats.get(ReassemblyStats::COUNT), None);
+}
@@ -8,6 +8,7 @@
 
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::network_capabilities::NetworkCapability;
+use crate::net::sixlowpan::sixlowpan_state::SixlowpanState;
 use crate::net::stream::encode_u16;
 use crate::net::stream::encode_u8;
 use crate::net::stream::SResult;
@@ -27,7 +28,7 @@ use kernel::debug;
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
-use kernel::utilities::cells::MapCell;
+use kernel::utilities::cells::{MapCell, OptionalCell};
 use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
 use kernel::{ErrorCode, ProcessId};
 
@@ -120,6 +121,9 @@ pub struct UDPDriver<'a> {
     driver_send_cap: &'static dyn UdpDriverCapability,
 
     net_cap: &'static NetworkCapability,
+
+    /// 6LoWPAN layer whose reassembly counters are exposed to apps, if any
+    sixlowpan: OptionalCell<&'a dyn SixlowpanState<'a>>,
 }
 
 impl<'a> UDPDriver<'a> {
@@ -148,9 +152,16 @@ impl<'a> UDPDriver<'a> {
             kernel_buffer: MapCell::new(kernel_buffer),
             driver_send_cap: driver_send_cap,
             net_cap: net_cap,
+            sixlowpan: OptionalCell::empty(),
         }
     }
 
+    /// Sets the 6LoWPAN layer whose reassembly counters are returned by
+    /// command 5.
+    pub fn set_sixlowpan_state(&self, sixlowpan: &'a dyn SixlowpanState<'a>) {
+        self.sixlowpan.set(sixlowpan);
+    }
+
     /// If the driver is currently idle and there are pending transmissions,
     /// pick an app with a pending transmission and return its `ProcessId`.
     fn get_next_tx_if_idle(&self) -> Option<ProcessId> {
@@ -378,6 +389,7 @@ impl<'a> SyscallDriver for UDPDriver<'a> {
     ///        /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
     ///        This represents the size of the payload buffer in the kernel. Apps can use this
     ///        syscall to ensure they do not attempt to send too-large messages.
+    /// - `5`: Returns the 6LoWPAN reassembly counter with index `arg1`.
 
     fn command(
         &self,
@@ -552,6 +564,18 @@ impl<'a> SyscallDriver for UDPDriver<'a> {
                 }
             }
             4 => CommandReturn::success_u32(self.max_tx_pyld_len as u32),
+
+            // Returns the 6LoWPAN reassembly counter with index `arg1`
+            5 => self
+                .sixlowpan
+                .map_or(CommandReturn::failure(ErrorCode::NOSUPPORT), |sixlowpan| {
+                    sixlowpan
+                        .reassembly_stats()
+                        .get(arg1)
+                        .map_or(CommandReturn::failure(ErrorCode::INVAL), |value| {
+                            CommandReturn::success_u32(value)
+                        })
+                }),
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
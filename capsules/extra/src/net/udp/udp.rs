@@ -90,11 +90,13 @@ impl UDPHeader {
     pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
         stream_len_cond!(buf, self.get_hdr_size() + offset);
 
+        // The fields are stored in network byte order, while `encode_u16`
+        // takes a value in host byte order
         let mut off = offset;
-        off = enc_consume!(buf, off; encode_u16, self.src_port);
-        off = enc_consume!(buf, off; encode_u16, self.dst_port);
-        off = enc_consume!(buf, off; encode_u16, self.len);
-        off = enc_consume!(buf, off; encode_u16, self.cksum);
+        off = enc_consume!(buf, off; encode_u16, self.get_src_port());
+        off = enc_consume!(buf, off; encode_u16, self.get_dst_port());
+        off = enc_consume!(buf, off; encode_u16, self.get_len());
+        off = enc_consume!(buf, off; encode_u16, self.get_cksum());
         stream_done!(off, off);
     }
 
@@ -112,13 +114,37 @@ impl UDPHeader {
         let mut udp_header = Self::new();
         let off = 0;
         let (off, src_port) = dec_try!(buf, off; decode_u16);
-        udp_header.src_port = u16::from_be(src_port);
+        udp_header.set_src_port(src_port);
         let (off, dst_port) = dec_try!(buf, off; decode_u16);
-        udp_header.dst_port = u16::from_be(dst_port);
+        udp_header.set_dst_port(dst_port);
         let (off, len) = dec_try!(buf, off; decode_u16);
-        udp_header.len = u16::from_be(len);
+        udp_header.set_len(len);
         let (off, cksum) = dec_try!(buf, off; decode_u16);
-        udp_header.cksum = u16::from_be(cksum);
+        udp_header.set_cksum(cksum);
         stream_done!(off, udp_header);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_is_in_network_byte_order() {
+        let mut header = UDPHeader::new();
+        header.set_src_port(1000);
+        header.set_dst_port(2000);
+        header.set_len(12);
+        header.set_cksum(0x3f1f);
+
+        let mut buf = [0; 8];
+        assert_eq!(header.encode(&mut buf, 0).done(), Some((8, 8)));
+        assert_eq!(buf, [0x03, 0xe8, 0x07, 0xd0, 0x00, 0x0c, 0x3f, 0x1f]);
+
+        let decoded = UDPHeader::decode(&buf).done().unwrap().1;
+        assert_eq!(decoded.get_src_port(), 1000);
+        assert_eq!(decoded.get_dst_port(), 2000);
+        assert_eq!(decoded.get_len(), 12);
+        assert_eq!(decoded.get_cksum(), 0x3f1f);
+    }
+}
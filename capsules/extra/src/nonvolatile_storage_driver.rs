@@ -1,9 +1,15 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! Each application gets a region of its own within the memory space that has
+//! been provided to userland, and can only read and write that region. An app
+//! asks for a region with the `Nonvolatile Storage` TBF header, which gives its
+//! size, and the region is found by the `write_id` of the app's persistent ACL
+//! TBF header, so that the app gets the same region back after a reboot. Apps
+//! without both headers have no storage. Apps address their region from 0.
+//!
+//! The regions are allocated the first time each app uses its storage, and
+//! recorded in a table at the start of the userspace memory space. Regions are
+//! never freed. A region can only grow if it is the last one allocated.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
@@ -57,7 +63,7 @@
 use core::cell::Cell;
 use core::cmp;
 
-use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
 use kernel::hil;
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
@@ -84,6 +90,18 @@ mod rw_allow {
 
 pub const BUF_LEN: usize = 512;
 
+/// The number of apps that can be given a region.
+pub const MAX_REGIONS: usize = 8;
+
+/// Marks the start of the table of regions, so that storage which has never
+/// held one is seen as empty.
+const TABLE_MAGIC: u32 = 0x5245_4749;
+
+/// The bytes at the start of the userspace memory space used for the table of
+/// regions: the magic number and then a write_id, offset and length for each
+/// region.
+pub const TABLE_LEN: usize = 4 + MAX_REGIONS * 12;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -92,10 +110,34 @@ pub enum NonvolatileCommand {
     KernelWrite,
 }
 
+/// What is using the underlying storage. `Regions` is the driver itself,
+/// reading or writing the table of the regions of apps for the app with
+/// `processid`.
 #[derive(Clone, Copy)]
 pub enum NonvolatileUser {
     App { processid: ProcessId },
     Kernel,
+    Regions { processid: ProcessId },
+}
+
+/// The region of the userspace memory space given to the apps with a
+/// `write_id`. The offset is from the start of the userspace memory space, and
+/// a length of zero means the entry is unused.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Region {
+    write_id: u32,
+    offset: usize,
+    length: usize,
+}
+
+impl Region {
+    const fn empty() -> Region {
+        Region {
+            write_id: 0,
+            offset: 0,
+            length: 0,
+        }
+    }
 }
 
 pub struct App {
@@ -136,6 +178,8 @@ pub struct NonvolatileStorage<'a> {
     userspace_start_address: usize,
     // How many bytes allocated to userspace.
     userspace_length: usize,
+    // The regions of apps, once they have been read from the storage.
+    regions: OptionalCell<[Region; MAX_REGIONS]>,
     // The first byte that is accessible from the kernel.
     kernel_start_address: usize,
     // How many bytes allocated to kernel.
@@ -179,6 +223,7 @@ impl<'a> NonvolatileStorage<'a> {
             current_user: OptionalCell::empty(),
             userspace_start_address: userspace_start_address,
             userspace_length: userspace_length,
+            regions: OptionalCell::empty(),
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
             kernel_client: OptionalCell::empty(),
@@ -203,11 +248,12 @@ impl<'a> NonvolatileStorage<'a> {
         // Do bounds check.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
+                // Userspace sees its region starting at address 0 wherever it
+                // is in the physical memory.
+                let region_length = processid.map_or(0, |processid| self.region_length(processid));
+                if offset >= region_length
+                    || length > region_length
+                    || offset + length > region_length
                 {
                     return Err(ErrorCode::INVAL);
                 }
@@ -244,7 +290,7 @@ impl<'a> NonvolatileStorage<'a> {
                             };
 
                             // Check that it exists.
-                            if allow_buf_len == 0 || self.buffer.is_none() {
+                            if allow_buf_len == 0 {
                                 return Err(ErrorCode::RESERVE);
                             }
 
@@ -261,31 +307,14 @@ impl<'a> NonvolatileStorage<'a> {
                                     processid: processid,
                                 });
 
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    let _ = kernel_data
-                                        .get_readonly_processbuffer(ro_allow::WRITE)
-                                        .and_then(|write| {
-                                            write.enter(|app_buffer| {
-                                                self.buffer.map(|kernel_buffer| {
-                                                    // Check that the internal buffer and the buffer that was
-                                                    // allowed are long enough.
-                                                    let write_len =
-                                                        cmp::min(active_len, kernel_buffer.len());
-
-                                                    let d = &app_buffer[0..write_len];
-                                                    for (i, c) in kernel_buffer[0..write_len]
-                                                        .iter_mut()
-                                                        .enumerate()
-                                                    {
-                                                        *c = d[i].get();
-                                                    }
-                                                });
-                                            })
-                                        });
-                                }
-
-                                self.userspace_call_driver(command, offset, active_len)
+                                self.userspace_start(
+                                    processid,
+                                    app,
+                                    kernel_data,
+                                    command,
+                                    offset,
+                                    active_len,
+                                )
                             } else {
                                 // Some app is using the storage, we must wait.
                                 if app.pending_command == true {
@@ -342,16 +371,196 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
-    fn userspace_call_driver(
+    // The number of bytes of storage an app has, which is what it asks for if
+    // it has a `write_id` to find its region by.
+    fn region_length(&self, processid: ProcessId) -> usize {
+        processid
+            .get_storage_permissions()
+            .and_then(|permissions| permissions.get_write_id())
+            .and_then(|_| processid.get_nonvolatile_storage_size())
+            .unwrap_or(0)
+    }
+
+    // Start a command for the app that is the current user. If the region of
+    // the app is not known yet, this instead starts reading or writing the
+    // table of regions and leaves the command pending, to be started again
+    // when that finishes.
+    fn userspace_start(
         &self,
+        processid: ProcessId,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
         command: NonvolatileCommand,
         offset: usize,
         length: usize,
     ) -> Result<(), ErrorCode> {
-        // Calculate where we want to actually read from in the physical
-        // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let result = self.region_start(processid).and_then(|start| match start {
+            Some(start) => self.userspace_call_driver(kernel_data, command, start + offset, length),
+            None => {
+                app.pending_command = true;
+                app.command = command;
+                app.offset = offset;
+                app.length = length;
+                Ok(())
+            }
+        });
+        if result.is_err() {
+            self.current_user.clear();
+        }
+        result
+    }
 
+    // Find the physical address of the region of an app, allocating it if the
+    // app does not have one yet. Returns `None` if the table of regions has to
+    // be read, or written with the new region, first.
+    fn region_start(&self, processid: ProcessId) -> Result<Option<usize>, ErrorCode> {
+        let write_id = processid
+            .get_storage_permissions()
+            .and_then(|permissions| permissions.get_write_id())
+            .ok_or(ErrorCode::NOSUPPORT)?;
+        let length = processid
+            .get_nonvolatile_storage_size()
+            .ok_or(ErrorCode::NOSUPPORT)?;
+        if self.userspace_length < TABLE_LEN {
+            return Err(ErrorCode::NOMEM);
+        }
+
+        let mut regions = match self.regions.extract() {
+            Some(regions) => regions,
+            None => {
+                self.read_regions(processid)?;
+                return Ok(None);
+            }
+        };
+
+        // The new table is only kept once it has been written, in
+        // `write_done`.
+        match Self::allocate_region(&mut regions, write_id, length, self.userspace_length)? {
+            (offset, false) => Ok(Some(self.userspace_start_address + offset)),
+            (_, true) => {
+                self.write_regions(processid, &regions)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Find the region of `length` bytes for `write_id` in `regions`, adding or
+    // growing it if needed. Returns the offset of the region and whether
+    // `regions` changed and has to be written back.
+    fn allocate_region(
+        regions: &mut [Region; MAX_REGIONS],
+        write_id: u32,
+        length: usize,
+        userspace_length: usize,
+    ) -> Result<(usize, bool), ErrorCode> {
+        let end = regions
+            .iter()
+            .map(|region| region.offset + region.length)
+            .fold(TABLE_LEN, cmp::max);
+        match regions
+            .iter()
+            .position(|region| region.length > 0 && region.write_id == write_id)
+        {
+            Some(index) => {
+                let region = &mut regions[index];
+                if region.length >= length {
+                    return Ok((region.offset, false));
+                }
+                // The app asks for more than it had, which is only possible
+                // if nothing was allocated after it.
+                if region.offset + region.length != end || region.offset + length > userspace_length
+                {
+                    return Err(ErrorCode::NOMEM);
+                }
+                region.length = length;
+                Ok((region.offset, true))
+            }
+            None => {
+                let free = regions
+                    .iter()
+                    .position(|region| region.length == 0)
+                    .ok_or(ErrorCode::NOMEM)?;
+                if end + length > userspace_length {
+                    return Err(ErrorCode::NOMEM);
+                }
+                regions[free] = Region {
+                    write_id,
+                    offset: end,
+                    length,
+                };
+                Ok((end, true))
+            }
+        }
+    }
+
+    fn read_regions(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        self.current_user
+            .set(NonvolatileUser::Regions { processid });
+        self.driver
+            .read(buffer, self.userspace_start_address, TABLE_LEN)
+    }
+
+    fn write_regions(
+        &self,
+        processid: ProcessId,
+        regions: &[Region; MAX_REGIONS],
+    ) -> Result<(), ErrorCode> {
+        let buffer = self.buffer.take().ok_or(ErrorCode::RESERVE)?;
+        Self::encode_regions(buffer, regions);
+        self.current_user
+            .set(NonvolatileUser::Regions { processid });
+        self.driver
+            .write(buffer, self.userspace_start_address, TABLE_LEN)
+    }
+
+    fn encode_regions(buffer: &mut [u8], regions: &[Region; MAX_REGIONS]) {
+        buffer[0..4].copy_from_slice(&TABLE_MAGIC.to_le_bytes());
+        for (entry, region) in buffer[4..TABLE_LEN].chunks_mut(12).zip(regions.iter()) {
+            entry[0..4].copy_from_slice(&region.write_id.to_le_bytes());
+            entry[4..8].copy_from_slice(&(region.offset as u32).to_le_bytes());
+            entry[8..12].copy_from_slice(&(region.length as u32).to_le_bytes());
+        }
+    }
+
+    // The table of regions could not be read or written, so fail the command
+    // of the app that needed it rather than trying again forever. The app is
+    // told that no bytes were read or written.
+    fn fail_pending_command(&self, processid: ProcessId) {
+        let _ = self.apps.enter(processid, |app, kernel_data| {
+            if app.pending_command {
+                app.pending_command = false;
+                let upcall = match app.command {
+                    NonvolatileCommand::UserspaceRead => 0,
+                    _ => 1,
+                };
+                kernel_data.schedule_upcall(upcall, (0, 0, 0)).ok();
+            }
+        });
+    }
+
+    fn parse_regions(buffer: &[u8]) -> [Region; MAX_REGIONS] {
+        let mut regions = [Region::empty(); MAX_REGIONS];
+        let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if word(&buffer[0..4]) == TABLE_MAGIC {
+            for (region, entry) in regions.iter_mut().zip(buffer[4..TABLE_LEN].chunks(12)) {
+                *region = Region {
+                    write_id: word(&entry[0..4]),
+                    offset: word(&entry[4..8]) as usize,
+                    length: word(&entry[8..12]) as usize,
+                };
+            }
+        }
+        regions
+    }
+
+    fn userspace_call_driver(
+        &self,
+        kernel_data: &GrantKernelData,
+        command: NonvolatileCommand,
+        physical_address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
         self.buffer
             .take()
             .map_or(Err(ErrorCode::RESERVE), |buffer| {
@@ -359,7 +568,22 @@ impl<'a> NonvolatileStorage<'a> {
                 // allowed are long enough.
                 let active_len = cmp::min(length, buffer.len());
 
-                // self.current_app.set(Some(processid));
+                // Need to copy bytes if this is a write!
+                if command == NonvolatileCommand::UserspaceWrite {
+                    let _ = kernel_data
+                        .get_readonly_processbuffer(ro_allow::WRITE)
+                        .and_then(|write| {
+                            write.enter(|app_buffer| {
+                                let write_len = cmp::min(active_len, app_buffer.len());
+
+                                let d = &app_buffer[0..write_len];
+                                for (i, c) in buffer[0..write_len].iter_mut().enumerate() {
+                                    *c = d[i].get();
+                                }
+                            })
+                        });
+                }
+
                 match command {
                     NonvolatileCommand::UserspaceRead => {
                         self.driver.read(buffer, physical_address, active_len)
@@ -397,19 +621,15 @@ impl<'a> NonvolatileStorage<'a> {
             // If the kernel is not requesting anything, check all of the apps.
             for cntr in self.apps.iter() {
                 let processid = cntr.processid();
-                let started_command = cntr.enter(|app, _| {
+                let started_command = cntr.enter(|app, kernel_data| {
                     if app.pending_command {
                         app.pending_command = false;
                         self.current_user.set(NonvolatileUser::App {
                             processid: processid,
                         });
-                        if let Ok(()) =
-                            self.userspace_call_driver(app.command, app.offset, app.length)
-                        {
-                            true
-                        } else {
-                            false
-                        }
+                        let (command, offset, length) = (app.command, app.offset, app.length);
+                        self.userspace_start(processid, app, kernel_data, command, offset, length)
+                            .is_ok()
                     } else {
                         false
                     }
@@ -433,6 +653,16 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         client.read_done(buffer, length);
                     });
                 }
+                NonvolatileUser::Regions { processid } => {
+                    // A short read leaves the table unread, to be read
+                    // again by the next app to use its region.
+                    if length == TABLE_LEN {
+                        self.regions.set(Self::parse_regions(buffer));
+                    } else {
+                        self.fail_pending_command(processid);
+                    }
+                    self.buffer.replace(buffer);
+                }
                 NonvolatileUser::App { processid } => {
                     let _ = self.apps.enter(processid, move |_, kernel_data| {
                         // Need to copy in the contents of the buffer
@@ -471,6 +701,16 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         client.write_done(buffer, length);
                     });
                 }
+                NonvolatileUser::Regions { processid } => {
+                    // The buffer still holds the table that was written, so
+                    // keep it only if all of it reached the storage.
+                    if length == TABLE_LEN {
+                        self.regions.set(Self::parse_regions(buffer));
+                    } else {
+                        self.fail_pending_command(processid);
+                    }
+                    self.buffer.replace(buffer);
+                }
                 NonvolatileUser::App { processid } => {
                     let _ = self.apps.enter(processid, move |_app, kernel_data| {
                         // Replace the buffer we used to do this write.
@@ -542,7 +782,7 @@ impl SyscallDriver for NonvolatileStorage<'_> {
     /// ### `command_num`
     ///
     /// - `0`: Return Ok(()) if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes in the app's region.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
     fn command(
@@ -559,7 +799,7 @@ impl SyscallDriver for NonvolatileStorage<'_> {
 
             1 /* How many bytes are accessible from userspace */ => {
                 // TODO: Would break on 64-bit platforms
-                CommandReturn::success_u32(self.userspace_length as u32)
+                CommandReturn::success_u32(self.region_length(processid) as u32)
             },
 
             2 /* Issue a read command */ => {
@@ -600,3 +840,102 @@ impl SyscallDriver for NonvolatileStorage<'_> {
         self.apps.enter(processid, |_, _| {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERSPACE_LENGTH: usize = 1024;
+
+    fn allocate(
+        regions: &mut [Region; MAX_REGIONS],
+        write_id: u32,
+        length: usize,
+    ) -> Result<(usize, bool), ErrorCode> {
+        NonvolatileStorage::allocate_region(regions, write_id, length, USERSPACE_LENGTH)
+    }
+
+    #[test]
+    fn regions_are_allocated_after_the_table() {
+        let mut regions = [Region::empty(); MAX_REGIONS];
+        assert_eq!(allocate(&mut regions, 7, 100), Ok((TABLE_LEN, true)));
+        assert_eq!(allocate(&mut regions, 9, 50), Ok((TABLE_LEN + 100, true)));
+        // Asking again, for the same or less, finds the same region without
+        // changing the table.
+        assert_eq!(allocate(&mut regions, 7, 100), Ok((TABLE_LEN, false)));
+        assert_eq!(allocate(&mut regions, 9, 10), Ok((TABLE_LEN + 100, false)));
+        assert_eq!(
+            regions[1],
+            Region {
+                write_id: 9,
+                offset: TABLE_LEN + 100,
+                length: 50,
+            }
+        );
+    }
+
+    #[test]
+    fn only_the_last_region_grows() {
+        let mut regions = [Region::empty(); MAX_REGIONS];
+        allocate(&mut regions, 1, 100).unwrap();
+        allocate(&mut regions, 2, 100).unwrap();
+        assert_eq!(allocate(&mut regions, 1, 200), Err(ErrorCode::NOMEM));
+        assert_eq!(allocate(&mut regions, 2, 200), Ok((TABLE_LEN + 100, true)));
+        assert_eq!(regions[1].length, 200);
+        // Nor past the end of the userspace memory space.
+        assert_eq!(
+            allocate(&mut regions, 2, USERSPACE_LENGTH),
+            Err(ErrorCode::NOMEM)
+        );
+        assert_eq!(regions[1].length, 200);
+        // A new region starts after the grown one.
+        assert_eq!(allocate(&mut regions, 3, 10), Ok((TABLE_LEN + 300, true)));
+    }
+
+    #[test]
+    fn full_tables_and_storage_are_nomem() {
+        let mut regions = [Region::empty(); MAX_REGIONS];
+        for write_id in 0..MAX_REGIONS as u32 {
+            allocate(&mut regions, write_id, 10).unwrap();
+        }
+        let table = regions;
+        assert_eq!(allocate(&mut regions, 100, 10), Err(ErrorCode::NOMEM));
+        assert_eq!(regions, table);
+
+        let mut regions = [Region::empty(); MAX_REGIONS];
+        assert_eq!(
+            allocate(&mut regions, 1, USERSPACE_LENGTH - TABLE_LEN + 1),
+            Err(ErrorCode::NOMEM)
+        );
+        assert_eq!(
+            allocate(&mut regions, 1, USERSPACE_LENGTH - TABLE_LEN),
+            Ok((TABLE_LEN, true))
+        );
+    }
+
+    #[test]
+    fn regions_are_found_again_after_a_reboot() {
+        let mut regions = [Region::empty(); MAX_REGIONS];
+        allocate(&mut regions, 0x1234, 100).unwrap();
+        allocate(&mut regions, 0x5678, 60).unwrap();
+
+        let mut storage = [0xff; TABLE_LEN];
+        NonvolatileStorage::encode_regions(&mut storage, &regions);
+        let mut reloaded = NonvolatileStorage::parse_regions(&storage);
+        assert_eq!(reloaded, regions);
+        assert_eq!(
+            allocate(&mut reloaded, 0x5678, 60),
+            Ok((TABLE_LEN + 100, false))
+        );
+        assert_eq!(allocate(&mut reloaded, 0x1234, 100), Ok((TABLE_LEN, false)));
+    }
+
+    #[test]
+    fn storage_without_a_table_has_no_regions() {
+        let erased = [0xff; TABLE_LEN];
+        assert_eq!(
+            NonvolatileStorage::parse_regions(&erased),
+            [Region::empty(); MAX_REGIONS]
+        );
+    }
+}
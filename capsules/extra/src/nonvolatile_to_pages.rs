@@ -308,3 +308,113 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'_, F> {
 
     fn erase_complete(&self, _error: hil::flash::Error) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_flash::{Fault, SimFlash, SimPage};
+    use kernel::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use std::boxed::Box;
+    use std::vec;
+
+    const PAGE_SIZE: usize = 64;
+
+    struct TestClient {
+        done: OptionalCell<usize>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl NonvolatileStorageClient<'static> for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.done.set(length);
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.done.set(length);
+        }
+    }
+
+    fn setup() -> (
+        &'static SimFlash<'static, PAGE_SIZE>,
+        &'static NonvolatileToPages<'static, SimFlash<'static, PAGE_SIZE>>,
+        &'static TestClient,
+    ) {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let storage = Box::leak(vec![0xFF; 4 * PAGE_SIZE].into_boxed_slice());
+        let flash = Box::leak(Box::new(SimFlash::new(storage, ddc)));
+        flash.initialise(ddc.register(flash).unwrap());
+        // Like most flash controllers used below this capsule
+        flash.set_erase_on_write(true);
+
+        let nv_to_page = Box::leak(Box::new(NonvolatileToPages::new(
+            flash,
+            Box::leak(Box::new(SimPage::default())),
+        )));
+        hil::flash::HasClient::set_client(flash, nv_to_page);
+        let client = Box::leak(Box::new(TestClient {
+            done: OptionalCell::empty(),
+            buffer: TakeCell::new(Box::leak(vec![0; 3 * PAGE_SIZE].into_boxed_slice())),
+        }));
+        nv_to_page.set_client(client);
+        (flash, nv_to_page, client)
+    }
+
+    #[test]
+    fn writes_across_pages() {
+        let (flash, nv_to_page, client) = setup();
+        let buffer = client.buffer.take().unwrap();
+        buffer
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8);
+
+        // Starting and ending part of the way through a page
+        nv_to_page.write(buffer, 10, 2 * PAGE_SIZE).unwrap();
+        flash.run();
+        assert_eq!(client.done.take(), Some(2 * PAGE_SIZE));
+        flash.map_storage(|storage| {
+            assert!(storage[..10].iter().all(|b| *b == 0xFF));
+            assert!(storage[10..][..2 * PAGE_SIZE]
+                .iter()
+                .enumerate()
+                .all(|(i, b)| *b == i as u8));
+            assert!(storage[10 + 2 * PAGE_SIZE..].iter().all(|b| *b == 0xFF));
+        });
+
+        let buffer = client.buffer.take().unwrap();
+        buffer.fill(0);
+        nv_to_page.read(buffer, 12, 100).unwrap();
+        flash.run();
+        assert_eq!(client.done.take(), Some(100));
+        let buffer = client.buffer.take().unwrap();
+        assert!(buffer[..100]
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == i as u8 + 2));
+    }
+
+    #[test]
+    fn torn_write_keeps_other_pages() {
+        let (flash, nv_to_page, client) = setup();
+        let buffer = client.buffer.take().unwrap();
+        buffer.fill(0);
+
+        // The second page written loses power half way through
+        flash.inject(Fault::TornWrite(PAGE_SIZE / 2), 1);
+        nv_to_page.write(buffer, 0, 3 * PAGE_SIZE).unwrap();
+        flash.run();
+        assert!(!flash.powered());
+        assert!(client.done.is_none());
+        flash.map_storage(|storage| {
+            assert!(storage[..PAGE_SIZE + PAGE_SIZE / 2].iter().all(|b| *b == 0));
+            assert!(storage[PAGE_SIZE + PAGE_SIZE / 2..]
+                .iter()
+                .all(|b| *b == 0xFF));
+        });
+    }
+}
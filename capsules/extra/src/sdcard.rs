@@ -1243,6 +1243,12 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         self.is_initialized.get()
     }
 
+    /// Whether a command is in progress, so that a read or write would be
+    /// refused with `BUSY`.
+    pub fn is_busy(&self) -> bool {
+        self.txbuffer.is_none() || self.rxbuffer.is_none()
+    }
+
     /// watches SD card detect pin for changes, sends callback on change
     pub fn detect_changes(&self) {
         self.detect_pin.get().map(|pin| {
@@ -1276,12 +1282,29 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         }
     }
 
+    /// Takes back the buffer of a read or write which failed, either when
+    /// starting it or with an `error` callback. The buffer of a read or
+    /// write refused with `BUSY` is not kept.
+    pub fn take_failed_buffer(&self) -> Option<&'static mut [u8]> {
+        self.client_buffer.take()
+    }
+
     pub fn read_blocks(
         &self,
         buffer: &'static mut [u8],
         sector: u32,
         count: u32,
     ) -> Result<(), ErrorCode> {
+        // a command in progress holds the SPI buffers, and the user buffer
+        // it was given, which must stay with it
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // save the user buffer for later, or for `take_failed_buffer` if the
+        // command cannot be started
+        self.client_buffer.replace(buffer);
+
         // only if initialized and installed
         if self.is_installed() {
             if self.is_initialized() {
@@ -1291,8 +1314,6 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                         self.rxbuffer
                             .take()
                             .map_or(Err(ErrorCode::NOMEM), move |rxbuffer| {
-                                // save the user buffer for later
-                                self.client_buffer.replace(buffer);
                                 self.client_offset.set(0);
 
                                 // convert block address to byte address for non-block
@@ -1341,6 +1362,16 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         sector: u32,
         count: u32,
     ) -> Result<(), ErrorCode> {
+        // a command in progress holds the SPI buffers, and the user buffer
+        // it was given, which must stay with it
+        if self.is_busy() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // save the user buffer for later, or for `take_failed_buffer` if the
+        // command cannot be started
+        self.client_buffer.replace(buffer);
+
         // only if initialized and installed
         if self.is_installed() {
             if self.is_initialized() {
@@ -1350,8 +1381,6 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                         self.rxbuffer
                             .take()
                             .map_or(Err(ErrorCode::NOMEM), move |rxbuffer| {
-                                // save the user buffer for later
-                                self.client_buffer.replace(buffer);
                                 self.client_offset.set(0);
 
                                 // convert block address to byte address for non-block
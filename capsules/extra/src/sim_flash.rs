@@ -0,0 +1,597 @@
+//! A flash simulated in memory, to test storage capsules on the host.
+//!
+//! `SimFlash` implements `hil::flash::Flash` over a RAM buffer with the
+//! semantics of NOR flash: erasing a page sets all of its bits, and writing
+//! can only clear bits, so a byte written twice holds the AND of both
+//! values. Controllers that erase a page as part of writing it can be
+//! modelled with `set_erase_on_write()`.
+//!
+//! Like a chip driver, the flash completes each operation from a deferred
+//! call rather than from within the call that started it. A test can also
+//! call `run()` to complete operations until there are none left.
+//!
+//! Faults can be injected to check that capsules survive them:
+//!
+//! - A write or erase can be torn by a power loss part of the way through.
+//!   The operation never completes, and the flash stays off until
+//!   `power_on()`, when a test restarts the capsule over what was stored.
+//! - Reads, writes and erases can fail with `Error::FlashError`.
+//! - Bits can be flipped in the data returned by a read, or in the storage
+//!   itself to model a cell losing its charge.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let storage = static_init!([u8; 4 * 512], [0xFF; 4 * 512]);
+//! let flash = static_init!(
+//!     SimFlash<'static, 512>,
+//!     SimFlash::new(storage, dynamic_deferred_caller)
+//! );
+//! flash.initialise(dynamic_deferred_caller.register(flash).unwrap());
+//! hil::flash::HasClient::set_client(flash, nv_to_page);
+//!
+//! // Lose power half way through the write after the next one
+//! flash.inject(Fault::TornWrite(256), 1);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::flash::{self, Flash};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::log::LogVolume;
+
+/// A page of a `SimFlash` with `S` byte pages.
+pub struct SimPage<const S: usize>(pub [u8; S]);
+
+impl<const S: usize> Default for SimPage<S> {
+    fn default() -> Self {
+        SimPage([0; S])
+    }
+}
+
+impl<const S: usize> AsMut<[u8]> for SimPage<S> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// A fault to inject into an operation of a `SimFlash`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Power is lost after writing this many bytes of the page.
+    TornWrite(usize),
+    /// Power is lost after erasing this many bytes of the page.
+    TornErase(usize),
+    /// The read fails, leaving the buffer unchanged.
+    ReadError,
+    /// The write fails, leaving the page unchanged.
+    WriteError,
+    /// The erase fails, leaving the page unchanged.
+    EraseError,
+    /// The read returns the byte at `offset` in the page with `bit` flipped.
+    /// The page stored is unchanged.
+    ReadBitFlip { offset: usize, bit: u8 },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+impl Fault {
+    /// Whether the fault is injected into `operation`.
+    fn applies_to(&self, operation: Operation) -> bool {
+        match (self, operation) {
+            (Fault::ReadError | Fault::ReadBitFlip { .. }, Operation::Read(_)) => true,
+            (Fault::TornWrite(_) | Fault::WriteError, Operation::Write(_)) => true,
+            (Fault::TornErase(_) | Fault::EraseError, Operation::Erase(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+pub struct SimFlash<'a, const S: usize> {
+    client: OptionalCell<&'a dyn flash::Client<SimFlash<'a, S>>>,
+    storage: TakeCell<'a, [u8]>,
+    pages: usize,
+    /// Erase each page before writing it
+    erase_on_write: Cell<bool>,
+    deferred_caller: &'static DynamicDeferredCall,
+    deferred_handle: OptionalCell<DeferredCallHandle>,
+    operation: OptionalCell<Operation>,
+    buffer: TakeCell<'static, SimPage<S>>,
+    /// The fault to inject, and the number of operations it applies to to
+    /// skip first.
+    fault: OptionalCell<(Fault, usize)>,
+    powered: Cell<bool>,
+    reads: Cell<usize>,
+    writes: Cell<usize>,
+    erases: Cell<usize>,
+}
+
+impl<'a, const S: usize> SimFlash<'a, S> {
+    /// Creates a flash with as many pages as fit in `storage`. `storage` is
+    /// used as it is, so it should be filled with 0xFF to start erased.
+    pub fn new(
+        storage: &'a mut [u8],
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> SimFlash<'a, S> {
+        let pages = storage.len() / S;
+        SimFlash {
+            client: OptionalCell::empty(),
+            storage: TakeCell::new(storage),
+            pages,
+            erase_on_write: Cell::new(false),
+            deferred_caller,
+            deferred_handle: OptionalCell::empty(),
+            operation: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            fault: OptionalCell::empty(),
+            powered: Cell::new(true),
+            reads: Cell::new(0),
+            writes: Cell::new(0),
+            erases: Cell::new(0),
+        }
+    }
+
+    pub fn initialise(&self, deferred_call_handle: DeferredCallHandle) {
+        self.deferred_handle.set(deferred_call_handle);
+    }
+
+    /// Erase each page before writing it, as some flash controllers do, so
+    /// that a page holds exactly what was last written to it.
+    pub fn set_erase_on_write(&self, erase_on_write: bool) {
+        self.erase_on_write.set(erase_on_write);
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Injects `fault` into an operation it applies to, after `skip` of them
+    /// have completed normally. This replaces any fault not yet injected.
+    pub fn inject(&self, fault: Fault, skip: usize) {
+        self.fault.set((fault, skip));
+    }
+
+    /// Whether a fault is still waiting to be injected.
+    pub fn fault_pending(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    /// Flips `bit` of the byte stored at `address`.
+    pub fn flip_bit(&self, address: usize, bit: u8) {
+        self.storage.map(|storage| storage[address] ^= 1 << bit);
+    }
+
+    /// Whether the flash is powered. It is off after a torn write or erase.
+    pub fn powered(&self) -> bool {
+        self.powered.get()
+    }
+
+    /// Turns the power back on, losing the operation that was in progress.
+    pub fn power_on(&self) {
+        self.operation.clear();
+        self.buffer.take();
+        self.powered.set(true);
+    }
+
+    /// Calls `f` with the contents of the flash.
+    pub fn map_storage<R, F: FnOnce(&mut [u8]) -> R>(&self, f: F) -> Option<R> {
+        self.storage.map(f)
+    }
+
+    /// The number of reads, writes and erases that have completed or been
+    /// torn.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (self.reads.get(), self.writes.get(), self.erases.get())
+    }
+
+    /// Whether an operation is in progress.
+    pub fn busy(&self) -> bool {
+        self.operation.is_some()
+    }
+
+    /// Completes the operation in progress, returning false if there is
+    /// none.
+    fn complete(&self) -> bool {
+        if !self.powered.get() {
+            return false;
+        }
+        let operation = match self.operation.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+        let fault = self.take_fault(operation);
+
+        match operation {
+            Operation::Read(page) => {
+                self.reads.set(self.reads.get() + 1);
+                let buffer = self.buffer.take().unwrap();
+                let error = if fault == Some(Fault::ReadError) {
+                    flash::Error::FlashError
+                } else {
+                    self.storage
+                        .map(|storage| buffer.0.copy_from_slice(&storage[page * S..][..S]));
+                    if let Some(Fault::ReadBitFlip { offset, bit }) = fault {
+                        buffer.0[offset] ^= 1 << bit;
+                    }
+                    flash::Error::CommandComplete
+                };
+                self.client
+                    .map(|client| client.read_complete(buffer, error));
+            }
+            Operation::Write(page) => {
+                self.writes.set(self.writes.get() + 1);
+                let buffer = self.buffer.take().unwrap();
+                let length = match fault {
+                    Some(Fault::TornWrite(length)) => length.min(S),
+                    Some(_) => 0,
+                    None => S,
+                };
+                self.storage.map(|storage| {
+                    let stored = &mut storage[page * S..][..S];
+                    if fault != Some(Fault::WriteError) && self.erase_on_write.get() {
+                        stored.fill(0xFF);
+                    }
+                    for (stored, new) in stored[..length].iter_mut().zip(buffer.0.iter()) {
+                        // Writing can only clear bits
+                        *stored &= *new;
+                    }
+                });
+                let error = match fault {
+                    Some(Fault::TornWrite(_)) => {
+                        self.powered.set(false);
+                        return true;
+                    }
+                    Some(_) => flash::Error::FlashError,
+                    None => flash::Error::CommandComplete,
+                };
+                self.client
+                    .map(|client| client.write_complete(buffer, error));
+            }
+            Operation::Erase(page) => {
+                self.erases.set(self.erases.get() + 1);
+                let length = match fault {
+                    Some(Fault::TornErase(length)) => length.min(S),
+                    Some(_) => 0,
+                    None => S,
+                };
+                self.storage
+                    .map(|storage| storage[page * S..][..length].fill(0xFF));
+                let error = match fault {
+                    Some(Fault::TornErase(_)) => {
+                        self.powered.set(false);
+                        return true;
+                    }
+                    Some(_) => flash::Error::FlashError,
+                    None => flash::Error::CommandComplete,
+                };
+                self.client.map(|client| client.erase_complete(error));
+            }
+        }
+        true
+    }
+
+    /// Completes operations, including those started by the client as
+    /// others complete, until there are none left.
+    pub fn run(&self) {
+        while self.complete() {}
+    }
+
+    /// Returns the fault to inject into `operation`, if it is the one the
+    /// fault waits for.
+    fn take_fault(&self, operation: Operation) -> Option<Fault> {
+        let (fault, skip) = self.fault.extract()?;
+        if !fault.applies_to(operation) {
+            return None;
+        }
+        if skip > 0 {
+            self.fault.set((fault, skip - 1));
+            return None;
+        }
+        self.fault.clear();
+        Some(fault)
+    }
+
+    fn start(&self, operation: Operation) -> Result<(), ErrorCode> {
+        if !self.powered.get() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        match operation {
+            Operation::Read(page) | Operation::Write(page) | Operation::Erase(page)
+                if page >= self.pages =>
+            {
+                return Err(ErrorCode::INVAL);
+            }
+            _ => {}
+        }
+        self.operation.set(operation);
+        self.deferred_handle
+            .map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+}
+
+impl<const S: usize> DynamicDeferredCallClient for SimFlash<'_, S> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.complete();
+    }
+}
+
+/// A log over the flash reads its entries from the storage, which starts at
+/// address 0.
+impl<'a, const S: usize> LogVolume for &'a SimFlash<'a, S> {
+    fn address(&self) -> usize {
+        0
+    }
+
+    fn len(&self) -> usize {
+        self.pages * S
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        self.storage
+            .map(|storage| buf.copy_from_slice(&storage[offset..offset + buf.len()]));
+    }
+}
+
+impl<'a, C: flash::Client<Self>, const S: usize> flash::HasClient<'a, C> for SimFlash<'a, S> {
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<const S: usize> Flash for SimFlash<'_, S> {
+    type Page = SimPage<S>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(Operation::Read(page_number)) {
+            Ok(()) => {
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(Operation::Write(page_number)) {
+            Ok(()) => {
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        self.start(Operation::Erase(page_number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use kernel::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 16;
+
+    #[derive(Debug, PartialEq)]
+    enum Done {
+        Read(Vec<u8>, flash::Error),
+        Write(flash::Error),
+        Erase(flash::Error),
+    }
+
+    /// Records the operations completed, keeping the buffer.
+    struct TestClient {
+        done: RefCell<Vec<Done>>,
+        buffer: TakeCell<'static, SimPage<PAGE_SIZE>>,
+    }
+
+    impl flash::Client<SimFlash<'static, PAGE_SIZE>> for TestClient {
+        fn read_complete(&self, buffer: &'static mut SimPage<PAGE_SIZE>, error: flash::Error) {
+            self.done
+                .borrow_mut()
+                .push(Done::Read(buffer.0.to_vec(), error));
+            self.buffer.replace(buffer);
+        }
+
+        fn write_complete(&self, buffer: &'static mut SimPage<PAGE_SIZE>, error: flash::Error) {
+            self.done.borrow_mut().push(Done::Write(error));
+            self.buffer.replace(buffer);
+        }
+
+        fn erase_complete(&self, error: flash::Error) {
+            self.done.borrow_mut().push(Done::Erase(error));
+        }
+    }
+
+    struct Test {
+        ddc: &'static DynamicDeferredCall,
+        flash: &'static SimFlash<'static, PAGE_SIZE>,
+        client: &'static TestClient,
+    }
+
+    impl Test {
+        fn new(pages: usize) -> Test {
+            let states: &'static [DynamicDeferredCallClientState] =
+                Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+            let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+            let storage = Box::leak(vec![0xFF; pages * PAGE_SIZE].into_boxed_slice());
+            let flash = Box::leak(Box::new(SimFlash::new(storage, ddc)));
+            flash.initialise(ddc.register(flash).unwrap());
+            let client = Box::leak(Box::new(TestClient {
+                done: RefCell::new(Vec::new()),
+                buffer: TakeCell::new(Box::leak(Box::new(SimPage::default()))),
+            }));
+            flash::HasClient::set_client(flash, client);
+            Test { ddc, flash, client }
+        }
+
+        fn read(&self, page: usize) -> Done {
+            let buffer = self.client.buffer.take().unwrap();
+            assert!(self.flash.read_page(page, buffer).is_ok());
+            self.complete()
+        }
+
+        fn write(&self, page: usize, data: [u8; PAGE_SIZE]) -> Result<(), ErrorCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer.0 = data;
+            self.flash.write_page(page, buffer).map_err(|(e, buffer)| {
+                self.client.buffer.replace(buffer);
+                e
+            })
+        }
+
+        /// Completes the operation in progress from the deferred call.
+        fn complete(&self) -> Done {
+            assert!(self.ddc.has_pending());
+            self.flash.run();
+            self.client.done.borrow_mut().pop().unwrap()
+        }
+
+        fn page(&self, page: usize) -> Vec<u8> {
+            self.flash
+                .map_storage(|storage| storage[page * PAGE_SIZE..][..PAGE_SIZE].to_vec())
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn writes_only_clear_bits() {
+        let test = Test::new(2);
+        test.write(1, [0x0F; PAGE_SIZE]).unwrap();
+        assert_eq!(test.complete(), Done::Write(flash::Error::CommandComplete));
+        test.write(1, [0x3C; PAGE_SIZE]).unwrap();
+        test.complete();
+        assert_eq!(
+            test.read(1),
+            Done::Read(vec![0x0C; PAGE_SIZE], flash::Error::CommandComplete)
+        );
+        assert_eq!(test.page(0), [0xFF; PAGE_SIZE]);
+
+        test.flash.erase_page(1).unwrap();
+        assert_eq!(test.complete(), Done::Erase(flash::Error::CommandComplete));
+        assert_eq!(test.page(1), [0xFF; PAGE_SIZE]);
+
+        // Unless the page is erased first
+        test.write(1, [0x0F; PAGE_SIZE]).unwrap();
+        test.complete();
+        test.flash.set_erase_on_write(true);
+        test.write(1, [0x3C; PAGE_SIZE]).unwrap();
+        test.complete();
+        assert_eq!(test.page(1), [0x3C; PAGE_SIZE]);
+        assert_eq!(test.flash.counts(), (1, 4, 1));
+    }
+
+    #[test]
+    fn completes_from_deferred_call() {
+        let test = Test::new(2);
+        test.write(0, [0; PAGE_SIZE]).unwrap();
+        // Nothing happens until the deferred call
+        assert!(test.client.done.borrow().is_empty());
+        assert!(test.flash.busy());
+        assert_eq!(test.flash.erase_page(1), Err(ErrorCode::BUSY));
+        assert_eq!(test.page(0), [0xFF; PAGE_SIZE]);
+
+        test.flash
+            .call(test.flash.deferred_handle.extract().unwrap());
+        assert_eq!(
+            test.client.done.borrow_mut().pop(),
+            Some(Done::Write(flash::Error::CommandComplete))
+        );
+        assert_eq!(test.page(0), [0; PAGE_SIZE]);
+
+        assert_eq!(test.flash.erase_page(2), Err(ErrorCode::INVAL));
+        assert!(!test.flash.busy());
+    }
+
+    #[test]
+    fn tears_writes_and_erases() {
+        let test = Test::new(2);
+        test.flash.inject(Fault::TornWrite(4), 1);
+        test.write(0, [0; PAGE_SIZE]).unwrap();
+        test.complete();
+        assert!(test.flash.fault_pending());
+
+        // The write after the next one loses power part of the way through
+        test.write(1, [0; PAGE_SIZE]).unwrap();
+        test.flash.run();
+        assert!(test.client.done.borrow().is_empty());
+        assert!(!test.flash.powered());
+        assert!(!test.flash.fault_pending());
+        let mut torn = [0xFF; PAGE_SIZE];
+        torn[..4].fill(0);
+        assert_eq!(test.page(1), torn);
+        assert_eq!(test.flash.erase_page(1), Err(ErrorCode::OFF));
+
+        test.flash.power_on();
+        test.flash.inject(Fault::TornErase(8), 0);
+        test.flash.erase_page(0).unwrap();
+        test.flash.run();
+        let mut torn = [0; PAGE_SIZE];
+        torn[..8].fill(0xFF);
+        assert_eq!(test.page(0), torn);
+        assert!(!test.flash.powered());
+    }
+
+    #[test]
+    fn injects_errors() {
+        let test = Test::new(1);
+        test.write(0, [0xAA; PAGE_SIZE]).unwrap();
+        test.complete();
+
+        test.flash.inject(Fault::WriteError, 0);
+        test.write(0, [0; PAGE_SIZE]).unwrap();
+        assert_eq!(test.complete(), Done::Write(flash::Error::FlashError));
+        test.flash.inject(Fault::EraseError, 0);
+        test.flash.erase_page(0).unwrap();
+        assert_eq!(test.complete(), Done::Erase(flash::Error::FlashError));
+        assert_eq!(test.page(0), [0xAA; PAGE_SIZE]);
+
+        test.flash.inject(Fault::ReadError, 0);
+        assert_eq!(
+            test.read(0),
+            Done::Read(vec![0; PAGE_SIZE], flash::Error::FlashError)
+        );
+
+        // A bit flipped when reading is only in the buffer, while one
+        // flipped in storage stays
+        test.flash
+            .inject(Fault::ReadBitFlip { offset: 3, bit: 0 }, 0);
+        let mut flipped = vec![0xAA; PAGE_SIZE];
+        flipped[3] = 0xAB;
+        assert_eq!(
+            test.read(0),
+            Done::Read(flipped.clone(), flash::Error::CommandComplete)
+        );
+        assert_eq!(test.page(0), [0xAA; PAGE_SIZE]);
+        test.flash.flip_bit(3, 0);
+        assert_eq!(test.page(0), flipped);
+    }
+}
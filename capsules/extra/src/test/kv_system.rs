@@ -40,18 +40,24 @@
 //! Key: [18, 52, 86, 120, 154, 188, 222, 240] with value [16, 32, 48] was added
 //! Now retrieving the key
 //! Key: [18, 52, 86, 120, 154, 188, 222, 240] with value [16, 32, 48, 0] was retrieved
+//! Now listing the keys
+//! Found key: [18, 52, 86, 120, 154, 188, 222, 240]
+//! Finished listing the keys
 //! Removed Key: [18, 52, 86, 120, 154, 188, 222, 240]
 //! Try to read removed key: [18, 52, 86, 120, 154, 188, 222, 240]
 //! Unable to find key: [18, 52, 86, 120, 154, 188, 222, 240]
 //! Let's start a garbage collection
 //! Finished garbage collection
+//! Statistics: Statistics { free: ..., used: ..., invalid: ..., ... }
+//! Started a transaction
+//! Committed the transaction
 //! ---Finished TicKV Tests---
 //! ```
 
 use core::cell::Cell;
 use core::marker::PhantomData;
 use kernel::debug;
-use kernel::hil::kv_system::{self, KVSystem, KeyType};
+use kernel::hil::kv_system::{self, KVSystem, KeyType, Statistics};
 use kernel::utilities::cells::TakeCell;
 use kernel::ErrorCode;
 
@@ -67,6 +73,8 @@ pub struct KVSystemTest<'a, S: KVSystem<'static>, T: KeyType> {
     value: TakeCell<'static, [u8]>,
     ret_buffer: TakeCell<'static, [u8]>,
     state: Cell<CurrentState>,
+    expected_key: Cell<Option<T>>,
+    found_key: Cell<bool>,
 }
 
 impl<'a, S: KVSystem<'static>, T: KeyType> KVSystemTest<'a, S, T> {
@@ -83,6 +91,8 @@ impl<'a, S: KVSystem<'static>, T: KeyType> KVSystemTest<'a, S, T> {
             value: TakeCell::new(value),
             ret_buffer: TakeCell::new(static_buf),
             state: Cell::new(CurrentState::Normal),
+            expected_key: Cell::new(None),
+            found_key: Cell::new(false),
         }
     }
 }
@@ -140,7 +150,10 @@ impl<'a, S: KVSystem<'static, K = T>, T: KeyType + core::fmt::Debug> kv_system::
             Ok(()) => {
                 debug!("Key: {:?} with value {:?} was retrieved", key, ret_buf);
                 self.ret_buffer.replace(ret_buf);
-                self.kv_system.invalidate_key(key).unwrap();
+                self.expected_key.set(Some(*key));
+
+                debug!("Now listing the keys");
+                self.kv_system.enumerate(0, key).unwrap();
             }
             Err(e) => {
                 if self.state.get() == CurrentState::ExpectGetValueFail {
@@ -174,15 +187,79 @@ impl<'a, S: KVSystem<'static, K = T>, T: KeyType + core::fmt::Debug> kv_system::
         }
     }
 
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut T) {
+        match result {
+            Ok(cursor) => {
+                debug!("Found key: {:?}", key);
+                self.found_key
+                    .set(self.found_key.get() || Some(*key) == self.expected_key.get());
+                self.kv_system.enumerate(cursor, key).unwrap();
+            }
+            Err(ErrorCode::NOSUPPORT) => {
+                debug!("Finished listing the keys");
+                if !self.found_key.get() {
+                    panic!("The key added wasn't listed");
+                }
+
+                // Put the key back, to remove it
+                *key = self.expected_key.get().unwrap();
+                self.kv_system.invalidate_key(key).unwrap();
+            }
+            Err(e) => {
+                panic!("Error listing keys: {:?}", e);
+            }
+        }
+    }
+
     fn garbage_collect_complete(&self, result: Result<(), ErrorCode>) {
         match result {
             Ok(()) => {
                 debug!("Finished garbage collection");
-                debug!("---Finished TicKV Tests---");
+                self.kv_system.statistics().unwrap();
             }
             Err(e) => {
                 panic!("Error running garbage collection: {:?}", e);
             }
         }
     }
+
+    fn statistics_complete(&self, result: Result<Statistics, ErrorCode>) {
+        match result {
+            Ok(statistics) => {
+                debug!("Statistics: {:?}", statistics);
+                self.kv_system.start_transaction().unwrap();
+            }
+            Err(e) => {
+                panic!("Error finding statistics: {:?}", e);
+            }
+        }
+    }
+
+    fn start_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        match result {
+            Ok(()) => {
+                debug!("Started a transaction");
+                self.kv_system.commit_transaction().unwrap();
+            }
+            Err(e) => {
+                panic!("Error starting a transaction: {:?}", e);
+            }
+        }
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        match result {
+            Ok(()) => {
+                debug!("Committed the transaction");
+                debug!("---Finished TicKV Tests---");
+            }
+            Err(e) => {
+                panic!("Error committing the transaction: {:?}", e);
+            }
+        }
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        panic!("The transaction was aborted: {:?}", result);
+    }
 }
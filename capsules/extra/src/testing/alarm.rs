@@ -0,0 +1,102 @@
+//! An alarm whose time only moves forward when the test asks.
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use kernel::hil::time::{Alarm, AlarmClient, Frequency, Ticks, Ticks32, Time};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+/// An alarm counting ticks of frequency `F`.
+pub struct MockAlarm<'a, F: Frequency> {
+    now: Cell<u32>,
+    reference: Cell<u32>,
+    dt: Cell<u32>,
+    armed: Cell<bool>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+    _frequency: PhantomData<F>,
+}
+
+impl<'a, F: Frequency> MockAlarm<'a, F> {
+    pub fn new() -> MockAlarm<'a, F> {
+        MockAlarm {
+            now: Cell::new(0),
+            reference: Cell::new(0),
+            dt: Cell::new(0),
+            armed: Cell::new(false),
+            client: OptionalCell::empty(),
+            _frequency: PhantomData,
+        }
+    }
+
+    /// Ticks until the alarm expires, if it is armed.
+    pub fn remaining(&self) -> Option<u32> {
+        if !self.armed.get() {
+            return None;
+        }
+        let elapsed = self.now.get().wrapping_sub(self.reference.get());
+        Some(self.dt.get().saturating_sub(elapsed))
+    }
+
+    /// Moves time to the expiry of the alarm and fires it, if it is armed.
+    pub fn fire(&self) -> bool {
+        match self.remaining() {
+            Some(remaining) => {
+                self.now.set(self.now.get().wrapping_add(remaining));
+                self.armed.set(false);
+                self.client.map(|client| client.alarm());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Advances time by `ticks`, firing the alarm each time it expires.
+    pub fn advance(&self, ticks: u32) {
+        let end = self.now.get().wrapping_add(ticks);
+        while self.remaining().map_or(false, |remaining| {
+            remaining <= end.wrapping_sub(self.now.get())
+        }) {
+            self.fire();
+        }
+        self.now.set(end);
+    }
+}
+
+impl<F: Frequency> Time for MockAlarm<'_, F> {
+    type Frequency = F;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.now.get())
+    }
+}
+
+impl<'a, F: Frequency> Alarm<'a> for MockAlarm<'a, F> {
+    fn set_alarm_client(&self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.reference.set(reference.into_u32());
+        self.dt.set(dt.into_u32());
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        Ticks32::from(self.reference.get().wrapping_add(self.dt.get()))
+    }
+
+    fn disarm(&self) -> Result<(), ErrorCode> {
+        self.armed.set(false);
+        Ok(())
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        Ticks32::from(1)
+    }
+}
@@ -0,0 +1,151 @@
+//! A stand-in for AES-CCM whose operations complete when the test asks.
+//!
+//! The message is XORed with the key and nonce, and the MIC is a hash of the
+//! key, nonce, associated data and plaintext. Changing any of them makes the
+//! MIC invalid, which is what the users of CCM rely on, but the output is of
+//! course not confidential.
+
+use core::cell::Cell;
+use core::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use kernel::hil::symmetric_encryption::{
+    CCMClient, AES128CCM, AES128_KEY_SIZE, CCM_MIN_NONCE_LENGTH, CCM_NONCE_LENGTH,
+};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+#[derive(Copy, Clone, Default)]
+struct Operation {
+    a_off: usize,
+    m_off: usize,
+    m_len: usize,
+    mic_len: usize,
+    confidential: bool,
+    encrypting: bool,
+}
+
+pub struct MockCCM<'a> {
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+    nonce_len: Cell<usize>,
+    operation: Cell<Operation>,
+    buf: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn CCMClient>,
+}
+
+impl<'a> MockCCM<'a> {
+    pub fn new() -> MockCCM<'a> {
+        MockCCM {
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            nonce: Cell::new([0; CCM_NONCE_LENGTH]),
+            nonce_len: Cell::new(CCM_NONCE_LENGTH),
+            operation: Cell::new(Operation::default()),
+            buf: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn mic(&self, associated_data: &[u8], message: &[u8], mic: &mut [u8]) {
+        let mut hasher = DefaultHasher::new();
+        self.key.get().hash(&mut hasher);
+        self.nonce.get()[..self.nonce_len.get()].hash(&mut hasher);
+        associated_data.hash(&mut hasher);
+        message.hash(&mut hasher);
+        let hash = hasher.finish().to_le_bytes();
+        for (i, b) in mic.iter_mut().enumerate() {
+            *b = hash[i % hash.len()];
+        }
+    }
+
+    fn xor(&self, message: &mut [u8]) {
+        let (key, nonce) = (self.key.get(), self.nonce.get());
+        let nonce = &nonce[..self.nonce_len.get()];
+        for (i, b) in message.iter_mut().enumerate() {
+            *b ^= key[i % key.len()] ^ nonce[i % nonce.len()];
+        }
+    }
+
+    /// Completes the operation in progress, if any, and returns whether there
+    /// was one.
+    pub fn complete(&self) -> bool {
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return false,
+        };
+        let op = self.operation.get();
+        let (head, rest) = buf.split_at_mut(op.m_off);
+        let associated_data = &head[op.a_off..];
+        let (message, rest) = rest.split_at_mut(op.m_len);
+        let received_mic = &mut rest[..op.mic_len];
+
+        let mut mic = [0; 16];
+        let mic = &mut mic[..op.mic_len];
+        let tag_is_valid = if op.encrypting {
+            self.mic(associated_data, message, received_mic);
+            if op.confidential {
+                self.xor(message);
+            }
+            true
+        } else {
+            if op.confidential {
+                self.xor(message);
+            }
+            self.mic(associated_data, message, mic);
+            received_mic == mic
+        };
+        self.client
+            .map(|client| client.crypt_done(buf, Ok(()), tag_is_valid));
+        true
+    }
+}
+
+impl<'a> AES128CCM<'a> for MockCCM<'a> {
+    fn set_client(&'a self, client: &'a dyn CCMClient) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+        self.key.set(key.try_into().map_err(|_| ErrorCode::INVAL)?);
+        Ok(())
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+        if nonce.len() < CCM_MIN_NONCE_LENGTH || nonce.len() > CCM_NONCE_LENGTH {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut new_nonce = [0; CCM_NONCE_LENGTH];
+        new_nonce[..nonce.len()].copy_from_slice(nonce);
+        self.nonce.set(new_nonce);
+        self.nonce_len.set(nonce.len());
+        Ok(())
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        mic_len: usize,
+        confidential: bool,
+        encrypting: bool,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.buf.is_some() {
+            return Err((ErrorCode::BUSY, buf));
+        }
+        if a_off > m_off || m_off + m_len + mic_len > buf.len() || mic_len > 16 {
+            return Err((ErrorCode::INVAL, buf));
+        }
+        self.operation.set(Operation {
+            a_off,
+            m_off,
+            m_len,
+            mic_len,
+            confidential,
+            encrypting,
+        });
+        self.buf.replace(buf);
+        Ok(())
+    }
+}
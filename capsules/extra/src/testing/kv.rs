@@ -0,0 +1,235 @@
+//! A KV system that keeps its records in memory, where tests can read and
+//! change them, and only completes its operations when the test asks.
+//!
+//! Capsules which only need a working KV system are tested over TicKV, with
+//! `testing::tickv`.
+
+use core::cell::{Cell, RefCell};
+use std::vec::Vec;
+
+use kernel::hil::kv_system::{self, KVSystem, Statistics};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    AppendKey,
+    GetValue,
+    InvalidateKey,
+    StartTransaction,
+    CommitTransaction,
+    AbortTransaction,
+    GarbageCollect(usize),
+    Statistics,
+}
+
+/// A KV system with `regions` regions and `free` bytes of free space, as
+/// reported by `statistics()`.
+pub struct MockKV<'a> {
+    regions: usize,
+    free: usize,
+    /// The hashed key and record of each key
+    records: RefCell<Vec<([u8; 8], Vec<u8>)>>,
+    pending: OptionalCell<Op>,
+    result: Cell<Result<(), ErrorCode>>,
+    key: TakeCell<'static, [u8; 8]>,
+    buf: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn kv_system::Client<[u8; 8]>>,
+}
+
+impl<'a> MockKV<'a> {
+    pub fn new(regions: usize, free: usize) -> MockKV<'a> {
+        MockKV {
+            regions,
+            free,
+            records: RefCell::new(Vec::new()),
+            pending: OptionalCell::empty(),
+            result: Cell::new(Ok(())),
+            key: TakeCell::empty(),
+            buf: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// The record stored for `key`.
+    pub fn record(&self, key: [u8; 8]) -> Vec<u8> {
+        self.records
+            .borrow()
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, record)| record.clone())
+            .unwrap()
+    }
+
+    /// Replaces the record stored for `key`.
+    pub fn set_record(&self, key: [u8; 8], record: Vec<u8>) {
+        let mut records = self.records.borrow_mut();
+        records.retain(|(k, _)| *k != key);
+        records.push((key, record));
+    }
+
+    fn start(&self, op: Op, result: Result<(), ErrorCode>) -> Result<(), ErrorCode> {
+        if self.pending.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.pending.set(op);
+        self.result.set(result);
+        Ok(())
+    }
+
+    /// Completes the operation in progress, returning false if there is
+    /// none.
+    pub fn complete(&self) -> bool {
+        let op = match self.pending.take() {
+            Some(op) => op,
+            None => return false,
+        };
+        let result = self.result.get();
+        self.client.map(|client| match op {
+            Op::AppendKey => client.append_key_complete(
+                result,
+                self.key.take().unwrap(),
+                self.buf.take().unwrap(),
+            ),
+            Op::GetValue => client.get_value_complete(
+                result,
+                self.key.take().unwrap(),
+                self.buf.take().unwrap(),
+            ),
+            Op::InvalidateKey => client.invalidate_key_complete(result, self.key.take().unwrap()),
+            Op::StartTransaction => client.start_transaction_complete(result),
+            Op::CommitTransaction => client.commit_transaction_complete(result),
+            Op::AbortTransaction => client.abort_transaction_complete(result),
+            Op::GarbageCollect(_) => client.garbage_collect_complete(result),
+            Op::Statistics => client.statistics_complete(Ok(Statistics {
+                free: self.free,
+                regions: self.regions,
+                ..Statistics::default()
+            })),
+        });
+        true
+    }
+}
+
+impl<'a> KVSystem<'a> for MockKV<'a> {
+    type K = [u8; 8];
+
+    fn set_client(&self, client: &'a dyn kv_system::Client<[u8; 8]>) {
+        self.client.set(client);
+    }
+
+    fn generate_key(
+        &self,
+        unhashed_key: &'static mut [u8],
+        key_buf: &'static mut [u8; 8],
+    ) -> Result<
+        (),
+        (
+            &'static mut [u8],
+            &'static mut [u8; 8],
+            Result<(), ErrorCode>,
+        ),
+    > {
+        Err((unhashed_key, key_buf, Err(ErrorCode::NOSUPPORT)))
+    }
+
+    fn append_key(
+        &self,
+        key: &'static mut [u8; 8],
+        value: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut [u8; 8],
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    > {
+        if let Err(error) = self.start(Op::AppendKey, Ok(())) {
+            return Err((key, value, Err(error)));
+        }
+        self.set_record(*key, value.to_vec());
+        self.key.replace(key);
+        self.buf.replace(value);
+        Ok(())
+    }
+
+    fn get_value(
+        &self,
+        key: &'static mut [u8; 8],
+        ret_buf: &'static mut [u8],
+    ) -> Result<
+        (),
+        (
+            &'static mut [u8; 8],
+            &'static mut [u8],
+            Result<(), ErrorCode>,
+        ),
+    > {
+        let result = match self.records.borrow().iter().find(|(k, _)| k == key) {
+            Some((_, record)) => {
+                let length = record.len().min(ret_buf.len());
+                ret_buf[..length].copy_from_slice(&record[..length]);
+                Ok(())
+            }
+            None => Err(ErrorCode::NOSUPPORT),
+        };
+        if let Err(error) = self.start(Op::GetValue, result) {
+            return Err((key, ret_buf, Err(error)));
+        }
+        self.key.replace(key);
+        self.buf.replace(ret_buf);
+        Ok(())
+    }
+
+    fn invalidate_key(
+        &self,
+        key: &'static mut [u8; 8],
+    ) -> Result<(), (&'static mut [u8; 8], Result<(), ErrorCode>)> {
+        let index = self.records.borrow().iter().position(|(k, _)| k == key);
+        let result = index.map(|_| ()).ok_or(ErrorCode::NOSUPPORT);
+        if let Err(error) = self.start(Op::InvalidateKey, result) {
+            return Err((key, Err(error)));
+        }
+        if let Some(index) = index {
+            self.records.borrow_mut().remove(index);
+        }
+        self.key.replace(key);
+        Ok(())
+    }
+
+    fn garbage_collect(&self) -> Result<usize, Result<(), ErrorCode>> {
+        Err(Err(ErrorCode::NOSUPPORT))
+    }
+
+    fn garbage_collect_region(&self, region: usize) -> Result<(), ErrorCode> {
+        if region >= self.regions {
+            return Err(ErrorCode::INVAL);
+        }
+        self.start(Op::GarbageCollect(region), Ok(()))
+    }
+
+    fn statistics(&self) -> Result<(), ErrorCode> {
+        self.start(Op::Statistics, Ok(()))
+    }
+
+    fn enumerate(
+        &self,
+        _cursor: usize,
+        key: &'static mut [u8; 8],
+    ) -> Result<(), (&'static mut [u8; 8], Result<(), ErrorCode>)> {
+        Err((key, Err(ErrorCode::NOSUPPORT)))
+    }
+
+    fn start_transaction(&self) -> Result<(), ErrorCode> {
+        self.start(Op::StartTransaction, Ok(()))
+    }
+
+    fn commit_transaction(&self) -> Result<(), ErrorCode> {
+        self.start(Op::CommitTransaction, Ok(()))
+    }
+
+    fn abort_transaction(&self) -> Result<(), ErrorCode> {
+        self.start(Op::AbortTransaction, Ok(()))
+    }
+}
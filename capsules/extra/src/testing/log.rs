@@ -0,0 +1,121 @@
+//! A `Log` over a `SimFlash`, so that the capsules built on a log are tested
+//! over the log itself.
+
+use core::cell::Cell;
+use std::boxed::Box;
+use std::vec;
+
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+    DynamicDeferredCallClientState,
+};
+use kernel::hil::flash;
+use kernel::hil::log::{LogWrite, LogWriteClient};
+use kernel::ErrorCode;
+
+use crate::log::Log;
+use crate::sim_flash::{SimFlash, SimPage};
+
+pub type FlashLog<const S: usize> =
+    Log<'static, SimFlash<'static, S>, &'static SimFlash<'static, S>>;
+
+/// A log over a flash with `S` byte pages.
+pub struct SimLog<const S: usize> {
+    pub flash: &'static SimFlash<'static, S>,
+    pub log: &'static FlashLog<S>,
+    handle: DeferredCallHandle,
+}
+
+impl<const S: usize> SimLog<S> {
+    /// A log over `pages` erased pages.
+    pub fn new(pages: usize, circular: bool) -> SimLog<S> {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let storage = Box::leak(vec![0xFF; pages * S].into_boxed_slice());
+        let flash = Box::leak(Box::new(SimFlash::new(storage, ddc)));
+        flash.initialise(ddc.register(flash).unwrap());
+        // A log writes pages again without erasing them, so it needs a flash
+        // that erases as it writes.
+        flash.set_erase_on_write(true);
+        SimLog::start(flash, circular)
+    }
+
+    /// A new log over what is stored in the flash, as after a reboot.
+    pub fn restart(&self, circular: bool) -> SimLog<S> {
+        self.flash.power_on();
+        SimLog::start(self.flash, circular)
+    }
+
+    fn start(flash: &'static SimFlash<'static, S>, circular: bool) -> SimLog<S> {
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let pagebuffer = Box::leak(Box::new(SimPage::default()));
+        let log = Box::leak(Box::new(Log::new(flash, flash, pagebuffer, ddc, circular)));
+        flash::HasClient::set_client(flash, log);
+        let handle = ddc.register(log).unwrap();
+        log.initialize_callback_handle(handle);
+        SimLog { flash, log, handle }
+    }
+
+    /// Appends an entry through a new log over what is stored in the flash,
+    /// as if it had been written by someone else before a reboot, and
+    /// returns that log.
+    pub fn push(&self, data: &[u8], circular: bool) -> SimLog<S> {
+        let log = self.restart(circular);
+        let writer = Box::leak(Box::new(Writer {
+            result: Cell::new(None),
+        }));
+        log.log.set_append_client(writer);
+        let buffer = Box::leak(data.to_vec().into_boxed_slice());
+        assert!(log.log.append(buffer, data.len()).is_ok());
+        log.run();
+        assert_eq!(writer.result.take(), Some(Ok(())));
+        assert!(log.log.sync().is_ok());
+        log.run();
+        assert_eq!(writer.result.take(), Some(Ok(())));
+        log
+    }
+
+    /// Completes the operations of the flash and the deferred callbacks of
+    /// the log, including those started by clients as others complete,
+    /// until there are none left.
+    pub fn run(&self) {
+        loop {
+            self.flash.run();
+            // Once the flash has completed what it can, a busy log waits for
+            // its deferred callback, unless power was lost and the operation
+            // never completes.
+            if !self.log.busy() || !self.flash.powered() {
+                break;
+            }
+            self.log.call(self.handle);
+        }
+    }
+}
+
+/// Keeps the result of the last append or sync.
+struct Writer {
+    result: Cell<Option<Result<(), ErrorCode>>>,
+}
+
+impl LogWriteClient for Writer {
+    fn append_done(
+        &self,
+        _buffer: &'static mut [u8],
+        _length: usize,
+        _records_lost: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.result.set(Some(result));
+    }
+
+    fn sync_done(&self, result: Result<(), ErrorCode>) {
+        self.result.set(Some(result));
+    }
+
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        self.result.set(Some(result));
+    }
+}
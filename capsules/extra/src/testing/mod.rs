@@ -0,0 +1,9 @@
+//! Fakes of kernel interfaces shared by the tests of several capsules.
+
+pub mod alarm;
+pub mod ccm;
+pub mod kv;
+pub mod log;
+pub mod rng;
+pub mod tickv;
+pub mod udp;
@@ -0,0 +1,60 @@
+//! A random number generator that counts up, and only delivers numbers when
+//! the test asks.
+
+use core::cell::Cell;
+use core::iter;
+
+use kernel::hil::rng::{Client, Continue, Rng};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+pub struct MockRng<'a> {
+    next: Cell<u32>,
+    requested: Cell<bool>,
+    client: OptionalCell<&'a dyn Client>,
+}
+
+impl<'a> MockRng<'a> {
+    pub fn new() -> MockRng<'a> {
+        MockRng {
+            next: Cell::new(0),
+            requested: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Delivers numbers to the client if it requested some, and returns
+    /// whether it did.
+    pub fn supply(&self) -> bool {
+        if !self.requested.replace(false) {
+            return false;
+        }
+        let mut numbers = iter::from_fn(|| {
+            let number = self.next.get();
+            self.next.set(number.wrapping_add(1));
+            Some(number)
+        });
+        self.client.map(|client| {
+            if let Continue::More = client.randomness_available(&mut numbers, Ok(())) {
+                self.requested.set(true);
+            }
+        });
+        true
+    }
+}
+
+impl<'a> Rng<'a> for MockRng<'a> {
+    fn get(&self) -> Result<(), ErrorCode> {
+        self.requested.set(true);
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ErrorCode> {
+        self.requested.set(false);
+        Ok(())
+    }
+
+    fn set_client(&'a self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+}
@@ -0,0 +1,145 @@
+//! A `TicKVStore` over a `SimFlash`, so that the capsules built on a KV
+//! system are tested over TicKV itself.
+
+use core::cell::Cell;
+use std::boxed::Box;
+use std::vec;
+
+use kernel::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::hil::flash;
+use kernel::hil::kv_system::{self, KVSystem, Statistics};
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
+
+use crate::sim_flash::{SimFlash, SimPage};
+use crate::sip_hash::SipHasher24;
+use crate::tickv::{TicKVKeyType, TicKVStore};
+
+/// The size of a region of TicKV, which is one page of the flash.
+pub const REGION_SIZE: usize = 2048;
+
+pub type SimTicKV = TicKVStore<'static, SimFlash<'static, REGION_SIZE>, SipHasher24<'static>>;
+
+/// A flash of `regions` erased regions.
+pub fn new_flash(regions: usize) -> &'static SimFlash<'static, REGION_SIZE> {
+    let states: &'static [DynamicDeferredCallClientState] =
+        Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+    let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+    let storage = Box::leak(vec![0xFF; regions * REGION_SIZE].into_boxed_slice());
+    let flash = Box::leak(Box::new(SimFlash::new(storage, ddc)));
+    flash.initialise(ddc.register(flash).unwrap());
+    flash
+}
+
+/// Starts TicKV over what is stored in `flash`, as after a reset.
+pub fn start(
+    flash: &'static SimFlash<'static, REGION_SIZE>,
+    client: &'static dyn kv_system::Client<TicKVKeyType>,
+) -> &'static SimTicKV {
+    let states: &'static [DynamicDeferredCallClientState] =
+        Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+    let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+    let hasher = Box::leak(Box::new(SipHasher24::new(ddc)));
+    let store = Box::leak(Box::new(TicKVStore::new(
+        flash,
+        hasher,
+        Box::leak(Box::new([0; REGION_SIZE])),
+        Box::leak(Box::new(SimPage::default())),
+        0,
+        flash.pages() * REGION_SIZE,
+    )));
+    flash::HasClient::set_client(flash, store);
+    store.set_client(client);
+
+    store.initialise();
+    flash.run();
+    store
+}
+
+/// Keeps the result of the last operation, and the buffers given back.
+pub struct Client {
+    pub result: Cell<Option<Result<(), ErrorCode>>>,
+    pub key: TakeCell<'static, TicKVKeyType>,
+    pub value: TakeCell<'static, [u8]>,
+}
+
+impl Client {
+    pub fn new() -> &'static Client {
+        Box::leak(Box::new(Client {
+            result: Cell::new(None),
+            key: TakeCell::new(Box::leak(Box::new([0; 8]))),
+            value: TakeCell::new(Box::leak(vec![0; 16].into_boxed_slice())),
+        }))
+    }
+
+    fn done(&self, result: Result<(), ErrorCode>) {
+        assert!(self.result.replace(Some(result)).is_none());
+    }
+}
+
+impl kv_system::Client<TicKVKeyType> for Client {
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: &'static mut [u8],
+        _key_buf: &'static mut TicKVKeyType,
+    ) {
+        unreachable!()
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut TicKVKeyType,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.value.replace(value);
+        self.done(result);
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut TicKVKeyType,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.value.replace(ret_buf);
+        self.done(result);
+    }
+
+    fn invalidate_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut TicKVKeyType,
+    ) {
+        self.key.replace(key);
+        self.done(result);
+    }
+
+    fn garbage_collect_complete(&self, result: Result<(), ErrorCode>) {
+        self.done(result);
+    }
+
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut TicKVKeyType) {
+        self.key.replace(key);
+        self.done(result.map(|_| ()));
+    }
+
+    fn statistics_complete(&self, result: Result<Statistics, ErrorCode>) {
+        self.done(result.map(|_| ()));
+    }
+
+    fn start_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        self.done(result);
+    }
+
+    fn commit_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        self.done(result);
+    }
+
+    fn abort_transaction_complete(&self, result: Result<(), ErrorCode>) {
+        self.done(result);
+    }
+}
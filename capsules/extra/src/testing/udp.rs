@@ -0,0 +1,124 @@
+//! A UDP sender which logs every datagram sent, and only completes sending
+//! when the test asks.
+
+use core::cell::{Cell, RefCell};
+use std::vec::Vec;
+
+use kernel::capabilities::UdpDriverCapability;
+use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::leasable_buffer::LeasableMutableBuffer;
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortBindingTx;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use crate::net::udp::UDPHeader;
+
+/// Sends one datagram at a time through `send_to()`.
+pub struct MockUdpSender {
+    sending: RefCell<Option<LeasableMutableBuffer<'static, u8>>>,
+    /// The destination and payload of each datagram sent
+    log: RefCell<Vec<(IPAddr, u16, Vec<u8>)>>,
+    /// Whether the next datagram is lost
+    lose: Cell<bool>,
+    client: OptionalCell<&'static dyn UDPSendClient>,
+}
+
+impl MockUdpSender {
+    pub fn new() -> MockUdpSender {
+        MockUdpSender {
+            sending: RefCell::new(None),
+            log: RefCell::new(Vec::new()),
+            lose: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Completes sending the datagram in flight, if any, and returns it
+    /// unless it is lost.
+    pub fn complete(&self) -> Option<Option<Vec<u8>>> {
+        let dgram = self.sending.borrow_mut().take()?;
+        let payload = dgram[..].to_vec();
+        self.client.map(|client| client.send_done(Ok(()), dgram));
+        Some(Some(payload).filter(|_| !self.lose.replace(false)))
+    }
+
+    /// Loses the next datagram sent.
+    pub fn lose_next(&self) {
+        self.lose.set(true);
+    }
+
+    /// The number of datagrams sent.
+    pub fn sent(&self) -> usize {
+        self.log.borrow().len()
+    }
+
+    /// The payload of the last datagram sent.
+    pub fn last_sent(&self) -> Vec<u8> {
+        self.log.borrow().last().unwrap().2.clone()
+    }
+
+    /// The address and port the last datagram was sent to.
+    pub fn last_destination(&self) -> (IPAddr, u16) {
+        let log = self.log.borrow();
+        let (dest, dst_port, _) = log.last().unwrap();
+        (*dest, *dst_port)
+    }
+}
+
+impl UDPSender<'static> for MockUdpSender {
+    fn set_client(&self, client: &'static dyn UDPSendClient) {
+        self.client.set(client);
+    }
+
+    fn send_to(
+        &'static self,
+        dest: IPAddr,
+        dst_port: u16,
+        buf: LeasableMutableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableMutableBuffer<'static, u8>> {
+        if self.sending.borrow().is_some() {
+            return Err(buf);
+        }
+        self.log
+            .borrow_mut()
+            .push((dest, dst_port, buf[..].to_vec()));
+        *self.sending.borrow_mut() = Some(buf);
+        Ok(())
+    }
+
+    fn driver_send_to(
+        &'static self,
+        _dest: IPAddr,
+        _dst_port: u16,
+        _src_port: u16,
+        buf: LeasableMutableBuffer<'static, u8>,
+        _driver_send_cap: &dyn UdpDriverCapability,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableMutableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn send(
+        &'static self,
+        _dest: IPAddr,
+        _udp_header: UDPHeader,
+        buf: LeasableMutableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableMutableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn get_binding(&self) -> Option<UdpPortBindingTx> {
+        None
+    }
+
+    fn is_bound(&self) -> bool {
+        true
+    }
+
+    fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+        Some(binding)
+    }
+}
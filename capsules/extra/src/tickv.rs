@@ -49,8 +49,17 @@ enum Operation {
     AppendKey,
     InvalidateKey,
     GarbageCollect,
+    GarbageCollectRegion(usize),
+    Enumerate,
+    Statistics,
+    StartTransaction,
+    CommitTransaction,
+    AbortTransaction,
 }
 
+/// The hash of `tickv::MAIN_KEY`, which is stored with the other keys.
+const MAIN_KEY_HASH: u64 = 0x7bc9f7ff4f76f244;
+
 pub struct TickFSFlashCtrl<'a, F: Flash + 'static> {
     flash: &'a F,
     flash_read_buffer: TakeCell<'static, F::Page>,
@@ -111,6 +120,9 @@ impl<'a, F: Flash> tickv::flash_controller::FlashController<2048> for TickFSFlas
     }
 
     fn erase_region(&self, region_number: usize) -> Result<(), tickv::error_codes::ErrorCode> {
+        // Writes overlay the page buffer, which now has to match the erased
+        // page
+        self.flash_read_buffer.map(|buf| buf.as_mut().fill(0xFF));
         let _ = self.flash.erase_page(self.region_offset + region_number);
 
         Err(tickv::error_codes::ErrorCode::EraseNotReady(region_number))
@@ -122,6 +134,8 @@ pub type TicKVKeyType = [u8; 8];
 pub struct TicKVStore<'a, F: Flash + 'static, H: Hasher<'a, 8>> {
     tickv: AsyncTicKV<'a, TickFSFlashCtrl<'a, F>, 2048>,
     hasher: &'a H,
+    /// The number of flash pages TicKV is stored in
+    regions: usize,
     operation: Cell<Operation>,
     next_operation: Cell<Operation>,
 
@@ -130,6 +144,9 @@ pub struct TicKVStore<'a, F: Flash + 'static, H: Hasher<'a, 8>> {
     ret_buffer: TakeCell<'static, [u8]>,
     unhashed_key_buf: TakeCell<'static, [u8]>,
     key_buf: TakeCell<'static, [u8; 8]>,
+    cursor: Cell<usize>,
+    /// Set when the operation continues after the write in progress
+    write_continues: Cell<bool>,
 
     client: OptionalCell<&'a dyn kv_system::Client<TicKVKeyType>>,
 }
@@ -152,6 +169,7 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> TicKVStore<'a, F, H> {
         Self {
             tickv,
             hasher,
+            regions: flash_size / 2048,
             operation: Cell::new(Operation::None),
             next_operation: Cell::new(Operation::None),
             value_buffer: Cell::new(None),
@@ -159,12 +177,14 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> TicKVStore<'a, F, H> {
             ret_buffer: TakeCell::empty(),
             unhashed_key_buf: TakeCell::empty(),
             key_buf: TakeCell::empty(),
+            cursor: Cell::new(0),
+            write_continues: Cell::new(false),
             client: OptionalCell::empty(),
         }
     }
 
     pub fn initialise(&self) {
-        let _ret = self.tickv.initialise(0x7bc9f7ff4f76f244);
+        let _ret = self.tickv.initialise(MAIN_KEY_HASH);
         self.operation.set(Operation::Init);
     }
 
@@ -216,9 +236,213 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> TicKVStore<'a, F, H> {
                 }
                 _ => {}
             },
+            Operation::GarbageCollectRegion(region) => {
+                if let Err(error) = self.garbage_collect_region(region) {
+                    self.client.map(move |cb| {
+                        cb.garbage_collect_complete(Err(error));
+                    });
+                }
+            }
+            Operation::Enumerate => {
+                match self.enumerate(self.cursor.get(), self.key_buffer.take().unwrap()) {
+                    Err((key, error)) => {
+                        self.client.map(move |cb| {
+                            cb.enumerate_complete(error.and(Err(ErrorCode::FAIL)), key);
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Operation::Statistics => {
+                if let Err(error) = self.statistics() {
+                    self.client.map(move |cb| {
+                        cb.statistics_complete(Err(error));
+                    });
+                }
+            }
+            Operation::StartTransaction => {
+                if let Err(error) = self.start_transaction() {
+                    self.client.map(move |cb| {
+                        cb.start_transaction_complete(Err(error));
+                    });
+                }
+            }
+            Operation::CommitTransaction => {
+                if let Err(error) = self.commit_transaction() {
+                    self.client.map(move |cb| {
+                        cb.commit_transaction_complete(Err(error));
+                    });
+                }
+            }
+            Operation::AbortTransaction => {
+                if let Err(error) = self.abort_transaction() {
+                    self.client.map(move |cb| {
+                        cb.abort_transaction_complete(Err(error));
+                    });
+                }
+            }
         }
         self.next_operation.set(Operation::None);
     }
+
+    /// Start `operation` on the transaction, by calling `op`.
+    fn start_transaction_operation(
+        &self,
+        operation: Operation,
+        op: impl FnOnce() -> Result<tickv::success_codes::SuccessCode, tickv::error_codes::ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(operation);
+
+                match op() {
+                    Ok(_)
+                    | Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                    | Err(tickv::error_codes::ErrorCode::EraseNotReady(_)) => Ok(()),
+                    Err(tickv::error_codes::ErrorCode::WriteNotReady(_)) => {
+                        self.write_continues.set(true);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.operation.set(Operation::None);
+                        match e {
+                            tickv::error_codes::ErrorCode::TransactionInProgress => {
+                                Err(ErrorCode::ALREADY)
+                            }
+                            tickv::error_codes::ErrorCode::NoTransaction => Err(ErrorCode::INVAL),
+                            _ => Err(ErrorCode::FAIL),
+                        }
+                    }
+                }
+            }
+            Operation::Init => {
+                // The init process is still occurring.
+                // We can save this request and start it after init
+                self.next_operation.set(operation);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err(ErrorCode::BUSY)
+            }
+        }
+    }
+
+    /// Handle the result of continuing an operation that can write more than
+    /// once. That is initialising, which can recover a transaction, and
+    /// starting, committing or aborting a transaction.
+    fn transaction_step(
+        &self,
+        ret: Result<tickv::success_codes::SuccessCode, tickv::error_codes::ErrorCode>,
+    ) {
+        match ret {
+            Err(tickv::error_codes::ErrorCode::WriteNotReady(_)) => {
+                // Continue once the write completes
+                self.write_continues.set(true);
+            }
+            // Queued writes complete the operation in `write_complete()`
+            Ok(tickv::success_codes::SuccessCode::Queued)
+            | Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+            | Err(tickv::error_codes::ErrorCode::EraseNotReady(_)) => {}
+            Ok(_) => self.transaction_complete(Ok(())),
+            Err(_) => self.transaction_complete(Err(ErrorCode::FAIL)),
+        }
+    }
+
+    fn transaction_complete(&self, result: Result<(), ErrorCode>) {
+        let operation = self.operation.get();
+        self.operation.set(Operation::None);
+
+        match operation {
+            Operation::Init => self.complete_init(),
+            Operation::StartTransaction => {
+                self.client.map(|cb| {
+                    cb.start_transaction_complete(result);
+                });
+            }
+            Operation::CommitTransaction => {
+                self.client.map(|cb| {
+                    cb.commit_transaction_complete(result);
+                });
+            }
+            Operation::AbortTransaction => {
+                self.client.map(|cb| {
+                    cb.abort_transaction_complete(result);
+                });
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Handle the result of continuing a garbage collection, which reads,
+    /// erases and writes.
+    fn garbage_collect_step(
+        &self,
+        ret: Result<tickv::success_codes::SuccessCode, tickv::error_codes::ErrorCode>,
+    ) {
+        let result = match ret {
+            Ok(_) => Ok(()),
+            Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+            | Err(tickv::error_codes::ErrorCode::WriteNotReady(_))
+            | Err(tickv::error_codes::ErrorCode::EraseNotReady(_)) => return,
+            Err(_) => Err(ErrorCode::FAIL),
+        };
+
+        self.operation.set(Operation::None);
+        self.client.map(|cb| {
+            cb.garbage_collect_complete(result);
+        });
+    }
+
+    fn complete_statistics(&self, result: Result<tickv::Statistics, ErrorCode>) {
+        self.operation.set(Operation::None);
+
+        let result = result.map(|statistics| kv_system::Statistics {
+            free: statistics.free,
+            used: statistics.used,
+            invalid: statistics.invalid,
+            regions: self.regions,
+            min_erase_count: statistics.min_erase_count,
+            max_erase_count: statistics.max_erase_count,
+        });
+        self.client.map(|cb| {
+            cb.statistics_complete(result);
+        });
+    }
+
+    /// Continue enumerating from `cursor`, after the main key was found.
+    fn continue_enumerate(&self, cursor: usize) {
+        match self.tickv.next_key(cursor) {
+            Ok(entry) => self.key_found(entry),
+            Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => {}
+            Err(e) => self.complete_enumerate(Err(e)),
+        }
+    }
+
+    fn key_found(&self, entry: tickv::tickv::KeyEntry) {
+        if entry.hashed_key == MAIN_KEY_HASH {
+            // The main key is internal to TicKV, skip it
+            self.continue_enumerate(entry.next);
+        } else {
+            self.key_buffer.map(|key| {
+                *key = entry.hashed_key.to_le_bytes();
+            });
+            self.complete_enumerate(Ok(entry.next));
+        }
+    }
+
+    fn complete_enumerate(&self, result: Result<usize, tickv::error_codes::ErrorCode>) {
+        self.operation.set(Operation::None);
+
+        let result = match result {
+            Ok(cursor) => Ok(cursor),
+            Err(tickv::error_codes::ErrorCode::KeyNotFound) => Err(ErrorCode::NOSUPPORT),
+            Err(_) => Err(ErrorCode::FAIL),
+        };
+        self.client.map(|cb| {
+            cb.enumerate_complete(result, self.key_buffer.take().unwrap());
+        });
+    }
 }
 
 impl<'a, F: Flash, H: Hasher<'a, 8>> hasher::Client<8> for TicKVStore<'a, F, H> {
@@ -253,13 +477,10 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> flash::Client<F> for TicKVStore<'a, F, H> {
         });
 
         match self.operation.get() {
-            Operation::Init => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
-                    self.operation.set(Operation::None)
-                }
-                _ => {}
-            },
+            Operation::Init
+            | Operation::StartTransaction
+            | Operation::CommitTransaction
+            | Operation::AbortTransaction => self.transaction_step(ret),
             Operation::GetKey => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
@@ -289,24 +510,58 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> flash::Client<F> for TicKVStore<'a, F, H> {
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
+                Ok(_)
+                | Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::WriteNotReady(_)) => {}
+                Err(_) => {
+                    // For example the key already exists
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.append_key_complete(
+                            Err(ErrorCode::FAIL),
+                            self.key_buffer.take().unwrap(),
+                            self.tickv.get_stored_value_buffer().unwrap(),
+                        );
+                    });
+                }
             },
             Operation::InvalidateKey => match ret {
                 Ok(tickv::success_codes::SuccessCode::Complete)
                 | Ok(tickv::success_codes::SuccessCode::Written) => {
                     self.operation.set(Operation::None);
                 }
-                _ => {}
-            },
-            Operation::GarbageCollect => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
+                Ok(_)
+                | Err(tickv::error_codes::ErrorCode::ReadNotReady(_))
+                | Err(tickv::error_codes::ErrorCode::WriteNotReady(_)) => {}
+                Err(_) => {
+                    // For example the key doesn't exist
                     self.operation.set(Operation::None);
                     self.client.map(|cb| {
-                        cb.garbage_collect_complete(Ok(()));
+                        cb.invalidate_key_complete(
+                            Err(ErrorCode::FAIL),
+                            self.key_buffer.take().unwrap(),
+                        );
                     });
                 }
-                _ => {}
+            },
+            Operation::GarbageCollect | Operation::GarbageCollectRegion(_) => {
+                self.garbage_collect_step(ret)
+            }
+            Operation::Enumerate => match ret {
+                Ok(_) => {
+                    let entry = self.tickv.get_stored_key_entry().unwrap();
+                    self.key_found(entry);
+                }
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => {}
+                Err(e) => self.complete_enumerate(Err(e)),
+            },
+            Operation::Statistics => match ret {
+                Ok(_) => {
+                    let statistics = self.tickv.get_stored_statistics().unwrap();
+                    self.complete_statistics(Ok(statistics));
+                }
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => {}
+                Err(_) => self.complete_statistics(Err(ErrorCode::FAIL)),
             },
             _ => unreachable!(),
         }
@@ -320,8 +575,16 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> flash::Client<F> for TicKVStore<'a, F, H> {
             .replace(pagebuffer);
 
         match self.operation.get() {
-            Operation::Init => {
-                self.complete_init();
+            Operation::Init
+            | Operation::StartTransaction
+            | Operation::CommitTransaction
+            | Operation::AbortTransaction => {
+                if self.write_continues.take() {
+                    let (ret, _buf_buffer) = self.tickv.continue_operation();
+                    self.transaction_step(ret);
+                } else {
+                    self.transaction_complete(Ok(()));
+                }
             }
             Operation::AppendKey => {
                 self.operation.set(Operation::None);
@@ -339,6 +602,11 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> flash::Client<F> for TicKVStore<'a, F, H> {
                     cb.invalidate_key_complete(Ok(()), self.key_buffer.take().unwrap());
                 });
             }
+            Operation::GarbageCollect | Operation::GarbageCollectRegion(_) => {
+                // Continue after writing the header of an erased region
+                let (ret, _buf_buffer) = self.tickv.continue_operation();
+                self.garbage_collect_step(ret);
+            }
             _ => unreachable!(),
         }
     }
@@ -358,16 +626,9 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> flash::Client<F> for TicKVStore<'a, F, H> {
                 }
                 _ => {}
             },
-            Operation::GarbageCollect => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
-                    self.operation.set(Operation::None);
-                    self.client.map(|cb| {
-                        cb.garbage_collect_complete(Ok(()));
-                    });
-                }
-                _ => {}
-            },
+            Operation::GarbageCollect | Operation::GarbageCollectRegion(_) => {
+                self.garbage_collect_step(ret)
+            }
             _ => unreachable!(),
         }
     }
@@ -559,4 +820,203 @@ impl<'a, F: Flash, H: Hasher<'a, 8>> KVSystem<'a> for TicKVStore<'a, F, H> {
             }
         }
     }
+
+    fn garbage_collect_region(&self, region: usize) -> Result<(), ErrorCode> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::GarbageCollectRegion(region));
+
+                // Reads from flash always complete in `read_complete()`
+                match self.tickv.garbage_collect_region(region) {
+                    Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => Ok(()),
+                    Err(tickv::error_codes::ErrorCode::RegionNotFound) => {
+                        self.operation.set(Operation::None);
+                        Err(ErrorCode::INVAL)
+                    }
+                    _ => {
+                        self.operation.set(Operation::None);
+                        Err(ErrorCode::FAIL)
+                    }
+                }
+            }
+            Operation::Init => {
+                // The init process is still occurring.
+                // We can save this request and start it after init
+                self.next_operation
+                    .set(Operation::GarbageCollectRegion(region));
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err(ErrorCode::BUSY)
+            }
+        }
+    }
+
+    fn statistics(&self) -> Result<(), ErrorCode> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::Statistics);
+
+                // Reads from flash always complete in `read_complete()`
+                match self.tickv.statistics() {
+                    Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => Ok(()),
+                    _ => {
+                        self.operation.set(Operation::None);
+                        Err(ErrorCode::FAIL)
+                    }
+                }
+            }
+            Operation::Init => {
+                // The init process is still occurring.
+                // We can save this request and start it after init
+                self.next_operation.set(Operation::Statistics);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err(ErrorCode::BUSY)
+            }
+        }
+    }
+
+    fn enumerate(
+        &self,
+        cursor: usize,
+        key: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, Result<(), ErrorCode>)> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::Enumerate);
+
+                // Reads from flash always complete in `read_complete()`
+                match self.tickv.next_key(cursor) {
+                    Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => {
+                        self.key_buffer.replace(key);
+                        Ok(())
+                    }
+                    _ => {
+                        self.operation.set(Operation::None);
+                        Err((key, Err(ErrorCode::FAIL)))
+                    }
+                }
+            }
+            Operation::Init => {
+                // The init process is still occurring.
+                // We can save this request and start it after init
+                self.next_operation.set(Operation::Enumerate);
+                self.key_buffer.replace(key);
+                self.cursor.set(cursor);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err((key, Err(ErrorCode::BUSY)))
+            }
+        }
+    }
+
+    fn start_transaction(&self) -> Result<(), ErrorCode> {
+        self.start_transaction_operation(Operation::StartTransaction, || {
+            self.tickv.start_transaction()
+        })
+    }
+
+    fn commit_transaction(&self) -> Result<(), ErrorCode> {
+        self.start_transaction_operation(Operation::CommitTransaction, || {
+            self.tickv.commit_transaction()
+        })
+    }
+
+    fn abort_transaction(&self) -> Result<(), ErrorCode> {
+        self.start_transaction_operation(Operation::AbortTransaction, || {
+            self.tickv.abort_transaction()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_flash::{Fault, SimFlash};
+    use crate::testing::tickv::{self, new_flash, Client, SimTicKV, REGION_SIZE};
+
+    const REGIONS: usize = 4;
+
+    fn start(
+        flash: &'static SimFlash<'static, REGION_SIZE>,
+    ) -> (&'static SimTicKV, &'static Client) {
+        let client = Client::new();
+        (tickv::start(flash, client), client)
+    }
+
+    fn append(store: &SimTicKV, client: &Client, key: u8, value: u8) -> Result<(), ErrorCode> {
+        let key_buf = client.key.take().unwrap();
+        *key_buf = [key; 8];
+        let value_buf = client.value.take().unwrap();
+        value_buf.fill(value);
+        store
+            .append_key(key_buf, value_buf)
+            .map_err(|(key, value, e)| {
+                client.key.replace(key);
+                client.value.replace(value);
+                e.unwrap_err()
+            })?;
+        store.tickv.tickv.controller.flash.run();
+        client.result.take().unwrap()
+    }
+
+    fn get(store: &SimTicKV, client: &Client, key: u8) -> Result<u8, ErrorCode> {
+        let key_buf = client.key.take().unwrap();
+        *key_buf = [key; 8];
+        store
+            .get_value(key_buf, client.value.take().unwrap())
+            .map_err(|(key, value, e)| {
+                client.key.replace(key);
+                client.value.replace(value);
+                e.unwrap_err()
+            })?;
+        store.tickv.tickv.controller.flash.run();
+        client.result.take().unwrap()?;
+        client.value.map(|value| value[0]).ok_or(ErrorCode::FAIL)
+    }
+
+    #[test]
+    fn stores_keys_in_flash() {
+        let flash = new_flash(REGIONS);
+        let (store, client) = start(flash);
+        assert_eq!(append(store, client, 1, 0x11), Ok(()));
+        assert_eq!(append(store, client, 2, 0x22), Ok(()));
+        assert_eq!(get(store, client, 1), Ok(0x11));
+
+        // The keys are still there after a reset
+        let (store, client) = start(flash);
+        assert_eq!(get(store, client, 2), Ok(0x22));
+        assert!(get(store, client, 3).is_err());
+    }
+
+    #[test]
+    fn survives_torn_append() {
+        let flash = new_flash(REGIONS);
+        let (store, client) = start(flash);
+        assert_eq!(append(store, client, 1, 0x11), Ok(()));
+
+        // Power is lost part of the way through writing the next key
+        flash.inject(Fault::TornWrite(20), 0);
+        let key_buf = client.key.take().unwrap();
+        *key_buf = [2; 8];
+        assert!(store
+            .append_key(key_buf, client.value.take().unwrap())
+            .is_ok());
+        flash.run();
+        assert!(!flash.powered());
+
+        flash.power_on();
+        let (store, client) = start(flash);
+        assert_eq!(get(store, client, 1), Ok(0x11));
+        assert!(get(store, client, 2).is_err());
+        // The torn key can't be read, but other keys can still be added
+        assert_eq!(append(store, client, 3, 0x33), Ok(()));
+        assert_eq!(get(store, client, 3), Ok(0x33));
+    }
 }
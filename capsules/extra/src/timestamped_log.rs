@@ -0,0 +1,473 @@
+//! Records when each entry of a log was appended, and optionally compresses
+//! entries.
+//!
+//! `TimestampedLog` sits between a log, such as `capsules_extra::log::Log`,
+//! and its clients, and implements the same `LogRead` and `LogWrite`
+//! interfaces. Each entry appended through it gets a header with the time
+//! since boot, in microseconds, and its sequence number: the number of entries
+//! appended since boot. If compression is enabled, the payload of an entry is
+//! compressed when that makes it shorter. The format is that of
+//! `tock_log_format::entry`, so `tools/log-export` can read the log from an
+//! image of its flash volume.
+//!
+//! Reading gives the payloads of entries, decompressed, and the header of the
+//! last entry read is available from `last_header()`. Reading an entry which is
+//! not in this format fails with `FAIL`.
+//!
+//! The time is tracked from the ticks of a `Time` between appends, so entries
+//! must be appended at least once per wrap-around of its counter for the
+//! timestamps to be monotonic.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let timestamped_log = static_init!(
+//!     capsules_extra::timestamped_log::TimestampedLog<'static, Log, Rtc>,
+//!     capsules_extra::timestamped_log::TimestampedLog::new(
+//!         log,
+//!         &peripherals.rtc,
+//!         &mut ENTRY_BUFFER,
+//!         true,
+//!     )
+//! );
+//! log.set_read_client(timestamped_log);
+//! log.set_append_client(timestamped_log);
+//! timestamped_log.set_read_client(log_reader);
+//! timestamped_log.set_append_client(log_writer);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::hil::time::{Frequency, Ticks, Time};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+pub use tock_log_format::entry::HEADER_SIZE;
+use tock_log_format::entry::{self, Header};
+
+pub struct TimestampedLog<'a, L: LogRead<'a> + LogWrite<'a>, T: Time> {
+    log: &'a L,
+    time: &'a T,
+    read_client: OptionalCell<&'a dyn LogReadClient>,
+    append_client: OptionalCell<&'a dyn LogWriteClient>,
+    /// Whether to compress entries
+    compress: bool,
+    /// Holds each entry, with its header, as it is read or appended
+    entry: TakeCell<'static, [u8]>,
+    /// The buffer of the client, and the length of its entry
+    buffer: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    /// The sequence number of the next entry
+    sequence: Cell<u32>,
+    /// The ticks since boot at the last append, and the counter then
+    ticks: Cell<u64>,
+    last_now: OptionalCell<T::Ticks>,
+    last_header: OptionalCell<Header>,
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>, T: Time> TimestampedLog<'a, L, T> {
+    /// `entry` must hold the largest entry of the log, and is what limits
+    /// the length of entries with their header.
+    pub fn new(
+        log: &'a L,
+        time: &'a T,
+        entry: &'static mut [u8],
+        compress: bool,
+    ) -> TimestampedLog<'a, L, T> {
+        TimestampedLog {
+            log,
+            time,
+            read_client: OptionalCell::empty(),
+            append_client: OptionalCell::empty(),
+            compress,
+            entry: TakeCell::new(entry),
+            buffer: TakeCell::empty(),
+            length: Cell::new(0),
+            sequence: Cell::new(0),
+            ticks: Cell::new(0),
+            last_now: OptionalCell::empty(),
+            last_header: OptionalCell::empty(),
+        }
+    }
+
+    /// The header of the last entry read.
+    pub fn last_header(&self) -> Option<Header> {
+        self.last_header.extract()
+    }
+
+    /// The time since boot, in microseconds.
+    fn now_us(&self) -> u64 {
+        let now = self.time.now();
+        // The counter starts at boot.
+        let elapsed = match self.last_now.extract() {
+            Some(last) => now.wrapping_sub(last).into_u32(),
+            None => now.into_u32(),
+        };
+        self.last_now.set(now);
+        let ticks = self.ticks.get() + u64::from(elapsed);
+        self.ticks.set(ticks);
+
+        let frequency = u64::from(T::Frequency::frequency());
+        ticks / frequency * 1_000_000 + ticks % frequency * 1_000_000 / frequency
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>, T: Time> LogRead<'a> for TimestampedLog<'a, L, T> {
+    type EntryID = L::EntryID;
+
+    fn set_read_client(&'a self, read_client: &'a dyn LogReadClient) {
+        self.read_client.set(read_client);
+    }
+
+    /// Reads the payload of the next entry. Fails with `FAIL` if the entry
+    /// is not timestamped, and `SIZE` if the payload does not fit in the
+    /// first `length` bytes of `buffer`.
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        let entry = match self.entry.take() {
+            Some(entry) => entry,
+            None => return Err((ErrorCode::BUSY, buffer)),
+        };
+        let entry_length = entry.len();
+        match self.log.read(entry, entry_length) {
+            Ok(()) => {
+                self.buffer.replace(buffer);
+                self.length.set(length);
+                Ok(())
+            }
+            Err((error, entry)) => {
+                self.entry.replace(entry);
+                Err((error, buffer))
+            }
+        }
+    }
+
+    fn log_start(&self) -> Self::EntryID {
+        self.log.log_start()
+    }
+
+    fn log_end(&self) -> Self::EntryID {
+        self.log.log_end()
+    }
+
+    fn next_read_entry_id(&self) -> Self::EntryID {
+        self.log.next_read_entry_id()
+    }
+
+    fn seek(&self, entry: Self::EntryID) -> Result<(), ErrorCode> {
+        self.log.seek(entry)
+    }
+
+    fn get_size(&self) -> usize {
+        self.log.get_size()
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>, T: Time> LogWrite<'a> for TimestampedLog<'a, L, T> {
+    fn set_append_client(&'a self, append_client: &'a dyn LogWriteClient) {
+        self.append_client.set(append_client);
+    }
+
+    /// Appends the first `length` bytes of `buffer` as the payload of an
+    /// entry. Fails with `SIZE` if the entry does not fit in the entry
+    /// buffer.
+    fn append(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if length == 0 || length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        let entry = match self.entry.take() {
+            Some(entry) => entry,
+            None => return Err((ErrorCode::BUSY, buffer)),
+        };
+
+        let header = Header {
+            sequence: self.sequence.get(),
+            timestamp_us: self.now_us(),
+            compressed: false,
+        };
+        let entry_length = match entry::encode(header, &buffer[..length], self.compress, entry) {
+            Ok(entry_length) => entry_length,
+            Err(_) => {
+                self.entry.replace(entry);
+                return Err((ErrorCode::SIZE, buffer));
+            }
+        };
+        match self.log.append(entry, entry_length) {
+            Ok(()) => {
+                self.buffer.replace(buffer);
+                self.length.set(length);
+                Ok(())
+            }
+            Err((error, entry)) => {
+                self.entry.replace(entry);
+                Err((error, buffer))
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<(), ErrorCode> {
+        self.log.sync()
+    }
+
+    fn erase(&self) -> Result<(), ErrorCode> {
+        self.log.erase()
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>, T: Time> LogReadClient for TimestampedLog<'a, L, T> {
+    fn read_done(&self, entry: &'static mut [u8], length: usize, result: Result<(), ErrorCode>) {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.entry.replace(entry);
+                return;
+            }
+        };
+        let result = result.and_then(|()| {
+            let output = &mut buffer[..self.length.get()];
+            match entry::decode(&entry[..length], output) {
+                Ok((header, payload_length)) => {
+                    self.last_header.set(header);
+                    Ok(payload_length)
+                }
+                Err(tock_log_format::Error::NoSpace) => Err(ErrorCode::SIZE),
+                Err(_) => Err(ErrorCode::FAIL),
+            }
+        });
+        self.entry.replace(entry);
+        self.read_client.map(move |client| match result {
+            Ok(payload_length) => client.read_done(buffer, payload_length, Ok(())),
+            Err(error) => client.read_done(buffer, 0, Err(error)),
+        });
+    }
+
+    fn seek_done(&self, result: Result<(), ErrorCode>) {
+        self.read_client.map(|client| client.seek_done(result));
+    }
+}
+
+impl<'a, L: LogRead<'a> + LogWrite<'a>, T: Time> LogWriteClient for TimestampedLog<'a, L, T> {
+    fn append_done(
+        &self,
+        entry: &'static mut [u8],
+        _length: usize,
+        records_lost: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.entry.replace(entry);
+        if result.is_ok() {
+            self.sequence.set(self.sequence.get().wrapping_add(1));
+        }
+        let length = if result.is_ok() { self.length.get() } else { 0 };
+        self.buffer.take().map(move |buffer| {
+            self.append_client
+                .map(move |client| client.append_done(buffer, length, records_lost, result))
+        });
+    }
+
+    fn sync_done(&self, result: Result<(), ErrorCode>) {
+        self.append_client.map(|client| client.sync_done(result));
+    }
+
+    fn erase_done(&self, result: Result<(), ErrorCode>) {
+        self.append_client.map(|client| client.erase_done(result));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::ENTRY_HEADER_SIZE;
+    use crate::testing::log::{FlashLog, SimLog};
+    use core::cell::RefCell;
+    use kernel::hil::time::{Freq1KHz, Ticks16};
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    struct FakeTime {
+        now: Cell<u16>,
+    }
+
+    impl Time for FakeTime {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks16;
+
+        fn now(&self) -> Ticks16 {
+            u32::from(self.now.get()).into()
+        }
+    }
+
+    struct Client {
+        /// The data and result of the last read or append
+        done: RefCell<Option<(Vec<u8>, Result<(), ErrorCode>)>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl LogReadClient for Client {
+        fn read_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.done.replace(Some((buffer[..length].to_vec(), result)));
+            self.buffer.replace(buffer);
+        }
+
+        fn seek_done(&self, _result: Result<(), ErrorCode>) {}
+    }
+
+    impl LogWriteClient for Client {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            _records_lost: bool,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.done.replace(Some((buffer[..length].to_vec(), result)));
+            self.buffer.replace(buffer);
+        }
+
+        fn sync_done(&self, _result: Result<(), ErrorCode>) {}
+
+        fn erase_done(&self, _result: Result<(), ErrorCode>) {}
+    }
+
+    const PAGE_SIZE: usize = 256;
+
+    struct Test {
+        time: &'static FakeTime,
+        log: SimLog<PAGE_SIZE>,
+        timestamped: &'static TimestampedLog<'static, FlashLog<PAGE_SIZE>, FakeTime>,
+        client: &'static Client,
+    }
+
+    impl Test {
+        fn new(compress: bool) -> Test {
+            Test::over(SimLog::new(16, true), compress)
+        }
+
+        fn over(log: SimLog<PAGE_SIZE>, compress: bool) -> Test {
+            let time = Box::leak(Box::new(FakeTime { now: Cell::new(0) }));
+            let entry = Box::leak(vec![0; 128].into_boxed_slice());
+            let timestamped = Box::leak(Box::new(TimestampedLog::new(
+                log.log, time, entry, compress,
+            )));
+            let client = Box::leak(Box::new(Client {
+                done: RefCell::new(None),
+                buffer: TakeCell::new(Box::leak(vec![0; 128].into_boxed_slice())),
+            }));
+            log.log.set_read_client(timestamped);
+            log.log.set_append_client(timestamped);
+            timestamped.set_read_client(client);
+            timestamped.set_append_client(client);
+            Test {
+                time,
+                log,
+                timestamped,
+                client,
+            }
+        }
+
+        fn append(&self, data: &[u8]) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            assert!(self.timestamped.append(buffer, data.len()).is_ok());
+            self.log.run();
+            let (appended, result) = self.client.done.take().unwrap();
+            assert_eq!(result, Ok(()));
+            assert_eq!(appended, data);
+        }
+
+        fn read(&self, length: usize) -> Result<Vec<u8>, ErrorCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            if let Err((error, buffer)) = self.timestamped.read(buffer, length) {
+                self.client.buffer.replace(buffer);
+                return Err(error);
+            }
+            self.log.run();
+            let (data, result) = self.client.done.take().unwrap();
+            result.map(|()| data)
+        }
+
+        /// Reads the next entry, returning its header.
+        fn header(&self) -> Header {
+            assert!(self.read(128).is_ok());
+            self.timestamped.last_header().unwrap()
+        }
+    }
+
+    #[test]
+    fn stamps_entries() {
+        let test = Test::new(false);
+        test.time.now.set(500);
+        test.append(b"booted");
+        test.time.now.set(65_000);
+        test.append(b"later");
+        // The counter wraps around.
+        test.time.now.set(100);
+        test.append(b"after the wrap");
+
+        let header = test.header();
+        assert_eq!((header.sequence, header.timestamp_us), (0, 500_000));
+        assert!(!header.compressed);
+        assert_eq!(test.header().timestamp_us, 65_000_000);
+        let header = test.header();
+        assert_eq!((header.sequence, header.timestamp_us), (2, 65_636_000));
+
+        // Reading gives the payloads.
+        assert!(test.timestamped.seek(test.log.log.log_start()).is_ok());
+        test.log.run();
+        assert_eq!(test.read(128).as_deref(), Ok(&b"booted"[..]));
+        assert_eq!(test.timestamped.last_header().unwrap().sequence, 0);
+        assert_eq!(test.read(128).as_deref(), Ok(&b"later"[..]));
+    }
+
+    #[test]
+    fn compresses_entries() {
+        let test = Test::new(true);
+        let payload = [b'z'; 100];
+        test.append(&payload);
+        let compressed_end = test.log.log.log_end();
+        test.append(b"short");
+        assert!(compressed_end - test.log.log.log_start() < ENTRY_HEADER_SIZE + HEADER_SIZE + 20);
+        assert!(test.header().compressed);
+        assert!(!test.header().compressed);
+
+        assert!(test.timestamped.seek(test.log.log.log_start()).is_ok());
+        test.log.run();
+        assert_eq!(test.read(99), Err(ErrorCode::SIZE));
+        assert!(test.timestamped.seek(test.log.log.log_start()).is_ok());
+        test.log.run();
+        assert_eq!(test.read(100).as_deref(), Ok(&payload[..]));
+        assert!(test.timestamped.last_header().unwrap().compressed);
+        assert_eq!(test.read(100).as_deref(), Ok(&b"short"[..]));
+        assert_eq!(test.read(100), Err(ErrorCode::FAIL));
+    }
+
+    #[test]
+    fn entries_without_a_timestamp_cannot_be_read() {
+        let test = Test::new(true);
+        test.append(b"stamped");
+        assert!(test.timestamped.sync().is_ok());
+        test.log.run();
+
+        let test = Test::over(test.log.push(&[9; 20], true), true);
+        assert_eq!(test.read(100).as_deref(), Ok(&b"stamped"[..]));
+        assert_eq!(test.read(100), Err(ErrorCode::FAIL));
+    }
+}
@@ -5,12 +5,15 @@
 use core::cell::Cell;
 use core::cmp;
 
+use super::composite::{DescriptorWriter, FunctionClass, FunctionSetupResult, UsbFunction};
 use super::descriptors;
 use super::descriptors::Buffer64;
 use super::descriptors::CdcInterfaceDescriptor;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
 use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -32,6 +35,9 @@ const ENDPOINT_IN_NUM: usize = 2;
 /// Identifying number for the endpoint when transferring data from the host to
 /// us.
 const ENDPOINT_OUT_NUM: usize = 3;
+/// Identifying number for the notification endpoint, which is declared but
+/// never used.
+const ENDPOINT_NOTIFY_NUM: usize = 4;
 
 static LANGUAGES: &'static [u16; 1] = &[
     0x0409, // English (United States)
@@ -50,6 +56,12 @@ pub const CDC_BUFFER_TIMEOUT_MS: u32 = 10000;
 
 const N_ENDPOINTS: usize = 3;
 
+/// Request code of GET_LINE_CODING.
+const GET_LINE_CODING: u8 = 0x21;
+/// Line coding reported to the host: 115200 baud, 1 stop bit, no parity and
+/// 8 data bits.
+const LINE_CODING: [u8; 7] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
 /// States of the CDC driver.
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum State {
@@ -107,8 +119,97 @@ impl From<u8> for CDCCntrlMessage {
     }
 }
 
+/// Returns the descriptors of the communication and data interfaces, numbered
+/// from `first_interface`, with the CDC functional descriptors of the
+/// communication interface and the endpoints of each interface.
+fn function_descriptors(
+    first_interface: u8,
+) -> (
+    [InterfaceDescriptor; 2],
+    [CdcInterfaceDescriptor; 4],
+    [EndpointDescriptor; 1],
+    [EndpointDescriptor; 2],
+) {
+    let interfaces = [
+        InterfaceDescriptor {
+            interface_number: first_interface,
+            interface_class: 0x02,    // CDC communication
+            interface_subclass: 0x02, // abstract control model (ACM)
+            interface_protocol: 0x01, // V.25ter (AT commands)
+            ..InterfaceDescriptor::default()
+        },
+        InterfaceDescriptor {
+            interface_number: first_interface + 1,
+            interface_class: 0x0a,    // CDC data
+            interface_subclass: 0x00, // none
+            interface_protocol: 0x00, // none
+            ..InterfaceDescriptor::default()
+        },
+    ];
+
+    let cdc_descriptors = [
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
+            field1: 0x10, // CDC
+            field2: 0x11, // CDC
+        },
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
+            field1: 0x00,                // Capabilities
+            field2: first_interface + 1, // Data interface
+        },
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
+            field1: 0x06, // Capabilities
+            field2: 0x00, // unused
+        },
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
+            field1: first_interface,     // Communication interface
+            field2: first_interface + 1, // Data interface
+        },
+    ];
+
+    let comm_endpoints = [EndpointDescriptor {
+        endpoint_address: EndpointAddress::new_const(
+            ENDPOINT_NOTIFY_NUM,
+            TransferDirection::DeviceToHost,
+        ),
+        transfer_type: TransferType::Interrupt,
+        max_packet_size: 8,
+        interval: 16,
+    }];
+
+    let data_endpoints = [
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_IN_NUM,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: 64,
+            interval: 0,
+        },
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_OUT_NUM,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: 64,
+            interval: 0,
+        },
+    ];
+
+    (interfaces, cdc_descriptors, comm_endpoints, data_endpoints)
+}
+
 /// Implementation of the Abstract Control Model (ACM) for the Communications
 /// Class Device (CDC) over USB.
+///
+/// The CDC device is either the only function of the USB device, in which
+/// case it is the client of the USB controller, or a function of a
+/// `CompositeDevice`.
 pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     /// Helper USB client library for handling many USB operations.
     client_ctrl: ClientCtrl<'a, 'static, U>,
@@ -191,74 +292,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         deferred_caller: &'a DynamicDeferredCall,
         host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [
-            InterfaceDescriptor {
-                interface_number: 0,
-                interface_class: 0x02,    // CDC communication
-                interface_subclass: 0x02, // abstract control model (ACM)
-                interface_protocol: 0x01, // V.25ter (AT commands)
-                ..InterfaceDescriptor::default()
-            },
-            InterfaceDescriptor {
-                interface_number: 1,
-                interface_class: 0x0a,    // CDC data
-                interface_subclass: 0x00, // none
-                interface_protocol: 0x00, // none
-                ..InterfaceDescriptor::default()
-            },
-        ];
-
-        let cdc_descriptors: &mut [CdcInterfaceDescriptor] = &mut [
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
-                field1: 0x10, // CDC
-                field2: 0x11, // CDC
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
-                field1: 0x00, // Capabilities
-                field2: 0x01, // Data interface 1
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
-                field1: 0x06, // Capabilities
-                field2: 0x00, // unused
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
-                field1: 0x00, // Interface 0
-                field2: 0x01, // Interface 1
-            },
-        ];
-
-        let endpoints: &[&[EndpointDescriptor]] = &[
-            &[EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(4, TransferDirection::DeviceToHost),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 8,
-                interval: 16,
-            }],
-            &[
-                EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        2,
-                        TransferDirection::DeviceToHost,
-                    ),
-                    transfer_type: TransferType::Bulk,
-                    max_packet_size: 64,
-                    interval: 0,
-                },
-                EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        3,
-                        TransferDirection::HostToDevice,
-                    ),
-                    transfer_type: TransferType::Bulk,
-                    max_packet_size: 64,
-                    interval: 0,
-                },
-            ],
-        ];
+        let (mut interfaces, cdc_descriptors, comm_endpoints, data_endpoints) =
+            function_descriptors(0);
 
         let (device_descriptor_buffer, other_descriptor_buffer) =
             descriptors::create_descriptor_buffers(
@@ -275,10 +310,10 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                 descriptors::ConfigurationDescriptor {
                     ..descriptors::ConfigurationDescriptor::default()
                 },
-                interfaces,
-                endpoints,
+                &mut interfaces,
+                &[&comm_endpoints, &data_endpoints],
                 None, // No HID descriptor
-                Some(cdc_descriptors),
+                Some(&cdc_descriptors),
             );
 
         Self {
@@ -370,15 +405,9 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             _ => {}
         }
     }
-}
-
-impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
-    for CdcAcm<'a, U, A>
-{
-    fn enable(&'a self) {
-        // Set up the default control endpoint
-        self.client_ctrl.enable();
 
+    /// Sets up the IN and OUT endpoints, and starts the boot period.
+    fn enable_endpoints(&'a self) {
         // Setup buffers for IN and OUT data transfer.
         self.controller()
             .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
@@ -398,6 +427,102 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
         );
     }
 
+    /// Tracks the CDC messages that signal that a client connects.
+    fn class_request(&self, request_code: u8) {
+        match CDCCntrlMessage::from(request_code) {
+            CDCCntrlMessage::SetLineCoding => {
+                self.ctrl_state.set(CtrlState::SetLineCoding);
+            }
+            CDCCntrlMessage::SetControlLineState => {
+                // Bit 0 and 1 of the value (setup_data.value) can be set
+                // D0: Indicates to DCE if DTE is present or not.
+                //     - 0 -> Not present
+                //     - 1 -> Present
+                // D1: Carrier control for half duplex modems.
+                //     - 0 -> Deactivate carrier
+                //     - 1 -> Activate carrier
+                //
+                // Currently we don't care about the value, just that this
+                // event has occurred. If it has happened, update the flag
+                // in `State::Connecting`.
+                self.set_connecting_state(false, true);
+
+                self.ctrl_state.set(CtrlState::SetControlLineState);
+            }
+            CDCCntrlMessage::SendBreak => {
+                // On Mac, we seem to get the SEND_BREAK to signal that a
+                // client disconnects.
+                self.state.set(State::Enumerated)
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the data stage of a SET_LINE_CODING request.
+    fn line_coding_received(&self, packet: &[VolatileCell<u8>]) {
+        // Check what state our Ctrl endpoint is in.
+        match self.ctrl_state.get() {
+            CtrlState::SetLineCoding => {
+                // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
+                // We can parse the data we got.
+                descriptors::CdcAcmSetLineCodingData::get(packet).map(|line_coding| {
+                    // If the device is configuring the baud rate to what we
+                    // expect, we continue with the connecting process.
+                    if line_coding.baud_rate == 115200 {
+                        self.set_connecting_state(true, false);
+                    }
+
+                    // Check if the baud rate we got matches the special flag
+                    // value (1200 baud). If so, we run an optional function
+                    // provided when the CDC stack was configured.
+                    if line_coding.baud_rate == 1200 {
+                        self.host_initiated_function.map(|f| {
+                            f();
+                        });
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the completion of a control transfer, and starts sending
+    /// once a client is connected.
+    fn ctrl_transfer_complete(&self) {
+        self.ctrl_state.set(CtrlState::Idle);
+
+        // Here we check to see if we just got connected to a CDC client. If so,
+        // we do a delay before transmitting if needed.
+        match self.state.get() {
+            State::Connecting {
+                line_coding,
+                line_state,
+            } => {
+                if line_coding && line_state {
+                    self.state.set(State::ConnectingDelay);
+
+                    // Wait a 100 ms before sending data.
+                    self.timeout_alarm.set_alarm(
+                        self.timeout_alarm.now(),
+                        self.timeout_alarm.ticks_from_ms(100),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
+    for CdcAcm<'a, U, A>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        self.enable_endpoints();
+    }
+
     fn attach(&'a self) {
         self.client_ctrl.attach();
         self.state.set(State::Attached);
@@ -414,35 +539,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// client is connected or not.
     fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
         descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
-            let b_request = setup_data.request_code;
-
-            match CDCCntrlMessage::from(b_request) {
-                CDCCntrlMessage::SetLineCoding => {
-                    self.ctrl_state.set(CtrlState::SetLineCoding);
-                }
-                CDCCntrlMessage::SetControlLineState => {
-                    // Bit 0 and 1 of the value (setup_data.value) can be set
-                    // D0: Indicates to DCE if DTE is present or not.
-                    //     - 0 -> Not present
-                    //     - 1 -> Present
-                    // D1: Carrier control for half duplex modems.
-                    //     - 0 -> Deactivate carrier
-                    //     - 1 -> Activate carrier
-                    //
-                    // Currently we don't care about the value, just that this
-                    // event has occurred. If it has happened, update the flag
-                    // in `State::Connecting`.
-                    self.set_connecting_state(false, true);
-
-                    self.ctrl_state.set(CtrlState::SetControlLineState);
-                }
-                CDCCntrlMessage::SendBreak => {
-                    // On Mac, we seem to get the SEND_BREAK to signal that a
-                    // client disconnects.
-                    self.state.set(State::Enumerated)
-                }
-                _ => {}
-            }
+            self.class_request(setup_data.request_code);
         });
 
         self.client_ctrl.ctrl_setup(endpoint)
@@ -455,32 +552,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle a Control Out transaction
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
-        // Check what state our Ctrl endpoint is in.
-        match self.ctrl_state.get() {
-            CtrlState::SetLineCoding => {
-                // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
-                // We can parse the data we got.
-                descriptors::CdcAcmSetLineCodingData::get(&self.client_ctrl.ctrl_buffer.buf).map(
-                    |line_coding| {
-                        // If the device is configuring the baud rate to what we
-                        // expect, we continue with the connecting process.
-                        if line_coding.baud_rate == 115200 {
-                            self.set_connecting_state(true, false);
-                        }
-
-                        // Check if the baud rate we got matches the special flag
-                        // value (1200 baud). If so, we run an optional function
-                        // provided when the CDC stack was configured.
-                        if line_coding.baud_rate == 1200 {
-                            self.host_initiated_function.map(|f| {
-                                f();
-                            });
-                        }
-                    },
-                );
-            }
-            _ => {}
-        }
+        self.line_coding_received(&self.client_ctrl.ctrl_buffer.buf);
 
         self.client_ctrl.ctrl_out(endpoint, packet_bytes)
     }
@@ -491,27 +563,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
-        self.ctrl_state.set(CtrlState::Idle);
-
-        // Here we check to see if we just got connected to a CDC client. If so,
-        // we do a delay before transmitting if needed.
-        match self.state.get() {
-            State::Connecting {
-                line_coding,
-                line_state,
-            } => {
-                if line_coding && line_state {
-                    self.state.set(State::ConnectingDelay);
-
-                    // Wait a 100 ms before sending data.
-                    self.timeout_alarm.set_alarm(
-                        self.timeout_alarm.now(),
-                        self.timeout_alarm.ticks_from_ms(100),
-                    );
-                }
-            }
-            _ => {}
-        }
+        self.ctrl_transfer_complete();
 
         self.client_ctrl.ctrl_status_complete(endpoint)
     }
@@ -657,6 +709,98 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 }
 
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> UsbFunction<'a> for CdcAcm<'a, U, A> {
+    fn interface_count(&self) -> u8 {
+        2
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0x02,    // CDC communication
+            subclass: 0x02, // abstract control model (ACM)
+            protocol: 0x01, // V.25ter (AT commands)
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        (1 << ENDPOINT_IN_NUM) | (1 << ENDPOINT_OUT_NUM) | (1 << ENDPOINT_NOTIFY_NUM)
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        let (mut interfaces, cdc_descriptors, comm_endpoints, data_endpoints) =
+            function_descriptors(first_interface);
+        interfaces[0].num_endpoints = comm_endpoints.len() as u8;
+        interfaces[1].num_endpoints = data_endpoints.len() as u8;
+
+        writer.write(&interfaces[0]);
+        for descriptor in cdc_descriptors.iter() {
+            writer.write(descriptor);
+        }
+        for endpoint in comm_endpoints.iter() {
+            writer.write(endpoint);
+        }
+        writer.write(&interfaces[1]);
+        for endpoint in data_endpoints.iter() {
+            writer.write(endpoint);
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn bus_reset(&'a self) {
+        self.state.set(State::Enumerated);
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData, data: &[Cell<u8>]) -> FunctionSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return FunctionSetupResult::Error,
+        }
+        self.class_request(setup.request_code);
+        match setup.request_type.transfer_direction() {
+            TransferDirection::HostToDevice => FunctionSetupResult::Out,
+            TransferDirection::DeviceToHost => match setup.request_code {
+                GET_LINE_CODING if data.len() >= LINE_CODING.len() => {
+                    // Report the only line coding we expect hosts to use
+                    for (i, b) in LINE_CODING.iter().enumerate() {
+                        data[i].set(*b);
+                    }
+                    FunctionSetupResult::In(LINE_CODING.len())
+                }
+                _ => FunctionSetupResult::Error,
+            },
+        }
+    }
+
+    fn ctrl_out(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlOutResult {
+        self.line_coding_received(packet);
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.ctrl_transfer_complete();
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
 impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Configure for CdcAcm<'a, U, A> {
     fn configure(&self, _parameters: uart::Parameters) -> Result<(), ErrorCode> {
         // Since this is not a real UART, we don't need to consider these
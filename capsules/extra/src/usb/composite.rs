@@ -0,0 +1,783 @@
+//! A composite USB device, which exposes several functions at once (for
+//! instance a CDC-ACM console, a HID interface and a vendor interface).
+//!
+//! The composite device owns the default control endpoint. It answers the
+//! standard device requests, builds the configuration descriptor from the
+//! descriptors of the functions, and routes the other requests and the
+//! endpoint callbacks of the controller to the function they belong to:
+//!
+//! ```txt
+//!     UsbFunction   UsbFunction   UsbFunction
+//!          ^             ^             ^
+//!          +-------------+-------------+
+//!                        |
+//!                 CompositeDevice
+//!                    |       ^
+//!                    v       |
+//!                  UsbController
+//! ```
+//!
+//! Functions are assigned consecutive interface numbers in the order they
+//! are added. The interfaces of functions with more than one interface are
+//! grouped by an interface association descriptor, so that hosts bind a
+//! single driver to them, and the device descriptor uses the class codes of
+//! such "multi-interface function" devices. Functions choose their endpoint
+//! numbers, which must not overlap.
+//!
+//! Functions may also define string descriptors, whose indices follow the
+//! three strings of the device.
+//!
+//! Requests to an interface or an endpoint are routed to the function owning
+//! it. Class and vendor requests to the device as a whole are stalled, as no
+//! function can tell that they are meant for it. Standard requests to
+//! endpoints are answered by the composite device, which keeps the halt state
+//! of the endpoints of the functions and stalls their transactions while they
+//! are halted.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let composite = static_init!(
+//!     CompositeDevice<'static, nrf52840::usbd::Usbd<'static>>,
+//!     CompositeDevice::new(&nrf52840_peripherals.usbd, 64, 0x6667, 0xabcd, STRINGS)
+//! );
+//! composite.add_function(cdc).unwrap();
+//! composite.add_function(ctap).unwrap();
+//! nrf52840_peripherals.usbd.set_client(composite);
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+
+use super::descriptors::Buffer64;
+use super::descriptors::ConfigurationDescriptor;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::DeviceDescriptor;
+use super::descriptors::FeatureSelector;
+use super::descriptors::InterfaceAssociationDescriptor;
+use super::descriptors::LanguagesDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::StringDescriptor;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::{OptionalCell, VolatileCell};
+use kernel::ErrorCode;
+
+/// Maximum number of functions of a composite device.
+pub const MAX_FUNCTIONS: usize = 4;
+
+/// Space for the configuration descriptor, and for the data of the other
+/// control transfers.
+pub const DESCRIPTOR_BUFLEN: usize = 256;
+
+/// Length of the configuration descriptor itself, which precedes the
+/// descriptors of the functions.
+const CONFIGURATION_DESCRIPTOR_LEN: usize = 9;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Class codes of a function, reported in its interface association
+/// descriptor.
+#[derive(Copy, Clone, Debug)]
+pub struct FunctionClass {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// How a function handles a request on the control endpoint.
+pub enum FunctionSetupResult {
+    /// The function wrote the given number of bytes to send to the host in
+    /// the data stage. The data is truncated to the length requested.
+    In(usize),
+
+    /// The request is accepted. The packets of the data stage, if any, are
+    /// passed to `ctrl_out()`.
+    Out,
+
+    /// The request is not supported, and is stalled.
+    Error,
+}
+
+/// Appends descriptors to a buffer, and remembers whether any of them did
+/// not fit.
+pub struct DescriptorWriter<'b> {
+    buf: &'b [Cell<u8>],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'b> DescriptorWriter<'b> {
+    pub fn new(buf: &'b [Cell<u8>]) -> Self {
+        DescriptorWriter {
+            buf: buf,
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    pub fn write(&mut self, descriptor: &dyn Descriptor) {
+        match descriptor.write_to(&self.buf[self.len..]) {
+            0 => self.overflow = true,
+            len => self.len += len,
+        }
+    }
+
+    /// Returns the length of the descriptors written, or `None` if they did
+    /// not fit.
+    pub fn finish(self) -> Option<usize> {
+        if self.overflow {
+            None
+        } else {
+            Some(self.len)
+        }
+    }
+}
+
+/// A function of a composite device.
+///
+/// Functions set up and use their endpoints directly on the controller, but
+/// receive the controller callbacks through the composite device.
+pub trait UsbFunction<'a> {
+    /// Number of interfaces of the function.
+    fn interface_count(&self) -> u8;
+
+    /// Class codes of the function as a whole.
+    fn function_class(&self) -> FunctionClass;
+
+    /// Endpoints used by the function, with bit `n` set if endpoint `n` is
+    /// used in either direction.
+    fn endpoint_mask(&self) -> u16;
+
+    /// Writes the descriptors of the interfaces of the function, each
+    /// followed by its class-specific and endpoint descriptors. Interfaces
+    /// are numbered from `first_interface`.
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter);
+
+    /// Sets up the endpoints of the function.
+    fn enable(&'a self);
+
+    /// Called when the bus is reset.
+    fn bus_reset(&'a self) {}
+
+    /// Called when the host selects (`true`) or deselects the configuration.
+    fn configured(&'a self, _configured: bool) {}
+
+    /// Handles a request to one of the interfaces of the function, or a
+    /// class or vendor request to one of its endpoints. Data to send to the
+    /// host is written to `data`.
+    fn ctrl_setup(&'a self, _setup: SetupData, _data: &[Cell<u8>]) -> FunctionSetupResult {
+        FunctionSetupResult::Error
+    }
+
+    /// Handles a packet of the data stage of a request accepted with
+    /// `FunctionSetupResult::Out`.
+    fn ctrl_out(&'a self, _packet: &[VolatileCell<u8>]) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    /// Called when a request handled by the function completes.
+    fn ctrl_status_complete(&'a self) {}
+
+    /// Returns the string descriptor `index`, if the function defines it.
+    /// Indices 1 to 3 are the strings of the device, so functions use
+    /// higher indices, which the board assigns.
+    fn string(&self, _index: u8) -> Option<&str> {
+        None
+    }
+
+    /// Handles a Bulk/Interrupt IN transaction on an endpoint of the
+    /// function.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult;
+
+    /// Handles a Bulk/Interrupt OUT transaction on an endpoint of the
+    /// function.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult;
+
+    /// Called when a packet was transmitted on an endpoint of the function.
+    fn packet_transmitted(&'a self, endpoint: usize);
+}
+
+#[derive(Copy, Clone)]
+struct Registration<'a> {
+    function: &'a dyn UsbFunction<'a>,
+    first_interface: u8,
+}
+
+/// States of the control endpoint.
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Init,
+
+    /// We are doing a Control In transfer of some data in `self.storage`,
+    /// with the given extent remaining to send.
+    CtrlIn(usize, usize),
+
+    /// We will accept data from the host, on behalf of a function.
+    CtrlOut,
+
+    SetAddress,
+}
+
+pub struct CompositeDevice<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// A 64-byte buffer for the control endpoint.
+    ctrl_buffer: Buffer64,
+
+    /// Storage for composing responses to control requests.
+    storage: [Cell<u8>; DESCRIPTOR_BUFLEN],
+
+    device_descriptor: DeviceDescriptor,
+
+    /// Manufacturer, product and serial number.
+    strings: &'a [&'a str; 3],
+
+    functions: [OptionalCell<Registration<'a>>; MAX_FUNCTIONS],
+
+    /// Number of interfaces of the functions added so far.
+    interface_count: Cell<u8>,
+
+    /// Endpoints used by the functions added so far.
+    endpoint_mask: Cell<u16>,
+
+    state: Cell<State>,
+
+    /// Index of the function handling the current control transfer.
+    ctrl_function: OptionalCell<usize>,
+
+    /// Value of the configuration selected by the host, 0 if none.
+    configuration: Cell<u8>,
+
+    /// Halted endpoints, with bit `n` set for OUT endpoint `n` and bit
+    /// `16 + n` for IN endpoint `n`.
+    halted: Cell<u32>,
+
+    enabled: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CompositeDevice<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'a [&'a str; 3],
+    ) -> Self {
+        CompositeDevice {
+            controller: controller,
+            ctrl_buffer: Buffer64::default(),
+            storage: [(); DESCRIPTOR_BUFLEN].map(|_| Cell::default()),
+            device_descriptor: DeviceDescriptor {
+                vendor_id: vendor_id,
+                product_id: product_id,
+                manufacturer_string: 1,
+                product_string: 2,
+                serial_number_string: 3,
+                // Multi-interface function, with interface association
+                // descriptors
+                class: 0xef,
+                subclass: 0x02,
+                protocol: 0x01,
+                max_packet_size_ep0: max_ctrl_packet_size,
+                ..DeviceDescriptor::default()
+            },
+            strings: strings,
+            functions: Default::default(),
+            interface_count: Cell::new(0),
+            endpoint_mask: Cell::new(0),
+            state: Cell::new(State::Init),
+            ctrl_function: OptionalCell::empty(),
+            configuration: Cell::new(0),
+            halted: Cell::new(0),
+            enabled: Cell::new(false),
+        }
+    }
+
+    #[inline]
+    pub fn controller(&self) -> &'a U {
+        self.controller
+    }
+
+    /// Adds a function to the device. Functions must be added before the
+    /// device is enabled.
+    ///
+    /// Returns `ALREADY` if the device is enabled, `INVAL` if the function
+    /// uses the control endpoint or an endpoint of another function, `NOMEM`
+    /// if the device has `MAX_FUNCTIONS` functions already, and `SIZE` if
+    /// its descriptors do not fit in the configuration descriptor.
+    pub fn add_function(&self, function: &'a dyn UsbFunction<'a>) -> Result<(), ErrorCode> {
+        if self.enabled.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        let mask = function.endpoint_mask();
+        if mask & 1 != 0 || mask & self.endpoint_mask.get() != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let slot = self
+            .functions
+            .iter()
+            .find(|slot| slot.is_none())
+            .ok_or(ErrorCode::NOMEM)?;
+        let first_interface = self.interface_count.get();
+        let interface_count = first_interface
+            .checked_add(function.interface_count())
+            .ok_or(ErrorCode::SIZE)?;
+
+        slot.set(Registration {
+            function: function,
+            first_interface: first_interface,
+        });
+        self.interface_count.set(interface_count);
+        if self.write_configuration().is_none() {
+            slot.clear();
+            self.interface_count.set(first_interface);
+            return Err(ErrorCode::SIZE);
+        }
+        self.endpoint_mask.set(self.endpoint_mask.get() | mask);
+        Ok(())
+    }
+
+    /// Returns whether the host selected the configuration of the device.
+    pub fn is_configured(&self) -> bool {
+        self.configuration.get() != 0
+    }
+
+    fn function(&self, index: usize) -> Option<&'a dyn UsbFunction<'a>> {
+        self.functions
+            .get(index)
+            .and_then(|slot| slot.map(|registration| registration.function))
+    }
+
+    fn for_each_function<F: Fn(&'a dyn UsbFunction<'a>)>(&self, f: F) {
+        for slot in self.functions.iter() {
+            slot.map(|registration| f(registration.function));
+        }
+    }
+
+    /// Returns the string `index` defined by one of the functions.
+    fn function_string(&self, index: u8) -> Option<&'a str> {
+        self.functions.iter().find_map(|slot| {
+            slot.map(|registration| registration.function.string(index))
+                .flatten()
+        })
+    }
+
+    fn function_of_interface(&self, interface: u8) -> Option<usize> {
+        self.functions.iter().position(|slot| {
+            slot.map_or(false, |registration| {
+                interface >= registration.first_interface
+                    && interface - registration.first_interface
+                        < registration.function.interface_count()
+            })
+        })
+    }
+
+    fn function_of_endpoint(&self, endpoint: usize) -> Option<usize> {
+        if endpoint >= 16 {
+            return None;
+        }
+        self.functions.iter().position(|slot| {
+            slot.map_or(false, |registration| {
+                registration.function.endpoint_mask() & (1 << endpoint) != 0
+            })
+        })
+    }
+
+    /// Returns the halt state bit of the endpoint with address `address`, if
+    /// the endpoint belongs to a function.
+    fn halt_bit(&self, address: u16) -> Option<u32> {
+        let endpoint = (address & 0xf) as usize;
+        if address & !0x8f != 0 || self.function_of_endpoint(endpoint).is_none() {
+            return None;
+        }
+        match address & 0x80 {
+            0 => Some(1 << endpoint),
+            _ => Some(1 << (16 + endpoint)),
+        }
+    }
+
+    fn is_halted(&self, bit: u32) -> bool {
+        self.halted.get() & bit != 0
+    }
+
+    /// Writes the configuration descriptor, followed by the descriptors of
+    /// the functions, to `self.storage`. Returns the total length, or `None`
+    /// if the descriptors do not fit.
+    fn write_configuration(&self) -> Option<usize> {
+        let mut writer = DescriptorWriter::new(&self.storage[CONFIGURATION_DESCRIPTOR_LEN..]);
+        for slot in self.functions.iter() {
+            slot.map(|registration| {
+                let function = registration.function;
+                let interface_count = function.interface_count();
+                if interface_count > 1 {
+                    let class = function.function_class();
+                    writer.write(&InterfaceAssociationDescriptor {
+                        first_interface: registration.first_interface,
+                        interface_count: interface_count,
+                        function_class: class.class,
+                        function_subclass: class.subclass,
+                        function_protocol: class.protocol,
+                        string_index: 0,
+                    });
+                }
+                function.write_descriptors(registration.first_interface, &mut writer);
+            });
+        }
+        let len = writer.finish()?;
+
+        let configuration = ConfigurationDescriptor {
+            num_interfaces: self.interface_count.get(),
+            related_descriptor_length: len,
+            ..ConfigurationDescriptor::default()
+        };
+        configuration.write_to(&self.storage);
+        Some(CONFIGURATION_DESCRIPTOR_LEN + len)
+    }
+
+    /// Starts the data stage of a Control In transfer of the first `len`
+    /// bytes of `self.storage`.
+    fn send_storage(&self, len: usize, requested_length: u16) -> hil::usb::CtrlSetupResult {
+        let end = min(min(len, requested_length as usize), DESCRIPTOR_BUFLEN);
+        self.state.set(State::CtrlIn(0, end));
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Passes a request to the function at `index`.
+    fn function_setup(&self, index: usize, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        let result = self
+            .function(index)
+            .map_or(FunctionSetupResult::Error, |function| {
+                function.ctrl_setup(setup, &self.storage)
+            });
+        match result {
+            FunctionSetupResult::In(len) => {
+                self.ctrl_function.set(index);
+                self.send_storage(len, setup.length)
+            }
+            FunctionSetupResult::Out => {
+                self.ctrl_function.set(index);
+                self.state.set(State::CtrlOut);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            FunctionSetupResult::Error => hil::usb::CtrlSetupResult::ErrGeneric,
+        }
+    }
+
+    fn handle_standard_device_request(
+        &self,
+        setup: SetupData,
+        request: StandardRequest,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => match descriptor_type {
+                DescriptorType::Device => match descriptor_index {
+                    0 => {
+                        let len = self.device_descriptor.write_to(&self.storage);
+                        self.send_storage(len, requested_length)
+                    }
+                    _ => hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex,
+                },
+                DescriptorType::Configuration => match descriptor_index {
+                    0 => self
+                        .write_configuration()
+                        .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |len| {
+                            self.send_storage(len, requested_length)
+                        }),
+                    _ => hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex,
+                },
+                DescriptorType::String => {
+                    let len = match descriptor_index {
+                        0 => LanguagesDescriptor { langs: LANGUAGES }.write_to(&self.storage),
+                        i if (i as usize) <= self.strings.len() && lang_id == LANGUAGES[0] => {
+                            StringDescriptor {
+                                string: self.strings[i as usize - 1],
+                            }
+                            .write_to(&self.storage)
+                        }
+                        i if lang_id == LANGUAGES[0] => {
+                            self.function_string(i).map_or(0, |string| {
+                                StringDescriptor { string: string }.write_to(&self.storage)
+                            })
+                        }
+                        _ => 0,
+                    };
+                    if len > 0 {
+                        self.send_storage(len, requested_length)
+                    } else {
+                        hil::usb::CtrlSetupResult::ErrInvalidStringIndex
+                    }
+                }
+                DescriptorType::DeviceQualifier => {
+                    // We are full-speed only, so we must respond with a
+                    // request error
+                    hil::usb::CtrlSetupResult::ErrNoDeviceQualifier
+                }
+                _ => hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+            },
+            StandardRequest::SetAddress { device_address } => {
+                // Load the address we've been assigned, and enable it once
+                // this request gets to the Status stage.
+                self.controller.set_address(device_address);
+                self.state.set(State::SetAddress);
+                hil::usb::CtrlSetupResult::OkSetAddress
+            }
+            StandardRequest::SetConfiguration {
+                configuration_value,
+            } => {
+                if configuration_value > 1 {
+                    return hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex;
+                }
+                self.configuration.set(configuration_value);
+                // Selecting a configuration clears the halt of all endpoints
+                self.halted.set(0);
+                self.for_each_function(|function| function.configured(configuration_value != 0));
+                hil::usb::CtrlSetupResult::Ok
+            }
+            StandardRequest::GetConfiguration => {
+                self.storage[0].set(self.configuration.get());
+                self.send_storage(1, setup.length)
+            }
+            StandardRequest::GetStatus { .. } => {
+                // Self-powered, without remote wakeup
+                self.storage[0].set(1);
+                self.storage[1].set(0);
+                self.send_storage(2, setup.length)
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    fn handle_standard_endpoint_request(
+        &self,
+        setup: SetupData,
+        request: StandardRequest,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetStatus { recipient_index } => {
+                // The default control endpoint is never halted
+                let halted = match recipient_index & 0x7f {
+                    0 => false,
+                    _ => match self.halt_bit(recipient_index) {
+                        Some(bit) => self.is_halted(bit),
+                        None => return hil::usb::CtrlSetupResult::ErrGeneric,
+                    },
+                };
+                self.storage[0].set(halted as u8);
+                self.storage[1].set(0);
+                self.send_storage(2, setup.length)
+            }
+            StandardRequest::SetFeature {
+                feature: FeatureSelector::EndpointHalt,
+                recipient_index,
+                ..
+            } => match self.halt_bit(recipient_index) {
+                Some(bit) => {
+                    self.halted.set(self.halted.get() | bit);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                None => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+            StandardRequest::ClearFeature {
+                feature: FeatureSelector::EndpointHalt,
+                recipient_index,
+            } => match self.halt_bit(recipient_index) {
+                Some(bit) => {
+                    let was_halted = self.is_halted(bit);
+                    self.halted.set(self.halted.get() & !bit);
+                    // Let the function carry on with the transfers it has
+                    // queued
+                    if was_halted {
+                        let endpoint = (recipient_index & 0xf) as usize;
+                        if recipient_index & 0x80 != 0 {
+                            self.controller.endpoint_resume_in(endpoint);
+                        } else {
+                            self.controller.endpoint_resume_out(endpoint);
+                        }
+                    }
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                None => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CompositeDevice<'a, U> {
+    fn enable(&'a self) {
+        self.enabled.set(true);
+
+        // Set up the default control endpoint
+        self.controller
+            .endpoint_set_ctrl_buffer(&self.ctrl_buffer.buf);
+        self.controller
+            .enable_as_device(hil::usb::DeviceSpeed::Full); // must be Full for Bulk transfers
+        self.controller
+            .endpoint_out_enable(TransferType::Control, 0);
+
+        self.for_each_function(|function| function.enable());
+    }
+
+    fn attach(&'a self) {
+        self.controller.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.configuration.set(0);
+        self.halted.set(0);
+        self.state.set(State::Init);
+        self.ctrl_function.clear();
+        self.for_each_function(|function| function.bus_reset());
+    }
+
+    /// Handle a Control Setup transaction
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        if endpoint != 0 {
+            // Only the default Control endpoint is supported
+            return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex;
+        }
+        let setup = match SetupData::get(&self.ctrl_buffer.buf) {
+            Some(setup) => setup,
+            None => return hil::usb::CtrlSetupResult::ErrNoParse,
+        };
+        self.ctrl_function.clear();
+        self.state.set(State::Init);
+
+        match setup.request_type.recipient() {
+            Recipient::Device => match setup.get_standard_request() {
+                Some(request) => self.handle_standard_device_request(setup, request),
+                // Class and vendor requests to the device are not meant for
+                // any function in particular
+                None => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+            },
+            Recipient::Interface => self.function_of_interface(setup.index as u8).map_or(
+                hil::usb::CtrlSetupResult::ErrInvalidInterfaceIndex,
+                |index| self.function_setup(index, setup),
+            ),
+            Recipient::Endpoint => match setup.get_standard_request() {
+                Some(request) => self.handle_standard_endpoint_request(setup, request),
+                None => self
+                    .function_of_endpoint((setup.index & 0xf) as usize)
+                    .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |index| {
+                        self.function_setup(index, setup)
+                    }),
+            },
+            _ => hil::usb::CtrlSetupResult::ErrGeneric,
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.state.get() {
+            State::CtrlIn(start, end) if endpoint == 0 => {
+                let len = end.saturating_sub(start);
+                if len > 0 {
+                    let packet_bytes = min(self.ctrl_buffer.buf.len(), len);
+                    let packet = &self.storage[start..start + packet_bytes];
+                    let buf = &self.ctrl_buffer.buf;
+
+                    // Copy a packet into the endpoint buffer
+                    for (i, b) in packet.iter().enumerate() {
+                        buf[i].set(b.get());
+                    }
+
+                    let start = start + packet_bytes;
+                    let transfer_complete = start >= end;
+                    self.state.set(State::CtrlIn(start, end));
+
+                    hil::usb::CtrlInResult::Packet(packet_bytes, transfer_complete)
+                } else {
+                    hil::usb::CtrlInResult::Packet(0, true)
+                }
+            }
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.state.get() {
+            State::CtrlOut if endpoint == 0 => {
+                let len = min(packet_bytes as usize, self.ctrl_buffer.buf.len());
+                self.ctrl_function
+                    .extract()
+                    .and_then(|index| self.function(index))
+                    .map_or(hil::usb::CtrlOutResult::Halted, |function| {
+                        function.ctrl_out(&self.ctrl_buffer.buf[..len])
+                    })
+            }
+            // Bad state
+            _ => hil::usb::CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status(&'a self, _endpoint: usize) {
+        // Entered Status stage
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, _endpoint: usize) {
+        if self.state.get() == State::SetAddress {
+            self.controller.enable_address();
+        }
+        self.state.set(State::Init);
+        self.ctrl_function
+            .take()
+            .and_then(|index| self.function(index))
+            .map(|function| function.ctrl_status_complete());
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        if endpoint < 16 && self.is_halted(1 << (16 + endpoint)) {
+            return hil::usb::InResult::Error;
+        }
+        self.function_of_endpoint(endpoint)
+            .and_then(|index| self.function(index))
+            .map_or(hil::usb::InResult::Error, |function| {
+                function.packet_in(transfer_type, endpoint)
+            })
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        if endpoint < 16 && self.is_halted(1 << endpoint) {
+            return hil::usb::OutResult::Error;
+        }
+        self.function_of_endpoint(endpoint)
+            .and_then(|index| self.function(index))
+            .map_or(hil::usb::OutResult::Error, |function| {
+                function.packet_out(transfer_type, endpoint, packet_bytes)
+            })
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.function_of_endpoint(endpoint)
+            .and_then(|index| self.function(index))
+            .map(|function| function.packet_transmitted(endpoint));
+    }
+}
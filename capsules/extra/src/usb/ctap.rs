@@ -5,8 +5,10 @@
 use core::cell::Cell;
 use core::cmp;
 
+use super::composite::{DescriptorWriter, FunctionClass, FunctionSetupResult, UsbFunction};
 use super::descriptors;
 use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
 use super::descriptors::DescriptorType;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
@@ -15,6 +17,8 @@ use super::descriptors::HIDDescriptor;
 use super::descriptors::HIDSubordinateDescriptor;
 use super::descriptors::InterfaceDescriptor;
 use super::descriptors::ReportDescriptor;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -77,7 +81,46 @@ static HID_DESCRIPTOR: HIDDescriptor<'static> = HIDDescriptor {
     sub_descriptors: SUB_HID_DESCRIPTOR,
 };
 
+/// Returns the descriptor of the HID interface, numbered `interface_number`,
+/// and of its endpoints.
+fn function_descriptors(interface_number: u8) -> (InterfaceDescriptor, [EndpointDescriptor; 2]) {
+    let interface = InterfaceDescriptor {
+        interface_number: interface_number,
+        interface_class: 0x03,    // HID
+        interface_subclass: 0x00, // No subcall
+        interface_protocol: 0x00, // No protocol
+        ..InterfaceDescriptor::default()
+    };
+
+    let endpoints = [
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_NUM,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: 64,
+            interval: 5,
+        },
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_NUM,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: 64,
+            interval: 5,
+        },
+    ];
+
+    (interface, endpoints)
+}
+
 /// Implementation of the CTAP HID (Human Interface Device)
+///
+/// The CTAP HID is either the only function of the USB device, in which case
+/// it is the client of the USB controller, or a function of a
+/// `CompositeDevice`.
 pub struct CtapHid<'a, U: 'a> {
     /// Helper USB client library for handling many USB operations.
     client_ctrl: ClientCtrl<'a, 'static, U>,
@@ -108,34 +151,7 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
         product_id: u16,
         strings: &'static [&'static str; 3],
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
-            interface_number: 0,
-            interface_class: 0x03,    // HID
-            interface_subclass: 0x00, // No subcall
-            interface_protocol: 0x00, // No protocol
-            ..InterfaceDescriptor::default()
-        }];
-
-        let endpoints: &[&[EndpointDescriptor]] = &[&[
-            EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(
-                    ENDPOINT_NUM,
-                    TransferDirection::DeviceToHost,
-                ),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 64,
-                interval: 5,
-            },
-            EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(
-                    ENDPOINT_NUM,
-                    TransferDirection::HostToDevice,
-                ),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 64,
-                interval: 5,
-            },
-        ]];
+        let (interface, endpoints) = function_descriptors(0);
 
         let (device_descriptor_buffer, other_descriptor_buffer) =
             descriptors::create_descriptor_buffers(
@@ -152,8 +168,8 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
                 descriptors::ConfigurationDescriptor {
                     ..descriptors::ConfigurationDescriptor::default()
                 },
-                interfaces,
-                endpoints,
+                &mut [interface],
+                &[&endpoints],
                 Some(&HID_DESCRIPTOR),
                 None,
             );
@@ -187,6 +203,17 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
         self.client.set(client);
     }
 
+    /// Sets up the IN and OUT endpoint.
+    fn enable_endpoints(&'a self) {
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+    }
+
     fn can_receive(&'a self) -> bool {
         self.client
             .map(move |client| client.can_receive())
@@ -253,13 +280,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         // Set up the default control endpoint
         self.client_ctrl.enable();
 
-        // Setup buffers for IN and OUT data transfer.
-        self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
-        self.controller()
-            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -404,3 +425,84 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         });
     }
 }
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for CtapHid<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0x03, // HID
+            subclass: 0x00,
+            protocol: 0x00,
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        1 << ENDPOINT_NUM
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        let (mut interface, endpoints) = function_descriptors(first_interface);
+        interface.num_endpoints = endpoints.len() as u8;
+
+        writer.write(&interface);
+        writer.write(&HID_DESCRIPTOR);
+        for endpoint in endpoints.iter() {
+            writer.write(endpoint);
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData, data: &[Cell<u8>]) -> FunctionSetupResult {
+        match setup.get_standard_request() {
+            Some(StandardRequest::GetDescriptor {
+                descriptor_type: DescriptorType::HID,
+                ..
+            }) => match HID_DESCRIPTOR.write_to(data) {
+                0 => FunctionSetupResult::Error,
+                len => FunctionSetupResult::In(len),
+            },
+            Some(StandardRequest::GetDescriptor {
+                descriptor_type: DescriptorType::Report,
+                ..
+            }) => match REPORT.write_to(data) {
+                0 => FunctionSetupResult::Error,
+                len => FunctionSetupResult::In(len),
+            },
+            Some(_) => FunctionSetupResult::Error,
+            None => match setup.request_type.transfer_direction() {
+                // Class requests, such as SET_IDLE, are accepted and ignored
+                TransferDirection::HostToDevice => FunctionSetupResult::Out,
+                TransferDirection::DeviceToHost => FunctionSetupResult::Error,
+            },
+        }
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
@@ -157,6 +157,7 @@ pub enum DescriptorType {
     DeviceQualifier,
     OtherSpeedConfiguration,
     InterfacePower,
+    InterfaceAssociation = 0x0b,
     HID = 0x21,
     Report = 0x22,
     CdcInterface = 0x24,
@@ -172,6 +173,7 @@ fn get_descriptor_type(byte: u8) -> Option<DescriptorType> {
         6 => Some(DescriptorType::DeviceQualifier),
         7 => Some(DescriptorType::OtherSpeedConfiguration),
         8 => Some(DescriptorType::InterfacePower),
+        0x0b => Some(DescriptorType::InterfaceAssociation),
         0x21 => Some(DescriptorType::HID),
         0x22 => Some(DescriptorType::Report),
         0x24 => Some(DescriptorType::CdcInterface),
@@ -655,6 +657,64 @@ impl Descriptor for InterfaceDescriptor {
     }
 }
 
+/// Groups the interfaces of a function of a composite device (USB ECN
+/// "Interface Association Descriptors").
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8); // Size of descriptor
+        buf[1].set(DescriptorType::InterfaceAssociation as u8);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
+/// Capabilities of a Device Firmware Upgrade interface (DFU 1.1, section
+/// 4.1.3).
+pub struct DfuFunctionalDescriptor {
+    /// Bit 0: download capable, bit 1: upload capable, bit 2: the device
+    /// remains usable after manifestation, bit 3: the device detaches
+    /// itself.
+    pub attributes: u8,
+    pub detach_timeout: u16,
+    /// Maximum number of bytes per control transfer.
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+}
+
+impl Descriptor for DfuFunctionalDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(9); // Size of descriptor
+        buf[1].set(0x21); // DFU functional, which shares its value with HID
+        buf[2].set(self.attributes);
+        put_u16(&buf[3..5], self.detach_timeout);
+        put_u16(&buf[5..7], self.transfer_size);
+        put_u16(&buf[7..9], self.dfu_version);
+        9
+    }
+}
+
 pub struct EndpointAddress(u8);
 
 impl EndpointAddress {
@@ -860,6 +920,38 @@ impl Descriptor for CdcInterfaceDescriptor {
     }
 }
 
+/// The Ethernet Networking functional descriptor of a CDC-ECM function
+/// (CDC ECM 1.2, section 5.4).
+pub struct CdcEthernetNetworkingDescriptor {
+    /// Index of the string holding the MAC address, as 12 hexadecimal
+    /// digits.
+    pub mac_address_string: u8,
+    pub statistics: u32,
+    pub max_segment_size: u16,
+    pub multicast_filters: u16,
+    pub power_filters: u8,
+}
+
+impl Descriptor for CdcEthernetNetworkingDescriptor {
+    fn size(&self) -> usize {
+        13
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(13);
+        buf[1].set(DescriptorType::CdcInterface as u8);
+        buf[2].set(CdcInterfaceDescriptorSubType::EthernetNetworking as u8);
+        buf[3].set(self.mac_address_string);
+        for (b, v) in buf[4..8].iter().zip(self.statistics.to_le_bytes().iter()) {
+            b.set(*v);
+        }
+        put_u16(&buf[8..10], self.max_segment_size);
+        put_u16(&buf[10..12], self.multicast_filters);
+        buf[12].set(self.power_filters);
+        13
+    }
+}
+
 /// The data structure sent in a CDC-ACM Set Line Coding message.
 #[derive(Debug, Copy, Clone)]
 pub struct CdcAcmSetLineCodingData {
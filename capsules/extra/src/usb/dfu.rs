@@ -0,0 +1,614 @@
+//! USB Device Firmware Upgrade (DFU 1.1) of applications.
+//!
+//! This function lets a host install a TBF application image with stock
+//! tools, for instance `dfu-util -D app.tbf`. The image is written to app
+//! flash after the existing applications, and its credentials are checked by
+//! the `AppCredentialsChecker` of the board before it is marked as enabled.
+//! The kernel runs it from the next boot. An image with a higher version
+//! number than an installed application with the same identifier replaces
+//! it, following the usual credentials checking rules.
+//!
+//! The interface is always in DFU mode (rather than run-time mode), so that
+//! the host can download without resetting the device first. Each block
+//! sent by the host is written to flash before the next one is accepted:
+//!
+//! 1. The first block must start with the TBF header. The image is placed
+//!    after the end of the applications, aligned to its size if that is a
+//!    power of two (as the MPUs of most chips require), and always leaving
+//!    room for a padding header before it. The image is written exactly as
+//!    the host sent it, so its credentials cover the same bytes as when the
+//!    kernel checks them at boot.
+//! 2. The download ends with an empty block. The device then checks the
+//!    footers of the image in turn, until the checker accepts or rejects a
+//!    credential. If none is accepted, the image is accepted only if the
+//!    checker does not require credentials.
+//! 3. Once accepted, a padding header is written at the end of the
+//!    applications, which links the image into their list. Until then that
+//!    header is still erased, which ends the list before the image, so a
+//!    rejected, aborted or interrupted download is never loaded. A rejected
+//!    image gets the host the `errVERIFY` status.
+//!
+//! Kernel images are not supported, as the kernel cannot overwrite itself
+//! without a bootloader.
+//!
+//! The function is meant to be part of a `CompositeDevice`, which owns the
+//! control endpoint. It uses no other endpoint.
+//!
+//! The function must be the client of its checker, so the checker cannot be
+//! the instance the kernel checks processes with: the board creates another
+//! one for it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let dfu = static_init!(
+//!     UsbDfu<'static>,
+//!     UsbDfu::new(
+//!         nv_to_page,
+//!         core::slice::from_raw_parts(&_sapps as *const u8, app_flash_len),
+//!         checker,
+//!         static_init!([u8; TRANSFER_SIZE], [0; TRANSFER_SIZE])
+//!     )
+//! );
+//! nv_to_page.set_client(dfu);
+//! checker.set_client(dfu);
+//! composite.add_function(dfu).unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+
+use super::composite::{DescriptorWriter, FunctionClass, FunctionSetupResult, UsbFunction};
+use super::descriptors::DfuFunctionalDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::process_checker::{AppCredentialsChecker, CheckResult};
+use kernel::utilities::cells::{TakeCell, VolatileCell};
+use kernel::ErrorCode;
+use tock_tbf::types::{InitialTbfParseError, TbfFooterV2Credentials};
+
+/// Maximum number of bytes of a block of the download.
+pub const TRANSFER_SIZE: usize = 512;
+
+/// Time the host should wait before asking for the status of a block being
+/// written, or of the image being checked.
+const POLL_TIMEOUT_MS: u32 = 20;
+
+/// Length of the base of a TBF header, which is all a padding header has.
+const TBF_BASE_LEN: usize = 16;
+
+/// Class-specific requests.
+mod request {
+    pub const DETACH: u8 = 0;
+    pub const DNLOAD: u8 = 1;
+    pub const UPLOAD: u8 = 2;
+    pub const GETSTATUS: u8 = 3;
+    pub const CLRSTATUS: u8 = 4;
+    pub const GETSTATE: u8 = 5;
+    pub const ABORT: u8 = 6;
+}
+
+/// States of the DFU protocol, as reported to the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    Error = 10,
+}
+
+/// Status of the last operation, as reported to the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Ok = 0x00,
+    ErrTarget = 0x01,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrVerify = 0x07,
+    ErrStalledPacket = 0x0f,
+}
+
+/// Flash operations in progress.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operation {
+    None,
+    /// Writing a block of the image.
+    Writing,
+    /// Waiting for the checker.
+    Checking,
+    /// Writing the padding header linking the image to the applications.
+    Enabling,
+}
+
+pub struct UsbDfu<'a> {
+    /// Storage used to write app flash. Addresses are those at which app
+    /// flash is mapped.
+    storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    /// App flash, where images are read back to be checked.
+    app_flash: &'static [u8],
+    /// Checker of the images, whose client must be this function.
+    checker: &'a dyn AppCredentialsChecker<'a>,
+
+    /// Holds a block of the download, or a header to write.
+    buffer: TakeCell<'static, [u8]>,
+
+    state: Cell<State>,
+    status: Cell<Status>,
+    operation: Cell<Operation>,
+
+    /// Offset in app flash of the end of the applications, where the padding
+    /// before the image starts.
+    padding_start: Cell<usize>,
+    /// Offset in app flash of the image, and its total size.
+    image_start: Cell<usize>,
+    image_size: Cell<usize>,
+    /// Bytes of the image written so far.
+    received: Cell<usize>,
+
+    /// Bytes of the block being received, and the length of the block.
+    block_len: Cell<usize>,
+    block_expected: Cell<usize>,
+
+    /// Index of the footer being checked.
+    footer: Cell<usize>,
+
+    /// Number of the DFU interface.
+    interface: Cell<u8>,
+}
+
+impl<'a> UsbDfu<'a> {
+    /// Creates a function writing images to `app_flash` through `storage`.
+    /// `buffer` must hold at least `TRANSFER_SIZE` bytes.
+    pub fn new(
+        storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        app_flash: &'static [u8],
+        checker: &'a dyn AppCredentialsChecker<'a>,
+        buffer: &'static mut [u8],
+    ) -> Self {
+        UsbDfu {
+            storage: storage,
+            app_flash: app_flash,
+            checker: checker,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            status: Cell::new(Status::Ok),
+            operation: Cell::new(Operation::None),
+            padding_start: Cell::new(0),
+            image_start: Cell::new(0),
+            image_size: Cell::new(0),
+            received: Cell::new(0),
+            block_len: Cell::new(0),
+            block_expected: Cell::new(0),
+            footer: Cell::new(0),
+            interface: Cell::new(0),
+        }
+    }
+
+    /// Returns the offset of the end of the list of applications in app
+    /// flash.
+    fn apps_end(&self) -> usize {
+        let mut offset = 0;
+        while let Some(lengths) = self
+            .app_flash
+            .get(offset..offset + 8)
+            .and_then(|lengths| lengths.try_into().ok())
+        {
+            let total_size = match tock_tbf::parse::parse_tbf_header_lengths(lengths) {
+                Ok((_, _, total_size)) | Err(InitialTbfParseError::InvalidHeader(total_size)) => {
+                    total_size as usize
+                }
+                Err(InitialTbfParseError::UnableToParse) => break,
+            };
+            if total_size == 0 {
+                break;
+            }
+            offset += total_size;
+        }
+        offset
+    }
+
+    /// Chooses where to write an image of `size` bytes. Returns the offset
+    /// of the image, or `None` if it does not fit.
+    fn place_image(&self, size: usize) -> Option<usize> {
+        let base = self.app_flash.as_ptr() as usize;
+        let end = self.apps_end();
+        let alignment = if size.is_power_of_two() { size } else { 4 };
+        let mut address = (base + end + alignment - 1) & !(alignment - 1);
+        // The gap must fit the padding header
+        while address - (base + end) < TBF_BASE_LEN {
+            address += alignment;
+        }
+        let start = address - base;
+        if start.checked_add(size)? <= self.app_flash.len() {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Reads the TBF header at the start of the first block, and chooses
+    /// where to write the image.
+    fn start_image(&self, block: &[u8]) -> Result<(), Status> {
+        if block.len() < TBF_BASE_LEN || u16::from_le_bytes([block[0], block[1]]) != 2 {
+            return Err(Status::ErrFile);
+        }
+        let header_size = u16::from_le_bytes([block[2], block[3]]) as usize;
+        let total_size = u32::from_le_bytes([block[4], block[5], block[6], block[7]]) as usize;
+        if header_size < TBF_BASE_LEN || header_size > total_size {
+            return Err(Status::ErrFile);
+        }
+        let start = self.place_image(total_size).ok_or(Status::ErrAddress)?;
+
+        self.padding_start.set(self.apps_end());
+        self.image_start.set(start);
+        self.image_size.set(total_size);
+        Ok(())
+    }
+
+    /// Writes the block received from the host to flash.
+    fn write_block(&self) {
+        let result = self.buffer.take().map_or(Err(Status::ErrTarget), |buffer| {
+            let len = self.block_len.get();
+            if self.received.get() == 0 {
+                if let Err(status) = self.start_image(&buffer[..len]) {
+                    self.buffer.replace(buffer);
+                    return Err(status);
+                }
+            }
+            if self.received.get() + len > self.image_size.get() {
+                self.buffer.replace(buffer);
+                return Err(Status::ErrAddress);
+            }
+            let address =
+                self.app_flash.as_ptr() as usize + self.image_start.get() + self.received.get();
+            self.storage
+                .write(buffer, address, len)
+                .or(Err(Status::ErrWrite))
+        });
+        match result {
+            Ok(()) => self.operation.set(Operation::Writing),
+            Err(status) => self.fail(status),
+        }
+    }
+
+    /// Starts checking the image, once it was fully received.
+    fn start_manifest(&self) {
+        if self.received.get() == 0 || self.received.get() < self.image_size.get() {
+            self.fail(Status::ErrNotDone);
+            return;
+        }
+        self.state.set(State::Manifest);
+        self.footer.set(0);
+        self.operation.set(Operation::Checking);
+        self.check_next_footer();
+    }
+
+    /// Writes `header` at `offset` in app flash, as part of `operation`.
+    fn write_header(
+        &self,
+        header: &[u8],
+        offset: usize,
+        operation: Operation,
+    ) -> Result<(), ErrorCode> {
+        let result = self
+            .buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                buffer[..header.len()].copy_from_slice(header);
+                self.storage.write(
+                    buffer,
+                    self.app_flash.as_ptr() as usize + offset,
+                    header.len(),
+                )
+            });
+        match result {
+            Ok(()) => self.operation.set(operation),
+            Err(_) => self.operation.set(Operation::None),
+        }
+        result
+    }
+
+    /// Passes the next footer of the image to the checker. If there is none
+    /// left, the image is accepted only if credentials are not required.
+    fn check_next_footer(&self) {
+        let image = &self.app_flash[self.image_start.get()..][..self.image_size.get()];
+        let header = image
+            .get(..8)
+            .and_then(|lengths| lengths.try_into().ok())
+            .and_then(|lengths| tock_tbf::parse::parse_tbf_header_lengths(lengths).ok())
+            .and_then(|(version, header_size, _)| {
+                tock_tbf::parse::parse_tbf_header(&image[..header_size as usize], version).ok()
+            });
+        let header = match header {
+            Some(header) if header.is_app() => header,
+            _ => return self.fail(Status::ErrFile),
+        };
+        let binary_end = cmp::min(header.get_binary_end() as usize, image.len());
+        let binary = &image[..binary_end];
+
+        let mut footers = &image[binary_end..];
+        let mut index = 0;
+        while let Ok((credentials, len)) = tock_tbf::parse::parse_tbf_footer(footers) {
+            if index >= self.footer.get() {
+                self.footer.set(index);
+                match self.checker.check_credentials(credentials, binary) {
+                    Ok(()) => return,
+                    Err((ErrorCode::NOSUPPORT, _, _)) | Err((ErrorCode::ALREADY, _, _)) => {}
+                    Err(_) => return self.fail(Status::ErrVerify),
+                }
+            }
+            footers = match footers.get(len as usize + 4..) {
+                Some(footers) => footers,
+                None => break,
+            };
+            index += 1;
+        }
+
+        if self.checker.require_credentials() {
+            self.fail(Status::ErrVerify);
+        } else {
+            self.enable_image();
+        }
+    }
+
+    /// Links the image, which is now trusted, to the end of the applications
+    /// with a padding header, which has no TLV.
+    fn enable_image(&self) {
+        let gap = self.image_start.get() - self.padding_start.get();
+        let mut header = [0; TBF_BASE_LEN];
+        header[0..2].copy_from_slice(&2u16.to_le_bytes());
+        header[2..4].copy_from_slice(&(TBF_BASE_LEN as u16).to_le_bytes());
+        header[4..8].copy_from_slice(&(gap as u32).to_le_bytes());
+        // The checksum is the XOR of the words of the header
+        let checksum = (2 | (TBF_BASE_LEN as u32) << 16) ^ gap as u32;
+        header[12..16].copy_from_slice(&checksum.to_le_bytes());
+        if self
+            .write_header(&header, self.padding_start.get(), Operation::Enabling)
+            .is_err()
+        {
+            self.fail(Status::ErrWrite);
+        }
+    }
+
+    /// Ends the current download with `status`. What was written of the
+    /// image stays unreachable, past the end of the applications.
+    fn fail(&self, status: Status) {
+        self.state.set(State::Error);
+        self.status.set(status);
+        self.operation.set(Operation::None);
+        self.received.set(0);
+    }
+
+    /// Writes the answer to GETSTATUS to `data`, and advances the state of
+    /// the download.
+    fn get_status(&self, data: &[Cell<u8>]) -> usize {
+        if self.state.get() == State::ManifestSync && self.operation.get() == Operation::None {
+            self.start_manifest();
+        }
+        let (state, poll_timeout) = match (self.state.get(), self.operation.get()) {
+            (State::DnloadSync, Operation::Writing) => (State::DnBusy, POLL_TIMEOUT_MS),
+            (State::Manifest, _) => (State::Manifest, POLL_TIMEOUT_MS),
+            (state, _) => (state, 0),
+        };
+        data[0].set(self.status.get() as u8);
+        for (i, b) in poll_timeout.to_le_bytes()[..3].iter().enumerate() {
+            data[1 + i].set(*b);
+        }
+        data[4].set(state as u8);
+        data[5].set(0); // No status description string
+        6
+    }
+
+    /// Handles a request to the DFU interface that the current state does
+    /// not allow.
+    fn stall(&self) -> FunctionSetupResult {
+        if self.operation.get() == Operation::None {
+            self.fail(Status::ErrStalledPacket);
+        }
+        FunctionSetupResult::Error
+    }
+}
+
+impl<'a> UsbFunction<'a> for UsbDfu<'a> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0xfe,    // Application specific
+            subclass: 0x01, // Device firmware upgrade
+            protocol: 0x02, // DFU mode
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        0
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        self.interface.set(first_interface);
+        let class = self.function_class();
+        writer.write(&InterfaceDescriptor {
+            interface_number: first_interface,
+            interface_class: class.class,
+            interface_subclass: class.subclass,
+            interface_protocol: class.protocol,
+            ..InterfaceDescriptor::default()
+        });
+        writer.write(&DfuFunctionalDescriptor {
+            // Download capable and manifestation tolerant
+            attributes: 0x01 | 0x04,
+            detach_timeout: 0,
+            transfer_size: TRANSFER_SIZE as u16,
+            dfu_version: 0x0110,
+        });
+    }
+
+    fn enable(&'a self) {}
+
+    fn ctrl_setup(&'a self, setup: SetupData, data: &[Cell<u8>]) -> FunctionSetupResult {
+        // Other requests are refused without changing the state of the
+        // download, as they are not meant for DFU
+        match (
+            setup.request_type.request_type(),
+            setup.request_type.recipient(),
+        ) {
+            (RequestType::Class, Recipient::Interface)
+                if setup.index == self.interface.get() as u16 => {}
+            _ => return FunctionSetupResult::Error,
+        }
+        let direction_in = match setup.request_type.transfer_direction() {
+            TransferDirection::DeviceToHost => true,
+            TransferDirection::HostToDevice => false,
+        };
+        match (setup.request_code, direction_in) {
+            (request::DNLOAD, false) => match self.state.get() {
+                State::Idle | State::DnloadIdle if setup.length > 0 => {
+                    if setup.length as usize > TRANSFER_SIZE {
+                        return self.stall();
+                    }
+                    self.block_len.set(0);
+                    self.block_expected.set(setup.length as usize);
+                    self.state.set(State::DnloadSync);
+                    FunctionSetupResult::Out
+                }
+                State::DnloadIdle => {
+                    // The end of the download
+                    self.state.set(State::ManifestSync);
+                    FunctionSetupResult::Out
+                }
+                _ => self.stall(),
+            },
+            (request::GETSTATUS, true) => FunctionSetupResult::In(self.get_status(data)),
+            (request::GETSTATE, true) => {
+                data[0].set(self.state.get() as u8);
+                FunctionSetupResult::In(1)
+            }
+            (request::CLRSTATUS, false) => match self.state.get() {
+                State::Error if self.operation.get() == Operation::None => {
+                    self.state.set(State::Idle);
+                    self.status.set(Status::Ok);
+                    FunctionSetupResult::Out
+                }
+                _ => self.stall(),
+            },
+            (request::ABORT, false) => match self.state.get() {
+                State::Idle | State::DnloadIdle | State::Error
+                    if self.operation.get() == Operation::None =>
+                {
+                    self.received.set(0);
+                    self.state.set(State::Idle);
+                    self.status.set(Status::Ok);
+                    FunctionSetupResult::Out
+                }
+                _ => self.stall(),
+            },
+            // Images cannot be read back, and the interface is always in
+            // DFU mode
+            (request::UPLOAD, _) | (request::DETACH, _) => self.stall(),
+            _ => self.stall(),
+        }
+    }
+
+    fn ctrl_out(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlOutResult {
+        if self.state.get() != State::DnloadSync {
+            return hil::usb::CtrlOutResult::Halted;
+        }
+        let offset = self.block_len.get();
+        let len = cmp::min(packet.len(), self.block_expected.get() - offset);
+        self.buffer
+            .map_or(hil::usb::CtrlOutResult::Halted, |buffer| {
+                for (b, p) in buffer[offset..offset + len].iter_mut().zip(packet) {
+                    *b = p.get();
+                }
+                self.block_len.set(offset + len);
+                hil::usb::CtrlOutResult::Ok
+            })
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        if self.state.get() == State::DnloadSync && self.operation.get() == Operation::None {
+            if self.block_len.get() == self.block_expected.get() {
+                self.write_block();
+            } else {
+                self.fail(Status::ErrNotDone);
+            }
+        }
+    }
+
+    fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        hil::usb::InResult::Error
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for UsbDfu<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        match self.operation.get() {
+            Operation::Writing => {
+                self.operation.set(Operation::None);
+                self.received.set(self.received.get() + length);
+                self.state.set(State::DnloadIdle);
+            }
+            Operation::Enabling => {
+                // The image is complete, ready for the next one
+                self.operation.set(Operation::None);
+                self.received.set(0);
+                self.state.set(State::Idle);
+            }
+            Operation::Checking | Operation::None => {
+                self.operation.set(Operation::None);
+            }
+        }
+    }
+}
+
+impl<'a> kernel::process_checker::Client<'a> for UsbDfu<'a> {
+    fn check_done(
+        &self,
+        result: Result<CheckResult, ErrorCode>,
+        _credentials: TbfFooterV2Credentials,
+        _binary: &'a [u8],
+    ) {
+        if self.operation.get() != Operation::Checking {
+            return;
+        }
+        match result {
+            Ok(CheckResult::Accept) => self.enable_image(),
+            Ok(CheckResult::Reject) => self.fail(Status::ErrVerify),
+            Ok(CheckResult::Pass) | Err(_) => {
+                self.footer.set(self.footer.get() + 1);
+                self.check_next_footer();
+            }
+        }
+    }
+}
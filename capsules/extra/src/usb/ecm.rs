@@ -0,0 +1,538 @@
+//! USB CDC-ECM (Ethernet Control Model) function, which makes the device
+//! appear as a USB Ethernet adapter to the host.
+//!
+//! The function implements `hil::ethernet::EthernetAdapter`, so that the
+//! IPv6 and UDP stack in `capsules::net` runs over it through
+//! `IP6EthernetStruct`, as over any other Ethernet adapter. Hosts support
+//! ECM without a specific driver (for instance `cdc_ether` on Linux).
+//!
+//! The function has two interfaces: a communication interface, whose
+//! interrupt endpoint notifies the host that the link is up, and a data
+//! interface. Ethernet frames are exchanged on the bulk endpoints of the
+//! second alternate setting of the data interface, each frame as a sequence
+//! of packets that ends with a short (possibly empty) packet. The default
+//! alternate setting has no endpoints, and no frames are exchanged while the
+//! host selects it.
+//!
+//! The host learns the MAC address of its end of the link from a string
+//! descriptor, which the function defines at an index chosen by the board.
+//! This address must differ from the one the device uses.
+//!
+//! Frames are received into a buffer of `MAX_FRAME_LEN` bytes and passed to
+//! the client as soon as they are complete. Frames that do not fit are
+//! dropped.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let rx_buffer = static_init!([u8; MAX_FRAME_LEN], [0; MAX_FRAME_LEN]);
+//! let ecm = static_init!(
+//!     CdcEcm<'static, nrf52840::usbd::Usbd<'static>>,
+//!     CdcEcm::new(
+//!         &nrf52840_peripherals.usbd,
+//!         4, // MAC string index
+//!         EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]), // Host MAC
+//!         1, // Notifications
+//!         2, // Data IN
+//!         3, // Data OUT
+//!         rx_buffer,
+//!     )
+//! );
+//! composite.add_function(ecm).unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::{DescriptorWriter, FunctionClass, FunctionSetupResult, UsbFunction};
+use super::descriptors::Buffer64;
+use super::descriptors::CdcEthernetNetworkingDescriptor;
+use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::CdcInterfaceDescriptorSubType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use crate::net::ethernet::EthernetAddress;
+
+use kernel::hil;
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Largest Ethernet frame, without the frame check sequence.
+pub const MAX_FRAME_LEN: usize = 1514;
+
+/// Size of the packets on the bulk endpoints.
+const PACKET_SIZE: usize = 64;
+
+/// Size of the packets on the notification endpoint, which fits the largest
+/// notification.
+const NOTIFICATION_PACKET_SIZE: usize = 16;
+
+/// Bit rate reported to the host, that of a full-speed device.
+const BIT_RATE: u32 = 12_000_000;
+
+/// Standard requests to the interfaces, which select their alternate
+/// settings.
+mod request {
+    pub const GET_INTERFACE: u8 = 0x0a;
+    pub const SET_INTERFACE: u8 = 0x0b;
+}
+
+/// Notification codes (CDC 1.2, table 20).
+mod notification {
+    pub const NETWORK_CONNECTION: u8 = 0x00;
+    pub const CONNECTION_SPEED_CHANGE: u8 = 0x2a;
+}
+
+/// The notifications left to send to announce the link.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Notify {
+    None,
+    Connection,
+    Speed,
+}
+
+pub struct CdcEcm<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// Index of the string descriptor holding the MAC address of the host.
+    mac_string_index: u8,
+    /// The MAC address of the host, in hexadecimal digits.
+    mac_string: [u8; 12],
+
+    endpoint_notify: usize,
+    endpoint_in: usize,
+    endpoint_out: usize,
+
+    notify_buffer: Buffer64,
+    in_buffer: Buffer64,
+    out_buffer: Buffer64,
+
+    /// Number of the communication interface, followed by the data
+    /// interface.
+    first_interface: Cell<u8>,
+    /// Whether the host selected the alternate setting of the data
+    /// interface with endpoints.
+    active: Cell<bool>,
+    notify: Cell<Notify>,
+
+    client: OptionalCell<&'a dyn EthernetAdapterClient>,
+
+    /// The frame being sent, its length, the number of bytes sent so far
+    /// and the size of the last packet.
+    tx_frame: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    tx_packet: Cell<usize>,
+
+    /// The frame being received, and the number of bytes received so far.
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    /// Whether the frame being received is dropped.
+    rx_dropped: Cell<bool>,
+    receive_enabled: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CdcEcm<'a, U> {
+    /// Creates the function. `mac_string_index` must not be used by other
+    /// strings of the device, and `host_address` is the MAC address of the
+    /// host interface.
+    pub fn new(
+        controller: &'a U,
+        mac_string_index: u8,
+        host_address: EthernetAddress,
+        endpoint_notify: usize,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        rx_buffer: &'static mut [u8],
+    ) -> Self {
+        const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        let mut mac_string = [0; 12];
+        for (digits, byte) in mac_string.chunks_mut(2).zip(host_address.0.iter()) {
+            digits[0] = HEX_DIGITS[(byte >> 4) as usize];
+            digits[1] = HEX_DIGITS[(byte & 0xf) as usize];
+        }
+
+        CdcEcm {
+            controller: controller,
+            mac_string_index: mac_string_index,
+            mac_string: mac_string,
+            endpoint_notify: endpoint_notify,
+            endpoint_in: endpoint_in,
+            endpoint_out: endpoint_out,
+            notify_buffer: Buffer64::default(),
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            first_interface: Cell::new(0),
+            active: Cell::new(false),
+            notify: Cell::new(Notify::None),
+            client: OptionalCell::empty(),
+            tx_frame: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_packet: Cell::new(0),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            rx_dropped: Cell::new(false),
+            receive_enabled: Cell::new(false),
+        }
+    }
+
+    /// Starts or stops the exchange of frames, when the host selects an
+    /// alternate setting of the data interface.
+    fn set_active(&self, active: bool) {
+        self.active.set(active);
+        self.rx_len.set(0);
+        self.rx_dropped.set(false);
+        if active {
+            // Announce the link
+            self.notify.set(Notify::Connection);
+            self.controller.endpoint_resume_in(self.endpoint_notify);
+        } else {
+            self.notify.set(Notify::None);
+            self.tx_frame.take().map(|frame| {
+                let len = self.tx_len.get();
+                self.client
+                    .map(move |client| client.tx_done(Err(ErrorCode::CANCEL), frame, len));
+            });
+        }
+    }
+
+    /// Writes the header of a notification to `notify_buffer`.
+    fn write_notification(&self, code: u8, value: u16, length: u16) {
+        let header = [
+            0xa1, // Class request from the interface to the host
+            code,
+            value as u8,
+            (value >> 8) as u8,
+            self.first_interface.get(),
+            0,
+            length as u8,
+            (length >> 8) as u8,
+        ];
+        for (packet, b) in self.notify_buffer.buf.iter().zip(header.iter()) {
+            packet.set(*b);
+        }
+    }
+
+    fn notification_in(&self) -> hil::usb::InResult {
+        match self.notify.get() {
+            Notify::None => hil::usb::InResult::Delay,
+            Notify::Connection => {
+                self.write_notification(notification::NETWORK_CONNECTION, 1, 0);
+                hil::usb::InResult::Packet(8)
+            }
+            Notify::Speed => {
+                self.write_notification(notification::CONNECTION_SPEED_CHANGE, 0, 8);
+                let rates = BIT_RATE.to_le_bytes();
+                for (packet, b) in self.notify_buffer.buf[8..16]
+                    .iter()
+                    .zip(rates.iter().chain(rates.iter()))
+                {
+                    packet.set(*b);
+                }
+                hil::usb::InResult::Packet(16)
+            }
+        }
+    }
+
+    fn data_in(&self) -> hil::usb::InResult {
+        self.tx_frame.map_or(hil::usb::InResult::Delay, |frame| {
+            let offset = self.tx_offset.get();
+            let len = cmp::min(self.tx_len.get() - offset, PACKET_SIZE);
+            for (packet, b) in self.in_buffer.buf.iter().zip(frame[offset..].iter()) {
+                packet.set(*b);
+            }
+            self.tx_packet.set(len);
+            // A frame that fills its last packet is followed by an empty
+            // packet, which is sent the same way.
+            hil::usb::InResult::Packet(len)
+        })
+    }
+
+    fn frame_received(&self) {
+        let len = self.rx_len.get();
+        self.rx_len.set(0);
+        if self.rx_dropped.take() || !self.receive_enabled.get() || len == 0 {
+            return;
+        }
+        self.rx_buffer.map(|buffer| {
+            self.client.map(|client| client.rx_frame(&buffer[..len]));
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> EthernetAdapter<'a> for CdcEcm<'a, U> {
+    fn set_client(&self, client: &'a dyn EthernetAdapterClient) {
+        self.client.set(client);
+    }
+
+    fn enable_receive(&self) {
+        self.receive_enabled.set(true);
+    }
+
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_frame.is_some() {
+            return Err((ErrorCode::BUSY, frame));
+        }
+        if len > frame.len() || len > MAX_FRAME_LEN {
+            return Err((ErrorCode::SIZE, frame));
+        }
+        if !self.active.get() {
+            return Err((ErrorCode::OFF, frame));
+        }
+        self.tx_frame.replace(frame);
+        self.tx_len.set(len);
+        self.tx_offset.set(0);
+        self.controller.endpoint_resume_in(self.endpoint_in);
+        Ok(())
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for CdcEcm<'a, U> {
+    fn interface_count(&self) -> u8 {
+        2
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0x02,    // CDC
+            subclass: 0x06, // Ethernet Control Model
+            protocol: 0x00,
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        (1 << self.endpoint_notify) | (1 << self.endpoint_in) | (1 << self.endpoint_out)
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        self.first_interface.set(first_interface);
+
+        writer.write(&InterfaceDescriptor {
+            interface_number: first_interface,
+            interface_class: 0x02,    // CDC
+            interface_subclass: 0x06, // Ethernet Control Model
+            interface_protocol: 0x00,
+            num_endpoints: 1,
+            ..InterfaceDescriptor::default()
+        });
+        writer.write(&CdcInterfaceDescriptor {
+            subtype: CdcInterfaceDescriptorSubType::Header,
+            field1: 0x10, // CDC 1.10
+            field2: 0x01,
+        });
+        writer.write(&CdcInterfaceDescriptor {
+            subtype: CdcInterfaceDescriptorSubType::Union,
+            field1: first_interface,     // Communication interface
+            field2: first_interface + 1, // Data interface
+        });
+        writer.write(&CdcEthernetNetworkingDescriptor {
+            mac_address_string: self.mac_string_index,
+            statistics: 0,
+            max_segment_size: MAX_FRAME_LEN as u16,
+            multicast_filters: 0,
+            power_filters: 0,
+        });
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_notify,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: NOTIFICATION_PACKET_SIZE as u16,
+            interval: 32,
+        });
+
+        // The default alternate setting of the data interface has no
+        // endpoints
+        for alternate_setting in 0..2 {
+            writer.write(&InterfaceDescriptor {
+                interface_number: first_interface + 1,
+                alternate_setting: alternate_setting,
+                interface_class: 0x0a, // CDC Data
+                interface_subclass: 0x00,
+                interface_protocol: 0x00,
+                num_endpoints: 2 * alternate_setting,
+                ..InterfaceDescriptor::default()
+            });
+        }
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_in,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: PACKET_SIZE as u16,
+            interval: 0,
+        });
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_out,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: PACKET_SIZE as u16,
+            interval: 0,
+        });
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_notify, &self.notify_buffer.buf);
+        self.controller
+            .endpoint_in_enable(TransferType::Interrupt, self.endpoint_notify);
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_in, &self.in_buffer.buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint_out, &self.out_buffer.buf);
+        if self.endpoint_in == self.endpoint_out {
+            self.controller
+                .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_in);
+        } else {
+            self.controller
+                .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
+            self.controller
+                .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
+        }
+    }
+
+    fn bus_reset(&'a self) {
+        self.set_active(false);
+    }
+
+    fn configured(&'a self, _configured: bool) {
+        // Selecting a configuration also selects the default alternate
+        // settings
+        self.set_active(false);
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData, data: &[Cell<u8>]) -> FunctionSetupResult {
+        let data_interface = self.first_interface.get() as u16 + 1;
+        match setup.request_type.request_type() {
+            RequestType::Standard => match setup.request_code {
+                request::GET_INTERFACE => {
+                    let setting = if setup.index == data_interface && self.active.get() {
+                        1
+                    } else {
+                        0
+                    };
+                    data[0].set(setting);
+                    FunctionSetupResult::In(1)
+                }
+                request::SET_INTERFACE => match (setup.index == data_interface, setup.value) {
+                    (true, setting @ 0..=1) => {
+                        self.set_active(setting == 1);
+                        FunctionSetupResult::Out
+                    }
+                    (false, 0) => FunctionSetupResult::Out,
+                    _ => FunctionSetupResult::Error,
+                },
+                _ => FunctionSetupResult::Error,
+            },
+            RequestType::Class => match setup.request_type.transfer_direction() {
+                // SET_ETHERNET_PACKET_FILTER and the multicast filters are
+                // accepted; all frames are passed to the client, which
+                // filters them.
+                TransferDirection::HostToDevice => FunctionSetupResult::Out,
+                // No statistics are kept
+                TransferDirection::DeviceToHost => FunctionSetupResult::Error,
+            },
+            RequestType::Vendor | RequestType::Reserved => FunctionSetupResult::Error,
+        }
+    }
+
+    fn string(&self, index: u8) -> Option<&str> {
+        if index == self.mac_string_index {
+            core::str::from_utf8(&self.mac_string).ok()
+        } else {
+            None
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt if endpoint == self.endpoint_notify => self.notification_in(),
+            TransferType::Bulk if self.active.get() => self.data_in(),
+            _ => hil::usb::InResult::Error,
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {}
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                return hil::usb::OutResult::Error;
+            }
+        }
+        if !self.active.get() {
+            return hil::usb::OutResult::Error;
+        }
+
+        let packet_len = cmp::min(packet_bytes as usize, PACKET_SIZE);
+        let offset = self.rx_len.get();
+        self.rx_buffer.map(|buffer| {
+            if offset + packet_len > buffer.len() {
+                self.rx_dropped.set(true);
+            } else {
+                for (b, packet) in buffer[offset..offset + packet_len]
+                    .iter_mut()
+                    .zip(self.out_buffer.buf.iter())
+                {
+                    *b = packet.get();
+                }
+            }
+        });
+        if !self.rx_dropped.get() {
+            self.rx_len.set(offset + packet_len);
+        }
+
+        // A short packet ends the frame
+        if packet_len < PACKET_SIZE {
+            self.frame_received();
+        }
+        hil::usb::OutResult::Ok
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if endpoint == self.endpoint_notify {
+            match self.notify.get() {
+                Notify::Connection => {
+                    self.notify.set(Notify::Speed);
+                    self.controller.endpoint_resume_in(self.endpoint_notify);
+                }
+                Notify::Speed | Notify::None => self.notify.set(Notify::None),
+            }
+            return;
+        }
+
+        let packet = self.tx_packet.get();
+        self.tx_offset.set(self.tx_offset.get() + packet);
+        if packet == PACKET_SIZE {
+            // More data, or the empty packet ending the frame
+            self.controller.endpoint_resume_in(self.endpoint_in);
+        } else {
+            self.tx_frame.take().map(|frame| {
+                let len = self.tx_len.get();
+                self.client
+                    .map(move |client| client.tx_done(Ok(()), frame, len));
+            });
+        }
+    }
+}
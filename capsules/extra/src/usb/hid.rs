@@ -0,0 +1,534 @@
+//! USB HID keyboard and mouse functions, with boot protocol support.
+//!
+//! A `BootHid` is a single HID interface with an interrupt IN endpoint,
+//! which reports either keyboard or mouse input. It belongs to the boot
+//! interface subclass, so that firmware which does not parse report
+//! descriptors (such as a BIOS) can use it with the fixed boot report
+//! formats. Hosts that parse the report descriptor use the report protocol,
+//! which for the mouse adds a wheel to the report.
+//!
+//! Reports are exchanged as 8-byte buffers through `hil::usb_hid::UsbHid`:
+//!
+//! - Keyboard input: `[modifiers, 0, key1, key2, key3, key4, key5, key6]`,
+//!   where the keys are usage IDs of the keyboard usage page. The report is
+//!   the same in both protocols.
+//! - Keyboard output: `[leds, ..]`, the LED state set by the host with a
+//!   SET_REPORT request, with Num Lock in bit 0, Caps Lock in bit 1, Scroll
+//!   Lock in bit 2, Compose in bit 3 and Kana in bit 4.
+//! - Mouse input: `[buttons, x, y, wheel, ..]`, where x, y and the wheel are
+//!   signed relative movements. The wheel is not sent in the boot protocol.
+//!
+//! A report is sent when the client provides it. The idle rate set by the
+//! host is recorded, but reports are not repeated while they do not change.
+//!
+//! The function is meant to be part of a `CompositeDevice`, which owns the
+//! control endpoint.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let keyboard = static_init!(
+//!     BootHid<'static, nrf52840::usbd::Usbd<'static>>,
+//!     BootHid::new(&nrf52840_peripherals.usbd, BootDevice::Keyboard, 1)
+//! );
+//! composite.add_function(keyboard).unwrap();
+//! ```
+
+use core::cell::Cell;
+
+use super::composite::{DescriptorWriter, FunctionClass, FunctionSetupResult, UsbFunction};
+use super::descriptors::Buffer8;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::TransferDirection;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::ErrorCode;
+
+/// Size of the report buffers exchanged with the client.
+pub const REPORT_SIZE: usize = 8;
+
+/// Polling interval of the interrupt endpoint, in milliseconds.
+const POLL_INTERVAL: u8 = 10;
+
+/// HID class requests.
+mod request {
+    pub const GET_REPORT: u8 = 0x01;
+    pub const GET_IDLE: u8 = 0x02;
+    pub const GET_PROTOCOL: u8 = 0x03;
+    pub const SET_REPORT: u8 = 0x09;
+    pub const SET_IDLE: u8 = 0x0a;
+    pub const SET_PROTOCOL: u8 = 0x0b;
+}
+
+/// Report types, in the high byte of the value of GET_REPORT and SET_REPORT.
+mod report_type {
+    pub const INPUT: u8 = 0x01;
+    pub const OUTPUT: u8 = 0x02;
+}
+
+/// The report descriptor of the keyboard, which matches the boot keyboard
+/// report (HID 1.11, appendix B.1).
+static KEYBOARD_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): modifiers
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x01, //   Input (Constant): reserved
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): LEDs
+    0x75, 0x03, //   Report Size (3)
+    0x95, 0x01, //   Report Count (1)
+    0x91, 0x01, //   Output (Constant): padding
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xFF, //   Usage Maximum (255)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, // Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x06, //   Report Count (6)
+    0x81, 0x00, //   Input (Data, Array): keys
+    0xC0, // End Collection
+];
+
+/// The report descriptor of the mouse. Its first three bytes match the boot
+/// mouse report (HID 1.11, appendix B.2), and are followed by the wheel.
+static MOUSE_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xA1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (Button 1)
+    0x29, 0x03, //     Usage Maximum (Button 3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x75, 0x01, //     Report Size (1)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x02, //     Input (Data, Variable, Absolute): buttons
+    0x75, 0x05, //     Report Size (5)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x01, //     Input (Constant): padding
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7F, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data, Variable, Relative): x, y, wheel
+    0xC0, //   End Collection
+    0xC0, // End Collection
+];
+
+static KEYBOARD_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: KEYBOARD_REPORT_DESCRIPTOR,
+};
+
+static MOUSE_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: MOUSE_REPORT_DESCRIPTOR,
+};
+
+static KEYBOARD_HID_DESCRIPTOR: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: KEYBOARD_REPORT_DESCRIPTOR.len() as u16,
+    }],
+};
+
+static MOUSE_HID_DESCRIPTOR: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: MOUSE_REPORT_DESCRIPTOR.len() as u16,
+    }],
+};
+
+/// The kind of device, which is also its boot interface protocol.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BootDevice {
+    Keyboard = 1,
+    Mouse = 2,
+}
+
+impl BootDevice {
+    fn hid_descriptor(self) -> &'static HIDDescriptor<'static> {
+        match self {
+            BootDevice::Keyboard => &KEYBOARD_HID_DESCRIPTOR,
+            BootDevice::Mouse => &MOUSE_HID_DESCRIPTOR,
+        }
+    }
+
+    fn report_descriptor(self) -> &'static ReportDescriptor<'static> {
+        match self {
+            BootDevice::Keyboard => &KEYBOARD_REPORT,
+            BootDevice::Mouse => &MOUSE_REPORT,
+        }
+    }
+
+    /// The idle rate after a reset, in units of 4 ms (HID 1.11, 7.2.4).
+    fn default_idle_rate(self) -> u8 {
+        match self {
+            BootDevice::Keyboard => 125, // 500 ms
+            BootDevice::Mouse => 0,      // Only report changes
+        }
+    }
+}
+
+/// The protocol selected by the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+pub struct BootHid<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    device: BootDevice,
+
+    /// Interrupt endpoint on which reports are sent to the host.
+    endpoint: usize,
+    in_buffer: Buffer8,
+
+    client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; REPORT_SIZE]>>,
+
+    protocol: Cell<Protocol>,
+    idle_rate: Cell<u8>,
+
+    /// The last input report sent, returned by GET_REPORT.
+    report: [Cell<u8>; REPORT_SIZE],
+
+    /// The report being sent, if any.
+    send_buffer: TakeCell<'static, [u8; REPORT_SIZE]>,
+
+    /// The buffer to receive the next output report into, if any.
+    recv_buffer: TakeCell<'static, [u8; REPORT_SIZE]>,
+
+    /// The last output report (the keyboard LEDs) set by the host.
+    leds: Cell<u8>,
+    /// Whether the data stage of a SET_REPORT request is expected.
+    set_report: Cell<bool>,
+    /// Whether an output report is waiting for a receive buffer.
+    recv_pending: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> BootHid<'a, U> {
+    /// Creates a keyboard or a mouse sending reports on the interrupt
+    /// endpoint `endpoint`.
+    pub fn new(controller: &'a U, device: BootDevice, endpoint: usize) -> Self {
+        BootHid {
+            controller: controller,
+            device: device,
+            endpoint: endpoint,
+            in_buffer: Buffer8::default(),
+            client: OptionalCell::empty(),
+            protocol: Cell::new(Protocol::Report),
+            idle_rate: Cell::new(device.default_idle_rate()),
+            report: Default::default(),
+            send_buffer: TakeCell::empty(),
+            recv_buffer: TakeCell::empty(),
+            leds: Cell::new(0),
+            set_report: Cell::new(false),
+            recv_pending: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; REPORT_SIZE]>) {
+        self.client.set(client);
+    }
+
+    pub fn device(&self) -> BootDevice {
+        self.device
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.get()
+    }
+
+    /// Length of the input reports in the current protocol.
+    fn report_len(&self) -> usize {
+        match (self.device, self.protocol.get()) {
+            (BootDevice::Keyboard, _) => 8,
+            (BootDevice::Mouse, Protocol::Boot) => 3,
+            (BootDevice::Mouse, Protocol::Report) => 4,
+        }
+    }
+
+    /// Passes the last output report to the client, if it can take it.
+    fn deliver_output_report(&self) {
+        if !self.client.map_or(false, |client| client.can_receive()) {
+            return;
+        }
+        self.recv_buffer.take().map(|buf| {
+            buf.iter_mut().for_each(|b| *b = 0);
+            buf[0] = self.leds.get();
+            self.recv_pending.set(false);
+            self.client.map(move |client| {
+                client.packet_received(Ok(()), buf, self.endpoint);
+            });
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; REPORT_SIZE]>
+    for BootHid<'a, U>
+{
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; REPORT_SIZE],
+    ) -> Result<usize, (ErrorCode, &'static mut [u8; REPORT_SIZE])> {
+        if self.send_buffer.is_some() {
+            return Err((ErrorCode::BUSY, send));
+        }
+        self.send_buffer.replace(send);
+        self.controller.endpoint_resume_in(self.endpoint);
+
+        Ok(self.report_len())
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; REPORT_SIZE], ErrorCode> {
+        self.send_buffer.take().ok_or(ErrorCode::INVAL)
+    }
+
+    /// Receives the next output report. Only keyboards have output reports.
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; REPORT_SIZE],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; REPORT_SIZE])> {
+        if self.device != BootDevice::Keyboard {
+            return Err((ErrorCode::NOSUPPORT, recv));
+        }
+        if self.recv_buffer.is_some() {
+            return Err((ErrorCode::BUSY, recv));
+        }
+        self.recv_buffer.replace(recv);
+        if self.recv_pending.get() {
+            self.deliver_output_report();
+        }
+
+        Ok(())
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; REPORT_SIZE], ErrorCode> {
+        self.recv_buffer.take().ok_or(ErrorCode::INVAL)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for BootHid<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0x03,    // HID
+            subclass: 0x01, // Boot interface
+            protocol: self.device as u8,
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        1 << self.endpoint
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        writer.write(&InterfaceDescriptor {
+            interface_number: first_interface,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x01, // Boot interface
+            interface_protocol: self.device as u8,
+            num_endpoints: 1,
+            ..InterfaceDescriptor::default()
+        });
+        writer.write(self.device.hid_descriptor());
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: REPORT_SIZE as u16,
+            interval: POLL_INTERVAL,
+        });
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint, &self.in_buffer.buf);
+        self.controller
+            .endpoint_in_enable(TransferType::Interrupt, self.endpoint);
+    }
+
+    fn bus_reset(&'a self) {
+        self.protocol.set(Protocol::Report);
+        self.idle_rate.set(self.device.default_idle_rate());
+        self.set_report.set(false);
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData, data: &[Cell<u8>]) -> FunctionSetupResult {
+        self.set_report.set(false);
+        match setup.request_type.request_type() {
+            RequestType::Standard => {
+                let descriptor: &dyn Descriptor = match setup.get_standard_request() {
+                    Some(StandardRequest::GetDescriptor {
+                        descriptor_type: DescriptorType::HID,
+                        ..
+                    }) => self.device.hid_descriptor(),
+                    Some(StandardRequest::GetDescriptor {
+                        descriptor_type: DescriptorType::Report,
+                        ..
+                    }) => self.device.report_descriptor(),
+                    _ => return FunctionSetupResult::Error,
+                };
+                match descriptor.write_to(data) {
+                    0 => FunctionSetupResult::Error,
+                    len => FunctionSetupResult::In(len),
+                }
+            }
+            RequestType::Class => {
+                let value_high = (setup.value >> 8) as u8;
+                let value_low = setup.value as u8;
+                match setup.request_code {
+                    request::GET_REPORT => match value_high {
+                        report_type::INPUT => {
+                            let len = self.report_len();
+                            for (d, r) in data.iter().zip(self.report[..len].iter()) {
+                                d.set(r.get());
+                            }
+                            FunctionSetupResult::In(len)
+                        }
+                        report_type::OUTPUT if self.device == BootDevice::Keyboard => {
+                            data[0].set(self.leds.get());
+                            FunctionSetupResult::In(1)
+                        }
+                        _ => FunctionSetupResult::Error,
+                    },
+                    request::SET_REPORT => match value_high {
+                        report_type::OUTPUT if self.device == BootDevice::Keyboard => {
+                            self.set_report.set(true);
+                            FunctionSetupResult::Out
+                        }
+                        _ => FunctionSetupResult::Error,
+                    },
+                    request::GET_IDLE => {
+                        data[0].set(self.idle_rate.get());
+                        FunctionSetupResult::In(1)
+                    }
+                    request::SET_IDLE => {
+                        // The idle rate applies to all reports, as there
+                        // are no report IDs.
+                        self.idle_rate.set(value_high);
+                        FunctionSetupResult::Out
+                    }
+                    request::GET_PROTOCOL => {
+                        data[0].set(self.protocol.get() as u8);
+                        FunctionSetupResult::In(1)
+                    }
+                    request::SET_PROTOCOL => match value_low {
+                        0 => {
+                            self.protocol.set(Protocol::Boot);
+                            FunctionSetupResult::Out
+                        }
+                        1 => {
+                            self.protocol.set(Protocol::Report);
+                            FunctionSetupResult::Out
+                        }
+                        _ => FunctionSetupResult::Error,
+                    },
+                    _ => FunctionSetupResult::Error,
+                }
+            }
+            RequestType::Vendor | RequestType::Reserved => FunctionSetupResult::Error,
+        }
+    }
+
+    fn ctrl_out(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlOutResult {
+        if !self.set_report.get() {
+            return hil::usb::CtrlOutResult::Halted;
+        }
+        if let Some(leds) = packet.first() {
+            self.leds.set(leds.get());
+            self.recv_pending.set(true);
+        }
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        if self.set_report.take() && self.recv_pending.get() {
+            self.deliver_output_report();
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => self.send_buffer.map_or(hil::usb::InResult::Delay, |buf| {
+                let len = self.report_len();
+                for ((packet, report), b) in self
+                    .in_buffer
+                    .buf
+                    .iter()
+                    .zip(self.report.iter())
+                    .zip(buf[..len].iter())
+                {
+                    packet.set(*b);
+                    report.set(*b);
+                }
+                hil::usb::InResult::Packet(len)
+            }),
+            TransferType::Control | TransferType::Isochronous | TransferType::Bulk => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        // There is no OUT endpoint
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.send_buffer.take().map(|buf| {
+            self.client.map(move |client| {
+                client.packet_transmitted(Ok(()), buf, endpoint);
+            });
+        });
+    }
+}
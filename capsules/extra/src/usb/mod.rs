@@ -1,6 +1,17 @@
 pub mod cdc;
+pub mod composite;
 pub mod ctap;
 pub mod descriptors;
+pub mod dfu;
+pub mod ecm;
+pub mod hid;
+pub mod msc;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
+pub mod vendor;
+
+#[cfg(test)]
+mod sim;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,703 @@
+//! USB Mass Storage Class, with the Bulk-Only Transport and SCSI transparent
+//! command set.
+//!
+//! This function exposes a region of nonvolatile storage to the host as a
+//! removable drive of 512-byte blocks, for instance to read logs written by
+//! the device with the file manager of the host. The storage is any
+//! `hil::nonvolatile_storage::NonvolatileStorage`, such as external flash
+//! behind `nonvolatile_to_pages`, and must support accesses of one block at
+//! any block-aligned address.
+//!
+//! The host sends each command in a Command Block Wrapper (CBW) on the OUT
+//! endpoint, followed by a data phase in the direction of the command, and
+//! the device answers with a Command Status Wrapper (CSW) on the IN endpoint.
+//! The data phase always transfers the length announced by the host: missing
+//! data is padded (or discarded when written by the host), and reported as a
+//! residue in the CSW. Commands that are not supported fail, with sense data
+//! that the host retrieves with REQUEST SENSE.
+//!
+//! The function is meant to be part of a `CompositeDevice`, which owns the
+//! control endpoint.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let msc = static_init!(
+//!     UsbMassStorage<'static, nrf52840::usbd::Usbd<'static>>,
+//!     UsbMassStorage::new(
+//!         &nrf52840_peripherals.usbd,
+//!         nv_to_page,
+//!         0x40000,
+//!         2048,
+//!         1,
+//!         2,
+//!         static_init!([u8; BLOCK_SIZE], [0; BLOCK_SIZE])
+//!     )
+//! );
+//! nv_to_page.set_client(msc);
+//! composite.add_function(msc).unwrap();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::{DescriptorWriter, FunctionClass, FunctionSetupResult, UsbFunction};
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::TakeCell;
+use kernel::utilities::cells::VolatileCell;
+
+/// Size of the blocks of the drive.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Size of the packets of the bulk endpoints.
+const PACKET_SIZE: usize = 64;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LEN: usize = 13;
+
+/// Class-specific requests.
+const BULK_ONLY_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+
+/// Identification of the drive, reported by INQUIRY.
+const VENDOR_ID: &[u8; 8] = b"Tock    ";
+const PRODUCT_ID: &[u8; 16] = b"Mass Storage    ";
+const PRODUCT_REVISION: &[u8; 4] = b"1.0 ";
+
+/// SCSI operation codes.
+mod scsi {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const MODE_SENSE_6: u8 = 0x1a;
+    pub const START_STOP_UNIT: u8 = 0x1b;
+    pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+    pub const READ_FORMAT_CAPACITIES: u8 = 0x23;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+    pub const VERIFY_10: u8 = 0x2f;
+    pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+}
+
+/// Status of a command, reported in the CSW.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CommandStatus {
+    Passed = 0,
+    Failed = 1,
+}
+
+/// Sense keys and additional sense codes describing why a command failed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Sense {
+    NoSense,
+    InvalidCommand,
+    InvalidField,
+    LbaOutOfRange,
+    ReadError,
+    WriteError,
+    NotReady,
+}
+
+impl Sense {
+    /// Returns the sense key and the additional sense code.
+    fn codes(self) -> (u8, u8) {
+        match self {
+            Sense::NoSense => (0x00, 0x00),
+            Sense::InvalidCommand => (0x05, 0x20),
+            Sense::InvalidField => (0x05, 0x24),
+            Sense::LbaOutOfRange => (0x05, 0x21),
+            Sense::ReadError => (0x03, 0x11),
+            Sense::WriteError => (0x03, 0x0c),
+            Sense::NotReady => (0x02, 0x04),
+        }
+    }
+}
+
+/// Phases of the Bulk-Only Transport.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    /// Waiting for a CBW.
+    Idle,
+    /// Sending data to the host.
+    DataIn,
+    /// Receiving data from the host.
+    DataOut,
+    /// Waiting for the storage to read a block.
+    Reading,
+    /// Waiting for the storage to write a block.
+    Writing,
+    /// Sending the CSW.
+    Status,
+}
+
+/// A Command Block Wrapper.
+struct CommandBlock {
+    tag: u32,
+    data_length: usize,
+    data_in: bool,
+    command: [u8; 16],
+}
+
+impl CommandBlock {
+    fn decode(packet: &[VolatileCell<u8>]) -> Option<CommandBlock> {
+        if packet.len() < CBW_LEN {
+            return None;
+        }
+        let u32_at = |i: usize| {
+            u32::from_le_bytes([
+                packet[i].get(),
+                packet[i + 1].get(),
+                packet[i + 2].get(),
+                packet[i + 3].get(),
+            ])
+        };
+        let command_length = packet[14].get() as usize;
+        if u32_at(0) != CBW_SIGNATURE || command_length == 0 || command_length > 16 {
+            return None;
+        }
+        let mut command = [0; 16];
+        for (i, b) in command.iter_mut().take(command_length).enumerate() {
+            *b = packet[15 + i].get();
+        }
+        Some(CommandBlock {
+            tag: u32_at(4),
+            data_length: u32_at(8) as usize,
+            data_in: packet[12].get() & 0x80 != 0,
+            command: command,
+        })
+    }
+}
+
+pub struct UsbMassStorage<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// Storage holding the blocks of the drive.
+    storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    /// Address of the first block in the storage.
+    start_address: usize,
+    /// Number of blocks of the drive.
+    block_count: u32,
+
+    endpoint_in: usize,
+    endpoint_out: usize,
+    in_buffer: Buffer64,
+    out_buffer: Buffer64,
+
+    /// Holds a block, or the response to a command.
+    buffer: TakeCell<'static, [u8]>,
+
+    state: Cell<State>,
+
+    /// Tag of the current command, echoed in the CSW.
+    tag: Cell<u32>,
+    /// Bytes of the data phase left to transfer.
+    remaining: Cell<usize>,
+    /// Bytes of the data phase that will not be transferred as meaningful
+    /// data, as announced in the CSW.
+    residue: Cell<usize>,
+    status: Cell<CommandStatus>,
+    /// Why the last failed command failed.
+    sense: Cell<Sense>,
+
+    /// Extent of `buffer` holding meaningful data to send, or space for
+    /// data to receive. Past it, data is padding.
+    buffer_offset: Cell<usize>,
+    buffer_len: Cell<usize>,
+    /// Length of the packet being sent.
+    packet_len: Cell<usize>,
+
+    /// Next block to read or write, and the number of blocks left.
+    block: Cell<u32>,
+    blocks_remaining: Cell<u32>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbMassStorage<'a, U> {
+    /// Creates a drive of `block_count` blocks, starting at `start_address`
+    /// in `storage`. `buffer` must hold at least `BLOCK_SIZE` bytes.
+    pub fn new(
+        controller: &'a U,
+        storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start_address: usize,
+        block_count: u32,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        buffer: &'static mut [u8],
+    ) -> Self {
+        UsbMassStorage {
+            controller: controller,
+            storage: storage,
+            start_address: start_address,
+            block_count: block_count,
+            endpoint_in: endpoint_in,
+            endpoint_out: endpoint_out,
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            tag: Cell::new(0),
+            remaining: Cell::new(0),
+            residue: Cell::new(0),
+            status: Cell::new(CommandStatus::Passed),
+            sense: Cell::new(Sense::NoSense),
+            buffer_offset: Cell::new(0),
+            buffer_len: Cell::new(0),
+            packet_len: Cell::new(0),
+            block: Cell::new(0),
+            blocks_remaining: Cell::new(0),
+        }
+    }
+
+    /// Records that the current command failed.
+    fn fail(&self, sense: Sense) {
+        self.status.set(CommandStatus::Failed);
+        self.sense.set(sense);
+    }
+
+    /// Goes back to waiting for a command, abandoning the current one.
+    fn reset(&self) {
+        self.state.set(State::Idle);
+        self.blocks_remaining.set(0);
+        self.remaining.set(0);
+    }
+
+    /// Writes the response to a command without data from the storage into
+    /// `buffer`, and returns its length.
+    fn respond(&self, command: &[u8; 16], buffer: &mut [u8]) -> usize {
+        match command[0] {
+            scsi::TEST_UNIT_READY
+            | scsi::START_STOP_UNIT
+            | scsi::PREVENT_ALLOW_MEDIUM_REMOVAL
+            | scsi::VERIFY_10
+            | scsi::SYNCHRONIZE_CACHE_10 => 0,
+            scsi::REQUEST_SENSE => {
+                let (key, asc) = self.sense.get().codes();
+                self.sense.set(Sense::NoSense);
+                buffer[..18].iter_mut().for_each(|b| *b = 0);
+                buffer[0] = 0x70; // Current errors, fixed format
+                buffer[2] = key;
+                buffer[7] = 10; // Additional sense length
+                buffer[12] = asc;
+                18
+            }
+            scsi::INQUIRY => {
+                buffer[0] = 0x00; // Direct access block device
+                buffer[1] = 0x80; // Removable
+                buffer[2] = 0x04; // SPC-2
+                buffer[3] = 0x02; // Response data format
+                buffer[4] = 36 - 5; // Additional length
+                buffer[5..8].iter_mut().for_each(|b| *b = 0);
+                buffer[8..16].copy_from_slice(VENDOR_ID);
+                buffer[16..32].copy_from_slice(PRODUCT_ID);
+                buffer[32..36].copy_from_slice(PRODUCT_REVISION);
+                36
+            }
+            scsi::MODE_SENSE_6 => {
+                // Mode parameter header only, without write protection
+                buffer[..4].copy_from_slice(&[3, 0, 0, 0]);
+                4
+            }
+            scsi::READ_FORMAT_CAPACITIES => {
+                buffer[..4].copy_from_slice(&[0, 0, 0, 8]);
+                buffer[4..8].copy_from_slice(&self.block_count.to_be_bytes());
+                // Formatted media, followed by the block length
+                buffer[8..12].copy_from_slice(&(0x02000000 | BLOCK_SIZE as u32).to_be_bytes());
+                12
+            }
+            scsi::READ_CAPACITY_10 => {
+                let last_block = self.block_count.saturating_sub(1);
+                buffer[..4].copy_from_slice(&last_block.to_be_bytes());
+                buffer[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                8
+            }
+            _ => {
+                self.fail(Sense::InvalidCommand);
+                0
+            }
+        }
+    }
+
+    /// Starts executing a command received in a CBW. Returns what to answer
+    /// to the packet of the CBW.
+    fn start_command(&self, cbw: CommandBlock) -> hil::usb::OutResult {
+        self.tag.set(cbw.tag);
+        self.remaining.set(cbw.data_length);
+        self.residue.set(cbw.data_length);
+        self.status.set(CommandStatus::Passed);
+        self.buffer_offset.set(0);
+        self.buffer_len.set(0);
+        self.blocks_remaining.set(0);
+
+        match cbw.command[0] {
+            scsi::READ_10 | scsi::WRITE_10 => {
+                let block = u32::from_be_bytes([
+                    cbw.command[2],
+                    cbw.command[3],
+                    cbw.command[4],
+                    cbw.command[5],
+                ]);
+                let count = u16::from_be_bytes([cbw.command[7], cbw.command[8]]) as u32;
+                let data_in = cbw.command[0] == scsi::READ_10;
+                let length = count as usize * BLOCK_SIZE;
+                if block as u64 + count as u64 > self.block_count as u64 {
+                    self.fail(Sense::LbaOutOfRange);
+                } else if data_in != cbw.data_in || length > cbw.data_length {
+                    self.fail(Sense::InvalidField);
+                } else {
+                    self.block.set(block);
+                    self.blocks_remaining.set(count);
+                    self.residue.set(cbw.data_length - length);
+                }
+            }
+            _ => {
+                let length = self.buffer.map_or(0, |buffer| {
+                    let length = self.respond(&cbw.command, buffer);
+                    cmp::min(length, cbw.data_length)
+                });
+                if self.buffer.is_none() {
+                    // The storage still holds the buffer, after an aborted
+                    // command
+                    self.fail(Sense::NotReady);
+                } else if length > 0 && !cbw.data_in {
+                    self.fail(Sense::InvalidField);
+                } else {
+                    self.buffer_len.set(length);
+                    self.residue.set(cbw.data_length - length);
+                }
+            }
+        }
+
+        if cbw.data_length == 0 {
+            self.send_status();
+            hil::usb::OutResult::Delay
+        } else if cbw.data_in {
+            self.state.set(State::DataIn);
+            self.next_in_chunk();
+            hil::usb::OutResult::Delay
+        } else {
+            self.state.set(State::DataOut);
+            if self.blocks_remaining.get() > 0 {
+                self.buffer_len.set(BLOCK_SIZE);
+            }
+            hil::usb::OutResult::Ok
+        }
+    }
+
+    /// Continues sending data to the host, reading the next block first if
+    /// the previous one was sent.
+    fn next_in_chunk(&self) {
+        if self.buffer_offset.get() < self.buffer_len.get() || self.blocks_remaining.get() == 0 {
+            self.controller.endpoint_resume_in(self.endpoint_in);
+            return;
+        }
+        let address = self.start_address + self.block.get() as usize * BLOCK_SIZE;
+        let result = self.buffer.take().map(|buffer| {
+            self.storage
+                .read(buffer, address, BLOCK_SIZE)
+                .map_err(|_| ())
+        });
+        match result {
+            Some(Ok(())) => self.state.set(State::Reading),
+            _ => {
+                // Pad the rest of the data phase
+                self.fail(Sense::ReadError);
+                self.residue.set(self.remaining.get());
+                self.blocks_remaining.set(0);
+                self.buffer_offset.set(0);
+                self.buffer_len.set(0);
+                self.controller.endpoint_resume_in(self.endpoint_in);
+            }
+        }
+    }
+
+    /// Writes the block received from the host to the storage.
+    fn write_block(&self) {
+        let address = self.start_address + self.block.get() as usize * BLOCK_SIZE;
+        let result = self.buffer.take().map(|buffer| {
+            self.storage
+                .write(buffer, address, BLOCK_SIZE)
+                .map_err(|_| ())
+        });
+        match result {
+            Some(Ok(())) => self.state.set(State::Writing),
+            _ => {
+                // Discard the rest of the data phase
+                self.fail(Sense::WriteError);
+                self.residue.set(self.remaining.get());
+                self.blocks_remaining.set(0);
+                self.buffer_len.set(0);
+                self.continue_out();
+            }
+        }
+    }
+
+    /// Continues receiving data from the host, or ends the data phase.
+    fn continue_out(&self) {
+        if self.remaining.get() > 0 {
+            self.state.set(State::DataOut);
+            self.controller.endpoint_resume_out(self.endpoint_out);
+        } else {
+            self.send_status();
+        }
+    }
+
+    /// Starts sending the CSW of the current command.
+    fn send_status(&self) {
+        self.state.set(State::Status);
+        self.controller.endpoint_resume_in(self.endpoint_in);
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for UsbMassStorage<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0x08,    // Mass storage
+            subclass: 0x06, // SCSI transparent command set
+            protocol: 0x50, // Bulk-Only Transport
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        (1 << self.endpoint_in) | (1 << self.endpoint_out)
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        let class = self.function_class();
+        writer.write(&InterfaceDescriptor {
+            interface_number: first_interface,
+            interface_class: class.class,
+            interface_subclass: class.subclass,
+            interface_protocol: class.protocol,
+            num_endpoints: 2,
+            ..InterfaceDescriptor::default()
+        });
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_in,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: PACKET_SIZE as u16,
+            interval: 0,
+        });
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_out,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: PACKET_SIZE as u16,
+            interval: 0,
+        });
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_in, &self.in_buffer.buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint_out, &self.out_buffer.buf);
+        if self.endpoint_in == self.endpoint_out {
+            self.controller
+                .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_in);
+        } else {
+            self.controller
+                .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
+            self.controller
+                .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
+        }
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData, data: &[Cell<u8>]) -> FunctionSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class => match setup.request_code {
+                BULK_ONLY_RESET => {
+                    self.reset();
+                    FunctionSetupResult::Out
+                }
+                GET_MAX_LUN => {
+                    // A single logical unit
+                    data[0].set(0);
+                    FunctionSetupResult::In(1)
+                }
+                _ => FunctionSetupResult::Error,
+            },
+            _ => FunctionSetupResult::Error,
+        }
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        // Accept the next CBW after a reset
+        if self.state.get() == State::Idle {
+            self.controller.endpoint_resume_out(self.endpoint_out);
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {}
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                return hil::usb::InResult::Error;
+            }
+        }
+        let packet = &self.in_buffer.buf;
+        match self.state.get() {
+            State::DataIn if self.remaining.get() > 0 => {
+                let offset = self.buffer_offset.get();
+                let available = self.buffer_len.get().saturating_sub(offset);
+                let len = if available > 0 {
+                    let len = cmp::min(cmp::min(available, PACKET_SIZE), self.remaining.get());
+                    self.buffer.map(|buffer| {
+                        for (p, b) in packet.iter().zip(buffer[offset..offset + len].iter()) {
+                            p.set(*b);
+                        }
+                    });
+                    len
+                } else {
+                    let len = cmp::min(PACKET_SIZE, self.remaining.get());
+                    packet[..len].iter().for_each(|p| p.set(0));
+                    len
+                };
+                self.packet_len.set(len);
+                hil::usb::InResult::Packet(len)
+            }
+            State::Status => {
+                let mut csw = [0; CSW_LEN];
+                csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+                csw[4..8].copy_from_slice(&self.tag.get().to_le_bytes());
+                csw[8..12].copy_from_slice(&(self.residue.get() as u32).to_le_bytes());
+                csw[12] = self.status.get() as u8;
+                for (p, b) in packet.iter().zip(csw.iter()) {
+                    p.set(*b);
+                }
+                self.packet_len.set(CSW_LEN);
+                hil::usb::InResult::Packet(CSW_LEN)
+            }
+            _ => hil::usb::InResult::Delay,
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {}
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                return hil::usb::OutResult::Error;
+            }
+        }
+        let packet_bytes = cmp::min(packet_bytes as usize, PACKET_SIZE);
+        let packet = &self.out_buffer.buf[..packet_bytes];
+        match self.state.get() {
+            State::Idle => match CommandBlock::decode(packet) {
+                Some(cbw) if packet_bytes == CBW_LEN => self.start_command(cbw),
+                // Invalid CBWs are ignored
+                _ => hil::usb::OutResult::Ok,
+            },
+            State::DataOut => {
+                let len = cmp::min(packet_bytes, self.remaining.get());
+                self.remaining.set(self.remaining.get() - len);
+
+                let offset = self.buffer_offset.get();
+                let stored = cmp::min(len, self.buffer_len.get().saturating_sub(offset));
+                if stored > 0 {
+                    self.buffer.map(|buffer| {
+                        for (b, p) in buffer[offset..offset + stored].iter_mut().zip(packet) {
+                            *b = p.get();
+                        }
+                    });
+                    self.buffer_offset.set(offset + stored);
+                }
+
+                if self.blocks_remaining.get() > 0 && self.buffer_offset.get() >= BLOCK_SIZE {
+                    self.write_block();
+                    hil::usb::OutResult::Delay
+                } else if self.remaining.get() == 0 {
+                    self.send_status();
+                    hil::usb::OutResult::Delay
+                } else {
+                    hil::usb::OutResult::Ok
+                }
+            }
+            _ => hil::usb::OutResult::Delay,
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::DataIn => {
+                let len = self.packet_len.get();
+                self.remaining.set(self.remaining.get().saturating_sub(len));
+                if self.buffer_offset.get() < self.buffer_len.get() {
+                    self.buffer_offset.set(self.buffer_offset.get() + len);
+                }
+                if self.remaining.get() == 0 {
+                    self.send_status();
+                } else {
+                    self.next_in_chunk();
+                }
+            }
+            State::Status => {
+                self.state.set(State::Idle);
+                self.controller.endpoint_resume_out(self.endpoint_out);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::nonvolatile_storage::NonvolatileStorageClient<'static>
+    for UsbMassStorage<'a, U>
+{
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::Reading {
+            // The command was aborted
+            return;
+        }
+        self.block.set(self.block.get() + 1);
+        self.blocks_remaining.set(self.blocks_remaining.get() - 1);
+        self.buffer_offset.set(0);
+        self.buffer_len.set(BLOCK_SIZE);
+        self.state.set(State::DataIn);
+        self.controller.endpoint_resume_in(self.endpoint_in);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::Writing {
+            // The command was aborted
+            return;
+        }
+        self.block.set(self.block.get() + 1);
+        self.blocks_remaining.set(self.blocks_remaining.get() - 1);
+        self.buffer_offset.set(0);
+        if self.blocks_remaining.get() == 0 {
+            self.buffer_len.set(0);
+        }
+        self.continue_out();
+    }
+}
@@ -0,0 +1,452 @@
+//! A simulated USB device controller, driven by a scripted host, to test
+//! class drivers without hardware.
+//!
+//! `SimController` implements `hil::usb::UsbController`, so that a class
+//! driver (such as `CdcAcm` or `CtapHid`) uses it as it would use the
+//! controller of a chip. Its other methods play the part of the host: they
+//! reset the bus, run control transfers (setup, data and status stages), and
+//! send IN and OUT tokens to the other endpoints. Each of them calls into the
+//! driver synchronously and returns what the host would see, so tests run
+//! deterministically.
+//!
+//! The controller never calls into the driver from one of the
+//! `UsbController` methods. As on hardware, an IN endpoint only answers the
+//! host once the driver resumes it, and an OUT endpoint NAKs the host after
+//! the driver delays a packet, until the driver resumes it.
+
+use core::cell::Cell;
+use kernel::hil::usb::{
+    Client, CtrlInResult, CtrlOutResult, CtrlSetupResult, DeviceSpeed, InResult, OutResult,
+    TransferType, UsbController,
+};
+use kernel::utilities::cells::{OptionalCell, VolatileCell};
+use std::string::String;
+use std::vec::Vec;
+
+/// Number of endpoints, including the default control endpoint.
+const N_ENDPOINTS: usize = 16;
+
+/// Standard request codes.
+const GET_DESCRIPTOR: u8 = 6;
+const SET_ADDRESS: u8 = 5;
+const SET_CONFIGURATION: u8 = 9;
+
+/// Descriptor types.
+pub const DEVICE: u8 = 1;
+pub const CONFIGURATION: u8 = 2;
+pub const STRING: u8 = 3;
+
+/// Language of the string descriptors the host asks for.
+const LANGUAGE: u16 = 0x0409;
+
+/// The handshake with which the device refused a transaction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Handshake {
+    /// The device is not ready; the host would retry later.
+    Nak,
+    /// The device does not support the request.
+    Stall,
+}
+
+/// The descriptors the host read during enumeration.
+pub struct Enumeration {
+    pub device: Vec<u8>,
+    pub configuration: Vec<u8>,
+}
+
+struct Endpoint<'a> {
+    in_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    out_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    in_type: OptionalCell<TransferType>,
+    out_type: OptionalCell<TransferType>,
+    /// The driver has data for the host.
+    in_resumed: Cell<bool>,
+    /// The driver delayed an OUT packet, and has not resumed the endpoint.
+    out_paused: Cell<bool>,
+}
+
+impl Endpoint<'_> {
+    fn new() -> Self {
+        Endpoint {
+            in_buffer: OptionalCell::empty(),
+            out_buffer: OptionalCell::empty(),
+            in_type: OptionalCell::empty(),
+            out_type: OptionalCell::empty(),
+            in_resumed: Cell::new(false),
+            out_paused: Cell::new(false),
+        }
+    }
+}
+
+pub struct SimController<'a> {
+    client: OptionalCell<&'a dyn Client<'a>>,
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    endpoints: [Endpoint<'a>; N_ENDPOINTS],
+    speed: OptionalCell<DeviceSpeed>,
+    attached: Cell<bool>,
+    /// The address the device answers to.
+    address: Cell<u16>,
+    /// The address set by the driver, applied once it enables it.
+    pending_address: OptionalCell<u16>,
+}
+
+impl<'a> SimController<'a> {
+    pub fn new() -> Self {
+        SimController {
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            endpoints: [(); N_ENDPOINTS].map(|_| Endpoint::new()),
+            speed: OptionalCell::empty(),
+            attached: Cell::new(false),
+            address: Cell::new(0),
+            pending_address: OptionalCell::empty(),
+        }
+    }
+
+    fn client(&self) -> &'a dyn Client<'a> {
+        self.client.extract().expect("no USB client")
+    }
+
+    fn ctrl_buffer(&self) -> &'a [VolatileCell<u8>] {
+        self.ctrl_buffer.extract().expect("no control buffer")
+    }
+
+    /// Enables the driver and lets it attach to the bus, as a board does.
+    pub fn power_up(&self) {
+        self.client().enable();
+        self.client().attach();
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached.get()
+    }
+
+    pub fn is_full_speed(&self) -> bool {
+        self.speed
+            .map_or(false, |speed| matches!(speed, DeviceSpeed::Full))
+    }
+
+    /// The address the device currently answers to.
+    pub fn address(&self) -> u16 {
+        self.address.get()
+    }
+
+    /// Resets the bus, which brings the device back to the default address.
+    pub fn reset(&self) {
+        self.address.set(0);
+        self.pending_address.clear();
+        for endpoint in self.endpoints.iter() {
+            endpoint.in_resumed.set(false);
+            endpoint.out_paused.set(false);
+        }
+        self.client().bus_reset();
+    }
+
+    /// Sends a setup packet on the default control endpoint.
+    pub fn setup(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<(), Handshake> {
+        let packet = [
+            request_type,
+            request,
+            value as u8,
+            (value >> 8) as u8,
+            index as u8,
+            (index >> 8) as u8,
+            length as u8,
+            (length >> 8) as u8,
+        ];
+        let buf = self.ctrl_buffer();
+        for (i, b) in packet.iter().enumerate() {
+            buf[i].set(*b);
+        }
+        match self.client().ctrl_setup(0) {
+            CtrlSetupResult::Ok | CtrlSetupResult::OkSetAddress => Ok(()),
+            _ => Err(Handshake::Stall),
+        }
+    }
+
+    /// Runs the status stage of a control transfer, which the device
+    /// acknowledges.
+    pub fn status(&self) {
+        self.client().ctrl_status(0);
+        self.client().ctrl_status_complete(0);
+    }
+
+    /// Runs a control read of at most `length` bytes.
+    pub fn control_in(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, Handshake> {
+        self.setup(request_type, request, value, index, length)?;
+
+        let buf = self.ctrl_buffer();
+        let mut data = Vec::new();
+        loop {
+            match self.client().ctrl_in(0) {
+                CtrlInResult::Packet(n, complete) => {
+                    data.extend(buf[..n].iter().map(|b| b.get()));
+                    if complete || n < buf.len() || data.len() >= length as usize {
+                        break;
+                    }
+                }
+                CtrlInResult::Delay => return Err(Handshake::Nak),
+                CtrlInResult::Error => return Err(Handshake::Stall),
+            }
+        }
+        data.truncate(length as usize);
+
+        self.status();
+        Ok(data)
+    }
+
+    /// Runs a control write of `data`, which may be empty.
+    pub fn control_out(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), Handshake> {
+        self.setup(request_type, request, value, index, data.len() as u16)?;
+
+        let buf = self.ctrl_buffer();
+        for packet in data.chunks(buf.len()) {
+            for (i, b) in packet.iter().enumerate() {
+                buf[i].set(*b);
+            }
+            match self.client().ctrl_out(0, packet.len() as u32) {
+                CtrlOutResult::Ok => {}
+                CtrlOutResult::Delay => return Err(Handshake::Nak),
+                CtrlOutResult::Halted => return Err(Handshake::Stall),
+            }
+        }
+
+        self.status();
+        Ok(())
+    }
+
+    pub fn get_descriptor(
+        &self,
+        descriptor_type: u8,
+        index: u8,
+        length: u16,
+    ) -> Result<Vec<u8>, Handshake> {
+        let language = if descriptor_type == STRING && index != 0 {
+            LANGUAGE
+        } else {
+            0
+        };
+        self.control_in(
+            0x80,
+            GET_DESCRIPTOR,
+            (descriptor_type as u16) << 8 | index as u16,
+            language,
+            length,
+        )
+    }
+
+    /// Reads the string descriptor `index`.
+    pub fn string(&self, index: u8) -> Result<String, Handshake> {
+        let descriptor = self.get_descriptor(STRING, index, 255)?;
+        let len = (descriptor[0] as usize).min(descriptor.len());
+        let units = descriptor[2..len]
+            .chunks(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        Ok(char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect())
+    }
+
+    pub fn set_address(&self, address: u16) -> Result<(), Handshake> {
+        self.control_out(0x00, SET_ADDRESS, address, 0, &[])
+    }
+
+    pub fn set_configuration(&self, configuration: u8) -> Result<(), Handshake> {
+        self.control_out(0x00, SET_CONFIGURATION, configuration as u16, 0, &[])
+    }
+
+    /// Enumerates the device as a host does after a reset: reads the
+    /// beginning of the device descriptor, assigns `address`, reads the
+    /// device and configuration descriptors and the supported languages, and
+    /// selects the first configuration.
+    pub fn enumerate(&self, address: u16) -> Result<Enumeration, Handshake> {
+        self.get_descriptor(DEVICE, 0, 64)?;
+        self.set_address(address)?;
+        let device = self.get_descriptor(DEVICE, 0, 18)?;
+
+        let header = self.get_descriptor(CONFIGURATION, 0, 9)?;
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let configuration = self.get_descriptor(CONFIGURATION, 0, total_length)?;
+
+        self.get_descriptor(STRING, 0, 255)?;
+        self.set_configuration(1)?;
+
+        Ok(Enumeration {
+            device,
+            configuration,
+        })
+    }
+
+    /// Whether the driver resumed IN endpoint `endpoint`, that is, has data
+    /// for the host.
+    pub fn in_resumed(&self, endpoint: usize) -> bool {
+        self.endpoints[endpoint].in_resumed.get()
+    }
+
+    /// Whether OUT endpoint `endpoint` NAKs the host.
+    pub fn out_paused(&self, endpoint: usize) -> bool {
+        self.endpoints[endpoint].out_paused.get()
+    }
+
+    /// Sends an IN token to `endpoint`, returning the packet the device sent.
+    pub fn packet_in(&self, endpoint: usize) -> Result<Vec<u8>, Handshake> {
+        let ep = &self.endpoints[endpoint];
+        let transfer_type = ep.in_type.extract().expect("IN endpoint not enabled");
+        if !ep.in_resumed.take() {
+            return Err(Handshake::Nak);
+        }
+        match self.client().packet_in(transfer_type, endpoint) {
+            InResult::Packet(n) => {
+                let packet = ep
+                    .in_buffer
+                    .map(|buf| buf[..n].iter().map(|b| b.get()).collect())
+                    .expect("no IN buffer");
+                self.client().packet_transmitted(endpoint);
+                Ok(packet)
+            }
+            InResult::Delay => Err(Handshake::Nak),
+            InResult::Error => Err(Handshake::Stall),
+        }
+    }
+
+    /// Reads packets from `endpoint` until a short packet or a NAK, and
+    /// returns their data.
+    pub fn read(&self, endpoint: usize) -> Result<Vec<u8>, Handshake> {
+        let max_packet_size = self.endpoints[endpoint]
+            .in_buffer
+            .map_or(0, |buf| buf.len());
+        let mut data = Vec::new();
+        loop {
+            match self.packet_in(endpoint) {
+                Ok(packet) => {
+                    data.extend_from_slice(&packet);
+                    if packet.len() < max_packet_size {
+                        return Ok(data);
+                    }
+                }
+                Err(Handshake::Nak) => return Ok(data),
+                Err(Handshake::Stall) => return Err(Handshake::Stall),
+            }
+        }
+    }
+
+    /// Sends `packet` to OUT endpoint `endpoint`.
+    pub fn packet_out(&self, endpoint: usize, packet: &[u8]) -> Result<(), Handshake> {
+        let ep = &self.endpoints[endpoint];
+        let transfer_type = ep.out_type.extract().expect("OUT endpoint not enabled");
+        if ep.out_paused.get() {
+            return Err(Handshake::Nak);
+        }
+        ep.out_buffer.map(|buf| {
+            assert!(packet.len() <= buf.len(), "packet larger than the buffer");
+            for (i, b) in packet.iter().enumerate() {
+                buf[i].set(*b);
+            }
+        });
+        match self
+            .client()
+            .packet_out(transfer_type, endpoint, packet.len() as u32)
+        {
+            OutResult::Ok => Ok(()),
+            OutResult::Delay => {
+                // The packet is consumed, but the next ones wait for the
+                // driver
+                ep.out_paused.set(true);
+                Ok(())
+            }
+            OutResult::Error => Err(Handshake::Stall),
+        }
+    }
+
+    /// Sends `data` to `endpoint` in packets as large as its buffer.
+    pub fn write(&self, endpoint: usize, data: &[u8]) -> Result<(), Handshake> {
+        let max_packet_size = self.endpoints[endpoint]
+            .out_buffer
+            .map_or(0, |buf| buf.len());
+        data.chunks(max_packet_size)
+            .try_for_each(|packet| self.packet_out(endpoint, packet))
+    }
+}
+
+impl<'a> UsbController<'a> for SimController<'a> {
+    fn set_client(&self, client: &'a dyn Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        assert!(buf.len() >= 8, "control buffer too small for setup packets");
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.endpoints[endpoint].in_buffer.set(buf);
+    }
+
+    fn endpoint_set_out_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.endpoints[endpoint].out_buffer.set(buf);
+    }
+
+    fn enable_as_device(&self, speed: DeviceSpeed) {
+        self.speed.set(speed);
+    }
+
+    fn attach(&self) {
+        self.attached.set(true);
+    }
+
+    fn detach(&self) {
+        self.attached.set(false);
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.pending_address.set(addr);
+    }
+
+    fn enable_address(&self) {
+        self.pending_address
+            .take()
+            .map(|address| self.address.set(address));
+    }
+
+    fn endpoint_in_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoints[endpoint].in_type.set(transfer_type);
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoints[endpoint].out_type.set(transfer_type);
+    }
+
+    fn endpoint_in_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoint_in_enable(transfer_type, endpoint);
+        self.endpoint_out_enable(transfer_type, endpoint);
+    }
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        self.endpoints[endpoint].in_resumed.set(true);
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        self.endpoints[endpoint].out_paused.set(false);
+    }
+}
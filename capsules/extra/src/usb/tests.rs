@@ -0,0 +1,1537 @@
+//! Tests of the class drivers, which a scripted host drives through
+//! `SimController` from enumeration to data transfers.
+
+use crate::net::ethernet::EthernetAddress;
+use crate::sha256::Sha256Software;
+use crate::testing::alarm::MockAlarm;
+use crate::usb::cdc::{CdcAcm, CDC_BUFFER_TIMEOUT_MS};
+use crate::usb::composite::{CompositeDevice, UsbFunction};
+use crate::usb::ctap::CtapHid;
+use crate::usb::dfu::{UsbDfu, TRANSFER_SIZE};
+use crate::usb::ecm::{CdcEcm, MAX_FRAME_LEN};
+use crate::usb::hid::{BootDevice, BootHid, Protocol, REPORT_SIZE};
+use crate::usb::msc::{UsbMassStorage, BLOCK_SIZE};
+use crate::usb::sim::{Handshake, SimController, CONFIGURATION};
+use crate::usb::usbc_client;
+use crate::usb::vendor::VendorBulk;
+use core::cell::{Cell, RefCell};
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+    DynamicDeferredCallClientState,
+};
+use kernel::hil::digest::Digest;
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::time::{Alarm, Freq1KHz};
+use kernel::hil::uart::{self, Receive, Transmit};
+use kernel::hil::usb::UsbController;
+use kernel::hil::usb_hid::{self, UsbHid};
+use kernel::process_checker::basic::AppCheckerSha256;
+use kernel::process_checker::AppCredentialsChecker;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+use std::boxed::Box;
+use std::vec::Vec;
+
+static STRINGS: &'static [&'static str; 3] = &["Tock", "Simulated device", "0001"];
+
+const VENDOR_ID: u16 = 0x1209;
+const PRODUCT_ID: u16 = 0x0001;
+
+fn leak_buffer(len: usize) -> &'static mut [u8] {
+    Box::leak(std::vec![0; len].into_boxed_slice())
+}
+
+// ClientCtrl, through the sample `usbc_client::Client`. Its bus reset
+// handler prints with `debug!`, which needs the debug writer of a board, so
+// these tests enumerate the device without resetting the bus first.
+
+fn sample_client() -> &'static SimController<'static> {
+    let sim = Box::leak(Box::new(SimController::new()));
+    let client = Box::leak(Box::new(usbc_client::Client::new(
+        sim,
+        usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+    )));
+    sim.set_client(client);
+    sim.power_up();
+    sim
+}
+
+#[test]
+fn client_ctrl_enumeration() {
+    let sim = sample_client();
+    assert!(sim.is_attached());
+    assert!(sim.is_full_speed());
+
+    let enumeration = sim.enumerate(5).unwrap();
+    assert_eq!(sim.address(), 5);
+
+    let device = enumeration.device;
+    assert_eq!(&device[..2], &[18, 1]);
+    assert_eq!(device[7], 64);
+    assert_eq!(u16::from_le_bytes([device[8], device[9]]), 0x6667);
+    assert_eq!(u16::from_le_bytes([device[10], device[11]]), 0xabcd);
+
+    let configuration = enumeration.configuration;
+    assert_eq!(
+        u16::from_le_bytes([configuration[2], configuration[3]]) as usize,
+        configuration.len()
+    );
+    // One vendor-specific interface, with two endpoints
+    assert_eq!(configuration[4], 1);
+    assert_eq!(configuration[9 + 5], 0xff);
+    assert_eq!(configuration.len(), 9 + 9 + 2 * 7);
+
+    assert_eq!(sim.string(1).unwrap(), "XYZ Corp.");
+    assert_eq!(sim.string(2).unwrap(), "The Zorpinator");
+    assert_eq!(sim.string(3).unwrap(), "Serial No. 5");
+    assert_eq!(sim.string(4), Err(Handshake::Stall));
+}
+
+#[test]
+fn client_ctrl_short_reads() {
+    let sim = sample_client();
+
+    // The host gets at most what it asks for
+    assert_eq!(sim.get_descriptor(CONFIGURATION, 0, 4).unwrap().len(), 4);
+    assert_eq!(
+        sim.get_descriptor(CONFIGURATION, 1, 9),
+        Err(Handshake::Stall)
+    );
+}
+
+#[test]
+fn client_ctrl_address_applies_after_status_stage() {
+    let sim = sample_client();
+
+    sim.setup(0x00, 5, 9, 0, 0).unwrap();
+    assert_eq!(sim.address(), 0);
+    sim.status();
+    assert_eq!(sim.address(), 9);
+}
+
+#[test]
+fn client_ctrl_vendor_requests() {
+    let sim = sample_client();
+
+    assert_eq!(
+        sim.control_in(0xc0, 0x42, 0, 0, 64).unwrap(),
+        [0xa, 0xb, 0xc]
+    );
+    assert_eq!(sim.control_in(0xc0, 0x42, 0, 0, 2).unwrap(), [0xa, 0xb]);
+    assert_eq!(sim.control_out(0x40, 0x43, 0, 0, &[1, 2, 3]), Ok(()));
+}
+
+#[test]
+fn sample_client_echo_flow_control() {
+    let sim = sample_client();
+    sim.enumerate(1).unwrap();
+
+    assert_eq!(sim.packet_in(1), Err(Handshake::Nak));
+    sim.packet_out(2, b"abcdef").unwrap();
+    assert!(sim.in_resumed(1));
+
+    // The echo buffer holds 8 bytes, so the next packet is delayed
+    sim.packet_out(2, b"ghijkl").unwrap();
+    assert!(sim.out_paused(2));
+    assert_eq!(sim.packet_out(2, b"ghijkl"), Err(Handshake::Nak));
+
+    // Draining the buffer resumes the OUT endpoint
+    assert_eq!(sim.packet_in(1).unwrap(), b"abcdef");
+    assert!(!sim.out_paused(2));
+    sim.packet_out(2, b"ghijkl").unwrap();
+    assert_eq!(sim.read(1).unwrap(), b"ghijkl");
+}
+
+// CDC-ACM
+
+#[derive(Default)]
+struct Uart {
+    transmitted: RefCell<Vec<(usize, Result<(), ErrorCode>)>>,
+    received: RefCell<Vec<(Vec<u8>, Result<(), ErrorCode>)>>,
+}
+
+impl uart::TransmitClient for Uart {
+    fn transmitted_buffer(
+        &self,
+        _tx_buffer: &'static mut [u8],
+        tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.transmitted.borrow_mut().push((tx_len, rval));
+    }
+}
+
+impl uart::ReceiveClient for Uart {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: uart::Error,
+    ) {
+        self.received
+            .borrow_mut()
+            .push((rx_buffer[..rx_len].to_vec(), rval));
+    }
+}
+
+type Cdc = CdcAcm<'static, SimController<'static>, MockAlarm<'static, Freq1KHz>>;
+
+struct CdcHarness {
+    sim: &'static SimController<'static>,
+    cdc: &'static Cdc,
+    alarm: &'static MockAlarm<'static, Freq1KHz>,
+    uart: &'static Uart,
+}
+
+impl CdcHarness {
+    fn new() -> CdcHarness {
+        let sim = Box::leak(Box::new(SimController::new()));
+        let alarm = Box::leak(Box::new(MockAlarm::new()));
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let cdc = Box::leak(Box::new(CdcAcm::new(
+            sim, 64, VENDOR_ID, PRODUCT_ID, STRINGS, alarm, ddc, None,
+        )));
+        let handle = ddc.register(cdc).unwrap();
+        cdc.initialize_callback_handle(handle);
+        sim.set_client(cdc);
+        alarm.set_alarm_client(cdc);
+
+        let uart = Box::leak(Box::new(Uart::default()));
+        cdc.set_transmit_client(uart);
+        cdc.set_receive_client(uart);
+
+        sim.power_up();
+        sim.reset();
+        CdcHarness {
+            sim,
+            cdc,
+            alarm,
+            uart,
+        }
+    }
+
+    /// Opens the serial port as a terminal program does.
+    fn open_port(&self) {
+        let line_coding = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8]; // 115200 8N1
+        self.sim
+            .control_out(0x21, 0x20, 0, 0, &line_coding)
+            .unwrap();
+        self.sim.control_out(0x21, 0x22, 0x3, 0, &[]).unwrap();
+    }
+}
+
+#[test]
+fn cdc_enumeration() {
+    let h = CdcHarness::new();
+    let enumeration = h.sim.enumerate(1).unwrap();
+
+    assert_eq!(enumeration.device[4], 0x02);
+    assert_eq!(
+        u16::from_le_bytes([enumeration.device[8], enumeration.device[9]]),
+        VENDOR_ID
+    );
+    // Communication and data interfaces
+    assert_eq!(enumeration.configuration[4], 2);
+    assert_eq!(h.sim.string(2).unwrap(), "Simulated device");
+}
+
+#[test]
+fn cdc_transmits_once_host_connects() {
+    let h = CdcHarness::new();
+    let message: Vec<u8> = (0..70).collect();
+    let tx = leak_buffer(message.len());
+    tx.copy_from_slice(&message);
+
+    // Output is held back during the boot period
+    h.cdc.transmit_buffer(tx, message.len()).unwrap();
+    h.sim.enumerate(1).unwrap();
+    assert_eq!(h.sim.packet_in(2), Err(Handshake::Nak));
+
+    // and for a while after the host opens the port
+    h.open_port();
+    h.alarm.advance(99);
+    assert!(!h.sim.in_resumed(2));
+    h.alarm.advance(1);
+    assert!(h.sim.in_resumed(2));
+
+    assert_eq!(h.sim.packet_in(2).unwrap().len(), 64);
+    assert!(h.uart.transmitted.borrow().is_empty());
+    assert_eq!(h.sim.read(2).unwrap(), &message[64..]);
+    assert_eq!(*h.uart.transmitted.borrow(), [(70, Ok(()))]);
+    assert_eq!(h.sim.packet_in(2), Err(Handshake::Nak));
+}
+
+#[test]
+fn cdc_receives() {
+    let h = CdcHarness::new();
+    h.sim.enumerate(1).unwrap();
+    h.open_port();
+    h.alarm.advance(100);
+
+    h.cdc.receive_buffer(leak_buffer(8), 6).unwrap();
+    h.sim.write(3, b"hel").unwrap();
+    assert!(h.uart.received.borrow().is_empty());
+    h.sim.write(3, b"lo!").unwrap();
+    assert_eq!(*h.uart.received.borrow(), [(b"hello!".to_vec(), Ok(()))]);
+}
+
+#[test]
+fn cdc_gives_up_without_host() {
+    let h = CdcHarness::new();
+    h.cdc.transmit_buffer(leak_buffer(4), 4).unwrap();
+
+    h.alarm.advance(CDC_BUFFER_TIMEOUT_MS);
+    assert_eq!(*h.uart.transmitted.borrow(), [(0, Err(ErrorCode::FAIL))]);
+    assert!(!h.sim.in_resumed(2));
+}
+
+// CTAP HID
+
+/// Records the reports of a HID function with `N` byte reports, which
+/// receives them on endpoint 1.
+struct HidClient<const N: usize> {
+    received: RefCell<Vec<[u8; N]>>,
+    transmitted: Cell<usize>,
+    buffer: RefCell<Option<&'static mut [u8; N]>>,
+}
+
+impl<const N: usize> HidClient<N> {
+    fn new() -> &'static HidClient<N> {
+        Box::leak(Box::new(HidClient {
+            received: RefCell::new(Vec::new()),
+            transmitted: Cell::new(0),
+            buffer: RefCell::new(Some(Box::leak(Box::new([0; N])))),
+        }))
+    }
+}
+
+impl<'a, const N: usize> usb_hid::Client<'a, [u8; N]> for HidClient<N>
+where
+    [u8; N]: usb_hid::UsbHidType,
+{
+    fn packet_received(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; N],
+        endpoint: usize,
+    ) {
+        assert_eq!(result, Ok(()));
+        assert_eq!(endpoint, 1);
+        self.received.borrow_mut().push(*buffer);
+        *self.buffer.borrow_mut() = Some(buffer);
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        _buffer: &'static mut [u8; N],
+        _endpoint: usize,
+    ) {
+        assert_eq!(result, Ok(()));
+        self.transmitted.set(self.transmitted.get() + 1);
+    }
+
+    fn can_receive(&'a self) -> bool {
+        true
+    }
+}
+
+fn ctap() -> (
+    &'static SimController<'static>,
+    &'static CtapHid<'static, SimController<'static>>,
+    &'static HidClient<64>,
+) {
+    let sim = Box::leak(Box::new(SimController::new()));
+    let ctap = Box::leak(Box::new(CtapHid::new(sim, VENDOR_ID, PRODUCT_ID, STRINGS)));
+    sim.set_client(ctap);
+    let client = HidClient::new();
+    ctap.set_client(client);
+    sim.power_up();
+    sim.reset();
+    sim.enumerate(2).unwrap();
+    (sim, ctap, client)
+}
+
+#[test]
+fn ctap_report_descriptor() {
+    let (sim, _, _) = ctap();
+    let configuration = sim.get_descriptor(CONFIGURATION, 0, 255).unwrap();
+
+    // The HID descriptor follows the interface descriptor, and gives the
+    // length of the report descriptor
+    let hid = &configuration[18..];
+    assert_eq!(hid[1], 0x21);
+    assert_eq!(hid[6], 0x22);
+    let report_len = u16::from_le_bytes([hid[7], hid[8]]);
+
+    let report = sim.control_in(0x81, 6, 0x2200, 0, report_len).unwrap();
+    assert_eq!(report.len(), report_len as usize);
+    assert_eq!(&report[..3], &[0x06, 0xd0, 0xf1]);
+}
+
+#[test]
+fn ctap_sends_reports() {
+    let (sim, ctap, client) = ctap();
+
+    ctap.send_buffer(Box::leak(Box::new([0x5a; 64]))).unwrap();
+    assert!(sim.in_resumed(1));
+    assert_eq!(sim.packet_in(1).unwrap(), [0x5a; 64]);
+    assert_eq!(client.transmitted.get(), 1);
+    assert_eq!(sim.packet_in(1), Err(Handshake::Nak));
+}
+
+#[test]
+fn ctap_receives_one_report_at_a_time() {
+    let (sim, ctap, client) = ctap();
+    let buffer = client.buffer.borrow_mut().take().unwrap();
+    ctap.receive_buffer(buffer).unwrap();
+
+    sim.packet_out(1, &[1; 64]).unwrap();
+    assert_eq!(client.received.borrow().len(), 1);
+
+    // The next report waits until the client gives its buffer back
+    assert!(sim.out_paused(1));
+    assert_eq!(sim.packet_out(1, &[2; 64]), Err(Handshake::Nak));
+    let buffer = client.buffer.borrow_mut().take().unwrap();
+    ctap.receive_buffer(buffer).unwrap();
+    sim.packet_out(1, &[2; 64]).unwrap();
+
+    let received = client.received.borrow();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0], [1; 64]);
+    assert_eq!(received[1], [2; 64]);
+}
+
+// DFU
+
+mod dfu_request {
+    pub const DNLOAD: u8 = 1;
+    pub const GETSTATUS: u8 = 3;
+    pub const CLRSTATUS: u8 = 4;
+    pub const ABORT: u8 = 6;
+}
+
+const DFU_STATUS_OK: u8 = 0x00;
+const DFU_STATUS_ERR_FILE: u8 = 0x02;
+const DFU_STATUS_ERR_WRITE: u8 = 0x03;
+const DFU_STATUS_ERR_VERIFY: u8 = 0x07;
+const DFU_STATUS_ERR_STALLED_PACKET: u8 = 0x0f;
+
+const DFU_STATE_IDLE: u8 = 2;
+const DFU_STATE_DNBUSY: u8 = 4;
+const DFU_STATE_DNLOAD_IDLE: u8 = 5;
+const DFU_STATE_MANIFEST: u8 = 7;
+const DFU_STATE_ERROR: u8 = 10;
+
+/// App flash holds an application of `APP_LEN` bytes, and the image of
+/// `IMAGE_LEN` bytes goes at the next multiple of its size.
+const APP_LEN: usize = 0x60;
+const IMAGE_START: usize = 0x100;
+const IMAGE_LEN: usize = 0x100;
+const BINARY_END: usize = 0x80;
+
+#[repr(align(256))]
+struct AppFlash([u8; 0x400]);
+
+/// Returns the TBF header of an object of `total_len` bytes, with `tlvs`
+/// after the base.
+fn tbf_header(total_len: usize, flags: u32, tlvs: &[u8]) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&(16 + tlvs.len() as u16).to_le_bytes());
+    header.extend_from_slice(&(total_len as u32).to_le_bytes());
+    header.extend_from_slice(&flags.to_le_bytes());
+    header.extend_from_slice(&[0; 4]);
+    header.extend_from_slice(tlvs);
+    let checksum = header
+        .chunks(4)
+        .enumerate()
+        .filter(|(i, _)| *i != 3)
+        .fold(0, |checksum, (_, word)| {
+            checksum ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+        });
+    header[12..16].copy_from_slice(&checksum.to_le_bytes());
+    header
+}
+
+/// SHA-256 digest of the header and binary of `dfu_image()`, exactly as
+/// they are signed, with the enabled flag set.
+const IMAGE_SHA256: [u8; 32] = [
+    0x34, 0x71, 0xd7, 0x50, 0x72, 0x3a, 0xc0, 0x4f, 0xe9, 0x98, 0xb0, 0xc6, 0x8f, 0x36, 0x36, 0x7c,
+    0xe8, 0xfb, 0xe5, 0x32, 0xb9, 0x9f, 0x42, 0xeb, 0x5e, 0x40, 0x1e, 0x0f, 0xdb, 0x49, 0x5c, 0x72,
+];
+
+/// Returns an enabled application image with a program header and a SHA256
+/// credentials footer of `hash`.
+fn dfu_image_with_hash(hash: &[u8; 32]) -> Vec<u8> {
+    let mut program = Vec::new();
+    program.extend_from_slice(&9u16.to_le_bytes());
+    program.extend_from_slice(&20u16.to_le_bytes());
+    for field in [40, 0, 0, BINARY_END as u32, 1] {
+        program.extend_from_slice(&u32::to_le_bytes(field));
+    }
+    let mut image = tbf_header(IMAGE_LEN, 1, &program);
+    image.resize(BINARY_END, 0x5a);
+    image.extend_from_slice(&128u16.to_le_bytes());
+    image.extend_from_slice(&36u16.to_le_bytes());
+    image.extend_from_slice(&3u32.to_le_bytes());
+    image.extend_from_slice(hash);
+    image.resize(IMAGE_LEN, 0xff);
+    image
+}
+
+/// Returns a correctly signed application image.
+fn dfu_image() -> Vec<u8> {
+    dfu_image_with_hash(&IMAGE_SHA256)
+}
+
+/// Storage recording the writes to app flash, which completes them when the
+/// test says so.
+struct DfuStorage {
+    client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+    pending: TakeCell<'static, [u8]>,
+    pending_len: Cell<usize>,
+    writes: RefCell<Vec<(usize, Vec<u8>)>>,
+    fail: Cell<bool>,
+}
+
+impl DfuStorage {
+    fn complete(&self) {
+        let buffer = self.pending.take().expect("no pending write");
+        self.client
+            .map(|client| client.write_done(buffer, self.pending_len.get()));
+    }
+}
+
+impl NonvolatileStorage<'static> for DfuStorage {
+    fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+        self.client.set(client);
+    }
+
+    fn read(
+        &self,
+        _buffer: &'static mut [u8],
+        _address: usize,
+        _length: usize,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOSUPPORT)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        assert!(self.pending.is_none());
+        if self.fail.get() {
+            // The buffer is lost, as the HIL does not give it back
+            return Err(ErrorCode::FAIL);
+        }
+        self.writes
+            .borrow_mut()
+            .push((address, buffer[..length].to_vec()));
+        self.pending.replace(buffer);
+        self.pending_len.set(length);
+        Ok(())
+    }
+}
+
+struct DfuHarness {
+    sim: &'static SimController<'static>,
+    app_flash: &'static [u8],
+    storage: &'static DfuStorage,
+    sha: &'static Sha256Software<'static>,
+    handle: DeferredCallHandle,
+}
+
+impl DfuHarness {
+    /// Builds a device with a DFU function checking SHA256 credentials. The
+    /// storage cannot change the memory-mapped app flash, so app flash
+    /// already holds `flashed`, the image as the download writes it, past
+    /// the end of the list of applications.
+    fn new(flashed: &[u8]) -> DfuHarness {
+        let mut flash = AppFlash([0xff; 0x400]);
+        let app = tbf_header(APP_LEN, 1, &[]);
+        flash.0[..app.len()].copy_from_slice(&app);
+        flash.0[IMAGE_START..IMAGE_START + IMAGE_LEN].copy_from_slice(flashed);
+        let app_flash = &Box::leak(Box::new(flash)).0[..];
+
+        let sim = Box::leak(Box::new(SimController::new()));
+        let composite = Box::leak(Box::new(CompositeDevice::new(
+            sim, 64, VENDOR_ID, PRODUCT_ID, STRINGS,
+        )));
+        let storage = Box::leak(Box::new(DfuStorage {
+            client: OptionalCell::empty(),
+            pending: TakeCell::empty(),
+            pending_len: Cell::new(0),
+            writes: RefCell::new(Vec::new()),
+            fail: Cell::new(false),
+        }));
+        let states: &'static [DynamicDeferredCallClientState] =
+            Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let sha = Box::leak(Box::new(Sha256Software::new(ddc)));
+        let handle = ddc.register(sha).unwrap();
+        sha.initialize_callback_handle(handle);
+        let checker = Box::leak(Box::new(AppCheckerSha256::new(
+            sha,
+            Box::leak(Box::new([0; 32])),
+        )));
+        sha.set_client(checker);
+        let dfu = Box::leak(Box::new(UsbDfu::new(
+            storage,
+            app_flash,
+            checker,
+            leak_buffer(TRANSFER_SIZE),
+        )));
+        storage.set_client(dfu);
+        checker.set_client(dfu);
+        composite.add_function(dfu).unwrap();
+        sim.set_client(composite);
+
+        sim.power_up();
+        sim.reset();
+        sim.enumerate(1).unwrap();
+        DfuHarness {
+            sim,
+            app_flash,
+            storage,
+            sha,
+            handle,
+        }
+    }
+
+    fn address(&self, offset: usize) -> usize {
+        self.app_flash.as_ptr() as usize + offset
+    }
+
+    /// Returns the status and the state reported by DFU_GETSTATUS.
+    fn get_status(&self) -> (u8, u8) {
+        let status = self
+            .sim
+            .control_in(0xa1, dfu_request::GETSTATUS, 0, 0, 6)
+            .unwrap();
+        assert_eq!(status.len(), 6);
+        (status[0], status[4])
+    }
+
+    fn download(&self, block: u16, data: &[u8]) -> Result<(), Handshake> {
+        self.sim
+            .control_out(0x21, dfu_request::DNLOAD, block, 0, data)
+    }
+
+    /// Downloads `image` in two blocks, waiting for each to be written.
+    fn download_image(&self, image: &[u8]) {
+        for (i, block) in image.chunks(IMAGE_LEN / 2).enumerate() {
+            self.download(i as u16, block).unwrap();
+            assert_eq!(self.get_status(), (DFU_STATUS_OK, DFU_STATE_DNBUSY));
+            self.storage.complete();
+            assert_eq!(self.get_status(), (DFU_STATUS_OK, DFU_STATE_DNLOAD_IDLE));
+        }
+    }
+
+    /// Ends the download, and lets the checker hash the image and compare
+    /// the digest with its footer.
+    fn manifest(&self) {
+        self.download(2, &[]).unwrap();
+        assert_eq!(self.get_status(), (DFU_STATUS_OK, DFU_STATE_MANIFEST));
+        self.sha.call(self.handle);
+        self.sha.call(self.handle);
+    }
+}
+
+#[test]
+fn dfu_installs_image() {
+    let h = DfuHarness::new(&dfu_image());
+    h.download_image(&dfu_image());
+    h.manifest();
+    h.storage.complete();
+    assert_eq!(h.get_status(), (DFU_STATUS_OK, DFU_STATE_IDLE));
+
+    // The image is written exactly as it was signed, so the kernel checks
+    // the same bytes at boot
+    let writes = h.storage.writes.borrow();
+    let image = dfu_image();
+    assert_eq!(writes.len(), 3);
+    assert_eq!(writes[0], (h.address(IMAGE_START), image[..0x80].to_vec()));
+    assert_eq!(
+        writes[1],
+        (h.address(IMAGE_START + 0x80), image[0x80..].to_vec())
+    );
+    // Only once the credentials are accepted, a padding header links the
+    // application to the image
+    assert_eq!(
+        writes[2],
+        (
+            h.address(APP_LEN),
+            tbf_header(IMAGE_START - APP_LEN, 0, &[])
+        )
+    );
+}
+
+#[test]
+fn dfu_leaves_rejected_image_unlinked() {
+    let image = dfu_image_with_hash(&[0xcc; 32]);
+    let h = DfuHarness::new(&image);
+    h.download_image(&image);
+    h.manifest();
+    assert_eq!(h.get_status(), (DFU_STATUS_ERR_VERIFY, DFU_STATE_ERROR));
+
+    // The end of the applications is left erased, so the image is not
+    // loaded
+    assert_eq!(h.storage.writes.borrow().len(), 2);
+    assert!(h.app_flash[APP_LEN..IMAGE_START].iter().all(|b| *b == 0xff));
+    h.sim
+        .control_out(0x21, dfu_request::CLRSTATUS, 0, 0, &[])
+        .unwrap();
+    assert_eq!(h.get_status(), (DFU_STATUS_OK, DFU_STATE_IDLE));
+}
+
+#[test]
+fn dfu_rejects_block_without_header() {
+    let h = DfuHarness::new(&dfu_image());
+    assert_eq!(h.download(0, &[0; 64]), Ok(()));
+    assert_eq!(h.get_status(), (DFU_STATUS_ERR_FILE, DFU_STATE_ERROR));
+    assert!(h.storage.writes.borrow().is_empty());
+}
+
+#[test]
+fn dfu_recovers_from_failed_write() {
+    let h = DfuHarness::new(&dfu_image());
+    let image = dfu_image();
+    h.download(0, &image[..0x80]).unwrap();
+    h.storage.complete();
+
+    // Writing the second block fails. The part of the image written is
+    // past the end of the applications, so there is nothing to erase.
+    h.storage.fail.set(true);
+    h.download(1, &image[0x80..]).unwrap();
+    assert_eq!(h.get_status(), (DFU_STATUS_ERR_WRITE, DFU_STATE_ERROR));
+    h.sim
+        .control_out(0x21, dfu_request::CLRSTATUS, 0, 0, &[])
+        .unwrap();
+    assert_eq!(h.get_status(), (DFU_STATUS_OK, DFU_STATE_IDLE));
+    assert_eq!(h.storage.writes.borrow().len(), 1);
+}
+
+#[test]
+fn dfu_ignores_requests_for_others() {
+    let h = DfuHarness::new(&dfu_image());
+    let image = dfu_image();
+    h.download(0, &image[..0x80]).unwrap();
+    h.storage.complete();
+    assert_eq!(h.get_status(), (DFU_STATUS_OK, DFU_STATE_DNLOAD_IDLE));
+
+    // Class requests to the device, or to another interface, are refused
+    // without aborting the download
+    assert_eq!(
+        h.sim.control_out(0x20, dfu_request::ABORT, 0, 0, &[]),
+        Err(Handshake::Stall)
+    );
+    assert_eq!(
+        h.sim.control_out(0x21, dfu_request::ABORT, 0, 1, &[]),
+        Err(Handshake::Stall)
+    );
+    assert_eq!(h.get_status(), (DFU_STATUS_OK, DFU_STATE_DNLOAD_IDLE));
+
+    // A request to DFU that its state does not allow ends the download
+    assert_eq!(
+        h.sim.control_out(0x21, dfu_request::CLRSTATUS, 0, 0, &[]),
+        Err(Handshake::Stall)
+    );
+    assert_eq!(
+        h.get_status(),
+        (DFU_STATUS_ERR_STALLED_PACKET, DFU_STATE_ERROR)
+    );
+    assert_eq!(h.storage.writes.borrow().len(), 1);
+}
+
+// Composite devices
+
+type Composite = CompositeDevice<'static, SimController<'static>>;
+
+/// Builds a composite device with `functions`, and brings it up on the bus.
+fn composite(
+    sim: &'static SimController<'static>,
+    functions: &[&'static dyn UsbFunction<'static>],
+) -> &'static Composite {
+    let composite = Box::leak(Box::new(CompositeDevice::new(
+        sim, 64, VENDOR_ID, PRODUCT_ID, STRINGS,
+    )));
+    for function in functions {
+        composite.add_function(*function).unwrap();
+    }
+    sim.set_client(composite);
+    sim.power_up();
+    sim.reset();
+    composite
+}
+
+/// Splits a configuration descriptor into the descriptors it holds.
+fn descriptors(configuration: &[u8]) -> Vec<&[u8]> {
+    let mut descriptors = Vec::new();
+    let mut rest = configuration;
+    while !rest.is_empty() {
+        let (descriptor, next) = rest.split_at(rest[0] as usize);
+        descriptors.push(descriptor);
+        rest = next;
+    }
+    descriptors
+}
+
+const INTERFACE: u8 = 4;
+const ENDPOINT: u8 = 5;
+const INTERFACE_ASSOCIATION: u8 = 11;
+const CS_INTERFACE: u8 = 0x24;
+
+/// A CTAP HID function on endpoint 1, a CDC-ACM function on endpoints 2 to
+/// 4, and a vendor function on endpoints 5 and 6.
+fn composite_functions(
+    sim: &'static SimController<'static>,
+) -> [&'static dyn UsbFunction<'static>; 3] {
+    let ctap = Box::leak(Box::new(CtapHid::new(sim, VENDOR_ID, PRODUCT_ID, STRINGS)));
+    let alarm = Box::leak(Box::new(MockAlarm::<Freq1KHz>::new()));
+    let states: &'static [DynamicDeferredCallClientState] =
+        Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+    let ddc = Box::leak(Box::new(DynamicDeferredCall::new(states)));
+    let cdc = Box::leak(Box::new(CdcAcm::new(
+        sim, 64, VENDOR_ID, PRODUCT_ID, STRINGS, alarm, ddc, None,
+    )));
+    let vendor = Box::leak(Box::new(VendorBulk::new(sim, 5, 6)));
+    [ctap, cdc, vendor]
+}
+
+#[test]
+fn composite_numbers_interfaces_in_order() {
+    let sim = Box::leak(Box::new(SimController::new()));
+    composite(sim, &composite_functions(sim));
+    let enumeration = sim.enumerate(1).unwrap();
+
+    // Multi-interface function device class
+    assert_eq!(&enumeration.device[4..7], &[0xef, 0x02, 0x01]);
+    assert_eq!(enumeration.configuration[4], 4);
+
+    let descriptors = descriptors(&enumeration.configuration);
+    let interfaces: Vec<(u8, u8)> = descriptors
+        .iter()
+        .filter(|d| d[1] == INTERFACE)
+        .map(|d| (d[2], d[5]))
+        .collect();
+    assert_eq!(interfaces, [(0, 0x03), (1, 0x02), (2, 0x0a), (3, 0xff)]);
+
+    // Only the CDC-ACM function has more than one interface, and its
+    // interface association descriptor comes right before them
+    let associations: Vec<usize> = (0..descriptors.len())
+        .filter(|i| descriptors[*i][1] == INTERFACE_ASSOCIATION)
+        .collect();
+    assert_eq!(associations.len(), 1);
+    let association = descriptors[associations[0]];
+    assert_eq!(association, &[8, 11, 1, 2, 0x02, 0x02, 0x01, 0]);
+    assert_eq!(descriptors[associations[0] + 1][..3], [9, INTERFACE, 1]);
+
+    // The functional descriptors of CDC-ACM refer to its own interfaces
+    let union = descriptors
+        .iter()
+        .find(|d| d[1] == CS_INTERFACE && d[2] == 0x06)
+        .unwrap();
+    assert_eq!(&union[3..], &[1, 2]);
+
+    let endpoints: Vec<u8> = descriptors
+        .iter()
+        .filter(|d| d[1] == ENDPOINT)
+        .map(|d| d[2])
+        .collect();
+    assert_eq!(endpoints, [0x81, 0x01, 0x84, 0x82, 0x03, 0x85, 0x06]);
+}
+
+#[test]
+fn composite_routes_requests_to_functions() {
+    let sim = Box::leak(Box::new(SimController::new()));
+    let composite = composite(sim, &composite_functions(sim));
+    sim.enumerate(1).unwrap();
+    assert!(composite.is_configured());
+    assert_eq!(sim.control_in(0x80, 8, 0, 0, 1).unwrap(), [1]);
+
+    // GET_LINE_CODING to the communication interface of CDC-ACM
+    assert_eq!(
+        sim.control_in(0xa1, 0x21, 0, 1, 7).unwrap(),
+        [0x00, 0xc2, 0x01, 0x00, 0, 0, 8]
+    );
+    // The vendor function has no class requests, and there is no fifth
+    // interface
+    assert_eq!(sim.control_in(0xa1, 0x21, 0, 3, 7), Err(Handshake::Stall));
+    assert_eq!(sim.control_in(0xa1, 0x21, 0, 4, 7), Err(Handshake::Stall));
+
+    // Class requests to the device are not passed to any function, even
+    // one which would accept them for its interface
+    assert_eq!(sim.control_in(0xa0, 0x21, 0, 1, 7), Err(Handshake::Stall));
+    assert_eq!(
+        sim.control_out(0x20, 0x20, 0, 1, &[0x00, 0xc2, 0x01, 0x00, 0, 0, 8]),
+        Err(Handshake::Stall)
+    );
+
+    // Endpoints are routed to the function using them
+    assert_eq!(sim.packet_in(5), Err(Handshake::Nak));
+    assert_eq!(sim.packet_out(6, &[1; 64]), Ok(()));
+    assert!(sim.out_paused(6));
+}
+
+#[test]
+fn composite_keeps_endpoint_halt_state() {
+    const GET_STATUS: u8 = 0;
+    const CLEAR_FEATURE: u8 = 1;
+    const SET_FEATURE: u8 = 3;
+    const ENDPOINT_HALT: u16 = 0;
+
+    let sim = Box::leak(Box::new(SimController::new()));
+    composite(sim, &composite_functions(sim));
+    sim.enumerate(1).unwrap();
+    let status = |address| sim.control_in(0x82, GET_STATUS, 0, address, 2);
+    let halt = |address| sim.control_out(0x02, SET_FEATURE, ENDPOINT_HALT, address, &[]);
+    let clear = |address| sim.control_out(0x02, CLEAR_FEATURE, ENDPOINT_HALT, address, &[]);
+
+    // A halted OUT endpoint stalls the host until the halt is cleared
+    assert_eq!(status(0x06), Ok(vec![0, 0]));
+    assert_eq!(halt(0x06), Ok(()));
+    assert_eq!(status(0x06), Ok(vec![1, 0]));
+    assert_eq!(sim.packet_out(6, &[1; 64]), Err(Handshake::Stall));
+    assert_eq!(clear(0x06), Ok(()));
+    assert_eq!(status(0x06), Ok(vec![0, 0]));
+    assert_eq!(sim.packet_out(6, &[1; 64]), Ok(()));
+
+    // Clearing the halt of an IN endpoint resumes it, for the function to
+    // send what it has queued
+    assert_eq!(halt(0x85), Ok(()));
+    assert_eq!(status(0x85), Ok(vec![1, 0]));
+    assert_eq!(status(0x05), Ok(vec![0, 0]));
+    assert!(!sim.in_resumed(5));
+    assert_eq!(clear(0x85), Ok(()));
+    assert!(sim.in_resumed(5));
+    assert_eq!(sim.packet_in(5), Err(Handshake::Nak));
+
+    // Selecting the configuration clears every halt
+    assert_eq!(halt(0x85), Ok(()));
+    assert_eq!(sim.set_configuration(1), Ok(()));
+    assert_eq!(status(0x85), Ok(vec![0, 0]));
+
+    // The default control endpoint is never halted, and endpoints of no
+    // function cannot be
+    assert_eq!(status(0x80), Ok(vec![0, 0]));
+    assert_eq!(status(0x87), Err(Handshake::Stall));
+    assert_eq!(halt(0x87), Err(Handshake::Stall));
+    assert_eq!(clear(0x00), Err(Handshake::Stall));
+}
+
+#[test]
+fn composite_rejects_conflicting_functions() {
+    let sim = Box::leak(Box::new(SimController::new()));
+    let composite = Box::leak(Box::new(CompositeDevice::new(
+        sim, 64, VENDOR_ID, PRODUCT_ID, STRINGS,
+    )));
+    let leak =
+        |endpoint_in, endpoint_out| -> &'static VendorBulk<'static, SimController<'static>> {
+            Box::leak(Box::new(VendorBulk::new(sim, endpoint_in, endpoint_out)))
+        };
+
+    // The control endpoint belongs to the composite device
+    assert_eq!(composite.add_function(leak(0, 1)), Err(ErrorCode::INVAL));
+    assert_eq!(composite.add_function(leak(1, 2)), Ok(()));
+    assert_eq!(composite.add_function(leak(2, 3)), Err(ErrorCode::INVAL));
+    for endpoint in 3..6 {
+        assert_eq!(composite.add_function(leak(endpoint, endpoint)), Ok(()));
+    }
+    assert_eq!(composite.add_function(leak(7, 7)), Err(ErrorCode::NOMEM));
+
+    // Functions cannot be added once the device is enabled
+    sim.set_client(composite);
+    sim.power_up();
+    assert_eq!(composite.add_function(leak(8, 8)), Err(ErrorCode::ALREADY));
+}
+
+// Mass storage
+
+const MSC_BLOCK_COUNT: u32 = 4;
+/// The drive starts after the first block of the storage.
+const MSC_START: usize = BLOCK_SIZE;
+
+mod scsi {
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+}
+
+/// Storage in RAM, which completes accesses when the test says so.
+struct RamDisk {
+    data: RefCell<Vec<u8>>,
+    client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+    /// The buffer of the pending access
+    pending: TakeCell<'static, [u8]>,
+    /// The address of the pending access, and whether it is a write
+    access: Cell<(usize, bool)>,
+}
+
+impl RamDisk {
+    /// Completes the pending access. Returns false if there is none.
+    fn complete(&self) -> bool {
+        let buffer = match self.pending.take() {
+            Some(buffer) => buffer,
+            None => return false,
+        };
+        let (address, write) = self.access.get();
+        let mut data = self.data.borrow_mut();
+        let block = &mut data[address..address + buffer.len()];
+        self.client.map(|client| {
+            if write {
+                block.copy_from_slice(buffer);
+                client.write_done(buffer, block.len());
+            } else {
+                buffer.copy_from_slice(block);
+                client.read_done(buffer, block.len());
+            }
+        });
+        true
+    }
+}
+
+impl NonvolatileStorage<'static> for RamDisk {
+    fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+        self.client.set(client);
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        assert_eq!(length, buffer.len());
+        self.pending.replace(buffer);
+        self.access.set((address, false));
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> Result<(), ErrorCode> {
+        assert_eq!(length, buffer.len());
+        self.pending.replace(buffer);
+        self.access.set((address, true));
+        Ok(())
+    }
+}
+
+/// The status of a command reported in a CSW.
+#[derive(Debug, PartialEq)]
+struct Csw {
+    tag: u32,
+    residue: u32,
+    failed: bool,
+}
+
+struct MscHarness {
+    sim: &'static SimController<'static>,
+    disk: &'static RamDisk,
+}
+
+impl MscHarness {
+    /// A drive on endpoints 1 and 2, whose block `n` is filled with `n`.
+    fn new() -> MscHarness {
+        let data = (0..=MSC_BLOCK_COUNT as usize)
+            .flat_map(|block| std::vec![block as u8; BLOCK_SIZE])
+            .collect();
+        let disk = Box::leak(Box::new(RamDisk {
+            data: RefCell::new(data),
+            client: OptionalCell::empty(),
+            pending: TakeCell::empty(),
+            access: Cell::new((0, false)),
+        }));
+        let sim = Box::leak(Box::new(SimController::new()));
+        let msc = Box::leak(Box::new(UsbMassStorage::new(
+            sim,
+            disk,
+            MSC_START,
+            MSC_BLOCK_COUNT,
+            1,
+            2,
+            leak_buffer(BLOCK_SIZE),
+        )));
+        disk.set_client(msc);
+        composite(sim, &[msc]);
+        sim.enumerate(1).unwrap();
+        MscHarness { sim, disk }
+    }
+
+    /// Sends a CBW announcing a data phase of `data_length` bytes.
+    fn command(&self, tag: u32, data_length: u32, data_in: bool, command: &[u8]) {
+        let mut cbw = Vec::new();
+        cbw.extend_from_slice(&0x43425355u32.to_le_bytes());
+        cbw.extend_from_slice(&tag.to_le_bytes());
+        cbw.extend_from_slice(&data_length.to_le_bytes());
+        cbw.push(if data_in { 0x80 } else { 0x00 });
+        cbw.push(0); // LUN
+        cbw.push(command.len() as u8);
+        cbw.extend_from_slice(command);
+        cbw.resize(31, 0);
+        self.sim.packet_out(2, &cbw).unwrap();
+    }
+
+    /// Sends a READ(10) or WRITE(10) CBW.
+    fn rw10(&self, tag: u32, opcode: u8, block: u32, count: u16) {
+        let mut command = std::vec![opcode, 0];
+        command.extend_from_slice(&block.to_be_bytes());
+        command.push(0);
+        command.extend_from_slice(&count.to_be_bytes());
+        command.push(0);
+        let length = count as u32 * BLOCK_SIZE as u32;
+        self.command(tag, length, opcode == scsi::READ_10, &command);
+    }
+
+    /// Reads a data phase of `len` bytes, completing the reads of the
+    /// storage the drive waits for.
+    fn data_in(&self, len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while data.len() < len {
+            match self.sim.packet_in(1) {
+                Ok(packet) => data.extend_from_slice(&packet),
+                Err(Handshake::Nak) => assert!(self.disk.complete()),
+                Err(Handshake::Stall) => panic!("data phase stalled"),
+            }
+        }
+        assert_eq!(data.len(), len);
+        data
+    }
+
+    /// Sends a data phase, completing the writes of the storage the drive
+    /// waits for.
+    fn data_out(&self, data: &[u8]) {
+        for packet in data.chunks(64) {
+            while self.sim.packet_out(2, packet) == Err(Handshake::Nak) {
+                assert!(self.disk.complete());
+            }
+        }
+        self.disk.complete();
+    }
+
+    fn csw(&self) -> Csw {
+        let csw = self.sim.packet_in(1).unwrap();
+        assert_eq!(csw.len(), 13);
+        assert_eq!(&csw[..4], &0x53425355u32.to_le_bytes());
+        let u32_at = |i: usize| u32::from_le_bytes([csw[i], csw[i + 1], csw[i + 2], csw[i + 3]]);
+        Csw {
+            tag: u32_at(4),
+            residue: u32_at(8),
+            failed: csw[12] != 0,
+        }
+    }
+
+    /// Returns the sense key and additional sense code of the last failure.
+    fn sense(&self, tag: u32) -> (u8, u8) {
+        self.command(tag, 18, true, &[scsi::REQUEST_SENSE, 0, 0, 0, 18, 0]);
+        let sense = self.data_in(18);
+        assert_eq!(
+            self.csw(),
+            Csw {
+                tag: tag,
+                residue: 0,
+                failed: false
+            }
+        );
+        assert_eq!(sense[0], 0x70);
+        (sense[2], sense[12])
+    }
+}
+
+#[test]
+fn msc_interface() {
+    let h = MscHarness::new();
+    let configuration = h.sim.get_descriptor(CONFIGURATION, 0, 255).unwrap();
+    let interface = descriptors(&configuration)[1];
+    // Mass storage, SCSI transparent command set, Bulk-Only Transport
+    assert_eq!(&interface[4..8], &[2, 0x08, 0x06, 0x50]);
+    // GET_MAX_LUN
+    assert_eq!(h.sim.control_in(0xa1, 0xfe, 0, 0, 1).unwrap(), [0]);
+}
+
+#[test]
+fn msc_reads_blocks() {
+    let h = MscHarness::new();
+    h.rw10(7, scsi::READ_10, 1, 2);
+    let data = h.data_in(2 * BLOCK_SIZE);
+    // Drive blocks 1 and 2 are storage blocks 2 and 3
+    assert!(data[..BLOCK_SIZE].iter().all(|b| *b == 2));
+    assert!(data[BLOCK_SIZE..].iter().all(|b| *b == 3));
+    assert_eq!(
+        h.csw(),
+        Csw {
+            tag: 7,
+            residue: 0,
+            failed: false
+        }
+    );
+
+    // The drive waits for the next CBW
+    assert_eq!(h.sim.packet_in(1), Err(Handshake::Nak));
+    assert!(!h.sim.out_paused(2));
+}
+
+#[test]
+fn msc_writes_blocks() {
+    let h = MscHarness::new();
+    let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+    h.rw10(8, scsi::WRITE_10, 1, 2);
+    h.data_out(&data);
+    assert_eq!(
+        h.csw(),
+        Csw {
+            tag: 8,
+            residue: 0,
+            failed: false
+        }
+    );
+    let start = MSC_START + BLOCK_SIZE;
+    let disk = h.disk.data.borrow();
+    assert_eq!(&disk[start..start + data.len()], &data[..]);
+    // Neighbouring blocks are left alone
+    assert!(disk[start - BLOCK_SIZE..start].iter().all(|b| *b == 1));
+    assert!(disk[start + data.len()..].iter().all(|b| *b == 4));
+}
+
+#[test]
+fn msc_reports_errors_in_sense_data() {
+    let h = MscHarness::new();
+
+    // Blocks past the end of the drive: the data phase is padded, and its
+    // whole length reported as a residue
+    h.rw10(1, scsi::READ_10, 3, 2);
+    assert!(h.data_in(2 * BLOCK_SIZE).iter().all(|b| *b == 0));
+    assert_eq!(
+        h.csw(),
+        Csw {
+            tag: 1,
+            residue: 2 * BLOCK_SIZE as u32,
+            failed: true
+        }
+    );
+    // LOGICAL BLOCK ADDRESS OUT OF RANGE, then no sense once reported
+    assert_eq!(h.sense(2), (0x05, 0x21));
+    assert_eq!(h.sense(3), (0x00, 0x00));
+
+    // A write with a data phase to the host
+    h.command(
+        4,
+        BLOCK_SIZE as u32,
+        true,
+        &[scsi::WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0],
+    );
+    h.data_in(BLOCK_SIZE);
+    assert!(h.csw().failed);
+    // Nothing was written
+    assert!(h.disk.data.borrow()[MSC_START..2 * BLOCK_SIZE]
+        .iter()
+        .all(|b| *b == 1));
+    // INVALID FIELD IN CDB
+    assert_eq!(h.sense(5), (0x05, 0x24));
+
+    // An unsupported command, without data phase
+    h.command(6, 0, false, &[0xff, 0, 0, 0, 0, 0]);
+    assert_eq!(
+        h.csw(),
+        Csw {
+            tag: 6,
+            residue: 0,
+            failed: true
+        }
+    );
+    // INVALID COMMAND OPERATION CODE
+    assert_eq!(h.sense(7), (0x05, 0x20));
+}
+
+// HID keyboard and mouse
+
+type Hid = BootHid<'static, SimController<'static>>;
+
+/// A keyboard on endpoint 1 and a mouse on endpoint 2.
+fn boot_hid() -> (
+    &'static SimController<'static>,
+    (&'static Hid, &'static HidClient<REPORT_SIZE>),
+    (&'static Hid, &'static HidClient<REPORT_SIZE>),
+) {
+    let sim = Box::leak(Box::new(SimController::new()));
+    let keyboard = Box::leak(Box::new(BootHid::new(sim, BootDevice::Keyboard, 1)));
+    let mouse = Box::leak(Box::new(BootHid::new(sim, BootDevice::Mouse, 2)));
+    let keyboard_client = HidClient::new();
+    keyboard.set_client(keyboard_client);
+    let mouse_client = HidClient::new();
+    mouse.set_client(mouse_client);
+    composite(sim, &[keyboard, mouse]);
+    sim.enumerate(1).unwrap();
+    (sim, (keyboard, keyboard_client), (mouse, mouse_client))
+}
+
+#[test]
+fn hid_boot_interfaces() {
+    let (sim, _, _) = boot_hid();
+    let configuration = sim.get_descriptor(CONFIGURATION, 0, 255).unwrap();
+    let descriptors = descriptors(&configuration);
+    let interfaces: Vec<&[u8]> = descriptors
+        .iter()
+        .filter(|d| d[1] == INTERFACE)
+        .map(|d| &d[2..8])
+        .collect();
+    // HID, boot interface subclass, keyboard and mouse protocols
+    assert_eq!(
+        interfaces,
+        [&[0, 0, 1, 0x03, 0x01, 1], &[1, 0, 1, 0x03, 0x01, 2]]
+    );
+
+    // The report descriptor of each interface, with the length given in
+    // its HID descriptor
+    for (interface, usage) in [(0, 0x06), (1, 0x02)] {
+        let hid = descriptors
+            .iter()
+            .filter(|d| d[1] == 0x21)
+            .nth(interface)
+            .unwrap();
+        let report_len = u16::from_le_bytes([hid[7], hid[8]]);
+        let report = sim
+            .control_in(0x81, 6, 0x2200, interface as u16, report_len)
+            .unwrap();
+        assert_eq!(report.len(), report_len as usize);
+        assert_eq!(&report[..4], &[0x05, 0x01, 0x09, usage]);
+    }
+}
+
+#[test]
+fn hid_delivers_keyboard_reports() {
+    let (sim, (keyboard, client), _) = boot_hid();
+    assert_eq!(sim.packet_in(1), Err(Handshake::Nak));
+
+    // Left Shift and A
+    let report = [0x02, 0, 0x04, 0, 0, 0, 0, 0];
+    assert_eq!(keyboard.send_buffer(Box::leak(Box::new(report))), Ok(8));
+    assert_eq!(
+        keyboard
+            .send_buffer(Box::leak(Box::new([0; REPORT_SIZE])))
+            .map_err(|(e, _)| e),
+        Err(ErrorCode::BUSY)
+    );
+    assert_eq!(sim.packet_in(1).unwrap(), report);
+    assert_eq!(client.transmitted.get(), 1);
+    assert_eq!(sim.packet_in(1), Err(Handshake::Nak));
+
+    // GET_REPORT returns the last input report
+    assert_eq!(sim.control_in(0xa1, 0x01, 0x0100, 0, 8).unwrap(), report);
+}
+
+#[test]
+fn hid_delivers_mouse_reports_in_either_protocol() {
+    let (sim, _, (mouse, client)) = boot_hid();
+
+    // Buttons, x, y and the wheel in the report protocol
+    let report = [0x01, 5, 0xfb, 0xff, 0, 0, 0, 0];
+    assert_eq!(mouse.send_buffer(Box::leak(Box::new(report))), Ok(4));
+    assert_eq!(sim.packet_in(2).unwrap(), report[..4]);
+
+    // SET_PROTOCOL to the boot protocol drops the wheel
+    sim.control_out(0x21, 0x0b, 0, 1, &[]).unwrap();
+    assert_eq!(sim.control_in(0xa1, 0x03, 0, 1, 1).unwrap(), [0]);
+    assert_eq!(mouse.protocol(), Protocol::Boot);
+    assert_eq!(mouse.send_buffer(Box::leak(Box::new(report))), Ok(3));
+    assert_eq!(sim.packet_in(2).unwrap(), report[..3]);
+    assert_eq!(client.transmitted.get(), 2);
+
+    // A bus reset goes back to the report protocol
+    sim.reset();
+    assert_eq!(mouse.protocol(), Protocol::Report);
+}
+
+#[test]
+fn hid_delivers_keyboard_leds() {
+    let (sim, (keyboard, client), (mouse, _)) = boot_hid();
+
+    // SET_REPORT of an output report: Caps Lock
+    sim.control_out(0x21, 0x09, 0x0200, 0, &[0x02]).unwrap();
+    assert!(client.received.borrow().is_empty());
+    let buffer = client.buffer.borrow_mut().take().unwrap();
+    keyboard.receive_buffer(buffer).unwrap();
+    assert_eq!(*client.received.borrow(), [[0x02, 0, 0, 0, 0, 0, 0, 0]]);
+
+    // Once the client has a buffer, reports are delivered as they arrive
+    let buffer = client.buffer.borrow_mut().take().unwrap();
+    keyboard.receive_buffer(buffer).unwrap();
+    sim.control_out(0x21, 0x09, 0x0200, 0, &[0x03]).unwrap();
+    assert_eq!(client.received.borrow()[1][0], 0x03);
+    assert_eq!(sim.control_in(0xa1, 0x01, 0x0200, 0, 1).unwrap(), [0x03]);
+
+    // Mice have no output report
+    assert_eq!(
+        sim.control_out(0x21, 0x09, 0x0200, 1, &[0x02]),
+        Err(Handshake::Stall)
+    );
+    assert_eq!(
+        mouse
+            .receive_buffer(Box::leak(Box::new([0; REPORT_SIZE])))
+            .map_err(|(e, _)| e),
+        Err(ErrorCode::NOSUPPORT)
+    );
+}
+
+// CDC-ECM
+
+const ECM_MAC_STRING: u8 = 4;
+const ECM_NOTIFY: usize = 1;
+const ECM_IN: usize = 2;
+const ECM_OUT: usize = 3;
+
+#[derive(Default)]
+struct Ethernet {
+    sent: RefCell<Vec<(Result<(), ErrorCode>, usize)>>,
+    received: RefCell<Vec<Vec<u8>>>,
+}
+
+impl EthernetAdapterClient for Ethernet {
+    fn tx_done(&self, result: Result<(), ErrorCode>, _frame: &'static mut [u8], len: usize) {
+        self.sent.borrow_mut().push((result, len));
+    }
+
+    fn rx_frame(&self, frame: &[u8]) {
+        self.received.borrow_mut().push(frame.to_vec());
+    }
+}
+
+struct EcmHarness {
+    sim: &'static SimController<'static>,
+    ecm: &'static CdcEcm<'static, SimController<'static>>,
+    ethernet: &'static Ethernet,
+}
+
+impl EcmHarness {
+    fn new() -> EcmHarness {
+        let sim = Box::leak(Box::new(SimController::new()));
+        let ecm = Box::leak(Box::new(CdcEcm::new(
+            sim,
+            ECM_MAC_STRING,
+            EthernetAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+            ECM_NOTIFY,
+            ECM_IN,
+            ECM_OUT,
+            leak_buffer(MAX_FRAME_LEN),
+        )));
+        let ethernet = Box::leak(Box::new(Ethernet::default()));
+        ecm.set_client(ethernet);
+        ecm.enable_receive();
+        composite(sim, &[ecm]);
+        sim.enumerate(1).unwrap();
+        EcmHarness { sim, ecm, ethernet }
+    }
+
+    /// Selects the alternate setting of the data interface.
+    fn set_data_interface(&self, alternate_setting: u16) {
+        self.sim
+            .control_out(0x01, 0x0b, alternate_setting, 1, &[])
+            .unwrap();
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), ErrorCode> {
+        let buffer = leak_buffer(MAX_FRAME_LEN);
+        buffer[..frame.len()].copy_from_slice(frame);
+        self.ecm
+            .transmit(buffer, frame.len())
+            .map_err(|(error, _)| error)
+    }
+}
+
+fn frame(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+#[test]
+fn ecm_announces_the_link() {
+    let h = EcmHarness::new();
+    assert_eq!(h.sim.string(ECM_MAC_STRING).unwrap(), "020000000001");
+    let configuration = h.sim.get_descriptor(CONFIGURATION, 0, 255).unwrap();
+    let networking = descriptors(&configuration)
+        .into_iter()
+        .find(|d| d[1] == CS_INTERFACE && d[2] == 0x0f)
+        .unwrap()
+        .to_vec();
+    assert_eq!(networking[3], ECM_MAC_STRING);
+
+    // The link is down until the host selects the data interface with
+    // endpoints
+    assert_eq!(h.sim.packet_in(ECM_NOTIFY), Err(Handshake::Nak));
+    assert_eq!(h.sim.control_in(0x81, 0x0a, 0, 1, 1).unwrap(), [0]);
+    h.set_data_interface(1);
+    assert_eq!(h.sim.control_in(0x81, 0x0a, 0, 1, 1).unwrap(), [1]);
+
+    // NETWORK_CONNECTION, then CONNECTION_SPEED_CHANGE at 12 Mbit/s
+    assert_eq!(
+        h.sim.packet_in(ECM_NOTIFY).unwrap(),
+        [0xa1, 0x00, 1, 0, 0, 0, 0, 0]
+    );
+    let rate = 12_000_000u32.to_le_bytes();
+    let mut speed = std::vec![0xa1, 0x2a, 0, 0, 0, 0, 8, 0];
+    speed.extend_from_slice(&rate);
+    speed.extend_from_slice(&rate);
+    assert_eq!(h.sim.packet_in(ECM_NOTIFY).unwrap(), speed);
+    assert_eq!(h.sim.packet_in(ECM_NOTIFY), Err(Handshake::Nak));
+}
+
+#[test]
+fn ecm_sends_frames() {
+    let h = EcmHarness::new();
+    assert_eq!(h.transmit(&frame(60)), Err(ErrorCode::OFF));
+    h.set_data_interface(1);
+
+    // Frames end with a short packet
+    assert_eq!(h.transmit(&frame(130)), Ok(()));
+    assert_eq!(h.transmit(&frame(60)), Err(ErrorCode::BUSY));
+    assert_eq!(h.sim.packet_in(ECM_IN).unwrap().len(), 64);
+    assert_eq!(h.sim.packet_in(ECM_IN).unwrap().len(), 64);
+    assert!(h.ethernet.sent.borrow().is_empty());
+    assert_eq!(h.sim.packet_in(ECM_IN).unwrap(), frame(130)[128..]);
+    assert_eq!(*h.ethernet.sent.borrow(), [(Ok(()), 130)]);
+    assert_eq!(h.sim.packet_in(ECM_IN), Err(Handshake::Nak));
+
+    // which is empty if the frame fills its last packet
+    assert_eq!(h.transmit(&frame(128)), Ok(()));
+    assert_eq!(h.sim.read(ECM_IN).unwrap(), frame(128));
+    assert_eq!(h.ethernet.sent.borrow()[1], (Ok(()), 128));
+
+    // Deselecting the data interface cancels the frame being sent
+    assert_eq!(h.transmit(&frame(60)), Ok(()));
+    h.set_data_interface(0);
+    assert_eq!(h.ethernet.sent.borrow()[2], (Err(ErrorCode::CANCEL), 60));
+}
+
+#[test]
+fn ecm_receives_frames() {
+    let h = EcmHarness::new();
+    assert_eq!(h.sim.packet_out(ECM_OUT, &[0; 60]), Err(Handshake::Stall));
+    h.set_data_interface(1);
+
+    h.sim.write(ECM_OUT, &frame(100)).unwrap();
+    // A frame filling its last packet is ended by an empty packet
+    h.sim.write(ECM_OUT, &frame(128)).unwrap();
+    assert_eq!(h.ethernet.received.borrow().len(), 1);
+    h.sim.packet_out(ECM_OUT, &[]).unwrap();
+
+    // Frames larger than the receive buffer are dropped
+    h.sim
+        .write(ECM_OUT, &std::vec![0xff; MAX_FRAME_LEN + 64])
+        .unwrap();
+    h.sim.write(ECM_OUT, &frame(20)).unwrap();
+
+    assert_eq!(
+        *h.ethernet.received.borrow(),
+        [frame(100), frame(128), frame(20)]
+    );
+}
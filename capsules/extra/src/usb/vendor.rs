@@ -0,0 +1,265 @@
+//! Vendor-specific USB function with a pair of bulk endpoints.
+//!
+//! This function exposes a single interface of the vendor-specific class,
+//! which the host accesses through a generic driver such as libusb. Data is
+//! exchanged in packets of 64 bytes, with the same interface as the CTAP HID
+//! (`hil::usb_hid::UsbHid`), so that the same upper layers can be used over
+//! either.
+//!
+//! The function is meant to be part of a `CompositeDevice`, which owns the
+//! control endpoint.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let vendor = static_init!(
+//!     VendorBulk<'static, nrf52840::usbd::Usbd<'static>>,
+//!     VendorBulk::new(&nrf52840_peripherals.usbd, 5, 6)
+//! );
+//! composite.add_function(vendor).unwrap();
+//! ```
+
+use core::cell::Cell;
+
+use super::composite::{DescriptorWriter, FunctionClass, UsbFunction};
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::TransferDirection;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::OptionalCell;
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
+
+/// Packets are always 64 bytes, the maximum for full-speed bulk endpoints.
+const PACKET_SIZE: usize = 64;
+
+pub struct VendorBulk<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// Endpoint on which we send data to the host.
+    endpoint_in: usize,
+    /// Endpoint on which we receive data from the host.
+    endpoint_out: usize,
+
+    in_buffer: Buffer64,
+    out_buffer: Buffer64,
+
+    client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; PACKET_SIZE]>>,
+
+    /// The packet being sent, if any.
+    send_buffer: TakeCell<'static, [u8; PACKET_SIZE]>,
+
+    /// The buffer to receive the next packet into, if any.
+    recv_buffer: TakeCell<'static, [u8; PACKET_SIZE]>,
+
+    /// Whether a packet from the host is waiting in `out_buffer` for a
+    /// receive buffer.
+    recv_pending: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> VendorBulk<'a, U> {
+    /// Creates a function using `endpoint_in` and `endpoint_out`, which may
+    /// be the same endpoint.
+    pub fn new(controller: &'a U, endpoint_in: usize, endpoint_out: usize) -> Self {
+        VendorBulk {
+            controller: controller,
+            endpoint_in: endpoint_in,
+            endpoint_out: endpoint_out,
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            client: OptionalCell::empty(),
+            send_buffer: TakeCell::empty(),
+            recv_buffer: TakeCell::empty(),
+            recv_pending: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; PACKET_SIZE]>) {
+        self.client.set(client);
+    }
+
+    fn can_receive(&self) -> bool {
+        self.client.map_or(false, |client| client.can_receive())
+    }
+
+    /// Passes the packet in `out_buffer` to the client, if it can take it.
+    /// Returns whether it did.
+    fn deliver_packet(&self) -> bool {
+        if !self.can_receive() {
+            return false;
+        }
+        match self.recv_buffer.take() {
+            Some(buf) => {
+                for (b, packet) in buf.iter_mut().zip(self.out_buffer.buf.iter()) {
+                    *b = packet.get();
+                }
+                self.recv_pending.set(false);
+                self.client.map(move |client| {
+                    client.packet_received(Ok(()), buf, self.endpoint_out);
+                });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; PACKET_SIZE]>
+    for VendorBulk<'a, U>
+{
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; PACKET_SIZE],
+    ) -> Result<usize, (ErrorCode, &'static mut [u8; PACKET_SIZE])> {
+        if self.send_buffer.is_some() {
+            return Err((ErrorCode::BUSY, send));
+        }
+        let len = send.len();
+
+        self.send_buffer.replace(send);
+        self.controller.endpoint_resume_in(self.endpoint_in);
+
+        Ok(len)
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; PACKET_SIZE], ErrorCode> {
+        self.send_buffer.take().ok_or(ErrorCode::BUSY)
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; PACKET_SIZE],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; PACKET_SIZE])> {
+        if self.recv_buffer.is_some() {
+            return Err((ErrorCode::BUSY, recv));
+        }
+        self.recv_buffer.replace(recv);
+
+        if self.recv_pending.get() {
+            // A packet was held back, so pass it now. The endpoint resumes
+            // once the client provides the next buffer.
+            self.deliver_packet();
+        } else {
+            self.controller.endpoint_resume_out(self.endpoint_out);
+        }
+
+        Ok(())
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; PACKET_SIZE], ErrorCode> {
+        self.recv_buffer.take().ok_or(ErrorCode::BUSY)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for VendorBulk<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn function_class(&self) -> FunctionClass {
+        FunctionClass {
+            class: 0xff, // Vendor specific
+            subclass: 0x00,
+            protocol: 0x00,
+        }
+    }
+
+    fn endpoint_mask(&self) -> u16 {
+        (1 << self.endpoint_in) | (1 << self.endpoint_out)
+    }
+
+    fn write_descriptors(&self, first_interface: u8, writer: &mut DescriptorWriter) {
+        writer.write(&InterfaceDescriptor {
+            interface_number: first_interface,
+            interface_class: 0xff,
+            num_endpoints: 2,
+            ..InterfaceDescriptor::default()
+        });
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_in,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: PACKET_SIZE as u16,
+            interval: 0,
+        });
+        writer.write(&EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                self.endpoint_out,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: PACKET_SIZE as u16,
+            interval: 0,
+        });
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_in, &self.in_buffer.buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint_out, &self.out_buffer.buf);
+        if self.endpoint_in == self.endpoint_out {
+            self.controller
+                .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_in);
+        } else {
+            self.controller
+                .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
+            self.controller
+                .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
+        }
+    }
+
+    fn bus_reset(&'a self) {
+        self.recv_pending.set(false);
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => self.send_buffer.map_or(hil::usb::InResult::Delay, |buf| {
+                for (packet, b) in self.in_buffer.buf.iter().zip(buf.iter()) {
+                    packet.set(*b);
+                }
+                hil::usb::InResult::Packet(PACKET_SIZE)
+            }),
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                // The packet stays in `out_buffer`, and further packets are
+                // delayed, until the client takes it.
+                self.recv_pending.set(true);
+                self.deliver_packet();
+                hil::usb::OutResult::Delay
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                hil::usb::OutResult::Error
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.send_buffer.take().map(|buf| {
+            self.client.map(move |client| {
+                client.packet_transmitted(Ok(()), buf, endpoint);
+            });
+        });
+    }
+}
@@ -468,6 +468,10 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
         unimplemented!();
     }
 
+    fn rssi(&self) -> Option<i8> {
+        None
+    }
+
     fn set_receive_client(&self, client: &'a dyn ble_advertising::RxClient) {
         self.rx_client.set(client);
     }
@@ -536,6 +536,9 @@ pub struct Radio<'a> {
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    rssi: Cell<Option<i8>>,
 }
 
 impl<'a> Radio<'a> {
@@ -546,6 +549,9 @@ impl<'a> Radio<'a> {
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            access_address: Cell::new(ble_advertising::ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(nrf5x::constants::RADIO_CRCINIT_BLE),
+            rssi: Cell::new(None),
         }
     }
 
@@ -554,11 +560,16 @@ impl<'a> Radio<'a> {
     }
 
     fn tx(&self) {
+        self.registers.shorts.set(0);
         self.registers.event_ready.write(Event::READY::CLEAR);
         self.registers.task_txen.write(Task::ENABLE::SET);
     }
 
     fn rx(&self) {
+        // Sample the signal strength once the access address is received
+        self.registers
+            .shorts
+            .write(Shortcut::ADDRESS_RSSISTART::SET);
         self.registers.event_ready.write(Event::READY::CLEAR);
         self.registers.task_rxen.write(Task::ENABLE::SET);
     }
@@ -635,6 +646,9 @@ impl<'a> Radio<'a> {
                 | nrf5x::constants::RADIO_STATE_RXIDLE
                 | nrf5x::constants::RADIO_STATE_RXDISABLE
                 | nrf5x::constants::RADIO_STATE_RX => {
+                    // The sample is the magnitude of the negative RSSI
+                    let sample = self.registers.rssisample.read(RssiSample::RSSISAMPLE);
+                    self.rssi.set(Some(-(sample as i8)));
                     self.radio_off();
                     unsafe {
                         self.rx_client.map(|client| {
@@ -698,7 +712,7 @@ impl<'a> Radio<'a> {
         self.set_rx_address();
 
         self.ble_set_packet_config();
-        self.ble_set_advertising_access_address();
+        self.ble_set_access_address();
 
         self.ble_set_crc_config();
 
@@ -710,19 +724,19 @@ impl<'a> Radio<'a> {
         self.registers
             .crccnf
             .write(CrcConfiguration::LEN::THREE + CrcConfiguration::SKIPADDR::EXCLUDE);
-        self.registers
-            .crcinit
-            .set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        self.registers.crcinit.set(self.crc_init.get());
         self.registers
             .crcpoly
             .set(nrf5x::constants::RADIO_CRCPOLY_BLE);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
-    // Set access address to 0x8E89BED6
-    fn ble_set_advertising_access_address(&self) {
-        self.registers.prefix0.set(0x0000008e);
-        self.registers.base0.set(0x89bed600);
+    // The access address is 0x8E89BED6 on the advertising channels, and that
+    // of the connection on the data channels
+    fn ble_set_access_address(&self) {
+        let access_address = self.access_address.get();
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
     }
 
     // Packet configuration
@@ -802,6 +816,10 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
         self.enable_interrupts();
     }
 
+    fn rssi(&self) -> Option<i8> {
+        self.rssi.get()
+    }
+
     fn set_receive_client(&self, client: &'a dyn ble_advertising::RxClient) {
         self.rx_client.set(client);
     }
@@ -811,6 +829,18 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl ble_advertising::BleConnectionConfig for Radio<'_> {
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn stop_receive(&self) {
+        self.disable_all_interrupts();
+        self.radio_off();
+    }
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
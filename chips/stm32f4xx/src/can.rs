@@ -618,8 +618,27 @@ impl<'a> Can<'a> {
             }
         }
 
-        self.registers.can_firx[(filter_info.number as usize) * 2].modify(CAN_FiRx::FB.val(0));
-        self.registers.can_firx[(filter_info.number as usize) * 2 + 1].modify(CAN_FiRx::FB.val(0));
+        // In 32 bit mode the first register holds the identifier and the
+        // second the mask, or a second identifier in list mode. The IDE bit
+        // makes the filter match only frames of the same kind.
+        let identifier = match filter_info.id {
+            can::Id::Standard(id) => ((id as u32) & 0x7ff) << 21,
+            can::Id::Extended(id) => (id & 0x1fff_ffff) << 3 | 0b100,
+        };
+        let (first, second) = match (filter_info.scale_bits, filter_info.identifier_mode) {
+            (can::ScaleBits::Bits32, can::IdentifierMode::List) => (identifier, identifier),
+            (can::ScaleBits::Bits32, can::IdentifierMode::Mask) if filter_info.mask != 0 => {
+                let mask = match filter_info.id {
+                    can::Id::Standard(_) => (filter_info.mask & 0x7ff) << 21 | 0b100,
+                    can::Id::Extended(_) => (filter_info.mask & 0x1fff_ffff) << 3 | 0b100,
+                };
+                (identifier & mask, mask)
+            }
+            _ => (0, 0),
+        };
+        self.registers.can_firx[(filter_info.number as usize) * 2].modify(CAN_FiRx::FB.val(first));
+        self.registers.can_firx[(filter_info.number as usize) * 2 + 1]
+            .modify(CAN_FiRx::FB.val(second));
 
         // request filter mode to be mask or list
         match filter_info.identifier_mode {
@@ -1360,6 +1379,8 @@ impl<'a> can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }> for Can<'_> {
                         scale_bits: can::ScaleBits::Bits32,
                         identifier_mode: can::IdentifierMode::Mask,
                         fifo_number: 0,
+                        id: can::Id::Standard(0),
+                        mask: 0,
                     },
                     true,
                 );
@@ -1369,6 +1390,8 @@ impl<'a> can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }> for Can<'_> {
                         scale_bits: can::ScaleBits::Bits32,
                         identifier_mode: can::IdentifierMode::Mask,
                         fifo_number: 1,
+                        id: can::Id::Standard(0),
+                        mask: 0,
                     },
                     true,
                 );
@@ -1392,6 +1415,8 @@ impl<'a> can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }> for Can<'_> {
                         scale_bits: can::ScaleBits::Bits32,
                         identifier_mode: can::IdentifierMode::Mask,
                         fifo_number: 0,
+                        id: can::Id::Standard(0),
+                        mask: 0,
                     },
                     false,
                 );
@@ -1401,6 +1426,8 @@ impl<'a> can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }> for Can<'_> {
                         scale_bits: can::ScaleBits::Bits32,
                         identifier_mode: can::IdentifierMode::Mask,
                         fifo_number: 1,
+                        id: can::Id::Standard(0),
+                        mask: 0,
                     },
                     false,
                 );
@@ -1,11 +1,13 @@
 use core::cell::Cell;
 
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::registers::{register_bitfields, LocalRegisterCopy};
 use kernel::ErrorCode;
 
 use super::super::devices::{VirtIODeviceDriver, VirtIODeviceType};
 use super::super::queues::split_queue::{SplitVirtqueue, SplitVirtqueueClient, VirtqueueBuffer};
+use super::super::transports::VirtIOTransport;
 
 register_bitfields![u64,
     VirtIONetFeatures [
@@ -42,9 +44,10 @@ pub struct VirtIONet<'a> {
     rxqueue: &'a SplitVirtqueue<'static, 'static, 2>,
     txqueue: &'a SplitVirtqueue<'static, 'static, 2>,
     tx_header: OptionalCell<&'static mut [u8; 12]>,
+    tx_len: Cell<usize>,
     rx_header: OptionalCell<&'static mut [u8]>,
     rx_buffer: OptionalCell<&'static mut [u8]>,
-    client: OptionalCell<&'a dyn VirtIONetClient>,
+    client: OptionalCell<&'a dyn EthernetAdapterClient>,
 }
 
 impl<'a> VirtIONet<'a> {
@@ -64,6 +67,7 @@ impl<'a> VirtIONet<'a> {
             rxqueue,
             txqueue,
             tx_header: OptionalCell::new(tx_header),
+            tx_len: Cell::new(0),
             client: OptionalCell::empty(),
             rx_header: OptionalCell::new(rx_header),
             rx_buffer: OptionalCell::new(rx_buffer),
@@ -74,15 +78,23 @@ impl<'a> VirtIONet<'a> {
         self.id.get()
     }
 
-    pub fn set_client(&self, client: &'a dyn VirtIONetClient) {
-        self.client.set(client);
+    /// Read the MAC address of the network device.
+    ///
+    /// This driver requires `VIRTIO_NET_F_MAC`, so the device always exposes
+    /// its MAC address as the first field of its configuration space (5.1.4).
+    /// `transport` must be the transport this driver has been initialized
+    /// with.
+    pub fn mac_address(&self, transport: &dyn VirtIOTransport) -> Result<[u8; 6], ErrorCode> {
+        let mut mac = [0; 6];
+        transport.read_device_config(0, &mut mac)?;
+        Ok(mac)
     }
 
     // This is not executed as part of the `device_initialized` hook to avoid
     // missing any packets if a client has not been registered, and because this
     // device can be used in a transmit-only fashion before invoking this
     // function.
-    pub fn enable_rx(&self) {
+    fn enable_rx(&self) {
         // To start operation, put the receive buffers into the device initially
         let rx_buffer = self.rx_buffer.take().unwrap();
         let rx_buffer_len = rx_buffer.len();
@@ -105,16 +117,7 @@ impl<'a> VirtIONet<'a> {
             .unwrap();
     }
 
-    pub fn return_rx_buffer(&self, buf: &'static mut [u8]) {
-        assert!(self.rx_buffer.is_none());
-        assert!(self.rx_header.is_some());
-        self.rx_buffer.replace(buf);
-
-        // Re-register the RX buffer with the Virtqueue:
-        self.enable_rx();
-    }
-
-    pub fn send_packet(
+    fn send_packet(
         &self,
         packet: &'static mut [u8],
         packet_len: usize,
@@ -164,6 +167,7 @@ impl<'a> VirtIONet<'a> {
         self.txqueue
             .provide_buffer_chain(&mut buffer_chain)
             .map_err(move |ret| (buffer_chain[1].take().unwrap().buf, ret))?;
+        self.tx_len.set(packet_len);
 
         Ok(())
     }
@@ -184,9 +188,14 @@ impl<'a> SplitVirtqueueClient<'static> for VirtIONet<'a> {
             self.rx_header.replace(rx_header);
 
             let rx_buffer = buffer_chain[1].take().expect("No rx content buffer").buf;
-            self.client.map(move |client| {
-                client.packet_received(self.id.get(), rx_buffer, bytes_used - 12)
-            });
+            let rx_len = core::cmp::min(bytes_used.saturating_sub(12), rx_buffer.len());
+            self.client
+                .map(|client| client.rx_frame(&rx_buffer[..rx_len]));
+
+            // The frame has been consumed by the client, hand the buffer back
+            // to the device for the next reception.
+            self.rx_buffer.replace(rx_buffer);
+            self.enable_rx();
         } else if queue_number == self.txqueue.queue_number().unwrap() {
             // Sent a packet
 
@@ -195,7 +204,7 @@ impl<'a> SplitVirtqueueClient<'static> for VirtIONet<'a> {
 
             let packet_buf = buffer_chain[1].take().expect("No packet buffer").buf;
             self.client
-                .map(move |client| client.packet_sent(self.id.get(), packet_buf));
+                .map(move |client| client.tx_done(Ok(()), packet_buf, self.tx_len.get()));
         } else {
             panic!("Callback from unknown queue");
         }
@@ -237,7 +246,24 @@ impl<'a> VirtIODeviceDriver for VirtIONet<'a> {
     }
 }
 
-pub trait VirtIONetClient {
-    fn packet_sent(&self, id: usize, buffer: &'static mut [u8]);
-    fn packet_received(&self, id: usize, buffer: &'static mut [u8], len: usize);
+impl<'a> EthernetAdapter<'a> for VirtIONet<'a> {
+    fn set_client(&self, client: &'a dyn EthernetAdapterClient) {
+        self.client.set(client);
+    }
+
+    fn enable_receive(&self) {
+        self.enable_rx();
+    }
+
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if len > frame.len() {
+            return Err((ErrorCode::SIZE, frame));
+        }
+        self.send_packet(frame, len)
+            .map_err(|(buf, ecode)| (ecode, buf))
+    }
 }
@@ -6,6 +6,7 @@ use kernel::utilities::registers::{
     register_bitfields, InMemoryRegister, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::utilities::StaticRef;
+use kernel::ErrorCode;
 
 use super::super::devices::{VirtIODeviceDriver, VirtIODeviceType};
 use super::super::queues::Virtqueue;
@@ -82,9 +83,9 @@ pub struct VirtIOMMIODeviceRegisters {
     /// 0x100 - 0x19C device configuration space
     ///
     /// This is individually defined per device, with a variable
-    /// size. TODO: How to address this properly? Just hand around
-    /// addresses to this?
-    config: [u32; 40],
+    /// size. Drivers access it through
+    /// [`VirtIOTransport::read_device_config`].
+    config: [ReadWrite<u32>; 40],
 }
 
 register_bitfields![u32,
@@ -391,6 +392,30 @@ impl VirtIOTransport for VirtIOMMIODevice {
         Ok(device_type)
     }
 
+    fn read_device_config(&self, offset: usize, buf: &mut [u8]) -> Result<(), ErrorCode> {
+        let end = offset.checked_add(buf.len()).ok_or(ErrorCode::INVAL)?;
+        if end > self.regs.config.len() * 4 {
+            return Err(ErrorCode::SIZE);
+        }
+
+        // The device may change its configuration while we are reading it, in
+        // which case the configuration generation changes and the read must be
+        // retried (4.2.2.1).
+        loop {
+            let generation = self.regs.config_generation.get();
+
+            for (i, byte) in buf.iter_mut().enumerate() {
+                let pos = offset + i;
+                let word = self.regs.config[pos / 4].get().to_le_bytes();
+                *byte = word[pos % 4];
+            }
+
+            if self.regs.config_generation.get() == generation {
+                return Ok(());
+            }
+        }
+    }
+
     fn queue_notify(&self, queue_id: u32) {
         // TODO: better way to report an error here? This shouldn't usually be
         // triggered.
@@ -85,6 +85,15 @@ pub trait VirtIOTransport {
         queues: &'static [&'static dyn Virtqueue],
     ) -> Result<VirtIODeviceType, VirtIOInitializationError>;
 
+    /// Read from the device-specific configuration space.
+    ///
+    /// Copies `buf.len()` bytes starting at `offset` into the device
+    /// configuration space into `buf`. The transport ensures that the bytes
+    /// are read consistently, i.e. without the device changing its
+    /// configuration in between. Returns `SIZE` if the requested range is not
+    /// part of the configuration space.
+    fn read_device_config(&self, offset: usize, buf: &mut [u8]) -> Result<(), ErrorCode>;
+
     /// Notify the device of a changed [`Virtqueue`].
     ///
     /// Whenever a queue has been updated (e.g. move descriptors from the used
@@ -52,6 +52,9 @@ use crate::ErrorCode;
 pub trait BleAdvertisementDriver<'a> {
     fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel);
     fn receive_advertisement(&self, channel: RadioChannel);
+    /// Signal strength of the last packet received, in dBm, if the radio
+    /// measures it.
+    fn rssi(&self) -> Option<i8>;
     fn set_receive_client(&self, client: &'a dyn RxClient);
     fn set_transmit_client(&self, client: &'a dyn TxClient);
 }
@@ -60,6 +63,27 @@ pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> Result<(), ErrorCode>;
 }
 
+/// Access address of the advertising channels.
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8E89BED6;
+
+/// Initial value of the CRC on the advertising channels.
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+
+/// Configuration of a radio that also takes part in connections, whose data
+/// channel PDUs are sent and received with `transmit_advertisement` and
+/// `receive_advertisement` on the data channels.
+pub trait BleConnectionConfig {
+    /// Sets the access address and the initial CRC value of the following
+    /// transmissions and receptions. They are those of the connection on the
+    /// data channels, and `ADVERTISING_ACCESS_ADDRESS` and
+    /// `ADVERTISING_CRC_INIT` on the advertising channels.
+    fn set_access_address(&self, access_address: u32, crc_init: u32);
+
+    /// Stops a reception that did not complete, without calling the
+    /// receive client.
+    fn stop_receive(&self);
+}
+
 pub trait RxClient {
     fn receive_event(&self, buf: &'static mut [u8], len: u8, result: Result<(), ErrorCode>);
 }
@@ -113,7 +137,53 @@ pub enum RadioChannel {
     AdvertisingChannel39 = 80,
 }
 
+/// The data channels, in the order of their index.
+const DATA_CHANNELS: [RadioChannel; 37] = [
+    RadioChannel::DataChannel0,
+    RadioChannel::DataChannel1,
+    RadioChannel::DataChannel2,
+    RadioChannel::DataChannel3,
+    RadioChannel::DataChannel4,
+    RadioChannel::DataChannel5,
+    RadioChannel::DataChannel6,
+    RadioChannel::DataChannel7,
+    RadioChannel::DataChannel8,
+    RadioChannel::DataChannel9,
+    RadioChannel::DataChannel10,
+    RadioChannel::DataChannel11,
+    RadioChannel::DataChannel12,
+    RadioChannel::DataChannel13,
+    RadioChannel::DataChannel14,
+    RadioChannel::DataChannel15,
+    RadioChannel::DataChannel16,
+    RadioChannel::DataChannel17,
+    RadioChannel::DataChannel18,
+    RadioChannel::DataChannel19,
+    RadioChannel::DataChannel20,
+    RadioChannel::DataChannel21,
+    RadioChannel::DataChannel22,
+    RadioChannel::DataChannel23,
+    RadioChannel::DataChannel24,
+    RadioChannel::DataChannel25,
+    RadioChannel::DataChannel26,
+    RadioChannel::DataChannel27,
+    RadioChannel::DataChannel28,
+    RadioChannel::DataChannel29,
+    RadioChannel::DataChannel30,
+    RadioChannel::DataChannel31,
+    RadioChannel::DataChannel32,
+    RadioChannel::DataChannel33,
+    RadioChannel::DataChannel34,
+    RadioChannel::DataChannel35,
+    RadioChannel::DataChannel36,
+];
+
 impl RadioChannel {
+    /// The data channel with the given index, from 0 to 36.
+    pub fn data_channel(index: u8) -> Option<RadioChannel> {
+        DATA_CHANNELS.get(index as usize).copied()
+    }
+
     pub fn get_channel_index(&self) -> u32 {
         match *self {
             RadioChannel::DataChannel0 => 0,
@@ -145,7 +145,7 @@ pub enum IdentifierMode {
 }
 
 /// The identifier can be standard (11 bits) or extended (29 bits)
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Id {
     Standard(u16),
     Extended(u32),
@@ -168,6 +168,33 @@ pub struct FilterParameters {
 
     /// The receive FIFO Id that the filter will be applied to
     pub fifo_number: usize,
+
+    /// The identifier the messages are compared with
+    pub id: Id,
+
+    /// The bits of the identifier that must match `id` in `Mask` mode.
+    /// A mask of 0 accepts every message, standard or extended.
+    pub mask: u32,
+}
+
+impl FilterParameters {
+    /// Returns whether a message with the identifier `id` passes the
+    /// filter. In `List` mode the identifiers must be equal, and in `Mask`
+    /// mode the bits selected by the mask must match, for an identifier of
+    /// the same kind.
+    pub fn accepts(&self, id: Id) -> bool {
+        match (self.identifier_mode, self.id, id) {
+            (IdentifierMode::List, _, _) => self.id == id,
+            (IdentifierMode::Mask, _, _) if self.mask == 0 => true,
+            (IdentifierMode::Mask, Id::Standard(filter), Id::Standard(id)) => {
+                (filter as u32 ^ id as u32) & self.mask == 0
+            }
+            (IdentifierMode::Mask, Id::Extended(filter), Id::Extended(id)) => {
+                (filter ^ id) & self.mask == 0
+            }
+            (IdentifierMode::Mask, _, _) => false,
+        }
+    }
 }
 
 /// This structure defines the parameters for the timing mode
@@ -0,0 +1,41 @@
+//! Interface for Ethernet network adapters.
+//!
+//! An `EthernetAdapter` transmits and receives raw Ethernet II frames, that is
+//! frames starting with the destination and source MAC addresses, followed by
+//! the EtherType and the frame payload. The frame check sequence is not part
+//! of the frames passed through this interface and is expected to be handled
+//! by the adapter.
+
+use crate::ErrorCode;
+
+/// Callbacks from an Ethernet adapter.
+pub trait EthernetAdapterClient {
+    /// Called when a frame passed to `EthernetAdapter::transmit` has been sent
+    /// (or the transmission failed), returning ownership of the frame buffer.
+    fn tx_done(&self, result: Result<(), ErrorCode>, frame: &'static mut [u8], len: usize);
+
+    /// Called when a frame has been received. `frame` only contains the
+    /// received frame bytes. The buffer is owned by the adapter and is reused
+    /// for the next reception once this callback returns.
+    fn rx_frame(&self, frame: &[u8]);
+}
+
+/// A network adapter sending and receiving raw Ethernet II frames.
+pub trait EthernetAdapter<'a> {
+    /// Set the client receiving transmit and receive callbacks.
+    fn set_client(&self, client: &'a dyn EthernetAdapterClient);
+
+    /// Start receiving frames. Frames arriving before this is called may be
+    /// dropped by the adapter.
+    fn enable_receive(&self);
+
+    /// Transmit the first `len` bytes of `frame`.
+    ///
+    /// Returns `BUSY` if a transmission is already in progress and `SIZE` if
+    /// `len` exceeds the frame buffer. On error, the buffer is returned.
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
@@ -61,6 +61,24 @@ pub trait KeyType: Eq + Copy + Clone + Sized + AsRef<[u8]> + AsMut<[u8]> {}
 
 impl KeyType for [u8; 8] {}
 
+/// The space used by a KV system, found by `KVSystem::statistics()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Bytes that new keys can be stored in
+    pub free: usize,
+    /// Bytes holding valid keys, and data the KV system keeps itself
+    pub used: usize,
+    /// Bytes holding keys that have been invalidated. Garbage collection
+    /// frees some or all of them.
+    pub invalid: usize,
+    /// The number of regions that can be garbage collected separately
+    pub regions: usize,
+    /// The fewest times a region has been erased
+    pub min_erase_count: u32,
+    /// The most times a region has been erased
+    pub max_erase_count: u32,
+}
+
 /// Implement this trait and use `set_client()` in order to receive callbacks.
 pub trait StoreClient<K: KeyType> {
     /// This callback is called when the get operation completes
@@ -92,6 +110,27 @@ pub trait StoreClient<K: KeyType> {
     /// `result`: Nothing on success, 'ErrorCode' on error
     /// `key`: The key buffer
     fn delete_complete(&self, result: Result<(), ErrorCode>, key: &'static mut [u8]);
+
+    /// This callback is called when the enumerate operation completes
+    ///
+    /// `result`: The cursor to continue from on success, 'ErrorCode' on error
+    /// `key`: The key buffer, containing the hashed key found on success
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut [u8]);
+
+    /// This callback is called when the start_transaction operation completes
+    ///
+    /// `result`: Nothing on success, 'ErrorCode' on error
+    fn start_transaction_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the commit_transaction operation completes
+    ///
+    /// `result`: Nothing on success, 'ErrorCode' on error
+    fn commit_transaction_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the abort_transaction operation completes
+    ///
+    /// `result`: Nothing on success, 'ErrorCode' on error
+    fn abort_transaction_complete(&self, result: Result<(), ErrorCode>);
 }
 
 /// Implement this trait and use `set_client()` in order to receive callbacks.
@@ -142,6 +181,32 @@ pub trait Client<K: KeyType> {
     ///
     /// `result`: Nothing on success, 'ErrorCode' on error
     fn garbage_collect_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the enumerate operation completes
+    ///
+    /// `result`: The cursor to continue from on success, 'ErrorCode' on error
+    /// `key`: The key buffer, containing the key found on success
+    fn enumerate_complete(&self, result: Result<usize, ErrorCode>, key: &'static mut K);
+
+    /// This callback is called when the statistics operation completes
+    ///
+    /// `result`: The statistics on success, 'ErrorCode' on error
+    fn statistics_complete(&self, result: Result<Statistics, ErrorCode>);
+
+    /// This callback is called when the start_transaction operation completes
+    ///
+    /// `result`: Nothing on success, 'ErrorCode' on error
+    fn start_transaction_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the commit_transaction operation completes
+    ///
+    /// `result`: Nothing on success, 'ErrorCode' on error
+    fn commit_transaction_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the abort_transaction operation completes
+    ///
+    /// `result`: Nothing on success, 'ErrorCode' on error
+    fn abort_transaction_complete(&self, result: Result<(), ErrorCode>);
 }
 
 pub trait KVSystem<'a> {
@@ -255,4 +320,108 @@ pub trait KVSystem<'a> {
     ///    `INVAL`: An invalid parameter was passed
     ///    `NODEVICE`: No KV store was setup
     fn garbage_collect(&self) -> Result<usize, Result<(), ErrorCode>>;
+
+    /// Perform a garbage collection of a single region of the KV Store
+    ///
+    /// `region`: The region to collect, less than the number of `regions`
+    ///           in the `Statistics`.
+    ///
+    /// Collecting one region at a time lets other operations run in
+    /// between. `garbage_collect_complete()` is called once the region has
+    /// been collected.
+    ///
+    /// On success nothing will be returned.
+    /// On error an `ErrorCode` will be returned.
+    ///
+    /// The possible `ErrorCode`s are:
+    ///    `BUSY`: An operation is already in progress
+    ///    `INVAL`: There is no such region
+    ///    `NODEVICE`: No KV store was setup
+    fn garbage_collect_region(&self, region: usize) -> Result<(), ErrorCode>;
+
+    /// Find how much space is free, used and invalid, and how many times
+    /// the regions have been erased
+    ///
+    /// `statistics_complete()` is called with the statistics.
+    ///
+    /// On success nothing will be returned.
+    /// On error an `ErrorCode` will be returned.
+    ///
+    /// The possible `ErrorCode`s are:
+    ///    `BUSY`: An operation is already in progress
+    ///    `NODEVICE`: No KV store was setup
+    fn statistics(&self) -> Result<(), ErrorCode>;
+
+    /// Finds the first key stored at or after `cursor`
+    ///
+    /// `cursor`: Where to start looking. Use 0 to find the first key, and
+    ///           the cursor from `enumerate_complete()` to find the key
+    ///           after that one.
+    /// `key`: A buffer to store the hashed key found to.
+    ///
+    /// Keys are found in the order they are stored, which doesn't have to be
+    /// the order they were added in. Calling `enumerate()` until no key is
+    /// found lists all of the keys. Only hashed keys are stored, so users
+    /// that enumerate keys by a prefix of the unhashed key, like the KV
+    /// store capsule, store the prefix in the value and check it.
+    ///
+    /// On success nothing will be returned.
+    /// On error the key and a `Result<(), ErrorCode>` will be returned.
+    ///
+    /// The possible `Result<(), ErrorCode>`s are:
+    ///    `BUSY`: An operation is already in progress
+    ///    `INVAL`: An invalid parameter was passed
+    ///    `NODEVICE`: No KV store was setup
+    ///
+    /// `enumerate_complete()` reports `ENOSUPPORT` when there are no more
+    /// keys.
+    fn enumerate(
+        &self,
+        cursor: usize,
+        key: &'static mut Self::K,
+    ) -> Result<(), (&'static mut Self::K, Result<(), ErrorCode>)>;
+
+    /// Starts a transaction
+    ///
+    /// Until the transaction is committed or aborted, keys appended with
+    /// `append_key()` and invalidated with `invalidate_key()` are only
+    /// staged. Committing the transaction applies all of them at once, even
+    /// if power is lost while committing, and aborting it applies none of
+    /// them. A key can be invalidated and then appended again in the same
+    /// transaction, but a key appended by the transaction can't be
+    /// invalidated by it.
+    ///
+    /// On success nothing will be returned.
+    /// On error an `ErrorCode` will be returned.
+    ///
+    /// The possible `ErrorCode`s are:
+    ///    `BUSY`: An operation is already in progress
+    ///    `ALREADY`: A transaction has already been started
+    ///    `NODEVICE`: No KV store was setup
+    ///    `NOSUPPORT`: Transactions aren't supported
+    fn start_transaction(&self) -> Result<(), ErrorCode>;
+
+    /// Commits the transaction, applying every key appended and invalidated
+    /// since `start_transaction()`
+    ///
+    /// On success nothing will be returned.
+    /// On error an `ErrorCode` will be returned.
+    ///
+    /// The possible `ErrorCode`s are:
+    ///    `BUSY`: An operation is already in progress
+    ///    `INVAL`: No transaction has been started
+    ///    `NODEVICE`: No KV store was setup
+    fn commit_transaction(&self) -> Result<(), ErrorCode>;
+
+    /// Aborts the transaction, discarding every key appended and invalidated
+    /// since `start_transaction()`
+    ///
+    /// On success nothing will be returned.
+    /// On error an `ErrorCode` will be returned.
+    ///
+    /// The possible `ErrorCode`s are:
+    ///    `BUSY`: An operation is already in progress
+    ///    `INVAL`: No transaction has been started
+    ///    `NODEVICE`: No KV store was setup
+    fn abort_transaction(&self) -> Result<(), ErrorCode>;
 }
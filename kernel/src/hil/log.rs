@@ -65,6 +65,10 @@ pub trait LogWrite<'a> {
     /// previously overwritten). There is no guarantee that any changes to the log are persistent
     /// until it is synced. In the event of an error, not all pages may be synced, but the log will
     /// remain in a valid state.
+    ///
+    /// Implementations must call `sync_done` exactly once for every call that returns `Ok(())`,
+    /// including when there was nothing to sync, as clients wait for it before their next
+    /// operation. While another operation is in progress `BUSY` is returned instead.
     fn sync(&self) -> Result<(), ErrorCode>;
 
     /// Erase the entire log. In the event of a failure, only some pages may be erased, but the log
@@ -115,7 +115,13 @@ pub trait CCMClient {
     fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool);
 }
 
+/// The length of the nonce used by CCM* (IEEE 802.15.4), which is also the
+/// longest nonce CCM supports.
 pub const CCM_NONCE_LENGTH: usize = 13;
+/// The shortest nonce CCM supports. Shorter nonces allow longer messages,
+/// since the message length is encoded in `15 - nonce.len()` bytes
+/// (RFC 3610).
+pub const CCM_MIN_NONCE_LENGTH: usize = 7;
 
 pub trait AES128CCM<'a> {
     /// Set the client instance which will receive `crypt_done()` callbacks
@@ -124,7 +130,11 @@ pub trait AES128CCM<'a> {
     /// Set the key to be used for CCM encryption
     fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode>;
 
-    /// Set the nonce (length NONCE_LENGTH) to be used for CCM encryption
+    /// Set the nonce to be used for CCM encryption. The nonce is between
+    /// `CCM_MIN_NONCE_LENGTH` and `CCM_NONCE_LENGTH` bytes long; CCM*
+    /// (IEEE 802.15.4) uses `CCM_NONCE_LENGTH` byte nonces, and TLS/DTLS use
+    /// 12 byte nonces. Implementations may only support `CCM_NONCE_LENGTH`
+    /// byte nonces, and return `INVAL` for other lengths.
     fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode>;
 
     /// Try to begin the encryption/decryption process
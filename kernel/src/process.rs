@@ -194,6 +194,23 @@ impl ProcessId {
         self.kernel
             .process_map_or(None, *self, |process| process.get_storage_permissions())
     }
+
+    /// Get the ShortID of the application binary the process runs, which
+    /// identifies the application across reboots if it is `Fixed`.
+    pub fn short_app_id(&self) -> ShortID {
+        self.kernel
+            .process_map_or(ShortID::LocallyUnique, *self, |process| {
+                process.short_app_id()
+            })
+    }
+
+    /// Get the number of bytes of nonvolatile storage the process asks for.
+    /// Returns `None` if the process does not ask for any.
+    pub fn get_nonvolatile_storage_size(&self) -> Option<usize> {
+        self.kernel.process_map_or(None, *self, |process| {
+            process.get_nonvolatile_storage_size()
+        })
+    }
 }
 
 /// A compressed form of an Application Identifer.
@@ -503,6 +520,12 @@ pub trait Process {
     /// Returns `None` if the process has no storage permissions.
     fn get_storage_permissions(&self) -> Option<storage_permissions::StoragePermissions>;
 
+    /// Get the number of bytes of nonvolatile storage the process asks for in
+    /// its TBF header.
+    ///
+    /// Returns `None` if the process does not ask for any.
+    fn get_nonvolatile_storage_size(&self) -> Option<usize>;
+
     // mpu
 
     /// Configure the MPU to use the process's allocated regions.
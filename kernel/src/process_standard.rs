@@ -532,6 +532,12 @@ impl<C: Chip> Process for ProcessStandard<'_, C> {
         ))
     }
 
+    fn get_nonvolatile_storage_size(&self) -> Option<usize> {
+        self.header
+            .get_nonvolatile_storage_size()
+            .map(|size| size as usize)
+    }
+
     fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -126,7 +126,7 @@
 use crate::error_codes::ErrorCode;
 use crate::flash_controller::FlashController;
 use crate::success_codes::SuccessCode;
-use crate::tickv::{State, TicKV};
+use crate::tickv::{KeyEntry, RubbishState, State, Statistics, TicKV, TransactionState};
 use core::cell::Cell;
 
 /// The return type from the continue operation
@@ -144,6 +144,9 @@ pub struct AsyncTicKV<'a, C: FlashController<S>, const S: usize> {
     key: Cell<Option<u64>>,
     value: Cell<Option<&'static mut [u8]>>,
     buf: Cell<Option<&'static mut [u8]>>,
+    cursor: Cell<usize>,
+    key_entry: Cell<Option<KeyEntry>>,
+    statistics: Cell<Option<Statistics>>,
 }
 
 impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
@@ -159,6 +162,9 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
             key: Cell::new(None),
             value: Cell::new(None),
             buf: Cell::new(None),
+            cursor: Cell::new(0),
+            key_entry: Cell::new(None),
+            statistics: Cell::new(None),
         }
     }
 
@@ -263,6 +269,76 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
         self.tickv.garbage_collect()
     }
 
+    /// Perform a garbage collection of a single region
+    ///
+    /// On success the number of bytes freed will be returned.
+    /// On error a `ErrorCode` will be returned. Like for
+    /// `commit_transaction()`, `WriteNotReady` means `continue_operation()`
+    /// has to be called once the write completes.
+    pub fn garbage_collect_region(&self, region: usize) -> Result<usize, ErrorCode> {
+        self.tickv.garbage_collect_region(region)
+    }
+
+    /// Find how much space is free, used and invalid, and how many times
+    /// the regions have been erased.
+    ///
+    /// On success the `Statistics` will be returned.
+    /// On error a `ErrorCode` will be returned. If the operation completes
+    /// in `continue_operation()` the statistics can be retrieved with
+    /// `get_stored_statistics()`.
+    pub fn statistics(&self) -> Result<Statistics, ErrorCode> {
+        self.tickv.statistics()
+    }
+
+    /// Finds the first valid key stored at or after `cursor`.
+    ///
+    /// `cursor`: The position to start looking from. Use 0 to start from the
+    ///           beginning of the flash, and the `next` field of the last
+    ///           key found to continue from there.
+    ///
+    /// On success the `KeyEntry` will be returned.
+    /// On error a `ErrorCode` will be returned, `KeyNotFound` if there are
+    /// no more keys. If the operation completes in `continue_operation()`
+    /// the key can be retrieved with `get_stored_key_entry()`.
+    pub fn next_key(&self, cursor: usize) -> Result<KeyEntry, ErrorCode> {
+        match self.tickv.next_key(cursor) {
+            Ok(entry) => Ok(entry),
+            Err(e) => {
+                self.cursor.set(cursor);
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts a transaction. Until it is committed or aborted, the keys
+    /// appended and invalidated are only changed when it commits.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn start_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.start_transaction()
+    }
+
+    /// Commits the open transaction, changing all of its keys.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned. Unlike other operations,
+    /// `WriteNotReady` means `continue_operation()` has to be called once
+    /// the write completes.
+    pub fn commit_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.commit_transaction()
+    }
+
+    /// Aborts the open transaction, leaving all of its keys unchanged.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned. Like for
+    /// `commit_transaction()`, `WriteNotReady` means `continue_operation()`
+    /// has to be called once the write completes.
+    pub fn abort_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        self.tickv.abort_transaction()
+    }
+
     /// Copy data from `read_buffer` argument to the internal read_buffer.
     /// This should be used to copy the data that the implementation wanted
     /// to read when calling `read_region` after the async operation has
@@ -285,8 +361,21 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
         self.buf.take()
     }
 
+    /// Get the key found by a previous `next_key()` call that completed
+    /// in `continue_operation()`.
+    pub fn get_stored_key_entry(&self) -> Option<KeyEntry> {
+        self.key_entry.take()
+    }
+
+    /// Get the statistics found by a previous `statistics()` call that
+    /// completed in `continue_operation()`.
+    pub fn get_stored_statistics(&self) -> Option<Statistics> {
+        self.statistics.take()
+    }
+
     /// Continue the last operation after the async operation has completed.
-    /// This should be called from a read/erase complete callback.
+    /// This should be called from a read/erase complete callback, or from a
+    /// write complete callback if the operation returned `WriteNotReady`.
     /// NOTE: If called from a read callback, `set_read_buffer` should be
     /// called first to update the data.
     ///
@@ -320,6 +409,22 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
                 Ok(_) => Ok(SuccessCode::Complete),
                 Err(e) => Err(e),
             },
+            State::NextKey(_) => match self.tickv.next_key(self.cursor.get()) {
+                Ok(entry) => {
+                    self.key_entry.set(Some(entry));
+                    Ok(SuccessCode::Complete)
+                }
+                Err(e) => Err(e),
+            },
+            State::Transaction(TransactionState::Start(_)) => self.tickv.start_transaction(),
+            State::Transaction(_) => self.tickv.resolve_transaction(),
+            State::Statistics(_) => match self.tickv.statistics() {
+                Ok(statistics) => {
+                    self.statistics.set(Some(statistics));
+                    Ok(SuccessCode::Complete)
+                }
+                Err(e) => Err(e),
+            },
             _ => unreachable!(),
         };
 
@@ -331,7 +436,13 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
             Err(e) => match e {
                 ErrorCode::ReadNotReady(_) | ErrorCode::EraseNotReady(_) => (ret, None),
                 ErrorCode::WriteNotReady(_) => {
-                    self.tickv.state.set(State::None);
+                    // Resolving a transaction and garbage collecting
+                    // continue after the write
+                    match self.tickv.state.get() {
+                        State::Transaction(TransactionState::Write)
+                        | State::GarbageCollect(RubbishState::WriteHeader(_)) => {}
+                        _ => self.tickv.state.set(State::None),
+                    }
                     (ret, None)
                 }
                 _ => {
@@ -352,6 +463,7 @@ mod tests {
         use crate::async_ops::AsyncTicKV;
         use crate::error_codes::ErrorCode;
         use crate::flash_controller::FlashController;
+        use crate::success_codes::SuccessCode;
         use crate::tickv::{HASH_OFFSET, LEN_OFFSET, MAIN_KEY, VERSION, VERSION_OFFSET};
         use core::hash::{Hash, Hasher};
         use std::cell::Cell;
@@ -377,10 +489,10 @@ mod tests {
             assert_eq!(buf[HASH_OFFSET + 7], 0x44);
 
             // Check the check hash
-            assert_eq!(buf[HASH_OFFSET + 8], 0xbb);
-            assert_eq!(buf[HASH_OFFSET + 9], 0x32);
-            assert_eq!(buf[HASH_OFFSET + 10], 0x74);
-            assert_eq!(buf[HASH_OFFSET + 11], 0x1d);
+            assert_eq!(buf[HASH_OFFSET + 8], 0x3e);
+            assert_eq!(buf[HASH_OFFSET + 9], 0xa7);
+            assert_eq!(buf[HASH_OFFSET + 10], 0x40);
+            assert_eq!(buf[HASH_OFFSET + 11], 0x13);
         }
 
         fn check_region_one(buf: &[u8]) {
@@ -407,10 +519,10 @@ mod tests {
             assert_eq!(buf[42], 0x23);
 
             // Check the check hash
-            assert_eq!(buf[43], 0xfd);
-            assert_eq!(buf[44], 0x24);
-            assert_eq!(buf[45], 0xf0);
-            assert_eq!(buf[46], 0x07);
+            assert_eq!(buf[43], 0x54);
+            assert_eq!(buf[44], 0x72);
+            assert_eq!(buf[45], 0xf4);
+            assert_eq!(buf[46], 0x31);
         }
 
         fn check_region_two(buf: &[u8]) {
@@ -437,10 +549,10 @@ mod tests {
             assert_eq!(buf[42], 0x23);
 
             // Check the check hash
-            assert_eq!(buf[43], 0x1b);
-            assert_eq!(buf[44], 0x53);
-            assert_eq!(buf[45], 0xf9);
-            assert_eq!(buf[46], 0x54);
+            assert_eq!(buf[43], 0xb2);
+            assert_eq!(buf[44], 0x05);
+            assert_eq!(buf[45], 0xfd);
+            assert_eq!(buf[46], 0x62);
         }
 
         fn get_hashed_key(unhashed_key: &[u8]) -> u64 {
@@ -526,18 +638,19 @@ mod tests {
             fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
                 println!("Erase region: {}", region_number);
 
-                if self.async_erase_region.get() != region_number {
-                    // Pretend that we aren't ready
-                    self.async_erase_region.set(region_number);
-                    return Err(ErrorCode::EraseNotReady(region_number));
-                }
-
-                let mut local_buf = self.buf.borrow_mut()[region_number];
+                let local_buf = &mut self.buf.borrow_mut()[region_number];
 
                 for d in local_buf.iter_mut() {
                     *d = 0xFF;
                 }
 
+                if self.async_erase_region.get() != region_number {
+                    // Pretend that we aren't ready, the erase completes in
+                    // the background
+                    self.async_erase_region.set(region_number);
+                    return Err(ErrorCode::EraseNotReady(region_number));
+                }
+
                 Ok(())
             }
         }
@@ -875,5 +988,137 @@ mod tests {
                     .unwrap();
             }
         }
+
+        #[test]
+        fn test_next_key() {
+            let mut read_buf: [u8; 1024] = [0; 1024];
+            let mut hash_function = DefaultHasher::new();
+            MAIN_KEY.hash(&mut hash_function);
+            let hash = hash_function.finish();
+
+            let tickv =
+                AsyncTicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+
+            let mut ret = tickv.initialise(hash);
+            while ret.is_err() {
+                // There is no actual delay in the test, just continue now
+                let (r, _buf) = tickv.continue_operation();
+                ret = r;
+            }
+
+            static mut VALUE: [u8; 32] = [0x23; 32];
+
+            for key in [&b"ONE"[..], &b"TWO"[..]] {
+                println!("Add key {:?}", key);
+                let ret = unsafe { tickv.append_key(get_hashed_key(key), &mut VALUE) };
+                match ret {
+                    Err((_buf, ErrorCode::ReadNotReady(reg))) => {
+                        // There is no actual delay in the test, just continue now
+                        tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
+                        tickv.continue_operation().0.unwrap();
+                    }
+                    Ok(_) => {}
+                    _ => unreachable!(),
+                }
+            }
+
+            println!("List the keys");
+            let mut keys = vec![];
+            let mut cursor = 0;
+            loop {
+                let mut ret = tickv.next_key(cursor);
+                while let Err(ErrorCode::ReadNotReady(reg)) = ret {
+                    // There is no actual delay in the test, just continue now
+                    tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
+                    ret = tickv
+                        .continue_operation()
+                        .0
+                        .map(|_| tickv.get_stored_key_entry().unwrap());
+                }
+
+                match ret {
+                    Ok(entry) => {
+                        assert!(entry.next > cursor);
+                        keys.push(entry.hashed_key);
+                        cursor = entry.next;
+                    }
+                    Err(ErrorCode::KeyNotFound) => break,
+                    Err(e) => panic!("Error listing keys: {:?}", e),
+                }
+            }
+
+            keys.sort();
+            let mut expected = vec![hash, get_hashed_key(b"ONE"), get_hashed_key(b"TWO")];
+            expected.sort();
+            assert_eq!(keys, expected);
+        }
+
+        #[test]
+        fn test_transaction() {
+            let mut read_buf: [u8; 1024] = [0; 1024];
+            let mut hash_function = DefaultHasher::new();
+            MAIN_KEY.hash(&mut hash_function);
+
+            let tickv =
+                AsyncTicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+
+            // There is no actual delay in the test, just continue now
+            let finish = |mut ret: Result<SuccessCode, ErrorCode>| loop {
+                match ret {
+                    Err(ErrorCode::ReadNotReady(reg)) => {
+                        tickv.set_read_buffer(&tickv.tickv.controller.buf.borrow()[reg]);
+                    }
+                    Err(ErrorCode::EraseNotReady(_)) => {}
+                    _ => return ret,
+                }
+                ret = tickv.continue_operation().0;
+            };
+
+            finish(tickv.initialise(hash_function.finish())).unwrap();
+
+            static mut VALUE: [u8; 32] = [0x23; 32];
+            static mut NEW_VALUE: [u8; 32] = [0x42; 32];
+            static mut BUF: [u8; 32] = [0; 32];
+
+            for key in [&b"ONE"[..], &b"TWO"[..]] {
+                println!("Add key {:?}", key);
+                let ret = unsafe { tickv.append_key(get_hashed_key(key), &mut VALUE) };
+                finish(ret.map_err(|(_buf, e)| e)).unwrap();
+            }
+
+            println!("Start a transaction");
+            finish(tickv.start_transaction()).unwrap();
+
+            println!("Add key THREE");
+            let ret = unsafe { tickv.append_key(get_hashed_key(b"THREE"), &mut NEW_VALUE) };
+            finish(ret.map_err(|(_buf, e)| e)).unwrap();
+
+            println!("Delete key ONE");
+            finish(tickv.invalidate_key(get_hashed_key(b"ONE"))).unwrap();
+
+            println!("Get key THREE");
+            let ret = unsafe { tickv.get_key(get_hashed_key(b"THREE"), &mut BUF) };
+            assert_eq!(
+                finish(ret.map_err(|(_buf, e)| e)),
+                Err(ErrorCode::KeyNotFound)
+            );
+
+            println!("Commit the transaction");
+            finish(tickv.commit_transaction()).unwrap();
+
+            println!("Get key THREE");
+            let ret = unsafe { tickv.get_key(get_hashed_key(b"THREE"), &mut BUF) };
+            finish(ret.map_err(|(_buf, e)| e)).unwrap();
+            unsafe {
+                assert_eq!(BUF, NEW_VALUE);
+            }
+
+            println!("Get key ONE");
+            let ret = unsafe { tickv.get_key(get_hashed_key(b"ONE"), &mut BUF) };
+            assert_eq!(
+                finish(ret.map_err(|(_buf, e)| e)),
+                Err(ErrorCode::KeyNotFound)
+            );
+        }
     }
 }
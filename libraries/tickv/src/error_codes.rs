@@ -40,6 +40,14 @@ pub enum ErrorCode {
     WriteNotReady(usize),
     /// Indicates that the flash erase operation is not yet ready.
     EraseNotReady(usize),
+    /// A transaction is already open
+    TransactionInProgress,
+    /// There is no open transaction
+    NoTransaction,
+    /// There is no region with the requested number
+    RegionNotFound,
+    /// The key hash is one TicKV uses for its own objects
+    ReservedKey,
 }
 
 impl From<ErrorCode> for isize {
@@ -60,6 +68,10 @@ impl From<ErrorCode> for isize {
             ErrorCode::ReadNotReady(_) => -13,
             ErrorCode::WriteNotReady(_) => -14,
             ErrorCode::EraseNotReady(_) => -15,
+            ErrorCode::TransactionInProgress => -16,
+            ErrorCode::NoTransaction => -17,
+            ErrorCode::RegionNotFound => -18,
+            ErrorCode::ReservedKey => -19,
         }
     }
 }
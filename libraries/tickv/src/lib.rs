@@ -162,6 +162,24 @@
 //!
 //! You can then use the `get_key()` function to get the key back from flash.
 //!
+//! The valid keys stored can be listed with the `keys()` iterator. Only the
+//! hashes of the keys are stored, so the hashes are listed. With an async
+//! `FlashController` use `next_key()` instead, which continues from the
+//! position of the previous key.
+//!
+//! Hashing loses any structure of the keys, so TicKV can't tell which keys
+//! start with a prefix on its own. Users that list keys by prefix store the
+//! start of each key in its value, as the Tock KV store does, and check it
+//! for each key found.
+//!
+//! # Transactions
+//!
+//! Several keys can be changed atomically with a transaction. After
+//! `start_transaction()`, the keys appended and invalidated are only changed
+//! by `commit_transaction()`, or left unchanged by `abort_transaction()`.
+//! If power is lost, `initialise()` changes all of them if the transaction
+//! was committed and none of them otherwise.
+//!
 //! # Collisions
 //!
 //! TicKV will prevent a new key/value pair with a colliding hash of the key to be
@@ -222,6 +240,8 @@ pub use crate::error_codes::ErrorCode;
 #[doc(inline)]
 pub use crate::flash_controller::FlashController;
 #[doc(inline)]
+pub use crate::tickv::Statistics;
+#[doc(inline)]
 pub use crate::tickv::TicKV;
 pub use crate::tickv::MAIN_KEY;
 
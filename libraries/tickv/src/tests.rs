@@ -1,6 +1,9 @@
+use crate::crc32::Crc32;
 use crate::error_codes::ErrorCode;
 use crate::flash_controller::FlashController;
-use crate::tickv::{TicKV, HASH_OFFSET, LEN_OFFSET, MAIN_KEY, VERSION, VERSION_OFFSET};
+use crate::tickv::{
+    TicKV, HASH_OFFSET, LEN_OFFSET, MAIN_KEY, REGION_KEY, TRANSACTION_KEY, VERSION, VERSION_OFFSET,
+};
 use core::hash::{Hash, Hasher};
 use std::cell::Cell;
 use std::cell::RefCell;
@@ -25,10 +28,10 @@ fn check_region_main(buf: &[u8]) {
     assert_eq!(buf[HASH_OFFSET + 7], 0x44);
 
     // Check the check hash
-    assert_eq!(buf[HASH_OFFSET + 8], 0xbb);
-    assert_eq!(buf[HASH_OFFSET + 9], 0x32);
-    assert_eq!(buf[HASH_OFFSET + 10], 0x74);
-    assert_eq!(buf[HASH_OFFSET + 11], 0x1d);
+    assert_eq!(buf[HASH_OFFSET + 8], 0x3e);
+    assert_eq!(buf[HASH_OFFSET + 9], 0xa7);
+    assert_eq!(buf[HASH_OFFSET + 10], 0x40);
+    assert_eq!(buf[HASH_OFFSET + 11], 0x13);
 }
 
 fn check_region_one(buf: &[u8]) {
@@ -55,10 +58,10 @@ fn check_region_one(buf: &[u8]) {
     assert_eq!(buf[42], 0x23);
 
     // Check the check hash
-    assert_eq!(buf[43], 0xfd);
-    assert_eq!(buf[44], 0x24);
-    assert_eq!(buf[45], 0xf0);
-    assert_eq!(buf[46], 0x07);
+    assert_eq!(buf[43], 0x54);
+    assert_eq!(buf[44], 0x72);
+    assert_eq!(buf[45], 0xf4);
+    assert_eq!(buf[46], 0x31);
 }
 
 fn check_region_two(buf: &[u8]) {
@@ -85,10 +88,10 @@ fn check_region_two(buf: &[u8]) {
     assert_eq!(buf[42], 0x23);
 
     // Check the check hash
-    assert_eq!(buf[43], 0x1b);
-    assert_eq!(buf[44], 0x53);
-    assert_eq!(buf[45], 0xf9);
-    assert_eq!(buf[46], 0x54);
+    assert_eq!(buf[43], 0xb2);
+    assert_eq!(buf[44], 0x05);
+    assert_eq!(buf[45], 0xfd);
+    assert_eq!(buf[46], 0x62);
 }
 
 fn get_hashed_key(unhashed_key: &[u8]) -> u64 {
@@ -271,7 +274,7 @@ mod store_flast_ctrl {
 
         fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
             println!("Erase region: {}", region_number);
-            let mut local_buf = self.buf.borrow_mut()[region_number];
+            let local_buf = &mut self.buf.borrow_mut()[region_number];
 
             for d in local_buf.iter_mut() {
                 *d = 0xFF;
@@ -414,6 +417,177 @@ mod store_flast_ctrl {
         println!("Add Key ONE");
         tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
     }
+
+    #[test]
+    fn test_statistics() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+        tickv.initialise(hash).unwrap();
+
+        let value: [u8; 32] = [0x23; 32];
+        let region = (get_hashed_key(b"ONE") as usize & 0xFFFF) % 64;
+
+        println!("Only the main key is used");
+        let stats = tickv.statistics().unwrap();
+        assert_eq!(stats.used, 15);
+        assert_eq!(stats.invalid, 0);
+        assert_eq!(stats.free, 0x10000 - 15);
+        assert_eq!((stats.min_erase_count, stats.max_erase_count), (0, 0));
+
+        println!("Add and delete Key ONE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        tickv.invalidate_key(get_hashed_key(b"ONE")).unwrap();
+        let stats = tickv.statistics().unwrap();
+        assert_eq!(stats.used, 15);
+        assert_eq!(stats.invalid, 47);
+        assert_eq!(stats.free, 0x10000 - 15 - 47);
+
+        println!("Garbage collect the region of key ONE");
+        assert_eq!(tickv.garbage_collect_region(region), Ok(1024));
+        let stats = tickv.statistics().unwrap();
+        assert_eq!(stats.used, 15 + 19);
+        assert_eq!(stats.invalid, 0);
+        assert_eq!(stats.free, 0x10000 - 15 - 19);
+        assert_eq!((stats.min_erase_count, stats.max_erase_count), (0, 1));
+
+        println!("Collecting a region with only a header doesn't erase it");
+        assert_eq!(tickv.garbage_collect(), Ok(0));
+
+        println!("Add, delete and garbage collect Key ONE again");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        tickv.invalidate_key(get_hashed_key(b"ONE")).unwrap();
+        assert_eq!(tickv.garbage_collect(), Ok(1024));
+        let stats = tickv.statistics().unwrap();
+        assert_eq!(stats.used, 15 + 19);
+        assert_eq!((stats.min_erase_count, stats.max_erase_count), (0, 2));
+
+        println!("Key ONE can still be added after its region's header");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        let mut buf: [u8; 32] = [0; 32];
+        tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+        assert_eq!(buf, value);
+
+        assert_eq!(
+            tickv.garbage_collect_region(64),
+            Err(ErrorCode::RegionNotFound)
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+        tickv.initialise(hash).unwrap();
+
+        let value: [u8; 32] = [0x23; 32];
+
+        println!("List the main key");
+        let keys: std::vec::Vec<_> = tickv.keys().map(|key| key.unwrap()).collect();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].hashed_key, hash);
+        assert_eq!(keys[0].value_length, 0);
+
+        println!("Add Keys ONE, TWO and THREE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        tickv.append_key(get_hashed_key(b"TWO"), &value).unwrap();
+        tickv
+            .append_key(get_hashed_key(b"THREE"), &value[..16])
+            .unwrap();
+
+        println!("Delete Key TWO");
+        tickv.invalidate_key(get_hashed_key(b"TWO")).unwrap();
+
+        println!("List the keys");
+        let mut keys: std::vec::Vec<_> = tickv
+            .keys()
+            .map(|key| {
+                let key = key.unwrap();
+                (key.hashed_key, key.value_length)
+            })
+            .collect();
+        keys.sort();
+
+        let mut expected = vec![
+            (hash, 0),
+            (get_hashed_key(b"ONE"), 32),
+            (get_hashed_key(b"THREE"), 16),
+        ];
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        println!("Continue after the last key");
+        let last = tickv.keys().last().unwrap().unwrap();
+        assert_eq!(tickv.next_key(last.next), Err(ErrorCode::KeyNotFound));
+    }
+
+    #[test]
+    fn test_reserved_keys() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+        tickv.initialise(hash).unwrap();
+
+        for key in [TRANSACTION_KEY, REGION_KEY] {
+            assert_eq!(
+                tickv.append_key(key, &[0x23; 4]),
+                Err(ErrorCode::ReservedKey)
+            );
+            assert_eq!(tickv.invalidate_key(key), Err(ErrorCode::ReservedKey));
+        }
+    }
+
+    #[test]
+    fn test_version_one_object() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+        tickv.initialise(hash).unwrap();
+
+        let value: [u8; 32] = [0x23; 32];
+        let key = get_hashed_key(b"ONE");
+        tickv.append_key(key, &value).unwrap();
+
+        println!("Rewrite key ONE as a version 1 object");
+        {
+            let mut buf = tickv.controller.buf.borrow_mut();
+            let (region, offset) = buf
+                .iter()
+                .enumerate()
+                .find_map(|(region, data)| {
+                    data.windows(8)
+                        .position(|w| w == key.to_be_bytes())
+                        .map(|offset| (region, offset - HASH_OFFSET))
+                })
+                .unwrap();
+            let object = &mut buf[region][offset..offset + 47];
+            object[VERSION_OFFSET] = 1;
+
+            let mut check_sum = Crc32::new();
+            check_sum.update(&object[..43]);
+            object[43..].copy_from_slice(&check_sum.finalise().to_le_bytes());
+        }
+
+        let mut buf: [u8; 32] = [0; 32];
+        tickv.get_key(key, &mut buf).unwrap();
+        assert_eq!(buf, value);
+
+        tickv.invalidate_key(key).unwrap();
+        assert_eq!(tickv.get_key(key, &mut buf), Err(ErrorCode::KeyNotFound));
+    }
 }
 
 mod no_check_store_flast_ctrl {
@@ -463,7 +637,7 @@ mod no_check_store_flast_ctrl {
 
         fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
             println!("Erase region: {}", region_number);
-            let mut local_buf = self.buf.borrow_mut()[region_number];
+            let local_buf = &mut self.buf.borrow_mut()[region_number];
 
             for d in local_buf.iter_mut() {
                 *d = 0xFF;
@@ -558,3 +732,278 @@ mod no_check_store_flast_ctrl {
         );
     }
 }
+
+/// Tests using a flash controller that loses power after a number of writes
+mod power_loss_flash_ctrl {
+    use super::*;
+
+    const REGIONS: usize = 16;
+
+    /// The number of bytes of the write power is lost in that are
+    /// programmed, to tear it, at most all but the last one. None loses
+    /// power before the write starts.
+    const TORN: [Option<usize>; 4] = [None, Some(1), Some(8), Some(usize::MAX)];
+
+    struct FlashCtrl {
+        buf: RefCell<[[u8; 1024]; REGIONS]>,
+        // None if power is never lost
+        writes_left: Cell<Option<usize>>,
+        // Bytes programmed by the write power is lost in
+        torn: Option<usize>,
+        writes: Cell<usize>,
+    }
+
+    impl FlashCtrl {
+        fn new(buf: [[u8; 1024]; REGIONS], writes_left: Option<usize>) -> Self {
+            Self::torn(buf, writes_left, None)
+        }
+
+        fn torn(
+            buf: [[u8; 1024]; REGIONS],
+            writes_left: Option<usize>,
+            torn: Option<usize>,
+        ) -> Self {
+            Self {
+                buf: RefCell::new(buf),
+                writes_left: Cell::new(writes_left),
+                torn,
+                writes: Cell::new(0),
+            }
+        }
+
+        /// Programs `buf` at `address`. Like NOR flash, bits can only be
+        /// cleared.
+        fn program(&self, address: usize, buf: &[u8]) {
+            for (i, d) in buf.iter().enumerate() {
+                self.buf.borrow_mut()[address / 1024][(address % 1024) + i] &= *d;
+            }
+        }
+    }
+
+    impl FlashController<1024> for FlashCtrl {
+        fn read_region(
+            &self,
+            region_number: usize,
+            offset: usize,
+            buf: &mut [u8; 1024],
+        ) -> Result<(), ErrorCode> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.buf.borrow()[region_number][offset + i]
+            }
+
+            Ok(())
+        }
+
+        fn write(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
+            match self.writes_left.get() {
+                // The power is lost, nothing else gets written
+                Some(0) => return Err(ErrorCode::WriteFail),
+                Some(1) if self.torn.is_some() => {
+                    // Power is lost part of the way through this write
+                    self.writes_left.set(Some(0));
+                    let torn = self.torn.unwrap().min(buf.len() - 1);
+                    self.program(address, &buf[..torn]);
+                    return Err(ErrorCode::WriteFail);
+                }
+                Some(left) => self.writes_left.set(Some(left - 1)),
+                None => {}
+            }
+            self.writes.set(self.writes.get() + 1);
+
+            self.program(address, buf);
+            Ok(())
+        }
+
+        fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
+            for d in self.buf.borrow_mut()[region_number].iter_mut() {
+                *d = 0xFF;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn main_key() -> u64 {
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        hash_function.finish()
+    }
+
+    /// Flash holding the keys ONE and TWO
+    fn setup() -> [[u8; 1024]; REGIONS] {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let tickv = TicKV::<FlashCtrl, 1024>::new(
+            FlashCtrl::new([[0xFF; 1024]; REGIONS], None),
+            &mut read_buf,
+            0x400 * REGIONS,
+        );
+        tickv.initialise(main_key()).unwrap();
+        tickv
+            .append_key(get_hashed_key(b"ONE"), &[0x11; 32])
+            .unwrap();
+        tickv
+            .append_key(get_hashed_key(b"TWO"), &[0x12; 32])
+            .unwrap();
+
+        let buf = *tickv.controller.buf.borrow();
+        buf
+    }
+
+    /// Adds THREE, removes ONE and replaces TWO in a transaction
+    fn run_transaction(tickv: &TicKV<FlashCtrl, 1024>) -> Result<(), ErrorCode> {
+        tickv.start_transaction()?;
+        tickv.append_key(get_hashed_key(b"THREE"), &[0x33; 32])?;
+        tickv.invalidate_key(get_hashed_key(b"ONE"))?;
+        tickv.invalidate_key(get_hashed_key(b"TWO"))?;
+        tickv.append_key(get_hashed_key(b"TWO"), &[0x22; 32])?;
+        tickv.commit_transaction()?;
+        Ok(())
+    }
+
+    /// Checks that either all or none of the transaction happened, and
+    /// returns which one.
+    fn check_keys(tickv: &TicKV<FlashCtrl, 1024>) -> bool {
+        let mut buf: [u8; 32] = [0; 32];
+
+        let committed = tickv.get_key(get_hashed_key(b"THREE"), &mut buf).is_ok();
+        if committed {
+            assert_eq!(buf, [0x33; 32]);
+            assert_eq!(
+                tickv.get_key(get_hashed_key(b"ONE"), &mut buf),
+                Err(ErrorCode::KeyNotFound)
+            );
+            tickv.get_key(get_hashed_key(b"TWO"), &mut buf).unwrap();
+            assert_eq!(buf, [0x22; 32]);
+        } else {
+            tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+            assert_eq!(buf, [0x11; 32]);
+            tickv.get_key(get_hashed_key(b"TWO"), &mut buf).unwrap();
+            assert_eq!(buf, [0x12; 32]);
+        }
+
+        // Nothing is left of the transaction
+        let keys = tickv.keys().map(|key| key.unwrap().hashed_key);
+        assert_eq!(keys.count(), 3);
+
+        committed
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let tickv = TicKV::<FlashCtrl, 1024>::new(
+            FlashCtrl::new(setup(), None),
+            &mut read_buf,
+            0x400 * REGIONS,
+        );
+
+        tickv.start_transaction().unwrap();
+        assert_eq!(
+            tickv.start_transaction(),
+            Err(ErrorCode::TransactionInProgress)
+        );
+        tickv
+            .append_key(get_hashed_key(b"THREE"), &[0x33; 32])
+            .unwrap();
+        assert_eq!(
+            tickv.append_key(get_hashed_key(b"THREE"), &[0x33; 32]),
+            Err(ErrorCode::KeyAlreadyExists)
+        );
+        assert_eq!(
+            tickv.append_key(get_hashed_key(b"ONE"), &[0x33; 32]),
+            Err(ErrorCode::KeyAlreadyExists)
+        );
+        assert_eq!(
+            tickv.invalidate_key(get_hashed_key(b"THREE")),
+            Err(ErrorCode::KeyAlreadyExists)
+        );
+        tickv.invalidate_key(get_hashed_key(b"ONE")).unwrap();
+        assert_eq!(
+            tickv.invalidate_key(get_hashed_key(b"ONE")),
+            Err(ErrorCode::KeyNotFound)
+        );
+
+        // Nothing changes until the transaction commits
+        let mut buf: [u8; 32] = [0; 32];
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"THREE"), &mut buf),
+            Err(ErrorCode::KeyNotFound)
+        );
+        tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+
+        tickv.abort_transaction().unwrap();
+        assert_eq!(tickv.abort_transaction(), Err(ErrorCode::NoTransaction));
+        assert!(!check_keys(&tickv));
+
+        run_transaction(&tickv).unwrap();
+        assert!(check_keys(&tickv));
+    }
+
+    #[test]
+    fn test_transaction_power_loss() {
+        let flash = setup();
+
+        // Count the writes of the whole transaction
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let tickv = TicKV::<FlashCtrl, 1024>::new(
+            FlashCtrl::new(flash, None),
+            &mut read_buf,
+            0x400 * REGIONS,
+        );
+        run_transaction(&tickv).unwrap();
+        let writes = tickv.controller.writes.get();
+
+        for (lost_at, torn) in (0..=writes).flat_map(|lost_at| TORN.map(|torn| (lost_at, torn))) {
+            // A torn write is the one after the last whole one
+            if torn.is_some() && lost_at == writes {
+                continue;
+            }
+            println!("Lose power after {} writes, tearing {:?}", lost_at, torn);
+            let mut read_buf: [u8; 1024] = [0; 1024];
+            let tickv = TicKV::<FlashCtrl, 1024>::new(
+                FlashCtrl::torn(flash, Some(lost_at + torn.map_or(0, |_| 1)), torn),
+                &mut read_buf,
+                0x400 * REGIONS,
+            );
+            assert_eq!(run_transaction(&tickv).is_ok(), lost_at == writes);
+            let interrupted = *tickv.controller.buf.borrow();
+
+            // Power can also be lost while recovering
+            let mut recovery_lost_at = 0;
+            let committed = loop {
+                let mut read_buf: [u8; 1024] = [0; 1024];
+                let recovery = TicKV::<FlashCtrl, 1024>::new(
+                    FlashCtrl::torn(
+                        interrupted,
+                        Some(recovery_lost_at + torn.map_or(0, |_| 1)),
+                        torn,
+                    ),
+                    &mut read_buf,
+                    0x400 * REGIONS,
+                );
+                let recovered = recovery.initialise(main_key()).is_ok();
+                let partly_recovered = *recovery.controller.buf.borrow();
+
+                let mut read_buf: [u8; 1024] = [0; 1024];
+                let tickv = TicKV::<FlashCtrl, 1024>::new(
+                    FlashCtrl::new(partly_recovered, None),
+                    &mut read_buf,
+                    0x400 * REGIONS,
+                );
+                tickv.initialise(main_key()).unwrap();
+                let committed = check_keys(&tickv);
+
+                if recovered {
+                    break committed;
+                }
+                recovery_lost_at += 1;
+            };
+
+            // The transaction is committed by the sixth write. Tearing that
+            // write can leave it committed or not, but never partly.
+            if torn.is_none() || lost_at != 5 {
+                assert_eq!(committed, lost_at > 5);
+            }
+        }
+    }
+}
@@ -7,7 +7,16 @@ use crate::success_codes::SuccessCode;
 use core::cell::Cell;
 
 /// The current version of TicKV
-pub const VERSION: u8 = 1;
+pub const VERSION: u8 = 2;
+
+/// Objects of version 1 only use the valid flag, whose meaning is the same
+/// in version 2, so they are still read. Version 1 doesn't read version 2
+/// objects, which could be pending parts of a transaction.
+const VERSION_1: u8 = 1;
+
+fn supported_version(version: u8) -> bool {
+    version == VERSION || version == VERSION_1
+}
 
 #[derive(Clone, Copy, PartialEq)]
 pub(crate) enum InitState {
@@ -30,7 +39,20 @@ pub(crate) enum KeyState {
 #[derive(Clone, Copy, PartialEq)]
 pub(crate) enum RubbishState {
     ReadRegion(usize),
-    EraseRegion(usize),
+    /// Erasing a region, that had been erased `u32` times before
+    EraseRegion(usize, u32),
+    /// Writing the header of an erased region
+    WriteHeader(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum TransactionState {
+    /// Trying to read a region while starting a transaction
+    Start(KeyState),
+    /// Trying to read a region while resolving a transaction
+    Resolve(KeyState),
+    /// Waiting for a write while resolving a transaction
+    Write,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -49,6 +71,77 @@ pub(crate) enum State {
     InvalidateKey(KeyState),
     /// Running garbage collection
     GarbageCollect(RubbishState),
+    /// Looking for the next key
+    NextKey(KeyState),
+    /// Starting or resolving a transaction
+    Transaction(TransactionState),
+    /// Collecting statistics
+    Statistics(KeyState),
+}
+
+/// The location of an object in flash, and the byte holding its flags.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Object {
+    address: usize,
+    len_flags: u8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Resolve {
+    /// Looking for the marker. The transaction is committed if `Some(true)`,
+    /// rolled back if `Some(false)` and the marker decides if `None`.
+    FindMarker(Option<bool>),
+    /// Clearing the pending flag of the marker
+    Commit(Object),
+    /// Resolving the pending objects in a region. `target_done` is the
+    /// address of an invalidation object whose target has been invalidated.
+    Region {
+        commit: bool,
+        marker: Option<Object>,
+        region: usize,
+        target_done: Option<usize>,
+    },
+    /// Invalidating the target of the invalidation object at `object`
+    Target {
+        marker: Option<Object>,
+        region: usize,
+        object: usize,
+        target: Object,
+    },
+    /// Invalidating the marker
+    Marker(Option<Object>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Transaction {
+    /// No transaction is open
+    None,
+    /// A transaction is open, keys appended and invalidated are part of it
+    Open,
+    /// The transaction is being committed, rolled back or recovered
+    Resolve(Resolve),
+}
+
+/// What the open transaction does with a key.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct TransactionKeys {
+    /// The valid object of the key, from before the transaction
+    key: Option<Object>,
+    /// The transaction appends the key
+    appended: bool,
+    /// The transaction invalidates the key
+    invalidated: bool,
+    /// All of the regions the key can be in have been read
+    complete: bool,
+}
+
+impl TransactionKeys {
+    const NONE: Self = Self {
+        key: None,
+        appended: false,
+        invalidated: false,
+        complete: false,
+    };
 }
 
 /// The struct storing all of the TicKV information.
@@ -58,6 +151,81 @@ pub struct TicKV<'a, C: FlashController<S>, const S: usize> {
     flash_size: usize,
     pub(crate) read_buffer: Cell<Option<&'a mut [u8; S]>>,
     pub(crate) state: Cell<State>,
+    pub(crate) transaction: Cell<Transaction>,
+    transaction_keys: Cell<TransactionKeys>,
+    /// The region after the last one being garbage collected
+    collect_end: Cell<usize>,
+    /// The statistics of the regions read so far
+    statistics: Cell<Statistics>,
+}
+
+/// The space used in TicKV, found by `statistics()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Statistics {
+    /// Bytes that haven't been written since their region was erased
+    pub free: usize,
+    /// Bytes holding valid objects, including region headers
+    pub used: usize,
+    /// Bytes holding invalid objects. They are only freed once every
+    /// object in their region is invalid and the region is garbage
+    /// collected.
+    pub invalid: usize,
+    /// The fewest times a region has been erased by garbage collection
+    pub min_erase_count: u32,
+    /// The most times a region has been erased by garbage collection
+    pub max_erase_count: u32,
+}
+
+impl Statistics {
+    const NONE: Self = Self {
+        free: 0,
+        used: 0,
+        invalid: 0,
+        min_erase_count: u32::MAX,
+        max_erase_count: 0,
+    };
+}
+
+/// A valid key found by `next_key()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEntry {
+    /// The hashed key
+    pub hashed_key: u64,
+    /// The length of the value stored for the key
+    pub value_length: usize,
+    /// The position to continue looking for keys from. Pass this to
+    /// `next_key()` to find the key stored after this one.
+    pub next: usize,
+}
+
+/// An iterator over the valid keys in TicKV, created by `TicKV::keys()`.
+pub struct Keys<'b, 'a, C: FlashController<S>, const S: usize> {
+    tickv: &'b TicKV<'a, C, S>,
+    cursor: Option<usize>,
+}
+
+impl<'b, 'a, C: FlashController<S>, const S: usize> Iterator for Keys<'b, 'a, C, S> {
+    type Item = Result<KeyEntry, ErrorCode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cursor = self.cursor?;
+
+        match self.tickv.next_key(cursor) {
+            Ok(entry) => {
+                self.cursor = Some(entry.next);
+                Some(Ok(entry))
+            }
+            Err(ErrorCode::KeyNotFound) => {
+                self.cursor = None;
+                None
+            }
+            Err(e) => {
+                // Don't keep reading after an error
+                self.cursor = None;
+                Some(Err(e))
+            }
+        }
+    }
 }
 
 /// This is the current object header used for TicKV objects
@@ -71,13 +239,20 @@ struct ObjectHeader {
 }
 
 pub(crate) const FLAGS_VALID: u8 = 8;
+/// Set on objects written by a transaction that hasn't been resolved yet.
+pub(crate) const FLAGS_PENDING: u8 = 4;
+/// Set on objects that invalidate another object when their transaction
+/// commits.
+pub(crate) const FLAGS_INVALIDATE: u8 = 2;
+/// Set on the object marking a transaction.
+pub(crate) const FLAGS_TRANSACTION: u8 = 1;
 
 impl ObjectHeader {
-    fn new(hashed_key: u64, len: u16) -> Self {
+    fn new(hashed_key: u64, len: u16, flags: u8) -> Self {
         assert!(len < 0xFFF);
         Self {
             version: VERSION,
-            flags: FLAGS_VALID,
+            flags,
             len,
             hashed_key,
         }
@@ -95,6 +270,29 @@ pub(crate) const CHECK_SUM_LEN: usize = 4;
 /// `initialise()`.
 pub const MAIN_KEY: &[u8; 15] = b"tickv-super-key";
 
+/// The hashed key of the object marking a transaction.
+pub(crate) const TRANSACTION_KEY: u64 = 0x7469_636b_765f_7478;
+
+/// The hashed key of the header of a region.
+pub(crate) const REGION_KEY: u64 = 0x7469_636b_765f_7267;
+
+/// Keys TicKV uses for its own objects can't be appended or invalidated.
+fn check_key(hash: u64) -> Result<(), ErrorCode> {
+    if hash == TRANSACTION_KEY || hash == REGION_KEY {
+        Err(ErrorCode::ReservedKey)
+    } else {
+        Ok(())
+    }
+}
+
+/// The length of a region header: an object without flags whose value is
+/// the number of times the region has been erased (4 bytes, little endian).
+pub(crate) const REGION_HEADER_LENGTH: usize = HEADER_LENGTH + 4 + CHECK_SUM_LEN;
+
+/// The length of the value of an invalidation object: the address of the
+/// object to invalidate and the byte holding its flags.
+const INVALIDATE_LENGTH: usize = 5;
+
 /// This is the main TicKV struct.
 impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// Create a new struct
@@ -109,6 +307,10 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
             flash_size,
             read_buffer: Cell::new(Some(read_buffer)),
             state: Cell::new(State::None),
+            transaction: Cell::new(Transaction::None),
+            transaction_keys: Cell::new(TransactionKeys::NONE),
+            collect_end: Cell::new(0),
+            statistics: Cell::new(Statistics::NONE),
         }
     }
 
@@ -118,7 +320,9 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// `hashed_main_key`: The u64 hash of the const string `MAIN_KEY`.
     ///
     /// If the specified region has not already been setup for TicKV
-    /// the entire region will be erased.
+    /// the entire region will be erased. Otherwise a transaction interrupted
+    /// by a power loss is rolled back if it wasn't committed, and finished if
+    /// it was.
     ///
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
@@ -135,7 +339,12 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         };
 
         match key_ret {
-            Ok(ret) => Ok(ret),
+            Ok(_) => {
+                self.state.set(State::None);
+                self.transaction
+                    .set(Transaction::Resolve(Resolve::FindMarker(None)));
+                self.resolve_transaction()
+            }
             Err(e) => {
                 match e {
                     ErrorCode::ReadNotReady(reg) => {
@@ -241,7 +450,8 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         None
     }
 
-    /// Find a key in some loaded region data.
+    /// Find a key in some loaded region data, in an object with exactly
+    /// `flags` set.
     ///
     /// On success return the offset in the region_data where the key is and the
     /// total length of the key.
@@ -251,6 +461,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         &self,
         hash: u64,
         region_data: &[u8],
+        flags: u8,
     ) -> Result<(usize, u16), (bool, ErrorCode)> {
         // Determine the total size of our payload
 
@@ -276,11 +487,11 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                 empty = false;
 
                 // We found a version, check that we support it
-                if *region_data
-                    .get(offset + VERSION_OFFSET)
-                    .ok_or((false, ErrorCode::KeyNotFound))?
-                    != VERSION
-                {
+                if !supported_version(
+                    *region_data
+                        .get(offset + VERSION_OFFSET)
+                        .ok_or((false, ErrorCode::KeyNotFound))?,
+                ) {
                     return Err((false, ErrorCode::UnsupportedVersion));
                 }
 
@@ -301,12 +512,13 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     return Err((false, ErrorCode::KeyNotFound));
                 }
 
-                // Check to see if the entry has been deleted
+                // Check to see if the entry has been deleted, or isn't the
+                // kind of object we are looking for
                 if *region_data
                     .get(offset + LEN_OFFSET)
                     .ok_or((false, ErrorCode::CorruptData))?
-                    & 0x80
-                    != 0x80
+                    >> 4
+                    != flags
                 {
                     // Increment our offset by the length and repeat the loop
                     offset += total_length as usize;
@@ -367,9 +579,31 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     ///         or remove the `value`.
     /// `value`: A buffer containing the data to be stored to flash.
     ///
+    /// While a transaction is open the key is only added when the
+    /// transaction commits. A key that the transaction invalidates can be
+    /// appended again.
+    ///
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
     pub fn append_key(&self, hash: u64, value: &[u8]) -> Result<SuccessCode, ErrorCode> {
+        check_key(hash)?;
+        if self.transaction.get() == Transaction::Open {
+            return self.transaction_append_key(hash, value);
+        }
+
+        self.append_object(hash, value, FLAGS_VALID, State::AppendKey)
+    }
+
+    /// Appends an object with `flags` set to flash storage.
+    ///
+    /// `read_state`: The state to resume from after an async read.
+    fn append_object(
+        &self,
+        hash: u64,
+        value: &[u8],
+        flags: u8,
+        read_state: fn(KeyState) -> State,
+    ) -> Result<SuccessCode, ErrorCode> {
         let region = self.get_region(hash);
         let mut check_sum = crc32::Crc32::new();
 
@@ -382,7 +616,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         }
 
         // Create the header:
-        let header = ObjectHeader::new(hash, object_length as u16);
+        let header = ObjectHeader::new(hash, object_length as u16, flags);
 
         let mut region_offset: isize = 0;
 
@@ -398,15 +632,17 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                         }
                     }
                 }
-                State::AppendKey(key_state) => match key_state {
-                    KeyState::ReadRegion(reg) => reg as isize,
-                },
+                State::AppendKey(KeyState::ReadRegion(reg))
+                | State::InvalidateKey(KeyState::ReadRegion(reg))
+                | State::Transaction(TransactionState::Start(KeyState::ReadRegion(reg))) => {
+                    reg as isize
+                }
                 State::GarbageCollect(RubbishState::ReadRegion(reg)) => reg as isize,
                 _ => unreachable!(),
             };
 
             let mut region_data = self.read_buffer.take().unwrap();
-            if self.state.get() != State::AppendKey(KeyState::ReadRegion(new_region as usize))
+            if self.state.get() != read_state(KeyState::ReadRegion(new_region as usize))
                 && self.state.get()
                     != State::Init(InitState::AppendKeyReadRegion(new_region as usize))
             {
@@ -418,14 +654,14 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     Err(e) => {
                         self.read_buffer.replace(Some(region_data));
                         if let ErrorCode::ReadNotReady(reg) = e {
-                            self.state.set(State::AppendKey(KeyState::ReadRegion(reg)));
+                            self.state.set(read_state(KeyState::ReadRegion(reg)));
                         }
                         return Err(e);
                     }
                 };
             }
 
-            if self.find_key_offset(hash, region_data).is_ok() {
+            if self.find_key_offset(hash, region_data, flags).is_ok() {
                 // Check to make sure we don't already have this key
                 self.read_buffer.replace(Some(region_data));
                 return Err(ErrorCode::KeyAlreadyExists);
@@ -459,11 +695,11 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     != 0xFF
                 {
                     // We found a version, check that we support it
-                    if *region_data
-                        .get(offset + VERSION_OFFSET)
-                        .ok_or(ErrorCode::KeyNotFound)?
-                        != VERSION
-                    {
+                    if !supported_version(
+                        *region_data
+                            .get(offset + VERSION_OFFSET)
+                            .ok_or(ErrorCode::KeyNotFound)?,
+                    ) {
                         self.read_buffer.replace(Some(region_data));
                         return Err(ErrorCode::UnsupportedVersion);
                     }
@@ -593,12 +829,16 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     .get_mut(offset + HASH_OFFSET + 7)
                     .ok_or(ErrorCode::RegionFull)? = (header.hashed_key) as u8;
 
-                // Hash the new header data
-                check_sum.update(
-                    &region_data
+                // Hash the new header data. Objects written by a transaction
+                // are hashed as they will be once it commits.
+                let mut header_data = [0; HEADER_LENGTH];
+                header_data.copy_from_slice(
+                    region_data
                         .get(offset + VERSION_OFFSET..=offset + HASH_OFFSET + 7)
                         .ok_or(ErrorCode::CorruptData)?,
                 );
+                header_data[LEN_OFFSET] &= !(FLAGS_PENDING << 4);
+                check_sum.update(&header_data);
 
                 // Copy the value
                 let slice = region_data
@@ -690,7 +930,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                 };
             }
 
-            match self.find_key_offset(hash, region_data) {
+            match self.find_key_offset(hash, region_data, FLAGS_VALID) {
                 Ok((offset, total_length)) => {
                     // Add the header data to the check hash
                     check_sum.update(
@@ -781,12 +1021,21 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     ///
     /// `hash`: A hashed key.
     ///
+    /// While a transaction is open the key is only invalidated when the
+    /// transaction commits. Keys appended by the transaction can't be
+    /// invalidated by it, `KeyAlreadyExists` is returned for them.
+    ///
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
     ///
     /// If a power loss occurs before success is returned the data is
     /// assumed to be lost.
     pub fn invalidate_key(&self, hash: u64) -> Result<SuccessCode, ErrorCode> {
+        check_key(hash)?;
+        if self.transaction.get() == Transaction::Open {
+            return self.transaction_invalidate_key(hash);
+        }
+
         let region = self.get_region(hash);
 
         let mut region_offset: isize = 0;
@@ -820,7 +1069,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                 };
             }
 
-            match self.find_key_offset(hash, region_data) {
+            match self.find_key_offset(hash, region_data, FLAGS_VALID) {
                 Ok((offset, _data_len)) => {
                     // We found a key, let's delete it
                     *region_data
@@ -863,7 +1112,147 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         }
     }
 
-    fn garbage_collect_region(&self, region: usize) -> Result<usize, ErrorCode> {
+    /// Find the first valid key at or after `offset` in some loaded region
+    /// data.
+    ///
+    /// On success return the key, or None if there are no more keys in the
+    /// region.
+    fn find_next_key(
+        &self,
+        region: usize,
+        mut offset: usize,
+        region_data: &[u8],
+    ) -> Result<Option<KeyEntry>, ErrorCode> {
+        loop {
+            if offset + HEADER_LENGTH >= S {
+                // We have reached the end of the region
+                return Ok(None);
+            }
+
+            // Check to see if we have data
+            let version = *region_data
+                .get(offset + VERSION_OFFSET)
+                .ok_or(ErrorCode::CorruptData)?;
+            if version == 0xFF {
+                // We hit the end.
+                return Ok(None);
+            }
+
+            // We found a version, check that we support it
+            if !supported_version(version) {
+                return Err(ErrorCode::UnsupportedVersion);
+            }
+
+            // Find this entries length
+            let len_flags = *region_data
+                .get(offset + LEN_OFFSET)
+                .ok_or(ErrorCode::CorruptData)?;
+            let total_length = ((len_flags as u16) & !0xF0) << 8
+                | *region_data
+                    .get(offset + LEN_OFFSET + 1)
+                    .ok_or(ErrorCode::CorruptData)? as u16;
+
+            // Check to see if all fields are just 0
+            if total_length == 0 {
+                return Ok(None);
+            }
+
+            // Only return entries that haven't been deleted, and aren't
+            // part of a transaction
+            if len_flags >> 4 == FLAGS_VALID {
+                let mut hash = [0; 8];
+                hash.copy_from_slice(
+                    region_data
+                        .get(offset + HASH_OFFSET..offset + HEADER_LENGTH)
+                        .ok_or(ErrorCode::CorruptData)?,
+                );
+
+                return Ok(Some(KeyEntry {
+                    hashed_key: u64::from_be_bytes(hash),
+                    value_length: (total_length as usize)
+                        .checked_sub(HEADER_LENGTH + CHECK_SUM_LEN)
+                        .ok_or(ErrorCode::CorruptData)?,
+                    next: S * region + offset + total_length as usize,
+                }));
+            }
+
+            // Increment our offset by the length and repeat the loop
+            offset += total_length as usize;
+        }
+    }
+
+    /// Finds the first valid key stored at or after `cursor`.
+    ///
+    /// `cursor`: The position to start looking from. Use 0 to start from the
+    ///           beginning of the flash, and the `next` field of the last
+    ///           key found to continue from there.
+    ///
+    /// Keys are found in the order they are stored in flash, which is not
+    /// the order they were added in. Invalidated keys are skipped, the main
+    /// key is found like any other key.
+    ///
+    /// On success the `KeyEntry` will be returned.
+    /// On error a `ErrorCode` will be returned, `KeyNotFound` if there are
+    /// no more keys.
+    pub fn next_key(&self, cursor: usize) -> Result<KeyEntry, ErrorCode> {
+        let num_region = self.flash_size / S;
+
+        let (mut region, mut offset) = match self.state.get() {
+            State::None => (cursor / S, cursor % S),
+            State::NextKey(KeyState::ReadRegion(reg)) => {
+                if reg == cursor / S {
+                    (reg, cursor % S)
+                } else {
+                    // We moved on to a later region while reading
+                    (reg, 0)
+                }
+            }
+            _ => unreachable!(),
+        };
+
+        while region < num_region {
+            // Get the data from that region
+            let mut region_data = self.read_buffer.take().unwrap();
+            if self.state.get() != State::NextKey(KeyState::ReadRegion(region)) {
+                match self.controller.read_region(region, 0, &mut region_data) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        self.read_buffer.replace(Some(region_data));
+                        if let ErrorCode::ReadNotReady(reg) = e {
+                            self.state.set(State::NextKey(KeyState::ReadRegion(reg)));
+                        }
+                        return Err(e);
+                    }
+                };
+            }
+
+            let ret = self.find_next_key(region, offset, region_data);
+            self.read_buffer.replace(Some(region_data));
+
+            if let Some(entry) = ret? {
+                return Ok(entry);
+            }
+
+            region += 1;
+            offset = 0;
+        }
+
+        Err(ErrorCode::KeyNotFound)
+    }
+
+    /// Returns an iterator over all of the valid keys, in the same order
+    /// as `next_key()`.
+    ///
+    /// This can only be used with a `FlashController` that completes reads
+    /// synchronously. Otherwise use `next_key()` or `AsyncTicKV::next_key()`.
+    pub fn keys(&self) -> Keys<'_, 'a, C, S> {
+        Keys {
+            tickv: self,
+            cursor: Some(0),
+        }
+    }
+
+    fn collect_region(&self, region: usize) -> Result<usize, ErrorCode> {
         // Get the data from that region
         let mut region_data = self.read_buffer.take().unwrap();
         if self.state.get() != State::GarbageCollect(RubbishState::ReadRegion(region)) {
@@ -880,8 +1269,10 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
             };
         }
 
+        // The region header isn't an entry, it is written again after an
+        // erase
+        let (erase_count, mut offset) = self.read_region_header(region_data).unwrap_or((0, 0));
         let mut entry_found = false;
-        let mut offset: usize = 0;
 
         loop {
             if offset >= S {
@@ -897,11 +1288,11 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                 != 0xFF
             {
                 // We found a version, check that we support it
-                if *region_data
-                    .get(offset + VERSION_OFFSET)
-                    .ok_or(ErrorCode::KeyNotFound)?
-                    != VERSION
-                {
+                if !supported_version(
+                    *region_data
+                        .get(offset + VERSION_OFFSET)
+                        .ok_or(ErrorCode::KeyNotFound)?,
+                ) {
                     self.read_buffer.replace(Some(region_data));
                     return Err(ErrorCode::UnsupportedVersion);
                 }
@@ -958,33 +1349,93 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         if let Err(e) = self.controller.erase_region(region) {
             if let ErrorCode::EraseNotReady(reg) = e {
                 self.state
-                    .set(State::GarbageCollect(RubbishState::EraseRegion(reg)));
+                    .set(State::GarbageCollect(RubbishState::EraseRegion(
+                        reg,
+                        erase_count,
+                    )));
             }
             return Err(e);
         }
 
+        self.write_region_header(region, erase_count.saturating_add(1))?;
+
         Ok(S)
     }
 
-    /// Perform a garbage collection on TicKV
+    /// Write the header of `region`, which has just been erased.
+    fn write_region_header(&self, region: usize, erase_count: u32) -> Result<(), ErrorCode> {
+        let mut header = [0; REGION_HEADER_LENGTH];
+        header[VERSION_OFFSET] = VERSION;
+        // The header has no flags set, so it is never taken for a key
+        header[LEN_OFFSET] = (REGION_HEADER_LENGTH >> 8) as u8 & 0x0F;
+        header[LEN_OFFSET + 1] = (REGION_HEADER_LENGTH & 0xFF) as u8;
+        header[HASH_OFFSET..HEADER_LENGTH].copy_from_slice(&REGION_KEY.to_be_bytes());
+        header[HEADER_LENGTH..HEADER_LENGTH + 4].copy_from_slice(&erase_count.to_le_bytes());
+
+        let mut check_sum = crc32::Crc32::new();
+        check_sum.update(&header[..HEADER_LENGTH + 4]);
+        header[HEADER_LENGTH + 4..].copy_from_slice(&check_sum.finalise().to_ne_bytes());
+
+        if let Err(e) = self.controller.write(S * region, &header) {
+            if let ErrorCode::WriteNotReady(_) = e {
+                self.state
+                    .set(State::GarbageCollect(RubbishState::WriteHeader(region)));
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Read the header at the start of some loaded region data.
     ///
-    /// On success the number of bytes freed will be returned.
-    /// On error a `ErrorCode` will be returned.
-    pub fn garbage_collect(&self) -> Result<usize, ErrorCode> {
-        let num_region = self.flash_size / S;
+    /// Return the number of times the region has been erased and the length
+    /// of the header, or None if the region doesn't start with a header.
+    /// That is the case until the region is first garbage collected.
+    fn read_region_header(&self, region_data: &[u8]) -> Option<(u32, usize)> {
+        match self.read_object(region_data, 0) {
+            Ok(Some((len_flags, REGION_HEADER_LENGTH, REGION_KEY))) if len_flags >> 4 == 0 => {}
+            _ => return None,
+        }
+
+        // A header torn by a power loss is left as an invalid object
+        let mut check_sum = crc32::Crc32::new();
+        check_sum.update(region_data.get(..HEADER_LENGTH + 4)?);
+        if region_data.get(HEADER_LENGTH + 4..REGION_HEADER_LENGTH)?
+            != check_sum.finalise().to_ne_bytes()
+        {
+            return None;
+        }
+
+        let mut erase_count = [0; 4];
+        erase_count.copy_from_slice(region_data.get(HEADER_LENGTH..HEADER_LENGTH + 4)?);
+        Some((u32::from_le_bytes(erase_count), REGION_HEADER_LENGTH))
+    }
+
+    /// Garbage collect the regions from `first` to before `end`, or continue
+    /// the garbage collection in progress.
+    fn collect_regions(&self, first: usize, end: usize) -> Result<usize, ErrorCode> {
         let mut flash_freed = 0;
         let start = match self.state.get() {
-            State::None => 0,
+            State::None => {
+                self.collect_end.set(end);
+                first
+            }
             State::GarbageCollect(state) => match state {
                 RubbishState::ReadRegion(reg) => reg,
-                // We already erased region reg, so move to the next one
-                RubbishState::EraseRegion(reg) => reg + 1,
+                // We already erased region reg, so write its header and move
+                // to the next one
+                RubbishState::EraseRegion(reg, erase_count) => {
+                    self.write_region_header(reg, erase_count.saturating_add(1))?;
+                    reg + 1
+                }
+                RubbishState::WriteHeader(reg) => reg + 1,
             },
             _ => unreachable!(),
         };
 
-        for i in start..num_region {
-            match self.garbage_collect_region(i) {
+        for i in start..self.collect_end.get() {
+            match self.collect_region(i) {
                 Ok(freed) => flash_freed += freed,
                 Err(e) => return Err(e),
             }
@@ -992,4 +1443,581 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
 
         Ok(flash_freed)
     }
+
+    /// Perform a garbage collection on TicKV
+    ///
+    /// Every region whose objects are all invalid is erased, and given a
+    /// header counting the times it has been erased.
+    ///
+    /// On success the number of bytes freed will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn garbage_collect(&self) -> Result<usize, ErrorCode> {
+        self.collect_regions(0, self.flash_size / S)
+    }
+
+    /// Perform a garbage collection of a single region. Collecting one
+    /// region at a time keeps each call short, so other operations don't
+    /// have to wait for all of the flash to be read.
+    ///
+    /// `region`: The region to collect, from 0 to the number of regions.
+    ///
+    /// On success the number of bytes freed will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn garbage_collect_region(&self, region: usize) -> Result<usize, ErrorCode> {
+        if region >= self.flash_size / S {
+            return Err(ErrorCode::RegionNotFound);
+        }
+
+        self.collect_regions(region, region + 1)
+    }
+
+    /// Find how much space is free, used and invalid, and how many times
+    /// the regions have been erased.
+    ///
+    /// On success the `Statistics` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn statistics(&self) -> Result<Statistics, ErrorCode> {
+        let num_region = self.flash_size / S;
+        let start = match self.state.get() {
+            State::None => {
+                self.statistics.set(Statistics::NONE);
+                0
+            }
+            State::Statistics(KeyState::ReadRegion(reg)) => reg,
+            _ => unreachable!(),
+        };
+
+        for region in start..num_region {
+            let region_data =
+                self.load_region(region, State::Statistics(KeyState::ReadRegion(region)))?;
+            let ret = self.region_statistics(region_data);
+            self.read_buffer.replace(Some(region_data));
+            let region_stats = ret?;
+
+            let mut stats = self.statistics.get();
+            stats.free += region_stats.free;
+            stats.used += region_stats.used;
+            stats.invalid += region_stats.invalid;
+            stats.min_erase_count = stats.min_erase_count.min(region_stats.min_erase_count);
+            stats.max_erase_count = stats.max_erase_count.max(region_stats.max_erase_count);
+            self.statistics.set(stats);
+        }
+
+        Ok(self.statistics.get())
+    }
+
+    /// Find the statistics of some loaded region data.
+    fn region_statistics(&self, region_data: &[u8]) -> Result<Statistics, ErrorCode> {
+        let (erase_count, mut offset) = self.read_region_header(region_data).unwrap_or((0, 0));
+        let mut stats = Statistics {
+            free: 0,
+            used: offset,
+            invalid: 0,
+            min_erase_count: erase_count,
+            max_erase_count: erase_count,
+        };
+
+        while let Some((len_flags, total_length, _)) = self.read_object(region_data, offset)? {
+            if len_flags & (FLAGS_VALID << 4) != 0 {
+                stats.used += total_length;
+            } else {
+                stats.invalid += total_length;
+            }
+            offset += total_length;
+        }
+        stats.free = S.saturating_sub(offset);
+
+        Ok(stats)
+    }
+
+    /// Read `region` into the read buffer, unless the state shows that an
+    /// async read of it has completed.
+    fn load_region(&self, region: usize, state: State) -> Result<&'a mut [u8; S], ErrorCode> {
+        let region_data = self.read_buffer.take().unwrap();
+        if self.state.get() != state {
+            if let Err(e) = self.controller.read_region(region, 0, region_data) {
+                self.read_buffer.replace(Some(region_data));
+                if let ErrorCode::ReadNotReady(_) = e {
+                    self.state.set(state);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(region_data)
+    }
+
+    /// Read the header of the object at `offset` in some loaded region data.
+    ///
+    /// On success return the byte holding the flags, the total length and
+    /// the hashed key of the object, or None if there are no more objects.
+    fn read_object(
+        &self,
+        region_data: &[u8],
+        offset: usize,
+    ) -> Result<Option<(u8, usize, u64)>, ErrorCode> {
+        if offset + HEADER_LENGTH >= S {
+            // We have reached the end of the region
+            return Ok(None);
+        }
+
+        let version = *region_data
+            .get(offset + VERSION_OFFSET)
+            .ok_or(ErrorCode::CorruptData)?;
+        if version == 0xFF {
+            // We hit the end.
+            return Ok(None);
+        }
+        if !supported_version(version) {
+            return Err(ErrorCode::UnsupportedVersion);
+        }
+
+        let len_flags = *region_data
+            .get(offset + LEN_OFFSET)
+            .ok_or(ErrorCode::CorruptData)?;
+        let total_length = ((len_flags as usize) & !0xF0) << 8
+            | *region_data
+                .get(offset + LEN_OFFSET + 1)
+                .ok_or(ErrorCode::CorruptData)? as usize;
+        if total_length == 0 {
+            return Ok(None);
+        }
+
+        let mut hash = [0; 8];
+        hash.copy_from_slice(
+            region_data
+                .get(offset + HASH_OFFSET..offset + HEADER_LENGTH)
+                .ok_or(ErrorCode::CorruptData)?,
+        );
+
+        Ok(Some((len_flags, total_length, u64::from_be_bytes(hash))))
+    }
+
+    /// Read the regions `hash` can be stored in, in the same order as
+    /// `get_key()`, calling `f` with the address and flags byte of each
+    /// valid object with that hash. Stops early if `f` returns true.
+    ///
+    /// `start`: The region to resume from after an async read.
+    fn probe(
+        &self,
+        hash: u64,
+        start: Option<usize>,
+        read_state: fn(KeyState) -> State,
+        mut f: impl FnMut(usize, u8) -> bool,
+    ) -> Result<(), ErrorCode> {
+        let region = self.get_region(hash);
+        let mut new_region = start.unwrap_or(region) as isize;
+
+        loop {
+            let region_data = self.load_region(
+                new_region as usize,
+                read_state(KeyState::ReadRegion(new_region as usize)),
+            )?;
+            self.state.set(State::None);
+
+            let mut offset = 0;
+            let mut found = false;
+            let ret = loop {
+                match self.read_object(region_data, offset) {
+                    Ok(Some((len_flags, total_length, object_hash))) => {
+                        if object_hash == hash
+                            && len_flags & (FLAGS_VALID << 4) != 0
+                            && f(S * new_region as usize + offset, len_flags)
+                        {
+                            found = true;
+                            break Ok(());
+                        }
+                        offset += total_length;
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            // Like `find_key_offset()`, only keep looking after a region
+            // that has objects and still has space left
+            let cont = offset != 0 && offset + HEADER_LENGTH < S;
+            self.read_buffer.replace(Some(region_data));
+            ret?;
+
+            if found || !cont {
+                return Ok(());
+            }
+            match self.increment_region_offset(new_region) {
+                Some(o) => new_region = region as isize + o,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Find what the open transaction does with `hash`, reading all of the
+    /// regions it can be stored in.
+    fn find_transaction_keys(
+        &self,
+        hash: u64,
+        read_state: fn(KeyState) -> State,
+    ) -> Result<TransactionKeys, ErrorCode> {
+        let start = match self.state.get() {
+            State::AppendKey(KeyState::ReadRegion(reg))
+            | State::InvalidateKey(KeyState::ReadRegion(reg)) => Some(reg),
+            _ => {
+                self.transaction_keys.set(TransactionKeys::NONE);
+                None
+            }
+        };
+
+        let mut keys = self.transaction_keys.get();
+        let ret = self.probe(hash, start, read_state, |address, len_flags| {
+            let flags = len_flags >> 4;
+            if flags == FLAGS_VALID {
+                keys.key = Some(Object { address, len_flags });
+            } else if flags == FLAGS_VALID | FLAGS_PENDING {
+                keys.appended = true;
+            } else if flags == FLAGS_VALID | FLAGS_PENDING | FLAGS_INVALIDATE {
+                keys.invalidated = true;
+            }
+            false
+        });
+        self.transaction_keys.set(keys);
+
+        ret.map(|()| keys)
+    }
+
+    fn transaction_append_key(&self, hash: u64, value: &[u8]) -> Result<SuccessCode, ErrorCode> {
+        if !self.transaction_keys.get().complete {
+            let keys = self.find_transaction_keys(hash, State::AppendKey)?;
+            if keys.appended || (keys.key.is_some() && !keys.invalidated) {
+                return Err(ErrorCode::KeyAlreadyExists);
+            }
+            self.transaction_keys.set(TransactionKeys {
+                complete: true,
+                ..keys
+            });
+        }
+
+        let ret = self.append_object(hash, value, FLAGS_VALID | FLAGS_PENDING, State::AppendKey);
+        if !matches!(ret, Err(ErrorCode::ReadNotReady(_))) {
+            self.transaction_keys.set(TransactionKeys::NONE);
+        }
+        ret
+    }
+
+    fn transaction_invalidate_key(&self, hash: u64) -> Result<SuccessCode, ErrorCode> {
+        let mut keys = self.transaction_keys.get();
+        if !keys.complete {
+            keys = self.find_transaction_keys(hash, State::InvalidateKey)?;
+            keys.complete = true;
+            self.transaction_keys.set(keys);
+        }
+
+        let target = match keys.key {
+            _ if keys.appended => Err(ErrorCode::KeyAlreadyExists),
+            Some(target) if !keys.invalidated => Ok(target),
+            _ => Err(ErrorCode::KeyNotFound),
+        };
+        let ret = target.and_then(|target| {
+            // The invalidation object stores where the key is, so that it can
+            // be invalidated without looking for it again
+            let mut value = [0; INVALIDATE_LENGTH];
+            value[..4].copy_from_slice(&(target.address as u32).to_le_bytes());
+            value[4] = target.len_flags;
+
+            self.append_object(
+                hash,
+                &value,
+                FLAGS_VALID | FLAGS_PENDING | FLAGS_INVALIDATE,
+                State::InvalidateKey,
+            )
+        });
+        if !matches!(ret, Err(ErrorCode::ReadNotReady(_))) {
+            self.transaction_keys.set(TransactionKeys::NONE);
+        }
+        ret
+    }
+
+    /// Starts a transaction. Until it is committed or aborted, the keys
+    /// appended and invalidated are only changed when it commits, and all
+    /// of them are changed or none of them are, even if power is lost.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned, `TransactionInProgress` if
+    /// a transaction is already open.
+    pub fn start_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.transaction.get() != Transaction::None {
+            return Err(ErrorCode::TransactionInProgress);
+        }
+
+        let ret = self.append_object(
+            TRANSACTION_KEY,
+            &[],
+            FLAGS_VALID | FLAGS_PENDING | FLAGS_TRANSACTION,
+            |key_state| State::Transaction(TransactionState::Start(key_state)),
+        );
+        if ret.is_ok() {
+            self.transaction.set(Transaction::Open);
+        }
+        ret
+    }
+
+    /// Commits the open transaction, changing all of its keys.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned, `NoTransaction` if no
+    /// transaction is open. If power is lost after the transaction is
+    /// committed, `initialise()` finishes changing the keys.
+    pub fn commit_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.transaction.get() != Transaction::Open {
+            return Err(ErrorCode::NoTransaction);
+        }
+
+        self.transaction
+            .set(Transaction::Resolve(Resolve::FindMarker(Some(true))));
+        self.resolve_transaction()
+    }
+
+    /// Aborts the open transaction, leaving all of its keys unchanged.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned, `NoTransaction` if no
+    /// transaction is open.
+    pub fn abort_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        if self.transaction.get() != Transaction::Open {
+            return Err(ErrorCode::NoTransaction);
+        }
+
+        self.transaction
+            .set(Transaction::Resolve(Resolve::FindMarker(Some(false))));
+        self.resolve_transaction()
+    }
+
+    /// Find the first pending object in some loaded region data.
+    ///
+    /// On success return the object and, for invalidation objects, the
+    /// object they invalidate.
+    fn find_pending_object(
+        &self,
+        region: usize,
+        region_data: &[u8],
+    ) -> Result<Option<(Object, Option<Object>)>, ErrorCode> {
+        let mut offset = 0;
+
+        while let Some((len_flags, total_length, _)) = self.read_object(region_data, offset)? {
+            let flags = len_flags >> 4;
+            if flags & (FLAGS_VALID | FLAGS_PENDING | FLAGS_TRANSACTION)
+                == FLAGS_VALID | FLAGS_PENDING
+            {
+                let object = Object {
+                    address: S * region + offset,
+                    len_flags,
+                };
+                if flags & FLAGS_INVALIDATE == 0 {
+                    return Ok(Some((object, None)));
+                }
+
+                let value = region_data
+                    .get(offset + HEADER_LENGTH..offset + HEADER_LENGTH + INVALIDATE_LENGTH)
+                    .ok_or(ErrorCode::CorruptData)?;
+                let mut address = [0; 4];
+                address.copy_from_slice(&value[..4]);
+                let target = Object {
+                    address: u32::from_le_bytes(address) as usize,
+                    len_flags: value[4],
+                };
+
+                return Ok(Some((object, Some(target))));
+            }
+
+            offset += total_length;
+        }
+
+        Ok(None)
+    }
+
+    /// Write the byte holding the flags of the object at `address`, while
+    /// resolving a transaction.
+    fn write_len_flags(&self, address: usize, len_flags: u8) -> Result<(), ErrorCode> {
+        let ret = self.controller.write(address + LEN_OFFSET, &[len_flags]);
+        if let Err(ErrorCode::WriteNotReady(_)) = ret {
+            self.state.set(State::Transaction(TransactionState::Write));
+        }
+        ret
+    }
+
+    /// Commit, roll back or recover a transaction, depending on
+    /// `self.transaction`.
+    ///
+    /// Every write only clears flags, and objects are found again after
+    /// each one, so this can be repeated after a power loss at any point.
+    /// The object being written to is always in the last region read.
+    ///
+    /// A write that doesn't complete synchronously returns `WriteNotReady`,
+    /// the operation continues from `continue_operation()` once it has.
+    pub(crate) fn resolve_transaction(&self) -> Result<SuccessCode, ErrorCode> {
+        let num_region = self.flash_size / S;
+        let read_state = |key_state| State::Transaction(TransactionState::Resolve(key_state));
+
+        loop {
+            let resolve = match self.transaction.get() {
+                Transaction::Resolve(resolve) => resolve,
+                _ => return Err(ErrorCode::NoTransaction),
+            };
+
+            match resolve {
+                Resolve::FindMarker(commit) => {
+                    let start = match self.state.get() {
+                        State::Transaction(TransactionState::Resolve(KeyState::ReadRegion(
+                            reg,
+                        ))) => Some(reg),
+                        _ => None,
+                    };
+
+                    let mut marker = None;
+                    self.probe(TRANSACTION_KEY, start, read_state, |address, len_flags| {
+                        if (len_flags >> 4) & !FLAGS_PENDING == FLAGS_VALID | FLAGS_TRANSACTION {
+                            marker = Some(Object { address, len_flags });
+                        }
+                        marker.is_some()
+                    })?;
+
+                    let committed =
+                        marker.map(|marker| marker.len_flags & (FLAGS_PENDING << 4) == 0);
+                    let next = match (commit, committed) {
+                        // Nothing to recover
+                        (None, None) => Resolve::Marker(None),
+                        (Some(true), Some(false)) => Resolve::Commit(marker.unwrap()),
+                        (Some(commit), _) | (None, Some(commit)) => Resolve::Region {
+                            commit,
+                            marker,
+                            region: 0,
+                            target_done: None,
+                        },
+                    };
+                    self.transaction.set(Transaction::Resolve(next));
+                }
+                Resolve::Commit(marker) => {
+                    let region = marker.address / S;
+                    let region_data =
+                        self.load_region(region, read_state(KeyState::ReadRegion(region)))?;
+                    self.read_buffer.replace(Some(region_data));
+                    self.state.set(State::None);
+
+                    // This is the write that commits the transaction
+                    let len_flags = marker.len_flags & !(FLAGS_PENDING << 4);
+                    self.transaction.set(Transaction::Resolve(Resolve::Region {
+                        commit: true,
+                        marker: Some(Object {
+                            address: marker.address,
+                            len_flags,
+                        }),
+                        region: 0,
+                        target_done: None,
+                    }));
+                    self.write_len_flags(marker.address, len_flags)?;
+                }
+                Resolve::Region { marker, region, .. } if region >= num_region => {
+                    self.transaction
+                        .set(Transaction::Resolve(Resolve::Marker(marker)));
+                }
+                Resolve::Region {
+                    commit,
+                    marker,
+                    region,
+                    target_done,
+                } => {
+                    let region_data =
+                        self.load_region(region, read_state(KeyState::ReadRegion(region)))?;
+                    self.state.set(State::None);
+                    let ret = self.find_pending_object(region, region_data);
+                    self.read_buffer.replace(Some(region_data));
+
+                    match ret? {
+                        None => {
+                            self.transaction.set(Transaction::Resolve(Resolve::Region {
+                                commit,
+                                marker,
+                                region: region + 1,
+                                target_done: None,
+                            }));
+                        }
+                        Some((object, Some(target)))
+                            if commit && target_done != Some(object.address) =>
+                        {
+                            self.transaction.set(Transaction::Resolve(Resolve::Target {
+                                marker,
+                                region,
+                                object: object.address,
+                                target,
+                            }));
+                        }
+                        Some((object, target)) => {
+                            // Appended keys become valid on commit, everything
+                            // else is invalidated
+                            let len_flags = if commit && target.is_none() {
+                                object.len_flags & !(FLAGS_PENDING << 4)
+                            } else {
+                                object.len_flags & !(FLAGS_VALID << 4)
+                            };
+                            self.transaction.set(Transaction::Resolve(Resolve::Region {
+                                commit,
+                                marker,
+                                region,
+                                target_done: None,
+                            }));
+                            self.write_len_flags(object.address, len_flags)?;
+                        }
+                    }
+                }
+                Resolve::Target {
+                    marker,
+                    region,
+                    object,
+                    target,
+                } => {
+                    let target_region = target.address / S;
+                    let region_data = self.load_region(
+                        target_region,
+                        read_state(KeyState::ReadRegion(target_region)),
+                    )?;
+                    self.state.set(State::None);
+                    let len_flags = region_data.get(target.address % S + LEN_OFFSET).copied();
+                    self.read_buffer.replace(Some(region_data));
+
+                    self.transaction.set(Transaction::Resolve(Resolve::Region {
+                        commit: true,
+                        marker,
+                        region,
+                        target_done: Some(object),
+                    }));
+                    // Don't write again if the key was invalidated before a
+                    // power loss
+                    if len_flags == Some(target.len_flags) {
+                        self.write_len_flags(
+                            target.address,
+                            target.len_flags & !(FLAGS_VALID << 4),
+                        )?;
+                    }
+                }
+                Resolve::Marker(None) => {
+                    self.transaction.set(Transaction::None);
+                    return Ok(SuccessCode::Complete);
+                }
+                Resolve::Marker(Some(marker)) => {
+                    let region = marker.address / S;
+                    let region_data =
+                        self.load_region(region, read_state(KeyState::ReadRegion(region)))?;
+                    self.read_buffer.replace(Some(region_data));
+                    self.state.set(State::None);
+
+                    self.transaction.set(Transaction::None);
+                    return match self.controller.write(
+                        marker.address + LEN_OFFSET,
+                        &[marker.len_flags & !(FLAGS_VALID << 4)],
+                    ) {
+                        Ok(()) => Ok(SuccessCode::Written),
+                        Err(ErrorCode::WriteNotReady(_)) => Ok(SuccessCode::Queued),
+                        Err(e) => Err(e),
+                    };
+                }
+            }
+        }
+    }
 }
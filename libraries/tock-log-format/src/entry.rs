@@ -0,0 +1,180 @@
+//! Timestamped log entries.
+//!
+//! An entry starts with a header, in little endian:
+//!
+//! ```text
+//! 0      1      2             6                     14
+//! +------+------+-------------+---------------------+---------
+//! | ver  | flag | sequence    | timestamp (us)      | payload
+//! +------+------+-------------+---------------------+---------
+//! ```
+//!
+//! - `ver` is the version of the format, `VERSION`.
+//! - `flag` has `FLAG_COMPRESSED` set if the payload is compressed with
+//!   [`crate::lz`].
+//! - `sequence` counts the entries appended since boot, from 0, so it starts
+//!   again at 0 after each reboot.
+//! - `timestamp` is the time since boot the entry was appended at, in
+//!   microseconds.
+
+use crate::lz;
+use crate::Error;
+
+/// The version of the format of entries.
+pub const VERSION: u8 = 1;
+/// The payload is compressed.
+pub const FLAG_COMPRESSED: u8 = 0x01;
+/// The length of the header of an entry.
+pub const HEADER_SIZE: usize = 14;
+
+/// The header of an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The number of entries appended before this one since boot
+    pub sequence: u32,
+    /// The time since boot the entry was appended at, in microseconds
+    pub timestamp_us: u64,
+    /// Whether the payload is compressed
+    pub compressed: bool,
+}
+
+impl Header {
+    /// Parses the header at the start of `entry`, returning it and the
+    /// payload after it.
+    pub fn parse(entry: &[u8]) -> Result<(Header, &[u8]), Error> {
+        if entry.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if entry[0] != VERSION {
+            return Err(Error::UnknownVersion(entry[0]));
+        }
+        let mut sequence = [0; 4];
+        sequence.copy_from_slice(&entry[2..6]);
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&entry[6..14]);
+        let header = Header {
+            sequence: u32::from_le_bytes(sequence),
+            timestamp_us: u64::from_le_bytes(timestamp),
+            compressed: entry[1] & FLAG_COMPRESSED != 0,
+        };
+        Ok((header, &entry[HEADER_SIZE..]))
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer[0] = VERSION;
+        buffer[1] = if self.compressed { FLAG_COMPRESSED } else { 0 };
+        buffer[2..6].copy_from_slice(&self.sequence.to_le_bytes());
+        buffer[6..14].copy_from_slice(&self.timestamp_us.to_le_bytes());
+    }
+}
+
+/// Encodes an entry of `payload` into `output`, returning the length of the
+/// entry. If `compress` is set, the payload is compressed if that makes the
+/// entry shorter. The `compressed` field of `header` is ignored.
+pub fn encode(
+    mut header: Header,
+    payload: &[u8],
+    compress: bool,
+    output: &mut [u8],
+) -> Result<usize, Error> {
+    if output.len() < HEADER_SIZE {
+        return Err(Error::NoSpace);
+    }
+    let (head, body) = output.split_at_mut(HEADER_SIZE);
+
+    // Compressing must save at least a byte.
+    let limit = body.len().min(payload.len().saturating_sub(1));
+    let compressed = if compress {
+        lz::compress(payload, &mut body[..limit])
+    } else {
+        None
+    };
+    let length = match compressed {
+        Some(length) => length,
+        None => {
+            body.get_mut(..payload.len())
+                .ok_or(Error::NoSpace)?
+                .copy_from_slice(payload);
+            payload.len()
+        }
+    };
+
+    header.compressed = compressed.is_some();
+    header.write(head);
+    Ok(HEADER_SIZE + length)
+}
+
+/// Decodes `entry` into its header and its payload, which is written to
+/// `output`. Returns the header and the length of the payload.
+pub fn decode(entry: &[u8], output: &mut [u8]) -> Result<(Header, usize), Error> {
+    let (header, body) = Header::parse(entry)?;
+    let length = if header.compressed {
+        lz::decompress(body, output)?
+    } else {
+        output
+            .get_mut(..body.len())
+            .ok_or(Error::NoSpace)?
+            .copy_from_slice(body);
+        body.len()
+    };
+    Ok((header, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: Header = Header {
+        sequence: 7,
+        timestamp_us: 0x0123_4567_89AB,
+        compressed: false,
+    };
+
+    #[test]
+    fn encodes_headers() {
+        let mut entry = [0; 32];
+        assert_eq!(encode(HEADER, b"data", false, &mut entry), Ok(18));
+        assert_eq!(
+            entry[..18],
+            [1, 0, 7, 0, 0, 0, 0xAB, 0x89, 0x67, 0x45, 0x23, 0x01, 0, 0, b'd', b'a', b't', b'a']
+        );
+
+        let mut payload = [0; 8];
+        assert_eq!(decode(&entry[..18], &mut payload), Ok((HEADER, 4)));
+        assert_eq!(&payload[..4], b"data");
+    }
+
+    #[test]
+    fn compresses_when_it_helps() {
+        let mut entry = [0; 64];
+        // Too short to compress
+        let length = encode(HEADER, b"abc", true, &mut entry).unwrap();
+        assert_eq!(length, HEADER_SIZE + 3);
+        assert_eq!(Header::parse(&entry).unwrap().0, HEADER);
+
+        let payload = [b'x'; 100];
+        let length = encode(HEADER, &payload, true, &mut entry).unwrap();
+        assert!(length < 32);
+        let mut output = [0; 100];
+        let (header, decoded) = decode(&entry[..length], &mut output).unwrap();
+        assert!(header.compressed);
+        assert_eq!(header.sequence, 7);
+        assert_eq!(output[..decoded], payload);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut entry = [0; 16];
+        assert_eq!(
+            encode(HEADER, b"too long", false, &mut entry),
+            Err(Error::NoSpace)
+        );
+        assert_eq!(encode(HEADER, b"ok", false, &mut entry), Ok(16));
+
+        let mut output = [0; 1];
+        assert_eq!(decode(&entry, &mut output), Err(Error::NoSpace));
+        assert_eq!(decode(&entry[..10], &mut output), Err(Error::Truncated));
+        entry[0] = 2;
+        assert_eq!(decode(&entry, &mut output), Err(Error::UnknownVersion(2)));
+    }
+}
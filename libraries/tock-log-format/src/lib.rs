@@ -0,0 +1,36 @@
+//! Formats of the Tock flash log, shared by the kernel and host tools.
+//!
+//! - [`volume`] reads the entries of a raw image of a log volume, as written
+//!   by the `Log` capsule, in order from the oldest to the newest.
+//! - [`entry`] is an optional format for the data of entries, which records
+//!   when an entry was appended and its sequence number since boot, and can
+//!   compress the payload.
+//! - [`lz`] is the small LZ77 codec entries are compressed with.
+//!
+//! The crate is `no_std` and does not allocate.
+
+#![no_std]
+
+pub mod entry;
+pub mod lz;
+pub mod volume;
+
+/// The errors of decoding and encoding entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The input ended in the middle of a header or compressed stream.
+    Truncated,
+    /// The entry has a format version this crate does not know.
+    UnknownVersion(u8),
+    /// The compressed stream refers to data before its start.
+    Corrupt,
+    /// The output buffer is too small.
+    NoSpace,
+    /// The page or word size does not fit the volume.
+    Geometry,
+}
+
+// This is used to run the tests on a host
+#[cfg(test)]
+#[macro_use]
+extern crate std;
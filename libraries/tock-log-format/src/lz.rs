@@ -0,0 +1,196 @@
+//! A small LZ77 codec for log entries.
+//!
+//! The compressed stream is a sequence of tokens:
+//!
+//! - `0LLLLLLL`, followed by `L + 1` literal bytes.
+//! - `1LLLLOOO OOOOOOOO`, a copy of `L + MIN_MATCH` bytes from
+//!   `O + 1` bytes back in the output. The copy may overlap the bytes it
+//!   produces, which encodes runs.
+//!
+//! The compressor finds matches through a small hash table on the stack, so
+//! it needs no memory besides its input and output, and runs in time linear
+//! in the length of its input.
+
+use crate::Error;
+
+/// The shortest match which is encoded as a copy.
+pub const MIN_MATCH: usize = 3;
+/// The longest match a single copy encodes.
+pub const MAX_MATCH: usize = MIN_MATCH + 0x0F;
+/// How far back a copy can reach.
+pub const WINDOW: usize = 0x800;
+/// The most literal bytes a single token introduces.
+const MAX_LITERALS: usize = 0x80;
+
+const HASH_BITS: u32 = 7;
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+    (value.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+// Appends `literals` to `output` at `out`, returning the new end of the
+// output, or `None` if it does not fit.
+fn put_literals(literals: &[u8], output: &mut [u8], mut out: usize) -> Option<usize> {
+    for run in literals.chunks(MAX_LITERALS) {
+        let end = out + 1 + run.len();
+        let dest = output.get_mut(out..end)?;
+        dest[0] = (run.len() - 1) as u8;
+        dest[1..].copy_from_slice(run);
+        out = end;
+    }
+    Some(out)
+}
+
+/// Compresses `input` into `output`, returning the length of the compressed
+/// stream, or `None` if it does not fit in `output`.
+///
+/// Inputs longer than 64 KiB are not compressed.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    if input.len() > usize::from(u16::MAX) {
+        return None;
+    }
+
+    // The position of the last occurrence of each hash, plus one.
+    let mut table = [0u16; 1 << HASH_BITS];
+    let mut out = 0;
+    let mut literals = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let slot = &mut table[hash(&input[pos..])];
+        let candidate = usize::from(*slot);
+        *slot = (pos + 1) as u16;
+
+        if candidate > 0 && pos + 1 - candidate <= WINDOW {
+            let start = candidate - 1;
+            let length = input[pos..]
+                .iter()
+                .zip(&input[start..])
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length >= MIN_MATCH {
+                out = put_literals(&input[literals..pos], output, out)?;
+                let offset = pos - start - 1;
+                let copy = output.get_mut(out..out + 2)?;
+                copy[0] = 0x80 | ((length - MIN_MATCH) << 3) as u8 | (offset >> 8) as u8;
+                copy[1] = offset as u8;
+                out += 2;
+                pos += length;
+                literals = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    put_literals(&input[literals..], output, out)
+}
+
+/// Decompresses `input` into `output`, returning the length of the
+/// decompressed data.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    let mut pos = 0;
+    let mut out = 0;
+    while pos < input.len() {
+        let token = input[pos];
+        pos += 1;
+        if token & 0x80 == 0 {
+            let length = usize::from(token) + 1;
+            let literals = input.get(pos..pos + length).ok_or(Error::Truncated)?;
+            output
+                .get_mut(out..out + length)
+                .ok_or(Error::NoSpace)?
+                .copy_from_slice(literals);
+            pos += length;
+            out += length;
+        } else {
+            let low = *input.get(pos).ok_or(Error::Truncated)?;
+            pos += 1;
+            let length = usize::from((token >> 3) & 0x0F) + MIN_MATCH;
+            let offset = (usize::from(token & 0x07) << 8 | usize::from(low)) + 1;
+            if offset > out {
+                return Err(Error::Corrupt);
+            }
+            if out + length > output.len() {
+                return Err(Error::NoSpace);
+            }
+            // Byte by byte, as the copy may overlap what it produces.
+            for i in out..out + length {
+                output[i] = output[i - offset];
+            }
+            out += length;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn round_trip(input: &[u8]) -> usize {
+        let mut compressed = vec![0; input.len() * 2 + 2];
+        let length = compress(input, &mut compressed).unwrap();
+        let mut output = vec![0; input.len()];
+        assert_eq!(
+            decompress(&compressed[..length], &mut output),
+            Ok(input.len())
+        );
+        assert_eq!(output, input);
+        length
+    }
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(round_trip(b""), 0);
+        assert_eq!(round_trip(b"ab"), 3);
+        round_trip(b"abcabcabcabcabcabcabcabcabcabcabcabcabcabcabc");
+
+        // Data which does not compress, longer than a literal run
+        let noise: Vec<u8> = (0..1000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        round_trip(&noise);
+    }
+
+    #[test]
+    fn compresses_repetitive_data() {
+        // A run is a literal and overlapping copies.
+        assert!(round_trip(&[0x55; 200]) < 30);
+
+        let text = b"temperature=21.5C humidity=40% temperature=21.6C humidity=41% \
+                     temperature=21.6C humidity=41% temperature=21.7C humidity=40%";
+        assert!(round_trip(text) < text.len() / 2);
+    }
+
+    #[test]
+    fn reports_small_outputs() {
+        let mut output = [0; 4];
+        assert_eq!(compress(b"abcdefgh", &mut output), None);
+
+        let mut compressed = [0; 16];
+        let length = compress(&[7; 100], &mut compressed).unwrap();
+        assert_eq!(
+            decompress(&compressed[..length], &mut [0; 99]),
+            Err(Error::NoSpace)
+        );
+    }
+
+    #[test]
+    fn rejects_broken_streams() {
+        let mut output = [0; 64];
+        // A literal run longer than the stream
+        assert_eq!(decompress(&[5, 1, 2], &mut output), Err(Error::Truncated));
+        // A copy without its second byte
+        assert_eq!(
+            decompress(&[0, 1, 0x80], &mut output),
+            Err(Error::Truncated)
+        );
+        // A copy from before the start
+        assert_eq!(
+            decompress(&[0, 1, 0x80, 1], &mut output),
+            Err(Error::Corrupt)
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Reading raw images of log volumes.
+//!
+//! A volume is a sequence of flash pages. Each page starts with a header of
+//! one word, the position of the page in the log: its offset from the start
+//! of the log, which keeps growing as a circular log wraps around. The rest of
+//! the page holds entries, each a word with the length of the entry followed
+//! by its data, and is padded with `PAD_BYTE` after the last entry. Words are
+//! as wide as a `usize` of the target which wrote the log, and little endian.
+//!
+//! The ID of an entry is the position of the page it is in plus its offset in
+//! the page, as for the `Log` capsule, so entries are read in order of their
+//! IDs however many times the log wrapped around.
+
+use crate::Error;
+
+/// Pads the end of each page.
+pub const PAD_BYTE: u8 = 0xFF;
+
+/// An entry, as it is in the volume.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawEntry<'a> {
+    /// The ID of the entry, which is its position in the log
+    pub id: u64,
+    pub data: &'a [u8],
+}
+
+/// An image of a log volume.
+#[derive(Clone, Copy)]
+pub struct Volume<'a> {
+    image: &'a [u8],
+    page_size: usize,
+    word_size: usize,
+}
+
+impl<'a> Volume<'a> {
+    /// `image` holds the whole volume, written by a target with words of
+    /// `word_size` bytes, which must be 4 or 8.
+    pub fn new(image: &'a [u8], page_size: usize, word_size: usize) -> Result<Volume<'a>, Error> {
+        if (word_size != 4 && word_size != 8)
+            || page_size <= 2 * word_size
+            || image.is_empty()
+            || image.len() % page_size != 0
+        {
+            return Err(Error::Geometry);
+        }
+        Ok(Volume {
+            image,
+            page_size,
+            word_size,
+        })
+    }
+
+    fn word(&self, at: usize) -> u64 {
+        self.image[at..at + self.word_size]
+            .iter()
+            .rev()
+            .fold(0, |word, byte| word << 8 | u64::from(*byte))
+    }
+
+    /// The position in the log of the page at `index`, if it holds part of
+    /// the log.
+    fn page_id(&self, index: usize) -> Option<u64> {
+        let start = index * self.page_size;
+        let id = self.word(start);
+        (id % self.image.len() as u64 == start as u64).then(|| id)
+    }
+
+    /// The index and position of the first page of the log after the one at
+    /// position `after`.
+    fn next_page(&self, after: Option<u64>) -> Option<(usize, u64)> {
+        (0..self.image.len() / self.page_size)
+            .filter_map(|index| self.page_id(index).map(|id| (index, id)))
+            .filter(|(_, id)| after.map_or(true, |after| *id > after))
+            .min_by_key(|(_, id)| *id)
+    }
+
+    /// The entries of the log, from the oldest to the newest.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            volume: *self,
+            page: self.next_page(None),
+            offset: self.word_size,
+        }
+    }
+}
+
+/// Iterates over the entries of a volume.
+pub struct Entries<'a> {
+    volume: Volume<'a>,
+    /// The index and position of the page being read
+    page: Option<(usize, u64)>,
+    /// The offset of the next entry in the page
+    offset: usize,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = RawEntry<'a>;
+
+    fn next(&mut self) -> Option<RawEntry<'a>> {
+        let volume = self.volume;
+        let word_size = volume.word_size;
+        loop {
+            let (index, id) = self.page?;
+            let start = index * volume.page_size;
+            let data_start = self.offset + word_size;
+            if data_start <= volume.page_size && volume.image[start + self.offset] != PAD_BYTE {
+                let length = volume.word(start + self.offset);
+                if length > 0 && length <= (volume.page_size - data_start) as u64 {
+                    let end = data_start + length as usize;
+                    let entry = RawEntry {
+                        id: id + self.offset as u64,
+                        data: &volume.image[start + data_start..start + end],
+                    };
+                    self.offset = end;
+                    return Some(entry);
+                }
+            }
+            self.page = volume.next_page(Some(id));
+            self.offset = word_size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 32;
+
+    // Writes a page at `index` with the position `id` and `entries`.
+    fn write_page(image: &mut [u8], index: usize, id: u32, entries: &[&[u8]]) {
+        let page = &mut image[index * PAGE_SIZE..(index + 1) * PAGE_SIZE];
+        page.fill(PAD_BYTE);
+        page[..4].copy_from_slice(&id.to_le_bytes());
+        let mut offset = 4;
+        for entry in entries {
+            page[offset..offset + 4].copy_from_slice(&(entry.len() as u32).to_le_bytes());
+            page[offset + 4..offset + 4 + entry.len()].copy_from_slice(entry);
+            offset += 4 + entry.len();
+        }
+    }
+
+    fn entries(image: &[u8]) -> Vec<(u64, &[u8])> {
+        Volume::new(image, PAGE_SIZE, 4)
+            .unwrap()
+            .entries()
+            .map(|entry| (entry.id, entry.data))
+            .collect()
+    }
+
+    #[test]
+    fn reads_entries_in_order() {
+        let mut image = [PAD_BYTE; 3 * PAGE_SIZE];
+        assert!(entries(&image).is_empty());
+
+        write_page(&mut image, 0, 0, &[b"first", b"second"]);
+        write_page(&mut image, 1, 32, &[b"third"]);
+        assert_eq!(
+            entries(&image),
+            [(4, &b"first"[..]), (13, b"second"), (36, b"third")]
+        );
+    }
+
+    #[test]
+    fn reads_across_wrap_around() {
+        // The log wrapped around, so the newest page is the first.
+        let mut image = [PAD_BYTE; 3 * PAGE_SIZE];
+        write_page(&mut image, 0, 96, &[b"newest"]);
+        write_page(&mut image, 1, 32, &[b"oldest"]);
+        write_page(&mut image, 2, 64, &[b"middle"]);
+        assert_eq!(
+            entries(&image),
+            [(36, &b"oldest"[..]), (68, b"middle"), (100, b"newest")]
+        );
+    }
+
+    #[test]
+    fn stops_at_broken_entries() {
+        let mut image = [0; 2 * PAGE_SIZE];
+        // An entry which runs past the end of its page ends the page.
+        write_page(&mut image, 0, 0, &[b"ok", b"cut"]);
+        image[10..14].copy_from_slice(&100u32.to_le_bytes());
+        // As does an entry of zero length, as in a page never padded.
+        write_page(&mut image, 1, 32, &[b"ok too"]);
+        image[46..PAGE_SIZE * 2].fill(0);
+        assert_eq!(entries(&image), [(4, &b"ok"[..]), (36, b"ok too")]);
+    }
+
+    #[test]
+    fn checks_geometry() {
+        let image = [0; 64];
+        assert!(Volume::new(&image, 24, 4).is_err());
+        assert!(Volume::new(&image, 32, 2).is_err());
+        assert!(Volume::new(&image[..0], 32, 4).is_err());
+        assert!(Volume::new(&image, 32, 8).is_ok());
+    }
+}
@@ -4,6 +4,10 @@
 #![forbid(unsafe_code)]
 #![no_std]
 
+// This is used to run the tests on a host
+#[cfg(test)]
+extern crate std;
+
 pub mod parse;
 #[allow(dead_code)] // Some fields not read on device, but read when creating headers
 pub mod types;
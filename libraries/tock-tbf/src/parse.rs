@@ -134,6 +134,7 @@ pub fn parse_tbf_header(
                 let mut permissions_pointer: Option<types::TbfHeaderV2Permissions<8>> = None;
                 let mut persistent_acls_pointer: Option<types::TbfHeaderV2PersistentAcl<8>> = None;
                 let mut kernel_version: Option<types::TbfHeaderV2KernelVersion> = None;
+                let mut nonvolatile_storage: Option<types::TbfHeaderV2NonvolatileStorage> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -275,6 +276,22 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderNonvolatileStorage => {
+                            let entry_len = mem::size_of::<types::TbfHeaderV2NonvolatileStorage>();
+                            if tlv_header.length as usize == entry_len {
+                                nonvolatile_storage = Some(
+                                    remaining
+                                        .get(0..entry_len)
+                                        .ok_or(types::TbfParseError::NotEnoughFlash)?
+                                        .try_into()?,
+                                );
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -296,6 +313,7 @@ pub fn parse_tbf_header(
                     permissions: permissions_pointer,
                     persistent_acls: persistent_acls_pointer,
                     kernel_version: kernel_version,
+                    nonvolatile_storage: nonvolatile_storage,
                 };
 
                 Ok(types::TbfHeader::TbfHeaderV2(tbf_header))
@@ -326,3 +344,51 @@ pub fn parse_tbf_footer(
         _ => Err(types::TbfParseError::BadTlvEntry(tlv_header.tipe as usize)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A TBF header with a single TLV entry, with the checksum filled in.
+    fn header(tlv: &[u8]) -> &'static [u8] {
+        let mut header = std::vec::Vec::new();
+        let header_size = (16 + tlv.len()) as u16;
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&header_size.to_le_bytes());
+        header.extend_from_slice(&1024u32.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(tlv);
+        let checksum = header
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .fold(0, |checksum, word| checksum ^ word);
+        header[12..16].copy_from_slice(&checksum.to_le_bytes());
+        std::boxed::Box::leak(header.into_boxed_slice())
+    }
+
+    #[test]
+    fn parses_nonvolatile_storage() {
+        let tlv = [10, 0, 4, 0, 0x00, 0x10, 0x00, 0x00];
+        let parsed = parse_tbf_header(header(&tlv), 2).unwrap();
+        assert_eq!(parsed.get_nonvolatile_storage_size(), Some(0x1000));
+    }
+
+    #[test]
+    fn apps_without_nonvolatile_storage_have_none() {
+        // Only a package name.
+        let tlv = [3, 0, 4, 0, b'a', b'p', b'p', b's'];
+        let parsed = parse_tbf_header(header(&tlv), 2).unwrap();
+        assert_eq!(parsed.get_package_name(), Some("apps"));
+        assert_eq!(parsed.get_nonvolatile_storage_size(), None);
+    }
+
+    #[test]
+    fn rejects_nonvolatile_storage_of_the_wrong_length() {
+        let tlv = [10, 0, 8, 0, 0x00, 0x10, 0x00, 0x00, 0, 0, 0, 0];
+        assert!(matches!(
+            parse_tbf_header(header(&tlv), 2),
+            Err(types::TbfParseError::BadTlvEntry(10))
+        ));
+    }
+}
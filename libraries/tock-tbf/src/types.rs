@@ -125,6 +125,7 @@ pub enum TbfHeaderTypes {
     TbfHeaderPersistentAcl = 7,
     TbfHeaderKernelVersion = 8,
     TbfHeaderProgram = 9,
+    TbfHeaderNonvolatileStorage = 10,
     TbfFooterCredentials = 128,
 
     /// Some field in the header that we do not understand. Since the TLV format
@@ -236,6 +237,12 @@ pub struct TbfHeaderV2KernelVersion {
     minor: u16,
 }
 
+/// The size of the region of nonvolatile storage the app needs.
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2NonvolatileStorage {
+    size: u32,
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TbfFooterV2CredentialsType {
     Reserved = 0,
@@ -314,6 +321,7 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             7 => Ok(TbfHeaderTypes::TbfHeaderPersistentAcl),
             8 => Ok(TbfHeaderTypes::TbfHeaderKernelVersion),
             9 => Ok(TbfHeaderTypes::TbfHeaderProgram),
+            10 => Ok(TbfHeaderTypes::TbfHeaderNonvolatileStorage),
             128 => Ok(TbfHeaderTypes::TbfFooterCredentials),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
@@ -595,6 +603,20 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2KernelVersion {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2NonvolatileStorage {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2NonvolatileStorage, Self::Error> {
+        Ok(TbfHeaderV2NonvolatileStorage {
+            size: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
 impl core::convert::TryFrom<&'static [u8]> for TbfFooterV2Credentials {
     type Error = TbfParseError;
 
@@ -663,6 +685,7 @@ pub struct TbfHeaderV2 {
     pub(crate) permissions: Option<TbfHeaderV2Permissions<8>>,
     pub(crate) persistent_acls: Option<TbfHeaderV2PersistentAcl<NUM_PERSISTENT_ACLS>>,
     pub(crate) kernel_version: Option<TbfHeaderV2KernelVersion>,
+    pub(crate) nonvolatile_storage: Option<TbfHeaderV2NonvolatileStorage>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -924,6 +947,15 @@ impl TbfHeader {
         }
     }
 
+    /// Get the size of the region of nonvolatile storage this process needs.
+    /// Returns `None` if the nonvolatile storage header is not included.
+    pub fn get_nonvolatile_storage_size(&self) -> Option<u32> {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd.nonvolatile_storage.map(|storage| storage.size),
+            _ => None,
+        }
+    }
+
     /// Return the offset where the binary ends in the TBF or 0 if there
     /// is no binary. If there is a Main header the end offset is the size
     /// of the TBF, while if there is a Program header it can be smaller.
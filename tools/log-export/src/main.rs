@@ -0,0 +1,146 @@
+//! Exports the entries of a Tock log from a raw image of its flash volume.
+
+use std::process::exit;
+
+use tock_log_format::entry::{self, Header};
+use tock_log_format::volume::Volume;
+
+/// Prints an error message and usage string. Used to report command line
+/// argument errors.
+fn usage_error(message: &str) -> ! {
+    eprintln!(
+        "{}
+
+Usage: log-export --page-size <bytes> [--word-size <bytes>] [--raw] <image>
+Print the entries of the log in the raw flash volume image <image>.
+
+Options:
+  --page-size <bytes>  The size of the flash pages of the log.
+  --word-size <bytes>  The size of a usize of the target which wrote the log,
+                       4 (the default) or 8.
+  --raw                Print entries without decoding them as timestamped
+                       entries.",
+        message
+    );
+    exit(2);
+}
+
+struct Options {
+    page_size: usize,
+    word_size: usize,
+    raw: bool,
+    image: String,
+}
+
+fn parse_size(value: Option<String>, name: &str) -> usize {
+    value
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| usage_error(&format!("{} must be a number of bytes", name)))
+}
+
+fn options() -> Options {
+    let mut page_size = None;
+    let mut word_size = 4;
+    let mut raw = false;
+    let mut image = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--page-size" => page_size = Some(parse_size(args.next(), "--page-size")),
+            "--word-size" => word_size = parse_size(args.next(), "--word-size"),
+            "--raw" => raw = true,
+            _ if arg.starts_with("--") => usage_error(&format!("Unknown option {}", arg)),
+            _ if image.is_none() => image = Some(arg),
+            _ => usage_error("Only one image can be exported"),
+        }
+    }
+
+    Options {
+        page_size: page_size.unwrap_or_else(|| usage_error("--page-size is required")),
+        word_size,
+        raw,
+        image: image.unwrap_or_else(|| usage_error("No image given")),
+    }
+}
+
+/// Whether text holds no control characters other than newlines and tabs.
+fn is_printable(text: &str) -> bool {
+    !text
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+}
+
+/// Formats data as a string if it is printable, and in hex otherwise.
+fn format_data(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) if is_printable(text) => format!("{:?}", text),
+        _ => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    }
+}
+
+/// Tells the boots of the entries of a log apart, from their sequence
+/// numbers starting again.
+struct Boots {
+    boot: usize,
+    last: Option<Header>,
+}
+
+impl Boots {
+    fn boot_of(&mut self, header: Header) -> usize {
+        if let Some(last) = self.last {
+            if header.sequence <= last.sequence || header.timestamp_us < last.timestamp_us {
+                self.boot += 1;
+            }
+        }
+        self.last = Some(header);
+        self.boot
+    }
+}
+
+fn main() {
+    let options = options();
+    let image = std::fs::read(&options.image).unwrap_or_else(|error| {
+        eprintln!("Cannot read {}: {}", options.image, error);
+        exit(1);
+    });
+    let volume = Volume::new(&image, options.page_size, options.word_size).unwrap_or_else(|_| {
+        eprintln!(
+            "The image is not a whole number of {} byte pages, or the word size is not 4 or 8",
+            options.page_size
+        );
+        exit(1);
+    });
+
+    let mut boots = Boots {
+        boot: 0,
+        last: None,
+    };
+    // Payloads are never larger than a page, but compressed ones are longer
+    // once decompressed.
+    let mut payload = vec![0; 64 * options.page_size];
+    for raw in volume.entries() {
+        if options.raw {
+            println!("{}\t{}", raw.id, format_data(raw.data));
+            continue;
+        }
+        match entry::decode(raw.data, &mut payload) {
+            Ok((header, length)) => println!(
+                "{}\tboot {}\t#{}\t{}.{:06}s\t{}{}",
+                raw.id,
+                boots.boot_of(header),
+                header.sequence,
+                header.timestamp_us / 1_000_000,
+                header.timestamp_us % 1_000_000,
+                if header.compressed { "lz " } else { "" },
+                format_data(&payload[..length]),
+            ),
+            Err(error) => println!(
+                "{}\tnot timestamped ({:?})\t{}",
+                raw.id,
+                error,
+                format_data(raw.data)
+            ),
+        }
+    }
+}
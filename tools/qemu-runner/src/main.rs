@@ -1,4 +1,6 @@
+use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
 use std::process::Command;
+use std::time::Duration;
 
 use rexpect::errors::Error;
 use rexpect::session::PtySession;
@@ -67,6 +69,82 @@ fn earlgrey_cw310() -> Result<(), Error> {
     Ok(())
 }
 
+/// Exchanges a CoAP request with the kernel's CoAP endpoint over a TAP
+/// network device, which QEMU creates as root. This needs `sudo` without a
+/// password.
+fn qemu_rv32_virt_network() -> Result<(), Error> {
+    // First, build the board if needed
+    // n.b. rexpect's `exp_eof` does not actually block main thread, so use
+    // the standard Rust process library mechanism instead.
+    let mut build = Command::new("make")
+        .arg("-C")
+        .arg("../../boards/qemu_rv32_virt")
+        .spawn()
+        .expect("failed to spawn build");
+    assert!(build.wait().unwrap().success());
+
+    let mut p = spawn(
+        "make run -C ../../boards/qemu_rv32_virt NETDEV=SUDO-TAP",
+        Some(10_000),
+    )?;
+
+    p.exp_string("QEMU RISC-V 32-bit \"virt\" machine, initialization complete.")?;
+    p.exp_string("Entering main loop.")?;
+
+    // QEMU created tap0, which has to be up for the host to use it
+    let mut up = Command::new("sudo")
+        .args(["ip", "link", "set", "tap0", "up"])
+        .spawn()
+        .expect("failed to spawn ip");
+    assert!(up.wait().unwrap().success());
+    let ifindex: u32 = std::fs::read_to_string("/sys/class/net/tap0/ifindex")
+        .expect("no tap0 interface")
+        .trim()
+        .parse()
+        .unwrap();
+
+    // A confirmable GET of /tock-ci, which no app serves, with message ID
+    // 0x1234. The board's link-local address follows from the default
+    // NETDEV_MAC of 52:54:00:12:34:56.
+    let request = b"\x40\x01\x12\x34\xb7tock-ci";
+    let board = SocketAddrV6::new(
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0x5054, 0xff, 0xfe12, 0x3456),
+        5683,
+        0,
+        ifindex,
+    );
+    let socket = UdpSocket::bind("[::]:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+
+    // The host's link-local address takes a moment to become usable, and
+    // the board has to resolve the host's MAC address before answering
+    let mut response = [0; 64];
+    let mut received = None;
+    for _ in 0..20 {
+        if socket.send_to(request, board).is_err() {
+            std::thread::sleep(Duration::from_millis(500));
+            continue;
+        }
+        if let Ok((len, _)) = socket.recv_from(&mut response) {
+            received = Some(len);
+            break;
+        }
+    }
+    let len = received.expect("no CoAP response from the board");
+
+    // A piggybacked 4.04 Not Found acknowledging the request
+    assert!(len >= 4);
+    assert_eq!(&response[..4], b"\x60\x84\x12\x34");
+
+    // Test completed, kill QEMU
+    kill_qemu(&mut p)?;
+
+    p.exp_string("QEMU: Terminated")?;
+    Ok(())
+}
+
 fn main() {
     println!("Tock qemu-runner starting...");
     println!("");
@@ -77,4 +155,9 @@ fn main() {
     println!("Running earlgrey_cw310 tests...");
     earlgrey_cw310().unwrap_or_else(|e| panic!("earlgrey_cw310 job failed with {}", e));
     println!("earlgrey_cw310 SUCCESS.");
+    println!("");
+    println!("Running qemu_rv32_virt network tests...");
+    qemu_rv32_virt_network()
+        .unwrap_or_else(|e| panic!("qemu_rv32_virt network job failed with {}", e));
+    println!("qemu_rv32_virt network SUCCESS.");
 }